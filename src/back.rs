@@ -1,10 +1,33 @@
 //! The back-end of the compiler.
 
 pub mod asm;
+pub mod baremetal;
 pub mod codegen;
+pub mod disasm;
+pub mod emu;
+pub mod encode;
+pub mod frame;
+#[cfg(feature = "cranelift")]
+pub mod jit;
+pub mod layout;
+pub mod legalize;
+pub mod link;
+pub mod liveness;
+pub mod llvm;
+pub mod materialize;
+pub mod parse;
+pub mod regalloc;
+pub mod runner;
+pub mod runtime;
+pub mod schedule;
+pub mod stats;
+pub mod verify;
+pub mod wasm;
 
 pub use asm::*;
 pub use codegen::*;
+pub use liveness::Liveness;
+pub use regalloc::Allocator;
 
 #[cfg(test)]
 mod tests;
@@ -1,11 +1,11 @@
 //! The 64-bit RISCV (RV64G) backend.
 //!
-//! The backend fuses the register allocation into code generation (there is no
-//! separate register allocator), which is simpler than the full CFlat compiler.
+//! Code generation selects instructions over virtual registers, and a separate
+//! register allocation phase (see [crate::back::regalloc]) maps them to
+//! physical registers or stack slots.
 //!
-//! Additionally, we have a much simpler program structure (we need to call only
-//! runtime functions, and there are no function definitions).  So, an output
-//! program is just a sequence of instructions and labels.
+//! An output program is a list of functions, each a sequence of basic blocks
+//! with its own stack frame, and the global variables they share.
 //!
 //! # Design decisions
 //!
@@ -17,10 +17,26 @@
 //! ABI specification.
 //!
 //! Our treatment of the ABI is simpler because of the following design decisions:
-//! - All our primitives are 64 bits so we don't need to deal with words < 8 bytes.
+//! - All our primitives are one word (64 bits, or 32 bits on RV32) so we don't
+//!   need to deal with smaller values.
 //! - We never put aggregates on the stack (or pass them between functions) so we
 //!   don't need to handle large return values.
 //!
+//! # Targets
+//!
+//! The backend targets RV64 by default, and RV32 with the ILP32 ABI (see
+//! [Target]).  The ABIs are the same except for the size of registers and
+//! words: on RV32, loads and stores move 4 bytes (`lw` and `sw`), and
+//! stack arguments take 4 bytes each.  The slots in our own frames are 8
+//! bytes on both, which keeps the frame layout below the same.
+//!
+//! Programs run on Linux by default, where the C library starts the program
+//! and provides `exit`.  Under the RISC-V proxy kernel (`pk`), which runs
+//! programs on Spike, the output has no C library to rely on: it starts at
+//! its own `_start`, which calls `main`, and exits with `pk`'s `exit` system
+//! call (see [System]).  On bare metal, the output also brings its own
+//! runtime, which talks to the SBI firmware (see [crate::back::baremetal]).
+//!
 //! # Call stack frame
 //!
 //! In risc-v, the stack grows down (from higher memory addresses to lower memory
@@ -55,7 +71,9 @@
 //!   Low memory addresses
 //! ```
 //!
-//! - The current stack frame is between what fp and sp point to.
+//! - The current stack frame is between what fp and sp point to.  The layout
+//!   of the frame of each function, with the offsets of its parts and of its
+//!   variables, is in [crate::back::frame].
 //!
 //! - The caller saves registers designated as caller-saved, and puts some of
 //!   the arguments on the stack before making the call (see the calling
@@ -67,7 +85,8 @@
 //! # Calling convention
 //!
 //! The calling convention here is a simplified version of the full calling
-//! convention.  Specifically, we do not handle passing floating point values.
+//! convention, the one of the LP64D ABI (ILP32D on RV32), where doubles go in
+//! floating-point registers.
 //!
 //! 1. The caller saves caller-saved registers (by pushing them to the stack).
 //! 2. The caller places the argument values to the registers and the stack (see
@@ -85,12 +104,14 @@
 //! 5. The callee reserves the stack space for locals & saved registers.
 //! 6. The callee saves callee-saved registers (except the frame pointer and the
 //!    stack pointer).
-//!    - This is done by the register allocator.
+//!    - Register allocation finds the ones the function writes (see
+//!      [Function::used_registers]), and the prologue stores them at the
+//!      bottom of the frame.
 //! 7. The callee code is executed, then callee puts the return value to a0 (or
 //!    a1:a0, see below).
 //! 8. The callee restores callee-saved registers (first general-purpose
 //!    registers, then the frame pointer and the stack pointer).
-//!    - This is done by the register allocator.
+//!    - The epilogue loads the ones the prologue saved.
 //! 9. The callee jumps back to the caller by jumping to the saved return
 //!    address.
 //! 10. The caller frees the space allocated for arguments (this is done by
@@ -100,9 +121,20 @@
 //!
 //! ## Argument-passing
 //!
-//! We deal with passing at most 1 argument and always returning 1 argument
-//! (`print` doesn't need to return anything but we can pretend that it does).
-//! The passed argument and the return value are both stored on a0.
+//! All arguments are words.  The first eight are passed in a0--a7, in order,
+//! and the rest in the words at the top of the caller's stack, where the
+//! caller reserves 16-byte aligned space for them right before the call and
+//! frees it right after.  The ninth argument is at the stack pointer, the
+//! tenth one a word above it, and so on.  The callee finds them above its
+//! saved return address, so the ninth one is at `16(fp)`.
+//!
+//! Functions always return 1 value (`print` doesn't need to return anything
+//! but we can pretend that it does), which is stored on a0.
+//!
+//! Doubles are passed in fa0--fa7 instead, in order among the doubles, and a
+//! returned double is stored on fa0.  A double that doesn't fit in fa0--fa7
+//! goes where the next integer argument would, in an argument register or
+//! on the stack.
 //!
 //! # Registers
 //!
@@ -113,6 +145,8 @@
 //!
 //! - ra (return address), t0--t7 (temporary registers), a0--a7 (argument
 //!   registers).
+//! - ft0--ft11 (temporary floating-point registers), fa0--fa7 (floating-point
+//!   argument registers).
 //!
 //! ## Callee-saved registers
 //!
@@ -122,38 +156,238 @@
 //! - fp (a.k.a. s0) and sp are the frame pointer and the stack pointer
 //!   respectively.
 //! - s1--s11 are general-purpose callee-saved registers.
+//! - fs0--fs11 are floating-point callee-saved registers.  The prologue saves
+//!   the ones the function writes above the general-purpose ones.
+//!
+//! ## Floating-point registers
+//!
+//! The floating-point instructions hold doubles in physical registers (see
+//! [FRegister]).  The tiny IR has no doubles yet, so code generation doesn't
+//! select them.  Liveness and the register allocators only follow the
+//! general-purpose registers, so whatever emits floating-point instructions
+//! picks their registers itself, and has to treat ft0--ft11 and fa0--fa7 as
+//! changed by calls.
 //!
 //! ## Reservation of temporary registers
 //!
 //! - the code generator is permitted to use t0--t6 as long as their use don't
-//!   span multiple instructions.
+//!   span multiple instructions.  The register allocator uses t0--t2 to load
+//!   and store spilled registers around an instruction, and the assembly
+//!   output uses t4--t6 for the forms the assembler doesn't have.
 //!
 //! # Register allocation
 //!
-//! There is no register allocator, all variables are saved on the stack.
+//! Code generation uses a fresh virtual register for each variable and
+//! temporary, and only names physical registers where the ABI fixes them.
+//! The register allocator gives the most used virtual registers callee-saved
+//! registers, and saves the rest in stack slots below the frame pointer.
+//!
+//! # Assembly output
+//!
+//! [Program::asm_code] emits the functions in the `.text` section, each under
+//! its own name: the prologue, which builds the frame above, then the basic
+//! blocks in order, then the epilogue, which blocks jump to in order to
+//! return.  Block labels are local to the object file and mangled as
+//! `.L<function>.<block>`, with the characters that the assembler doesn't
+//! take escaped (see [local_label]).  The global variables follow, labeled
+//! `.Lglobal.<name>` and aligned to words: the ones with initial values in
+//! the `.data` section, and the ones that start out as zero in the `.bss`
+//! section.  Instructions refer to globals through the `la`, `ld`, and `sd`
+//! pseudo-instructions, which the assembler expands to PC-relative
+//! sequences.
+//!
+//! Position-independent code (see [AsmOptions::pic]) starts with `.option
+//! pic`.  The globals are local to the object file, so `lla` loads their
+//! PC-relative addresses, while `la` would go through the global offset table
+//! under that option.  Other functions may be in another
+//! shared object, or be preempted by one, so calls go through the procedure
+//! linkage table with `@plt`.
+//!
+//! With [AsmOptions::compressed], the output starts with `.option rvc`, which
+//! lets the assembler pick the 16-bit form of each instruction whose operands
+//! qualify, like `c.addi` or `c.ld`.  Most of the forms only take s0, s1, and
+//! a0--a5, so how much it helps depends on the registers the allocator
+//! picks, which [crate::back::encode::Binary::compressed_code_size]
+//! measures: the code of the `squares` test program shrinks from 432 to
+//! 258 bytes with the simple allocator, and from 352 to 210 bytes with graph
+//! coloring.
+//!
+//! With [AsmOptions::extensions], the output enables the optional extensions
+//! of the ISA for the assembler with `.option arch`, like `+zba` for the
+//! `sh1add` to `sh3add` instructions that the code generator uses to index
+//! arrays (see [crate::back::codegen]).
+//!
+//! Immediates that don't fit in their instructions are split (see
+//! [crate::back::legalize]), including the frame size in the prologue.
+//!
+//! # Stack maps
+//!
+//! Programs compiled for a precise garbage collector (see
+//! [crate::back::codegen::Options::gc]) call [GC_INIT_FN] when `main` starts,
+//! and keep the heap pointers that are live across calls in stack slots, so
+//! that the collector can find them, and update them if it moves objects.
+//! Each call that returns is followed by a [Instruction::StackMap], which
+//! becomes a label at the return address.  The output then has a table in
+//! the `.data` section, labeled [STACK_MAPS], of words: the number of
+//! entries, and then for each call, the return address, the number of slots
+//! that hold live heap pointers, and their offsets from the frame pointer.
+//!
+//! The collector walks the frames from the one that called the runtime: a
+//! frame's return address is 8 bytes above its frame pointer, and the
+//! caller's frame pointer right at it.  The entry for the return address
+//! lists the slots of the caller's frame, relative to the caller's frame
+//! pointer.  `main`'s return address isn't in the table, which ends the
+//! walk.
+//!
+//! # Line numbers
+//!
+//! With line-number information (see
+//! [crate::back::codegen::Options::debug_file]), the code of each source
+//! statement starts with a [Instruction::Loc], which becomes a `.loc`
+//! directive, and the output numbers the source files with `.file`
+//! directives at the top.  The assembler turns them into a DWARF
+//! `.debug_line` section, which is enough for `gdb` and `lldb` to show the
+//! source and step through it line by line.  The output has no other debug
+//! information, such as the locations of variables.
 #![allow(dead_code)]
 
 use derive_more::Display;
-use std::collections::BTreeMap as Map;
 
+use crate::back::baremetal;
+use crate::back::legalize::legalize_instruction;
 use crate::common::*;
 
 use Location::*;
 use Memory::*;
 use Register::*;
 
-/// Word and pointer size for this processor
-const WORD_SIZE: i32 = 8;
-const LOG2_WORD_SIZE: i32 = 3;
+/// The bytes of each stack slot for a saved register.  Slots are this large
+/// on either target, so that the two for the return address and the frame
+/// pointer keep the stack 16-byte aligned.
+pub const SLOT_SIZE: i32 = 8;
+
+/// The target architectures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// 64-bit RISC-V with the LP64 ABI.
+    #[default]
+    Riscv64,
+    /// 32-bit RISC-V with the ILP32 ABI.
+    Riscv32,
+}
+
+impl Target {
+    /// The architecture of a target, if it's RISC-V.
+    pub fn of(spec: &TargetSpec) -> Option<Target> {
+        match spec.arch {
+            Arch::Riscv64 => Some(Target::Riscv64),
+            Arch::Riscv32 => Some(Target::Riscv32),
+            Arch::X86_64 | Arch::Wasm32 => None,
+        }
+    }
+
+    /// The bytes in a register, a pointer, and a word of memory.
+    pub fn word_size(self) -> i32 {
+        match self {
+            Target::Riscv64 => 8,
+            Target::Riscv32 => 4,
+        }
+    }
+}
+
+/// The optional extensions of the ISA that the code may use, besides M.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Extensions {
+    /// Zba, for address generation: `sh1add`, `sh2add`, and `sh3add`.
+    pub zba: bool,
+    /// Zbb, the basic bit manipulation, like `andn`, `min`, and `max`.
+    pub zbb: bool,
+}
+
+impl Extensions {
+    /// The names of the extensions, as `-march` and `.option arch` take them.
+    pub fn names(self) -> Vec<&'static str> {
+        [(self.zba, "zba"), (self.zbb, "zbb")]
+            .into_iter()
+            .filter_map(|(on, name)| on.then_some(name))
+            .collect()
+    }
+}
 
-/// The name of the GC initializer
-const GC_INIT_FN: &str = "_cflat_init_gc";
+/// The name of the runtime function that sets up the garbage collector,
+/// which `main` calls first with stack maps
+pub const GC_INIT_FN: &str = "_cflat_init_gc";
+
+/// The label of the table of stack maps
+pub const STACK_MAPS: &str = "_cflat_stack_maps";
 
 /// The name of the allocation function provided by the runtime
-const ALLOC_FN: &str = "_cflat_alloc";
+pub const ALLOC_FN: &str = "_cflat_alloc";
+
+/// The name of the runtime function that reads a number from the standard
+/// input and returns it
+pub const READ_FN: &str = "_cflat_read";
+
+/// The name of the runtime function that prints its argument
+pub const PRINT_FN: &str = "_cflat_print";
+
+/// The name of the runtime hook that writes the profile counters
+pub const PROFILE_DUMP_FN: &str = "_cflat_profile_dump";
+
+/// The name of the C library function that ends the program with the status
+/// in a0
+pub const EXIT_FN: &str = "exit";
+
+/// The name of the routine that ends the program with the status in a0 on
+/// systems without a C library, which the assembly output includes.  Calls to
+/// [EXIT_FN] call it instead.
+pub const STANDALONE_EXIT_FN: &str = "_cflat_exit";
+
+/// The number of the `exit` system call of the proxy kernel, which is the
+/// same as Linux's
+const PK_SYS_EXIT: i32 = 93;
 
-// Argument registers used in the RISC-V ABI
-static ARG_REGISTERS: [Register; 8] = [A0, A1, A2, A3, A4, A5, A6, A7];
+/// The name of the runtime function that reports a stack overflow and ends
+/// the program
+pub const STACK_OVERFLOW_FN: &str = "_cflat_stack_overflow";
+
+/// The name of the runtime function that reports a division by zero and ends
+/// the program
+pub const DIV_BY_ZERO_FN: &str = "_cflat_div_by_zero";
+
+/// The name of the runtime function that reports an arithmetic overflow and
+/// ends the program
+pub const OVERFLOW_FN: &str = "_cflat_overflow";
+
+/// The name of the routine that returns a0 * a1 without the M extension.  The
+/// assembly output includes it if the program calls it.
+pub const MUL_FN: &str = "_cflat_mul";
+
+/// The name of the routine that returns a0 / a1 without the M extension,
+/// rounding toward zero, or -1 if a1 is zero like `div`.  The assembly output
+/// includes it if the program calls it.
+pub const DIV_FN: &str = "_cflat_div";
+
+/// The label of the epilogue, which blocks jump to in order to return from
+/// the function.  Block names starting with `$` are reserved for the
+/// compiler, so no block has this name.
+pub const EPILOGUE: &str = "$epilogue";
+
+/// The label of the block that calls [STACK_OVERFLOW_FN], which functions
+/// jump to when their frame goes past the stack limit.
+pub const STACK_OVERFLOW: &str = "$stack_overflow";
+
+/// The label of the block that calls [DIV_BY_ZERO_FN], which functions jump
+/// to instead of dividing by zero when divisions trap.
+pub const DIV_BY_ZERO: &str = "$div_by_zero";
+
+/// The label of the block that calls [OVERFLOW_FN], which functions jump to
+/// when the result of an arithmetic operation doesn't fit in a word and
+/// overflows trap.
+pub const OVERFLOW: &str = "$overflow";
+
+/// Argument registers used in the RISC-V ABI
+pub static ARG_REGISTERS: [Register; 8] = [A0, A1, A2, A3, A4, A5, A6, A7];
 
 /// Registers for the actual risc-v machine, in the order in the register file.
 #[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq, PartialOrd, Ord)]
@@ -225,12 +459,156 @@ pub enum Register {
     T6,
 }
 
+/// Floating-point registers of the D extension, in the order in the register
+/// file.
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[allow(missing_docs)]
+pub enum FRegister {
+    #[display("ft0")]
+    Ft0,
+    #[display("ft1")]
+    Ft1,
+    #[display("ft2")]
+    Ft2,
+    #[display("ft3")]
+    Ft3,
+    #[display("ft4")]
+    Ft4,
+    #[display("ft5")]
+    Ft5,
+    #[display("ft6")]
+    Ft6,
+    #[display("ft7")]
+    Ft7,
+    #[display("fs0")]
+    Fs0,
+    #[display("fs1")]
+    Fs1,
+    #[display("fa0")]
+    Fa0,
+    #[display("fa1")]
+    Fa1,
+    #[display("fa2")]
+    Fa2,
+    #[display("fa3")]
+    Fa3,
+    #[display("fa4")]
+    Fa4,
+    #[display("fa5")]
+    Fa5,
+    #[display("fa6")]
+    Fa6,
+    #[display("fa7")]
+    Fa7,
+    #[display("fs2")]
+    Fs2,
+    #[display("fs3")]
+    Fs3,
+    #[display("fs4")]
+    Fs4,
+    #[display("fs5")]
+    Fs5,
+    #[display("fs6")]
+    Fs6,
+    #[display("fs7")]
+    Fs7,
+    #[display("fs8")]
+    Fs8,
+    #[display("fs9")]
+    Fs9,
+    #[display("fs10")]
+    Fs10,
+    #[display("fs11")]
+    Fs11,
+    #[display("ft8")]
+    Ft8,
+    #[display("ft9")]
+    Ft9,
+    #[display("ft10")]
+    Ft10,
+    #[display("ft11")]
+    Ft11,
+}
+
+impl Register {
+    /// All registers, in the order in the register file.
+    pub const ALL: [Register; 32] = [
+        Zero, Ra, Sp, Gp, Tp, T0, T1, T2, Fp, S1, A0, A1, A2, A3, A4, A5, A6, A7, S2, S3, S4, S5,
+        S6, S7, S8, S9, S10, S11, T3, T4, T5, T6,
+    ];
+}
+
+impl FRegister {
+    /// All floating-point registers, in the order in the register file.
+    pub const ALL: [FRegister; 32] = [
+        FRegister::Ft0,
+        FRegister::Ft1,
+        FRegister::Ft2,
+        FRegister::Ft3,
+        FRegister::Ft4,
+        FRegister::Ft5,
+        FRegister::Ft6,
+        FRegister::Ft7,
+        FRegister::Fs0,
+        FRegister::Fs1,
+        FRegister::Fa0,
+        FRegister::Fa1,
+        FRegister::Fa2,
+        FRegister::Fa3,
+        FRegister::Fa4,
+        FRegister::Fa5,
+        FRegister::Fa6,
+        FRegister::Fa7,
+        FRegister::Fs2,
+        FRegister::Fs3,
+        FRegister::Fs4,
+        FRegister::Fs5,
+        FRegister::Fs6,
+        FRegister::Fs7,
+        FRegister::Fs8,
+        FRegister::Fs9,
+        FRegister::Fs10,
+        FRegister::Fs11,
+        FRegister::Ft8,
+        FRegister::Ft9,
+        FRegister::Ft10,
+        FRegister::Ft11,
+    ];
+
+    /// Does the callee have to preserve this register?
+    pub fn is_callee_saved(self) -> bool {
+        use FRegister::*;
+
+        matches!(
+            self,
+            Fs0 | Fs1 | Fs2 | Fs3 | Fs4 | Fs5 | Fs6 | Fs7 | Fs8 | Fs9 | Fs10 | Fs11
+        )
+    }
+}
+
+/// Registers before register allocation: either virtual registers, which the
+/// allocator maps to physical registers or stack slots, or physical registers
+/// that the ABI requires, like the argument registers of a call.
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub enum VReg {
+    #[display("{_0}")]
+    Phys(Register),
+    #[display("v{_0}")]
+    Virt(u32),
+}
+
+impl From<Register> for VReg {
+    fn from(r: Register) -> Self {
+        VReg::Phys(r)
+    }
+}
+
 /// Memory locations that RISC-V instructions can access to.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Display)]
-enum Memory {
+pub enum Memory<R = Register> {
     /// A memory location whose value is in the given register + offset
     #[display("{}({})", _1, _0)]
-    Mem(Register, i32),
+    Mem(R, i32),
     /// A global variable with offset.  Effectively, this address is calculated
     /// via an offset from the PC, but we represent it as if it is an absolute
     /// value we can store to keep the backend simple until final assembly-out
@@ -244,9 +622,19 @@ enum Memory {
     },
 }
 
-impl Memory {
+impl<R> Memory<R> {
+    /// Replace the register, if there is one, with `f` of it.
+    pub fn map_registers<S>(self, f: impl FnOnce(R) -> S) -> Memory<S> {
+        match self {
+            Mem(r, offset) => Mem(f(r), offset),
+            Global { index, offset } => Global { index, offset },
+        }
+    }
+}
+
+impl<R: Copy> Memory<R> {
     /// Return registers that are used to describe this location.
-    pub fn used_registers(&self) -> Option<Register> {
+    pub fn used_registers(&self) -> Option<R> {
         match self {
             Mem(r, _offset) => Some(*r),
             Global { .. } => None,
@@ -254,7 +642,7 @@ impl Memory {
     }
 
     /// Get a memory location with given offset from this location.
-    pub fn offset(&self, offset: i32) -> Memory {
+    pub fn offset(&self, offset: i32) -> Memory<R> {
         match *self {
             Mem(r, off) => Mem(r, off + offset),
             Global { index, offset: off } => Global {
@@ -266,22 +654,22 @@ impl Memory {
 }
 /// Locations (both memory and register) that RISC-V instructions can access to.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
-enum Location {
+enum Location<R = Register> {
     /// A memory location
-    MemoryL(Memory),
-    Reg(Register),
+    MemoryL(Memory<R>),
+    Reg(R),
 }
 
-impl Location {
+impl<R: Copy> Location<R> {
     /// Return registers that are used to describe this location.
-    pub fn used_registers(&self) -> Option<Register> {
+    pub fn used_registers(&self) -> Option<R> {
         match self {
             MemoryL(m) => m.used_registers(),
             Reg(r) => Some(*r),
         }
     }
 
-    pub fn get_memory(&self) -> Option<&Memory> {
+    pub fn get_memory(&self) -> Option<&Memory<R>> {
         match self {
             MemoryL(m) => Some(m),
             Reg(_) => None,
@@ -289,7 +677,7 @@ impl Location {
     }
 
     /// Get a memory location with given offset from this location.
-    pub fn offset(&self, offset: i32) -> Location {
+    pub fn offset(&self, offset: i32) -> Location<R> {
         match self {
             Reg(_) if offset == 0 => *self,
             Reg(_) => {
@@ -304,31 +692,37 @@ impl Location {
 
 /// A RISC-V instruction that is parametric over the register type.
 #[derive(Clone, Eq, PartialEq, Debug)]
-enum Instruction {
+pub enum Instruction<R = Register> {
     La {
-        dst: Register,
-        src: Memory,
+        dst: R,
+        src: Memory<R>,
     },
     Ld {
-        dst: Register,
-        src: Memory,
+        dst: R,
+        src: Memory<R>,
     },
     Sd {
-        dst: Memory,
-        src: Register,
+        dst: Memory<R>,
+        src: R,
     },
     Li {
-        dst: Register,
+        dst: R,
         imm: i64,
     },
+    /// Load the 20-bit immediate into bits 12--31, sign-extended to 64 bits,
+    /// and clear the rest.  The immediate is between 0 and 0xfffff.
+    Lui {
+        dst: R,
+        imm: i32,
+    },
     /// Basic arithmetic operations between two registers: addition,
     /// subtraction, multiplication, division, and bit operations.  See
     /// [ArithOp] for supported operations.
     Arith {
         op: ArithOp,
-        dst: Register,
-        lhs: Register,
-        rhs: Register,
+        dst: R,
+        lhs: R,
+        rhs: R,
     },
     /// Basic arithmetic operations between a register and an immediate:
     /// addition, subtraction, multiplication, division, and bit operations.
@@ -338,8 +732,8 @@ enum Instruction {
     /// instructions later.
     ArithI {
         op: ArithOp,
-        dst: Register,
-        lhs: Register,
+        dst: R,
+        lhs: R,
         rhs: i32,
     },
     /// Jump to a label (a fixed memory address).  This emits just a `jal`
@@ -347,7 +741,7 @@ enum Instruction {
     /// The rest of the program is responsible for implementing the correct
     /// function call protocol when using this instruction for calls.
     Jal {
-        dst: Register,
+        dst: R,
         target: JumpTarget,
     }, // can also be used for jumps
     /// Jump to an address stored in a memory location.  This emits just a
@@ -356,29 +750,85 @@ enum Instruction {
     /// correct function call protocol when using this instruction for calls and
     /// returns.
     Jalr {
-        dst: Register,
-        target: Register,
+        dst: R,
+        target: R,
     },
     Branch {
         cond: Condition,
-        lhs: Register,
-        rhs: Register,
+        lhs: R,
+        rhs: R,
         target: JumpTarget,
     },
     /// Pseudo-ops seqz, snez, sltz, sgtz, ... :
     /// dst = 1 if lhs cond 0, otherwise dst = 0.
     SCmpZ {
-        dst: Register,
-        lhs: Register,
+        dst: R,
+        lhs: R,
         cond: Condition,
     },
+    /// Load a double.
+    Fld {
+        dst: FRegister,
+        src: Memory<R>,
+    },
+    /// Store a double.
+    Fsd {
+        dst: Memory<R>,
+        src: FRegister,
+    },
+    /// Arithmetic between two doubles.  See [FArithOp] for supported
+    /// operations.
+    FArith {
+        op: FArithOp,
+        dst: FRegister,
+        lhs: FRegister,
+        rhs: FRegister,
+    },
+    /// dst = 1 if lhs op rhs, otherwise dst = 0.  Comparisons with NaN are
+    /// false.
+    FCmp {
+        op: FCmpOp,
+        dst: R,
+        lhs: FRegister,
+        rhs: FRegister,
+    },
+    /// Copy a double between floating-point registers.
+    FMv {
+        dst: FRegister,
+        src: FRegister,
+    },
+    /// Convert a signed integer to the nearest double.
+    FCvtFromInt {
+        dst: FRegister,
+        src: R,
+    },
+    /// Convert a double to a signed integer, rounding toward zero.
+    FCvtToInt {
+        dst: R,
+        src: FRegister,
+    },
     /// In-line comments in the output for debugging
     Comment(String),
+    /// The stack map of the call right before, which emits no code.  Code
+    /// generation fills in the heap pointers that are live after the call,
+    /// and register allocation puts them in stack slots and replaces them
+    /// with the offsets of the slots from the frame pointer.
+    StackMap {
+        live: Vec<R>,
+        slots: Vec<i32>,
+    },
+    /// The code of the given line and column of a source file starts here,
+    /// which emits no code.
+    Loc {
+        file: Id,
+        line: u32,
+        column: u32,
+    },
 }
 
-impl Instruction {
+impl<R: Copy + From<Register>> Instruction<R> {
     /// Return the registers used by this instruction.
-    pub fn used_registers(&self) -> Vec<Register> {
+    pub fn used_registers(&self) -> Vec<R> {
         use Instruction::*;
 
         match self {
@@ -397,7 +847,7 @@ impl Instruction {
                 lhs,
                 rhs: _,
             } => vec![*dst, *lhs],
-            Li { dst, .. } => vec![*dst],
+            Li { dst, .. } | Lui { dst, .. } => vec![*dst],
             Jalr { target, dst } => vec![*target, *dst],
             Jal { target: _, dst } => vec![*dst],
             Branch {
@@ -407,26 +857,113 @@ impl Instruction {
                 rhs,
             } => vec![*lhs, *rhs],
             SCmpZ { dst, lhs, cond: _ } => vec![*lhs, *dst],
-            Comment(_) => vec![],
+            Fld { src: mem, .. } | Fsd { dst: mem, .. } => {
+                mem.used_registers().into_iter().collect()
+            }
+            FCmp { dst, .. } | FCvtToInt { dst, .. } => vec![*dst],
+            FCvtFromInt { src, .. } => vec![*src],
+            FArith { .. } | FMv { .. } | Comment(_) | Loc { .. } => vec![],
+            StackMap { live, .. } => live.clone(),
+        }
+    }
+
+    /// The register this instruction writes to, if any.
+    pub fn def(&self) -> Option<R> {
+        use Instruction::*;
+
+        match self {
+            La { dst, .. }
+            | Ld { dst, .. }
+            | Li { dst, .. }
+            | Lui { dst, .. }
+            | Arith { dst, .. }
+            | ArithI { dst, .. }
+            | Jal { dst, .. }
+            | Jalr { dst, .. }
+            | SCmpZ { dst, .. }
+            | FCmp { dst, .. }
+            | FCvtToInt { dst, .. } => Some(*dst),
+            Sd { .. }
+            | Branch { .. }
+            | Fld { .. }
+            | Fsd { .. }
+            | FArith { .. }
+            | FMv { .. }
+            | FCvtFromInt { .. }
+            | Comment(_)
+            | StackMap { .. }
+            | Loc { .. } => None,
+        }
+    }
+
+    /// The registers this instruction reads.
+    pub fn uses(&self) -> Vec<R> {
+        use Instruction::*;
+
+        match self {
+            La { src, .. } | Ld { src, .. } | Fld { src, .. } | Fsd { dst: src, .. } => {
+                src.used_registers().into_iter().collect()
+            }
+            Sd { dst, src } => dst.used_registers().into_iter().chain(Some(*src)).collect(),
+            Arith { lhs, rhs, .. } | Branch { lhs, rhs, .. } => vec![*lhs, *rhs],
+            ArithI { lhs, .. } | SCmpZ { lhs, .. } => vec![*lhs],
+            Jalr { target, .. } => vec![*target],
+            FCvtFromInt { src, .. } => vec![*src],
+            StackMap { live, .. } => live.clone(),
+            Li { .. }
+            | Lui { .. }
+            | Jal { .. }
+            | FArith { .. }
+            | FCmp { .. }
+            | FMv { .. }
+            | FCvtToInt { .. }
+            | Comment(_)
+            | Loc { .. } => vec![],
+        }
+    }
+
+    /// The floating-point register this instruction writes to, if any.
+    pub fn fp_def(&self) -> Option<FRegister> {
+        use Instruction::*;
+
+        match self {
+            Fld { dst, .. } | FArith { dst, .. } | FMv { dst, .. } | FCvtFromInt { dst, .. } => {
+                Some(*dst)
+            }
+            _ => None,
+        }
+    }
+
+    /// The floating-point registers this instruction reads.
+    pub fn fp_uses(&self) -> Vec<FRegister> {
+        use Instruction::*;
+
+        match self {
+            Fsd { src, .. } | FMv { src, .. } | FCvtToInt { src, .. } => vec![*src],
+            FArith { lhs, rhs, .. } | FCmp { lhs, rhs, .. } => vec![*lhs, *rhs],
+            _ => vec![],
         }
     }
 
     /// Create a jump instruction that does not save the return address.
-    pub fn jump(target: JumpTarget) -> Instruction {
-        Instruction::Jal { dst: Zero, target }
+    pub fn jump(target: JumpTarget) -> Self {
+        Instruction::Jal {
+            dst: Zero.into(),
+            target,
+        }
     }
 
     /// Create a jump instruction that emulates a direct call using the ra
     /// register for the return address.
-    pub fn call(callee: Id) -> Instruction {
+    pub fn call(callee: Id) -> Self {
         Instruction::Jal {
-            dst: Ra,
+            dst: Ra.into(),
             target: JumpTarget::Global(callee),
         }
     }
 
     /// Create an instruction that moves values between registers.
-    pub fn mov(dst: Register, src: Register) -> Instruction {
+    pub fn mov(dst: R, src: R) -> Self {
         Instruction::ArithI {
             op: ArithOp::Add,
             dst,
@@ -437,7 +974,7 @@ impl Instruction {
 
     /// Generate a single load instruction from given location to the given
     /// register.  This should generate a move if the source is also a register.
-    fn read(dst: Register, src: Location) -> Self {
+    fn read(dst: R, src: Location<R>) -> Self {
         match src {
             Reg(r) => Self::mov(dst, r),
             MemoryL(src) => Self::Ld { dst, src },
@@ -446,7 +983,7 @@ impl Instruction {
 
     /// Generate a single load instruction from given location to the given
     /// register.  This should generate a move if the source is also a register.
-    fn write(dst: Location, src: Register) -> Self {
+    fn write(dst: Location<R>, src: R) -> Self {
         match dst {
             Reg(r) => Self::mov(r, src),
             MemoryL(dst) => Self::Sd { dst, src },
@@ -454,7 +991,91 @@ impl Instruction {
     }
 }
 
-impl std::fmt::Display for Instruction {
+impl<R> Instruction<R> {
+    /// Replace every register, read or written, with `f` of it.
+    pub fn map_registers<S>(self, mut f: impl FnMut(R) -> S) -> Instruction<S> {
+        use Instruction::*;
+
+        match self {
+            La { dst, src } => La {
+                dst: f(dst),
+                src: src.map_registers(f),
+            },
+            Ld { dst, src } => Ld {
+                dst: f(dst),
+                src: src.map_registers(f),
+            },
+            Sd { dst, src } => Sd {
+                dst: dst.map_registers(&mut f),
+                src: f(src),
+            },
+            Li { dst, imm } => Li { dst: f(dst), imm },
+            Lui { dst, imm } => Lui { dst: f(dst), imm },
+            Arith { op, dst, lhs, rhs } => Arith {
+                op,
+                dst: f(dst),
+                lhs: f(lhs),
+                rhs: f(rhs),
+            },
+            ArithI { op, dst, lhs, rhs } => ArithI {
+                op,
+                dst: f(dst),
+                lhs: f(lhs),
+                rhs,
+            },
+            Jal { dst, target } => Jal {
+                dst: f(dst),
+                target,
+            },
+            Jalr { dst, target } => Jalr {
+                dst: f(dst),
+                target: f(target),
+            },
+            Branch {
+                cond,
+                lhs,
+                rhs,
+                target,
+            } => Branch {
+                cond,
+                lhs: f(lhs),
+                rhs: f(rhs),
+                target,
+            },
+            SCmpZ { dst, lhs, cond } => SCmpZ {
+                dst: f(dst),
+                lhs: f(lhs),
+                cond,
+            },
+            Fld { dst, src } => Fld {
+                dst,
+                src: src.map_registers(f),
+            },
+            Fsd { dst, src } => Fsd {
+                dst: dst.map_registers(f),
+                src,
+            },
+            FArith { op, dst, lhs, rhs } => FArith { op, dst, lhs, rhs },
+            FCmp { op, dst, lhs, rhs } => FCmp {
+                op,
+                dst: f(dst),
+                lhs,
+                rhs,
+            },
+            FMv { dst, src } => FMv { dst, src },
+            FCvtFromInt { dst, src } => FCvtFromInt { dst, src: f(src) },
+            FCvtToInt { dst, src } => FCvtToInt { dst: f(dst), src },
+            Comment(s) => Comment(s),
+            StackMap { live, slots } => StackMap {
+                live: live.into_iter().map(f).collect(),
+                slots,
+            },
+            Loc { file, line, column } => Loc { file, line, column },
+        }
+    }
+}
+
+impl<R: std::fmt::Display> std::fmt::Display for Instruction<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Instruction::*;
 
@@ -468,8 +1089,11 @@ impl std::fmt::Display for Instruction {
             Ld { dst, src } => write!(f, "ld {dst}, {src}"),
             Sd { dst, src } => write!(f, "sd {src}, {dst}"),
             Li { dst, imm } => write!(f, "li {dst}, {imm}"),
+            Lui { dst, imm } => write!(f, "lui {dst}, {imm:#x}"),
             Arith { op, dst, lhs, rhs } => write!(f, "{op} {dst}, {lhs}, {rhs}"),
-            ArithI { op, dst, lhs, rhs } => write!(f, "{op}i {dst}, {lhs}, {rhs}"),
+            ArithI { op, dst, lhs, rhs } => {
+                write!(f, "{} {dst}, {lhs}, {rhs}", op.immediate_form())
+            }
             Jal { dst, target } => write!(f, "jal {dst}, {}", target_to_string(target)),
             Jalr { dst, target } => write!(f, "jalr {dst}, {target}"),
             Branch {
@@ -482,14 +1106,26 @@ impl std::fmt::Display for Instruction {
                 write!(f, "b{cond} {lhs}, {rhs}, {target}")
             }
             SCmpZ { dst, lhs, cond } => write!(f, "s{cond}z {dst}, {lhs}"),
+            Fld { dst, src } => write!(f, "fld {dst}, {src}"),
+            Fsd { dst, src } => write!(f, "fsd {src}, {dst}"),
+            FArith { op, dst, lhs, rhs } => write!(f, "{op} {dst}, {lhs}, {rhs}"),
+            FCmp { op, dst, lhs, rhs } => write!(f, "{op} {dst}, {lhs}, {rhs}"),
+            FMv { dst, src } => write!(f, "fmv.d {dst}, {src}"),
+            FCvtFromInt { dst, src } => write!(f, "fcvt.d.l {dst}, {src}"),
+            FCvtToInt { dst, src } => write!(f, "fcvt.l.d {dst}, {src}, rtz"),
             Comment(s) => write!(f, "# {s:?}"),
+            StackMap { live, slots } => {
+                let live = live.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                write!(f, "# stack map: {} {slots:?}", live.join(", "))
+            }
+            Loc { file, line, column } => write!(f, "# {file}:{line}:{column}"),
         }
     }
 }
 
 /// Conditions for branching
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
-enum Condition {
+pub enum Condition {
     #[display("eq")]
     Equal,
     #[display("ne")]
@@ -504,15 +1140,34 @@ enum Condition {
     GreaterEq,
 }
 
+impl Condition {
+    /// The condition that holds exactly when this one doesn't.
+    pub fn negate(self) -> Condition {
+        use Condition::*;
+
+        match self {
+            Equal => NotEqual,
+            NotEqual => Equal,
+            Less => GreaterEq,
+            LessEq => Greater,
+            Greater => LessEq,
+            GreaterEq => Less,
+        }
+    }
+}
+
 /// Arithmetic operations used in the `Arith` family of instructions.
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
-enum ArithOp {
+pub enum ArithOp {
     #[display("add")]
     Add,
     #[display("sub")]
     Sub,
     #[display("mul")]
     Mul,
+    /// The upper half of the product of two signed words.
+    #[display("mulh")]
+    MulH,
     #[display("div")]
     Div,
     /// Set if less than given immediate. dst = 1 if lhs < rhs, otherwise dst = 0.
@@ -530,37 +1185,837 @@ enum ArithOp {
     Sra,
     #[display("sll")]
     Sll,
+    /// Add the lower 32 bits, and sign-extend the 32-bit result.
+    #[display("addw")]
+    AddW,
+    /// Shift `lhs` left by 1, 2, or 3 bits and add `rhs` (Zba).
+    #[display("sh1add")]
+    Sh1Add,
+    #[display("sh2add")]
+    Sh2Add,
+    #[display("sh3add")]
+    Sh3Add,
+    /// `lhs` and the complement of `rhs` (Zbb).
+    #[display("andn")]
+    AndN,
+    /// The smaller or the larger of two signed words (Zbb).
+    #[display("min")]
+    Min,
+    #[display("max")]
+    Max,
+}
+
+impl ArithOp {
+    /// The name of the instruction that takes an immediate instead of `rhs`.
+    pub fn immediate_form(&self) -> String {
+        match self {
+            ArithOp::AddW => "addiw".into(),
+            op => format!("{op}i"),
+        }
+    }
+}
+
+/// Arithmetic operations between doubles.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
+pub enum FArithOp {
+    #[display("fadd.d")]
+    Add,
+    #[display("fsub.d")]
+    Sub,
+    #[display("fmul.d")]
+    Mul,
+    #[display("fdiv.d")]
+    Div,
+}
+
+/// Comparisons between doubles.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
+pub enum FCmpOp {
+    #[display("feq.d")]
+    Equal,
+    #[display("flt.d")]
+    Less,
+    #[display("fle.d")]
+    LessEq,
 }
 
 /// Jump targets.
 #[derive(Clone, Eq, PartialEq, Debug)]
-enum JumpTarget {
+pub enum JumpTarget {
     /// A local jump target in the same function.  These target names are
     /// mangled in the final assembly code so that basic block names in each
-    /// function are independent from others.
+    /// function are independent from others (see [local_label]).
     Local(Id),
     /// A global jump target.  These targets are used for jumping to global
     /// error handling code.
     Global(Id),
 }
 
-struct BasicBlock {
-    id: Id,
-    instructions: Vec<Instruction>,
+/// A basic block, which is a label followed by instructions.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct BasicBlock<R = Register> {
+    pub id: Id,
+    pub instructions: Vec<Instruction<R>>,
+}
+
+/// A backend program.  Code generation produces programs over virtual
+/// registers, and register allocation turns them into programs over physical
+/// registers, which can be emitted.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Program<R = Register> {
+    /// The functions, in the order they are emitted.
+    pub functions: Vec<Function<R>>,
+    /// The global variables.  [Memory::Global] refers to them by their index
+    /// here.
+    pub globals: Vec<GlobalVar>,
+    /// The architecture the program is for.
+    pub target: Target,
+}
+
+/// A global variable, which is a sequence of words.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct GlobalVar {
+    pub id: Id,
+    /// The initial values of the words.
+    pub init: Vec<i64>,
+}
+
+impl GlobalVar {
+    /// A global variable of the given number of words that start out as zero.
+    pub fn zeroed(id: Id, words: usize) -> Self {
+        GlobalVar {
+            id,
+            init: vec![0; words],
+        }
+    }
+
+    /// Do all the words start out as zero?  Then the global goes in the
+    /// `.bss` section, which takes no space in the object file.
+    pub fn is_zeroed(&self) -> bool {
+        self.init.iter().all(|w| *w == 0)
+    }
+}
+
+/// A function of a backend program, with its own stack frame.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Function<R = Register> {
+    /// The name of the function, which is its entry symbol.
+    pub id: Id,
+    /// The basic blocks in the order they are emitted.  The first one is the
+    /// entry block, which comes right after the prologue.
+    pub basic_blocks: Vec<BasicBlock<R>>,
+    /// The bytes of stack space for local variables, which are right below
+    /// the frame pointer.
+    pub stack_space: i32,
+    /// Callee-saved registers the function writes, in order.  Register
+    /// allocation fills this in, and the prologue and the epilogue save and
+    /// restore them below the local variables.
+    pub used_registers: Vec<Register>,
+    /// The variables of the tiny IR function, in the order of their IDs, and
+    /// where they live.  Code generation puts each one in its own virtual
+    /// register, and register allocation replaces those with their homes,
+    /// leaving out the variables that the code never mentions.
+    pub variables: Vec<(Id, VarLocation<R>)>,
+}
+
+/// Where a variable of the tiny IR lives in the code of its function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Display)]
+pub enum VarLocation<R = Register> {
+    /// A register, which is virtual until register allocation.
+    #[display("{_0}")]
+    Reg(R),
+    /// The stack slot at the given offset from the frame pointer.
+    #[display("{_0}(fp)")]
+    Stack(i32),
+}
+
+impl<R> Program<R> {
+    /// Apply `f` to each function.
+    pub fn map_functions<S>(self, f: impl FnMut(Function<R>) -> Function<S>) -> Program<S> {
+        Program {
+            functions: self.functions.into_iter().map(f).collect(),
+            globals: self.globals,
+            target: self.target,
+        }
+    }
+}
+
+/// Settings for the assembly output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AsmOptions {
+    /// Generate position-independent code, which can be linked into PIE
+    /// executables and shared objects.
+    pub pic: bool,
+    /// Let the assembler use the 16-bit instructions of the C extension.
+    pub compressed: bool,
+    /// The extensions that the code may use, which the output enables for
+    /// the assembler.  The division routine uses Zbb.
+    pub extensions: Extensions,
+    /// The system that runs the program.
+    pub system: System,
 }
 
-/// A backend program.
-pub struct Program {
-    id: Id,
-    basic_blocks: Map<Id, BasicBlock>,
-    stack_space: i32,
-    /// Callee-saved registers used in the main function.  This is used for
-    /// generating register save/restore code in function prologue/epilogue.
-    used_registers: Vec<Register>,
+/// The system that runs a program, which decides how it starts and exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum System {
+    /// Linux, or any system with a C library, whose startup code calls
+    /// `main`, and whose `exit` ends the program.
+    #[default]
+    Linux,
+    /// The RISC-V proxy kernel, without a C library.  The program starts at
+    /// `_start`, which sets up the global pointer, calls `main`, and exits
+    /// with its result, and [STANDALONE_EXIT_FN] makes the `exit` system
+    /// call.
+    ProxyKernel,
+    /// Bare metal, under an SBI implementation like OpenSBI.  The output
+    /// includes the startup code and the runtime, which does I/O with SBI
+    /// calls (see [crate::back::baremetal]).
+    BareMetal,
+}
+
+impl System {
+    /// The system of a target, if this backend has code for it.
+    pub fn of(spec: &TargetSpec) -> Option<System> {
+        match spec.os {
+            Os::Linux => Some(System::Linux),
+            Os::ProxyKernel => Some(System::ProxyKernel),
+            Os::BareMetal => Some(System::BareMetal),
+            Os::WasmHost => None,
+        }
+    }
 }
 
 impl Program {
+    /// Generate the assembly code for the GNU assembler with the default
+    /// settings.
     pub fn asm_code(&self) -> String {
-        todo!("generate the final assembly code")
+        self.asm_code_with(&AsmOptions::default())
+    }
+
+    /// Generate the assembly code for the GNU assembler.
+    pub fn asm_code_with(&self, options: &AsmOptions) -> String {
+        let mut out = String::new();
+        let mut line = |s: String| {
+            out.push_str(&s);
+            out.push('\n');
+        };
+
+        if options.pic {
+            line("    .option pic".into());
+        }
+        if options.compressed {
+            line("    .option rvc".into());
+        }
+        let extensions = options.extensions.names();
+        if !extensions.is_empty() {
+            line(format!("    .option arch, +{}", extensions.join(", +")));
+        }
+        let files = self.source_files();
+        for (i, file) in files.iter().enumerate() {
+            line(format!("    .file {} {:?}", i + 1, file.as_str()));
+        }
+        line("    .text".into());
+        // The labels of the return addresses of calls, and the slots that
+        // hold heap pointers after them.
+        let mut stack_maps = vec![];
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 {
+                line("".into());
+            }
+            let id = func.id;
+            let emitter = Emitter {
+                func: id,
+                globals: &self.globals,
+                files: &files,
+                pic: options.pic,
+                system: options.system,
+                target: self.target,
+            };
+            line(format!("    .globl {id}"));
+            line(format!("    .type {id}, @function"));
+            line(format!("{id}:"));
+            for insn in func.prologue().into_iter().flat_map(legalize_instruction) {
+                line(format!("    {}", emitter.emit(&insn)));
+            }
+            for block in &func.basic_blocks {
+                line(format!("{}:", emitter.local_label(block.id)));
+                for insn in &block.instructions {
+                    if let Instruction::StackMap { slots, .. } = insn {
+                        let label =
+                            emitter.local_label(Id::new(format!("$gc{}", stack_maps.len())));
+                        line(format!("{label}:"));
+                        stack_maps.push((label, slots));
+                    }
+                    line(format!("    {}", emitter.emit(insn)));
+                }
+            }
+            line(format!("{}:", emitter.local_label(Id::from_ref(EPILOGUE))));
+            for insn in func
+                .epilogue_code()
+                .into_iter()
+                .flat_map(legalize_instruction)
+            {
+                line(format!("    {}", emitter.emit(&insn)));
+            }
+            line(format!("    .size {id}, .-{id}"));
+        }
+        match options.system {
+            System::Linux => {}
+            System::ProxyKernel => {
+                line("".into());
+                line(pk_entry());
+            }
+            System::BareMetal => {
+                line("".into());
+                line(baremetal::runtime(self.target));
+            }
+        }
+        for name in [MUL_FN, DIV_FN] {
+            if self.calls(name) {
+                line("".into());
+                line(soft_routine(name, self.target, options.extensions));
+            }
+        }
+
+        let word = self.target.word_size();
+        let directive = match self.target {
+            Target::Riscv64 => ".dword",
+            Target::Riscv32 => ".word",
+        };
+        let (bss, data): (Vec<_>, Vec<_>) = self.globals.iter().partition(|g| g.is_zeroed());
+        if !data.is_empty() {
+            line("".into());
+            line("    .data".into());
+            line(format!("    .p2align {}", word.trailing_zeros()));
+            for global in data {
+                line(format!("{}:", global_label(global.id)));
+                for value in &global.init {
+                    line(format!("    {directive} {value}"));
+                }
+            }
+        }
+        if self.calls(GC_INIT_FN) {
+            line("".into());
+            line("    .data".into());
+            line(format!("    .p2align {}", word.trailing_zeros()));
+            line(format!("    .globl {STACK_MAPS}"));
+            line(format!("{STACK_MAPS}:"));
+            line(format!("    {directive} {}", stack_maps.len()));
+            for (label, slots) in stack_maps {
+                let words = [label, slots.len().to_string()]
+                    .into_iter()
+                    .chain(slots.iter().map(|offset| offset.to_string()));
+                line(format!(
+                    "    {directive} {}",
+                    words.collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+        if !bss.is_empty() {
+            line("".into());
+            line("    .bss".into());
+            line(format!("    .p2align {}", word.trailing_zeros()));
+            for global in bss {
+                line(format!("{}:", global_label(global.id)));
+                line(format!("    .zero {}", word as usize * global.init.len()));
+            }
+        }
+        out
+    }
+}
+
+impl<R> Program<R> {
+    /// Does any function call `name`?
+    fn calls(&self, name: &str) -> bool {
+        self.functions
+            .iter()
+            .flat_map(|f| &f.basic_blocks)
+            .flat_map(|b| &b.instructions)
+            .any(|insn| match insn {
+                Instruction::Jal {
+                    target: JumpTarget::Global(callee),
+                    ..
+                } => callee.as_str() == name,
+                _ => false,
+            })
+    }
+
+    /// The source files that [Instruction::Loc] refers to, in the order
+    /// they first appear.
+    fn source_files(&self) -> Vec<Id> {
+        let mut files = vec![];
+        for insn in self
+            .functions
+            .iter()
+            .flat_map(|f| &f.basic_blocks)
+            .flat_map(|b| &b.instructions)
+        {
+            if let Instruction::Loc { file, .. } = insn {
+                if !files.contains(file) {
+                    files.push(*file);
+                }
+            }
+        }
+        files
+    }
+}
+
+impl Function {
+    /// The bytes between the frame pointer and the stack pointer: the local
+    /// variables and the callee-saved registers, rounded up to keep the stack
+    /// 16-byte aligned.
+    pub(crate) fn frame_size(&self) -> i32 {
+        let saved = self.used_registers.len() + self.saved_fp_registers().len();
+        let size = self.stack_space + SLOT_SIZE * saved as i32;
+        (size + 15) / 16 * 16
+    }
+
+    /// The callee-saved floating-point registers the function writes.  They
+    /// are saved above the general-purpose callee-saved registers.
+    pub(crate) fn saved_fp_registers(&self) -> Vec<FRegister> {
+        self.basic_blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .filter_map(Instruction::fp_def)
+            .filter(|r| r.is_callee_saved())
+            .collect::<Set<_>>()
+            .into_iter()
+            .collect()
     }
+
+    /// The stack slot of the `i`th saved floating-point register.
+    fn fp_save_slot(&self, i: usize) -> Memory {
+        Mem(Sp, SLOT_SIZE * (self.used_registers.len() + i) as i32)
+    }
+
+    /// Save the return address and the frame pointer, make the frame pointer
+    /// point to the saved frame pointer, reserve the frame, and save the
+    /// callee-saved registers at its bottom.
+    pub(crate) fn prologue(&self) -> Vec<Instruction> {
+        let mut code = vec![
+            Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: Sp,
+                lhs: Sp,
+                rhs: -2 * SLOT_SIZE,
+            },
+            Instruction::Sd {
+                dst: Mem(Sp, SLOT_SIZE),
+                src: Ra,
+            },
+            Instruction::Sd {
+                dst: Mem(Sp, 0),
+                src: Fp,
+            },
+            Instruction::mov(Fp, Sp),
+        ];
+        if self.frame_size() > 0 {
+            code.push(Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: Sp,
+                lhs: Sp,
+                rhs: -self.frame_size(),
+            });
+        }
+        for (i, r) in self.used_registers.iter().enumerate() {
+            code.push(Instruction::Sd {
+                dst: Mem(Sp, SLOT_SIZE * i as i32),
+                src: *r,
+            });
+        }
+        for (i, r) in self.saved_fp_registers().into_iter().enumerate() {
+            code.push(Instruction::Fsd {
+                dst: self.fp_save_slot(i),
+                src: r,
+            });
+        }
+        code
+    }
+
+    /// Undo the prologue and return to the caller.
+    pub(crate) fn epilogue_code(&self) -> Vec<Instruction> {
+        let mut code = vec![];
+        for (i, r) in self.saved_fp_registers().into_iter().enumerate() {
+            code.push(Instruction::Fld {
+                dst: r,
+                src: self.fp_save_slot(i),
+            });
+        }
+        for (i, r) in self.used_registers.iter().enumerate() {
+            code.push(Instruction::Ld {
+                dst: *r,
+                src: Mem(Sp, SLOT_SIZE * i as i32),
+            });
+        }
+        code.extend([
+            Instruction::mov(Sp, Fp),
+            Instruction::Ld {
+                dst: Fp,
+                src: Mem(Sp, 0),
+            },
+            Instruction::Ld {
+                dst: Ra,
+                src: Mem(Sp, SLOT_SIZE),
+            },
+            Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: Sp,
+                lhs: Sp,
+                rhs: 2 * SLOT_SIZE,
+            },
+            Instruction::Jalr {
+                dst: Zero,
+                target: Ra,
+            },
+        ]);
+        code
+    }
+}
+
+/// Prints instructions of a function in the syntax of the GNU assembler.
+struct Emitter<'a> {
+    /// The name of the function.
+    func: Id,
+    /// The global variables.
+    globals: &'a [GlobalVar],
+    /// The source files, which `.loc` directives refer to by their position
+    /// here plus one.
+    files: &'a [Id],
+    /// Is the code position-independent?
+    pic: bool,
+    /// The system, which decides what exiting calls.
+    system: System,
+    /// The architecture, which decides the size of the words we load and
+    /// store.
+    target: Target,
+}
+
+impl Emitter<'_> {
+    /// The label of a basic block.
+    fn local_label(&self, block: Id) -> String {
+        local_label(self.func, block)
+    }
+
+    /// The label of the global variable with the given index.
+    fn global_label(&self, index: usize) -> String {
+        global_label(self.globals[index].id)
+    }
+
+    /// The address of a memory location, as an operand.  Globals are
+    /// addressed by their label, which the assembler turns into a
+    /// PC-relative address.
+    fn address(&self, mem: &Memory) -> String {
+        match *mem {
+            Mem(r, offset) => format!("{offset}({r})"),
+            Global { index, offset: 0 } => self.global_label(index),
+            Global { index, offset } => format!("{}{offset:+}", self.global_label(index)),
+        }
+    }
+
+    /// The suffix of the loads and stores of a word: `d` for double words,
+    /// and `w` for words.
+    fn width(&self) -> char {
+        match self.target {
+            Target::Riscv64 => 'd',
+            Target::Riscv32 => 'w',
+        }
+    }
+
+    /// The suffix of the conversions between doubles and words: `l` for
+    /// double words, and `w` for words.
+    fn int_width(&self) -> char {
+        match self.target {
+            Target::Riscv64 => 'l',
+            Target::Riscv32 => 'w',
+        }
+    }
+
+    fn target(&self, target: &JumpTarget) -> String {
+        match target {
+            JumpTarget::Local(block) => self.local_label(*block),
+            JumpTarget::Global(name) => self.callee(*name).to_string(),
+        }
+    }
+
+    /// The function that calls to `name` call, which is [STANDALONE_EXIT_FN]
+    /// instead of [EXIT_FN] without a C library.
+    fn callee(&self, name: Id) -> Id {
+        match self.system {
+            System::ProxyKernel | System::BareMetal if name.as_str() == EXIT_FN => {
+                Id::from_ref(STANDALONE_EXIT_FN)
+            }
+            _ => name,
+        }
+    }
+
+    /// Print an instruction in the syntax of the GNU assembler.  Unlike
+    /// [Instruction]'s [Display] implementation, which is for debugging, this
+    /// resolves labels and expands the forms the assembler doesn't have.
+    fn emit(&self, insn: &Instruction) -> String {
+        use Instruction::*;
+
+        match insn {
+            // With `.option pic`, `la` goes through the GOT, which our own
+            // globals don't need.
+            La {
+                dst,
+                src: src @ Global { .. },
+            } if self.pic => format!("lla {dst}, {}", self.address(src)),
+            La { dst, src } => format!("la {dst}, {}", self.address(src)),
+            Ld { dst, src } => format!("l{} {dst}, {}", self.width(), self.address(src)),
+            // Storing to a symbol needs a register for its address.
+            Sd {
+                dst: dst @ Global { .. },
+                src,
+            } => format!(
+                "s{} {src}, {}, {}",
+                self.width(),
+                self.address(dst),
+                scratch(&[*src])
+            ),
+            Sd { dst, src } => format!("s{} {src}, {}", self.width(), self.address(dst)),
+            Li { dst, imm } => format!("li {dst}, {imm}"),
+            Lui { dst, imm } => format!("lui {dst}, {imm:#x}"),
+            Arith { op, dst, lhs, rhs } => format!("{op} {dst}, {lhs}, {rhs}"),
+            ArithI { op, dst, lhs, rhs } => match op {
+                ArithOp::Add
+                | ArithOp::Slt
+                | ArithOp::And
+                | ArithOp::Or
+                | ArithOp::Xor
+                | ArithOp::Srl
+                | ArithOp::Sra
+                | ArithOp::Sll
+                | ArithOp::AddW => format!("{} {dst}, {lhs}, {rhs}", op.immediate_form()),
+                ArithOp::Sub => format!("addi {dst}, {lhs}, {}", -(*rhs as i64)),
+                // There are no immediate forms, so load the immediate first.
+                ArithOp::Mul
+                | ArithOp::MulH
+                | ArithOp::Div
+                | ArithOp::Sh1Add
+                | ArithOp::Sh2Add
+                | ArithOp::Sh3Add
+                | ArithOp::AndN
+                | ArithOp::Min
+                | ArithOp::Max => {
+                    let tmp = scratch(&[*lhs]);
+                    format!("li {tmp}, {rhs}\n    {op} {dst}, {lhs}, {tmp}")
+                }
+            },
+            Jal {
+                dst: Ra,
+                target: JumpTarget::Global(name),
+            } if self.pic => format!("call {}@plt", self.callee(*name)),
+            Jal {
+                dst: Ra,
+                target: JumpTarget::Global(name),
+            } => format!("call {}", self.callee(*name)),
+            Jal { dst, target } => format!("jal {dst}, {}", self.target(target)),
+            Jalr { dst, target } => format!("jalr {dst}, 0({target})"),
+            Branch {
+                cond,
+                lhs,
+                rhs,
+                target,
+            } => format!("b{cond} {lhs}, {rhs}, {}", self.target(target)),
+            SCmpZ { dst, lhs, cond } => format!("s{cond}z {dst}, {lhs}"),
+            Fld {
+                dst,
+                src: src @ Global { .. },
+            } => format!("fld {dst}, {}, {}", self.address(src), scratch(&[])),
+            Fld { dst, src } => format!("fld {dst}, {}", self.address(src)),
+            Fsd {
+                dst: dst @ Global { .. },
+                src,
+            } => format!("fsd {src}, {}, {}", self.address(dst), scratch(&[])),
+            Fsd { dst, src } => format!("fsd {src}, {}", self.address(dst)),
+            FArith { op, dst, lhs, rhs } => format!("{op} {dst}, {lhs}, {rhs}"),
+            FCmp { op, dst, lhs, rhs } => format!("{op} {dst}, {lhs}, {rhs}"),
+            FMv { dst, src } => format!("fmv.d {dst}, {src}"),
+            FCvtFromInt { dst, src } => format!("fcvt.d.{} {dst}, {src}", self.int_width()),
+            FCvtToInt { dst, src } => format!("fcvt.{}.d {dst}, {src}, rtz", self.int_width()),
+            Comment(s) => format!("# {}", s.replace('\n', " ")),
+            StackMap { slots, .. } => {
+                let slots = slots.iter().map(|offset| format!("{offset}(fp)"));
+                format!("# heap pointers: {}", slots.collect::<Vec<_>>().join(", "))
+            }
+            Loc { file, line, column } => {
+                let number = self.files.iter().position(|f| f == file).unwrap() + 1;
+                format!(".loc {number} {line} {column}")
+            }
+        }
+    }
+}
+
+/// The entry point of programs under the proxy kernel, which falls through
+/// to [STANDALONE_EXIT_FN] when `main` returns.  Relaxation would turn
+/// loading the global pointer into setting it to itself, so it's off for
+/// that.
+fn pk_entry() -> String {
+    format!(
+        "    .globl _start
+    .type _start, @function
+_start:
+    .option push
+    .option norelax
+    lla gp, __global_pointer$
+    .option pop
+    call main
+    .size _start, .-_start
+    .globl {STANDALONE_EXIT_FN}
+    .type {STANDALONE_EXIT_FN}, @function
+{STANDALONE_EXIT_FN}:
+    li a7, {PK_SYS_EXIT}
+    ecall
+    .size {STANDALONE_EXIT_FN}, .-{STANDALONE_EXIT_FN}"
+    )
+}
+
+/// The code of [MUL_FN] or [DIV_FN], which are leaf functions that only
+/// change caller-saved registers.  Multiplication adds the shifted `a0` for
+/// each bit of `a1`, and division takes the magnitudes of its operands and
+/// shifts the bits of the dividend into the remainder one by one, subtracting
+/// the divisor when it fits.  Both wrap around like `mul` and `div`.  With
+/// Zbb, the magnitude of a number is the larger of it and its negation.
+fn soft_routine(name: &str, target: Target, extensions: Extensions) -> String {
+    let bits = target.word_size() * 8;
+    let magnitudes = if extensions.zbb {
+        "    sub t0, zero, a0
+    max a0, a0, t0
+    sub t0, zero, a1
+    max a1, a1, t0"
+            .to_string()
+    } else {
+        format!(
+            "    bge a0, zero, .L{name}.lhs
+    sub a0, zero, a0
+.L{name}.lhs:
+    bge a1, zero, .L{name}.rhs
+    sub a1, zero, a1
+.L{name}.rhs:"
+        )
+    };
+    let body = match name {
+        MUL_FN => format!(
+            "    addi t0, a0, 0
+    li a0, 0
+.L{name}.loop:
+    beq a1, zero, .L{name}.done
+    andi t1, a1, 1
+    beq t1, zero, .L{name}.next
+    add a0, a0, t0
+.L{name}.next:
+    slli t0, t0, 1
+    srli a1, a1, 1
+    jal zero, .L{name}.loop
+.L{name}.done:
+    jalr zero, 0(ra)"
+        ),
+        DIV_FN => format!(
+            "    beq a1, zero, .L{name}.by_zero
+    # The sign bit of t2 is the sign of the quotient.
+    xor t2, a0, a1
+{magnitudes}
+    li t0, 0
+    li t1, 0
+    li t3, {bits}
+.L{name}.loop:
+    slli t1, t1, 1
+    srli t4, a0, {}
+    or t1, t1, t4
+    slli a0, a0, 1
+    slli t0, t0, 1
+    bltu t1, a1, .L{name}.next
+    sub t1, t1, a1
+    ori t0, t0, 1
+.L{name}.next:
+    addi t3, t3, -1
+    bne t3, zero, .L{name}.loop
+    addi a0, t0, 0
+    bge t2, zero, .L{name}.done
+    sub a0, zero, a0
+.L{name}.done:
+    jalr zero, 0(ra)
+.L{name}.by_zero:
+    li a0, -1
+    jalr zero, 0(ra)",
+            bits - 1
+        ),
+        _ => unreachable!("`{name}` is not a multiplication or division routine"),
+    };
+    format!("    .type {name}, @function\n{name}:\n{body}\n    .size {name}, .-{name}")
+}
+
+/// The label of a block of a function, `.L<function>.<block>`.  Labels
+/// starting with `.L` are local to the object file, and prefixing them with
+/// the function keeps the blocks of different functions apart.
+///
+/// The assembler only takes ASCII letters, digits, `_`, `$`, and `.` in
+/// labels, so each other byte of the names becomes `$` and its two hex
+/// digits, and so do the dots in the function name, which makes the first
+/// dot the end of the function.  A `$` followed by two hex digits becomes
+/// `$24`, so escapes are never ambiguous: different pairs of names get
+/// different labels, and the same pair always gets the same one.
+pub fn local_label(func: Id, block: Id) -> String {
+    format!(".L{}.{}", mangle(&func, false), mangle(&block, true))
+}
+
+/// The names of the function and the block that [local_label] made a label
+/// from, if it did.
+pub fn unmangle_label(label: &str) -> Option<(Id, Id)> {
+    let (func, block) = label.strip_prefix(".L")?.split_once('.')?;
+    Some((Id::new(unmangle(func)?), Id::new(unmangle(block)?)))
+}
+
+/// Escape a name for a label, keeping its dots if `dots` is set.
+fn mangle(name: &str, dots: bool) -> String {
+    let bytes = name.as_bytes();
+    let mut label = String::new();
+    for (i, b) in bytes.iter().enumerate() {
+        let hex_follows = bytes
+            .get(i + 1..i + 3)
+            .is_some_and(|next| next.iter().all(u8::is_ascii_hexdigit));
+        match b {
+            b'$' if !hex_follows => label.push('$'),
+            b'.' if dots => label.push('.'),
+            b if b.is_ascii_alphanumeric() || *b == b'_' => label.push(*b as char),
+            b => label += &format!("${b:02x}"),
+        }
+    }
+    label
+}
+
+/// Undo [mangle], or return `None` if the escapes don't make a name.
+fn unmangle(label: &str) -> Option<String> {
+    let bytes = label.as_bytes();
+    let mut name = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'$' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                name.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            }
+            _ => {
+                name.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(name).ok()
+}
+
+/// The label of a global variable.
+fn global_label(name: Id) -> String {
+    format!(".Lglobal.{name}")
+}
+
+/// A temporary register that is none of the given ones.  These registers are
+/// reserved for the instructions that the backend expands after register
+/// allocation.
+pub fn scratch(avoid: &[Register]) -> Register {
+    [T6, T5, T4]
+        .into_iter()
+        .find(|r| !avoid.contains(r))
+        .unwrap()
 }
@@ -0,0 +1,299 @@
+//! Running programs on bare metal.
+//!
+//! Bare-metal programs run in supervisor mode under an SBI implementation
+//! like OpenSBI, which QEMU's `virt` machine loads by default, with no
+//! operating system or C library.  So the assembly output brings its own
+//! startup code and runtime:
+//!
+//! - `_start` sets up the global pointer and the stack, clears the `.bss`
+//!   section, calls `main`, and exits with its result.
+//! - [STANDALONE_EXIT_FN] shuts the machine down with the SBI system reset
+//!   extension, or the legacy shutdown call on older firmware.
+//! - [PRINT_FN] and [READ_FN] write and read the console a character at a
+//!   time with the legacy SBI console calls.  Reading waits for the
+//!   characters, since a console has no end of input.
+//! - [ALLOC_FN] takes zeroed words from a heap between the program and the
+//!   stack, and ends the program with an error when it runs out.  It never
+//!   frees them, so [GC_INIT_FN] does nothing.
+//!
+//! The program links with [LINKER_SCRIPT], which loads it where OpenSBI
+//! jumps to on QEMU (`0x80200000`), and places the heap and the stack in the
+//! rest of the RAM.  The runtime works on RV64 and RV32, with the loads and
+//! stores of the target's words.
+
+use crate::back::asm::{
+    Target, ALLOC_FN, DIV_BY_ZERO_FN, GC_INIT_FN, OVERFLOW_FN, PRINT_FN, PROFILE_DUMP_FN, READ_FN,
+    STACK_OVERFLOW_FN, STANDALONE_EXIT_FN,
+};
+
+/// The extension ID of the SBI system reset extension ("SRST")
+const SBI_SRST: i32 = 0x53525354;
+
+/// The extension IDs of the legacy SBI calls that write and read a character
+/// of the console, and shut the machine down
+const SBI_PUTCHAR: i32 = 1;
+const SBI_GETCHAR: i32 = 2;
+const SBI_SHUTDOWN: i32 = 8;
+
+/// The linker script for bare-metal programs.  The heap ends 1 MiB below the
+/// top of the RAM, where the stack starts.
+pub const LINKER_SCRIPT: &str = "OUTPUT_ARCH(riscv)
+ENTRY(_start)
+
+MEMORY
+{
+  RAM (rwx) : ORIGIN = 0x80200000, LENGTH = 126M
+}
+
+SECTIONS
+{
+  .text : {
+    *(.text.init)
+    *(.text .text.*)
+  } > RAM
+  .rodata : {
+    *(.rodata .rodata.* .srodata .srodata.*)
+  } > RAM
+  .data : {
+    *(.data .data.*)
+    __global_pointer$ = . + 0x800;
+    *(.sdata .sdata.*)
+  } > RAM
+  .bss : {
+    . = ALIGN(8);
+    __bss_start = .;
+    *(.sbss .sbss.* .bss .bss.* COMMON)
+    . = ALIGN(8);
+    __bss_end = .;
+  } > RAM
+  . = ALIGN(16);
+  __heap_start = .;
+  __stack_top = ORIGIN(RAM) + LENGTH(RAM);
+  __heap_end = __stack_top - 0x100000;
+}
+";
+
+/// The startup code and the runtime of bare-metal programs.  `_start` goes in
+/// `.text.init` so that the linker script puts it first, and the code ends
+/// back in `.text`.  The runtime's functions only change caller-saved
+/// registers, and SBI calls only change `a0` and `a1`.
+pub fn runtime(target: Target) -> String {
+    let word = target.word_size();
+    let shift = word.trailing_zeros();
+    let (load, store) = match target {
+        Target::Riscv64 => ("ld", "sd"),
+        Target::Riscv32 => ("lw", "sw"),
+    };
+    format!(
+        "    .section .text.init, \"ax\", @progbits
+    .globl _start
+    .type _start, @function
+_start:
+    .option push
+    .option norelax
+    lla gp, __global_pointer$
+    .option pop
+    lla sp, __stack_top
+    lla t0, __bss_start
+    lla t1, __bss_end
+1:
+    bgeu t0, t1, 2f
+    {store} zero, 0(t0)
+    addi t0, t0, {word}
+    j 1b
+2:
+    lla t0, __heap_start
+    {store} t0, .Lheap, t1
+    call main
+    tail {STANDALONE_EXIT_FN}
+    .size _start, .-_start
+
+    .text
+    .globl {STANDALONE_EXIT_FN}
+    .type {STANDALONE_EXIT_FN}, @function
+{STANDALONE_EXIT_FN}:
+    snez a1, a0
+    li a0, 0
+    li a6, 0
+    li a7, {SBI_SRST}
+    ecall
+    li a7, {SBI_SHUTDOWN}
+    ecall
+1:
+    wfi
+    j 1b
+    .size {STANDALONE_EXIT_FN}, .-{STANDALONE_EXIT_FN}
+
+    .globl {PRINT_FN}
+    .type {PRINT_FN}, @function
+{PRINT_FN}:
+    # Write the digits backwards into a buffer on the stack, from the
+    # negated value so that the smallest one works too.
+    addi sp, sp, -32
+    addi t0, sp, 31
+    li t1, 10
+    sb t1, 0(t0)
+    mv t2, a0
+    blez t2, 1f
+    neg t2, t2
+1:
+    rem t3, t2, t1
+    div t2, t2, t1
+    li t4, 48
+    sub t3, t4, t3
+    addi t0, t0, -1
+    sb t3, 0(t0)
+    bnez t2, 1b
+    bgez a0, 2f
+    li t3, 45
+    addi t0, t0, -1
+    sb t3, 0(t0)
+2:
+    addi t1, sp, 32
+    li a7, {SBI_PUTCHAR}
+3:
+    lbu a0, 0(t0)
+    ecall
+    addi t0, t0, 1
+    bltu t0, t1, 3b
+    addi sp, sp, 32
+    ret
+    .size {PRINT_FN}, .-{PRINT_FN}
+
+    .globl {READ_FN}
+    .type {READ_FN}, @function
+{READ_FN}:
+    li t0, 32
+    # Skip the whitespace before the number.
+1:
+    jal t6, .Lgetchar
+    bleu a0, t0, 1b
+    # t1 is the negated value, t2 says whether it's negative, and t3 counts
+    # the digits.
+    li t1, 0
+    li t2, 0
+    li t3, 0
+    li t4, 45
+    bne a0, t4, 3f
+    li t2, 1
+2:
+    jal t6, .Lgetchar
+3:
+    bleu a0, t0, 4f
+    addi a0, a0, -48
+    li t4, 9
+    bgtu a0, t4, .Lnot_a_number
+    li t4, 10
+    mul t1, t1, t4
+    sub t1, t1, a0
+    addi t3, t3, 1
+    j 2b
+4:
+    beqz t3, .Lnot_a_number
+    bnez t2, 5f
+    neg t1, t1
+5:
+    mv a0, t1
+    ret
+.Lgetchar:
+    li a7, {SBI_GETCHAR}
+    ecall
+    bltz a0, .Lgetchar
+    jr t6
+.Lnot_a_number:
+    lla t0, .Lnot_a_number_message
+    j .Lfail
+    .size {READ_FN}, .-{READ_FN}
+
+    .globl {ALLOC_FN}
+    .type {ALLOC_FN}, @function
+{ALLOC_FN}:
+    # Negative sizes are too large as unsigned numbers.
+    {load} t0, .Lheap
+    lla t1, __heap_end
+    sub t1, t1, t0
+    srli t1, t1, {shift}
+    bgtu a0, t1, .Lout_of_memory
+    slli t1, a0, {shift}
+    add t1, t0, t1
+    addi t1, t1, 15
+    andi t1, t1, -16
+    {store} t1, .Lheap, t2
+    mv a0, t0
+1:
+    bgeu t0, t1, 2f
+    {store} zero, 0(t0)
+    addi t0, t0, {word}
+    j 1b
+2:
+    ret
+.Lout_of_memory:
+    lla t0, .Lout_of_memory_message
+    j .Lfail
+    .size {ALLOC_FN}, .-{ALLOC_FN}
+
+    .globl {STACK_OVERFLOW_FN}
+    .type {STACK_OVERFLOW_FN}, @function
+{STACK_OVERFLOW_FN}:
+    lla t0, .Lstack_overflow_message
+    j .Lfail
+    .size {STACK_OVERFLOW_FN}, .-{STACK_OVERFLOW_FN}
+
+    .globl {DIV_BY_ZERO_FN}
+    .type {DIV_BY_ZERO_FN}, @function
+{DIV_BY_ZERO_FN}:
+    lla t0, .Ldiv_by_zero_message
+    j .Lfail
+    .size {DIV_BY_ZERO_FN}, .-{DIV_BY_ZERO_FN}
+
+    .globl {OVERFLOW_FN}
+    .type {OVERFLOW_FN}, @function
+{OVERFLOW_FN}:
+    lla t0, .Loverflow_message
+    j .Lfail
+    .size {OVERFLOW_FN}, .-{OVERFLOW_FN}
+
+    .globl {PROFILE_DUMP_FN}
+    .type {PROFILE_DUMP_FN}, @function
+{PROFILE_DUMP_FN}:
+    ret
+    .size {PROFILE_DUMP_FN}, .-{PROFILE_DUMP_FN}
+
+    .globl {GC_INIT_FN}
+    .type {GC_INIT_FN}, @function
+{GC_INIT_FN}:
+    ret
+    .size {GC_INIT_FN}, .-{GC_INIT_FN}
+
+.Lfail:
+    li a7, {SBI_PUTCHAR}
+1:
+    lbu a0, 0(t0)
+    beqz a0, 2f
+    ecall
+    addi t0, t0, 1
+    j 1b
+2:
+    li a0, 1
+    j {STANDALONE_EXIT_FN}
+
+    .section .rodata
+.Lnot_a_number_message:
+    .asciz \"Runtime error: The input is not a number.\\n\"
+.Lout_of_memory_message:
+    .asciz \"Runtime error: Out of memory.\\n\"
+.Lstack_overflow_message:
+    .asciz \"Runtime error: Stack overflow.\\n\"
+.Ldiv_by_zero_message:
+    .asciz \"Runtime error: Division by zero.\\n\"
+.Loverflow_message:
+    .asciz \"Runtime error: Arithmetic overflow.\\n\"
+
+    .bss
+    .balign 8
+.Lheap:
+    .zero 8
+
+    .text"
+    )
+}
@@ -1,8 +1,845 @@
 //! The code generator
+//!
+//! Code generation selects instructions for a tiny IR program, using a fresh
+//! virtual register for each variable and temporary, then runs the register
+//! allocator, legalizes the immediates, and optionally schedules the
+//! instructions.  Debug builds verify the result (see
+//! [crate::back::verify]).  Variables are numbered like their virtual
+//! registers, so `%3` lives in `v3`.
+//!
+//! Blocks are emitted in the order of their IDs, which the layout pass
+//! chooses.  A jump to the next block is left out, and a branch jumps to the
+//! target that doesn't come next, so the other one falls through.  The
+//! backend can reorder the blocks once more (see [crate::back::layout]).
+//!
+//! Each function gets its own frame.  Variables start out as zero, so the
+//! ones that are live at the start of a function are cleared there.
+//! Constants that don't fit in 32 bits are built with short instruction
+//! sequences (see [crate::back::materialize]).  Calls pass their arguments
+//! as the calling convention says (see [crate::back::asm]), and reads,
+//! prints, and allocations call the runtime the same way.  Profile counters
+//! are global variables, and when the program exits, it passes the address of
+//! the first one and their number to the runtime hook that dumps them.  `main`
+//! exits by returning, and other functions by calling the C library's `exit`.
+//!
+//! With a stack limit, `main` stores the lowest address the stack may grow
+//! to in another global, and each other function compares the stack pointer
+//! with it right after the prologue.  If the frame goes past it, the function
+//! jumps to a block at its end that calls the runtime's error routine.  The
+//! frame is already reserved by then, so the limit should leave some room.
+//!
+//! For a precise garbage collector, `main` calls the runtime's initializer
+//! first, and each call is followed by a stack map with the variables of
+//! type `ptr` that are live after it, which the register allocator keeps in
+//! stack slots.
+//!
+//! The tiny IR marks where the code of each statement starts with the
+//! statement's span in the source code.  With the source, the code can start
+//! with a comment that shows the statement's line, and with line-number
+//! information for debuggers (see [crate::back::asm]).
+//!
+//! Without the M extension, multiplications and divisions call routines that
+//! the assembly output includes (see [MUL_FN] and [DIV_FN]).  smol has no
+//! remainder, so those are the only instructions the extension would have.
+//!
+//! With Zba, a shift left by 1 to 3 bits whose only use is the addition right
+//! after it, like in the address of an array element, becomes a `sh1add` to
+//! `sh3add`.
+//!
+//! On RV32, values are 32 bits, so arithmetic wraps around at 32 bits.  The
+//! constants of a program have to fit (see [oversized_constant]), and the
+//! larger ones that folding makes from arithmetic that overflows wrap around
+//! like the arithmetic would.  Memory words are 4 bytes, so the offsets of
+//! loads and stores, which the tiny IR counts in 8-byte words, are halved.
 
-use crate::back::asm;
-use crate::middle::tir;
+use crate::back::asm::*;
+use crate::back::layout::layout;
+use crate::back::legalize::legalize;
+use crate::back::materialize::materialize;
+use crate::back::regalloc::{self, Allocator};
+use crate::back::schedule::schedule;
+use crate::back::verify::verify;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::{ssa, tir, Liveness, Profile};
 
-pub fn code_gen(_program: tir::Program) -> asm::Program {
-    todo!()
+use Register::*;
+use VReg::*;
+
+/// Settings for the code generator.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The register allocator.
+    pub allocator: Allocator,
+    /// Reorder the blocks so that more of the control flow falls through.
+    pub layout: bool,
+    /// The profile that guides block layout, if there is one.
+    pub profile: Option<Profile>,
+    /// Reorder the instructions of each block to hide latencies.
+    pub schedule: bool,
+    /// The bytes of stack the program may use, if each function should check
+    /// that it stays within them.
+    pub stack_limit: Option<u32>,
+    /// The architecture to generate code for.
+    pub target: Target,
+    /// Multiply and divide by calling routines instead of using the M
+    /// extension, which some cores don't have.
+    pub soft_mul_div: bool,
+    /// The optional extensions that the code may use.
+    pub extensions: Extensions,
+    /// Report divisions by zero and end the program, instead of giving -1.
+    pub trap_div_zero: bool,
+    /// Report additions, subtractions, and multiplications whose results
+    /// don't fit in a word and end the program, instead of wrapping around.
+    /// The check of multiplications needs the M extension, so this doesn't
+    /// check those of `soft_mul_div`.
+    pub trap_overflow: bool,
+    /// Initialize the garbage collector, and emit stack maps for it (see
+    /// [crate::back::asm]).
+    pub gc: bool,
+    /// The source code of the program, which the spans in the tiny IR refer
+    /// to.  Comments and line-number information need it.
+    pub source: Option<String>,
+    /// Start the code of each statement with a comment that shows its line.
+    pub asm_comments: bool,
+    /// The name of the source file, if the output should have line-number
+    /// information for debuggers.
+    pub debug_file: Option<String>,
+}
+
+/// Generate code for a program with the default settings.
+pub fn code_gen(program: tir::Program) -> Program {
+    code_gen_with(program, &Options::default())
+}
+
+/// Generate code for a program.
+pub fn code_gen_with(program: tir::Program, options: &Options) -> Program {
+    log::debug!(
+        "Generating code for {:?} with the {:?} allocator, {} block layout, {} scheduling.",
+        options.target,
+        options.allocator,
+        if options.layout { "with" } else { "without" },
+        if options.schedule { "with" } else { "without" }
+    );
+    let mut code = select_with(program, options);
+    if options.layout {
+        code = layout(code, options.profile.as_ref());
+    }
+    let code = legalize(regalloc::allocate(code, options.allocator));
+    let code = if options.schedule {
+        schedule(code)
+    } else {
+        code
+    };
+    if cfg!(debug_assertions) {
+        if let Err(e) = verify(&code) {
+            panic!("{e}");
+        }
+    }
+    code
+}
+
+/// Select the instructions for a program with the default settings, without
+/// allocating registers.
+pub fn select(program: tir::Program) -> Program<VReg> {
+    select_with(program, &Options::default())
+}
+
+/// The first constant of the program that doesn't fit in a value of the
+/// target, if there is one.  smol's values are 64 bits, and RV32's are 32
+/// bits, so a program for RV32 whose constants don't fit would compute
+/// something else.
+pub fn oversized_constant(program: &tir::Program, target: Target) -> Option<i64> {
+    let fits = |c: i64| match target {
+        Target::Riscv64 => true,
+        Target::Riscv32 => i32::try_from(c).is_ok(),
+    };
+    program
+        .func
+        .values()
+        .flat_map(|f| f.block.values())
+        .flat_map(|b| &b.insn)
+        .find_map(|i| match i {
+            tir::Instruction::Const { src, .. } if !fits(*src) => Some(*src),
+            _ => None,
+        })
+}
+
+/// Select the instructions for a program, without allocating registers.
+pub fn select_with(program: tir::Program, options: &Options) -> Program<VReg> {
+    let counters = program.num_counters();
+    // `main` comes first, and the others in the order of their names.
+    let mut names = program.func.keys().copied().collect::<Vec<_>>();
+    names.sort_by_key(|f| *f != tir::Program::main());
+    let mut globals = (0..counters)
+        .map(|k| GlobalVar::zeroed(Id::new(format!("$count{k}")), 1))
+        .collect::<Vec<_>>();
+    // The lowest address the stack may grow to, which `main` computes.
+    let stack_limit = options.stack_limit.map(|bytes| {
+        globals.push(GlobalVar::zeroed(Id::from_ref("$stack_limit"), 1));
+        (globals.len() - 1, bytes)
+    });
+    Program {
+        functions: names
+            .iter()
+            .map(|f| {
+                let func = &program.func[f];
+                select_function(*f, func, counters, stack_limit, options)
+            })
+            .collect(),
+        globals,
+        target: options.target,
+    }
+}
+
+/// Select the instructions for the function `name`, in a program with the
+/// given number of profile counters.  If the stack is limited, the global
+/// with the given index holds the limit, and `main` sets it to the given
+/// number of bytes below its frame.
+fn select_function(
+    name: Id,
+    func: &tir::Function,
+    counters: u32,
+    stack_limit: Option<(usize, u32)>,
+    options: &Options,
+) -> Function<VReg> {
+    let func = if func
+        .block
+        .values()
+        .flat_map(|b| &b.insn)
+        .any(|i| i.is_phi())
+    {
+        ssa::destruct_function(func.clone())
+    } else {
+        func.clone()
+    };
+
+    let mut gen = Gen {
+        func: &func,
+        is_main: name == tir::Program::main(),
+        next_vreg: func.names.num_values() as u32,
+        counters,
+        target: options.target,
+        soft_mul_div: options.soft_mul_div,
+        trap_div_zero: options.trap_div_zero,
+        checks_div: false,
+        trap_overflow: options.trap_overflow,
+        checks_overflow: false,
+        gc: options.gc,
+        source: options.source.as_deref(),
+        asm_comments: options.asm_comments,
+        debug_file: options.debug_file.as_deref().map(Id::from_ref),
+        code: vec![],
+    };
+    if options.gc && gen.is_main {
+        gen.emit(Instruction::call(Id::from_ref(GC_INIT_FN)));
+    }
+    // `main` sets the stack limit below its own frame, and the others check
+    // that their frames are above it.
+    let checks_stack = stack_limit.is_some() && !gen.is_main;
+    if let Some((index, bytes)) = stack_limit {
+        if gen.is_main {
+            gen.set_stack_limit(index, bytes);
+        } else {
+            gen.check_stack(index);
+        }
+    }
+    // The caller passes the first arguments in registers, and the rest on
+    // the stack above the saved frame pointer and return address.
+    for (i, param) in func.params.iter().enumerate() {
+        let dst = Virt(param.0);
+        match ARG_REGISTERS.get(i) {
+            Some(r) => gen.emit(Instruction::mov(dst, Phys(*r))),
+            None => gen.emit(Instruction::Ld {
+                dst,
+                src: Memory::Mem(
+                    Phys(Fp),
+                    2 * SLOT_SIZE + options.target.word_size() * (i - ARG_REGISTERS.len()) as i32,
+                ),
+            }),
+        }
+    }
+    // Variables start out as zero, so the ones that may be read before they
+    // are written have to be cleared first.
+    for var in &Liveness::new(&func).live_in[&tir::Function::entry()] {
+        if !func.params.contains(var) {
+            gen.emit(Instruction::Li {
+                dst: Virt(var.0),
+                imm: 0,
+            });
+        }
+    }
+    let order = func.block.keys().copied().collect::<Vec<_>>();
+    let mut basic_blocks = order
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            gen.block(&func.block[b], order.get(i + 1).copied());
+            BasicBlock {
+                id: func.names.block(*b),
+                instructions: std::mem::take(&mut gen.code),
+            }
+        })
+        .collect::<Vec<_>>();
+    // The blocks that report errors come last, after the epilogue.
+    let mut errors = vec![];
+    if checks_stack {
+        errors.push((STACK_OVERFLOW, STACK_OVERFLOW_FN));
+    }
+    if gen.checks_div {
+        errors.push((DIV_BY_ZERO, DIV_BY_ZERO_FN));
+    }
+    if gen.checks_overflow {
+        errors.push((OVERFLOW, OVERFLOW_FN));
+    }
+    if !errors.is_empty() {
+        // The last block no longer falls through to the epilogue.
+        let last = &mut basic_blocks.last_mut().unwrap().instructions;
+        if !matches!(
+            last.last(),
+            Some(Instruction::Jal {
+                dst: Phys(Zero),
+                ..
+            })
+        ) {
+            last.push(Instruction::jump(JumpTarget::Local(Id::from_ref(EPILOGUE))));
+        }
+        basic_blocks.extend(errors.into_iter().map(|(label, handler)| BasicBlock {
+            id: Id::from_ref(label),
+            instructions: vec![Instruction::call(Id::from_ref(handler))],
+        }));
+    }
+    let mut code = Function {
+        id: name,
+        basic_blocks,
+        stack_space: 0,
+        used_registers: vec![],
+        variables: (0..func.names.num_values() as u32)
+            .map(|v| (func.names.value(tir::ValueId(v)), VarLocation::Reg(Virt(v))))
+            .collect(),
+    };
+    if options.gc {
+        find_gc_roots(&func, &mut code);
+    }
+    if options.extensions.zba {
+        fuse_shift_add(&mut code);
+    }
+    code
+}
+
+/// Turn each shift left by 1 to 3 bits that is only used by the addition
+/// right after it into a `sh1add` to `sh3add`, which is how arrays are
+/// indexed.
+fn fuse_shift_add(code: &mut Function<VReg>) {
+    let liveness = crate::back::Liveness::new(code);
+    for block in &mut code.basic_blocks {
+        let live_after = liveness.live_after_each(block);
+        let mut fused = Vec::with_capacity(block.instructions.len());
+        let mut i = 0;
+        while i < block.instructions.len() {
+            if let (
+                Instruction::ArithI {
+                    op: ArithOp::Sll,
+                    dst: shifted,
+                    lhs: src,
+                    rhs: amount @ 1..=3,
+                },
+                Some(Instruction::Arith {
+                    op: ArithOp::Add,
+                    dst,
+                    lhs,
+                    rhs,
+                }),
+            ) = (&block.instructions[i], block.instructions.get(i + 1))
+            {
+                let other = match (lhs == shifted, rhs == shifted) {
+                    (true, false) => Some(rhs),
+                    (false, true) => Some(lhs),
+                    _ => None,
+                };
+                if let Some(other) = other {
+                    if dst == shifted || !live_after[i + 1].contains(shifted) {
+                        log::trace!(
+                            "Fusing `{}` and `{}` in `{}`.",
+                            block.instructions[i],
+                            block.instructions[i + 1],
+                            block.id
+                        );
+                        fused.push(Instruction::Arith {
+                            op: [ArithOp::Sh1Add, ArithOp::Sh2Add, ArithOp::Sh3Add]
+                                [*amount as usize - 1],
+                            dst: *dst,
+                            lhs: *src,
+                            rhs: *other,
+                        });
+                        i += 2;
+                        continue;
+                    }
+                }
+            }
+            fused.push(block.instructions[i].clone());
+            i += 1;
+        }
+        block.instructions = fused;
+    }
+}
+
+/// Fill in the stack maps with the variables of type `ptr` that are live
+/// after each call.
+fn find_gc_roots(func: &tir::Function, code: &mut Function<VReg>) {
+    let is_pointer = |r: &VReg| match r {
+        Virt(v) => func.type_of(tir::ValueId(*v)) == Some(tir::Type::Ptr),
+        Phys(_) => false,
+    };
+    let liveness = crate::back::Liveness::new(code);
+    for block in &mut code.basic_blocks {
+        let live_after = liveness.live_after_each(block);
+        for (insn, live) in block.instructions.iter_mut().zip(live_after) {
+            if let Instruction::StackMap { live: roots, .. } = insn {
+                *roots = live.into_iter().filter(is_pointer).collect();
+            }
+        }
+    }
+}
+
+/// The state of instruction selection for a function.
+struct Gen<'a> {
+    func: &'a tir::Function,
+    /// Is this `main`, which ends the program by returning?
+    is_main: bool,
+    /// The next virtual register that no variable uses.
+    next_vreg: u32,
+    /// The number of profile counters, which are the first globals.
+    counters: u32,
+    /// The architecture.
+    target: Target,
+    /// Do multiplications and divisions call routines?
+    soft_mul_div: bool,
+    /// Do divisions by zero jump to the block that reports them?
+    trap_div_zero: bool,
+    /// Has the function checked for a division by zero?
+    checks_div: bool,
+    /// Do overflows jump to the block that reports them?
+    trap_overflow: bool,
+    /// Has the function checked for an overflow?
+    checks_overflow: bool,
+    /// Does each call get a stack map?
+    gc: bool,
+    /// The source code, if there is one.
+    source: Option<&'a str>,
+    /// Do statements get comments?
+    asm_comments: bool,
+    /// The source file, if statements get line numbers.
+    debug_file: Option<Id>,
+    /// The instructions of the current block.
+    code: Vec<Instruction<VReg>>,
+}
+
+impl Gen<'_> {
+    fn fresh(&mut self) -> VReg {
+        self.next_vreg += 1;
+        Virt(self.next_vreg - 1)
+    }
+
+    fn emit(&mut self, insn: Instruction<VReg>) {
+        self.code.push(insn);
+    }
+
+    fn label(&self, b: tir::BlockId) -> JumpTarget {
+        JumpTarget::Local(self.func.names.block(b))
+    }
+
+    /// Call a function, and return the register holding its result.  The
+    /// arguments after the eighth go on the stack, in space that is reserved
+    /// for the call.
+    fn call(&mut self, callee: Id, args: &[VReg]) -> VReg {
+        let on_stack = &args[args.len().min(ARG_REGISTERS.len())..];
+        let word = self.target.word_size();
+        let space = (word * on_stack.len() as i32 + 15) / 16 * 16;
+        if space > 0 {
+            self.emit(Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: Phys(Sp),
+                lhs: Phys(Sp),
+                rhs: -space,
+            });
+        }
+        for (i, arg) in on_stack.iter().enumerate() {
+            self.emit(Instruction::Sd {
+                dst: Memory::Mem(Phys(Sp), word * i as i32),
+                src: *arg,
+            });
+        }
+        for (r, arg) in ARG_REGISTERS.iter().zip(args) {
+            self.emit(Instruction::mov(Phys(*r), *arg));
+        }
+        self.emit(Instruction::call(callee));
+        if self.gc {
+            self.emit(Instruction::StackMap {
+                live: vec![],
+                slots: vec![],
+            });
+        }
+        if space > 0 {
+            self.emit(Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: Phys(Sp),
+                lhs: Phys(Sp),
+                rhs: space,
+            });
+        }
+        Phys(A0)
+    }
+
+    /// Call a runtime function, and return the register holding its result.
+    fn call_runtime(&mut self, name: &str, args: &[VReg]) -> VReg {
+        self.call(Id::from_ref(name), args)
+    }
+
+    /// Set the stack limit in the global with the given index to `bytes`
+    /// below the stack pointer.
+    fn set_stack_limit(&mut self, index: usize, bytes: u32) {
+        let t = self.fresh();
+        self.emit(Instruction::Li {
+            dst: t,
+            imm: bytes.into(),
+        });
+        self.emit(Instruction::Arith {
+            op: ArithOp::Sub,
+            dst: t,
+            lhs: Phys(Sp),
+            rhs: t,
+        });
+        self.emit(Instruction::Sd {
+            dst: Memory::Global { index, offset: 0 },
+            src: t,
+        });
+    }
+
+    /// Make sure that the frame is within the stack limit in the global with
+    /// the given index, or else jump to the block that reports the overflow.
+    fn check_stack(&mut self, index: usize) {
+        let t = self.fresh();
+        self.emit(Instruction::Ld {
+            dst: t,
+            src: Memory::Global { index, offset: 0 },
+        });
+        self.emit(Instruction::Branch {
+            cond: Condition::Less,
+            lhs: Phys(Sp),
+            rhs: t,
+            target: JumpTarget::Local(Id::from_ref(STACK_OVERFLOW)),
+        });
+    }
+
+    /// Compute `lhs op rhs` into `dst`, or jump to the block that reports the
+    /// overflow if the result doesn't fit in a word.
+    fn checked_arith(&mut self, op: BOp, dst: VReg, lhs: VReg, rhs: VReg) {
+        let result = self.fresh();
+        let (a, b) = (self.fresh(), self.fresh());
+        match op {
+            // Adding a negative number gives less than `lhs`, and adding
+            // anything else doesn't, unless the sum wraps around.
+            // Subtraction is the other way around.
+            BOp::Add | BOp::Sub => {
+                let (op, cond) = if op == BOp::Add {
+                    (ArithOp::Add, Condition::Less)
+                } else {
+                    (ArithOp::Sub, Condition::Greater)
+                };
+                self.emit(Instruction::Arith {
+                    op,
+                    dst: result,
+                    lhs,
+                    rhs,
+                });
+                self.emit(Instruction::Arith {
+                    op: ArithOp::Slt,
+                    dst: a,
+                    lhs: result,
+                    rhs: lhs,
+                });
+                self.emit(Instruction::SCmpZ {
+                    dst: b,
+                    lhs: rhs,
+                    cond,
+                });
+            }
+            // The product fits if its upper half is all copies of the sign
+            // bit of its lower half.
+            BOp::Mul => {
+                self.emit(Instruction::Arith {
+                    op: ArithOp::Mul,
+                    dst: result,
+                    lhs,
+                    rhs,
+                });
+                self.emit(Instruction::Arith {
+                    op: ArithOp::MulH,
+                    dst: a,
+                    lhs,
+                    rhs,
+                });
+                self.emit(Instruction::ArithI {
+                    op: ArithOp::Sra,
+                    dst: b,
+                    lhs: result,
+                    rhs: self.target.word_size() * 8 - 1,
+                });
+            }
+            _ => unreachable!("only addition, subtraction, and multiplication overflow"),
+        }
+        self.emit(Instruction::Branch {
+            cond: Condition::NotEqual,
+            lhs: a,
+            rhs: b,
+            target: JumpTarget::Local(Id::from_ref(OVERFLOW)),
+        });
+        self.checks_overflow = true;
+        self.emit(Instruction::mov(dst, result));
+    }
+
+    /// Generate the code of a block, which is followed by `next`.
+    fn block(&mut self, block: &tir::Block, next: Option<tir::BlockId>) {
+        for insn in &block.insn {
+            self.instruction(insn);
+        }
+        for t in &block.term {
+            self.terminator(t, next);
+        }
+    }
+
+    fn instruction(&mut self, insn: &tir::Instruction) {
+        use tir::Instruction::*;
+
+        let v = |x: &tir::ValueId| Virt(x.0);
+        if let Arith {
+            op: BOp::Div, rhs, ..
+        } = insn
+        {
+            if self.trap_div_zero {
+                self.checks_div = true;
+                self.emit(Instruction::Branch {
+                    cond: Condition::Equal,
+                    lhs: v(rhs),
+                    rhs: Phys(Zero),
+                    target: JumpTarget::Local(Id::from_ref(DIV_BY_ZERO)),
+                });
+            }
+        }
+        match insn {
+            Copy { dst, src } => self.emit(Instruction::mov(v(dst), v(src))),
+            // The assembler expands `li` well for 32-bit constants.  Values
+            // are 32 bits on RV32, so larger constants, which only folding
+            // makes, wrap around.
+            Const { dst, src } if i32::try_from(*src).is_ok() || self.target == Target::Riscv32 => {
+                self.emit(Instruction::Li {
+                    dst: v(dst),
+                    imm: (*src as i32).into(),
+                })
+            }
+            Const { dst, src } => self.code.extend(materialize(v(dst), *src)),
+            Arith {
+                op: op @ (BOp::Add | BOp::Sub | BOp::Mul),
+                dst,
+                lhs,
+                rhs,
+            } if self.trap_overflow && !(*op == BOp::Mul && self.soft_mul_div) => {
+                self.checked_arith(*op, v(dst), v(lhs), v(rhs))
+            }
+            Arith {
+                op: op @ (BOp::Mul | BOp::Div),
+                dst,
+                lhs,
+                rhs,
+            } if self.soft_mul_div => {
+                let routine = if *op == BOp::Mul { MUL_FN } else { DIV_FN };
+                let result = self.call_runtime(routine, &[v(lhs), v(rhs)]);
+                self.emit(Instruction::mov(v(dst), result));
+            }
+            Arith { op, dst, lhs, rhs } => {
+                // RISC-V division by zero gives -1, like smol's, unless it
+                // traps.
+                let op = match op {
+                    BOp::Add => ArithOp::Add,
+                    BOp::Sub => ArithOp::Sub,
+                    BOp::Mul => ArithOp::Mul,
+                    BOp::Div => ArithOp::Div,
+                    BOp::Lt => ArithOp::Slt,
+                };
+                self.emit(Instruction::Arith {
+                    op,
+                    dst: v(dst),
+                    lhs: v(lhs),
+                    rhs: v(rhs),
+                });
+            }
+            Shift {
+                op,
+                dst,
+                src,
+                amount,
+            } => {
+                let op = match op {
+                    tir::ShiftOp::Left => ArithOp::Sll,
+                    tir::ShiftOp::Arith => ArithOp::Sra,
+                    tir::ShiftOp::Logical => ArithOp::Srl,
+                };
+                // Shifting a 32-bit value by 32 bits or more leaves only
+                // copies of its sign bit, or nothing.
+                let bits = self.target.word_size() as u32 * 8;
+                if *amount >= bits && op != ArithOp::Sra {
+                    self.emit(Instruction::Li {
+                        dst: v(dst),
+                        imm: 0,
+                    });
+                } else {
+                    self.emit(Instruction::ArithI {
+                        op,
+                        dst: v(dst),
+                        lhs: v(src),
+                        rhs: (*amount).min(bits - 1) as i32,
+                    });
+                }
+            }
+            Read(dst) => {
+                let result = self.call_runtime(READ_FN, &[]);
+                self.emit(Instruction::mov(v(dst), result));
+            }
+            Print(src) => {
+                self.call_runtime(PRINT_FN, &[v(src)]);
+            }
+            Phi { .. } => unreachable!("phis are removed before code generation"),
+            Call { dst, callee, args } => {
+                let args = args.iter().map(v).collect::<Vec<_>>();
+                let result = self.call(*callee, &args);
+                self.emit(Instruction::mov(v(dst), result));
+            }
+            Alloc { dst, size } => {
+                let result = self.call_runtime(ALLOC_FN, &[v(size)]);
+                self.emit(Instruction::mov(v(dst), result));
+            }
+            Load { dst, addr, offset } => self.emit(Instruction::Ld {
+                dst: v(dst),
+                src: Memory::Mem(v(addr), self.offset(*offset)),
+            }),
+            Store { addr, offset, src } => self.emit(Instruction::Sd {
+                dst: Memory::Mem(v(addr), self.offset(*offset)),
+                src: v(src),
+            }),
+            Count(k) => {
+                let counter = Memory::Global {
+                    index: *k as usize,
+                    offset: 0,
+                };
+                let t = self.fresh();
+                self.emit(Instruction::Ld {
+                    dst: t,
+                    src: counter,
+                });
+                self.emit(Instruction::ArithI {
+                    op: ArithOp::Add,
+                    dst: t,
+                    lhs: t,
+                    rhs: 1,
+                });
+                self.emit(Instruction::Sd {
+                    dst: counter,
+                    src: t,
+                });
+            }
+            Loc(span) => {
+                let Some(source) = self.source else {
+                    return;
+                };
+                let (number, line) = span.line(source);
+                if self.asm_comments {
+                    self.emit(Instruction::Comment(format!("{number}: {}", line.trim())));
+                }
+                if let Some(file) = self.debug_file {
+                    self.emit(Instruction::Loc {
+                        file,
+                        line: number as u32,
+                        column: span.column(source) as u32,
+                    });
+                }
+            }
+        }
+    }
+
+    fn terminator(&mut self, t: &tir::Terminator, next: Option<tir::BlockId>) {
+        use tir::Terminator::*;
+
+        match t {
+            Jump(target) => {
+                if Some(*target) != next {
+                    self.emit(Instruction::jump(self.label(*target)));
+                }
+            }
+            Branch { guard, tt, ff } => {
+                let guard = Virt(guard.0);
+                let (cond, target, other) = if Some(*tt) == next {
+                    (Condition::Equal, *ff, *tt)
+                } else {
+                    (Condition::NotEqual, *tt, *ff)
+                };
+                self.emit(Instruction::Branch {
+                    cond,
+                    lhs: guard,
+                    rhs: Phys(Zero),
+                    target: self.label(target),
+                });
+                if Some(other) != next {
+                    self.emit(Instruction::jump(self.label(other)));
+                }
+            }
+            Exit(status) => {
+                if self.counters > 0 {
+                    self.emit(Instruction::La {
+                        dst: Phys(A0),
+                        src: Memory::Global {
+                            index: 0,
+                            offset: 0,
+                        },
+                    });
+                    self.emit(Instruction::Li {
+                        dst: Phys(A1),
+                        imm: self.counters.into(),
+                    });
+                    self.emit(Instruction::call(Id::from_ref(PROFILE_DUMP_FN)));
+                }
+                match status {
+                    Some(status) => self.emit(Instruction::mov(Phys(A0), Virt(status.0))),
+                    None => self.emit(Instruction::Li {
+                        dst: Phys(A0),
+                        imm: 0,
+                    }),
+                }
+                if self.is_main {
+                    self.return_(next);
+                } else {
+                    self.emit(Instruction::call(Id::from_ref(EXIT_FN)));
+                }
+            }
+            Return(value) => {
+                self.emit(Instruction::mov(Phys(A0), Virt(value.0)));
+                self.return_(next);
+            }
+        }
+    }
+
+    /// The offset of a load or a store as an immediate.  The tiny IR counts
+    /// it in bytes of 8-byte words, so on targets with smaller words, it
+    /// shrinks with them.
+    fn offset(&self, offset: i64) -> i32 {
+        let offset = offset * i64::from(self.target.word_size()) / 8;
+        i32::try_from(offset).expect("memory offsets should fit in 32 bits")
+    }
+
+    /// Jump to the epilogue, which comes after the last block.
+    fn return_(&mut self, next: Option<tir::BlockId>) {
+        if next.is_some() {
+            self.emit(Instruction::jump(JumpTarget::Local(Id::from_ref(EPILOGUE))));
+        }
+    }
 }
@@ -0,0 +1,362 @@
+//! A disassembler for the machine code of [crate::back::encode].
+//!
+//! This decodes the words that the encoder produces back into
+//! [Instruction]s, which lets tests check that encoding and decoding agree,
+//! and shows what a binary contains when the linked program misbehaves.  It
+//! only knows the instructions that the encoder emits, so anything else is an
+//! error.
+//!
+//! Decoding can't undo everything the encoder does:
+//! - Pseudo-instructions come back as the instructions they expand to: `li`
+//!   is `lui` and `addi(w)`, `ble` and `bgt` are `bge` and `blt` with the
+//!   operands swapped, and subtracting an immediate is adding its negation.
+//! - `auipc` has no [Instruction], since the backend only emits it as the
+//!   first half of a call or an access to a global, so it decodes to
+//!   [Decoded::Auipc].  Nor does the `jalr` with an offset that completes a
+//!   call, which decodes to [Decoded::Jalr].
+//! - Jumps and branches target addresses rather than blocks, so their targets
+//!   are local labels that name the address in hex, like `0x1c`.
+
+use derive_more::Display;
+
+use crate::back::asm::*;
+use crate::back::encode::Binary;
+use crate::common::*;
+
+/// Machine code that doesn't decode to any instruction the backend emits.
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+#[display("Cannot decode {_0}")]
+pub struct DecodeError(String);
+
+/// A decoded machine instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    Instruction(Instruction),
+    /// Add the 20-bit immediate, shifted left by 12 bits, to the address of
+    /// the instruction.
+    Auipc {
+        dst: Register,
+        imm: i32,
+    },
+    /// Jump to the address in `target` plus `offset`, and store the address
+    /// of the next instruction in `dst`.
+    Jalr {
+        dst: Register,
+        target: Register,
+        offset: i32,
+    },
+}
+
+impl std::fmt::Display for Decoded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+
+        let label = |target: &JumpTarget| match target {
+            JumpTarget::Local(l) | JumpTarget::Global(l) => *l,
+        };
+        match self {
+            Decoded::Auipc { dst, imm } => write!(f, "auipc {dst}, {imm:#x}"),
+            Decoded::Jalr {
+                dst,
+                target,
+                offset,
+            } => write!(f, "jalr {dst}, {offset}({target})"),
+            Decoded::Instruction(Jal { dst, target }) => write!(f, "jal {dst}, {}", label(target)),
+            Decoded::Instruction(Branch {
+                cond,
+                lhs,
+                rhs,
+                target,
+            }) => write!(f, "b{cond} {lhs}, {rhs}, {}", label(target)),
+            Decoded::Instruction(insn) => write!(f, "{insn}"),
+        }
+    }
+}
+
+/// The integer register with the given number.
+fn x(n: u32) -> Register {
+    Register::ALL[n as usize]
+}
+
+/// The floating-point register with the given number.
+fn f(n: u32) -> FRegister {
+    FRegister::ALL[n as usize]
+}
+
+/// The target of a jump or a branch by `offset` bytes from `pc`.
+fn jump_target(pc: u32, offset: i32) -> JumpTarget {
+    JumpTarget::Local(Id::from(format!("{:#x}", pc.wrapping_add_signed(offset))))
+}
+
+/// Decode the instruction `word` at address `pc`.
+pub fn decode(word: u32, pc: u32, target: Target) -> Result<Decoded, DecodeError> {
+    use Instruction::*;
+
+    let error = || Err(DecodeError(format!("{word:#010x} at {pc:#x}")));
+    let opcode = word & 0x7f;
+    let rd = word >> 7 & 0x1f;
+    let funct3 = word >> 12 & 0x7;
+    let rs1 = word >> 15 & 0x1f;
+    let rs2 = word >> 20 & 0x1f;
+    let funct7 = word >> 25;
+    let imm_i = word as i32 >> 20;
+    let imm_s = (word as i32 >> 25) << 5 | rd as i32;
+    let imm_b = (word as i32 >> 31) << 12
+        | ((word >> 7 & 1) << 11 | (word >> 25 & 0x3f) << 5 | (word >> 8 & 0xf) << 1) as i32;
+    let imm_u = (word >> 12) as i32;
+    let imm_j = (word as i32 >> 31) << 20
+        | (word & 0xff000 | (word >> 20 & 1) << 11 | (word >> 21 & 0x3ff) << 1) as i32;
+    let width = match target {
+        Target::Riscv64 => 0b011,
+        Target::Riscv32 => 0b010,
+    };
+    let shamt_bits = match target {
+        Target::Riscv64 => 6,
+        Target::Riscv32 => 5,
+    };
+    let mem = |offset: i32| Memory::Mem(x(rs1), offset);
+
+    let insn = match opcode {
+        0x37 => Lui {
+            dst: x(rd),
+            imm: imm_u,
+        },
+        0x17 => {
+            return Ok(Decoded::Auipc {
+                dst: x(rd),
+                imm: imm_u,
+            })
+        }
+        0x6f => Jal {
+            dst: x(rd),
+            target: jump_target(pc, imm_j),
+        },
+        0x67 if funct3 == 0 && imm_i == 0 => Jalr {
+            dst: x(rd),
+            target: x(rs1),
+        },
+        0x67 if funct3 == 0 => {
+            return Ok(Decoded::Jalr {
+                dst: x(rd),
+                target: x(rs1),
+                offset: imm_i,
+            })
+        }
+        0x63 => {
+            let cond = match funct3 {
+                0b000 => Condition::Equal,
+                0b001 => Condition::NotEqual,
+                0b100 => Condition::Less,
+                0b101 => Condition::GreaterEq,
+                _ => return error(),
+            };
+            Branch {
+                cond,
+                lhs: x(rs1),
+                rhs: x(rs2),
+                target: jump_target(pc, imm_b),
+            }
+        }
+        0x03 if funct3 == width => Ld {
+            dst: x(rd),
+            src: mem(imm_i),
+        },
+        0x23 if funct3 == width => Sd {
+            dst: mem(imm_s),
+            src: x(rs2),
+        },
+        0x07 if funct3 == 0b011 => Fld {
+            dst: f(rd),
+            src: mem(imm_i),
+        },
+        0x27 if funct3 == 0b011 => Fsd {
+            dst: mem(imm_s),
+            src: f(rs2),
+        },
+        // `seqz` is `sltiu` with 1, the only unsigned comparison the backend
+        // emits with an immediate.
+        0x13 if funct3 == 0b011 && imm_i == 1 => SCmpZ {
+            dst: x(rd),
+            lhs: x(rs1),
+            cond: Condition::Equal,
+        },
+        0x13 => {
+            let shamt = imm_i & ((1 << shamt_bits) - 1);
+            let (op, rhs) = match (funct3, imm_i >> shamt_bits) {
+                (0b000, _) => (ArithOp::Add, imm_i),
+                (0b010, _) => (ArithOp::Slt, imm_i),
+                (0b100, _) => (ArithOp::Xor, imm_i),
+                (0b110, _) => (ArithOp::Or, imm_i),
+                (0b111, _) => (ArithOp::And, imm_i),
+                (0b001, 0) => (ArithOp::Sll, shamt),
+                (0b101, 0) => (ArithOp::Srl, shamt),
+                (0b101, upper) if upper << shamt_bits == 0x400 => (ArithOp::Sra, shamt),
+                _ => return error(),
+            };
+            ArithI {
+                op,
+                dst: x(rd),
+                lhs: x(rs1),
+                rhs,
+            }
+        }
+        0x1b if funct3 == 0 && target == Target::Riscv64 => ArithI {
+            op: ArithOp::AddW,
+            dst: x(rd),
+            lhs: x(rs1),
+            rhs: imm_i,
+        },
+        // `snez`, `sltz`, and `sgtz` compare with `zero`.
+        0x33 if funct7 == 0 && funct3 == 0b011 && rs1 == 0 => SCmpZ {
+            dst: x(rd),
+            lhs: x(rs2),
+            cond: Condition::NotEqual,
+        },
+        0x33 if funct7 == 0 && funct3 == 0b010 && rs2 == 0 => SCmpZ {
+            dst: x(rd),
+            lhs: x(rs1),
+            cond: Condition::Less,
+        },
+        0x33 if funct7 == 0 && funct3 == 0b010 && rs1 == 0 => SCmpZ {
+            dst: x(rd),
+            lhs: x(rs2),
+            cond: Condition::Greater,
+        },
+        0x33 => {
+            let op = match (funct7, funct3) {
+                (0, 0b000) => ArithOp::Add,
+                (0x20, 0b000) => ArithOp::Sub,
+                (1, 0b000) => ArithOp::Mul,
+                (1, 0b001) => ArithOp::MulH,
+                (1, 0b100) => ArithOp::Div,
+                (0, 0b010) => ArithOp::Slt,
+                (0, 0b111) => ArithOp::And,
+                (0, 0b110) => ArithOp::Or,
+                (0, 0b100) => ArithOp::Xor,
+                (0, 0b101) => ArithOp::Srl,
+                (0x20, 0b101) => ArithOp::Sra,
+                (0, 0b001) => ArithOp::Sll,
+                (0x10, 0b010) => ArithOp::Sh1Add,
+                (0x10, 0b100) => ArithOp::Sh2Add,
+                (0x10, 0b110) => ArithOp::Sh3Add,
+                (0x20, 0b111) => ArithOp::AndN,
+                (0x05, 0b100) => ArithOp::Min,
+                (0x05, 0b110) => ArithOp::Max,
+                _ => return error(),
+            };
+            Arith {
+                op,
+                dst: x(rd),
+                lhs: x(rs1),
+                rhs: x(rs2),
+            }
+        }
+        0x3b if funct7 == 0 && funct3 == 0 && target == Target::Riscv64 => Arith {
+            op: ArithOp::AddW,
+            dst: x(rd),
+            lhs: x(rs1),
+            rhs: x(rs2),
+        },
+        0x53 => match (funct7, funct3) {
+            (0b0000001 | 0b0000101 | 0b0001001 | 0b0001101, 0b111) => FArith {
+                op: match funct7 {
+                    0b0000001 => FArithOp::Add,
+                    0b0000101 => FArithOp::Sub,
+                    0b0001001 => FArithOp::Mul,
+                    _ => FArithOp::Div,
+                },
+                dst: f(rd),
+                lhs: f(rs1),
+                rhs: f(rs2),
+            },
+            (0b1010001, 0b000..=0b010) => FCmp {
+                op: match funct3 {
+                    0b010 => FCmpOp::Equal,
+                    0b001 => FCmpOp::Less,
+                    _ => FCmpOp::LessEq,
+                },
+                dst: x(rd),
+                lhs: f(rs1),
+                rhs: f(rs2),
+            },
+            (0b0010001, 0b000) if rs1 == rs2 => FMv {
+                dst: f(rd),
+                src: f(rs1),
+            },
+            (0b1101001, _) if (rs2, funct3) == cvt_from_int(target) => FCvtFromInt {
+                dst: f(rd),
+                src: x(rs1),
+            },
+            (0b1100001, 0b001) if rs2 == cvt_from_int(target).0 => FCvtToInt {
+                dst: x(rd),
+                src: f(rs1),
+            },
+            _ => return error(),
+        },
+        _ => return error(),
+    };
+    Ok(Decoded::Instruction(insn))
+}
+
+/// The `rs2` and the rounding mode of `fcvt.d.l` (`fcvt.d.w` on RV32), as
+/// the encoder picks them.
+fn cvt_from_int(target: Target) -> (u32, u32) {
+    match target {
+        Target::Riscv64 => (0b00010, 0b111),
+        Target::Riscv32 => (0, 0b000),
+    }
+}
+
+impl Binary {
+    /// Decode the code of the binary, which comes before its globals, into
+    /// the address and the instruction of each word.
+    pub fn decode(&self, target: Target) -> Result<Vec<(u32, Decoded)>, DecodeError> {
+        let mut words = self.bytes[..self.data as usize]
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        // The padding before the globals is a zero word, which is never an
+        // instruction.
+        if words.last() == Some(&0) {
+            words.pop();
+        }
+        words
+            .into_iter()
+            .enumerate()
+            .map(|(i, word)| {
+                let pc = 4 * i as u32;
+                decode(word, pc, target).map(|insn| (pc, insn))
+            })
+            .collect()
+    }
+
+    /// A listing of the code of the binary, like `objdump -d` prints: each
+    /// word with its address and its instruction, with a label before each
+    /// function and a comment on each call outside the binary.
+    pub fn disassemble(&self, target: Target) -> Result<String, DecodeError> {
+        let functions = self
+            .functions
+            .iter()
+            .map(|(id, offset)| (*offset, *id))
+            .collect::<Map<_, _>>();
+        let relocations = self
+            .relocations
+            .iter()
+            .map(|r| (r.offset, r.symbol))
+            .collect::<Map<_, _>>();
+        let mut out = String::new();
+        for (pc, insn) in self.decode(target)? {
+            if let Some(name) = functions.get(&pc) {
+                out.push_str(&format!("{pc:08x} <{name}>:\n"));
+            }
+            let at = pc as usize;
+            let word = u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap());
+            out.push_str(&format!("{pc:8x}:\t{word:08x}\t{insn}"));
+            if let Some(symbol) = relocations.get(&pc) {
+                out.push_str(&format!(" # {symbol}"));
+            }
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
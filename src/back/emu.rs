@@ -0,0 +1,395 @@
+//! A RISC-V emulator, which runs the code the backend generates without
+//! QEMU or hardware.
+//!
+//! This runs the machine code of a program (see [Program::encode]) on a
+//! simulated RV64IM machine with the Zba and Zbb instructions that the backend
+//! may emit, which is enough for everything it emits for the tiny IR.  The emulator plays the part of the runtime, too: calls to
+//! the runtime's functions jump to addresses below the code, and when the
+//! machine gets there, the emulator reads, prints, or allocates like the
+//! runtime library (see [crate::back::runtime]), and returns to the caller.
+//! It also does the work of the multiplication and division routines of
+//! `--no-m`, and of the C library's `exit`.
+//!
+//! The machine has a flat memory of [MEMORY_SIZE] bytes.  The code and the
+//! globals start at [CODE_BASE], the heap follows them and grows up, and the
+//! stack starts at the top and grows down.  Allocations are zeroed, and
+//! accessing memory outside of the code, the globals, the heap, and the
+//! stack is an error.  The profile dump hook does nothing; the interpreter
+//! collects profiles.  Nor does the emulator collect garbage, so the GC
+//! initializer does nothing too.
+
+use std::io::{BufRead, Write};
+
+use derive_more::Display;
+
+use crate::back::asm::*;
+use crate::common::*;
+
+/// The bytes of memory of the machine.
+pub const MEMORY_SIZE: u64 = 1 << 24;
+
+/// The address where the code starts.
+pub const CODE_BASE: u64 = 0x10000;
+
+/// The bytes at the top of the memory that only the stack may use.
+const STACK_SIZE: u64 = 1 << 20;
+
+/// The address of the first runtime function.  Each one takes a word, in the
+/// order of [RUNTIME].
+const RUNTIME_BASE: u64 = 0x1000;
+
+/// The runtime functions of the emulator.
+const RUNTIME: [&str; 11] = [
+    READ_FN,
+    PRINT_FN,
+    ALLOC_FN,
+    PROFILE_DUMP_FN,
+    GC_INIT_FN,
+    STACK_OVERFLOW_FN,
+    DIV_BY_ZERO_FN,
+    OVERFLOW_FN,
+    EXIT_FN,
+    MUL_FN,
+    DIV_FN,
+];
+
+/// The address that `main` returns to, right after the runtime functions,
+/// which ends the program like [EXIT_FN].
+const MAIN_RETURN: u64 = RUNTIME_BASE + 4 * RUNTIME.len() as u64;
+
+/// Errors that keep a program from running to the end.
+#[derive(Debug, Display)]
+#[display("{_0}")]
+pub struct EmuError(String);
+
+fn error<T>(msg: String) -> Result<T, EmuError> {
+    Err(EmuError(format!("Runtime error: {msg}")))
+}
+
+/// Run a program, reading from `input` and writing to `output`.  Returns the
+/// exit status, which is the value `main` returns, or the status of `$exit`.
+pub fn run(program: &Program, input: impl BufRead, output: impl Write) -> Result<i64, EmuError> {
+    run_counted(program, input, output).map(|(status, _)| status)
+}
+
+/// Run a program like `run`, and return its exit status and the number of
+/// instructions it retired.  The runtime functions that the emulator stands
+/// in for don't count, since they retire no instructions of the program.
+pub fn run_counted(
+    program: &Program,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<(i64, u64), EmuError> {
+    if program.target != Target::Riscv64 {
+        return Err(EmuError("The emulator only runs RV64 code.".to_string()));
+    }
+    let mut binary = program.encode().map_err(|e| EmuError(e.to_string()))?;
+    for relocation in binary.relocations.clone() {
+        let Some(i) = RUNTIME.iter().position(|f| **f == *relocation.symbol) else {
+            return Err(EmuError(format!(
+                "Undefined function `{}`.",
+                relocation.symbol
+            )));
+        };
+        let address = (RUNTIME_BASE + 4 * i as u64) as i64 - CODE_BASE as i64;
+        binary
+            .relocate(&relocation, address)
+            .map_err(|e| EmuError(e.to_string()))?;
+    }
+    let Some(main) = binary.functions.get(&Id::from_ref("main")) else {
+        return Err(EmuError("The program has no `main`.".to_string()));
+    };
+
+    let mut memory = vec![0; MEMORY_SIZE as usize];
+    let end = CODE_BASE as usize + binary.bytes.len();
+    memory[CODE_BASE as usize..end].copy_from_slice(&binary.bytes);
+    let mut machine = Machine {
+        regs: [0; 32],
+        pc: CODE_BASE + u64::from(*main),
+        memory,
+        heap: (end as u64).next_multiple_of(16),
+        input: Input::new(&mut input),
+        output: &mut output,
+        retired: 0,
+    };
+    machine.regs[Register::Sp as usize] = MEMORY_SIZE as i64;
+    machine.regs[Register::Ra as usize] = MAIN_RETURN as i64;
+    loop {
+        if let Some(status) = machine.step()? {
+            return Ok((status, machine.retired));
+        }
+    }
+}
+
+/// The state of the machine.
+struct Machine<'a> {
+    regs: [i64; 32],
+    pc: u64,
+    memory: Vec<u8>,
+    /// The address of the next allocation.
+    heap: u64,
+    input: Input<&'a mut dyn BufRead>,
+    output: &'a mut dyn Write,
+    /// The number of instructions the program has retired.
+    retired: u64,
+}
+
+impl Machine<'_> {
+    fn reg(&self, r: u32) -> i64 {
+        self.regs[r as usize]
+    }
+
+    fn set(&mut self, r: u32, value: i64) {
+        if r != 0 {
+            self.regs[r as usize] = value;
+        }
+    }
+
+    /// The range of memory at `addr` with `size` bytes, if the program may
+    /// access it.
+    fn range(&self, addr: i64, size: u64) -> Result<std::ops::Range<usize>, EmuError> {
+        let addr = addr as u64;
+        match addr.checked_add(size) {
+            Some(end) if addr >= CODE_BASE && end <= MEMORY_SIZE => Ok(addr as usize..end as usize),
+            _ => error(format!("Invalid memory access at {:#x}.", addr)),
+        }
+    }
+
+    /// Load `size` bytes, sign-extended or zero-extended.
+    fn load(&self, addr: i64, size: u64, signed: bool) -> Result<i64, EmuError> {
+        let mut bytes = [0; 8];
+        let range = self.range(addr, size)?;
+        bytes[..size as usize].copy_from_slice(&self.memory[range]);
+        let value = u64::from_le_bytes(bytes);
+        let unused = 64 - 8 * size as u32;
+        Ok(if signed {
+            ((value << unused) as i64) >> unused
+        } else {
+            value as i64
+        })
+    }
+
+    fn store(&mut self, addr: i64, size: u64, value: i64) -> Result<(), EmuError> {
+        let range = self.range(addr, size)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Ok(())
+    }
+
+    /// Run one instruction, or one runtime function.  Returns the exit status
+    /// if the program ends.
+    fn step(&mut self) -> Result<Option<i64>, EmuError> {
+        if (RUNTIME_BASE..=MAIN_RETURN).contains(&self.pc) && self.pc.is_multiple_of(4) {
+            return self.runtime();
+        }
+        let pc = self.pc;
+        let insn = self.load(pc as i64, 4, false)? as u32;
+        self.retired += 1;
+        let opcode = insn & 0x7f;
+        let rd = insn >> 7 & 0x1f;
+        let funct3 = insn >> 12 & 0x7;
+        let rs1 = insn >> 15 & 0x1f;
+        let rs2 = insn >> 20 & 0x1f;
+        let funct7 = insn >> 25;
+        let (lhs, rhs) = (self.reg(rs1), self.reg(rs2));
+        let imm_i = i64::from(insn as i32 >> 20);
+        let imm_s = i64::from((insn as i32 >> 25) << 5 | rd as i32);
+        let imm_b = i64::from(
+            (insn as i32 >> 31) << 12
+                | ((insn >> 7 & 1) << 11 | (insn >> 25 & 0x3f) << 5 | (insn >> 8 & 0xf) << 1)
+                    as i32,
+        );
+        let imm_u = i64::from((insn & 0xfffff000) as i32);
+        let imm_j = i64::from(
+            (insn as i32 >> 31) << 20
+                | (insn & 0xff000 | (insn >> 20 & 1) << 11 | (insn >> 21 & 0x3ff) << 1) as i32,
+        );
+        let illegal = || error(format!("Illegal instruction {insn:#010x} at {pc:#x}."));
+        let mut next = pc.wrapping_add(4);
+        match opcode {
+            0x37 => self.set(rd, imm_u),
+            0x17 => self.set(rd, (pc as i64).wrapping_add(imm_u)),
+            0x6f => {
+                self.set(rd, next as i64);
+                next = (pc as i64).wrapping_add(imm_j) as u64;
+            }
+            0x67 if funct3 == 0 => {
+                let target = lhs.wrapping_add(imm_i) & !1;
+                self.set(rd, next as i64);
+                next = target as u64;
+            }
+            0x63 => {
+                let taken = match funct3 {
+                    0b000 => lhs == rhs,
+                    0b001 => lhs != rhs,
+                    0b100 => lhs < rhs,
+                    0b101 => lhs >= rhs,
+                    0b110 => (lhs as u64) < rhs as u64,
+                    0b111 => lhs as u64 >= rhs as u64,
+                    _ => return illegal(),
+                };
+                if taken {
+                    next = (pc as i64).wrapping_add(imm_b) as u64;
+                }
+            }
+            0x03 => {
+                let addr = lhs.wrapping_add(imm_i);
+                let value = match funct3 {
+                    0b000 => self.load(addr, 1, true)?,
+                    0b001 => self.load(addr, 2, true)?,
+                    0b010 => self.load(addr, 4, true)?,
+                    0b011 => self.load(addr, 8, true)?,
+                    0b100 => self.load(addr, 1, false)?,
+                    0b101 => self.load(addr, 2, false)?,
+                    0b110 => self.load(addr, 4, false)?,
+                    _ => return illegal(),
+                };
+                self.set(rd, value);
+            }
+            0x23 if funct3 <= 0b011 => {
+                self.store(lhs.wrapping_add(imm_s), 1 << funct3, rhs)?;
+            }
+            0x13 => {
+                let shamt = (imm_i & 0x3f) as u32;
+                let value = match (funct3, imm_i >> 6) {
+                    (0b000, _) => lhs.wrapping_add(imm_i),
+                    (0b010, _) => (lhs < imm_i).into(),
+                    (0b011, _) => ((lhs as u64) < imm_i as u64).into(),
+                    (0b100, _) => lhs ^ imm_i,
+                    (0b110, _) => lhs | imm_i,
+                    (0b111, _) => lhs & imm_i,
+                    (0b001, 0) => lhs << shamt,
+                    (0b101, 0) => ((lhs as u64) >> shamt) as i64,
+                    (0b101, 0x10) => lhs >> shamt,
+                    _ => return illegal(),
+                };
+                self.set(rd, value);
+            }
+            0x1b => {
+                let (lhs, shamt) = (lhs as i32, (imm_i & 0x1f) as u32);
+                let value = match (funct3, imm_i >> 5) {
+                    (0b000, _) => lhs.wrapping_add(imm_i as i32),
+                    (0b001, 0) => lhs << shamt,
+                    (0b101, 0) => ((lhs as u32) >> shamt) as i32,
+                    (0b101, 0x20) => lhs >> shamt,
+                    _ => return illegal(),
+                };
+                self.set(rd, value.into());
+            }
+            0x33 => {
+                let shamt = (rhs & 0x3f) as u32;
+                let value = match (funct7, funct3) {
+                    (0, 0b000) => lhs.wrapping_add(rhs),
+                    (0x20, 0b000) => lhs.wrapping_sub(rhs),
+                    (0, 0b001) => lhs << shamt,
+                    (0, 0b010) => (lhs < rhs).into(),
+                    (0, 0b011) => ((lhs as u64) < rhs as u64).into(),
+                    (0, 0b100) => lhs ^ rhs,
+                    (0, 0b101) => ((lhs as u64) >> shamt) as i64,
+                    (0x20, 0b101) => lhs >> shamt,
+                    (0, 0b110) => lhs | rhs,
+                    (0, 0b111) => lhs & rhs,
+                    (0x10, 0b010) => (lhs << 1).wrapping_add(rhs),
+                    (0x10, 0b100) => (lhs << 2).wrapping_add(rhs),
+                    (0x10, 0b110) => (lhs << 3).wrapping_add(rhs),
+                    (0x20, 0b111) => lhs & !rhs,
+                    (0x05, 0b100) => lhs.min(rhs),
+                    (0x05, 0b110) => lhs.max(rhs),
+                    (1, 0b000) => lhs.wrapping_mul(rhs),
+                    (1, 0b001) => ((i128::from(lhs) * i128::from(rhs)) >> 64) as i64,
+                    (1, 0b010) => ((i128::from(lhs) * i128::from(rhs as u64)) >> 64) as i64,
+                    (1, 0b011) => ((u128::from(lhs as u64) * u128::from(rhs as u64)) >> 64) as i64,
+                    (1, 0b100) => div(lhs, rhs),
+                    (1, 0b101) => (lhs as u64).checked_div(rhs as u64).unwrap_or(u64::MAX) as i64,
+                    (1, 0b110) => rem(lhs, rhs),
+                    (1, 0b111) => (lhs as u64).checked_rem(rhs as u64).unwrap_or(lhs as u64) as i64,
+                    _ => return illegal(),
+                };
+                self.set(rd, value);
+            }
+            0x3b => {
+                let (lhs, rhs, shamt) = (lhs as i32, rhs as i32, (rhs & 0x1f) as u32);
+                let value = match (funct7, funct3) {
+                    (0, 0b000) => lhs.wrapping_add(rhs),
+                    (0x20, 0b000) => lhs.wrapping_sub(rhs),
+                    (0, 0b001) => lhs << shamt,
+                    (0, 0b101) => ((lhs as u32) >> shamt) as i32,
+                    (0x20, 0b101) => lhs >> shamt,
+                    (1, 0b000) => lhs.wrapping_mul(rhs),
+                    (1, 0b100) => div(lhs.into(), rhs.into()) as i32,
+                    (1, 0b101) => (lhs as u32).checked_div(rhs as u32).unwrap_or(u32::MAX) as i32,
+                    (1, 0b110) => rem(lhs.into(), rhs.into()) as i32,
+                    (1, 0b111) => (lhs as u32).checked_rem(rhs as u32).unwrap_or(lhs as u32) as i32,
+                    _ => return illegal(),
+                };
+                self.set(rd, value.into());
+            }
+            _ => return illegal(),
+        }
+        self.pc = next;
+        Ok(None)
+    }
+
+    /// Run the runtime function at the PC, and return to the caller.
+    fn runtime(&mut self) -> Result<Option<i64>, EmuError> {
+        use Register::*;
+
+        let (a0, a1) = (self.regs[A0 as usize], self.regs[A1 as usize]);
+        let result = match RUNTIME.get(((self.pc - RUNTIME_BASE) / 4) as usize) {
+            Some(&READ_FN) => self.input.read().or_else(error)?,
+            Some(&PRINT_FN) => {
+                if let Err(e) = writeln!(self.output, "{a0}") {
+                    return error(format!("Cannot write the output: {e}"));
+                }
+                a0
+            }
+            Some(&ALLOC_FN) => self.alloc(a0)?,
+            Some(&PROFILE_DUMP_FN | &GC_INIT_FN) => 0,
+            Some(&STACK_OVERFLOW_FN) => return error("Stack overflow.".to_string()),
+            Some(&DIV_BY_ZERO_FN) => return error("Division by zero.".to_string()),
+            Some(&OVERFLOW_FN) => return error("Arithmetic overflow.".to_string()),
+            Some(&MUL_FN) => a0.wrapping_mul(a1),
+            Some(&DIV_FN) => div(a0, a1),
+            // `exit`, or returning from `main`.
+            _ => return Ok(Some(a0)),
+        };
+        self.regs[A0 as usize] = result;
+        self.pc = self.regs[Ra as usize] as u64;
+        Ok(None)
+    }
+
+    /// Allocate `size` zeroed words on the heap.
+    fn alloc(&mut self, size: i64) -> Result<i64, EmuError> {
+        let limit = MEMORY_SIZE - STACK_SIZE;
+        let end = u64::try_from(size)
+            .ok()
+            .and_then(|words| words.checked_mul(8))
+            .and_then(|bytes| bytes.checked_add(self.heap))
+            .filter(|end| *end <= limit);
+        let Some(end) = end else {
+            return error(format!("Cannot allocate {size} words."));
+        };
+        let start = self.heap;
+        self.heap = end.next_multiple_of(16);
+        Ok(start as i64)
+    }
+}
+
+/// Signed division like `div`: division by zero gives -1, and the smallest
+/// value divided by -1 wraps around.
+fn div(lhs: i64, rhs: i64) -> i64 {
+    if rhs == 0 {
+        -1
+    } else {
+        lhs.wrapping_div(rhs)
+    }
+}
+
+/// Signed remainder like `rem`: the remainder of division by zero is the
+/// dividend.
+fn rem(lhs: i64, rhs: i64) -> i64 {
+    if rhs == 0 {
+        lhs
+    } else {
+        lhs.wrapping_rem(rhs)
+    }
+}
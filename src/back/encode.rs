@@ -0,0 +1,817 @@
+//! Machine code encoding.
+//!
+//! This turns a program after register allocation into RISC-V machine code,
+//! the way the assembler would from the assembly output, so the crate can
+//! produce binaries on its own.  The code of the functions comes first, one
+//! after another, and the global variables follow it, aligned to a word.
+//! Everything is addressed relative to the PC, so the binary can be loaded
+//! at any address.
+//!
+//! Pseudo-instructions expand like the GNU assembler expands them: `li` loads
+//! its immediate with `lui` and `addiw` (`addi` on RV32) or a single `addi`,
+//! and `call`, `la`, and accesses to globals take an `auipc` followed by an
+//! instruction with the lower 12 bits of the offset.  Since every
+//! instruction has a known size, we lay out the code first, and then encode
+//! each instruction with the addresses of the labels, functions, and globals
+//! it refers to.
+//!
+//! Calls and jumps to functions that aren't in the program, like those of the
+//! runtime and the multiplication and division routines of `--no-m`, are
+//! left for a linker as [Relocation]s, with zeros in place of their offsets.
+//! Branches that don't reach their targets are errors, since the assembler
+//! doesn't relax them either.
+
+use derive_more::Display;
+
+use crate::back::asm::*;
+use crate::back::legalize::{fits_imm12, legalize_instruction};
+use crate::back::materialize::materialize;
+use crate::common::*;
+
+use Register::*;
+
+/// Instructions that can't be encoded.
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+#[display("Cannot encode {_0}")]
+pub struct EncodeError(String);
+
+/// A program as machine code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binary {
+    /// The code, then the global variables, starting at offset 0.
+    pub bytes: Vec<u8>,
+    /// The offset of each function.
+    pub functions: Map<Id, u32>,
+    /// The offset of the first global variable.
+    pub data: u32,
+    /// The references to functions outside the program.
+    pub relocations: Vec<Relocation>,
+}
+
+/// A reference to a function outside the program, which a linker fills in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// The offset of the instruction that refers to the function.
+    pub offset: u32,
+    pub symbol: Id,
+    pub kind: RelocationKind,
+}
+
+/// How an instruction refers to a function outside the program.  These are
+/// the relocations of the same names in the RISC-V ELF psABI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// An `auipc` and a `jalr` that call the function.
+    Call,
+    /// A `jal` to the function.
+    Jal,
+}
+
+/// What an instruction refers to by its address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Symbol {
+    /// A block of the current function, or its epilogue.
+    Label(Id),
+    /// A function, which may be outside the program.
+    Function(Id),
+    /// A byte of a global variable.
+    Global { index: usize, offset: i32 },
+}
+
+/// A machine instruction whose offsets may not be known yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Machine {
+    /// An encoded instruction.
+    Word(u32),
+    /// A branch, encoded without its offset.
+    Branch(u32, Symbol),
+    /// A `jal`, encoded without its offset.
+    Jal(u32, Symbol),
+    /// An `auipc` into `rd`, and an instruction that adds the lower 12 bits
+    /// of the offset, encoded without them.  The second one is an S-type
+    /// instruction if `store` is true, and an I-type one otherwise.
+    PcRel {
+        rd: Register,
+        next: u32,
+        store: bool,
+        symbol: Symbol,
+    },
+}
+
+impl Machine {
+    fn size(&self) -> u32 {
+        match self {
+            Machine::PcRel { .. } => 8,
+            _ => 4,
+        }
+    }
+}
+
+// Major opcodes.
+const LOAD: u32 = 0x03;
+const LOAD_FP: u32 = 0x07;
+const OP_IMM: u32 = 0x13;
+const AUIPC: u32 = 0x17;
+const OP_IMM_32: u32 = 0x1b;
+const STORE: u32 = 0x23;
+const STORE_FP: u32 = 0x27;
+const OP: u32 = 0x33;
+const LUI: u32 = 0x37;
+const OP_32: u32 = 0x3b;
+const OP_FP: u32 = 0x53;
+const BRANCH: u32 = 0x63;
+const JALR: u32 = 0x67;
+const JAL: u32 = 0x6f;
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn u_type(imm: i32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+/// The offset bits of a B-type instruction.
+fn b_offset(offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | (imm >> 1 & 0xf) << 8 | (imm >> 11 & 1) << 7
+}
+
+/// The offset bits of a J-type instruction.
+fn j_offset(offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+}
+
+/// Does the instruction have a 16-bit form in the C extension?  These are
+/// the forms of RV64C and RV32C without the floating-point loads and stores
+/// of single precision, which we don't use.
+fn compressible(w: u32, target: Target) -> bool {
+    let rv64 = target == Target::Riscv64;
+    let (opcode, funct3, funct7) = (w & 0x7f, w >> 12 & 7, w >> 25);
+    let (rd, rs1, rs2) = (w >> 7 & 0x1f, w >> 15 & 0x1f, w >> 20 & 0x1f);
+    let imm_i = w as i32 >> 20;
+    let imm_s = (w as i32 >> 25) << 5 | (w >> 7 & 0x1f) as i32;
+    // The registers of the 3-bit fields, s0, s1, and a0 to a5.
+    let c = |r: u32| (8..16).contains(&r);
+    // An offset from a register, or from sp, scaled by `scale`.
+    let offset = |imm: i32, scale: i32, reg: u32| {
+        let slots = if reg == x(Sp) { 64 } else { 32 };
+        imm >= 0 && imm % scale == 0 && imm / scale < slots
+    };
+    let small = |imm: i32| (-32..32).contains(&imm);
+    let sp = x(Sp);
+    match opcode {
+        // addi: c.addi, c.li, c.mv, c.addi16sp, and c.addi4spn.
+        OP_IMM if funct3 == 0 => {
+            (rd == rs1 && rd != 0 && imm_i != 0 && small(imm_i))
+                || (rs1 == 0 && rd != 0 && small(imm_i))
+                || (imm_i == 0 && rd != 0 && rs1 != 0)
+                || (rd == sp
+                    && rs1 == sp
+                    && imm_i != 0
+                    && imm_i % 16 == 0
+                    && (-512..512).contains(&imm_i))
+                || (rs1 == sp && c(rd) && imm_i > 0 && imm_i % 4 == 0 && imm_i < 1024)
+        }
+        // c.slli, c.srli, c.srai, and c.andi.
+        OP_IMM if funct3 == 1 => rd == rs1 && rd != 0 && imm_i & 0x3f != 0,
+        OP_IMM if funct3 == 5 => rd == rs1 && c(rd) && imm_i & 0x3f != 0,
+        OP_IMM if funct3 == 7 => rd == rs1 && c(rd) && small(imm_i),
+        OP_IMM_32 if funct3 == 0 => rv64 && rd == rs1 && rd != 0 && small(imm_i),
+        // c.add, c.mv, and c.sub, c.xor, c.or, and c.and.
+        OP if funct7 == 0 && funct3 == 0 => rd != 0 && rs2 != 0 && (rd == rs1 || rs1 == 0),
+        OP if funct7 == 0x20 && funct3 == 0 => rd == rs1 && c(rd) && c(rs2),
+        OP if funct7 == 0 && matches!(funct3, 4 | 6 | 7) => rd == rs1 && c(rd) && c(rs2),
+        OP_32 if (funct7 == 0 || funct7 == 0x20) && funct3 == 0 => {
+            rv64 && rd == rs1 && c(rd) && c(rs2)
+        }
+        // Loads and stores of words, and of doubles, off sp or between the
+        // registers of the 3-bit fields.
+        LOAD | LOAD_FP | STORE | STORE_FP => {
+            let store = opcode == STORE || opcode == STORE_FP;
+            let (imm, data) = if store { (imm_s, rs2) } else { (imm_i, rd) };
+            let scale = match (opcode, funct3) {
+                (LOAD | STORE, 2) if !rv64 => 4,
+                (LOAD | STORE, 3) if rv64 => 8,
+                (LOAD_FP | STORE_FP, 3) => 8,
+                _ => return false,
+            };
+            let integer = opcode == LOAD || opcode == STORE;
+            (rs1 == sp && offset(imm, scale, sp) && (store || !integer || data != 0))
+                || (c(rs1) && c(data) && offset(imm, scale, rs1))
+        }
+        LUI => rd != 0 && rd != sp && (w as i32 >> 12) != 0 && small(w as i32 >> 12),
+        // c.jr and c.jalr.
+        JALR => funct3 == 0 && imm_i == 0 && rs1 != 0 && (rd == 0 || rd == x(Ra)),
+        // c.j, and c.jal on RV32.
+        JAL => {
+            let imm = ((w as i32 >> 31) << 20)
+                | ((w >> 21 & 0x3ff) << 1) as i32
+                | ((w >> 20 & 1) << 11) as i32
+                | (w & 0xff000) as i32;
+            (rd == 0 || (rd == x(Ra) && !rv64)) && (-2048..2048).contains(&imm)
+        }
+        // c.beqz and c.bnez.
+        BRANCH => {
+            let imm = ((w as i32 >> 31) << 12)
+                | ((w >> 25 & 0x3f) << 5) as i32
+                | ((w >> 8 & 0xf) << 1) as i32
+                | ((w >> 7 & 1) << 11) as i32;
+            funct3 < 2 && rs2 == 0 && c(rs1) && (-256..256).contains(&imm)
+        }
+        _ => false,
+    }
+}
+
+/// The register number of an integer register.  [Register] lists them in
+/// order.
+fn x(r: Register) -> u32 {
+    r as u32
+}
+
+/// The register number of a floating-point register.
+fn f(r: FRegister) -> u32 {
+    r as u32
+}
+
+/// The `funct3` of loads and stores of a word.
+fn word_funct3(target: Target) -> u32 {
+    match target {
+        Target::Riscv64 => 0b011,
+        Target::Riscv32 => 0b010,
+    }
+}
+
+impl Program {
+    /// Encode the program as machine code.
+    pub fn encode(&self) -> Result<Binary, EncodeError> {
+        // Expand the instructions of each function with the labels of its
+        // blocks.
+        let mut functions = vec![];
+        for func in &self.functions {
+            let (code, labels) = expand_function(func, self.target)?;
+            functions.push((func.id, code, labels));
+        }
+
+        // Lay out the functions and the globals.
+        let mut addresses = Map::new();
+        let mut end = 0;
+        for (id, code, _) in &functions {
+            addresses.insert(*id, end);
+            end += code.iter().map(Machine::size).sum::<u32>();
+        }
+        let word = self.target.word_size() as u32;
+        let data = end.next_multiple_of(8);
+        let mut globals = vec![];
+        let mut next = data;
+        for global in &self.globals {
+            globals.push(next);
+            next += word * global.init.len() as u32;
+        }
+
+        let mut bytes = vec![];
+        let mut relocations = vec![];
+        for (_, code, labels) in &functions {
+            let mut pc = bytes.len() as u32;
+            let mut label_addresses = Map::new();
+            let mut at = pc;
+            let mut index = 0;
+            for (i, insn) in code.iter().enumerate() {
+                while index < labels.len() && labels[index].1 == i {
+                    label_addresses.insert(labels[index].0, at);
+                    index += 1;
+                }
+                at += insn.size();
+            }
+            for (id, i) in &labels[index..] {
+                debug_assert_eq!(*i, code.len());
+                label_addresses.insert(*id, at);
+            }
+            let address = |symbol: &Symbol| -> Option<u32> {
+                match symbol {
+                    Symbol::Label(l) => Some(label_addresses[l]),
+                    Symbol::Function(name) => addresses.get(name).copied(),
+                    Symbol::Global { index, offset } => {
+                        Some(globals[*index].wrapping_add_signed(*offset))
+                    }
+                }
+            };
+            for insn in code {
+                let words = match insn {
+                    Machine::Word(w) => vec![*w],
+                    Machine::Branch(w, symbol) => {
+                        let offset = address(symbol).unwrap().wrapping_sub(pc) as i32;
+                        if !(-4096..4096).contains(&offset) {
+                            return Err(EncodeError(format!(
+                                "a branch to {symbol:?}, which is {offset} bytes away"
+                            )));
+                        }
+                        vec![w | b_offset(offset)]
+                    }
+                    Machine::Jal(w, symbol) => match address(symbol) {
+                        Some(a) => {
+                            let offset = a.wrapping_sub(pc) as i32;
+                            if !(-(1 << 20)..1 << 20).contains(&offset) {
+                                return Err(EncodeError(format!(
+                                    "a jump to {symbol:?}, which is {offset} bytes away"
+                                )));
+                            }
+                            vec![w | j_offset(offset)]
+                        }
+                        None => {
+                            relocations.push(relocation(pc, symbol, RelocationKind::Jal));
+                            vec![*w]
+                        }
+                    },
+                    Machine::PcRel {
+                        rd,
+                        next,
+                        store,
+                        symbol,
+                    } => match address(symbol) {
+                        Some(a) => {
+                            let offset = a.wrapping_sub(pc) as i32;
+                            let hi = offset.wrapping_add(0x800) >> 12;
+                            let lo = offset.wrapping_sub(hi << 12);
+                            let lo = if *store {
+                                s_type(lo, 0, 0, 0, 0)
+                            } else {
+                                i_type(lo, 0, 0, 0, 0)
+                            };
+                            vec![u_type(hi, x(*rd), AUIPC), next | lo]
+                        }
+                        None => {
+                            relocations.push(relocation(pc, symbol, RelocationKind::Call));
+                            vec![u_type(0, x(*rd), AUIPC), *next]
+                        }
+                    },
+                };
+                for w in words {
+                    bytes.extend(w.to_le_bytes());
+                    pc += 4;
+                }
+            }
+        }
+
+        bytes.resize(data as usize, 0);
+        for global in &self.globals {
+            for value in &global.init {
+                match self.target {
+                    Target::Riscv64 => bytes.extend(value.to_le_bytes()),
+                    Target::Riscv32 => bytes.extend((*value as i32).to_le_bytes()),
+                }
+            }
+        }
+        Ok(Binary {
+            bytes,
+            functions: addresses,
+            data,
+            relocations,
+        })
+    }
+}
+
+impl Binary {
+    /// The bytes of the code if the assembler used the 16-bit form of each
+    /// instruction that has one, the way it does with `.option rvc`.  Branches
+    /// and jumps qualify by their offsets in the uncompressed code, which can
+    /// only get shorter, so this slightly overestimates the size.
+    pub fn compressed_code_size(&self, target: Target) -> u32 {
+        self.bytes[..self.data as usize]
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            // Padding before the data is zeros, which isn't an instruction.
+            .filter(|w| *w != 0)
+            .map(|w| if compressible(w, target) { 2 } else { 4 })
+            .sum()
+    }
+
+    /// Fill in a relocation with the address of its function, which is
+    /// `address` bytes from the start of the binary.
+    pub fn relocate(&mut self, relocation: &Relocation, address: i64) -> Result<(), EncodeError> {
+        let at = relocation.offset as usize;
+        let offset = address - i64::from(relocation.offset);
+        let word = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let too_far = || {
+            Err(EncodeError(format!(
+                "a call to `{}`, which is {offset} bytes away",
+                relocation.symbol
+            )))
+        };
+        match relocation.kind {
+            RelocationKind::Call => {
+                let Ok(offset) = i32::try_from(offset) else {
+                    return too_far();
+                };
+                let hi = offset.wrapping_add(0x800) >> 12;
+                let lo = offset.wrapping_sub(hi << 12);
+                let auipc = word(&self.bytes, at) & 0xfff | u_type(hi, 0, 0);
+                let jalr = word(&self.bytes, at + 4) & 0xfffff | i_type(lo, 0, 0, 0, 0);
+                self.bytes[at..at + 4].copy_from_slice(&auipc.to_le_bytes());
+                self.bytes[at + 4..at + 8].copy_from_slice(&jalr.to_le_bytes());
+            }
+            RelocationKind::Jal => {
+                if !(-(1 << 20)..1 << 20).contains(&offset) {
+                    return too_far();
+                }
+                let jal = word(&self.bytes, at) & 0xfff | j_offset(offset as i32);
+                self.bytes[at..at + 4].copy_from_slice(&jal.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    /// The bytes of machine code of the function, with its prologue and its
+    /// epilogue.
+    pub fn code_size(&self, target: Target) -> Result<u32, EncodeError> {
+        let (code, _) = expand_function(self, target)?;
+        Ok(code.iter().map(Machine::size).sum())
+    }
+}
+
+/// The index of the first machine instruction of each block, and of the
+/// epilogue.
+type Labels = Vec<(Id, usize)>;
+
+/// The machine instructions of a function, and its labels.
+fn expand_function(func: &Function, target: Target) -> Result<(Vec<Machine>, Labels), EncodeError> {
+    let mut code = vec![];
+    let mut labels = vec![];
+    for insn in func.prologue().into_iter().flat_map(legalize_instruction) {
+        code.extend(expand(&insn, target)?);
+    }
+    for block in &func.basic_blocks {
+        labels.push((block.id, code.len()));
+        for insn in &block.instructions {
+            code.extend(expand(insn, target)?);
+        }
+    }
+    labels.push((Id::from_ref(EPILOGUE), code.len()));
+    for insn in func
+        .epilogue_code()
+        .into_iter()
+        .flat_map(legalize_instruction)
+    {
+        code.extend(expand(&insn, target)?);
+    }
+    Ok((code, labels))
+}
+
+/// A relocation for the instruction at `offset`, which refers to a function
+/// outside the program.
+fn relocation(offset: u32, symbol: &Symbol, kind: RelocationKind) -> Relocation {
+    let Symbol::Function(name) = symbol else {
+        unreachable!("only functions can be outside the program")
+    };
+    Relocation {
+        offset,
+        symbol: *name,
+        kind,
+    }
+}
+
+/// The machine instructions of an instruction, which has been legalized.
+fn expand(insn: &Instruction, target: Target) -> Result<Vec<Machine>, EncodeError> {
+    use Instruction::*;
+    use Machine::Word;
+
+    let error = || Err(EncodeError(format!("`{insn}`")));
+    let global = |mem: &Memory| match *mem {
+        Memory::Global { index, offset } => Symbol::Global { index, offset },
+        Memory::Mem(..) => unreachable!(),
+    };
+    let width = word_funct3(target);
+    let code = match insn {
+        La {
+            dst,
+            src: src @ Memory::Global { .. },
+        } => vec![Machine::PcRel {
+            rd: *dst,
+            next: i_type(0, x(*dst), 0b000, x(*dst), OP_IMM),
+            store: false,
+            symbol: global(src),
+        }],
+        La {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if fits_imm12((*offset).into()) => {
+            vec![Word(i_type(*offset, x(*base), 0b000, x(*dst), OP_IMM))]
+        }
+        Ld {
+            dst,
+            src: src @ Memory::Global { .. },
+        } => vec![Machine::PcRel {
+            rd: *dst,
+            next: i_type(0, x(*dst), width, x(*dst), LOAD),
+            store: false,
+            symbol: global(src),
+        }],
+        Ld {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if fits_imm12((*offset).into()) => {
+            vec![Word(i_type(*offset, x(*base), width, x(*dst), LOAD))]
+        }
+        Sd {
+            dst: dst @ Memory::Global { .. },
+            src,
+        } => {
+            let tmp = scratch(&[*src]);
+            vec![Machine::PcRel {
+                rd: tmp,
+                next: s_type(0, x(*src), x(tmp), width, STORE),
+                store: true,
+                symbol: global(dst),
+            }]
+        }
+        Sd {
+            dst: Memory::Mem(base, offset),
+            src,
+        } if fits_imm12((*offset).into()) => {
+            vec![Word(s_type(*offset, x(*src), x(*base), width, STORE))]
+        }
+        Li { dst, imm } => return li(*dst, *imm, target),
+        Lui { dst, imm } => vec![Word(u_type(*imm, x(*dst), LUI))],
+        Arith { op, dst, lhs, rhs } => {
+            let (funct7, funct3, opcode) = match op {
+                ArithOp::Add => (0, 0b000, OP),
+                ArithOp::Sub => (0x20, 0b000, OP),
+                ArithOp::Mul => (1, 0b000, OP),
+                ArithOp::MulH => (1, 0b001, OP),
+                ArithOp::Div => (1, 0b100, OP),
+                ArithOp::Slt => (0, 0b010, OP),
+                ArithOp::And => (0, 0b111, OP),
+                ArithOp::Or => (0, 0b110, OP),
+                ArithOp::Xor => (0, 0b100, OP),
+                ArithOp::Srl => (0, 0b101, OP),
+                ArithOp::Sra => (0x20, 0b101, OP),
+                ArithOp::Sll => (0, 0b001, OP),
+                ArithOp::AddW if target == Target::Riscv64 => (0, 0b000, OP_32),
+                ArithOp::AddW => return error(),
+                ArithOp::Sh1Add => (0x10, 0b010, OP),
+                ArithOp::Sh2Add => (0x10, 0b100, OP),
+                ArithOp::Sh3Add => (0x10, 0b110, OP),
+                ArithOp::AndN => (0x20, 0b111, OP),
+                ArithOp::Min => (0x05, 0b100, OP),
+                ArithOp::Max => (0x05, 0b110, OP),
+            };
+            vec![Word(r_type(
+                funct7,
+                x(*rhs),
+                x(*lhs),
+                funct3,
+                x(*dst),
+                opcode,
+            ))]
+        }
+        // There are no immediate forms, so load the immediate first.
+        ArithI {
+            op:
+                op @ (ArithOp::Mul
+                | ArithOp::MulH
+                | ArithOp::Div
+                | ArithOp::Sh1Add
+                | ArithOp::Sh2Add
+                | ArithOp::Sh3Add
+                | ArithOp::AndN
+                | ArithOp::Min
+                | ArithOp::Max),
+            dst,
+            lhs,
+            rhs,
+        } => {
+            let tmp = scratch(&[*lhs]);
+            let mut code = li(tmp, (*rhs).into(), target)?;
+            code.extend(expand(
+                &Arith {
+                    op: *op,
+                    dst: *dst,
+                    lhs: *lhs,
+                    rhs: tmp,
+                },
+                target,
+            )?);
+            code
+        }
+        ArithI { op, dst, lhs, rhs } => {
+            let shamt = match target {
+                Target::Riscv64 => 0..64,
+                Target::Riscv32 => 0..32,
+            };
+            let (imm, funct3, opcode) = match op {
+                ArithOp::Add => (*rhs, 0b000, OP_IMM),
+                ArithOp::Sub => (rhs.wrapping_neg(), 0b000, OP_IMM),
+                ArithOp::Slt => (*rhs, 0b010, OP_IMM),
+                ArithOp::And => (*rhs, 0b111, OP_IMM),
+                ArithOp::Or => (*rhs, 0b110, OP_IMM),
+                ArithOp::Xor => (*rhs, 0b100, OP_IMM),
+                ArithOp::Sll if shamt.contains(rhs) => (*rhs, 0b001, OP_IMM),
+                ArithOp::Srl if shamt.contains(rhs) => (*rhs, 0b101, OP_IMM),
+                ArithOp::Sra if shamt.contains(rhs) => (0x400 | *rhs, 0b101, OP_IMM),
+                ArithOp::AddW if target == Target::Riscv64 => (*rhs, 0b000, OP_IMM_32),
+                _ => return error(),
+            };
+            if !fits_imm12(imm.into()) && !matches!(op, ArithOp::Sra) {
+                return error();
+            }
+            vec![Word(i_type(imm, x(*lhs), funct3, x(*dst), opcode))]
+        }
+        Jal {
+            dst: Ra,
+            target: JumpTarget::Global(name),
+        } => vec![Machine::PcRel {
+            rd: Ra,
+            next: i_type(0, x(Ra), 0b000, x(Ra), JALR),
+            store: false,
+            symbol: Symbol::Function(*name),
+        }],
+        Jal { dst, target } => vec![Machine::Jal(u_type(0, x(*dst), JAL), symbol(target))],
+        Jalr { dst, target } => vec![Word(i_type(0, x(*target), 0b000, x(*dst), JALR))],
+        Branch {
+            cond,
+            lhs,
+            rhs,
+            target,
+        } => {
+            // `ble` and `bgt` are `bge` and `blt` with the operands swapped.
+            let (funct3, lhs, rhs) = match cond {
+                Condition::Equal => (0b000, lhs, rhs),
+                Condition::NotEqual => (0b001, lhs, rhs),
+                Condition::Less => (0b100, lhs, rhs),
+                Condition::GreaterEq => (0b101, lhs, rhs),
+                Condition::Greater => (0b100, rhs, lhs),
+                Condition::LessEq => (0b101, rhs, lhs),
+            };
+            let w = s_type(0, x(*rhs), x(*lhs), funct3, BRANCH);
+            vec![Machine::Branch(w, symbol(target))]
+        }
+        SCmpZ { dst, lhs, cond } => {
+            let (dst, lhs) = (x(*dst), x(*lhs));
+            let w = match cond {
+                Condition::Equal => i_type(1, lhs, 0b011, dst, OP_IMM),
+                Condition::NotEqual => r_type(0, lhs, x(Zero), 0b011, dst, OP),
+                Condition::Less => r_type(0, x(Zero), lhs, 0b010, dst, OP),
+                Condition::Greater => r_type(0, lhs, x(Zero), 0b010, dst, OP),
+                Condition::LessEq | Condition::GreaterEq => return error(),
+            };
+            vec![Word(w)]
+        }
+        Fld {
+            dst,
+            src: src @ Memory::Global { .. },
+        } => {
+            let tmp = scratch(&[]);
+            vec![Machine::PcRel {
+                rd: tmp,
+                next: i_type(0, x(tmp), 0b011, f(*dst), LOAD_FP),
+                store: false,
+                symbol: global(src),
+            }]
+        }
+        Fld {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if fits_imm12((*offset).into()) => {
+            vec![Word(i_type(*offset, x(*base), 0b011, f(*dst), LOAD_FP))]
+        }
+        Fsd {
+            dst: dst @ Memory::Global { .. },
+            src,
+        } => {
+            let tmp = scratch(&[]);
+            vec![Machine::PcRel {
+                rd: tmp,
+                next: s_type(0, f(*src), x(tmp), 0b011, STORE_FP),
+                store: true,
+                symbol: global(dst),
+            }]
+        }
+        Fsd {
+            dst: Memory::Mem(base, offset),
+            src,
+        } if fits_imm12((*offset).into()) => {
+            vec![Word(s_type(*offset, f(*src), x(*base), 0b011, STORE_FP))]
+        }
+        // The dynamic rounding mode is 0b111.
+        FArith { op, dst, lhs, rhs } => {
+            let funct7 = match op {
+                FArithOp::Add => 0b0000001,
+                FArithOp::Sub => 0b0000101,
+                FArithOp::Mul => 0b0001001,
+                FArithOp::Div => 0b0001101,
+            };
+            vec![Word(r_type(
+                funct7,
+                f(*rhs),
+                f(*lhs),
+                0b111,
+                f(*dst),
+                OP_FP,
+            ))]
+        }
+        FCmp { op, dst, lhs, rhs } => {
+            let funct3 = match op {
+                FCmpOp::Equal => 0b010,
+                FCmpOp::Less => 0b001,
+                FCmpOp::LessEq => 0b000,
+            };
+            vec![Word(r_type(
+                0b1010001,
+                f(*rhs),
+                f(*lhs),
+                funct3,
+                x(*dst),
+                OP_FP,
+            ))]
+        }
+        // `fmv.d` is `fsgnj.d` with the same source twice.
+        FMv { dst, src } => vec![Word(r_type(
+            0b0010001,
+            f(*src),
+            f(*src),
+            0b000,
+            f(*dst),
+            OP_FP,
+        ))],
+        // Converting a word is exact, so it has no rounding mode.
+        FCvtFromInt { dst, src } => {
+            let (rs2, rm) = match target {
+                Target::Riscv64 => (0b00010, 0b111),
+                Target::Riscv32 => (0, 0b000),
+            };
+            vec![Word(r_type(0b1101001, rs2, x(*src), rm, f(*dst), OP_FP))]
+        }
+        // Round toward zero, which is 0b001.
+        FCvtToInt { dst, src } => {
+            let rs2 = if target == Target::Riscv64 {
+                0b00010
+            } else {
+                0
+            };
+            vec![Word(r_type(0b1100001, rs2, f(*src), 0b001, x(*dst), OP_FP))]
+        }
+        // Stack maps and line numbers are only in the assembly output.
+        Comment(_) | StackMap { .. } | Loc { .. } => vec![],
+        _ => return error(),
+    };
+    Ok(code)
+}
+
+/// What a jump target refers to.
+fn symbol(target: &JumpTarget) -> Symbol {
+    match target {
+        JumpTarget::Local(l) => Symbol::Label(*l),
+        JumpTarget::Global(name) => Symbol::Function(*name),
+    }
+}
+
+/// The instructions of `li`, which on RV64 are those that
+/// [materialize] picks.
+fn li(dst: Register, imm: i64, target: Target) -> Result<Vec<Machine>, EncodeError> {
+    let code = match target {
+        Target::Riscv64 => materialize(dst, imm),
+        // Values are 32 bits, and `addi` wraps around at 32 bits.
+        Target::Riscv32 => {
+            let imm = imm as i32;
+            let hi = imm.wrapping_add(0x800) >> 12;
+            let lo = imm.wrapping_sub(hi << 12);
+            let mut code = vec![];
+            if hi != 0 {
+                code.push(Instruction::Lui {
+                    dst,
+                    imm: hi & 0xfffff,
+                });
+            }
+            if lo != 0 || hi == 0 {
+                let lhs = if hi != 0 { dst } else { Zero };
+                code.push(Instruction::ArithI {
+                    op: ArithOp::Add,
+                    dst,
+                    lhs,
+                    rhs: lo,
+                });
+            }
+            code
+        }
+    };
+    let mut result = vec![];
+    for insn in &code {
+        result.extend(expand(insn, target)?);
+    }
+    Ok(result)
+}
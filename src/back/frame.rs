@@ -0,0 +1,97 @@
+//! The layout of the stack frames, for debuggers and for tests of the frame
+//! diagram in [crate::back::asm].
+//!
+//! The layout of a function tells where each part of its frame is, as
+//! offsets from the frame pointer: the return address and the saved frame
+//! pointer above it, then the local variables right below it, then the
+//! callee-saved registers at the bottom, where the stack pointer points.
+//! Each variable of the tiny IR lives in a register or in a stack slot among
+//! the local variables (see [Function::variables]).
+
+use std::fmt::{Display, Formatter};
+
+use crate::back::asm::*;
+use crate::common::*;
+
+/// The layout of the frame of a function.  Offsets are from the frame
+/// pointer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameLayout {
+    pub function: Id,
+    /// The bytes the function takes of the stack: the return address and the
+    /// saved frame pointer, and everything between the frame pointer and the
+    /// stack pointer.
+    pub size: i32,
+    pub return_address: i32,
+    pub saved_fp: i32,
+    /// The offsets of the bytes of the local variables, from the lowest one
+    /// up to the frame pointer.
+    pub locals: std::ops::Range<i32>,
+    /// The callee-saved registers, and where the prologue saves them.
+    pub saved_registers: Vec<(Register, i32)>,
+    pub saved_fp_registers: Vec<(FRegister, i32)>,
+    /// The variables of the tiny IR function, and where they live.
+    pub variables: Vec<(Id, VarLocation)>,
+}
+
+impl Function {
+    /// The layout of the frame of the function.
+    pub fn frame_layout(&self) -> FrameLayout {
+        let frame_size = self.frame_size();
+        // The prologue saves the registers from the stack pointer up.
+        let saved = |i: usize| -frame_size + SLOT_SIZE * i as i32;
+        FrameLayout {
+            function: self.id,
+            size: 2 * SLOT_SIZE + frame_size,
+            return_address: SLOT_SIZE,
+            saved_fp: 0,
+            locals: -self.stack_space..0,
+            saved_registers: self
+                .used_registers
+                .iter()
+                .enumerate()
+                .map(|(i, r)| (*r, saved(i)))
+                .collect(),
+            saved_fp_registers: self
+                .saved_fp_registers()
+                .into_iter()
+                .enumerate()
+                .map(|(i, r)| (r, saved(self.used_registers.len() + i)))
+                .collect(),
+            variables: self.variables.clone(),
+        }
+    }
+}
+
+impl Program {
+    /// The layouts of the frames of the functions, in order.
+    pub fn frame_layouts(&self) -> Vec<FrameLayout> {
+        self.functions.iter().map(Function::frame_layout).collect()
+    }
+}
+
+impl Display for FrameLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {} bytes", self.function, self.size)?;
+        writeln!(f, "  {:>5}(fp)  return address", self.return_address)?;
+        writeln!(f, "  {:>5}(fp)  saved fp", self.saved_fp)?;
+        if !self.locals.is_empty() {
+            writeln!(
+                f,
+                "  {:>5}(fp)  locals ({} bytes)",
+                self.locals.start,
+                self.locals.len()
+            )?;
+        }
+        for (r, offset) in &self.saved_registers {
+            writeln!(f, "  {offset:>5}(fp)  saved {r}")?;
+        }
+        for (r, offset) in &self.saved_fp_registers {
+            writeln!(f, "  {offset:>5}(fp)  saved {r}")?;
+        }
+        for (name, location) in &self.variables {
+            writeln!(f, "  {name} in {location}")?;
+        }
+        Ok(())
+    }
+}
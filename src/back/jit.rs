@@ -0,0 +1,493 @@
+//! The Cranelift JIT, which compiles a tiny IR program to native code in
+//! memory and runs it right away.
+//!
+//! This is only there with the `cranelift` feature.  Cranelift targets the
+//! host, so it doesn't need an assembler or an emulator, which makes it the
+//! fast way to run a program.
+//!
+//! Each variable is a Cranelift variable, which Cranelift's frontend turns
+//! into SSA form, and each block becomes a Cranelift block.  The compiled
+//! functions take a pointer to the state of the run as an extra first
+//! parameter, and pass it to the Rust functions that print, read, and
+//! allocate.  Allocations stay alive until the program ends, and accessing
+//! memory outside of them is undefined behavior like in the assembly output.
+//! Profile counters don't count; the interpreter collects profiles.
+//!
+//! Native code can't unwind to the caller of the program, so exiting from a
+//! function other than `main`, or failing to read the input, marks the run
+//! as done, and every function returns as soon as a call comes back with
+//! the run done.
+
+use std::io::{BufRead, Write};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Signature, UserFuncName};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use derive_more::Display;
+
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::{ssa, tir};
+
+/// Errors that keep a program from running to the end.
+#[derive(Debug, Display)]
+#[display("{_0}")]
+pub struct JitError(String);
+
+/// The state of a run, which the compiled code gets a pointer to.
+#[repr(C)]
+struct Run<'a> {
+    /// Whether the program has ended.  The compiled code reads this, so it
+    /// comes first.
+    done: i64,
+    /// The exit status of a function other than `main` that exits, right
+    /// after `done` for the same reason.
+    status: i64,
+    input: Input<&'a mut dyn BufRead>,
+    output: &'a mut dyn Write,
+    /// The allocations, which live until the end of the run.
+    heap: Vec<Box<[i64]>>,
+    /// The error that ended the run, if any.
+    error: Option<String>,
+}
+
+impl Run<'_> {
+    /// End the run with an error.
+    fn fail(&mut self, msg: String) {
+        self.done = 1;
+        self.error.get_or_insert(msg);
+    }
+}
+
+extern "C" fn print(run: *mut Run, value: i64) {
+    // SAFETY: the compiled code passes on the pointer that `run` gave it,
+    // and nothing else refers to the state while the program runs.
+    let run = unsafe { &mut *run };
+    if let Err(e) = writeln!(run.output, "{value}") {
+        run.fail(format!("Cannot write the output: {e}"));
+    }
+}
+
+extern "C" fn read(run: *mut Run) -> i64 {
+    // SAFETY: as in `print`.
+    let run = unsafe { &mut *run };
+    run.input.read().unwrap_or_else(|e| {
+        run.fail(e);
+        0
+    })
+}
+
+extern "C" fn alloc(run: *mut Run, size: i64) -> i64 {
+    // SAFETY: as in `print`.
+    let run = unsafe { &mut *run };
+    let Ok(words) = usize::try_from(size) else {
+        run.fail(format!("Cannot allocate {size} words."));
+        return 0;
+    };
+    let block = vec![0; words].into_boxed_slice();
+    let addr = block.as_ptr() as i64;
+    run.heap.push(block);
+    addr
+}
+
+/// The Rust functions the compiled code calls, by the names it calls them.
+/// Tiny IR names can't contain dots, so they don't clash with the functions
+/// of the program.
+const SHIMS: [(&str, *const u8); 3] = [
+    ("smol.print", print as *const u8),
+    ("smol.read", read as *const u8),
+    ("smol.alloc", alloc as *const u8),
+];
+
+/// Compile a program and run it, reading from `input` and writing to
+/// `output`.  Returns the exit status of the program, which is the value
+/// `main` returns, or the status of `$exit`.
+pub fn run(
+    program: &tir::Program,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<i64, JitError> {
+    let mut jit = Jit::new()?;
+    let main = jit.compile(program)?;
+    // SAFETY: `main` was compiled with the signature of `MainFn`.
+    let main = unsafe { std::mem::transmute::<*const u8, MainFn>(main) };
+    let mut run = Run {
+        done: 0,
+        status: 0,
+        input: Input::new(&mut input),
+        output: &mut output,
+        heap: vec![],
+        error: None,
+    };
+    let status = main(&mut run);
+    let _ = run.output.flush();
+    match run.error {
+        Some(e) => Err(JitError(format!("Runtime error: {e}"))),
+        None if run.done != 0 => Ok(run.status),
+        None => Ok(status),
+    }
+}
+
+/// The type of the compiled `main`.
+type MainFn = extern "C" fn(*mut Run) -> i64;
+
+/// The module that the program is compiled into.
+struct Jit {
+    module: JITModule,
+}
+
+impl Jit {
+    fn new() -> Result<Self, JitError> {
+        let mut flags = settings::builder();
+        flags.set("is_pic", "false").unwrap();
+        let isa = cranelift_native::builder()
+            .map_err(|e| JitError(format!("Cannot compile for this machine: {e}")))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| JitError(format!("Cannot compile for this machine: {e}")))?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        for (name, f) in SHIMS {
+            builder.symbol(name, f);
+        }
+        Ok(Jit {
+            module: JITModule::new(builder),
+        })
+    }
+
+    /// The signature of a function of the program with `params` parameters,
+    /// or of a shim.
+    fn signature(&self, params: usize, returns: bool) -> Signature {
+        let mut sig = self.module.make_signature();
+        sig.params
+            .push(AbiParam::new(self.module.target_config().pointer_type()));
+        sig.params
+            .extend((0..params).map(|_| AbiParam::new(types::I64)));
+        if returns {
+            sig.returns.push(AbiParam::new(types::I64));
+        }
+        sig
+    }
+
+    /// Compile the program, and return the address of `main`.
+    fn compile(&mut self, program: &tir::Program) -> Result<*const u8, JitError> {
+        let error = |e: cranelift_module::ModuleError| JitError(format!("Cannot compile: {e}"));
+        let mut ids = Map::new();
+        for (name, func) in &program.func {
+            let sig = self.signature(func.params.len(), true);
+            let id = self
+                .module
+                .declare_function(name, Linkage::Local, &sig)
+                .map_err(error)?;
+            ids.insert(*name, id);
+        }
+        let shims = Shims {
+            print: self.declare_shim("smol.print", 1, false)?,
+            read: self.declare_shim("smol.read", 0, true)?,
+            alloc: self.declare_shim("smol.alloc", 1, true)?,
+        };
+
+        let mut ctx = self.module.make_context();
+        let mut builder_ctx = FunctionBuilderContext::new();
+        for (name, func) in &program.func {
+            let func = if func
+                .block
+                .values()
+                .flat_map(|b| &b.insn)
+                .any(|i| i.is_phi())
+            {
+                ssa::destruct_function(func.clone())
+            } else {
+                func.clone()
+            };
+            let id = ids[name];
+            ctx.func.signature = self.signature(func.params.len(), true);
+            ctx.func.name = UserFuncName::user(0, id.as_u32());
+            let gen = Gen {
+                func: &func,
+                is_main: *name == tir::Program::main(),
+                module: &mut self.module,
+                builder: FunctionBuilder::new(&mut ctx.func, &mut builder_ctx),
+                ids: &ids,
+                shims: &shims,
+                blocks: Map::new(),
+                run: None,
+                unwind: None,
+            };
+            gen.function();
+            self.module.define_function(id, &mut ctx).map_err(error)?;
+            self.module.clear_context(&mut ctx);
+        }
+        self.module.finalize_definitions().map_err(error)?;
+        let main = ids
+            .get(&tir::Program::main())
+            .ok_or_else(|| JitError("The program has no `main`.".to_string()))?;
+        Ok(self.module.get_finalized_function(*main))
+    }
+
+    fn declare_shim(
+        &mut self,
+        name: &str,
+        params: usize,
+        returns: bool,
+    ) -> Result<FuncId, JitError> {
+        let sig = self.signature(params, returns);
+        self.module
+            .declare_function(name, Linkage::Import, &sig)
+            .map_err(|e| JitError(format!("Cannot compile: {e}")))
+    }
+}
+
+/// The shims as functions of the module.
+struct Shims {
+    print: FuncId,
+    read: FuncId,
+    alloc: FuncId,
+}
+
+/// The state of compiling a function, which has no phis.
+struct Gen<'a> {
+    func: &'a tir::Function,
+    /// Is this `main`, which ends the program by returning?
+    is_main: bool,
+    module: &'a mut JITModule,
+    builder: FunctionBuilder<'a>,
+    /// The functions of the program.
+    ids: &'a Map<Id, FuncId>,
+    shims: &'a Shims,
+    /// The Cranelift block of each block.
+    blocks: Map<tir::BlockId, cranelift_codegen::ir::Block>,
+    /// The pointer to the state of the run.
+    run: Option<cranelift_codegen::ir::Value>,
+    /// The block that returns because the run is done.
+    unwind: Option<cranelift_codegen::ir::Block>,
+}
+
+impl Gen<'_> {
+    fn var(v: &tir::ValueId) -> Variable {
+        Variable::from_u32(v.0)
+    }
+
+    fn function(mut self) {
+        let func = self.func;
+        // Cranelift's entry block can't be jumped to, so it sets up the
+        // variables and jumps to the tiny IR entry block.
+        let start = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(start);
+        for b in func.block.keys() {
+            let block = self.builder.create_block();
+            self.blocks.insert(*b, block);
+        }
+        let unwind = self.builder.create_block();
+        self.unwind = Some(unwind);
+
+        self.builder.switch_to_block(start);
+        let params = self.builder.block_params(start).to_vec();
+        self.run = Some(params[0]);
+        // Variables start out as zero, except for the parameters.
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        for v in (0..func.names.num_values() as u32).map(tir::ValueId) {
+            self.builder.declare_var(Self::var(&v), types::I64);
+            let value = match func.params.iter().position(|p| *p == v) {
+                Some(i) => params[i + 1],
+                None => zero,
+            };
+            self.builder.def_var(Self::var(&v), value);
+        }
+        let entry = self.blocks[&tir::Function::entry()];
+        self.builder.ins().jump(entry, &[]);
+
+        for (b, block) in &func.block {
+            self.builder.switch_to_block(self.blocks[b]);
+            for insn in &block.insn {
+                self.instruction(insn);
+            }
+            for t in &block.term {
+                self.terminator(t);
+            }
+        }
+
+        self.builder.switch_to_block(unwind);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.builder.ins().return_(&[zero]);
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn get(&mut self, v: &tir::ValueId) -> cranelift_codegen::ir::Value {
+        self.builder.use_var(Self::var(v))
+    }
+
+    fn set(&mut self, v: &tir::ValueId, value: cranelift_codegen::ir::Value) {
+        self.builder.def_var(Self::var(v), value);
+    }
+
+    /// Call a function of the module with the state of the run and the given
+    /// arguments, return right away if the run is done, and return the
+    /// result otherwise.
+    fn call(
+        &mut self,
+        callee: FuncId,
+        args: &[cranelift_codegen::ir::Value],
+    ) -> Option<cranelift_codegen::ir::Value> {
+        let callee = self.module.declare_func_in_func(callee, self.builder.func);
+        let mut all = vec![self.run.unwrap()];
+        all.extend(args);
+        let call = self.builder.ins().call(callee, &all);
+        let result = self.builder.inst_results(call).first().copied();
+        let done = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), self.run.unwrap(), 0);
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(done, self.unwind.unwrap(), &[], next, &[]);
+        self.builder.switch_to_block(next);
+        result
+    }
+
+    fn instruction(&mut self, insn: &tir::Instruction) {
+        use tir::Instruction::*;
+
+        match insn {
+            Copy { dst, src } => {
+                let value = self.get(src);
+                self.set(dst, value);
+            }
+            Const { dst, src } => {
+                let value = self.builder.ins().iconst(types::I64, *src);
+                self.set(dst, value);
+            }
+            Arith { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.get(lhs), self.get(rhs));
+                let value = match op {
+                    BOp::Add => self.builder.ins().iadd(lhs, rhs),
+                    BOp::Sub => self.builder.ins().isub(lhs, rhs),
+                    BOp::Mul => self.builder.ins().imul(lhs, rhs),
+                    BOp::Div => self.div(lhs, rhs),
+                    BOp::Lt => {
+                        let lt = self.builder.ins().icmp(IntCC::SignedLessThan, lhs, rhs);
+                        self.builder.ins().uextend(types::I64, lt)
+                    }
+                };
+                self.set(dst, value);
+            }
+            Shift {
+                op,
+                dst,
+                src,
+                amount,
+            } => {
+                let src = self.get(src);
+                let amount = i64::from(*amount);
+                let ins = self.builder.ins();
+                let value = match op {
+                    tir::ShiftOp::Left => ins.ishl_imm(src, amount),
+                    tir::ShiftOp::Arith => ins.sshr_imm(src, amount),
+                    tir::ShiftOp::Logical => ins.ushr_imm(src, amount),
+                };
+                self.set(dst, value);
+            }
+            Read(dst) => {
+                let value = self.call(self.shims.read, &[]).unwrap();
+                self.set(dst, value);
+            }
+            Print(src) => {
+                let src = self.get(src);
+                self.call(self.shims.print, &[src]);
+            }
+            Phi { .. } => unreachable!("phis are removed before code generation"),
+            Call { dst, callee, args } => {
+                let args = args.iter().map(|a| self.get(a)).collect::<Vec<_>>();
+                let value = self.call(self.ids[callee], &args).unwrap();
+                self.set(dst, value);
+            }
+            Alloc { dst, size } => {
+                let size = self.get(size);
+                let value = self.call(self.shims.alloc, &[size]).unwrap();
+                self.set(dst, value);
+            }
+            Load { dst, addr, offset } => {
+                let (addr, offset) = self.address(addr, *offset);
+                let value = self
+                    .builder
+                    .ins()
+                    .load(types::I64, MemFlags::new(), addr, offset);
+                self.set(dst, value);
+            }
+            Store { addr, offset, src } => {
+                let (addr, offset) = self.address(addr, *offset);
+                let src = self.get(src);
+                self.builder.ins().store(MemFlags::new(), src, addr, offset);
+            }
+            Count(_) | Loc(_) => {}
+        }
+    }
+
+    /// Divide the way smol does: division by zero gives -1, and dividing the
+    /// smallest value by -1 wraps around.  Cranelift's division traps in
+    /// both cases, so those divide by 1 instead and pick the right result.
+    fn div(
+        &mut self,
+        lhs: cranelift_codegen::ir::Value,
+        rhs: cranelift_codegen::ir::Value,
+    ) -> cranelift_codegen::ir::Value {
+        let b = &mut self.builder;
+        let by_zero = b.ins().icmp_imm(IntCC::Equal, rhs, 0);
+        let by_minus_one = b.ins().icmp_imm(IntCC::Equal, rhs, -1);
+        let special = b.ins().bor(by_zero, by_minus_one);
+        let one = b.ins().iconst(types::I64, 1);
+        let divisor = b.ins().select(special, one, rhs);
+        let quotient = b.ins().sdiv(lhs, divisor);
+        let negated = b.ins().ineg(lhs);
+        let quotient = b.ins().select(by_minus_one, negated, quotient);
+        let minus_one = b.ins().iconst(types::I64, -1);
+        b.ins().select(by_zero, minus_one, quotient)
+    }
+
+    /// The address and the immediate offset of a load or a store.
+    fn address(&mut self, addr: &tir::ValueId, offset: i64) -> (cranelift_codegen::ir::Value, i32) {
+        let addr = self.get(addr);
+        match i32::try_from(offset) {
+            Ok(offset) => (addr, offset),
+            Err(_) => (self.builder.ins().iadd_imm(addr, offset), 0),
+        }
+    }
+
+    fn terminator(&mut self, t: &tir::Terminator) {
+        use tir::Terminator::*;
+
+        match t {
+            Jump(target) => {
+                self.builder.ins().jump(self.blocks[target], &[]);
+            }
+            Branch { guard, tt, ff } => {
+                let guard = self.get(guard);
+                self.builder
+                    .ins()
+                    .brif(guard, self.blocks[tt], &[], self.blocks[ff], &[]);
+            }
+            Exit(status) => {
+                let status = match status {
+                    Some(status) => self.get(status),
+                    None => self.builder.ins().iconst(types::I64, 0),
+                };
+                if !self.is_main {
+                    let run = self.run.unwrap();
+                    let one = self.builder.ins().iconst(types::I64, 1);
+                    self.builder.ins().store(MemFlags::trusted(), one, run, 0);
+                    self.builder
+                        .ins()
+                        .store(MemFlags::trusted(), status, run, 8);
+                }
+                self.builder.ins().return_(&[status]);
+            }
+            Return(value) => {
+                let value = self.get(value);
+                self.builder.ins().return_(&[value]);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Block layout for fall-through.
+//!
+//! A jump to the block that comes next does nothing, and a branch can jump to
+//! either of its targets and fall through to the other.  Code generation
+//! keeps the order of the tiny IR blocks and leaves out the jumps it can, so
+//! this pass orders the blocks to let more of the control flow fall through:
+//!
+//! 1. We group the blocks into *chains*.  A chain starts at the entry or, for
+//!    the later ones, at the first block left, and continues with a successor
+//!    of its last block that isn't placed yet: the one it falls through to,
+//!    then the one it jumps to, then the one it branches to.  With a profile,
+//!    it continues with the hottest of them, and starts at the hottest block
+//!    left.
+//! 2. The chain of the entry comes first.  The epilogue follows the last
+//!    block, so the first other chain that ends by returning comes last.
+//! 3. Greedy chains aren't always better, so we only keep the new order if
+//!    fewer edges have to jump than in the old one.  With a profile, each
+//!    edge counts as often as the less frequent of its blocks runs.
+//!
+//! Then each block that fell through to a block that no longer follows it
+//! jumps there instead, and a jump to the block that now follows it goes
+//! away.  A branch to the next block that is followed by a jump becomes the
+//! opposite branch to the target of the jump.
+
+use std::cmp::Reverse;
+
+use crate::back::asm::*;
+use crate::back::liveness::successors;
+use crate::common::*;
+use crate::middle::Profile;
+
+use Register::*;
+
+/// Lay out the blocks of every function so that more of the control flow
+/// falls through, going by the profile if there is one.
+pub fn layout<R: Copy + PartialEq + From<Register>>(
+    program: Program<R>,
+    profile: Option<&Profile>,
+) -> Program<R> {
+    program.map_functions(|func| layout_function(func, profile))
+}
+
+/// Lay out the blocks of a function so that more of the control flow falls
+/// through, going by the profile if there is one.
+pub fn layout_function<R: Copy + PartialEq + From<Register>>(
+    func: Function<R>,
+    profile: Option<&Profile>,
+) -> Function<R> {
+    let ids = func.basic_blocks.iter().map(|b| b.id).collect::<Vec<_>>();
+    let n = ids.len();
+    let epilogue = Id::from_ref(EPILOGUE);
+    let index = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id, i))
+        .collect::<Map<_, _>>();
+    let succ = successors(&func);
+    let count = |i: usize| profile.and_then(|p| p.count(func.id, ids[i])).unwrap_or(0);
+    // The successors of a block that are blocks, by preference.  Blocks jump
+    // and branch before they fall through, so that's the reverse order.
+    let preferred = |i: usize| {
+        succ[&ids[i]]
+            .iter()
+            .rev()
+            .filter_map(|s| index.get(s).copied())
+            .collect::<Vec<_>>()
+    };
+
+    let mut placed = vec![false; n];
+    let mut chains: Vec<Vec<usize>> = vec![];
+    while placed.contains(&false) {
+        let mut next = if chains.is_empty() {
+            Some(0)
+        } else {
+            (0..n)
+                .filter(|i| !placed[*i])
+                .min_by_key(|i| Reverse(count(*i)))
+        };
+        let mut chain = vec![];
+        while let Some(i) = next {
+            placed[i] = true;
+            chain.push(i);
+            next = preferred(i)
+                .into_iter()
+                .filter(|s| !placed[*s])
+                .min_by_key(|s| Reverse(count(*s)));
+        }
+        chains.push(chain);
+    }
+    let returns = |chain: &Vec<usize>| succ[&ids[*chain.last().unwrap()]].contains(&epilogue);
+    if let Some(k) = (1..chains.len()).find(|k| returns(&chains[*k])) {
+        let chain = chains.remove(k);
+        chains.push(chain);
+    }
+
+    // The edges that don't fall through, weighted by the profile.
+    let cost = |order: &[usize]| -> u64 {
+        let mut cost = 0;
+        for (p, i) in order.iter().enumerate() {
+            let next = order.get(p + 1).map_or(epilogue, |j| ids[*j]);
+            for s in succ[&ids[*i]].iter().filter(|s| **s != next) {
+                cost += match profile {
+                    Some(_) => count(*i).min(index.get(s).map_or(count(*i), |s| count(*s))),
+                    None => 1,
+                };
+            }
+        }
+        cost
+    };
+    let order = chains.concat();
+    let order = if cost(&order) < cost(&(0..n).collect::<Vec<_>>()) {
+        order
+    } else {
+        (0..n).collect()
+    };
+
+    let mut blocks = func.basic_blocks.into_iter().map(Some).collect::<Vec<_>>();
+    let basic_blocks = order
+        .iter()
+        .enumerate()
+        .map(|(p, i)| {
+            let mut block = blocks[*i].take().unwrap();
+            let next = order.get(p + 1).map_or(epilogue, |j| ids[*j]);
+            let old_next = ids.get(i + 1).copied().unwrap_or(epilogue);
+            fix_up(&mut block.instructions, old_next, next);
+            block
+        })
+        .collect();
+    Function {
+        basic_blocks,
+        ..func
+    }
+}
+
+/// Fix the jumps at the end of a block that used to be followed by
+/// `old_next` and is now followed by `next`.
+fn fix_up<R: Copy + PartialEq + From<Register>>(
+    code: &mut Vec<Instruction<R>>,
+    old_next: Id,
+    next: Id,
+) {
+    let jump_target = |insn: Option<&Instruction<R>>| match insn {
+        Some(Instruction::Jal {
+            dst,
+            target: JumpTarget::Local(l),
+        }) if *dst == R::from(Zero) => Some(*l),
+        _ => None,
+    };
+    let falls_through = !matches!(
+        code.last(),
+        Some(Instruction::Jal { dst, .. }) if *dst == R::from(Zero)
+    );
+    if falls_through && old_next != next {
+        code.push(Instruction::jump(JumpTarget::Local(old_next)));
+    }
+    if jump_target(code.last()) == Some(next) {
+        code.pop();
+    } else if let (Some(to), [.., Instruction::Branch { cond, target, .. }, _]) =
+        (jump_target(code.last()), code.as_mut_slice())
+    {
+        if *target == JumpTarget::Local(next) {
+            *cond = cond.negate();
+            *target = JumpTarget::Local(to);
+            code.pop();
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Legalization of immediates.
+//!
+//! RISC-V I-type and S-type instructions hold a 12-bit signed immediate, so
+//! `addi` and friends take immediates in -2048..=2047, and loads and stores
+//! take offsets in the same range.  The instructions we generate hold 32-bit
+//! immediates, which the assembler would reject, so after register
+//! allocation we split the instructions with larger immediates: the
+//! immediate is loaded into a scratch register with `li`, and the register
+//! form of the instruction uses it.
+//!
+//! Multiplication and division have no immediate forms at all, and global
+//! addresses are resolved by the assembler, so the assembly output handles
+//! those.
+
+use crate::back::asm::*;
+
+/// Does the immediate fit in a 12-bit signed field?
+pub fn fits_imm12(imm: i64) -> bool {
+    (-2048..2048).contains(&imm)
+}
+
+/// Split the instructions with immediates that don't fit.
+pub fn legalize(program: Program) -> Program {
+    program.map_functions(legalize_function)
+}
+
+/// Split the instructions of a function with immediates that don't fit.
+pub fn legalize_function(func: Function) -> Function {
+    Function {
+        basic_blocks: func
+            .basic_blocks
+            .into_iter()
+            .map(|block| BasicBlock {
+                id: block.id,
+                instructions: block
+                    .instructions
+                    .into_iter()
+                    .flat_map(legalize_instruction)
+                    .collect(),
+            })
+            .collect(),
+        ..func
+    }
+}
+
+/// Split an instruction if its immediate doesn't fit.
+pub fn legalize_instruction(insn: Instruction) -> Vec<Instruction> {
+    use Instruction::*;
+
+    let tmp = scratch(&insn.used_registers());
+    match insn {
+        ArithI { op, dst, lhs, rhs } if !fits_arith_i(op, rhs) => vec![
+            Li {
+                dst: tmp,
+                imm: rhs.into(),
+            },
+            Arith {
+                op,
+                dst,
+                lhs,
+                rhs: tmp,
+            },
+        ],
+        La {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if !fits_imm12(offset.into()) => vec![
+            Li {
+                dst: tmp,
+                imm: offset.into(),
+            },
+            Arith {
+                op: ArithOp::Add,
+                dst,
+                lhs: base,
+                rhs: tmp,
+            },
+        ],
+        Ld {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if !fits_imm12(offset.into()) => {
+            let mut code = address(tmp, base, offset);
+            code.push(Ld {
+                dst,
+                src: Memory::Mem(tmp, 0),
+            });
+            code
+        }
+        Sd {
+            dst: Memory::Mem(base, offset),
+            src,
+        } if !fits_imm12(offset.into()) => {
+            let mut code = address(tmp, base, offset);
+            code.push(Sd {
+                dst: Memory::Mem(tmp, 0),
+                src,
+            });
+            code
+        }
+        Fld {
+            dst,
+            src: Memory::Mem(base, offset),
+        } if !fits_imm12(offset.into()) => {
+            let mut code = address(tmp, base, offset);
+            code.push(Fld {
+                dst,
+                src: Memory::Mem(tmp, 0),
+            });
+            code
+        }
+        Fsd {
+            dst: Memory::Mem(base, offset),
+            src,
+        } if !fits_imm12(offset.into()) => {
+            let mut code = address(tmp, base, offset);
+            code.push(Fsd {
+                dst: Memory::Mem(tmp, 0),
+                src,
+            });
+            code
+        }
+        insn => vec![insn],
+    }
+}
+
+/// Can the assembly output emit this register-immediate instruction as is?
+/// Subtraction becomes an `addi` of the negated immediate, and the output
+/// loads the immediates of multiplication and division itself.  Shift
+/// amounts are always small.
+pub(crate) fn fits_arith_i(op: ArithOp, imm: i32) -> bool {
+    match op {
+        ArithOp::Sub => fits_imm12(-i64::from(imm)),
+        ArithOp::Mul
+        | ArithOp::MulH
+        | ArithOp::Div
+        | ArithOp::Sh1Add
+        | ArithOp::Sh2Add
+        | ArithOp::Sh3Add
+        | ArithOp::AndN
+        | ArithOp::Min
+        | ArithOp::Max
+        | ArithOp::Sll
+        | ArithOp::Srl
+        | ArithOp::Sra => true,
+        _ => fits_imm12(imm.into()),
+    }
+}
+
+/// Compute `base + offset` into `dst`, which isn't `base`.
+fn address(dst: Register, base: Register, offset: i32) -> Vec<Instruction> {
+    vec![
+        Instruction::Li {
+            dst,
+            imm: offset.into(),
+        },
+        Instruction::Arith {
+            op: ArithOp::Add,
+            dst,
+            lhs: base,
+            rhs: dst,
+        },
+    ]
+}
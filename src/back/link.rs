@@ -0,0 +1,235 @@
+//! Linking executables.
+//!
+//! This assembles the assembly output and links it with the runtime into a
+//! static executable, with the C compiler driver of a RISC-V toolchain, or
+//! stops at the object file for builds that do their own linking.  The
+//! runtime is the crate's own unless the user brings one.  The driver does
+//! all of it, and finds the C library that the runtime and
+//! [EXIT_FN](crate::back::asm::EXIT_FN) need.  Programs for the proxy kernel
+//! start at their own `_start`, so they link without the C library's startup
+//! code, and with the bare-metal (newlib) toolchains if there are any.
+//! Bare-metal programs bring their runtime too, so they link without any
+//! libraries, with the linker script of [crate::back::baremetal].
+//!
+//! The driver is the command in the `SMOL_CC` environment variable if it's
+//! set, which may include arguments, like `clang --target=riscv64-linux-gnu
+//! --sysroot=/opt/riscv`.  Otherwise it's the first of the usual GCC cross
+//! compilers on the `PATH`, or clang, which can target RISC-V without a
+//! separate build.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use derive_more::Display;
+
+use crate::back::asm::{AsmOptions, System, Target};
+use crate::back::baremetal::LINKER_SCRIPT;
+use crate::back::runtime::RUNTIME_SOURCE;
+
+/// The environment variable that overrides the toolchain.
+pub const CC_VAR: &str = "SMOL_CC";
+
+/// The GCC cross compilers we look for, in order.  The bare-metal ones are
+/// multilib builds that link RV32 code too.
+const GCC_DRIVERS: [&str; 4] = [
+    "riscv64-linux-gnu-gcc",
+    "riscv64-unknown-linux-gnu-gcc",
+    "riscv64-unknown-elf-gcc",
+    "riscv32-unknown-elf-gcc",
+];
+
+/// Errors that keep a program from being linked.
+#[derive(Debug, Display)]
+#[display("{_0}")]
+pub struct LinkError(String);
+
+/// A C compiler driver that assembles and links RISC-V code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Toolchain {
+    /// The driver to run.
+    pub driver: PathBuf,
+    /// The arguments that come before ours, like the target of clang.
+    pub args: Vec<String>,
+}
+
+impl Toolchain {
+    /// Find a toolchain for the target and the system, in `SMOL_CC` or on
+    /// the `PATH`.
+    pub fn detect(target: Target, system: System) -> Result<Toolchain, LinkError> {
+        if let Some(cc) = std::env::var_os(CC_VAR) {
+            let cc = cc.to_string_lossy().into_owned();
+            let mut words = cc.split_whitespace().map(String::from);
+            let Some(driver) = words.next() else {
+                return Err(LinkError(format!("`{CC_VAR}` is empty.")));
+            };
+            return Ok(Toolchain {
+                driver: driver.into(),
+                args: words.collect(),
+            });
+        }
+        Toolchain::detect_in(
+            &std::env::var_os("PATH").unwrap_or_default(),
+            target,
+            system,
+        )
+    }
+
+    /// Find a toolchain for the target and the system in the directories of
+    /// `path`, which is in the format of the `PATH` environment variable.
+    pub fn detect_in(path: &OsStr, target: Target, system: System) -> Result<Toolchain, LinkError> {
+        let find = |name: &str| find_program(path, name);
+        // Prefer the compilers that default to the target, and the
+        // bare-metal ones without Linux.
+        let mut gcc = GCC_DRIVERS.to_vec();
+        if system != System::Linux {
+            gcc.sort_by_key(|name| !name.ends_with("-elf-gcc"));
+        }
+        if target == Target::Riscv32 {
+            gcc.sort_by_key(|name| !name.starts_with("riscv32"));
+        }
+        if let Some(driver) = gcc.iter().find_map(|name| find(name)) {
+            return Ok(Toolchain {
+                driver,
+                args: vec![],
+            });
+        }
+        if let Some(driver) = find("clang") {
+            let triple = match target {
+                Target::Riscv64 => "riscv64-linux-gnu",
+                Target::Riscv32 => "riscv32-linux-gnu",
+            };
+            return Ok(Toolchain {
+                driver,
+                args: vec![format!("--target={triple}")],
+            });
+        }
+        Err(LinkError(format!(
+            "Cannot find a RISC-V toolchain: none of {}, or clang, is on the `PATH`.  \
+             Install one, or set `{CC_VAR}` to the compiler to link with.",
+            GCC_DRIVERS.join(", ")
+        )))
+    }
+
+    /// Assemble `asm`, which was generated with `options`, and link it with
+    /// the runtime's files into the executable `output`.  Without any, it
+    /// links with the crate's own runtime (see [crate::back::runtime]).
+    /// Position-independent code links into a static PIE, except on bare
+    /// metal, where the code stays where the linker script puts it.
+    pub fn link(
+        &self,
+        asm: &str,
+        runtime: &[PathBuf],
+        output: &Path,
+        target: Target,
+        options: &AsmOptions,
+    ) -> Result<(), LinkError> {
+        // Missing files of the runtime would only show up in the driver's
+        // errors, among others.
+        if let Some(missing) = runtime.iter().find(|path| !path.is_file()) {
+            return Err(LinkError(format!(
+                "Cannot find the runtime file `{}`.",
+                missing.display()
+            )));
+        }
+        let source = temp_path(".s");
+        let script = (options.system == System::BareMetal).then(|| temp_path(".ld"));
+        let builtin =
+            (runtime.is_empty() && options.system != System::BareMetal).then(|| temp_path(".c"));
+        let files: Vec<_> = [
+            Some((&source, asm)),
+            script.as_ref().map(|path| (path, LINKER_SCRIPT)),
+            builtin.as_ref().map(|path| (path, RUNTIME_SOURCE)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        self.run(&files, target, |command| {
+            match options.system {
+                System::Linux | System::ProxyKernel if options.pic => command.arg("-static-pie"),
+                _ => command.arg("-static"),
+            };
+            match options.system {
+                System::Linux => {}
+                System::ProxyKernel => {
+                    command.arg("-nostartfiles");
+                }
+                System::BareMetal => {
+                    command.arg("-nostdlib").arg("-T").args(&script);
+                }
+            }
+            command
+                .arg("-o")
+                .arg(output)
+                .arg(&source)
+                .args(runtime)
+                .args(&builtin);
+        })
+    }
+
+    /// Assemble `asm` into the object file `output`, without linking it, for
+    /// builds that link it with the runtime themselves.
+    pub fn assemble(&self, asm: &str, output: &Path, target: Target) -> Result<(), LinkError> {
+        let source = temp_path(".s");
+        self.run(&[(&source, asm)], target, |command| {
+            command.arg("-c").arg("-o").arg(output).arg(&source);
+        })
+    }
+
+    /// Write the files that the driver reads, run it for the target with the
+    /// arguments that `args` adds, and remove the files when it's done.
+    fn run(
+        &self,
+        files: &[(&PathBuf, &str)],
+        target: Target,
+        args: impl FnOnce(&mut Command),
+    ) -> Result<(), LinkError> {
+        let remove = || {
+            for (path, _) in files {
+                let _ = std::fs::remove_file(path);
+            }
+        };
+        for (path, text) in files {
+            if let Err(e) = std::fs::write(path, text) {
+                remove();
+                return Err(LinkError(format!("Cannot write `{}`: {e}", path.display())));
+            }
+        }
+        let mut command = Command::new(&self.driver);
+        command.args(&self.args);
+        command.args(match target {
+            Target::Riscv64 => ["-march=rv64imafd", "-mabi=lp64d"],
+            Target::Riscv32 => ["-march=rv32imafd", "-mabi=ilp32d"],
+        });
+        args(&mut command);
+        log::debug!("Running {command:?}.");
+        let result = command.output();
+        remove();
+        let driver = self.driver.display();
+        let result = result.map_err(|e| LinkError(format!("Cannot run `{driver}`: {e}")))?;
+        if !result.status.success() {
+            return Err(LinkError(format!(
+                "`{driver}` failed ({}):\n{}",
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim_end()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The file called `name` in the directories of `path`, which is in the
+/// format of the `PATH` environment variable.
+pub(crate) fn find_program(path: &OsStr, name: &str) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(name))
+        .find(|file| file.is_file())
+}
+
+/// A fresh path in the temporary directory, ending in `suffix`.
+pub(crate) fn temp_path(suffix: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("smol-{}-{n}{suffix}", std::process::id()))
+}
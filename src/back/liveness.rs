@@ -0,0 +1,168 @@
+//! Liveness analysis over backend instructions.
+//!
+//! A register is live at a point if its current value may be read later.  We
+//! compute the live registers at the start and the end of each basic block
+//! with the usual backwards fixed-point iteration, over virtual or physical
+//! registers alike.
+//!
+//! Calls follow the calling convention: a call reads the argument registers
+//! and may change all caller-saved registers.  We don't know how many
+//! arguments a callee takes, so all argument registers count as read.  The
+//! epilogue reads the returned value in a0.  The zero register is never live.
+
+use crate::back::asm::*;
+use crate::common::*;
+
+use Register::*;
+
+/// The registers a call may read.
+const ARGUMENTS: [Register; 8] = [A0, A1, A2, A3, A4, A5, A6, A7];
+
+/// The registers a call may change.
+const CALLER_SAVED: [Register; 16] = [
+    Ra, T0, T1, T2, T3, T4, T5, T6, A0, A1, A2, A3, A4, A5, A6, A7,
+];
+
+/// Live registers at basic block boundaries.
+#[derive(Debug)]
+pub struct Liveness<R> {
+    pub live_in: Map<Id, Set<R>>,
+    pub live_out: Map<Id, Set<R>>,
+}
+
+impl<R: Copy + Ord + From<Register>> Liveness<R> {
+    /// Compute liveness for the given function.
+    pub fn new(func: &Function<R>) -> Self {
+        let succ = successors(func);
+        let mut live_in: Map<Id, Set<R>> = func
+            .basic_blocks
+            .iter()
+            .map(|b| (b.id, Set::new()))
+            .collect();
+        live_in.insert(Id::from_ref(EPILOGUE), [R::from(A0)].into());
+        let mut live_out = live_in.clone();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in func.basic_blocks.iter().rev() {
+                let out = succ[&block.id]
+                    .iter()
+                    .flat_map(|s| live_in[s].iter().copied())
+                    .collect::<Set<_>>();
+                let live = live_before(block, out.clone());
+                if live != live_in[&block.id] || out != live_out[&block.id] {
+                    live_in.insert(block.id, live);
+                    live_out.insert(block.id, out);
+                    changed = true;
+                }
+            }
+        }
+
+        Liveness { live_in, live_out }
+    }
+
+    /// Is the register live at the start of the block?
+    pub fn is_live_in(&self, block: Id, r: R) -> bool {
+        self.live_in.get(&block).is_some_and(|s| s.contains(&r))
+    }
+
+    /// Is the register live at the end of the block?
+    pub fn is_live_out(&self, block: Id, r: R) -> bool {
+        self.live_out.get(&block).is_some_and(|s| s.contains(&r))
+    }
+
+    /// The registers live right after each instruction of the given block.
+    pub fn live_after_each(&self, block: &BasicBlock<R>) -> Vec<Set<R>> {
+        let mut live = self.live_out[&block.id].clone();
+        let mut result = vec![Set::new(); block.instructions.len()];
+        for (i, insn) in block.instructions.iter().enumerate().rev() {
+            result[i] = live.clone();
+            transfer(insn, &mut live);
+        }
+        result
+    }
+}
+
+/// Is this a call, which saves the return address in ra?
+pub fn is_call<R: Copy + PartialEq + From<Register>>(insn: &Instruction<R>) -> bool {
+    matches!(
+        insn,
+        Instruction::Jal { dst, .. } | Instruction::Jalr { dst, .. } if *dst == R::from(Ra)
+    )
+}
+
+/// The registers an instruction may write, including the ones a call may
+/// change.
+pub fn defs<R: Copy + Ord + From<Register>>(insn: &Instruction<R>) -> Set<R> {
+    let mut defs = insn.def().into_iter().collect::<Set<_>>();
+    if is_call(insn) {
+        defs.extend(CALLER_SAVED.map(R::from));
+    }
+    defs.remove(&R::from(Zero));
+    defs
+}
+
+/// The registers an instruction may read, including the arguments of a call.
+pub fn uses<R: Copy + Ord + From<Register>>(insn: &Instruction<R>) -> Set<R> {
+    let mut uses = insn.uses().into_iter().collect::<Set<_>>();
+    if is_call(insn) {
+        uses.extend(ARGUMENTS.map(R::from));
+    }
+    uses.remove(&R::from(Zero));
+    uses
+}
+
+/// The labels each block may continue at.  The last block, and any block
+/// that jumps to the epilogue, continues at [EPILOGUE].
+pub fn successors<R: Copy + PartialEq + From<Register>>(func: &Function<R>) -> Map<Id, Vec<Id>> {
+    let blocks = &func.basic_blocks;
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| {
+            let mut succ = vec![];
+            for insn in &block.instructions {
+                match insn {
+                    Instruction::Jal {
+                        target: JumpTarget::Local(l),
+                        ..
+                    }
+                    | Instruction::Branch {
+                        target: JumpTarget::Local(l),
+                        ..
+                    } if !succ.contains(l) => succ.push(*l),
+                    _ => {}
+                }
+            }
+            let falls_through = !matches!(
+                block.instructions.last(),
+                Some(Instruction::Jal { dst, .. }) if *dst == R::from(Zero)
+            );
+            if falls_through {
+                let next = blocks.get(i + 1).map_or(Id::from_ref(EPILOGUE), |b| b.id);
+                if !succ.contains(&next) {
+                    succ.push(next);
+                }
+            }
+            (block.id, succ)
+        })
+        .collect()
+}
+
+/// Propagate liveness backwards through one instruction.
+fn transfer<R: Copy + Ord + From<Register>>(insn: &Instruction<R>, live: &mut Set<R>) {
+    for d in defs(insn) {
+        live.remove(&d);
+    }
+    live.extend(uses(insn));
+}
+
+/// Compute the registers live at the start of a block given the registers
+/// live at its end.
+fn live_before<R: Copy + Ord + From<Register>>(block: &BasicBlock<R>, mut live: Set<R>) -> Set<R> {
+    for insn in block.instructions.iter().rev() {
+        transfer(insn, &mut live);
+    }
+    live
+}
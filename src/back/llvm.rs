@@ -0,0 +1,368 @@
+//! The LLVM IR backend.
+//!
+//! This lowers a tiny IR program to textual LLVM IR, so its output can be
+//! compared with what `opt` makes of it, and compiled by `llc` or `clang` for
+//! any architecture LLVM supports.  The module has no data layout, and no
+//! target triple unless it's generated for a target (see
+//! [crate::common::TargetSpec]), so those come from the command line.
+//!
+//! Each variable lives in a stack slot (`%x.addr` for `x`) that the entry
+//! block allocates and clears, and instructions load their operands from the
+//! slots and store their results to them.  That keeps the output close to the
+//! tiny IR, and `opt -passes=mem2reg` turns it into SSA form.  Addresses are
+//! `i64`s like the other values, and loads and stores convert them to
+//! pointers.
+//!
+//! The program links with the same runtime as the assembly output: reads,
+//! prints, and allocations call it, and profile counters are an array that
+//! the runtime's hook dumps when the program exits.  `main` returns an `i32`
+//! like C's, and other functions exit by calling the C library's `exit`.
+//! Division follows smol's semantics, which would be undefined behavior for
+//! `sdiv`, so it goes through a helper function.
+
+use crate::back::asm::{ALLOC_FN, EXIT_FN, PRINT_FN, PROFILE_DUMP_FN, READ_FN};
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::{ssa, tir};
+
+/// The division function of the module.  Tiny IR names can't contain dots,
+/// so it doesn't clash with any function of the program.
+const DIV_FN: &str = "@smol.div";
+
+/// The global array of profile counters.
+const COUNTERS: &str = "@smol.counters";
+
+/// Division by zero gives -1, and dividing the smallest value by -1 wraps
+/// around.
+const DIV_DEFINITION: &str = "define internal i64 @smol.div(i64 %lhs, i64 %rhs) {
+start:
+  %by_zero = icmp eq i64 %rhs, 0
+  %by_minus_one = icmp eq i64 %rhs, -1
+  %special = or i1 %by_zero, %by_minus_one
+  br i1 %special, label %special.case, label %divide
+special.case:
+  %negated = sub i64 0, %lhs
+  %result = select i1 %by_zero, i64 -1, i64 %negated
+  ret i64 %result
+divide:
+  %quotient = sdiv i64 %lhs, %rhs
+  ret i64 %quotient
+}";
+
+/// Generate the LLVM IR of a program for a target, in the text format, with
+/// the target triple of LLVM for it.
+pub fn llvm_code_for(program: tir::Program, target: &TargetSpec) -> String {
+    format!(
+        "target triple = \"{}\"\n\n{}",
+        target.llvm_triple,
+        llvm_code(program)
+    )
+}
+
+/// Generate the LLVM IR of a program, in the text format.
+pub fn llvm_code(program: tir::Program) -> String {
+    let mut out = String::new();
+    let mut line = |s: String| {
+        out.push_str(&s);
+        out.push('\n');
+    };
+
+    let counters = program.num_counters();
+    line(format!("declare void @{PRINT_FN}(i64)"));
+    line(format!("declare i64 @{READ_FN}()"));
+    line(format!("declare i64 @{ALLOC_FN}(i64)"));
+    line(format!("declare void @{PROFILE_DUMP_FN}(ptr, i64)"));
+    line(format!("declare void @{EXIT_FN}(i32) noreturn"));
+    if counters > 0 {
+        line("".into());
+        line(format!(
+            "{COUNTERS} = internal global [{counters} x i64] zeroinitializer"
+        ));
+    }
+    line("".into());
+    line(DIV_DEFINITION.into());
+    // `main` comes first, and the others in the order of their names.
+    let mut names = program.func.keys().copied().collect::<Vec<_>>();
+    names.sort_by_key(|f| *f != tir::Program::main());
+    for name in names {
+        let func = &program.func[&name];
+        let func = if func
+            .block
+            .values()
+            .flat_map(|b| &b.insn)
+            .any(|i| i.is_phi())
+        {
+            ssa::destruct_function(func.clone())
+        } else {
+            func.clone()
+        };
+        let mut gen = Gen {
+            func: &func,
+            is_main: name == tir::Program::main(),
+            counters,
+            next: 0,
+            code: vec![],
+        };
+        line("".into());
+        for s in gen.function(name) {
+            line(s);
+        }
+    }
+    out
+}
+
+/// The state of generating a function, which has no phis.
+struct Gen<'a> {
+    func: &'a tir::Function,
+    /// Is this `main`, which returns an `i32` and ends the program by
+    /// returning?
+    is_main: bool,
+    /// The number of profile counters.
+    counters: u32,
+    /// The number of the next unnamed value.
+    next: u32,
+    /// The lines of the function so far.
+    code: Vec<String>,
+}
+
+impl Gen<'_> {
+    /// A fresh unnamed value.  LLVM wants them numbered in order, starting
+    /// after the parameters.
+    fn fresh(&mut self) -> String {
+        self.next += 1;
+        format!("%{}", self.next - 1)
+    }
+
+    fn emit(&mut self, s: String) {
+        self.code.push(format!("  {s}"));
+    }
+
+    /// The stack slot of a variable.
+    fn slot(&self, v: &tir::ValueId) -> String {
+        format!("%{}.addr", self.func.names.value(*v))
+    }
+
+    fn label(&self, b: &tir::BlockId) -> String {
+        format!("%{}", self.func.names.block(*b))
+    }
+
+    /// Load a variable from its slot.
+    fn get(&mut self, v: &tir::ValueId) -> String {
+        let value = self.fresh();
+        let slot = self.slot(v);
+        self.emit(format!("{value} = load i64, ptr {slot}"));
+        value
+    }
+
+    /// Store a value to the slot of a variable.
+    fn set(&mut self, v: &tir::ValueId, value: String) {
+        let slot = self.slot(v);
+        self.emit(format!("store i64 {value}, ptr {slot}"));
+    }
+
+    /// The lines of the function `name`.
+    fn function(&mut self, name: Id) -> Vec<String> {
+        let func = self.func;
+        let params = func.params.len() as u32;
+        let header = if self.is_main {
+            format!("define i32 @{name}() {{")
+        } else {
+            let params = (0..params).map(|i| format!("i64 %{i}")).collect::<Vec<_>>();
+            format!("define i64 @{name}({}) {{", params.join(", "))
+        };
+        // The entry block is unnamed, so it takes the number after the
+        // parameters.
+        self.next = params + 1;
+        for v in (0..func.names.num_values() as u32).map(tir::ValueId) {
+            let slot = self.slot(&v);
+            self.emit(format!("{slot} = alloca i64"));
+        }
+        // Every value has a slot, even the temporaries that no declaration
+        // names, and they start out as zero except for the parameters.
+        for v in (0..func.names.num_values() as u32).map(tir::ValueId) {
+            let value = match func.params.iter().position(|p| *p == v) {
+                Some(i) => format!("%{i}"),
+                None => "0".into(),
+            };
+            self.set(&v, value);
+        }
+        let entry = self.label(&tir::Function::entry());
+        self.emit(format!("br label {entry}"));
+        for (b, block) in &func.block {
+            self.code.push(format!("{}:", &self.label(b)[1..]));
+            for insn in &block.insn {
+                self.instruction(insn);
+            }
+            for t in &block.term {
+                self.terminator(t);
+            }
+        }
+        let mut code = vec![header];
+        code.append(&mut self.code);
+        code.push("}".into());
+        code
+    }
+
+    fn instruction(&mut self, insn: &tir::Instruction) {
+        use tir::Instruction::*;
+
+        match insn {
+            Copy { dst, src } => {
+                let value = self.get(src);
+                self.set(dst, value);
+            }
+            Const { dst, src } => self.set(dst, src.to_string()),
+            Arith { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.get(lhs), self.get(rhs));
+                let result = self.fresh();
+                match op {
+                    BOp::Add => self.emit(format!("{result} = add i64 {lhs}, {rhs}")),
+                    BOp::Sub => self.emit(format!("{result} = sub i64 {lhs}, {rhs}")),
+                    BOp::Mul => self.emit(format!("{result} = mul i64 {lhs}, {rhs}")),
+                    BOp::Div => self.emit(format!(
+                        "{result} = call i64 {DIV_FN}(i64 {lhs}, i64 {rhs})"
+                    )),
+                    BOp::Lt => {
+                        self.emit(format!("{result} = icmp slt i64 {lhs}, {rhs}"));
+                        let extended = self.fresh();
+                        self.emit(format!("{extended} = zext i1 {result} to i64"));
+                        self.set(dst, extended);
+                        return;
+                    }
+                }
+                self.set(dst, result);
+            }
+            Shift {
+                op,
+                dst,
+                src,
+                amount,
+            } => {
+                let src = self.get(src);
+                let result = self.fresh();
+                let op = match op {
+                    tir::ShiftOp::Left => "shl",
+                    tir::ShiftOp::Arith => "ashr",
+                    tir::ShiftOp::Logical => "lshr",
+                };
+                self.emit(format!("{result} = {op} i64 {src}, {amount}"));
+                self.set(dst, result);
+            }
+            Read(dst) => {
+                let result = self.fresh();
+                self.emit(format!("{result} = call i64 @{READ_FN}()"));
+                self.set(dst, result);
+            }
+            Print(src) => {
+                let src = self.get(src);
+                self.emit(format!("call void @{PRINT_FN}(i64 {src})"));
+            }
+            Phi { .. } => unreachable!("phis are removed before code generation"),
+            Call { dst, callee, args } => {
+                let args = args
+                    .iter()
+                    .map(|a| format!("i64 {}", self.get(a)))
+                    .collect::<Vec<_>>();
+                let result = self.fresh();
+                self.emit(format!(
+                    "{result} = call i64 @{callee}({})",
+                    args.join(", ")
+                ));
+                self.set(dst, result);
+            }
+            Alloc { dst, size } => {
+                let size = self.get(size);
+                let result = self.fresh();
+                self.emit(format!("{result} = call i64 @{ALLOC_FN}(i64 {size})"));
+                self.set(dst, result);
+            }
+            Load { dst, addr, offset } => {
+                let pointer = self.pointer(addr, *offset);
+                let result = self.fresh();
+                self.emit(format!("{result} = load i64, ptr {pointer}"));
+                self.set(dst, result);
+            }
+            Store { addr, offset, src } => {
+                let pointer = self.pointer(addr, *offset);
+                let src = self.get(src);
+                self.emit(format!("store i64 {src}, ptr {pointer}"));
+            }
+            Count(k) => {
+                let counter = format!(
+                    "getelementptr inbounds ([{} x i64], ptr {COUNTERS}, i64 0, i64 {k})",
+                    self.counters
+                );
+                let old = self.fresh();
+                self.emit(format!("{old} = load i64, ptr {counter}"));
+                let new = self.fresh();
+                self.emit(format!("{new} = add i64 {old}, 1"));
+                self.emit(format!("store i64 {new}, ptr {counter}"));
+            }
+            Loc(_) => {}
+        }
+    }
+
+    /// The pointer to the word at `addr + offset`.
+    fn pointer(&mut self, addr: &tir::ValueId, offset: i64) -> String {
+        let mut addr = self.get(addr);
+        if offset != 0 {
+            let sum = self.fresh();
+            self.emit(format!("{sum} = add i64 {addr}, {offset}"));
+            addr = sum;
+        }
+        let pointer = self.fresh();
+        self.emit(format!("{pointer} = inttoptr i64 {addr} to ptr"));
+        pointer
+    }
+
+    fn terminator(&mut self, t: &tir::Terminator) {
+        use tir::Terminator::*;
+
+        match t {
+            Jump(target) => {
+                let target = self.label(target);
+                self.emit(format!("br label {target}"));
+            }
+            Branch { guard, tt, ff } => {
+                let guard = self.get(guard);
+                let cond = self.fresh();
+                self.emit(format!("{cond} = icmp ne i64 {guard}, 0"));
+                let (tt, ff) = (self.label(tt), self.label(ff));
+                self.emit(format!("br i1 {cond}, label {tt}, label {ff}"));
+            }
+            Exit(status) => {
+                let status = match status {
+                    Some(status) => {
+                        let value = self.get(status);
+                        let status = self.fresh();
+                        self.emit(format!("{status} = trunc i64 {value} to i32"));
+                        status
+                    }
+                    None => "0".to_string(),
+                };
+                if self.counters > 0 {
+                    self.emit(format!(
+                        "call void @{PROFILE_DUMP_FN}(ptr {COUNTERS}, i64 {})",
+                        self.counters
+                    ));
+                }
+                if self.is_main {
+                    self.emit(format!("ret i32 {status}"));
+                } else {
+                    self.emit(format!("call void @{EXIT_FN}(i32 {status})"));
+                    self.emit("unreachable".into());
+                }
+            }
+            Return(value) if self.is_main => {
+                let value = self.get(value);
+                let status = self.fresh();
+                self.emit(format!("{status} = trunc i64 {value} to i32"));
+                self.emit(format!("ret i32 {status}"));
+            }
+            Return(value) => {
+                let value = self.get(value);
+                self.emit(format!("ret i64 {value}"));
+            }
+        }
+    }
+}
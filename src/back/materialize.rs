@@ -0,0 +1,75 @@
+//! Materialization of 64-bit constants.
+//!
+//! RISC-V has no instruction that loads a 64-bit immediate.  A 32-bit
+//! constant takes a `lui` of its upper 20 bits and an `addiw` of its lower 12
+//! bits.  Since `addiw` sign-extends the lower 12 bits, the upper part is
+//! rounded so that adding them gives the constant back.
+//!
+//! A larger constant is built from its upper bits: we split off the lower 12
+//! bits, build the rest, shifted right past its trailing zeros, recursively,
+//! then shift it back into place with `slli`, and add the lower bits with
+//! `addi`.  Skipping the trailing zeros keeps the sequences short for
+//! constants like `1 << 40`, which take two instructions rather than five.
+//! This is the algorithm LLVM uses, without its special cases, and it takes
+//! at most eight instructions.
+
+use crate::back::asm::*;
+
+/// The instructions that load `value` into `dst`, using no other registers.
+pub fn materialize<R: Copy + From<Register>>(dst: R, value: i64) -> Vec<Instruction<R>> {
+    let mut code = vec![];
+    build(dst, value, &mut code);
+    code
+}
+
+fn build<R: Copy + From<Register>>(dst: R, value: i64, code: &mut Vec<Instruction<R>>) {
+    let lo12 = sign_extend(value, 12);
+    if i32::try_from(value).is_ok() {
+        let hi20 = ((value + 0x800) >> 12) & 0xfffff;
+        if hi20 != 0 {
+            code.push(Instruction::Lui {
+                dst,
+                imm: hi20 as i32,
+            });
+        }
+        if lo12 != 0 || hi20 == 0 {
+            let (op, lhs) = if hi20 != 0 {
+                (ArithOp::AddW, dst)
+            } else {
+                (ArithOp::Add, Register::Zero.into())
+            };
+            code.push(Instruction::ArithI {
+                op,
+                dst,
+                lhs,
+                rhs: lo12 as i32,
+            });
+        }
+        return;
+    }
+
+    // Round the upper bits like `lui` does, and drop their trailing zeros.
+    // The value doesn't fit in 32 bits, so they aren't all zero.
+    let hi52 = ((value as u64).wrapping_add(0x800) >> 12) as i64;
+    let shift = 12 + hi52.trailing_zeros();
+    build(dst, sign_extend(hi52 >> (shift - 12), 64 - shift), code);
+    code.push(Instruction::ArithI {
+        op: ArithOp::Sll,
+        dst,
+        lhs: dst,
+        rhs: shift as i32,
+    });
+    if lo12 != 0 {
+        code.push(Instruction::ArithI {
+            op: ArithOp::Add,
+            dst,
+            lhs: dst,
+            rhs: lo12 as i32,
+        });
+    }
+}
+
+/// Sign-extend the lower `bits` bits of `value`.
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    (value << (64 - bits)) >> (64 - bits)
+}
@@ -0,0 +1,598 @@
+//! A parser for the assembly output.
+//!
+//! This reads the assembly code that [Program::asm_code_with] emits back into
+//! a [Program], so that tests can check that emitting round-trips, and
+//! backend tests can start from `.s` fixtures.  It reads the functions of the
+//! program and the global variables, and skips everything else: directives,
+//! and the functions of the runtime that some targets bring, which have no
+//! epilogue.  The target is RV32 if the prologues store words, and RV64
+//! otherwise.
+//!
+//! Emitting a parsed program gives back the same code, but some details of
+//! the original program are lost:
+//! - The frame size is rounded up to 16 bytes, so the stack space of a
+//!   function is what the frame has beyond its saved registers.
+//! - Instructions that the output expands come back as what they expand to.
+//!   For example, subtracting an immediate becomes adding its negation, and
+//!   multiplying by an immediate becomes `li` and `mul`.
+//! - Stack maps have no live registers, which register allocation removes
+//!   anyway, and calls to exit without a C library call
+//!   [STANDALONE_EXIT_FN].
+//! - The initialized globals come before the zeroed ones.
+//! - The functions don't know where the variables of the tiny IR live.
+
+use std::fmt::Debug;
+
+use derive_more::derive::Display;
+
+use crate::back::asm::*;
+use crate::common::*;
+
+#[derive(Display)]
+#[display("Assembly parse error: {}", self.0)]
+pub struct ParseError(String);
+
+impl Debug for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+fn error<T>(msg: String) -> ParseResult<T> {
+    Err(ParseError(msg))
+}
+
+/// The prefix of the labels of global variables.
+const GLOBAL_PREFIX: &str = ".Lglobal.";
+
+/// The prefix of the labels of the return addresses in stack maps.
+const STACK_MAP_PREFIX: &str = "$gc";
+
+const ARITH_OPS: [ArithOp; 19] = [
+    ArithOp::Add,
+    ArithOp::Sub,
+    ArithOp::Mul,
+    ArithOp::MulH,
+    ArithOp::Div,
+    ArithOp::Slt,
+    ArithOp::And,
+    ArithOp::Or,
+    ArithOp::Xor,
+    ArithOp::Srl,
+    ArithOp::Sra,
+    ArithOp::Sll,
+    ArithOp::AddW,
+    ArithOp::Sh1Add,
+    ArithOp::Sh2Add,
+    ArithOp::Sh3Add,
+    ArithOp::AndN,
+    ArithOp::Min,
+    ArithOp::Max,
+];
+
+const CONDITIONS: [Condition; 6] = [
+    Condition::Equal,
+    Condition::NotEqual,
+    Condition::Less,
+    Condition::LessEq,
+    Condition::Greater,
+    Condition::GreaterEq,
+];
+
+const F_ARITH_OPS: [FArithOp; 4] = [FArithOp::Add, FArithOp::Sub, FArithOp::Mul, FArithOp::Div];
+
+const F_CMP_OPS: [FCmpOp; 3] = [FCmpOp::Equal, FCmpOp::Less, FCmpOp::LessEq];
+
+/// Parse the assembly code of a program.
+pub fn parse(text: &str) -> ParseResult<Program> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect::<Vec<_>>();
+    let target = if lines.iter().any(|(_, line)| line.starts_with("sw ra, ")) {
+        Target::Riscv32
+    } else {
+        Target::Riscv64
+    };
+    let globals = parse_globals(&lines, target)?;
+    let mut parser = Parser {
+        files: vec![],
+        globals: &globals,
+        func: Id::from_ref(""),
+    };
+    let mut functions = vec![];
+    let mut section = ".text";
+    let mut i = 0;
+    while i < lines.len() {
+        let (number, line) = lines[i];
+        i += 1;
+        if let Some(rest) = line.strip_prefix(".file ") {
+            let at = |e: ParseError| ParseError(format!("Line {number}: {}", e.0));
+            parser
+                .files
+                .push(parse_file(rest, parser.files.len()).map_err(at)?);
+        } else if matches!(line, ".text" | ".data" | ".bss") {
+            section = line;
+        } else if let Some(name) = line.strip_prefix(".type ") {
+            let Some(name) = name.strip_suffix(", @function") else {
+                continue;
+            };
+            if section != ".text" {
+                continue;
+            }
+            // The function goes from its label to its `.size`.
+            let end = format!(".size {name}, .-{name}");
+            let Some(len) = lines[i..].iter().position(|(_, l)| *l == end) else {
+                return error(format!("Line {number}: Function `{name}` has no end."));
+            };
+            let body = &lines[i..i + len];
+            i += len + 1;
+            match body.first() {
+                Some((_, label)) if *label == format!("{name}:") => {}
+                _ => return error(format!("Line {number}: Function `{name}` has no label.")),
+            }
+            parser.func = Id::from_ref(name);
+            if let Some(func) = parser.function(&body[1..])? {
+                functions.push(func);
+            }
+        }
+    }
+    Ok(Program {
+        functions,
+        globals,
+        target,
+    })
+}
+
+/// Parse the operands of a `.file` directive, which should number the files
+/// in order from 1, given how many come before it.
+fn parse_file(operands: &str, before: usize) -> ParseResult<Id> {
+    let (number, name) = operands
+        .split_once(' ')
+        .ok_or_else(|| ParseError(format!("Malformed `.file {operands}`.")))?;
+    if number.parse() != Ok(before + 1) {
+        return error(format!("File {number} is out of order."));
+    }
+    let name = name
+        .strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .ok_or_else(|| ParseError(format!("The file name {name} isn't quoted.")))?;
+    Ok(Id::new(name.replace("\\\"", "\"").replace("\\\\", "\\")))
+}
+
+/// Parse the global variables in the `.data` and `.bss` sections.
+fn parse_globals(lines: &[(usize, &str)], target: Target) -> ParseResult<Vec<GlobalVar>> {
+    let word = target.word_size() as usize;
+    let mut globals: Vec<GlobalVar> = vec![];
+    // Is the last label a global variable's?
+    let mut in_global = false;
+    let mut section = ".text";
+    for (number, line) in lines {
+        let at = |msg: String| ParseError(format!("Line {number}: {msg}"));
+        if matches!(*line, ".text" | ".data" | ".bss") {
+            section = line;
+            in_global = false;
+        } else if section == ".text" {
+            continue;
+        } else if let Some(label) = line.strip_suffix(':') {
+            in_global = label.starts_with(GLOBAL_PREFIX);
+            if in_global {
+                globals.push(GlobalVar {
+                    id: Id::from_ref(&label[GLOBAL_PREFIX.len()..]),
+                    init: vec![],
+                });
+            }
+        } else if in_global {
+            let global = globals.last_mut().unwrap();
+            let (directive, value) = line
+                .split_once(' ')
+                .ok_or_else(|| at(format!("Unexpected `{line}` in a global variable.")))?;
+            let value = value
+                .parse::<i64>()
+                .map_err(|_| at(format!("Malformed number `{value}`.")))?;
+            match directive {
+                ".dword" | ".word" => global.init.push(value),
+                ".zero" => global.init.extend(vec![0; value as usize / word]),
+                _ => return Err(at(format!("Unexpected `{line}` in a global variable."))),
+            }
+        }
+    }
+    Ok(globals)
+}
+
+/// The state of parsing the functions.
+struct Parser<'a> {
+    /// The source files of `.loc` directives, in the order of their numbers.
+    files: Vec<Id>,
+    /// The global variables.
+    globals: &'a [GlobalVar],
+    /// The name of the current function.
+    func: Id,
+}
+
+impl Parser<'_> {
+    /// Parse the lines of a function after its label, or return `None` if
+    /// it has no epilogue, which makes it a function of the runtime.
+    fn function(&self, lines: &[(usize, &str)]) -> ParseResult<Option<Function>> {
+        let label = |line: &str| line.strip_suffix(':').and_then(|l| self.block(l));
+        let epilogue = Id::from_ref(EPILOGUE);
+        if !lines.iter().any(|(_, line)| label(line) == Some(epilogue)) {
+            return Ok(None);
+        }
+        let start = lines
+            .iter()
+            .position(|(_, line)| label(line).is_some())
+            .unwrap();
+        let prologue = self.instructions(&lines[..start])?;
+        let mut basic_blocks: Vec<BasicBlock> = vec![];
+        for (number, line) in &lines[start..] {
+            match label(line) {
+                Some(id) if id == epilogue => break,
+                Some(id) if id.starts_with(STACK_MAP_PREFIX) => {}
+                Some(id) => basic_blocks.push(BasicBlock {
+                    id,
+                    instructions: vec![],
+                }),
+                None => {
+                    let insn = self
+                        .instruction(line)
+                        .map_err(|ParseError(msg)| ParseError(format!("Line {number}: {msg}")))?;
+                    basic_blocks.last_mut().unwrap().instructions.push(insn);
+                }
+            }
+        }
+        let mut func = Function {
+            id: self.func,
+            basic_blocks,
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        };
+        let frame = frame(&prologue, &mut func.used_registers);
+        let saved = func.used_registers.len() + func.saved_fp_registers().len();
+        func.stack_space = frame - SLOT_SIZE * saved as i32;
+        Ok(Some(func))
+    }
+
+    fn instructions(&self, lines: &[(usize, &str)]) -> ParseResult<Vec<Instruction>> {
+        lines
+            .iter()
+            .map(|(number, line)| {
+                self.instruction(line)
+                    .map_err(|ParseError(msg)| ParseError(format!("Line {number}: {msg}")))
+            })
+            .collect()
+    }
+
+    /// Parse an instruction in the syntax of the GNU assembler.
+    fn instruction(&self, line: &str) -> ParseResult<Instruction> {
+        use Instruction::*;
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let comment = comment.strip_prefix(' ').unwrap_or(comment);
+            return Ok(match comment.strip_prefix("heap pointers:") {
+                Some(slots) => StackMap {
+                    live: vec![],
+                    slots: slots
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(|slot| match memory_offset(slot) {
+                            Some((offset, "fp")) => Ok(offset),
+                            _ => error(format!("Malformed stack slot `{slot}`.")),
+                        })
+                        .collect::<ParseResult<_>>()?,
+                },
+                None => Comment(comment.to_string()),
+            });
+        }
+        let (mnemonic, operands) = line.split_once(' ').unwrap_or((line, ""));
+        let ops = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let arity = |n: usize| {
+            if ops.len() == n {
+                Ok(())
+            } else {
+                error(format!("`{mnemonic}` takes {n} operands: `{line}`."))
+            }
+        };
+        let reg = |i: usize| register(ops[i]);
+        let freg = |i: usize| f_register(ops[i]);
+        let insn = match mnemonic {
+            ".loc" => {
+                let numbers = operands
+                    .split(' ')
+                    .map(|n| n.parse::<u32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| ParseError(format!("Malformed `{line}`.")))?;
+                let [file, row, column] = numbers[..] else {
+                    return error(format!("Malformed `{line}`."));
+                };
+                let file = (file as usize)
+                    .checked_sub(1)
+                    .and_then(|i| self.files.get(i))
+                    .ok_or_else(|| ParseError(format!("Unknown file {file}.")))?;
+                Loc {
+                    file: *file,
+                    line: row,
+                    column,
+                }
+            }
+            "la" | "lla" => {
+                arity(2)?;
+                La {
+                    dst: reg(0)?,
+                    src: self.memory(ops[1])?,
+                }
+            }
+            "ld" | "lw" => {
+                arity(2)?;
+                Ld {
+                    dst: reg(0)?,
+                    src: self.memory(ops[1])?,
+                }
+            }
+            // Stores to globals name a scratch register last.
+            "sd" | "sw" => {
+                if ops.len() != 3 {
+                    arity(2)?;
+                }
+                Sd {
+                    dst: self.memory(ops[1])?,
+                    src: reg(0)?,
+                }
+            }
+            "fld" => {
+                if ops.len() != 3 {
+                    arity(2)?;
+                }
+                Fld {
+                    dst: freg(0)?,
+                    src: self.memory(ops[1])?,
+                }
+            }
+            "fsd" => {
+                if ops.len() != 3 {
+                    arity(2)?;
+                }
+                Fsd {
+                    dst: self.memory(ops[1])?,
+                    src: freg(0)?,
+                }
+            }
+            "li" => {
+                arity(2)?;
+                Li {
+                    dst: reg(0)?,
+                    imm: number(ops[1])?,
+                }
+            }
+            "lui" => {
+                arity(2)?;
+                let imm = ops[1]
+                    .strip_prefix("0x")
+                    .and_then(|hex| i32::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| ParseError(format!("Malformed immediate `{}`.", ops[1])))?;
+                Lui { dst: reg(0)?, imm }
+            }
+            "call" => {
+                arity(1)?;
+                let callee = ops[0].strip_suffix("@plt").unwrap_or(ops[0]);
+                Instruction::call(Id::from_ref(callee))
+            }
+            "jal" => {
+                arity(2)?;
+                Jal {
+                    dst: reg(0)?,
+                    target: self.target(ops[1]),
+                }
+            }
+            "jalr" => {
+                arity(2)?;
+                let (0, target) = memory_offset(ops[1])
+                    .ok_or_else(|| ParseError(format!("Malformed target `{}`.", ops[1])))?
+                else {
+                    return error(format!("Malformed target `{}`.", ops[1]));
+                };
+                Jalr {
+                    dst: reg(0)?,
+                    target: register(target)?,
+                }
+            }
+            "fmv.d" => {
+                arity(2)?;
+                FMv {
+                    dst: freg(0)?,
+                    src: freg(1)?,
+                }
+            }
+            "fcvt.d.l" | "fcvt.d.w" => {
+                arity(2)?;
+                FCvtFromInt {
+                    dst: freg(0)?,
+                    src: reg(1)?,
+                }
+            }
+            "fcvt.l.d" | "fcvt.w.d" => {
+                arity(3)?;
+                FCvtToInt {
+                    dst: reg(0)?,
+                    src: freg(1)?,
+                }
+            }
+            _ => {
+                if let Some(op) = ARITH_OPS.iter().find(|op| op.to_string() == mnemonic) {
+                    arity(3)?;
+                    Arith {
+                        op: *op,
+                        dst: reg(0)?,
+                        lhs: reg(1)?,
+                        rhs: reg(2)?,
+                    }
+                } else if let Some(op) = ARITH_OPS.iter().find(|op| op.immediate_form() == mnemonic)
+                {
+                    arity(3)?;
+                    ArithI {
+                        op: *op,
+                        dst: reg(0)?,
+                        lhs: reg(1)?,
+                        rhs: number(ops[2])?,
+                    }
+                } else if let Some(cond) = CONDITIONS
+                    .iter()
+                    .find(|cond| format!("s{cond}z") == mnemonic)
+                {
+                    arity(2)?;
+                    SCmpZ {
+                        dst: reg(0)?,
+                        lhs: reg(1)?,
+                        cond: *cond,
+                    }
+                } else if let Some(cond) = CONDITIONS
+                    .iter()
+                    .find(|cond| format!("b{cond}") == mnemonic)
+                {
+                    arity(3)?;
+                    Branch {
+                        cond: *cond,
+                        lhs: reg(0)?,
+                        rhs: reg(1)?,
+                        target: self.target(ops[2]),
+                    }
+                } else if let Some(op) = F_ARITH_OPS.iter().find(|op| op.to_string() == mnemonic) {
+                    arity(3)?;
+                    FArith {
+                        op: *op,
+                        dst: freg(0)?,
+                        lhs: freg(1)?,
+                        rhs: freg(2)?,
+                    }
+                } else if let Some(op) = F_CMP_OPS.iter().find(|op| op.to_string() == mnemonic) {
+                    arity(3)?;
+                    FCmp {
+                        op: *op,
+                        dst: reg(0)?,
+                        lhs: freg(1)?,
+                        rhs: freg(2)?,
+                    }
+                } else {
+                    return error(format!("Unknown instruction `{line}`."));
+                }
+            }
+        };
+        Ok(insn)
+    }
+
+    /// Parse a memory operand: an offset from a register, or the label of a
+    /// global variable with an optional offset.
+    fn memory(&self, operand: &str) -> ParseResult<Memory> {
+        if let Some((offset, base)) = memory_offset(operand) {
+            return Ok(Memory::Mem(register(base)?, offset));
+        }
+        let malformed = || ParseError(format!("Malformed memory operand `{operand}`."));
+        let label = operand.strip_prefix(GLOBAL_PREFIX).ok_or_else(malformed)?;
+        let (name, offset) = match label.find(['+', '-']) {
+            Some(i) => (&label[..i], label[i..].parse().map_err(|_| malformed())?),
+            None => (label, 0),
+        };
+        let index = self
+            .globals
+            .iter()
+            .position(|g| g.id.as_str() == name)
+            .ok_or_else(|| ParseError(format!("Unknown global variable `{name}`.")))?;
+        Ok(Memory::Global { index, offset })
+    }
+
+    /// The target of a jump or a branch.  The labels of the current
+    /// function's blocks are local, and the rest are global.
+    fn target(&self, label: &str) -> JumpTarget {
+        match self.block(label) {
+            Some(block) => JumpTarget::Local(block),
+            None => JumpTarget::Global(Id::from_ref(label)),
+        }
+    }
+
+    /// The block of the current function with the given label, if it is one.
+    fn block(&self, label: &str) -> Option<Id> {
+        unmangle_label(label)
+            .filter(|(func, _)| *func == self.func)
+            .map(|(_, block)| block)
+    }
+}
+
+/// The size of the frame that the instructions of a prologue reserve, after
+/// saving the return address and the frame pointer.  The callee-saved
+/// registers that the prologue saves are added to `saved`.
+fn frame(prologue: &[Instruction], saved: &mut Vec<Register>) -> i32 {
+    use Instruction::*;
+    use Register::*;
+
+    let rest = prologue.get(4..).unwrap_or_default();
+    let (size, rest) = match rest {
+        [ArithI {
+            op: ArithOp::Add,
+            dst: Sp,
+            lhs: Sp,
+            rhs,
+        }, rest @ ..] => (-rhs, rest),
+        [Li { dst, imm }, Arith {
+            op: ArithOp::Add,
+            dst: Sp,
+            lhs: Sp,
+            rhs,
+        }, rest @ ..]
+            if dst == rhs =>
+        {
+            (-*imm as i32, rest)
+        }
+        _ => (0, rest),
+    };
+    for insn in rest {
+        if let Sd {
+            dst: Memory::Mem(Sp, _),
+            src,
+        } = insn
+        {
+            saved.push(*src);
+        }
+    }
+    size
+}
+
+/// Split an operand of the form `offset(register)`.
+fn memory_offset(operand: &str) -> Option<(i32, &str)> {
+    let (offset, rest) = operand.split_once('(')?;
+    Some((offset.parse().ok()?, rest.strip_suffix(')')?))
+}
+
+fn number<T: std::str::FromStr>(operand: &str) -> ParseResult<T> {
+    operand
+        .parse()
+        .map_err(|_| ParseError(format!("Malformed number `{operand}`.")))
+}
+
+/// An integer register by its ABI name.  `s0` is another name of `fp`.
+fn register(name: &str) -> ParseResult<Register> {
+    if name == "s0" {
+        return Ok(Register::Fp);
+    }
+    Register::ALL
+        .into_iter()
+        .find(|r| r.to_string() == name)
+        .ok_or_else(|| ParseError(format!("Unknown register `{name}`.")))
+}
+
+/// A floating-point register by its ABI name.
+fn f_register(name: &str) -> ParseResult<FRegister> {
+    FRegister::ALL
+        .into_iter()
+        .find(|r| r.to_string() == name)
+        .ok_or_else(|| ParseError(format!("Unknown floating-point register `{name}`.")))
+}
@@ -0,0 +1,287 @@
+//! Register allocation.
+//!
+//! The allocators map each virtual register to its *home*: a physical
+//! register, or a stack slot below the local variables.  A spilled register is
+//! loaded into a temporary register (t0--t2) before each instruction that
+//! reads it and stored back after each instruction that writes it.  An
+//! instruction names at most three registers, so three temporaries are
+//! enough.
+//!
+//! There are two allocators:
+//!
+//! - The simple allocator doesn't look at where registers are live.
+//!   Callee-saved registers keep their values across calls, so any virtual
+//!   register can live in one: the prologue and the epilogue save and restore
+//!   them for our caller.  We give the virtual registers that appear in the
+//!   most instructions their own callee-saved register, and spill the rest.
+//! - The graph-coloring allocator (see [coloring]) shares registers between
+//!   virtual registers that are never live at the same time, and removes
+//!   moves by giving both sides the same register.
+//!
+//! Either way, the heap pointers in stack maps get stack slots, where the
+//! garbage collector can find them, and the stack maps get the offsets of
+//! the slots.
+
+use crate::back::asm::*;
+use crate::common::*;
+
+use Register::*;
+use VReg::*;
+
+mod coloring;
+
+/// The register allocators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Allocator {
+    /// Give the most used registers callee-saved registers, and spill the
+    /// rest.
+    #[default]
+    Simple,
+    /// Chaitin-Briggs graph coloring with conservative coalescing.
+    GraphColor,
+}
+
+/// The registers that hold spilled values during an instruction.
+const SPILL_TEMPS: [Register; 3] = [T0, T1, T2];
+
+/// Where a virtual register lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Home {
+    Reg(Register),
+    /// The stack slot with the given index.
+    Slot(u32),
+}
+
+/// Map the virtual registers of a program to physical registers and stack
+/// slots with the given allocator.
+pub fn allocate(program: Program<VReg>, allocator: Allocator) -> Program {
+    program.map_functions(|func| allocate_function(func, allocator))
+}
+
+/// Map the virtual registers of a function to physical registers and stack
+/// slots in its frame with the given allocator.
+pub fn allocate_function(func: Function<VReg>, allocator: Allocator) -> Function {
+    let mut home = match allocator {
+        Allocator::Simple => simple(&func),
+        Allocator::GraphColor => coloring::color(&func),
+    };
+    spill_gc_roots(&func, &mut home);
+    log::debug!(
+        "Allocated the registers of `{}` with {allocator:?}: {} stack slots for {} virtual registers.",
+        func.id,
+        num_slots(&home),
+        home.len()
+    );
+    for (v, h) in &home {
+        log::trace!("`{}`: `{}` is in {h:?}.", func.id, Virt(*v));
+    }
+    assign(func, &home)
+}
+
+/// Move the virtual registers in stack maps that got physical registers to
+/// new stack slots.
+fn spill_gc_roots(func: &Function<VReg>, home: &mut Map<u32, Home>) {
+    let mut next = num_slots(home);
+    for insn in func.basic_blocks.iter().flat_map(|b| &b.instructions) {
+        if let Instruction::StackMap { live, .. } = insn {
+            for r in live {
+                if let Virt(v) = r {
+                    if let Some(h @ Home::Reg(_)) = home.get_mut(v) {
+                        *h = Home::Slot(next);
+                        next += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How many instructions each virtual register appears in.
+fn occurrences(func: &Function<VReg>) -> Map<u32, usize> {
+    let mut count: Map<u32, usize> = Map::new();
+    for insn in func.basic_blocks.iter().flat_map(|b| &b.instructions) {
+        for r in insn.def().into_iter().chain(insn.uses()) {
+            if let Virt(v) = r {
+                *count.entry(v).or_default() += 1;
+            }
+        }
+    }
+    count
+}
+
+/// The homes the simple allocator picks.
+fn simple(func: &Function<VReg>) -> Map<u32, Home> {
+    const ALLOCATABLE: [Register; 11] = [S1, S2, S3, S4, S5, S6, S7, S8, S9, S10, S11];
+
+    let mut by_uses = occurrences(func).into_iter().collect::<Vec<_>>();
+    by_uses.sort_by_key(|(v, n)| (std::cmp::Reverse(*n), *v));
+    let mut slots = 0;
+    by_uses
+        .into_iter()
+        .enumerate()
+        .map(|(i, (v, _))| match ALLOCATABLE.get(i) {
+            Some(r) => (v, Home::Reg(*r)),
+            None => {
+                slots += 1;
+                (v, Home::Slot(slots - 1))
+            }
+        })
+        .collect()
+}
+
+/// The number of stack slots the homes take.
+fn num_slots(home: &Map<u32, Home>) -> u32 {
+    home.values()
+        .filter_map(|h| match h {
+            Home::Slot(i) => Some(*i + 1),
+            Home::Reg(_) => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Replace each virtual register with its home.  Moves between registers
+/// that got the same home disappear.
+fn assign(func: Function<VReg>, home: &Map<u32, Home>) -> Function {
+    let slots = num_slots(home) as i32;
+    let stack_space = func.stack_space;
+    let offset = |i: u32| -stack_space - 8 * (i as i32 + 1);
+    let slot = |i: u32| Memory::Mem(Fp, offset(i));
+    let basic_blocks = func
+        .basic_blocks
+        .into_iter()
+        .map(|block| BasicBlock {
+            id: block.id,
+            instructions: block
+                .instructions
+                .into_iter()
+                .filter(|insn| !is_redundant_move(insn, home))
+                .flat_map(|insn| match insn {
+                    Instruction::StackMap { live, .. } => vec![Instruction::StackMap {
+                        live: vec![],
+                        slots: live.iter().map(|r| offset(spill_slot(*r, home))).collect(),
+                    }],
+                    insn => rewrite(insn, home, slot),
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    let variables = func
+        .variables
+        .into_iter()
+        .filter_map(|(name, location)| {
+            let location = match location {
+                VarLocation::Reg(Phys(r)) => VarLocation::Reg(r),
+                VarLocation::Reg(Virt(v)) => match home.get(&v)? {
+                    Home::Reg(r) => VarLocation::Reg(*r),
+                    Home::Slot(i) => VarLocation::Stack(offset(*i)),
+                },
+                VarLocation::Stack(offset) => VarLocation::Stack(offset),
+            };
+            Some((name, location))
+        })
+        .collect();
+    Function {
+        id: func.id,
+        used_registers: saved_registers(&basic_blocks),
+        basic_blocks,
+        stack_space: stack_space + 8 * slots,
+        variables,
+    }
+}
+
+/// The stack slot of a virtual register in a stack map, which
+/// [spill_gc_roots] made sure it has.
+fn spill_slot(r: VReg, home: &Map<u32, Home>) -> u32 {
+    match r {
+        Virt(v) => match home[&v] {
+            Home::Slot(i) => i,
+            Home::Reg(_) => unreachable!("heap pointers in stack maps are spilled"),
+        },
+        Phys(_) => unreachable!("stack maps only have variables"),
+    }
+}
+
+/// The callee-saved registers that the code writes, in order, which the
+/// prologue saves and the epilogue restores.  These include the homes the
+/// allocator picked, but not the ones whose only uses were moves that
+/// disappeared, and also the ones that code generation named itself.
+fn saved_registers(blocks: &[BasicBlock]) -> Vec<Register> {
+    blocks
+        .iter()
+        .flat_map(|b| &b.instructions)
+        .filter_map(|insn| insn.def())
+        .filter(|r| is_callee_saved(*r))
+        .collect::<Set<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Callee-saved registers that the allocators may hand out, which the
+/// prologue has to save if the function writes them.
+fn is_callee_saved(r: Register) -> bool {
+    matches!(r, S1 | S2 | S3 | S4 | S5 | S6 | S7 | S8 | S9 | S10 | S11)
+}
+
+/// Is this a move whose two sides end up in the same place?
+fn is_redundant_move(insn: &Instruction<VReg>, home: &Map<u32, Home>) -> bool {
+    let place = |r: VReg| match r {
+        Phys(r) => Home::Reg(r),
+        Virt(v) => home[&v],
+    };
+    match insn {
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst,
+            lhs,
+            rhs: 0,
+        } => place(*dst) == place(*lhs),
+        _ => false,
+    }
+}
+
+/// Replace the virtual registers of an instruction with their homes, loading
+/// and storing the spilled ones around it.
+fn rewrite(
+    insn: Instruction<VReg>,
+    home: &Map<u32, Home>,
+    slot: impl Fn(u32) -> Memory,
+) -> Vec<Instruction> {
+    // The temporary register of each spilled register of the instruction.
+    let mut temps: Map<u32, Register> = Map::new();
+    let new_temp = |temps: &mut Map<u32, Register>, v: u32| {
+        let t = SPILL_TEMPS[temps.len()];
+        temps.insert(v, t);
+        t
+    };
+    let mut code = vec![];
+    for r in insn.uses() {
+        if let Virt(v) = r {
+            if let Home::Slot(i) = home[&v] {
+                if !temps.contains_key(&v) {
+                    let dst = new_temp(&mut temps, v);
+                    code.push(Instruction::Ld { dst, src: slot(i) });
+                }
+            }
+        }
+    }
+    let spilled_def = match insn.def() {
+        Some(Virt(v)) => match home[&v] {
+            Home::Slot(i) if temps.contains_key(&v) => Some((temps[&v], slot(i))),
+            Home::Slot(i) => Some((new_temp(&mut temps, v), slot(i))),
+            Home::Reg(_) => None,
+        },
+        _ => None,
+    };
+    code.push(insn.map_registers(|r| match r {
+        Phys(r) => r,
+        Virt(v) => match home[&v] {
+            Home::Reg(r) => r,
+            Home::Slot(_) => temps[&v],
+        },
+    }));
+    if let Some((src, dst)) = spilled_def {
+        code.push(Instruction::Sd { dst, src });
+    }
+    code
+}
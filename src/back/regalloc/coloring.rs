@@ -0,0 +1,289 @@
+//! Graph-coloring register allocation.
+//!
+//! This is the allocator of Chaitin, with the optimistic coloring and the
+//! conservative coalescing of Briggs.  Two registers *interfere* if one is
+//! written while the other is live, so they can't share a physical register.
+//! We build the interference graph from the liveness of the registers, then:
+//!
+//! 1. *Coalesce*: merge the two sides of a move if they don't interfere and
+//!    merging them can't make the graph harder to color.  Both sides then get
+//!    the same register, and the move disappears.  When one side is a
+//!    physical register, like the argument of a call, we use George's test
+//!    instead: every neighbor of the virtual register is either easy to
+//!    color, physical, or already interferes with the physical one.
+//! 2. *Simplify*: remove a register with fewer neighbors than there are
+//!    colors, since it can always be colored after its neighbors.  When there
+//!    is none, remove the register that is cheapest to spill for its number
+//!    of neighbors, optimistically hoping that its neighbors share colors.
+//! 3. *Select*: put the registers back in the reverse order, giving each one
+//!    a color none of its neighbors has.  Registers that find no color are
+//!    spilled.
+//!
+//! Physical registers the ABI fixes are *precolored*: they are part of the
+//! graph, but they keep their colors.  Calls write all caller-saved
+//! registers, so registers that live across a call interfere with those and
+//! end up in callee-saved registers or spilled.  We hand out the caller-saved
+//! colors first, since the callee-saved ones have to be saved by the
+//! prologue.
+
+use super::*;
+use crate::back::liveness::{self, Liveness};
+
+/// The registers we color with, in the order we prefer them.  The others are
+/// reserved: for the frame, for the return address, and as temporaries for
+/// spills and for the assembly output.
+const COLORS: [Register; 20] = [
+    A0, A1, A2, A3, A4, A5, A6, A7, T3, S1, S2, S3, S4, S5, S6, S7, S8, S9, S10, S11,
+];
+
+/// The number of colors.
+const K: usize = COLORS.len();
+
+/// Pick the home of each virtual register by coloring the interference
+/// graph.
+pub(super) fn color(func: &Function<VReg>) -> Map<u32, Home> {
+    let mut graph = Graph::build(func);
+    graph.coalesce();
+
+    // The cost of spilling a register is the number of instructions that
+    // would have to load or store it.
+    let mut cost: Map<VReg, usize> = Map::new();
+    for (v, n) in occurrences(func) {
+        *cost.entry(graph.find(Virt(v))).or_default() += n;
+    }
+
+    // SECTION: simplify
+
+    let mut degree = graph
+        .adj
+        .iter()
+        .map(|(r, adj)| (*r, adj.len()))
+        .collect::<Map<_, _>>();
+    let mut left = graph
+        .adj
+        .keys()
+        .copied()
+        .filter(|r| matches!(r, Virt(_)))
+        .collect::<Set<_>>();
+    let mut stack = vec![];
+    while !left.is_empty() {
+        let next = left
+            .iter()
+            .copied()
+            .find(|r| degree[r] < K)
+            .unwrap_or_else(|| {
+                // Compare cost / degree without dividing.
+                *left
+                    .iter()
+                    .min_by(|a, b| (cost[a] * degree[b]).cmp(&(cost[b] * degree[a])))
+                    .unwrap()
+            });
+        left.remove(&next);
+        for n in &graph.adj[&next] {
+            if left.contains(n) {
+                *degree.get_mut(n).unwrap() -= 1;
+            }
+        }
+        stack.push(next);
+    }
+
+    // SECTION: select
+
+    let mut colors: Map<VReg, Register> = Map::new();
+    let mut slots = 0;
+    let mut home: Map<VReg, Home> = Map::new();
+    while let Some(r) = stack.pop() {
+        let taken = graph.adj[&r]
+            .iter()
+            .filter_map(|n| match n {
+                Phys(p) => Some(*p),
+                Virt(_) => colors.get(n).copied(),
+            })
+            .collect::<Set<_>>();
+        match COLORS.iter().find(|c| !taken.contains(c)) {
+            Some(c) => {
+                colors.insert(r, *c);
+                home.insert(r, Home::Reg(*c));
+            }
+            None => {
+                home.insert(r, Home::Slot(slots));
+                slots += 1;
+            }
+        }
+    }
+
+    cost.keys()
+        .chain(graph.alias.keys())
+        .filter_map(|r| match *r {
+            Virt(v) => Some(v),
+            Phys(_) => None,
+        })
+        .map(|v| {
+            let h = match graph.find(Virt(v)) {
+                Phys(p) => Home::Reg(p),
+                rep => home[&rep],
+            };
+            (v, h)
+        })
+        .collect()
+}
+
+/// Registers that take part in allocation: the virtual ones and the colors.
+fn is_node(r: VReg) -> bool {
+    match r {
+        Phys(p) => COLORS.contains(&p),
+        Virt(_) => true,
+    }
+}
+
+/// The registers an instruction writes that take part in allocation,
+/// including the ones a call may change.
+fn defs(insn: &Instruction<VReg>) -> Vec<VReg> {
+    liveness::defs(insn)
+        .into_iter()
+        .filter(|r| is_node(*r))
+        .collect()
+}
+
+/// The registers an instruction reads that take part in allocation.
+fn uses(insn: &Instruction<VReg>) -> Vec<VReg> {
+    liveness::uses(insn)
+        .into_iter()
+        .filter(|r| is_node(*r))
+        .collect()
+}
+
+/// The two sides of a move.
+fn as_move(insn: &Instruction<VReg>) -> Option<(VReg, VReg)> {
+    match insn {
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst,
+            lhs,
+            rhs: 0,
+        } if is_node(*dst) && is_node(*lhs) => Some((*dst, *lhs)),
+        _ => None,
+    }
+}
+
+/// The interference graph.
+struct Graph {
+    /// The neighbors of each register that wasn't merged into another.
+    adj: Map<VReg, Set<VReg>>,
+    /// The moves, which are candidates for coalescing.
+    moves: Vec<(VReg, VReg)>,
+    /// The register each coalesced register was merged into.
+    alias: Map<VReg, VReg>,
+}
+
+impl Graph {
+    fn build(func: &Function<VReg>) -> Graph {
+        let mut graph = Graph {
+            adj: Map::new(),
+            moves: vec![],
+            alias: Map::new(),
+        };
+        let liveness = Liveness::new(func);
+        for block in &func.basic_blocks {
+            let mut live = liveness.live_out[&block.id]
+                .iter()
+                .copied()
+                .filter(|r| is_node(*r))
+                .collect::<Set<_>>();
+            for insn in block.instructions.iter().rev() {
+                for r in defs(insn).into_iter().chain(uses(insn)) {
+                    if let Virt(_) = r {
+                        graph.adj.entry(r).or_default();
+                    }
+                }
+                // The two sides of a move hold the same value, so they don't
+                // interfere because of it.
+                if let Some((dst, src)) = as_move(insn) {
+                    live.remove(&src);
+                    graph.moves.push((dst, src));
+                }
+                for d in defs(insn) {
+                    for l in &live {
+                        graph.add_edge(d, *l);
+                    }
+                }
+                for d in defs(insn) {
+                    live.remove(&d);
+                }
+                live.extend(uses(insn));
+            }
+        }
+        graph
+    }
+
+    fn add_edge(&mut self, a: VReg, b: VReg) {
+        if a == b || matches!((a, b), (Phys(_), Phys(_))) {
+            return;
+        }
+        self.adj.entry(a).or_default().insert(b);
+        self.adj.entry(b).or_default().insert(a);
+    }
+
+    /// The register `r` was merged into, if any.
+    fn find(&self, mut r: VReg) -> VReg {
+        while let Some(a) = self.alias.get(&r) {
+            r = *a;
+        }
+        r
+    }
+
+    /// The number of neighbors, which is unbounded for physical registers.
+    fn degree(&self, r: VReg) -> usize {
+        match r {
+            Phys(_) => usize::MAX,
+            Virt(_) => self.adj.get(&r).map_or(0, Set::len),
+        }
+    }
+
+    fn neighbors(&self, r: VReg) -> Set<VReg> {
+        self.adj.get(&r).cloned().unwrap_or_default()
+    }
+
+    /// Merge the sides of moves until no more can be merged safely.
+    fn coalesce(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (dst, src) in self.moves.clone() {
+                let (mut a, mut b) = (self.find(dst), self.find(src));
+                if let Virt(_) = a {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                if a == b || matches!(b, Phys(_)) || self.neighbors(a).contains(&b) {
+                    continue;
+                }
+                let safe = match a {
+                    // George: the neighbors of `b` don't get harder to color.
+                    // Physical registers already have colors that differ.
+                    Phys(_) => self.neighbors(b).iter().all(|t| {
+                        matches!(t, Phys(_)) || self.degree(*t) < K || self.neighbors(a).contains(t)
+                    }),
+                    // Briggs: the merged register has fewer than K neighbors
+                    // that are hard to color.
+                    Virt(_) => {
+                        let merged = &self.neighbors(a) | &self.neighbors(b);
+                        merged.iter().filter(|t| self.degree(**t) >= K).count() < K
+                    }
+                };
+                if safe {
+                    self.merge(a, b);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// Merge the virtual register `b` into `a`.
+    fn merge(&mut self, a: VReg, b: VReg) {
+        for t in self.adj.remove(&b).unwrap_or_default() {
+            self.adj.get_mut(&t).unwrap().remove(&b);
+            self.add_edge(a, t);
+        }
+        self.alias.insert(b, a);
+    }
+}
@@ -0,0 +1,260 @@
+//! Running programs under QEMU.
+//!
+//! This links the assembly output into an executable (see
+//! [crate::back::link]) and runs it with QEMU's user-mode emulator, which
+//! runs RISC-V Linux executables on any host.  The executable is static, so
+//! QEMU doesn't need a RISC-V sysroot.  The runner feeds the input to the
+//! program, and captures what it writes and the status it exits with.
+//!
+//! QEMU is the command in the `SMOL_QEMU` environment variable if it's set,
+//! and otherwise `qemu-riscv64` (`qemu-riscv32` on RV32) on the `PATH`.
+//!
+//! Programs for the proxy kernel run on Spike, the RISC-V ISA simulator,
+//! under `pk`.  That's the command in `SMOL_SPIKE` if it's set, and otherwise
+//! `spike` on the `PATH` with the `pk` on the `PATH` or in the usual place
+//! under `$RISCV`, where the RISC-V tools install.
+
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use derive_more::Display;
+
+use crate::back::asm::{AsmOptions, Target};
+use crate::back::link::{find_program, temp_path, LinkError, Toolchain};
+
+/// The environment variable that overrides QEMU.
+pub const QEMU_VAR: &str = "SMOL_QEMU";
+
+/// The environment variable that overrides Spike and the proxy kernel.
+pub const SPIKE_VAR: &str = "SMOL_SPIKE";
+
+/// Errors that keep a program from running.  Errors of the program itself
+/// are part of its [Outcome].
+#[derive(Debug, Display)]
+pub enum RunError {
+    /// There is no toolchain to link with, or linking failed.
+    #[display("{_0}")]
+    Link(LinkError),
+    /// QEMU isn't installed.
+    #[display(
+        "Cannot find `{_0}` on the `PATH`.  Install QEMU's user-mode emulator, or set \
+         `{QEMU_VAR}` to the emulator to run programs with."
+    )]
+    NoQemu(String),
+    /// Spike or the proxy kernel isn't installed.
+    #[display(
+        "Cannot find `{_0}`.  Install Spike and the proxy kernel, or set `{SPIKE_VAR}` to \
+         the command to run programs with, like `spike /opt/riscv/riscv64-unknown-elf/bin/pk`."
+    )]
+    NoSpike(String),
+    /// The emulator didn't start, or we couldn't talk to it.
+    #[display("Cannot run `{}`: {_1}", _0.display())]
+    Io(PathBuf, std::io::Error),
+}
+
+impl From<LinkError> for RunError {
+    fn from(e: LinkError) -> Self {
+        RunError::Link(e)
+    }
+}
+
+/// What a program did when it ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The exit status, or `None` if a signal killed the program.
+    pub status: Option<i32>,
+}
+
+/// QEMU's user-mode emulator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Qemu {
+    /// The emulator to run.
+    pub program: PathBuf,
+    /// The arguments that come before the executable.
+    pub args: Vec<String>,
+}
+
+impl Qemu {
+    /// Find QEMU for the target, in `SMOL_QEMU` or on the `PATH`.
+    pub fn detect(target: Target) -> Result<Qemu, RunError> {
+        if let Some((program, args)) = command_from_env(QEMU_VAR) {
+            return Ok(Qemu { program, args });
+        }
+        Qemu::detect_in(&std::env::var_os("PATH").unwrap_or_default(), target)
+    }
+
+    /// Find QEMU for the target in the directories of `path`, which is in
+    /// the format of the `PATH` environment variable.
+    pub fn detect_in(path: &OsStr, target: Target) -> Result<Qemu, RunError> {
+        let name = match target {
+            Target::Riscv64 => "qemu-riscv64",
+            Target::Riscv32 => "qemu-riscv32",
+        };
+        match find_program(path, name) {
+            Some(program) => Ok(Qemu {
+                program,
+                args: vec![],
+            }),
+            None => Err(RunError::NoQemu(name.to_string())),
+        }
+    }
+
+    /// Run the executable `exe` with the arguments `exe_args`, and with
+    /// `input` as its standard input.
+    pub fn run(&self, exe: &Path, exe_args: &[String], input: &[u8]) -> Result<Outcome, RunError> {
+        capture(&self.program, &self.args, exe, exe_args, input)
+    }
+}
+
+/// Spike, with the proxy kernel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spike {
+    /// The simulator to run.
+    pub program: PathBuf,
+    /// The arguments that come before the executable, which end with the
+    /// proxy kernel.
+    pub args: Vec<String>,
+}
+
+impl Spike {
+    /// Find Spike and the proxy kernel for the target, in `SMOL_SPIKE`, on
+    /// the `PATH`, or under `$RISCV`.
+    pub fn detect(target: Target) -> Result<Spike, RunError> {
+        if let Some((program, args)) = command_from_env(SPIKE_VAR) {
+            return Ok(Spike { program, args });
+        }
+        let riscv = std::env::var_os("RISCV").map(PathBuf::from);
+        Spike::detect_in(
+            &std::env::var_os("PATH").unwrap_or_default(),
+            riscv.as_deref(),
+            target,
+        )
+    }
+
+    /// Find Spike and the proxy kernel for the target in the directories of
+    /// `path`, which is in the format of the `PATH` environment variable, or
+    /// for the proxy kernel, under `riscv`.
+    pub fn detect_in(
+        path: &OsStr,
+        riscv: Option<&Path>,
+        target: Target,
+    ) -> Result<Spike, RunError> {
+        let program =
+            find_program(path, "spike").ok_or_else(|| RunError::NoSpike("spike".into()))?;
+        let (triple, isa) = match target {
+            Target::Riscv64 => ("riscv64-unknown-elf", "rv64imafd"),
+            Target::Riscv32 => ("riscv32-unknown-elf", "rv32imafd"),
+        };
+        let pk = riscv
+            .map(|riscv| riscv.join(triple).join("bin").join("pk"))
+            .filter(|pk| pk.is_file())
+            .or_else(|| find_program(path, "pk"))
+            .ok_or_else(|| RunError::NoSpike("pk".into()))?;
+        Ok(Spike {
+            program,
+            args: vec![format!("--isa={isa}"), pk.to_string_lossy().into_owned()],
+        })
+    }
+
+    /// Run the executable `exe` with the arguments `exe_args`, and with
+    /// `input` as its standard input.
+    pub fn run(&self, exe: &Path, exe_args: &[String], input: &[u8]) -> Result<Outcome, RunError> {
+        capture(&self.program, &self.args, exe, exe_args, input)
+    }
+}
+
+/// The command in the environment variable `var`, and its arguments, if it's
+/// set.
+fn command_from_env(var: &str) -> Option<(PathBuf, Vec<String>)> {
+    let command = std::env::var_os(var)?.to_string_lossy().into_owned();
+    let mut words = command.split_whitespace().map(String::from);
+    let program = words.next()?;
+    Some((program.into(), words.collect()))
+}
+
+/// Run `program` with `args`, the executable `exe`, and its arguments
+/// `exe_args`, feed it `input`, and capture what happens.
+fn capture(
+    program: &Path,
+    args: &[String],
+    exe: &Path,
+    exe_args: &[String],
+    input: &[u8],
+) -> Result<Outcome, RunError> {
+    let io = |e| RunError::Io(program.to_path_buf(), e);
+    let mut command = Command::new(program);
+    command.args(args).arg(exe).args(exe_args);
+    log::debug!("Running {command:?}.");
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io)?;
+    // The program may end without reading all of its input, so a broken pipe
+    // is fine.
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(input) {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(io(e)),
+        _ => drop(stdin),
+    }
+    let output = child.wait_with_output().map_err(io)?;
+    Ok(Outcome {
+        stdout: output.stdout,
+        stderr: output.stderr,
+        status: output.status.code(),
+    })
+}
+
+/// Link the assembly code `asm`, which was generated with `options`, with
+/// the runtime's files, and run it under QEMU with the arguments `exe_args`
+/// and with `input` as its standard input.
+pub fn run_qemu(
+    asm: &str,
+    runtime: &[PathBuf],
+    target: Target,
+    options: &AsmOptions,
+    exe_args: &[String],
+    input: &[u8],
+) -> Result<Outcome, RunError> {
+    let qemu = Qemu::detect(target)?;
+    link_and_run(asm, runtime, target, options, |exe| {
+        qemu.run(exe, exe_args, input)
+    })
+}
+
+/// Link the assembly code `asm` like [run_qemu], and run it on Spike under
+/// the proxy kernel.
+pub fn run_spike(
+    asm: &str,
+    runtime: &[PathBuf],
+    target: Target,
+    options: &AsmOptions,
+    exe_args: &[String],
+    input: &[u8],
+) -> Result<Outcome, RunError> {
+    let spike = Spike::detect(target)?;
+    link_and_run(asm, runtime, target, options, |exe| {
+        spike.run(exe, exe_args, input)
+    })
+}
+
+/// Link an executable in the temporary directory, and run it with `run`.
+fn link_and_run(
+    asm: &str,
+    runtime: &[PathBuf],
+    target: Target,
+    options: &AsmOptions,
+    run: impl FnOnce(&Path) -> Result<Outcome, RunError>,
+) -> Result<Outcome, RunError> {
+    let toolchain = Toolchain::detect(target, options.system)?;
+    let exe = temp_path("");
+    toolchain.link(asm, runtime, &exe, target, options)?;
+    let outcome = run(&exe);
+    let _ = std::fs::remove_file(&exe);
+    outcome
+}
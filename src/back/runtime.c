@@ -0,0 +1,64 @@
+/* The runtime of smol programs on systems with a C library (see
+ * src/back/runtime.rs).  Values are machine words, which are `long`s on both
+ * RV64 and RV32.  Errors are reported like the interpreter reports them, and
+ * end the program with status 1. */
+
+#include <errno.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* The longest input word that read takes as one. */
+#define MAX_WORD 127
+
+static void fail(const char *format, ...) {
+  va_list args;
+  fflush(stdout);
+  fputs("Runtime error: ", stderr);
+  va_start(args, format);
+  vfprintf(stderr, format, args);
+  va_end(args);
+  fputc('\n', stderr);
+  exit(1);
+}
+
+long _cflat_read(void) {
+  char word[MAX_WORD + 1];
+  char *end;
+  long value;
+  if (scanf("%127s", word) != 1)
+    fail("Unexpected end of input.");
+  errno = 0;
+  value = strtol(word, &end, 10);
+  if (*end != '\0' || errno == ERANGE)
+    fail("The input `%s` is not a number.", word);
+  return value;
+}
+
+void _cflat_print(long value) { printf("%ld\n", value); }
+
+/* Allocations are never freed, and calloc zeroes them. */
+long *_cflat_alloc(long size) {
+  long *words = size < 0 ? NULL : calloc(size > 0 ? size : 1, sizeof(long));
+  if (words == NULL)
+    fail("Cannot allocate %ld words.", size);
+  return words;
+}
+
+void _cflat_stack_overflow(void) { fail("Stack overflow."); }
+
+void _cflat_div_by_zero(void) { fail("Division by zero."); }
+
+void _cflat_overflow(void) { fail("Arithmetic overflow."); }
+
+/* The allocator never frees, so there is no collector to set up.  A precise
+ * collector would find the heap pointers of each frame in the table of stack
+ * maps, `_cflat_stack_maps`, which programs compiled with `--gc` have (see
+ * src/back/asm.rs). */
+void _cflat_init_gc(void) {}
+
+/* The interpreter collects profiles, so the counters stay where they are. */
+void _cflat_profile_dump(long *counters, long count) {
+  (void)counters;
+  (void)count;
+}
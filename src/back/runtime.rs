@@ -0,0 +1,26 @@
+//! The runtime library.
+//!
+//! Generated code calls the runtime to read
+//! ([READ_FN](crate::back::asm::READ_FN)), print
+//! ([PRINT_FN](crate::back::asm::PRINT_FN)), and allocate
+//! ([ALLOC_FN](crate::back::asm::ALLOC_FN)), and to report stack overflows
+//! ([STACK_OVERFLOW_FN](crate::back::asm::STACK_OVERFLOW_FN)), divisions by
+//! zero ([DIV_BY_ZERO_FN](crate::back::asm::DIV_BY_ZERO_FN)), and arithmetic
+//! overflows ([OVERFLOW_FN](crate::back::asm::OVERFLOW_FN)), dump profile
+//! counters ([PROFILE_DUMP_FN](crate::back::asm::PROFILE_DUMP_FN)), and set
+//! up the garbage collector ([GC_INIT_FN](crate::back::asm::GC_INIT_FN)).
+//! The crate ships the runtime as C source, which works with any C library on
+//! RV64 and RV32, so that programs link without the user writing one.  The
+//! linker compiles it along with the program unless the user names the
+//! runtime's files (see [crate::back::link]), and smolc prints it with
+//! `--out runtime`.
+//!
+//! The runtime behaves like the interpreter: it reads whitespace-separated
+//! numbers, prints one number per line, zeroes allocations, and reports
+//! errors with the interpreter's messages on the standard error, ending the
+//! program with status 1.  The emulator does the same without the C source
+//! (see [crate::back::emu]), and bare-metal programs carry a runtime in
+//! assembly instead (see [crate::back::baremetal]).
+
+/// The C source of the runtime.
+pub const RUNTIME_SOURCE: &str = include_str!("runtime.c");
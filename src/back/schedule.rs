@@ -0,0 +1,189 @@
+//! Instruction scheduling within basic blocks.
+//!
+//! Simple in-order cores stall when an instruction needs the result of a load
+//! or a multiplication that isn't ready yet, so it pays to put independent
+//! instructions between them.  This is a list scheduler: it splits each block
+//! at calls, control flow, comments, stack maps, and line numbers, which stay
+//! where they are, and orders the instructions between them.
+//!
+//! An instruction depends on an earlier one if it reads a register the
+//! earlier one writes, writes a register the earlier one reads or writes, or
+//! accesses memory the earlier one may store to or load from, when one of
+//! them stores.  Words at different offsets from the same register, and
+//! different globals, don't overlap.  Stack slots aren't globals, but
+//! other memory accesses may hit either.
+//!
+//! The scheduler issues one instruction per cycle.  At each step it picks an
+//! instruction whose operands are ready soonest, preferring the one with the
+//! longest chain of latencies after it, and then the earliest one.  The
+//! latencies are roughly those of small RV64 cores: loads and
+//! multiplications take three cycles, divisions take sixteen, floating-point
+//! arithmetic and conversions take four, except for divisions, which take
+//! twenty, and anything else takes one.  Floating-point registers count like
+//! the general-purpose ones.  This runs after register allocation, so it only
+//! reorders instructions whose registers don't conflict.
+
+use std::cmp::Reverse;
+
+use crate::back::asm::*;
+use crate::back::liveness::{defs, is_call, uses};
+
+use Register::*;
+
+/// Schedule the instructions of each block.
+pub fn schedule(program: Program) -> Program {
+    program.map_functions(schedule_function)
+}
+
+/// Schedule the instructions of each block of a function.
+pub fn schedule_function(func: Function) -> Function {
+    Function {
+        basic_blocks: func
+            .basic_blocks
+            .into_iter()
+            .map(|block| BasicBlock {
+                id: block.id,
+                instructions: schedule_block(block.instructions),
+            })
+            .collect(),
+        ..func
+    }
+}
+
+/// Schedule the instructions of a block between the ones that stay in place.
+pub fn schedule_block(code: Vec<Instruction>) -> Vec<Instruction> {
+    let mut result = vec![];
+    let mut region = vec![];
+    for insn in code {
+        if stays(&insn) {
+            result.extend(schedule_region(std::mem::take(&mut region)));
+            result.push(insn);
+        } else {
+            region.push(insn);
+        }
+    }
+    result.extend(schedule_region(region));
+    result
+}
+
+/// Instructions that the scheduler doesn't move.
+fn stays(insn: &Instruction) -> bool {
+    use Instruction::*;
+
+    is_call(insn)
+        || matches!(
+            insn,
+            Jal { .. } | Jalr { .. } | Branch { .. } | Comment(_) | StackMap { .. } | Loc { .. }
+        )
+}
+
+/// The cycles until the result of an instruction is ready.
+fn latency(insn: &Instruction) -> u32 {
+    use Instruction::*;
+
+    match insn {
+        Ld { .. } | Fld { .. } => 3,
+        FArith { op, .. } => match op {
+            FArithOp::Div => 20,
+            _ => 4,
+        },
+        FCvtFromInt { .. } | FCvtToInt { .. } => 4,
+        Arith { op, .. } | ArithI { op, .. } => match op {
+            ArithOp::Mul | ArithOp::MulH => 3,
+            ArithOp::Div => 16,
+            _ => 1,
+        },
+        _ => 1,
+    }
+}
+
+/// The memory an instruction accesses, and whether it stores to it.
+fn memory(insn: &Instruction) -> Option<(Memory, bool)> {
+    match insn {
+        Instruction::Ld { src, .. } | Instruction::Fld { src, .. } => Some((*src, false)),
+        Instruction::Sd { dst, .. } | Instruction::Fsd { dst, .. } => Some((*dst, true)),
+        _ => None,
+    }
+}
+
+/// May the eight bytes at the two memory locations overlap?  If the base
+/// register changes between the accesses, the accesses depend on the change
+/// anyway.
+fn may_overlap(a: Memory, b: Memory) -> bool {
+    use Memory::*;
+
+    match (a, b) {
+        (Mem(r, i), Mem(s, j)) if r == s => (i - j).abs() < 8,
+        (Global { index: i, .. }, Global { index: j, .. }) => i == j,
+        (Mem(Fp, _), Global { .. }) | (Global { .. }, Mem(Fp, _)) => false,
+        _ => true,
+    }
+}
+
+/// Does `later` have to stay after `earlier`?
+fn depends(earlier: &Instruction, later: &Instruction) -> bool {
+    let (d1, u1) = (defs(earlier), uses(earlier));
+    let (d2, u2) = (defs(later), uses(later));
+    if !d1.is_disjoint(&u2) || !d1.is_disjoint(&d2) || !u1.is_disjoint(&d2) {
+        return true;
+    }
+    let (f1, f2) = (earlier.fp_def(), later.fp_def());
+    if f1.is_some_and(|f| f2 == Some(f) || later.fp_uses().contains(&f))
+        || f2.is_some_and(|f| earlier.fp_uses().contains(&f))
+    {
+        return true;
+    }
+    match (memory(earlier), memory(later)) {
+        (Some((a, store_a)), Some((b, store_b))) => (store_a || store_b) && may_overlap(a, b),
+        _ => false,
+    }
+}
+
+/// List-schedule instructions that may be reordered as their dependencies
+/// allow.
+fn schedule_region(code: Vec<Instruction>) -> Vec<Instruction> {
+    let n = code.len();
+    if n < 2 {
+        return code;
+    }
+    let preds = (0..n)
+        .map(|j| {
+            (0..j)
+                .filter(|i| depends(&code[*i], &code[j]))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    // The longest chain of latencies from each instruction to the end.
+    let mut height = vec![0; n];
+    for i in (0..n).rev() {
+        height[i] = latency(&code[i])
+            + (i + 1..n)
+                .filter(|j| preds[*j].contains(&i))
+                .map(|j| height[j])
+                .max()
+                .unwrap_or(0);
+    }
+
+    let mut issued: Vec<Option<u32>> = vec![None; n];
+    let mut order = vec![];
+    let mut cycle = 0;
+    while order.len() < n {
+        let ready = |j: usize| {
+            preds[j]
+                .iter()
+                .map(|i| issued[*i].map(|c| c + latency(&code[*i])))
+                .try_fold(cycle, |t, r| r.map(|r| t.max(r)))
+        };
+        let (next, at) = (0..n)
+            .filter(|j| issued[*j].is_none())
+            .filter_map(|j| ready(j).map(|t| (j, t)))
+            .min_by_key(|(j, t)| (*t, Reverse(height[*j]), *j))
+            .unwrap();
+        issued[next] = Some(at);
+        order.push(next);
+        cycle = at + 1;
+    }
+
+    let mut code = code.into_iter().map(Some).collect::<Vec<_>>();
+    order.into_iter().map(|i| code[i].take().unwrap()).collect()
+}
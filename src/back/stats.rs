@@ -0,0 +1,235 @@
+//! Code size and instruction mix statistics, for comparing the code that
+//! optimization changes produce.
+//!
+//! The statistics of each function count the instructions of its blocks by
+//! class, not counting the prologue and the epilogue, nor the comments, stack
+//! maps, and line numbers, which emit no code.  The code size is that of the
+//! machine code of the whole function without compressed instructions (see
+//! [crate::back::encode]), and the frame is everything the function takes of
+//! the stack.  Spill code is the loads and stores of the stack slots that
+//! register allocation gave to virtual registers, which are the only memory
+//! below the frame pointer that the code of a function accesses.
+
+use std::fmt::{Display, Formatter};
+
+use serde_json::json;
+
+use crate::back::asm::*;
+use crate::common::*;
+
+/// The statistics of a function.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: Id,
+    /// Integer arithmetic, comparisons, and loading constants and addresses.
+    pub alu: usize,
+    pub mul_div: usize,
+    /// Loads and stores, of integers and of doubles.
+    pub loads: usize,
+    pub stores: usize,
+    pub branches: usize,
+    /// Jumps other than calls, including returns.
+    pub jumps: usize,
+    pub calls: usize,
+    /// Arithmetic, comparisons, moves, and conversions of doubles.
+    pub float: usize,
+    pub code_bytes: u32,
+    pub frame_size: u32,
+    pub spill_loads: usize,
+    pub spill_stores: usize,
+}
+
+impl FunctionStats {
+    /// The number of instructions.
+    pub fn instructions(&self) -> usize {
+        self.alu
+            + self.mul_div
+            + self.loads
+            + self.stores
+            + self.branches
+            + self.jumps
+            + self.calls
+            + self.float
+    }
+
+    fn new(func: &Function, target: Target) -> FunctionStats {
+        use Instruction::*;
+
+        let mut stats = FunctionStats {
+            name: func.id,
+            frame_size: (2 * SLOT_SIZE + func.frame_size()) as u32,
+            ..FunctionStats::default()
+        };
+        let is_spill_slot =
+            |mem: &Memory| matches!(mem, Memory::Mem(Register::Fp, offset) if *offset < 0);
+        for insn in func.basic_blocks.iter().flat_map(|b| &b.instructions) {
+            match insn {
+                Arith {
+                    op: ArithOp::Mul | ArithOp::MulH | ArithOp::Div,
+                    ..
+                }
+                | ArithI {
+                    op: ArithOp::Mul | ArithOp::MulH | ArithOp::Div,
+                    ..
+                } => stats.mul_div += 1,
+                La { .. }
+                | Li { .. }
+                | Lui { .. }
+                | Arith { .. }
+                | ArithI { .. }
+                | SCmpZ { .. } => stats.alu += 1,
+                Ld { src, .. } | Fld { src, .. } => {
+                    stats.loads += 1;
+                    stats.spill_loads += usize::from(is_spill_slot(src));
+                }
+                Sd { dst, .. } | Fsd { dst, .. } => {
+                    stats.stores += 1;
+                    stats.spill_stores += usize::from(is_spill_slot(dst));
+                }
+                Branch { .. } => stats.branches += 1,
+                Jal {
+                    dst: Register::Ra, ..
+                }
+                | Jalr {
+                    dst: Register::Ra, ..
+                } => stats.calls += 1,
+                Jal { .. } | Jalr { .. } => stats.jumps += 1,
+                FArith { .. }
+                | FCmp { .. }
+                | FMv { .. }
+                | FCvtFromInt { .. }
+                | FCvtToInt { .. } => stats.float += 1,
+                Comment(_) | StackMap { .. } | Loc { .. } => {}
+            }
+        }
+        // Code that the encoder doesn't support still takes about a word per
+        // instruction.
+        stats.code_bytes = func
+            .code_size(target)
+            .unwrap_or(4 * stats.instructions() as u32);
+        stats
+    }
+
+    /// The statistics as a JSON object.
+    pub fn to_json(&self) -> String {
+        json!({
+            "function": self.name.to_string(),
+            "instructions": self.instructions(),
+            "alu": self.alu,
+            "mul_div": self.mul_div,
+            "loads": self.loads,
+            "stores": self.stores,
+            "branches": self.branches,
+            "jumps": self.jumps,
+            "calls": self.calls,
+            "float": self.float,
+            "code_bytes": self.code_bytes,
+            "frame_size": self.frame_size,
+            "spill_loads": self.spill_loads,
+            "spill_stores": self.spill_stores,
+        })
+        .to_string()
+    }
+}
+
+/// The statistics of each function of a program, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CodeStats {
+    pub functions: Vec<FunctionStats>,
+}
+
+impl CodeStats {
+    /// The statistics of all functions together, named `total`.  The frame
+    /// is the largest one.
+    pub fn total(&self) -> FunctionStats {
+        let mut total = FunctionStats {
+            name: Id::from_ref("total"),
+            ..FunctionStats::default()
+        };
+        for s in &self.functions {
+            total.alu += s.alu;
+            total.mul_div += s.mul_div;
+            total.loads += s.loads;
+            total.stores += s.stores;
+            total.branches += s.branches;
+            total.jumps += s.jumps;
+            total.calls += s.calls;
+            total.float += s.float;
+            total.code_bytes += s.code_bytes;
+            total.frame_size = total.frame_size.max(s.frame_size);
+            total.spill_loads += s.spill_loads;
+            total.spill_stores += s.spill_stores;
+        }
+        total
+    }
+
+    /// The statistics as a JSON array, one function per line.
+    pub fn to_json(&self) -> String {
+        if self.functions.is_empty() {
+            return "[]\n".to_string();
+        }
+        let items = self
+            .functions
+            .iter()
+            .map(|f| format!("  {}", f.to_json()))
+            .collect::<Vec<_>>();
+        format!("[\n{}\n]\n", items.join(",\n"))
+    }
+}
+
+impl Display for CodeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>6} {:>6} {:>7} {:>6} {:>6} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6} {:>12} {:>13}",
+            "function",
+            "insns",
+            "alu",
+            "mul/div",
+            "loads",
+            "stores",
+            "branches",
+            "jumps",
+            "calls",
+            "float",
+            "bytes",
+            "frame",
+            "spill loads",
+            "spill stores"
+        )?;
+        for s in self.functions.iter().chain([&self.total()]) {
+            writeln!(
+                f,
+                "{:<16} {:>6} {:>6} {:>7} {:>6} {:>6} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6} {:>12} {:>13}",
+                s.name.as_str(),
+                s.instructions(),
+                s.alu,
+                s.mul_div,
+                s.loads,
+                s.stores,
+                s.branches,
+                s.jumps,
+                s.calls,
+                s.float,
+                s.code_bytes,
+                s.frame_size,
+                s.spill_loads,
+                s.spill_stores
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    /// The statistics of the functions of the program.
+    pub fn stats(&self) -> CodeStats {
+        CodeStats {
+            functions: self
+                .functions
+                .iter()
+                .map(|func| FunctionStats::new(func, self.target))
+                .collect(),
+        }
+    }
+}
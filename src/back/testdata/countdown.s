@@ -0,0 +1,30 @@
+    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+    addi sp, sp, -16
+    sd s1, 0(sp)
+.Lmain.$entry:
+    # read n, and print it down to 1
+    call _cflat_read
+    addi s1, a0, 0
+.Lmain.loop:
+    beq s1, zero, .Lmain.done
+    addi a0, s1, 0
+    call _cflat_print
+    addi s1, s1, -1
+    jal zero, .Lmain.loop
+.Lmain.done:
+    li a0, 0
+.Lmain.$epilogue:
+    ld s1, 0(sp)
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size main, .-main
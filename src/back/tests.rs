@@ -1 +1,3046 @@
 //! Unit tests for the backend.
+
+use super::*;
+use crate::back::legalize::legalize_instruction;
+use crate::back::materialize::materialize;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::tir::{Builder, ShiftOp, Type};
+use crate::middle::Profile;
+
+// SECTION: helpers
+
+fn block(name: &str, instructions: Vec<Instruction>) -> BasicBlock {
+    BasicBlock {
+        id: Id::from_ref(name),
+        instructions,
+    }
+}
+
+fn local(name: &str) -> JumpTarget {
+    JumpTarget::Local(Id::from_ref(name))
+}
+
+/// A program that reads `n`, stores the squares of 0 to `n - 1`, prints
+/// the last one and what dividing it by zero gives, and exits from another
+/// function if it's greater than 10.
+fn squares() -> crate::middle::tir::Program {
+    let mut b = Builder::new();
+    b.read("n");
+    b.constant("one", 1);
+    b.constant("zero", 0);
+    b.alloc("p", "n");
+    b.jump("head");
+    b.block("head");
+    b.arith(BOp::Lt, "c", "i", "n");
+    b.branch("c", "body", "done");
+    b.block("body");
+    b.call("r", "square", &["i"]);
+    b.shift(ShiftOp::Left, "at", "i", 3);
+    b.arith(BOp::Add, "at", "p", "at");
+    b.store("at", 0, "r");
+    b.arith(BOp::Add, "i", "i", "one");
+    b.jump("head");
+    b.block("done");
+    b.arith(BOp::Sub, "at", "at", "one");
+    b.load("last", "at", -7);
+    b.print("last");
+    b.arith(BOp::Div, "q", "last", "zero");
+    b.print("q");
+    b.call("r", "stop", &["last"]);
+    b.print("r");
+    b.exit();
+    b.function("square", &["x"], Type::I64);
+    b.arith(BOp::Mul, "x", "x", "x");
+    b.ret("x");
+    b.function("stop", &["x"], Type::I64);
+    b.constant("ten", 10);
+    b.arith(BOp::Lt, "c", "ten", "x");
+    b.branch("c", "yes", "no");
+    b.block("yes");
+    b.exit();
+    b.block("no");
+    b.ret("x");
+    b.finish()
+}
+
+// SECTION: tests
+
+#[test]
+fn asm_code_of_empty_frame() {
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block("$entry", vec![])],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    let expected = "    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+.Lmain.$entry:
+.Lmain.$epilogue:
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size main, .-main
+";
+    assert_eq!(program.asm_code(), expected);
+}
+
+#[test]
+fn asm_code() {
+    use Register::*;
+
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![
+                block(
+                    "$entry",
+                    vec![
+                        Instruction::Comment("x = 3 * y".into()),
+                        Instruction::Ld {
+                            dst: T0,
+                            src: Memory::Global {
+                                index: 1,
+                                offset: 0,
+                            },
+                        },
+                        Instruction::ArithI {
+                            op: ArithOp::Mul,
+                            dst: T0,
+                            lhs: T0,
+                            rhs: 3,
+                        },
+                        Instruction::Sd {
+                            dst: Memory::Mem(Fp, -8),
+                            src: T0,
+                        },
+                        Instruction::Branch {
+                            cond: Condition::Equal,
+                            lhs: T0,
+                            rhs: Zero,
+                            target: local("done"),
+                        },
+                    ],
+                ),
+                block(
+                    "loop",
+                    vec![
+                        Instruction::ArithI {
+                            op: ArithOp::Sub,
+                            dst: S1,
+                            lhs: T0,
+                            rhs: 1,
+                        },
+                        Instruction::Sd {
+                            dst: Memory::Global {
+                                index: 0,
+                                offset: 8,
+                            },
+                            src: S1,
+                        },
+                        Instruction::mov(A0, S1),
+                        Instruction::call(Id::from_ref("print")),
+                        Instruction::jump(local("loop")),
+                    ],
+                ),
+                block("done", vec![Instruction::jump(local(EPILOGUE))]),
+            ],
+            stack_space: 8,
+            used_registers: vec![S1],
+            variables: vec![],
+        }],
+        globals: vec![
+            GlobalVar {
+                id: Id::from_ref("a"),
+                init: vec![0, 7],
+            },
+            GlobalVar::zeroed(Id::from_ref("y"), 1),
+        ],
+        target: Target::Riscv64,
+    };
+    let expected = "    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+    addi sp, sp, -16
+    sd s1, 0(sp)
+.Lmain.$entry:
+    # x = 3 * y
+    ld t0, .Lglobal.y
+    li t6, 3
+    mul t0, t0, t6
+    sd t0, -8(fp)
+    beq t0, zero, .Lmain.done
+.Lmain.loop:
+    addi s1, t0, -1
+    sd s1, .Lglobal.a+8, t6
+    addi a0, s1, 0
+    call print
+    jal zero, .Lmain.loop
+.Lmain.done:
+    jal zero, .Lmain.$epilogue
+.Lmain.$epilogue:
+    ld s1, 0(sp)
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size main, .-main
+
+    .data
+    .p2align 3
+.Lglobal.a:
+    .dword 0
+    .dword 7
+
+    .bss
+    .p2align 3
+.Lglobal.y:
+    .zero 8
+";
+    assert_eq!(program.asm_code(), expected);
+}
+
+#[test]
+fn select() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.constant("one", 1);
+    b.arith(BOp::Lt, "c", "x", "one");
+    b.branch("c", "small", "big");
+    b.block("small");
+    b.count(0);
+    b.print("one");
+    b.jump("done");
+    b.block("big");
+    b.print("x");
+    b.jump("done");
+    b.block("done");
+    b.print("zero");
+    b.exit();
+    let program = codegen::select(b.finish());
+    let code = program.functions[0]
+        .basic_blocks
+        .iter()
+        .map(|b| {
+            let insns = b.instructions.iter().map(|i| format!("  {i}\n"));
+            format!("{}:\n{}", b.id, insns.collect::<String>())
+        })
+        .collect::<String>();
+    // `zero` is never written, so it is cleared first.  `small` comes next,
+    // so the branch jumps to `big` if `c` is zero, and `done` needs no jump
+    // to it.
+    let expected = "\
+$entry:
+  li v3, 0
+  jal ra, _cflat_read # global, function
+  addi v0, a0, 0
+  li v1, 1
+  slt v2, v0, v1
+  beq v2, zero, big # local, basic block
+small:
+  ld v4, 0(global#0)
+  addi v4, v4, 1
+  sd v4, 0(global#0)
+  addi a0, v1, 0
+  jal ra, _cflat_print # global, function
+  jal zero, done # local, basic block
+big:
+  addi a0, v0, 0
+  jal ra, _cflat_print # global, function
+done:
+  addi a0, v3, 0
+  jal ra, _cflat_print # global, function
+  la a0, 0(global#0)
+  li a1, 1
+  jal ra, _cflat_profile_dump # global, function
+  li a0, 0
+";
+    assert_eq!(code, expected);
+    assert_eq!(
+        program.globals,
+        [GlobalVar::zeroed(Id::from_ref("$count0"), 1)]
+    );
+}
+
+#[test]
+fn select_calls() {
+    let names = (0..10).map(|i| format!("x{i}")).collect::<Vec<_>>();
+    let args = names.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let mut b = Builder::new();
+    for (i, x) in names.iter().enumerate() {
+        b.constant(x, i as i64);
+    }
+    b.call("r", "f", &args);
+    b.print("r");
+    b.exit();
+    b.function("f", &args, Type::I64);
+    b.arith(BOp::Add, "s", "x0", "x9");
+    b.ret("s");
+    let program = codegen::select(b.finish());
+    let code = program
+        .functions
+        .iter()
+        .map(|f| {
+            let insns = f.basic_blocks[0]
+                .instructions
+                .iter()
+                .map(|i| format!("  {i}\n"));
+            format!("{}:\n{}", f.id, insns.collect::<String>())
+        })
+        .collect::<String>();
+    // The last two arguments go on the stack, and the callee finds them above
+    // its saved frame pointer and return address.
+    let expected = "\
+main:
+  li v0, 0
+  li v1, 1
+  li v2, 2
+  li v3, 3
+  li v4, 4
+  li v5, 5
+  li v6, 6
+  li v7, 7
+  li v8, 8
+  li v9, 9
+  addi sp, sp, -16
+  sd v8, 0(sp)
+  sd v9, 8(sp)
+  addi a0, v0, 0
+  addi a1, v1, 0
+  addi a2, v2, 0
+  addi a3, v3, 0
+  addi a4, v4, 0
+  addi a5, v5, 0
+  addi a6, v6, 0
+  addi a7, v7, 0
+  jal ra, f # global, function
+  addi sp, sp, 16
+  addi v10, a0, 0
+  addi a0, v10, 0
+  jal ra, _cflat_print # global, function
+  li a0, 0
+f:
+  addi v0, a0, 0
+  addi v1, a1, 0
+  addi v2, a2, 0
+  addi v3, a3, 0
+  addi v4, a4, 0
+  addi v5, a5, 0
+  addi v6, a6, 0
+  addi v7, a7, 0
+  ld v8, 16(fp)
+  ld v9, 24(fp)
+  add v10, v0, v9
+  addi a0, v10, 0
+";
+    assert_eq!(code, expected);
+}
+
+#[test]
+fn select_checks_stack() {
+    let mut b = Builder::new();
+    b.call("r", "f", &[]);
+    b.print("r");
+    b.exit();
+    b.function("f", &[], Type::I64);
+    b.constant("x", 1);
+    b.ret("x");
+    let options = Options {
+        stack_limit: Some(4096),
+        ..Options::default()
+    };
+    let program = codegen::select_with(b.finish(), &options);
+    let code = program
+        .functions
+        .iter()
+        .flat_map(|f| &f.basic_blocks)
+        .map(|b| {
+            let insns = b.instructions.iter().map(|i| format!("  {i}\n"));
+            format!("{}:\n{}", b.id, insns.collect::<String>())
+        })
+        .collect::<String>();
+    // `main` sets the limit, and `f` checks it.
+    let expected = "\
+$entry:
+  li v1, 4096
+  sub v1, sp, v1
+  sd v1, 0(global#0)
+  jal ra, f # global, function
+  addi v0, a0, 0
+  addi a0, v0, 0
+  jal ra, _cflat_print # global, function
+  li a0, 0
+$entry:
+  ld v1, 0(global#0)
+  blt sp, v1, $stack_overflow # local, basic block
+  li v0, 1
+  addi a0, v0, 0
+  jal zero, $epilogue # local, basic block
+$stack_overflow:
+  jal ra, _cflat_stack_overflow # global, function
+";
+    assert_eq!(code, expected);
+    assert_eq!(
+        program.globals,
+        [GlobalVar::zeroed(Id::from_ref("$stack_limit"), 1)]
+    );
+}
+
+#[test]
+fn select_for_riscv32() {
+    let names = (0..9).map(|i| format!("x{i}")).collect::<Vec<_>>();
+    let args = names.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let mut b = Builder::new();
+    b.constant("x0", 1 << 32 | 5);
+    b.alloc("p", "x0");
+    b.store("p", 16, "x0");
+    b.load("x1", "p", 8);
+    b.shift(ShiftOp::Left, "x2", "x1", 40);
+    b.shift(ShiftOp::Arith, "x3", "x1", 40);
+    b.call("r", "f", &args);
+    b.exit();
+    b.function("f", &args, Type::I64);
+    b.ret("x8");
+    let options = Options {
+        target: Target::Riscv32,
+        ..Options::default()
+    };
+    let program = codegen::select_with(b.finish(), &options);
+    assert_eq!(program.target, Target::Riscv32);
+    let code = program
+        .functions
+        .iter()
+        .map(|f| {
+            let insns = f.basic_blocks[0]
+                .instructions
+                .iter()
+                .map(|i| format!("  {i}\n"));
+            format!("{}:\n{}", f.id, insns.collect::<String>())
+        })
+        .collect::<String>();
+    // The constant wraps around, offsets count 4-byte words, shifting left
+    // by 40 clears the value, and the ninth argument takes one word.
+    let expected = "\
+main:
+  li v6, 0
+  li v7, 0
+  li v8, 0
+  li v9, 0
+  li v10, 0
+  li v0, 5
+  addi a0, v0, 0
+  jal ra, _cflat_alloc # global, function
+  addi v1, a0, 0
+  sd v0, 8(v1)
+  ld v2, 4(v1)
+  li v3, 0
+  srai v4, v2, 31
+  addi sp, sp, -16
+  sd v10, 0(sp)
+  addi a0, v0, 0
+  addi a1, v2, 0
+  addi a2, v3, 0
+  addi a3, v4, 0
+  addi a4, v6, 0
+  addi a5, v7, 0
+  addi a6, v8, 0
+  addi a7, v9, 0
+  jal ra, f # global, function
+  addi sp, sp, 16
+  addi v5, a0, 0
+  li a0, 0
+f:
+  addi v0, a0, 0
+  addi v1, a1, 0
+  addi v2, a2, 0
+  addi v3, a3, 0
+  addi v4, a4, 0
+  addi v5, a5, 0
+  addi v6, a6, 0
+  addi v7, a7, 0
+  ld v8, 16(fp)
+  addi a0, v8, 0
+";
+    assert_eq!(code, expected);
+}
+
+#[test]
+fn oversized_constants() {
+    let mut b = Builder::new();
+    b.constant("x", i32::MAX.into());
+    b.constant("y", i32::MIN.into());
+    b.exit();
+    let small = b.finish();
+    assert_eq!(oversized_constant(&small, Target::Riscv32), None);
+    let mut b = Builder::new();
+    b.constant("x", 1);
+    b.constant("y", 1 << 31);
+    b.constant("z", -1 << 40);
+    b.exit();
+    let large = b.finish();
+    assert_eq!(oversized_constant(&large, Target::Riscv32), Some(1 << 31));
+    assert_eq!(oversized_constant(&large, Target::Riscv64), None);
+}
+
+#[test]
+fn soft_mul_div() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.read("y");
+    b.arith(BOp::Mul, "z", "x", "y");
+    b.arith(BOp::Div, "z", "z", "y");
+    b.print("z");
+    b.exit();
+    let options = Options {
+        soft_mul_div: true,
+        ..Options::default()
+    };
+    let code = code_gen_with(b.finish(), &options).asm_code();
+    assert!(code.contains("    call _cflat_mul\n"));
+    assert!(code.contains("    call _cflat_div\n"));
+    assert!(code.contains("\n_cflat_mul:\n"));
+    assert!(code.contains("\n_cflat_div:\n"));
+    assert!(!code.contains(" mul ") && !code.contains(" div "));
+
+    // The routines are only there if the program calls them.
+    let mut b = Builder::new();
+    b.read("x");
+    b.print("x");
+    b.exit();
+    let code = code_gen_with(b.finish(), &options).asm_code();
+    assert!(!code.contains("_cflat_mul") && !code.contains("_cflat_div"));
+}
+
+#[test]
+fn asm_comments() {
+    let source = "$read x\n\n  $print x * 2\n";
+    let mut b = Builder::new();
+    b.loc(Span { start: 0, end: 7 });
+    b.read("x");
+    b.loc(Span { start: 11, end: 23 });
+    b.constant("two", 2);
+    b.arith(BOp::Mul, "y", "x", "two");
+    b.print("y");
+    b.exit();
+    let program = b.finish();
+    let options = Options {
+        source: Some(source.into()),
+        asm_comments: true,
+        ..Options::default()
+    };
+    let code = code_gen_with(program.clone(), &options).asm_code();
+    let read = code.find("    # 1: $read x\n").unwrap();
+    let print = code.find("    # 3: $print x * 2\n").unwrap();
+    assert!(read < code.find("call _cflat_read").unwrap());
+    assert!(print > code.find("call _cflat_read").unwrap());
+    assert!(print < code.find(" mul ").unwrap());
+
+    // The spans leave no trace without the source.
+    assert_eq!(
+        code_gen(program).asm_code(),
+        code.lines()
+            .filter(|line| !line.starts_with("    # "))
+            .map(|line| format!("{line}\n"))
+            .collect::<String>()
+    );
+}
+
+#[test]
+fn debug_line_numbers() {
+    let source = "$read x\n  $print x\n";
+    let mut b = Builder::new();
+    b.loc(Span { start: 0, end: 7 });
+    b.read("x");
+    b.loc(Span { start: 10, end: 18 });
+    b.print("x");
+    b.exit();
+    let program = b.finish();
+    let options = Options {
+        source: Some(source.into()),
+        debug_file: Some("echo.smol".into()),
+        ..Options::default()
+    };
+    let code = code_gen_with(program.clone(), &options);
+    let main = &code.functions[0];
+    let locs = main
+        .basic_blocks
+        .iter()
+        .flat_map(|b| &b.instructions)
+        .filter(|insn| matches!(insn, Instruction::Loc { .. }))
+        .collect::<Vec<_>>();
+    let file = Id::from_ref("echo.smol");
+    assert_eq!(
+        locs,
+        [
+            &Instruction::Loc {
+                file,
+                line: 1,
+                column: 1
+            },
+            &Instruction::Loc {
+                file,
+                line: 2,
+                column: 3
+            },
+        ]
+    );
+
+    let asm = code.asm_code();
+    assert!(asm.starts_with("    .file 1 \"echo.smol\"\n    .text\n"));
+    let read = asm.find("    .loc 1 1 1\n").unwrap();
+    let print = asm.find("    .loc 1 2 3\n").unwrap();
+    assert!(read < asm.find("call _cflat_read").unwrap());
+    assert!(print > asm.find("call _cflat_read").unwrap());
+    assert!(print < asm.find("call _cflat_print").unwrap());
+    assert!(!asm.contains("# 1:"));
+    // Line numbers emit no machine code.
+    assert_eq!(code.encode(), code_gen(program).encode());
+}
+
+#[test]
+fn wat_code() {
+    let mut b = Builder::new();
+    b.read("n");
+    b.jump("head");
+    b.block("head");
+    b.arith(BOp::Lt, "c", "i", "n");
+    b.branch("c", "body", "done");
+    b.block("body");
+    b.call("i", "next", &["i"]);
+    b.jump("head");
+    b.block("done");
+    b.arith(BOp::Div, "q", "n", "i");
+    b.print("q");
+    b.exit();
+    b.function("next", &["k"], Type::I64);
+    b.constant("one", 1);
+    b.arith(BOp::Add, "k", "k", "one");
+    b.ret("k");
+    let code = crate::back::wasm::wat_code(b.finish());
+    assert!(code.starts_with("(module\n  (import \"smol\" \"print\""));
+    assert!(code.contains("  (func $main (export \"main\") (result i64)\n"));
+    assert!(code.contains("  (func $next (param $k i64) (result i64)\n    (local $one i64)\n"));
+    // The blocks are nested in the order they run, and the entry block falls
+    // through to the loop header.
+    let dispatch = "    loop $@dispatch
+      block $done
+        block $body
+          block $head
+            block $$entry
+              local.get $@next
+              br_table $$entry $head $body $done
+            end
+            call $@read
+            local.set $n
+          end
+";
+    assert!(code.contains(dispatch));
+    // The loop goes back to its header by going around the dispatch loop.
+    assert!(code.contains(
+        "        call $next
+        local.set $i
+        i32.const 1
+        local.set $@next
+        br $@dispatch
+      end
+"
+    ));
+    assert!(code.contains("      call $@div\n"));
+    assert!(code.contains("      i64.const 0\n      return\n    end\n    unreachable)\n"));
+}
+
+#[test]
+fn targets() {
+    let pk = TargetSpec::parse("riscv64-pk").unwrap();
+    assert_eq!(
+        (Target::of(&pk), System::of(&pk)),
+        (Some(Target::Riscv64), Some(System::ProxyKernel))
+    );
+    assert_eq!(
+        TargetSpec::parse("riscv32").unwrap(),
+        TargetSpec::parse("riscv32-linux").unwrap()
+    );
+    assert_eq!(TargetSpec::default().name, "riscv64-linux");
+    let wasm = TargetSpec::parse("wasm32").unwrap();
+    assert_eq!(
+        (wasm.backend, Target::of(&wasm), System::of(&wasm)),
+        (Backend::Wasm, None, None)
+    );
+    let bare = TargetSpec::parse("riscv32-baremetal").unwrap();
+    assert_eq!(
+        (Target::of(&bare), System::of(&bare), bare.llvm_triple),
+        (
+            Some(Target::Riscv32),
+            Some(System::BareMetal),
+            "riscv32-unknown-elf"
+        )
+    );
+    assert!(TargetSpec::parse("riscv32-pk")
+        .unwrap_err()
+        .starts_with("Unknown target `riscv32-pk`."));
+
+    let x86 = TargetSpec::parse("x86_64-linux").unwrap();
+    assert_eq!(x86.backend, Backend::Llvm);
+    let mut b = Builder::new();
+    b.exit();
+    let code = crate::back::llvm::llvm_code_for(b.finish(), &x86);
+    assert!(code.starts_with("target triple = \"x86_64-unknown-linux-gnu\"\n\ndeclare"));
+}
+
+#[test]
+fn llvm_code() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.constant("zero", 0);
+    b.arith(BOp::Lt, "c", "x", "zero");
+    b.branch("c", "neg", "done");
+    b.block("neg");
+    b.call("x", "negate", &["x"]);
+    b.jump("done");
+    b.block("done");
+    b.print("x");
+    b.exit();
+    b.function("negate", &["y"], Type::I64);
+    b.arith(BOp::Sub, "y", "zero", "y");
+    b.ret("y");
+    let code = crate::back::llvm::llvm_code(b.finish());
+    let main = "define i32 @main() {
+  %x.addr = alloca i64
+  %zero.addr = alloca i64
+  %c.addr = alloca i64
+  store i64 0, ptr %x.addr
+  store i64 0, ptr %zero.addr
+  store i64 0, ptr %c.addr
+  br label %$entry
+$entry:
+  %1 = call i64 @_cflat_read()
+  store i64 %1, ptr %x.addr
+  store i64 0, ptr %zero.addr
+  %2 = load i64, ptr %x.addr
+  %3 = load i64, ptr %zero.addr
+  %4 = icmp slt i64 %2, %3
+  %5 = zext i1 %4 to i64
+  store i64 %5, ptr %c.addr
+  %6 = load i64, ptr %c.addr
+  %7 = icmp ne i64 %6, 0
+  br i1 %7, label %neg, label %done
+neg:
+  %8 = load i64, ptr %x.addr
+  %9 = call i64 @negate(i64 %8)
+  store i64 %9, ptr %x.addr
+  br label %done
+done:
+  %10 = load i64, ptr %x.addr
+  call void @_cflat_print(i64 %10)
+  ret i32 0
+}
+";
+    assert!(code.contains(main));
+    // Parameters are numbered before the entry block.
+    assert!(code.contains(
+        "define i64 @negate(i64 %0) {
+  %y.addr = alloca i64
+  %zero.addr = alloca i64
+  store i64 %0, ptr %y.addr
+  store i64 0, ptr %zero.addr
+  br label %$entry
+$entry:
+  %2 = load i64, ptr %zero.addr
+"
+    ));
+    assert!(code.contains("  ret i64 %5\n}\n"));
+}
+
+#[cfg(feature = "cranelift")]
+#[test]
+fn jit_runs_like_the_interpreter() {
+    use crate::back::jit;
+    use crate::middle::interp;
+
+    let program = squares();
+
+    for input in ["3", "4", "12"] {
+        let mut expected = vec![];
+        let interp = interp::run(&program, input.as_bytes(), &mut expected);
+        let mut output = vec![];
+        let result = jit::run(&program, input.as_bytes(), &mut output);
+        assert_eq!(output, expected, "input {input}");
+        assert_eq!(result.is_ok(), interp.is_ok(), "input {input}");
+    }
+
+    // Running out of input ends the run with an error.
+    let mut output = vec![];
+    let result = jit::run(&program, "".as_bytes(), &mut output);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Runtime error: Unexpected end of input."
+    );
+    assert!(output.is_empty());
+}
+
+#[test]
+fn stack_maps() {
+    for allocator in [Allocator::Simple, Allocator::GraphColor] {
+        let options = Options {
+            allocator,
+            gc: true,
+            ..Options::default()
+        };
+        let code = code_gen_with(squares(), &options);
+        let main = &code.functions[0];
+        let insns = || main.basic_blocks.iter().flat_map(|b| &b.instructions);
+        assert_eq!(
+            insns().next(),
+            Some(&Instruction::call(Id::from_ref(GC_INIT_FN)))
+        );
+        // Each call that returns has a stack map right after it.  `p` is
+        // live after the calls in the loop, in a slot that it's stored to.
+        let insns = insns().collect::<Vec<_>>();
+        let maps = insns
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [Instruction::Jal { target, .. }, Instruction::StackMap { live, slots }]
+                    if *target != JumpTarget::Global(Id::from_ref(GC_INIT_FN)) =>
+                {
+                    assert!(live.is_empty());
+                    Some((target.clone(), slots.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let calls = insns
+            .iter()
+            .filter(|insn| {
+                matches!(
+                    insn,
+                    Instruction::Jal {
+                        dst: Register::Ra,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(maps.len(), calls - 1, "{allocator:?}");
+        let square = JumpTarget::Global(Id::from_ref("square"));
+        let (_, slots) = maps.iter().find(|(target, _)| *target == square).unwrap();
+        let [offset] = slots[..] else {
+            panic!("{slots:?}")
+        };
+        assert!(insns.iter().any(|insn| matches!(
+            insn,
+            Instruction::Sd { dst: Memory::Mem(Register::Fp, o), .. } if *o == offset
+        )));
+        // No heap pointer is live after reading `n`.
+        let read = JumpTarget::Global(Id::from_ref(READ_FN));
+        assert!(maps.contains(&(read, vec![])));
+
+        let asm = code.asm_code();
+        assert!(asm.contains(&format!(
+            "    call square\n.Lmain.$gc2:\n    # heap pointers: {offset}(fp)\n"
+        )));
+        assert!(asm.contains(&format!("{STACK_MAPS}:\n    .dword {}\n", maps.len())));
+        assert!(asm.contains(&format!("    .dword .Lmain.$gc2, 1, {offset}\n")));
+    }
+    assert!(!code_gen(squares()).asm_code().contains(STACK_MAPS));
+}
+
+#[test]
+fn emu_runs_like_the_interpreter() {
+    use crate::middle::interp;
+
+    let program = squares();
+    let configurations = [
+        Options::default(),
+        Options {
+            allocator: Allocator::GraphColor,
+            layout: true,
+            schedule: true,
+            ..Options::default()
+        },
+        Options {
+            soft_mul_div: true,
+            stack_limit: Some(4096),
+            ..Options::default()
+        },
+        Options {
+            allocator: Allocator::GraphColor,
+            gc: true,
+            ..Options::default()
+        },
+    ];
+    for options in configurations {
+        let code = code_gen_with(program.clone(), &options);
+        for input in ["3", "4", "12"] {
+            let mut expected = vec![];
+            let interp = interp::run(&program, input.as_bytes(), &mut expected);
+            let mut output = vec![];
+            let result = emu::run(&code, input.as_bytes(), &mut output);
+            assert_eq!(output, expected, "input {input}, {options:?}");
+            assert_eq!(result.is_ok(), interp.is_ok(), "input {input}, {options:?}");
+        }
+    }
+
+    let code = code_gen(program);
+    let mut output = vec![];
+    let result = emu::run(&code, "".as_bytes(), &mut output);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Runtime error: Unexpected end of input."
+    );
+    assert!(output.is_empty());
+}
+
+#[test]
+fn trap_div_zero() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.read("y");
+    b.arith(BOp::Div, "z", "x", "y");
+    b.print("z");
+    b.exit();
+    let program = b.finish();
+
+    let code = code_gen(program.clone());
+    let mut output = vec![];
+    emu::run(&code, "7 0".as_bytes(), &mut output).unwrap();
+    assert_eq!(output, b"-1\n");
+
+    for soft_mul_div in [false, true] {
+        let options = Options {
+            trap_div_zero: true,
+            soft_mul_div,
+            ..Options::default()
+        };
+        let code = code_gen_with(program.clone(), &options);
+        let main = &code.functions[0];
+        assert_eq!(
+            main.basic_blocks.last().unwrap().id,
+            Id::from_ref(DIV_BY_ZERO)
+        );
+        let mut output = vec![];
+        emu::run(&code, "7 2".as_bytes(), &mut output).unwrap();
+        assert_eq!(output, b"3\n");
+        let mut output = vec![];
+        let error = emu::run(&code, "7 0".as_bytes(), &mut output).unwrap_err();
+        assert_eq!(error.to_string(), "Runtime error: Division by zero.");
+        assert!(output.is_empty());
+    }
+}
+
+#[test]
+fn trap_overflow() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.read("y");
+    b.arith(BOp::Add, "z", "x", "y");
+    b.print("z");
+    b.arith(BOp::Sub, "z", "x", "y");
+    b.print("z");
+    b.arith(BOp::Mul, "z", "x", "y");
+    b.print("z");
+    b.exit();
+    let program = b.finish();
+    let options = Options {
+        trap_overflow: true,
+        ..Options::default()
+    };
+    let code = code_gen_with(program.clone(), &options);
+    let cases = [
+        ("3 -4", Ok("-1\n7\n-12\n")),
+        (
+            "-4611686018427387904 2",
+            Ok("-4611686018427387902\n-4611686018427387906\n-9223372036854775808\n"),
+        ),
+        ("9223372036854775807 1", Err("")),
+        ("-9223372036854775808 -1", Err("")),
+        ("-9223372036854775808 1", Err("-9223372036854775807\n")),
+        ("4294967296 4294967296", Err("8589934592\n0\n")),
+    ];
+    for (input, expected) in cases {
+        let mut output = vec![];
+        let result = emu::run(&code, input.as_bytes(), &mut output);
+        match expected {
+            Ok(expected) => {
+                assert_eq!(result.unwrap(), 0, "{input}");
+                assert_eq!(String::from_utf8(output).unwrap(), expected, "{input}");
+            }
+            Err(expected) => {
+                assert_eq!(
+                    result.unwrap_err().to_string(),
+                    "Runtime error: Arithmetic overflow.",
+                    "{input}"
+                );
+                assert_eq!(String::from_utf8(output).unwrap(), expected, "{input}");
+            }
+        }
+    }
+
+    // Without the checks, the results wrap around.
+    let mut output = vec![];
+    emu::run(
+        &code_gen(program),
+        "9223372036854775807 1".as_bytes(),
+        &mut output,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "-9223372036854775808\n9223372036854775806\n9223372036854775807\n"
+    );
+}
+
+#[test]
+fn exit_status() {
+    use crate::middle::interp;
+
+    // `main` exits with `x + 1`, unless `check` exits with `x` first.
+    let mut b = Builder::new();
+    b.read("x");
+    b.call("y", "check", &["x"]);
+    b.print("y");
+    b.exit_with("y");
+    b.function("check", &["x"], Type::I64);
+    b.constant("zero", 0);
+    b.arith(BOp::Lt, "c", "x", "zero");
+    b.branch("c", "neg", "ok");
+    b.block("neg");
+    b.exit_with("x");
+    b.block("ok");
+    b.constant("one", 1);
+    b.arith(BOp::Add, "x", "x", "one");
+    b.ret("x");
+    let program = b.finish();
+    assert!(program.to_string().contains("  $exit y\n"));
+
+    for (input, status) in [("5", 6), ("-3", -3)] {
+        let mut output = vec![];
+        let result = interp::run_status(&program, input.as_bytes(), &mut output);
+        assert_eq!(result.unwrap(), status, "input {input}");
+        for options in [
+            Options::default(),
+            Options {
+                allocator: Allocator::GraphColor,
+                ..Options::default()
+            },
+        ] {
+            let code = code_gen_with(program.clone(), &options);
+            let mut output = vec![];
+            let result = emu::run(&code, input.as_bytes(), &mut output);
+            assert_eq!(result.unwrap(), status, "input {input}, {options:?}");
+        }
+        #[cfg(feature = "cranelift")]
+        {
+            let mut output = vec![];
+            let result = crate::back::jit::run(&program, input.as_bytes(), &mut output);
+            assert_eq!(result.unwrap(), status, "input {input}");
+        }
+    }
+
+    let wat = crate::back::wasm::wat_code(program.clone());
+    assert!(wat.contains("(import \"smol\" \"exit\" (func $@exit (param i64)))"));
+    assert!(wat.contains("local.get $x\n        call $@exit\n"));
+    let ll = crate::back::llvm::llvm_code(program);
+    assert!(ll.contains("call void @exit(i32 %"));
+}
+
+#[test]
+fn bit_manipulation() {
+    use crate::middle::interp;
+
+    let program = squares();
+    let zba = Extensions {
+        zba: true,
+        ..Extensions::default()
+    };
+    let options = Options {
+        extensions: zba,
+        ..Options::default()
+    };
+    // `p + (i << 3)` becomes a `sh3add`, and `i` is still live after it.
+    let code = select_with(program.clone(), &options);
+    let body = code.functions[0]
+        .basic_blocks
+        .iter()
+        .find(|b| b.id == Id::from_ref("body"))
+        .unwrap();
+    assert!(body.instructions.iter().any(|insn| matches!(
+        insn,
+        Instruction::Arith {
+            op: ArithOp::Sh3Add,
+            ..
+        }
+    )));
+    assert!(!body.instructions.iter().any(|insn| matches!(
+        insn,
+        Instruction::ArithI {
+            op: ArithOp::Sll,
+            ..
+        }
+    )));
+    let code = code_gen_with(program.clone(), &options);
+    for input in ["3", "12"] {
+        let mut expected = vec![];
+        interp::run(&program, input.as_bytes(), &mut expected).unwrap();
+        let mut output = vec![];
+        emu::run(&code, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(output, expected, "input {input}");
+    }
+    assert!(!code_gen(program.clone()).asm_code().contains("sh3add"));
+
+    // The output enables the extensions for the assembler, and the division
+    // routine takes magnitudes with `max`.
+    let options = Options {
+        soft_mul_div: true,
+        ..options
+    };
+    let code = code_gen_with(program, &options);
+    let extensions = Extensions {
+        zba: true,
+        zbb: true,
+    };
+    let asm = code.asm_code_with(&AsmOptions {
+        extensions,
+        ..AsmOptions::default()
+    });
+    assert!(asm.starts_with("    .option arch, +zba, +zbb\n    .text\n"));
+    assert!(asm.contains("    sub t0, zero, a1\n    max a1, a1, t0\n"));
+    assert!(!code.asm_code().contains("max"));
+}
+
+#[test]
+fn frame_layout() {
+    let options = Options {
+        gc: true,
+        ..Options::default()
+    };
+    let code = code_gen_with(squares(), &options);
+    let layouts = code.frame_layouts();
+    assert_eq!(layouts.len(), code.functions.len());
+    let main = &layouts[0];
+    assert_eq!(main.function, Id::from_ref("main"));
+    // From the top: the return address, the saved frame pointer, the local
+    // variables, and the callee-saved registers down to the stack pointer.
+    let frame_size = main.size - 2 * SLOT_SIZE;
+    assert_eq!((main.return_address, main.saved_fp), (SLOT_SIZE, 0));
+    assert_eq!(main.locals.end, 0);
+    assert!(!main.saved_registers.is_empty());
+    assert_eq!(main.saved_registers[0].1, -frame_size);
+    for (_, offset) in &main.saved_registers {
+        assert!((-frame_size..main.locals.start).contains(offset));
+    }
+    // The prologue saves the registers where the layout says.
+    let asm = code.asm_code();
+    for (r, offset) in &main.saved_registers {
+        assert!(asm.contains(&format!("    sd {r}, {}(sp)\n", offset + frame_size)));
+    }
+    // `p` is a heap pointer live across calls, so it lives in a stack slot.
+    let location = |name: &str| {
+        main.variables
+            .iter()
+            .find(|(v, _)| *v == Id::from_ref(name))
+            .unwrap()
+            .1
+    };
+    match location("p") {
+        VarLocation::Stack(offset) => assert!(main.locals.contains(&offset)),
+        l => panic!("{l}"),
+    }
+    let VarLocation::Reg(n) = location("n") else {
+        panic!("{}", location("n"))
+    };
+    assert!(main.saved_registers.iter().any(|(r, _)| *r == n));
+    assert!(main.to_string().starts_with(&format!(
+        "main: {} bytes\n      8(fp)  return address\n",
+        main.size
+    )));
+}
+
+#[test]
+fn allocate_spills() {
+    use Register::*;
+    use VReg::*;
+
+    // `v0` is used the most, and `v11` loses the tie with the other eleven.
+    let mut instructions = vec![];
+    for v in 0..11 {
+        for imm in 0..3 {
+            instructions.push(Instruction::Li { dst: Virt(v), imm });
+        }
+    }
+    instructions.push(Instruction::Li {
+        dst: Virt(11),
+        imm: 5,
+    });
+    instructions.push(Instruction::Arith {
+        op: ArithOp::Add,
+        dst: Virt(11),
+        lhs: Virt(11),
+        rhs: Virt(0),
+    });
+    let func = regalloc::allocate_function(
+        Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![BasicBlock {
+                id: Id::from_ref("$entry"),
+                instructions,
+            }],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        },
+        Allocator::Simple,
+    );
+    assert_eq!(func.stack_space, 8);
+    assert_eq!(
+        func.used_registers,
+        [S1, S2, S3, S4, S5, S6, S7, S8, S9, S10, S11]
+    );
+    let slot = Memory::Mem(Fp, -8);
+    assert_eq!(
+        func.basic_blocks[0].instructions[33..],
+        [
+            Instruction::Li { dst: T0, imm: 5 },
+            Instruction::Sd { dst: slot, src: T0 },
+            Instruction::Ld { dst: T0, src: slot },
+            Instruction::Arith {
+                op: ArithOp::Add,
+                dst: T0,
+                lhs: T0,
+                rhs: S1,
+            },
+            Instruction::Sd { dst: slot, src: T0 },
+        ]
+    );
+}
+
+#[test]
+fn allocate_saves_written_registers() {
+    use Register::*;
+    use VReg::*;
+
+    // `v1` gets s2, but its only instruction is a move that goes away.  s5
+    // is written without the allocator picking it.
+    let instructions = vec![
+        Instruction::Li {
+            dst: Virt(0),
+            imm: 1,
+        },
+        Instruction::Li {
+            dst: Phys(S5),
+            imm: 2,
+        },
+        Instruction::Sd {
+            dst: Memory::Mem(Virt(0), 0),
+            src: Phys(S5),
+        },
+        Instruction::mov(Virt(1), Virt(1)),
+    ];
+    let func = regalloc::allocate_function(
+        Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![BasicBlock {
+                id: Id::from_ref("$entry"),
+                instructions,
+            }],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        },
+        Allocator::Simple,
+    );
+    assert_eq!(func.used_registers, [S1, S5]);
+    let program = Program {
+        functions: vec![func],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    assert!(program.asm_code().contains(
+        "    addi sp, sp, -16
+    sd s1, 0(sp)
+    sd s5, 8(sp)
+.Lmain.$entry:
+"
+    ));
+}
+
+#[test]
+fn graph_coloring_coalesces() {
+    let mut b = Builder::new();
+    b.read("x");
+    b.read("y");
+    b.print("x");
+    b.arith(BOp::Add, "z", "x", "y");
+    b.copy("w", "z");
+    b.print("w");
+    b.exit();
+    let options = Options {
+        allocator: Allocator::GraphColor,
+        ..Options::default()
+    };
+    let program = code_gen_with(b.finish(), &options);
+    let main = &program.functions[0];
+    // `x` and `y` live across calls, so they need callee-saved registers,
+    // but `z` and `w` go straight to the argument register.
+    let expected = [
+        "jal ra, _cflat_read # global, function",
+        "addi s2, a0, 0",
+        "jal ra, _cflat_read # global, function",
+        "addi s1, a0, 0",
+        "addi a0, s2, 0",
+        "jal ra, _cflat_print # global, function",
+        "add a0, s2, s1",
+        "jal ra, _cflat_print # global, function",
+        "li a0, 0",
+    ];
+    let code = main.basic_blocks[0]
+        .instructions
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(code, expected);
+    assert_eq!(main.used_registers, [Register::S1, Register::S2]);
+    assert_eq!(main.stack_space, 0);
+}
+
+#[test]
+fn graph_coloring_spills() {
+    // All values live across the calls of the other reads and the prints,
+    // so only the callee-saved registers can hold them.
+    let names = (0..15).map(|i| format!("x{i}")).collect::<Vec<_>>();
+    let mut b = Builder::new();
+    for x in &names {
+        b.read(x);
+    }
+    for x in &names {
+        b.print(x);
+    }
+    b.exit();
+    let options = Options {
+        allocator: Allocator::GraphColor,
+        ..Options::default()
+    };
+    let program = code_gen_with(b.finish(), &options);
+    let main = &program.functions[0];
+    assert_eq!(main.used_registers.len(), 11);
+    assert_eq!(main.stack_space, 4 * 8);
+}
+
+#[test]
+fn liveness_across_blocks() {
+    use Register::*;
+
+    // entry: t0 = 1; beq t1, zero, done
+    // body:  a0 = t0; call print
+    // done:  a0 = t1
+    let func = Function {
+        id: Id::from_ref("main"),
+        basic_blocks: vec![
+            block(
+                "$entry",
+                vec![
+                    Instruction::Li { dst: T0, imm: 1 },
+                    Instruction::Branch {
+                        cond: Condition::Equal,
+                        lhs: T1,
+                        rhs: Zero,
+                        target: local("done"),
+                    },
+                ],
+            ),
+            block(
+                "body",
+                vec![
+                    Instruction::mov(A0, T0),
+                    Instruction::call(Id::from_ref("print")),
+                ],
+            ),
+            block("done", vec![Instruction::mov(A0, T1)]),
+        ],
+        stack_space: 0,
+        used_registers: vec![],
+        variables: vec![],
+    };
+    let id = Id::from_ref;
+    let succ = liveness::successors(&func);
+    assert_eq!(succ[&id("$entry")], [id("done"), id("body")]);
+    assert_eq!(succ[&id("done")], [id(EPILOGUE)]);
+
+    let live = Liveness::new(&func);
+    // The call reads the argument registers and changes t1, so only the
+    // other path carries t1 from the entry to `done`.
+    assert_eq!(live.live_in[&id("done")], [T1].into());
+    assert_eq!(
+        live.live_in[&id("body")],
+        [T0, A1, A2, A3, A4, A5, A6, A7].into()
+    );
+    assert_eq!(
+        live.live_in[&id("$entry")],
+        [T1, A1, A2, A3, A4, A5, A6, A7].into()
+    );
+    assert!(live.is_live_out(id("done"), A0));
+    let after = live.live_after_each(&func.basic_blocks[1]);
+    assert_eq!(after[0], [A0, A1, A2, A3, A4, A5, A6, A7].into());
+    assert_eq!(after[1], [T1].into());
+}
+
+#[test]
+fn liveness_defs_and_uses() {
+    use Register::*;
+
+    let call = Instruction::<Register>::call(Id::from_ref("f"));
+    assert!(liveness::defs(&call).contains(&T0));
+    assert!(liveness::defs(&call).contains(&Ra));
+    assert!(!liveness::defs(&call).contains(&S1));
+    assert!(liveness::uses(&call).contains(&A7));
+    let jump = Instruction::<Register>::jump(local("l"));
+    assert_eq!(liveness::defs(&jump), Set::new());
+    let sd = Instruction::Sd {
+        dst: Memory::Mem(Fp, -8),
+        src: Zero,
+    };
+    assert_eq!(liveness::uses(&sd), [Fp].into());
+}
+
+#[test]
+fn verify_rejects_malformed_code() {
+    use crate::back::verify::verify;
+    use Register::*;
+
+    let program = |target, instructions: Vec<Instruction>| Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block("$entry", instructions), block("next", vec![])],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![],
+        target,
+    };
+    let valid = vec![
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst: T0,
+            lhs: T0,
+            rhs: 2047,
+        },
+        Instruction::Ld {
+            dst: T1,
+            src: Memory::Mem(Fp, -2048),
+        },
+        Instruction::Lui {
+            dst: T0,
+            imm: 0xfffff,
+        },
+        Instruction::Li { dst: T0, imm: 4096 },
+        Instruction::Arith {
+            op: ArithOp::Sub,
+            dst: Sp,
+            lhs: Sp,
+            rhs: T0,
+        },
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst: Sp,
+            lhs: Sp,
+            rhs: 2032,
+        },
+        Instruction::Branch {
+            cond: Condition::Equal,
+            lhs: T0,
+            rhs: T1,
+            target: local("next"),
+        },
+        Instruction::jump(local(EPILOGUE)),
+    ];
+    verify(&program(Target::Riscv64, valid)).unwrap();
+    let invalid = [
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst: T0,
+            lhs: T0,
+            rhs: 2048,
+        },
+        Instruction::ArithI {
+            op: ArithOp::Sll,
+            dst: T0,
+            lhs: T0,
+            rhs: 64,
+        },
+        Instruction::Sd {
+            dst: Memory::Mem(Fp, -2056),
+            src: T0,
+        },
+        Instruction::Lui {
+            dst: T0,
+            imm: 0x100000,
+        },
+        Instruction::mov(Fp, T0),
+        Instruction::mov(T0, Fp),
+        Instruction::Sd {
+            dst: Memory::Mem(Sp, 0),
+            src: Fp,
+        },
+        Instruction::ArithI {
+            op: ArithOp::Add,
+            dst: Sp,
+            lhs: Sp,
+            rhs: -8,
+        },
+        Instruction::Arith {
+            op: ArithOp::Add,
+            dst: Sp,
+            lhs: Sp,
+            rhs: T0,
+        },
+        Instruction::jump(local("nowhere")),
+    ];
+    for insn in invalid {
+        let error = verify(&program(Target::Riscv64, vec![insn.clone()]));
+        assert!(error.is_err(), "{insn}");
+    }
+    let li = Instruction::Li {
+        dst: T0,
+        imm: 1 << 32,
+    };
+    verify(&program(Target::Riscv64, vec![li.clone()])).unwrap();
+    assert!(verify(&program(Target::Riscv32, vec![li])).is_err());
+}
+
+#[test]
+fn legalize_boundaries() {
+    use Register::*;
+
+    let addi = |op, rhs| Instruction::ArithI {
+        op,
+        dst: S1,
+        lhs: S2,
+        rhs,
+    };
+    let split = |op, rhs: i64| {
+        vec![
+            Instruction::Li { dst: T6, imm: rhs },
+            Instruction::Arith {
+                op,
+                dst: S1,
+                lhs: S2,
+                rhs: T6,
+            },
+        ]
+    };
+    for imm in [-2048, 0, 2047] {
+        assert_eq!(
+            legalize_instruction(addi(ArithOp::Add, imm)),
+            [addi(ArithOp::Add, imm)]
+        );
+    }
+    for imm in [-2049, 2048, i32::MAX] {
+        assert_eq!(
+            legalize_instruction(addi(ArithOp::Add, imm)),
+            split(ArithOp::Add, imm.into())
+        );
+    }
+    // Subtraction adds the negated immediate.
+    assert_eq!(
+        legalize_instruction(addi(ArithOp::Sub, 2048)),
+        [addi(ArithOp::Sub, 2048)]
+    );
+    assert_eq!(
+        legalize_instruction(addi(ArithOp::Sub, -2048)),
+        split(ArithOp::Sub, -2048)
+    );
+    assert_eq!(
+        legalize_instruction(addi(ArithOp::Mul, 1 << 20)),
+        [addi(ArithOp::Mul, 1 << 20)]
+    );
+
+    let load = |offset| Instruction::Ld {
+        dst: T6,
+        src: Memory::Mem(Fp, offset),
+    };
+    assert_eq!(legalize_instruction(load(-2048)), [load(-2048)]);
+    // The scratch register can't be the destination, which the address
+    // computation would overwrite too early.
+    assert_eq!(
+        legalize_instruction(load(-2056)),
+        [
+            Instruction::Li {
+                dst: T5,
+                imm: -2056
+            },
+            Instruction::Arith {
+                op: ArithOp::Add,
+                dst: T5,
+                lhs: Fp,
+                rhs: T5,
+            },
+            Instruction::Ld {
+                dst: T6,
+                src: Memory::Mem(T5, 0),
+            },
+        ]
+    );
+}
+
+#[test]
+fn asm_code_of_large_frame() {
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block("$entry", vec![])],
+            stack_space: 4096,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    assert!(program
+        .asm_code()
+        .contains("    addi fp, sp, 0\n    li t6, -4096\n    add sp, sp, t6\n"));
+}
+
+#[test]
+fn local_labels() {
+    let label = |func: &str, block: &str| local_label(Id::from_ref(func), Id::from_ref(block));
+    assert_eq!(label("main", "$entry"), ".Lmain.$entry");
+    assert_eq!(label("f", "head.1"), ".Lf.head.1");
+    assert_eq!(label("a.b", "c"), ".La$2eb.c");
+    assert_eq!(label("f", "x-y"), ".Lf.x$2dy");
+    assert_eq!(label("f", "$2d"), ".Lf.$242d");
+    assert_eq!(label("f", "é"), ".Lf.$c3$a9");
+
+    // Names that would collide without escaping get different labels, which
+    // only use the characters the assembler takes, and give the names back.
+    let pairs = [
+        ("a.b", "c"),
+        ("a", "b.c"),
+        ("a$2eb", "c"),
+        ("f", "x-y"),
+        ("f", "x$2dy"),
+        ("f", "x$242dy"),
+        ("f", "$$2d"),
+        ("f", "$"),
+        ("f", "$$"),
+        ("f", "é"),
+        ("f", "$c3$a9"),
+        ("f", ""),
+        ("", "f"),
+        ("f.", ""),
+    ];
+    let mut labels = Set::new();
+    for (func, block) in pairs {
+        let mangled = label(func, block);
+        assert_eq!(mangled, label(func, block));
+        assert!(mangled
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_$.".contains(c)));
+        assert_eq!(
+            unmangle_label(&mangled),
+            Some((Id::from_ref(func), Id::from_ref(block))),
+            "{mangled}"
+        );
+        assert!(labels.insert(mangled.clone()), "{mangled} collides");
+    }
+    assert_eq!(unmangle_label("_cflat_read"), None);
+
+    // The assembly output and its parser use them.
+    let mut b = Builder::new();
+    b.read("x");
+    b.jump("a-b");
+    b.block("a-b");
+    b.print("x");
+    b.exit();
+    let code = code_gen(b.finish());
+    let asm = code.asm_code();
+    assert!(asm.contains("\n.Lmain.a$2db:\n"));
+    let parsed = parse::parse(&asm).unwrap();
+    assert_eq!(
+        parsed.functions[0].basic_blocks,
+        code.functions[0].basic_blocks
+    );
+}
+
+#[test]
+fn asm_code_of_functions() {
+    use Register::*;
+
+    let program = Program {
+        functions: vec![
+            Function {
+                id: Id::from_ref("main"),
+                basic_blocks: vec![block("$entry", vec![Instruction::call(Id::from_ref("f"))])],
+                stack_space: 0,
+                used_registers: vec![],
+                variables: vec![],
+            },
+            Function {
+                id: Id::from_ref("f"),
+                basic_blocks: vec![block("$entry", vec![Instruction::jump(local("$entry"))])],
+                stack_space: 8,
+                used_registers: vec![S1],
+                variables: vec![],
+            },
+        ],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    let expected = "    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+.Lmain.$entry:
+    call f
+.Lmain.$epilogue:
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size main, .-main
+
+    .globl f
+    .type f, @function
+f:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+    addi sp, sp, -16
+    sd s1, 0(sp)
+.Lf.$entry:
+    jal zero, .Lf.$entry
+.Lf.$epilogue:
+    ld s1, 0(sp)
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size f, .-f
+";
+    assert_eq!(program.asm_code(), expected);
+}
+
+#[test]
+fn asm_code_of_pic() {
+    use Register::*;
+
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block(
+                "$entry",
+                vec![
+                    Instruction::La {
+                        dst: A0,
+                        src: Memory::Global {
+                            index: 0,
+                            offset: 8,
+                        },
+                    },
+                    Instruction::Ld {
+                        dst: A1,
+                        src: Memory::Global {
+                            index: 0,
+                            offset: 0,
+                        },
+                    },
+                    Instruction::call(Id::from_ref(PRINT_FN)),
+                ],
+            )],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![GlobalVar::zeroed(Id::from_ref("a"), 2)],
+        target: Target::Riscv64,
+    };
+    let code = program.asm_code_with(&AsmOptions {
+        pic: true,
+        ..AsmOptions::default()
+    });
+    assert!(code.starts_with("    .option pic\n    .text\n"));
+    assert!(code
+        .contains("    lla a0, .Lglobal.a+8\n    ld a1, .Lglobal.a\n    call _cflat_print@plt\n"));
+}
+
+#[test]
+fn asm_code_compressed() {
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block("$entry", vec![])],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    let options = AsmOptions {
+        compressed: true,
+        ..AsmOptions::default()
+    };
+    assert!(program
+        .asm_code_with(&options)
+        .starts_with("    .option rvc\n    .text\n"));
+    assert!(!program.asm_code().contains(".option"));
+}
+
+#[test]
+fn asm_code_for_riscv32() {
+    use Register::*;
+
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block(
+                "$entry",
+                vec![Instruction::Ld {
+                    dst: A0,
+                    src: Memory::Global {
+                        index: 0,
+                        offset: 4,
+                    },
+                }],
+            )],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![GlobalVar {
+            id: Id::from_ref("a"),
+            init: vec![3, 7],
+        }],
+        target: Target::Riscv32,
+    };
+    let code = program.asm_code();
+    assert!(code.contains("    sw ra, 8(sp)\n    sw fp, 0(sp)\n"));
+    assert!(code.contains("    lw a0, .Lglobal.a+4\n"));
+    assert!(code.ends_with("    .p2align 2\n.Lglobal.a:\n    .word 3\n    .word 7\n"));
+}
+
+#[test]
+fn asm_code_of_doubles() {
+    use FRegister::*;
+    use Register::*;
+
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block(
+                "$entry",
+                vec![
+                    Instruction::Fld {
+                        dst: Fs0,
+                        src: Memory::Global {
+                            index: 0,
+                            offset: 0,
+                        },
+                    },
+                    Instruction::FCvtFromInt { dst: Ft0, src: A0 },
+                    Instruction::FArith {
+                        op: FArithOp::Mul,
+                        dst: Fa0,
+                        lhs: Fs0,
+                        rhs: Ft0,
+                    },
+                    Instruction::FCmp {
+                        op: FCmpOp::Less,
+                        dst: A1,
+                        lhs: Ft0,
+                        rhs: Fa0,
+                    },
+                    Instruction::Fsd {
+                        dst: Memory::Mem(Fp, -8),
+                        src: Fa0,
+                    },
+                    Instruction::FCvtToInt { dst: A0, src: Fa0 },
+                ],
+            )],
+            stack_space: 8,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![GlobalVar::zeroed(Id::from_ref("x"), 1)],
+        target: Target::Riscv64,
+    };
+    let expected = "    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd fp, 0(sp)
+    addi fp, sp, 0
+    addi sp, sp, -16
+    fsd fs0, 0(sp)
+.Lmain.$entry:
+    fld fs0, .Lglobal.x, t6
+    fcvt.d.l ft0, a0
+    fmul.d fa0, fs0, ft0
+    flt.d a1, ft0, fa0
+    fsd fa0, -8(fp)
+    fcvt.l.d a0, fa0, rtz
+.Lmain.$epilogue:
+    fld fs0, 0(sp)
+    addi sp, fp, 0
+    ld fp, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    jalr zero, 0(ra)
+    .size main, .-main
+
+    .bss
+    .p2align 3
+.Lglobal.x:
+    .zero 8
+";
+    assert_eq!(program.asm_code(), expected);
+}
+
+#[test]
+fn parse_round_trips() {
+    let program = squares();
+    let configurations = [
+        (Options::default(), AsmOptions::default()),
+        (
+            Options {
+                allocator: Allocator::GraphColor,
+                layout: true,
+                schedule: true,
+                stack_limit: Some(4096),
+                ..Options::default()
+            },
+            AsmOptions {
+                pic: true,
+                ..AsmOptions::default()
+            },
+        ),
+        (
+            Options {
+                soft_mul_div: true,
+                gc: true,
+                ..Options::default()
+            },
+            AsmOptions {
+                system: System::BareMetal,
+                ..AsmOptions::default()
+            },
+        ),
+        (
+            Options {
+                target: Target::Riscv32,
+                ..Options::default()
+            },
+            AsmOptions {
+                system: System::ProxyKernel,
+                compressed: true,
+                ..AsmOptions::default()
+            },
+        ),
+        (
+            Options {
+                target: Target::Riscv32,
+                ..Options::default()
+            },
+            AsmOptions {
+                system: System::BareMetal,
+                ..AsmOptions::default()
+            },
+        ),
+    ];
+    for (options, asm_options) in configurations {
+        let code = code_gen_with(program.clone(), &options);
+        let text = code.asm_code_with(&asm_options);
+        let parsed = parse::parse(&text).unwrap();
+        assert_eq!(parsed.target, code.target);
+        assert_eq!(parsed.functions.len(), code.functions.len());
+        assert_eq!(parsed.asm_code_with(&asm_options), text, "{options:?}");
+    }
+
+    // Line numbers refer to the files of the program.
+    let mut b = Builder::new();
+    b.loc(Span { start: 0, end: 7 });
+    b.read("x");
+    b.exit();
+    let options = Options {
+        source: Some("$read x".into()),
+        debug_file: Some("read.smol".into()),
+        ..Options::default()
+    };
+    let code = code_gen_with(b.finish(), &options);
+    let parsed = parse::parse(&code.asm_code()).unwrap();
+    assert_eq!(
+        parsed.functions[0].basic_blocks,
+        code.functions[0].basic_blocks
+    );
+    assert_eq!(parsed.asm_code(), code.asm_code());
+}
+
+#[test]
+fn parse_fixture() {
+    use Register::*;
+
+    let text = include_str!("testdata/countdown.s");
+    let program = parse::parse(text).unwrap();
+    let main = &program.functions[0];
+    assert_eq!(main.used_registers, [S1]);
+    assert_eq!(
+        main.basic_blocks[1].instructions[0],
+        Instruction::Branch {
+            cond: Condition::Equal,
+            lhs: S1,
+            rhs: Zero,
+            target: local("done"),
+        }
+    );
+    assert_eq!(program.asm_code(), text);
+    let mut output = vec![];
+    assert_eq!(emu::run(&program, "3".as_bytes(), &mut output).unwrap(), 0);
+    assert_eq!(output, b"3\n2\n1\n");
+    // Each round of the loop retires `beq`, `addi`, the `auipc` and `jalr`
+    // of the call, `addi`, and `jal`.
+    let retired = |input: &str| {
+        emu::run_counted(&program, input.as_bytes(), std::io::sink())
+            .unwrap()
+            .1
+    };
+    assert_eq!(retired("4") - retired("3"), 6);
+    // The prologue, the read, the first `beq`, `li`, and the epilogue.
+    assert_eq!(retired("0"), 6 + 3 + 1 + 1 + 6);
+
+    let error = parse::parse(&text.replace("beq s1", "beq x1")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Assembly parse error: Line 16: Unknown register `x1`."
+    );
+}
+
+#[test]
+fn encode() {
+    use Register::*;
+
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![
+                block(
+                    "$entry",
+                    vec![
+                        Instruction::Ld {
+                            dst: T0,
+                            src: Memory::Global {
+                                index: 0,
+                                offset: 0,
+                            },
+                        },
+                        Instruction::Branch {
+                            cond: Condition::Less,
+                            lhs: T0,
+                            rhs: Zero,
+                            target: local("done"),
+                        },
+                        Instruction::call(Id::from_ref("print")),
+                    ],
+                ),
+                block("done", vec![Instruction::jump(local(EPILOGUE))]),
+            ],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![GlobalVar {
+            id: Id::from_ref("x"),
+            init: vec![-2],
+        }],
+        target: Target::Riscv64,
+    };
+    let expected = [
+        0xff010113, // addi sp, sp, -16
+        0x00113423, // sd ra, 8(sp)
+        0x00813023, // sd fp, 0(sp)
+        0x00010413, // addi fp, sp, 0
+        0x00000297, // auipc t0, 0
+        0x0302b283, // ld t0, 48(t0)
+        0x0002c663, // blt t0, zero, 12
+        0x00000097, // auipc ra, 0
+        0x000080e7, // jalr ra, 0(ra)
+        0x0040006f, // jal zero, 4
+        0x00040113, // addi sp, fp, 0
+        0x00013403, // ld fp, 0(sp)
+        0x00813083, // ld ra, 8(sp)
+        0x01010113, // addi sp, sp, 16
+        0x00008067, // jalr zero, 0(ra)
+        0x00000000, // padding
+        0xfffffffe, // x
+        0xffffffff,
+    ];
+    let binary = program.encode().unwrap();
+    let words = binary
+        .bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(words, expected);
+    assert_eq!(binary.functions, Map::from([(Id::from_ref("main"), 0)]));
+    assert_eq!(binary.data, 64);
+    assert_eq!(binary.compressed_code_size(Target::Riscv64), 38);
+    assert_eq!(
+        binary.relocations,
+        vec![encode::Relocation {
+            offset: 28,
+            symbol: Id::from_ref("print"),
+            kind: encode::RelocationKind::Call,
+        }]
+    );
+}
+
+#[test]
+fn compressed_code_size() {
+    // The figures in the documentation of `asm`.
+    for (allocator, full, compressed) in [
+        (Allocator::Simple, 432, 258),
+        (Allocator::GraphColor, 352, 210),
+    ] {
+        let options = Options {
+            allocator,
+            ..Options::default()
+        };
+        let binary = code_gen_with(squares(), &options).encode().unwrap();
+        assert_eq!(binary.data, full);
+        assert_eq!(binary.compressed_code_size(Target::Riscv64), compressed);
+    }
+}
+
+#[test]
+fn decode_inverts_encode() {
+    use disasm::Decoded;
+    use Register::*;
+
+    // Every instruction that encodes to a single word decodes back to
+    // itself, in the form that the encoder takes.
+    let regs = [Zero, Ra, Sp, T0, A0, S11, T6];
+    let fregs = [FRegister::Ft0, FRegister::Fa1, FRegister::Fs11];
+    let mems = [
+        Memory::Mem(Sp, 0),
+        Memory::Mem(A0, -2048),
+        Memory::Mem(T6, 2047),
+    ];
+    for target in [Target::Riscv64, Target::Riscv32] {
+        let shifts = [0, 1, target.word_size() * 8 - 1];
+        let mut code = vec![
+            Instruction::Lui { dst: T0, imm: 0 },
+            Instruction::Lui {
+                dst: A0,
+                imm: 0xfffff,
+            },
+        ];
+        for (i, &dst) in regs.iter().enumerate() {
+            let (lhs, rhs) = (regs[(i + 1) % regs.len()], regs[(i + 3) % regs.len()]);
+            code.push(Instruction::Jalr { dst, target: lhs });
+            for op in [
+                ArithOp::Add,
+                ArithOp::Sub,
+                ArithOp::Mul,
+                ArithOp::Div,
+                ArithOp::And,
+                ArithOp::Or,
+                ArithOp::Xor,
+                ArithOp::Srl,
+                ArithOp::Sra,
+                ArithOp::Sll,
+                ArithOp::Sh1Add,
+                ArithOp::Sh2Add,
+                ArithOp::Sh3Add,
+                ArithOp::AndN,
+                ArithOp::Min,
+                ArithOp::Max,
+            ] {
+                code.push(Instruction::Arith { op, dst, lhs, rhs });
+            }
+            for imm in [-2048, -1, 0, 2, 2047] {
+                for op in [
+                    ArithOp::Add,
+                    ArithOp::Slt,
+                    ArithOp::And,
+                    ArithOp::Or,
+                    ArithOp::Xor,
+                ] {
+                    code.push(Instruction::ArithI {
+                        op,
+                        dst,
+                        lhs,
+                        rhs: imm,
+                    });
+                }
+            }
+            for shift in shifts {
+                for op in [ArithOp::Sll, ArithOp::Srl, ArithOp::Sra] {
+                    code.push(Instruction::ArithI {
+                        op,
+                        dst,
+                        lhs,
+                        rhs: shift,
+                    });
+                }
+            }
+            if target == Target::Riscv64 {
+                code.push(Instruction::Arith {
+                    op: ArithOp::AddW,
+                    dst,
+                    lhs,
+                    rhs,
+                });
+                code.push(Instruction::ArithI {
+                    op: ArithOp::AddW,
+                    dst,
+                    lhs,
+                    rhs: -7,
+                });
+            }
+            for cond in [
+                Condition::Equal,
+                Condition::NotEqual,
+                Condition::Less,
+                Condition::Greater,
+            ] {
+                code.push(Instruction::SCmpZ { dst, lhs: T1, cond });
+            }
+        }
+        for (i, mem) in mems.into_iter().enumerate() {
+            code.push(Instruction::Ld {
+                dst: regs[i],
+                src: mem,
+            });
+            code.push(Instruction::Sd {
+                dst: mem,
+                src: regs[i + 1],
+            });
+            code.push(Instruction::Fld {
+                dst: fregs[i],
+                src: mem,
+            });
+            code.push(Instruction::Fsd {
+                dst: mem,
+                src: fregs[i],
+            });
+        }
+        for (i, &dst) in fregs.iter().enumerate() {
+            let (lhs, rhs) = (fregs[(i + 1) % 3], fregs[(i + 2) % 3]);
+            for op in [FArithOp::Add, FArithOp::Sub, FArithOp::Mul, FArithOp::Div] {
+                code.push(Instruction::FArith { op, dst, lhs, rhs });
+            }
+            for op in [FCmpOp::Equal, FCmpOp::Less, FCmpOp::LessEq] {
+                code.push(Instruction::FCmp {
+                    op,
+                    dst: regs[i],
+                    lhs,
+                    rhs,
+                });
+            }
+            code.push(Instruction::FMv { dst, src: lhs });
+            code.push(Instruction::FCvtFromInt { dst, src: regs[i] });
+            code.push(Instruction::FCvtToInt {
+                dst: regs[i],
+                src: lhs,
+            });
+        }
+
+        let program = Program {
+            functions: vec![Function {
+                id: Id::from_ref("main"),
+                basic_blocks: vec![block("$entry", code.clone())],
+                stack_space: 0,
+                used_registers: vec![],
+                variables: vec![],
+            }],
+            globals: vec![],
+            target,
+        };
+        let decoded = program.encode().unwrap().decode(target).unwrap();
+        // Skip the prologue, and the epilogue after the code.
+        let prologue = program.functions[0].prologue().len();
+        let decoded = decoded[prologue..prologue + code.len()]
+            .iter()
+            .map(|(_, insn)| insn.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decoded,
+            code.into_iter()
+                .map(Decoded::Instruction)
+                .collect::<Vec<_>>(),
+            "{target:?}"
+        );
+    }
+
+    // Pseudo-instructions decode to what they expand to.
+    let word = |insn: &Instruction| {
+        let program = Program {
+            functions: vec![Function {
+                id: Id::from_ref("main"),
+                basic_blocks: vec![block("$entry", vec![insn.clone()]), block("next", vec![])],
+                stack_space: 0,
+                used_registers: vec![],
+                variables: vec![],
+            }],
+            globals: vec![],
+            target: Target::Riscv64,
+        };
+        let binary = program.encode().unwrap();
+        let at = 4 * program.functions[0].prologue().len();
+        let word = u32::from_le_bytes(binary.bytes[at..at + 4].try_into().unwrap());
+        disasm::decode(word, at as u32, Target::Riscv64).unwrap()
+    };
+    assert_eq!(
+        word(&Instruction::Branch {
+            cond: Condition::LessEq,
+            lhs: A0,
+            rhs: A1,
+            target: local("next"),
+        }),
+        Decoded::Instruction(Instruction::Branch {
+            cond: Condition::GreaterEq,
+            lhs: A1,
+            rhs: A0,
+            target: local("0x14"),
+        })
+    );
+    assert_eq!(
+        word(&Instruction::ArithI {
+            op: ArithOp::Sub,
+            dst: A0,
+            lhs: A0,
+            rhs: 5,
+        }),
+        Decoded::Instruction(Instruction::ArithI {
+            op: ArithOp::Add,
+            dst: A0,
+            lhs: A0,
+            rhs: -5,
+        })
+    );
+    assert_eq!(
+        word(&Instruction::jump(local("next"))),
+        Decoded::Instruction(Instruction::Jal {
+            dst: Zero,
+            target: local("0x14"),
+        })
+    );
+    assert_eq!(
+        disasm::decode(0, 0x10, Target::Riscv64)
+            .unwrap_err()
+            .to_string(),
+        "Cannot decode 0x00000000 at 0x10"
+    );
+}
+
+#[test]
+fn disassemble() {
+    let program = code_gen(squares());
+    let binary = program.encode().unwrap();
+    let listing = binary.disassemble(Target::Riscv64).unwrap();
+    let lines = listing.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "00000000 <main>:");
+    assert_eq!(lines[1], "       0:\tff010113\taddi sp, sp, -16");
+    assert_eq!(
+        lines.len(),
+        binary.functions.len() + binary.decode(Target::Riscv64).unwrap().len()
+    );
+    let call = binary.relocations[0].offset;
+    assert!(listing.contains(&format!(
+        "{call:8x}:\t00000097\tauipc ra, 0x0 # {}",
+        binary.relocations[0].symbol
+    )));
+}
+
+#[test]
+fn code_stats() {
+    // The variables that don't get a callee-saved register spill.
+    let mut b = Builder::new();
+    for i in 0..12 {
+        b.read(&format!("x{i}"));
+    }
+    b.arith(BOp::Mul, "y", "x0", "x1");
+    b.print("y");
+    for i in 0..12 {
+        b.print(&format!("x{i}"));
+    }
+    b.exit();
+    let code = code_gen(b.finish());
+    let stats = code.stats();
+    let main = &stats.functions[0];
+    assert_eq!(main.name, Id::from_ref("main"));
+    assert_eq!(main.calls, 25);
+    assert_eq!(main.mul_div, 1);
+    assert_eq!(main.spill_stores, 2);
+    assert_eq!(main.spill_loads, 2);
+    assert_eq!(main.frame_size, 128);
+    assert_eq!(
+        main.code_bytes,
+        code.functions[0].code_size(code.target).unwrap()
+    );
+    assert_eq!(stats.total().instructions(), main.instructions());
+
+    let json = stats.to_json();
+    assert!(json.starts_with("[\n  {\"function\":\"main\",\"instructions\":"));
+    assert!(json.contains("\"spill_loads\":2,\"spill_stores\":2}"));
+    let table = stats.to_string();
+    assert_eq!(table.lines().count(), 3);
+    assert!(table.lines().nth(2).unwrap().starts_with("total"));
+}
+
+#[test]
+fn link_detects_toolchains() {
+    let dir = std::env::temp_dir().join(format!("smol-toolchains-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = std::env::join_paths([&dir]).unwrap();
+
+    let error = link::Toolchain::detect_in(&path, Target::Riscv64, System::Linux).unwrap_err();
+    assert!(error.to_string().contains(link::CC_VAR));
+
+    std::fs::write(dir.join("clang"), "").unwrap();
+    assert_eq!(
+        link::Toolchain::detect_in(&path, Target::Riscv32, System::Linux).unwrap(),
+        link::Toolchain {
+            driver: dir.join("clang"),
+            args: vec!["--target=riscv32-linux-gnu".into()],
+        }
+    );
+
+    // Cross compilers come before clang, and those for the target first, and
+    // then the bare-metal ones for the proxy kernel.
+    std::fs::write(dir.join("riscv64-linux-gnu-gcc"), "").unwrap();
+    std::fs::write(dir.join("riscv64-unknown-elf-gcc"), "").unwrap();
+    std::fs::write(dir.join("riscv32-unknown-elf-gcc"), "").unwrap();
+    let driver = |target, system| {
+        link::Toolchain::detect_in(&path, target, system)
+            .unwrap()
+            .driver
+    };
+    let gcc = |name| dir.join(name);
+    assert_eq!(
+        driver(Target::Riscv64, System::Linux),
+        gcc("riscv64-linux-gnu-gcc")
+    );
+    assert_eq!(
+        driver(Target::Riscv64, System::ProxyKernel),
+        gcc("riscv64-unknown-elf-gcc")
+    );
+    assert_eq!(
+        driver(Target::Riscv64, System::BareMetal),
+        gcc("riscv64-unknown-elf-gcc")
+    );
+    assert_eq!(
+        driver(Target::Riscv32, System::Linux),
+        gcc("riscv32-unknown-elf-gcc")
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn link_uses_the_builtin_runtime() {
+    // A shell that stands in for the driver, and copies the C source it
+    // compiles to the output.
+    let script = r#"while [ $# -gt 0 ]; do
+        case $1 in -o) out=$2; shift ;; *.c) src=$1 ;; esac
+        shift
+    done
+    cp "$src" "$out""#;
+    let toolchain = link::Toolchain {
+        driver: "sh".into(),
+        args: vec!["-c".into(), script.into(), "sh".into()],
+    };
+    let output = std::env::temp_dir().join(format!("smol-runtime-{}", std::process::id()));
+    let options = AsmOptions::default();
+    toolchain
+        .link("", &[], &output, Target::Riscv64, &options)
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        runtime::RUNTIME_SOURCE
+    );
+    std::fs::remove_file(&output).unwrap();
+
+    // Not with the user's runtime, or on bare metal.
+    let own = [std::path::PathBuf::from("runtime.o")];
+    assert_eq!(
+        toolchain
+            .link("", &own, &output, Target::Riscv64, &options)
+            .unwrap_err()
+            .to_string(),
+        "Cannot find the runtime file `runtime.o`."
+    );
+    let bare_metal = AsmOptions {
+        system: System::BareMetal,
+        ..AsmOptions::default()
+    };
+    assert!(toolchain
+        .link("", &[], &output, Target::Riscv64, &bare_metal)
+        .is_err());
+
+    for name in [
+        READ_FN,
+        PRINT_FN,
+        ALLOC_FN,
+        STACK_OVERFLOW_FN,
+        DIV_BY_ZERO_FN,
+        OVERFLOW_FN,
+        PROFILE_DUMP_FN,
+    ] {
+        assert!(
+            runtime::RUNTIME_SOURCE.contains(&format!("{name}(")),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn assemble_objects() {
+    // A shell that stands in for the driver, and copies the assembly code to
+    // the output if it only assembles.
+    let script = r#"while [ $# -gt 0 ]; do
+        case $1 in -c) only=1 ;; -o) out=$2; shift ;; *.s) src=$1 ;; esac
+        shift
+    done
+    [ -n "$only" ] && cp "$src" "$out""#;
+    let toolchain = link::Toolchain {
+        driver: "sh".into(),
+        args: vec!["-c".into(), script.into(), "sh".into()],
+    };
+    let output = std::env::temp_dir().join(format!("smol-object-{}.o", std::process::id()));
+    let asm = code_gen(squares()).asm_code();
+    toolchain.assemble(&asm, &output, Target::Riscv64).unwrap();
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), asm);
+    std::fs::remove_file(&output).unwrap();
+
+    let failing = link::Toolchain {
+        driver: "false".into(),
+        args: vec![],
+    };
+    let error = failing
+        .assemble(&asm, &output, Target::Riscv64)
+        .unwrap_err();
+    assert!(error.to_string().starts_with("`false` failed"));
+}
+
+#[test]
+fn runner_detects_qemu() {
+    let dir = std::env::temp_dir().join(format!("smol-qemu-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = std::env::join_paths([&dir]).unwrap();
+
+    let error = runner::Qemu::detect_in(&path, Target::Riscv64).unwrap_err();
+    assert!(matches!(&error, runner::RunError::NoQemu(name) if name == "qemu-riscv64"));
+    assert!(error.to_string().contains(runner::QEMU_VAR));
+
+    std::fs::write(dir.join("qemu-riscv32"), "").unwrap();
+    assert_eq!(
+        runner::Qemu::detect_in(&path, Target::Riscv32).unwrap(),
+        runner::Qemu {
+            program: dir.join("qemu-riscv32"),
+            args: vec![],
+        }
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn runner_detects_spike() {
+    let dir = std::env::temp_dir().join(format!("smol-spike-{}", std::process::id()));
+    let riscv = dir.join("riscv");
+    let pk = riscv.join("riscv64-unknown-elf/bin/pk");
+    std::fs::create_dir_all(pk.parent().unwrap()).unwrap();
+    let path = std::env::join_paths([&dir]).unwrap();
+    let detect = |riscv| runner::Spike::detect_in(&path, riscv, Target::Riscv64);
+
+    let error = detect(Some(&riscv)).unwrap_err();
+    assert!(matches!(&error, runner::RunError::NoSpike(name) if name == "spike"));
+    std::fs::write(dir.join("spike"), "").unwrap();
+    let error = detect(Some(&riscv)).unwrap_err();
+    assert!(matches!(&error, runner::RunError::NoSpike(name) if name == "pk"));
+
+    // The proxy kernel under `$RISCV` comes before the one on the `PATH`.
+    std::fs::write(dir.join("pk"), "").unwrap();
+    assert_eq!(
+        detect(None).unwrap().args[1],
+        dir.join("pk").to_str().unwrap()
+    );
+    std::fs::write(&pk, "").unwrap();
+    assert_eq!(
+        detect(Some(&riscv)).unwrap(),
+        runner::Spike {
+            program: dir.join("spike"),
+            args: vec!["--isa=rv64imafd".into(), pk.to_str().unwrap().into()],
+        }
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn runner_captures_the_outcome() {
+    // A shell that stands in for QEMU, and echoes its input.
+    let qemu = runner::Qemu {
+        program: "sh".into(),
+        args: vec![
+            "-c".into(),
+            "cat; echo \"ran $*\" >&2; exit 3".into(),
+            "qemu".into(),
+        ],
+    };
+    let outcome = qemu
+        .run(
+            std::path::Path::new("prog"),
+            &["a".into(), "b c".into()],
+            b"1 2\n",
+        )
+        .unwrap();
+    assert_eq!(
+        outcome,
+        runner::Outcome {
+            stdout: b"1 2\n".to_vec(),
+            stderr: b"ran prog a b c\n".to_vec(),
+            status: Some(3),
+        }
+    );
+}
+
+#[test]
+fn asm_code_for_the_proxy_kernel() {
+    let program = Program {
+        functions: vec![Function {
+            id: Id::from_ref("main"),
+            basic_blocks: vec![block(
+                "$entry",
+                vec![Instruction::call(Id::from_ref(EXIT_FN))],
+            )],
+            stack_space: 0,
+            used_registers: vec![],
+            variables: vec![],
+        }],
+        globals: vec![],
+        target: Target::Riscv64,
+    };
+    let code = program.asm_code_with(&AsmOptions {
+        system: System::ProxyKernel,
+        ..AsmOptions::default()
+    });
+    assert!(code.contains(".Lmain.$entry:\n    call _cflat_exit\n"));
+    let entry = "    .globl _start
+    .type _start, @function
+_start:
+    .option push
+    .option norelax
+    lla gp, __global_pointer$
+    .option pop
+    call main
+    .size _start, .-_start
+    .globl _cflat_exit
+    .type _cflat_exit, @function
+_cflat_exit:
+    li a7, 93
+    ecall
+    .size _cflat_exit, .-_cflat_exit
+";
+    assert!(code.ends_with(entry), "{code}");
+    assert!(!program.asm_code().contains("_start"));
+}
+
+#[test]
+fn asm_code_for_bare_metal() {
+    let program = code_gen(squares());
+    let code = program.asm_code_with(&AsmOptions {
+        system: System::BareMetal,
+        ..AsmOptions::default()
+    });
+    assert!(code.contains("    call _cflat_exit\n"), "{code}");
+    for name in [
+        "_start",
+        STANDALONE_EXIT_FN,
+        PRINT_FN,
+        READ_FN,
+        ALLOC_FN,
+        STACK_OVERFLOW_FN,
+        DIV_BY_ZERO_FN,
+        OVERFLOW_FN,
+        PROFILE_DUMP_FN,
+    ] {
+        assert!(code.contains(&format!("\n{name}:\n")), "{name}");
+    }
+
+    // The runtime of RV32 keeps the heap pointer in a word of 4 bytes.
+    let options = Options {
+        target: Target::Riscv32,
+        ..Options::default()
+    };
+    let code = code_gen_with(squares(), &options).asm_code_with(&AsmOptions {
+        system: System::BareMetal,
+        ..AsmOptions::default()
+    });
+    assert!(code.contains("    lw t0, .Lheap\n"), "{code}");
+    assert!(!code.contains("    sd "), "{code}");
+
+    // The linker script defines the symbols that the startup code and the
+    // runtime use.
+    let script = crate::back::baremetal::LINKER_SCRIPT;
+    assert!(script.contains("ENTRY(_start)"));
+    for symbol in [
+        "__global_pointer$",
+        "__bss_start",
+        "__bss_end",
+        "__heap_start",
+        "__heap_end",
+        "__stack_top",
+    ] {
+        assert!(code.contains(symbol), "{symbol}");
+        assert!(script.contains(&format!("{symbol} = ")), "{symbol}");
+    }
+}
+
+#[test]
+fn materialize_constants() {
+    use Register::*;
+
+    // Run the instructions, which only write `t0`.
+    fn run(code: &[Instruction]) -> i64 {
+        let mut t0 = 0i64;
+        for insn in code {
+            let reg = |r: &Register| match r {
+                Zero => 0,
+                T0 => t0,
+                _ => unreachable!(),
+            };
+            t0 = match insn {
+                Instruction::Lui { imm, .. } => i64::from(imm << 12),
+                Instruction::ArithI { op, lhs, rhs, .. } => {
+                    let (lhs, rhs) = (reg(lhs), i64::from(*rhs));
+                    match op {
+                        ArithOp::Add => lhs.wrapping_add(rhs),
+                        ArithOp::AddW => i64::from((lhs as i32).wrapping_add(rhs as i32)),
+                        ArithOp::Sll => lhs << rhs,
+                        _ => unreachable!(),
+                    }
+                }
+                _ => unreachable!(),
+            };
+        }
+        t0
+    }
+
+    let values = [
+        0,
+        1,
+        -1,
+        2047,
+        2048,
+        -2048,
+        -2049,
+        0x7fff_f800,
+        i32::MAX.into(),
+        i32::MIN.into(),
+        1 << 31,
+        -(1 << 31) - 1,
+        1 << 32,
+        1 << 40,
+        0x1234_5678_9abc_def0,
+        -0x1234_5678_9abc_def0,
+        0x7fff_ffff_ffff_f800,
+        i64::MAX,
+        i64::MIN,
+    ];
+    for value in values {
+        let code = materialize(T0, value);
+        assert_eq!(run(&code), value, "{value:#x}: {code:?}");
+        assert!(code.len() <= 8);
+        assert!(code
+            .iter()
+            .all(|i| legalize_instruction(i.clone()).len() == 1));
+    }
+    assert_eq!(materialize(T0, 1 << 40).len(), 2);
+    assert_eq!(
+        materialize(T0, 0x1_0000_0001),
+        [
+            Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: T0,
+                lhs: Zero,
+                rhs: 1,
+            },
+            Instruction::ArithI {
+                op: ArithOp::Sll,
+                dst: T0,
+                lhs: T0,
+                rhs: 32,
+            },
+            Instruction::ArithI {
+                op: ArithOp::Add,
+                dst: T0,
+                lhs: T0,
+                rhs: 1,
+            },
+        ]
+    );
+}
+
+#[test]
+fn layout_follows_jumps() {
+    use Register::*;
+
+    // `$entry` jumps to `b`, which jumps to `c`, which returns.
+    let func = Function {
+        id: Id::from_ref("main"),
+        basic_blocks: vec![
+            block(
+                "$entry",
+                vec![
+                    Instruction::Li { dst: A0, imm: 1 },
+                    Instruction::jump(local("b")),
+                ],
+            ),
+            block(
+                "c",
+                vec![
+                    Instruction::Li { dst: A0, imm: 3 },
+                    Instruction::jump(local(EPILOGUE)),
+                ],
+            ),
+            block(
+                "b",
+                vec![
+                    Instruction::Li { dst: A0, imm: 2 },
+                    Instruction::jump(local("c")),
+                ],
+            ),
+        ],
+        stack_space: 0,
+        used_registers: vec![],
+        variables: vec![],
+    };
+    let func = layout::layout_function(func, None);
+    assert_eq!(
+        func.basic_blocks,
+        [
+            block("$entry", vec![Instruction::Li { dst: A0, imm: 1 }]),
+            block("b", vec![Instruction::Li { dst: A0, imm: 2 }]),
+            block("c", vec![Instruction::Li { dst: A0, imm: 3 }]),
+        ]
+    );
+}
+
+#[test]
+fn layout_keeps_better_order() {
+    use Register::*;
+
+    // `$entry` branches to `rare` if t0 is zero, and `common` jumps to `done`,
+    // which `rare` falls through to.  Chaining `common` and `done` would leave
+    // `rare` to jump to `done`, and `done` to jump to the epilogue.
+    let func = Function {
+        id: Id::from_ref("main"),
+        basic_blocks: vec![
+            block(
+                "$entry",
+                vec![Instruction::Branch {
+                    cond: Condition::Equal,
+                    lhs: T0,
+                    rhs: Zero,
+                    target: local("rare"),
+                }],
+            ),
+            block(
+                "common",
+                vec![
+                    Instruction::Li { dst: A0, imm: 1 },
+                    Instruction::jump(local("done")),
+                ],
+            ),
+            block("rare", vec![Instruction::Li { dst: A0, imm: 2 }]),
+            block("done", vec![]),
+        ],
+        stack_space: 0,
+        used_registers: vec![],
+        variables: vec![],
+    };
+    assert_eq!(layout::layout_function(func.clone(), None), func);
+}
+
+#[test]
+fn layout_by_profile() {
+    use Register::*;
+
+    // A loop that counts t0 down, with its body last.
+    let func = Function {
+        id: Id::from_ref("main"),
+        basic_blocks: vec![
+            block("$entry", vec![Instruction::Li { dst: T0, imm: 10 }]),
+            block(
+                "header",
+                vec![Instruction::Branch {
+                    cond: Condition::NotEqual,
+                    lhs: T0,
+                    rhs: Zero,
+                    target: local("body"),
+                }],
+            ),
+            block(
+                "exit",
+                vec![
+                    Instruction::Li { dst: A0, imm: 0 },
+                    Instruction::jump(local(EPILOGUE)),
+                ],
+            ),
+            block(
+                "body",
+                vec![
+                    Instruction::ArithI {
+                        op: ArithOp::Sub,
+                        dst: T0,
+                        lhs: T0,
+                        rhs: 1,
+                    },
+                    Instruction::jump(local("header")),
+                ],
+            ),
+        ],
+        stack_space: 0,
+        used_registers: vec![],
+        variables: vec![],
+    };
+    // Without a profile, the exit is as likely as the body.
+    assert_eq!(layout::layout_function(func.clone(), None), func);
+
+    let profile = Profile::parse(
+        "# smol profile
+main $entry 1
+main header 11
+main body 10
+main exit 1
+",
+    )
+    .unwrap();
+    let func = layout::layout_function(func, Some(&profile));
+    // The body falls through from the header now, and the exit comes last.
+    assert_eq!(
+        func.basic_blocks,
+        [
+            block("$entry", vec![Instruction::Li { dst: T0, imm: 10 }]),
+            block(
+                "header",
+                vec![Instruction::Branch {
+                    cond: Condition::Equal,
+                    lhs: T0,
+                    rhs: Zero,
+                    target: local("exit"),
+                }],
+            ),
+            block(
+                "body",
+                vec![
+                    Instruction::ArithI {
+                        op: ArithOp::Sub,
+                        dst: T0,
+                        lhs: T0,
+                        rhs: 1,
+                    },
+                    Instruction::jump(local("header")),
+                ],
+            ),
+            block("exit", vec![Instruction::Li { dst: A0, imm: 0 }]),
+        ]
+    );
+}
+
+#[test]
+fn schedule_separates_loads() {
+    use Register::*;
+
+    let load = Instruction::Ld {
+        dst: S1,
+        src: Memory::Mem(Fp, -8),
+    };
+    let use_ = Instruction::Arith {
+        op: ArithOp::Add,
+        dst: S2,
+        lhs: S1,
+        rhs: S1,
+    };
+    let li = |dst| Instruction::Li { dst, imm: 1 };
+    // The independent `li`s fill the cycles until the load is ready.
+    assert_eq!(
+        schedule::schedule_block(vec![load.clone(), use_.clone(), li(S3), li(S4)]),
+        [load, li(S3), li(S4), use_]
+    );
+}
+
+#[test]
+fn schedule_keeps_dependencies() {
+    use Register::*;
+
+    let ld = |dst, base, offset| Instruction::Ld {
+        dst,
+        src: Memory::Mem(base, offset),
+    };
+    let sd = |base, offset, src| Instruction::Sd {
+        dst: Memory::Mem(base, offset),
+        src,
+    };
+    let code = vec![
+        Instruction::Li { dst: S1, imm: 1 },
+        sd(Fp, -8, S1),
+        // Reads the slot just stored to.
+        ld(S2, Fp, -8),
+        // Overwrites s1, which the store reads.
+        Instruction::Li { dst: S1, imm: 2 },
+        // May store to the same word as the loads.
+        sd(S3, 0, S1),
+        ld(S4, Fp, -16),
+        Instruction::call(Id::from_ref("f")),
+        ld(S5, Fp, -24),
+        Instruction::jump(local("next")),
+    ];
+    let scheduled = schedule::schedule_block(code.clone());
+    let position = |insn: &Instruction| scheduled.iter().position(|i| i == insn).unwrap();
+    for (i, j) in [
+        (0, 1),
+        (1, 2),
+        (1, 3),
+        (2, 4),
+        (3, 4),
+        (4, 5),
+        (5, 6),
+        (6, 7),
+        (7, 8),
+    ] {
+        assert!(
+            position(&code[i]) < position(&code[j]),
+            "{} moved past {}",
+            code[j],
+            code[i]
+        );
+    }
+    assert_eq!(scheduled.len(), code.len());
+}
@@ -0,0 +1,193 @@
+//! The backend verifier.
+//!
+//! This checks the invariants that the assembly output relies on, after
+//! register allocation and legalization:
+//! - Immediates fit in their encodings (see [crate::back::legalize]): 12 bits
+//!   for the immediates of arithmetic and the offsets of memory accesses, 20
+//!   bits for `lui`, less than the word size for shift amounts, and 32 bits
+//!   for `li` on RV32.
+//! - `fp` is the frame pointer and nothing else: the code of a function never
+//!   writes it, and only reads it as the base of a memory access.  It's the
+//!   same register as `s0`, which the backend never uses (see
+//!   [crate::back::asm]).
+//! - Every local jump target is a block of the function, or its epilogue.
+//! - The stack pointer only moves by multiples of 16 bytes, which keeps it
+//!   aligned as the ABI requires.  Moves by amounts that don't fit in an
+//!   immediate load them with the `li` right before.
+//!
+//! The prologue is checked too, except for the frame pointer, which it sets.
+
+use std::fmt::Debug;
+
+use derive_more::derive::Display;
+
+use crate::back::asm::*;
+use crate::back::legalize::{fits_arith_i, fits_imm12, legalize_instruction};
+use crate::common::*;
+
+#[derive(Display)]
+#[display("Malformed backend program: {}", self.0)]
+pub struct VerifyError(String);
+
+impl Debug for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+type VerifyResult = Result<(), VerifyError>;
+
+fn error<T>(msg: String) -> Result<T, VerifyError> {
+    Err(VerifyError(msg))
+}
+
+/// The alignment of the stack pointer in bytes.
+const STACK_ALIGNMENT: i64 = 16;
+
+/// Check that the program can be emitted.
+pub fn verify(program: &Program) -> VerifyResult {
+    for func in &program.functions {
+        verify_function(func, program.target)
+            .map_err(|VerifyError(msg)| VerifyError(format!("In function `{}`: {msg}", func.id)))?;
+    }
+    Ok(())
+}
+
+fn verify_function(func: &Function, target: Target) -> VerifyResult {
+    let prologue = func
+        .prologue()
+        .into_iter()
+        .flat_map(legalize_instruction)
+        .collect::<Vec<_>>();
+    verify_code(&prologue, target)
+        .map_err(|VerifyError(msg)| VerifyError(format!("In the prologue: {msg}")))?;
+    let labels = func
+        .basic_blocks
+        .iter()
+        .map(|b| b.id)
+        .chain([Id::from_ref(EPILOGUE)])
+        .collect::<Set<_>>();
+    for block in &func.basic_blocks {
+        let in_block = |VerifyError(msg)| VerifyError(format!("In block `{}`: {msg}", block.id));
+        verify_code(&block.instructions, target).map_err(in_block)?;
+        for insn in &block.instructions {
+            verify_frame_pointer(insn).map_err(in_block)?;
+            if let Instruction::Jal {
+                target: JumpTarget::Local(label),
+                ..
+            }
+            | Instruction::Branch {
+                target: JumpTarget::Local(label),
+                ..
+            } = insn
+            {
+                if !labels.contains(label) {
+                    return error(format!(
+                        "In block `{}`: `{insn}` jumps to an unknown block `{label}`.",
+                        block.id
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the immediates and the stack pointer adjustments of a sequence of
+/// instructions.
+fn verify_code(code: &[Instruction], target: Target) -> VerifyResult {
+    for (i, insn) in code.iter().enumerate() {
+        verify_immediates(insn, target)?;
+        verify_stack_pointer(insn, i.checked_sub(1).map(|j| &code[j]))?;
+    }
+    Ok(())
+}
+
+fn verify_immediates(insn: &Instruction, target: Target) -> VerifyResult {
+    use Instruction::*;
+
+    let bits = target.word_size() * 8;
+    let fits = match insn {
+        ArithI {
+            op: ArithOp::Sll | ArithOp::Srl | ArithOp::Sra,
+            rhs,
+            ..
+        } => (0..bits).contains(rhs),
+        ArithI { op, rhs, .. } => fits_arith_i(*op, *rhs),
+        Lui { imm, .. } => (0..=0xfffff).contains(imm),
+        Li { imm, .. } => target == Target::Riscv64 || i32::try_from(*imm).is_ok(),
+        La { src: mem, .. }
+        | Ld { src: mem, .. }
+        | Sd { dst: mem, .. }
+        | Fld { src: mem, .. }
+        | Fsd { dst: mem, .. } => match mem {
+            Memory::Mem(_, offset) => fits_imm12((*offset).into()),
+            Memory::Global { .. } => true,
+        },
+        _ => true,
+    };
+    if fits {
+        Ok(())
+    } else {
+        error(format!("The immediate of `{insn}` doesn't fit."))
+    }
+}
+
+/// Check that the instruction only uses `fp` as the base of a memory access.
+fn verify_frame_pointer(insn: &Instruction) -> VerifyResult {
+    if insn.def() == Some(Register::Fp) {
+        return error(format!("`{insn}` writes the frame pointer."));
+    }
+    let base = match insn {
+        Instruction::La { src: mem, .. }
+        | Instruction::Ld { src: mem, .. }
+        | Instruction::Sd { dst: mem, .. }
+        | Instruction::Fld { src: mem, .. }
+        | Instruction::Fsd { dst: mem, .. } => mem.used_registers(),
+        _ => None,
+    };
+    let reads = insn.uses().iter().filter(|r| **r == Register::Fp).count();
+    if reads > usize::from(base == Some(Register::Fp)) {
+        return error(format!(
+            "`{insn}` uses the frame pointer as a general-purpose register."
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the instruction keeps the stack pointer aligned.  `prev` is the
+/// instruction right before it, if any.
+fn verify_stack_pointer(insn: &Instruction, prev: Option<&Instruction>) -> VerifyResult {
+    use Instruction::*;
+
+    if insn.def() != Some(Register::Sp) {
+        return Ok(());
+    }
+    let amount = match insn {
+        ArithI {
+            op: ArithOp::Add | ArithOp::Sub,
+            lhs: Register::Sp,
+            rhs,
+            ..
+        } => Some(i64::from(*rhs)),
+        Arith {
+            op: ArithOp::Add | ArithOp::Sub,
+            lhs: Register::Sp,
+            rhs,
+            ..
+        } => match prev {
+            Some(Li { dst, imm }) if dst == rhs => Some(*imm),
+            _ => None,
+        },
+        // Restoring the stack pointer from the frame pointer in the epilogue
+        // is the only other way to set it, and the body has no epilogue.
+        _ => None,
+    };
+    match amount {
+        Some(amount) if amount % STACK_ALIGNMENT == 0 => Ok(()),
+        Some(_) => error(format!("`{insn}` misaligns the stack pointer.")),
+        None => error(format!(
+            "`{insn}` sets the stack pointer by an unknown amount."
+        )),
+    }
+}
@@ -0,0 +1,353 @@
+//! The WebAssembly backend.
+//!
+//! This lowers a tiny IR program straight to a WebAssembly module in the text
+//! format (WAT), without going through the RISC-V instructions.  Every value
+//! is an `i64`, including addresses, and every variable becomes a local of
+//! its function, which WebAssembly initializes to zero like the tiny IR does.
+//! Functions take their parameters as `i64`s and return an `i64`, and `main`
+//! is exported under its own name.
+//!
+//! The module imports its I/O from the host, which implements them however
+//! it likes (a browser page, or a test harness around wasmtime):
+//!
+//! - `smol.print` takes the value to print.
+//! - `smol.read` returns the next number of the input.
+//! - `smol.exit` ends the program with the given exit status.  Only functions
+//!   other than `main` call it, since `main` ends the program by returning
+//!   the status.
+//!
+//! WebAssembly only has structured control flow, so each function runs its
+//! blocks in a loop around a dispatch on the next block.  Block `i` is
+//! nested `i` levels deep in `block`s, and the `br_table` at the bottom
+//! breaks out of the right number of them to land on its code, which is
+//! followed by the code of the next block.  A jump to the next block falls
+//! through, and any other jump sets the next block and goes around the loop.
+//!
+//! The heap is the exported memory.  The module allocates words by bumping
+//! a pointer and growing the memory as needed, and never frees them, so
+//! allocations are zeroed like the tiny IR says.  Division follows the
+//! RISC-V semantics of smol, which WebAssembly's would trap on, so it goes
+//! through a helper function too.  Profile counters are exported as mutable
+//! globals named `count0`, `count1`, and so on, for the host to read when the
+//! program ends.
+
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::{ssa, tir};
+
+/// The module that the imported functions come from.
+pub const IMPORT_MODULE: &str = "smol";
+
+/// The local that holds the index of the next block to run.  Tiny IR names
+/// can't contain `@`, so it doesn't clash with any variable.
+const NEXT_BLOCK: &str = "$@next";
+
+/// The label of the loop around the blocks.
+const DISPATCH: &str = "$@dispatch";
+
+/// The allocation function of the module, which takes a number of words and
+/// returns the address of the first one.
+const ALLOC_FN: &str = "$@alloc";
+
+/// The division function of the module.
+const DIV_FN: &str = "$@div";
+
+/// The global that holds the address of the next free byte.
+const HEAP: &str = "$@heap";
+
+/// Generate a WebAssembly module for a program, in the text format.
+pub fn wat_code(program: tir::Program) -> String {
+    let mut out = String::new();
+    let mut line = |s: String| {
+        out.push_str(&s);
+        out.push('\n');
+    };
+
+    line("(module".into());
+    line(format!(
+        "  (import \"{IMPORT_MODULE}\" \"print\" (func $@print (param i64)))"
+    ));
+    line(format!(
+        "  (import \"{IMPORT_MODULE}\" \"read\" (func $@read (result i64)))"
+    ));
+    line(format!(
+        "  (import \"{IMPORT_MODULE}\" \"exit\" (func $@exit (param i64)))"
+    ));
+    line("  (memory (export \"memory\") 0)".into());
+    line(format!("  (global {HEAP} (mut i64) (i64.const 0))"));
+    for k in 0..program.num_counters() {
+        line(format!(
+            "  (global $@count{k} (export \"count{k}\") (mut i64) (i64.const 0))"
+        ));
+    }
+    line(RUNTIME.trim_end().into());
+    // `main` comes first, and the others in the order of their names.
+    let mut names = program.func.keys().copied().collect::<Vec<_>>();
+    names.sort_by_key(|f| *f != tir::Program::main());
+    for name in names {
+        let func = &program.func[&name];
+        let func = if func
+            .block
+            .values()
+            .flat_map(|b| &b.insn)
+            .any(|i| i.is_phi())
+        {
+            ssa::destruct_function(func.clone())
+        } else {
+            func.clone()
+        };
+        for s in function(name, &func) {
+            line(format!("  {s}"));
+        }
+    }
+    line(")".into());
+    out
+}
+
+/// The allocation and division functions.  Allocation traps if the size is
+/// negative or the memory can't grow enough.
+const RUNTIME: &str = "  (func $@alloc (param $words i64) (result i64)
+    (local $start i64)
+    (local $end i64)
+    (local $size i64)
+    (if (i64.lt_s (local.get $words) (i64.const 0))
+      (then unreachable))
+    (local.set $start (global.get $@heap))
+    (local.set $end
+      (i64.add (local.get $start) (i64.mul (local.get $words) (i64.const 8))))
+    (local.set $size
+      (i64.mul (i64.extend_i32_u (memory.size)) (i64.const 65536)))
+    (if (i64.gt_u (local.get $end) (local.get $size))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.wrap_i64
+                  (i64.div_u
+                    (i64.add (i64.sub (local.get $end) (local.get $size)) (i64.const 65535))
+                    (i64.const 65536))))
+              (i32.const -1))
+          (then unreachable))))
+    (global.set $@heap (local.get $end))
+    (local.get $start))
+  (func $@div (param $lhs i64) (param $rhs i64) (result i64)
+    (if (i64.eqz (local.get $rhs))
+      (then (return (i64.const -1))))
+    (if (i64.eq (local.get $rhs) (i64.const -1))
+      (then (return (i64.sub (i64.const 0) (local.get $lhs)))))
+    (i64.div_s (local.get $lhs) (local.get $rhs)))
+";
+
+/// The lines of a function, which has no phis.
+fn function(name: Id, func: &tir::Function) -> Vec<String> {
+    let var = |v: &tir::ValueId| format!("${}", func.names.value(*v));
+    let is_main = name == tir::Program::main();
+    let mut header = format!("(func ${name}");
+    if is_main {
+        header.push_str(&format!(" (export \"{name}\")"));
+    }
+    for p in &func.params {
+        header.push_str(&format!(" (param {} i64)", var(p)));
+    }
+    header.push_str(" (result i64)");
+    let mut code = vec![header];
+    // Every value gets a local, even the temporaries that no declaration
+    // names.
+    for v in (0..func.names.num_values() as u32).map(tir::ValueId) {
+        if !func.params.contains(&v) {
+            code.push(format!("  (local {} i64)", var(&v)));
+        }
+    }
+    code.push(format!("  (local {NEXT_BLOCK} i32)"));
+
+    let order = func.block.keys().copied().collect::<Vec<_>>();
+    let index = |b: &tir::BlockId| order.iter().position(|o| o == b).unwrap();
+    let label = |b: &tir::BlockId| format!("${}", func.names.block(*b));
+    code.push(format!("  loop {DISPATCH}"));
+    for (depth, b) in order.iter().enumerate().rev() {
+        code.push(format!(
+            "{}block {}",
+            "  ".repeat(order.len() - depth + 1),
+            label(b)
+        ));
+    }
+    let inner = "  ".repeat(order.len() + 2);
+    code.push(format!("{inner}local.get {NEXT_BLOCK}"));
+    let labels = order.iter().map(label).collect::<Vec<_>>();
+    code.push(format!("{inner}br_table {}", labels.join(" ")));
+    for (i, b) in order.iter().enumerate() {
+        let indent = "  ".repeat(order.len() - i + 1);
+        code.push(format!("{indent}end"));
+        let block = &func.block[b];
+        let mut body = vec![];
+        for insn in &block.insn {
+            instruction(insn, &var, &mut body);
+        }
+        let next = order.get(i + 1);
+        // Set the next block and go around the loop, unless it comes next.
+        let jump = |target: &tir::BlockId, body: &mut Vec<String>| {
+            if Some(target) != next {
+                body.push(format!("i32.const {}", index(target)));
+                body.push(format!("local.set {NEXT_BLOCK}"));
+                body.push(format!("br {DISPATCH}"));
+            }
+        };
+        for t in &block.term {
+            match t {
+                tir::Terminator::Jump(target) => jump(target, &mut body),
+                tir::Terminator::Branch { guard, tt, ff } => {
+                    body.push(format!("local.get {}", var(guard)));
+                    body.push("i64.const 0".into());
+                    body.push("i64.ne".into());
+                    body.push("if".into());
+                    let mut arm = vec![];
+                    jump(tt, &mut arm);
+                    body.extend(arm.into_iter().map(|s| format!("  {s}")));
+                    body.push("else".into());
+                    let mut arm = vec![];
+                    jump(ff, &mut arm);
+                    body.extend(arm.into_iter().map(|s| format!("  {s}")));
+                    body.push("end".into());
+                }
+                tir::Terminator::Exit(status) => {
+                    match status {
+                        Some(status) => body.push(format!("local.get {}", var(status))),
+                        None => body.push("i64.const 0".into()),
+                    }
+                    if is_main {
+                        body.push("return".into());
+                    } else {
+                        body.push("call $@exit".into());
+                        body.push("unreachable".into());
+                    }
+                }
+                tir::Terminator::Return(value) => {
+                    body.push(format!("local.get {}", var(value)));
+                    body.push("return".into());
+                }
+            }
+        }
+        code.extend(body.into_iter().map(|s| format!("{indent}{s}")));
+    }
+    code.push("  end".into());
+    // Every block ends by jumping or returning, so control never gets here.
+    code.push("  unreachable)".into());
+    code
+}
+
+/// Append the lines of an instruction to `code`.
+fn instruction(
+    insn: &tir::Instruction,
+    var: &impl Fn(&tir::ValueId) -> String,
+    code: &mut Vec<String>,
+) {
+    use tir::Instruction::*;
+
+    let get = |v: &tir::ValueId| format!("local.get {}", var(v));
+    match insn {
+        Copy { dst, src } => {
+            code.push(get(src));
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Const { dst, src } => {
+            code.push(format!("i64.const {src}"));
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Arith { op, dst, lhs, rhs } => {
+            code.push(get(lhs));
+            code.push(get(rhs));
+            match op {
+                BOp::Add => code.push("i64.add".into()),
+                BOp::Sub => code.push("i64.sub".into()),
+                BOp::Mul => code.push("i64.mul".into()),
+                BOp::Div => code.push(format!("call {DIV_FN}")),
+                BOp::Lt => {
+                    code.push("i64.lt_s".into());
+                    code.push("i64.extend_i32_u".into());
+                }
+            }
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Shift {
+            op,
+            dst,
+            src,
+            amount,
+        } => {
+            code.push(get(src));
+            code.push(format!("i64.const {amount}"));
+            code.push(
+                match op {
+                    tir::ShiftOp::Left => "i64.shl",
+                    tir::ShiftOp::Arith => "i64.shr_s",
+                    tir::ShiftOp::Logical => "i64.shr_u",
+                }
+                .into(),
+            );
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Read(dst) => {
+            code.push("call $@read".into());
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Print(src) => {
+            code.push(get(src));
+            code.push("call $@print".into());
+        }
+        Phi { .. } => unreachable!("phis are removed before code generation"),
+        Call { dst, callee, args } => {
+            for arg in args {
+                code.push(get(arg));
+            }
+            code.push(format!("call ${callee}"));
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Alloc { dst, size } => {
+            code.push(get(size));
+            code.push(format!("call {ALLOC_FN}"));
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Load { dst, addr, offset } => {
+            address(var(addr), *offset, code);
+            code.push(format!("i64.load{}", offset_immediate(*offset)));
+            code.push(format!("local.set {}", var(dst)));
+        }
+        Store { addr, offset, src } => {
+            address(var(addr), *offset, code);
+            code.push(get(src));
+            code.push(format!("i64.store{}", offset_immediate(*offset)));
+        }
+        Count(k) => {
+            code.push(format!("global.get $@count{k}"));
+            code.push("i64.const 1".into());
+            code.push("i64.add".into());
+            code.push(format!("global.set $@count{k}"));
+        }
+        Loc(_) => {}
+    }
+}
+
+/// Can the offset of a load or a store go in its immediate?  Immediates are
+/// unsigned, so negative offsets are added to the address instead.
+fn fits_offset(offset: i64) -> bool {
+    u32::try_from(offset).is_ok()
+}
+
+/// Push the 32-bit address of a load or a store, without the offset if it
+/// goes in the immediate.
+fn address(addr: String, offset: i64, code: &mut Vec<String>) {
+    code.push(format!("local.get {addr}"));
+    if !fits_offset(offset) {
+        code.push(format!("i64.const {offset}"));
+        code.push("i64.add".into());
+    }
+    code.push("i32.wrap_i64".into());
+}
+
+/// The immediate offset of a load or a store, if it goes there.
+fn offset_immediate(offset: i64) -> String {
+    if fits_offset(offset) && offset != 0 {
+        format!(" offset={offset}")
+    } else {
+        String::new()
+    }
+}
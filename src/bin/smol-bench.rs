@@ -0,0 +1,235 @@
+//! Benchmarks the optimizer: compiles each program at several optimization
+//! levels, runs the code under the built-in emulator, and compares how many
+//! instructions it retires and how big it is.  A `prog.input` next to
+//! `prog.smol` is the input of the program, which reads nothing otherwise.
+
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, ValueEnum};
+use serde_json::json;
+use smol::back::{code_gen, emu};
+use smol::common::catch_panic;
+use smol::front::*;
+use smol::middle::opt::{self, OptLevel, Pipeline};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Benchmark smol programs at several -O levels", long_about = None)]
+struct Args {
+    /// the programs, and the directories to find them in, recursively
+    #[arg(default_value = "bench")]
+    paths: Vec<PathBuf>,
+    /// the optimization levels to compare, the first one with the others
+    #[arg(
+        short = 'O',
+        long = "levels",
+        value_delimiter = ',',
+        default_values_t = [0, 1, 2, 3],
+        value_parser = clap::value_parser!(u8).range(0..=3)
+    )]
+    levels: Vec<u8>,
+    /// the format of the results on stdout
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// a table with a row per program, and a total
+    Table,
+    /// a JSON array of objects, one per program
+    Json,
+}
+
+/// The exit status when a program doesn't compile or run.
+const EXIT_FAILED: i32 = 1;
+
+/// The exit status when smol-bench is used wrong, or there are no programs.
+const EXIT_USAGE_ERROR: i32 = 2;
+
+/// How a program did at an optimization level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Measurement {
+    /// The instructions it retired.
+    instructions: u64,
+    /// The bytes of its code.
+    code_bytes: u32,
+}
+
+/// The measurements of a program at each level, or why it didn't compile or
+/// run.
+struct Row {
+    program: String,
+    results: Vec<Result<Measurement, String>>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut programs = vec![];
+    for path in &args.paths {
+        if let Err(e) = find_programs(path, &mut programs) {
+            eprintln!("error: Cannot read `{}`: {e}", path.display());
+            std::process::exit(EXIT_USAGE_ERROR);
+        }
+    }
+    if programs.is_empty() {
+        eprintln!("error: There are no `.smol` files to benchmark.");
+        std::process::exit(EXIT_USAGE_ERROR);
+    }
+
+    let rows = programs
+        .iter()
+        .map(|program| Row {
+            program: program.display().to_string(),
+            results: args
+                .levels
+                .iter()
+                .map(|level| measure(program, *level))
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    match args.format {
+        Format::Table => print!("{}", table(&rows, &args.levels)),
+        Format::Json => print!("{}", json(&rows, &args.levels)),
+    }
+    if rows.iter().flat_map(|r| &r.results).any(Result::is_err) {
+        std::process::exit(EXIT_FAILED);
+    }
+}
+
+/// Add the program at `path`, or the programs in it if it's a directory, in
+/// order.
+fn find_programs(path: &Path, programs: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().is_some_and(|e| e == "smol") {
+                find_programs(&entry, programs)?;
+            }
+        }
+    } else {
+        std::fs::metadata(path)?;
+        programs.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Compile the program at the level, and run it under the emulator.
+fn measure(path: &Path, level: u8) -> Result<Measurement, String> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path).map_err(|e| format!("Cannot read `{}`: {e}", path.display()))
+    };
+    let source = read(path)?;
+    let input_path = path.with_extension("input");
+    let input = if input_path.is_file() {
+        read(&input_path)?
+    } else {
+        String::new()
+    };
+    let level = OptLevel::new(level).expect("clap checks the range");
+    let run = || -> Result<Measurement, String> {
+        let ast = parse(&source).map_err(|e| e.to_string())?;
+        let ir = Pipeline::for_level(level).run(lower(ast), &opt::Options::default());
+        let code = code_gen(ir);
+        let (_, instructions) = emu::run_counted(&code, input.as_bytes(), std::io::sink())
+            .map_err(|e| e.to_string())?;
+        Ok(Measurement {
+            instructions,
+            code_bytes: code.stats().total().code_bytes,
+        })
+    };
+    // Panics in the compiler are errors of their programs, in the results.
+    catch_panic(run).unwrap_or_else(|panic| Err(format!("Internal compiler error: {panic}")))
+}
+
+// SECTION: reports
+
+/// The table of retired instructions, with the code size in parentheses, and
+/// how many times fewer instructions the last level retires than the first.
+/// The total row only counts the programs that ran at every level.
+fn table(rows: &[Row], levels: &[u8]) -> String {
+    let cell = |m: &Measurement| format!("{} ({}B)", m.instructions, m.code_bytes);
+    let speedup = |results: &[Measurement]| match (results.first(), results.last()) {
+        (Some(first), Some(last)) if last.instructions > 0 && results.len() > 1 => {
+            format!(
+                "{:.2}x",
+                first.instructions as f64 / last.instructions as f64
+            )
+        }
+        _ => "-".to_string(),
+    };
+    let width = rows
+        .iter()
+        .map(|r| r.program.len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+    let mut out = format!("{:<width$}", "program");
+    for level in levels {
+        out += &format!(" {:>20}", format!("-O{level}"));
+    }
+    out += &format!(" {:>8}\n", "speedup");
+
+    let mut total = vec![Measurement::default(); levels.len()];
+    let mut errors = vec![];
+    for row in rows {
+        out += &format!("{:<width$}", row.program);
+        for result in &row.results {
+            out += &format!(" {:>20}", result.as_ref().map_or("error".to_string(), cell));
+        }
+        match row.results.iter().cloned().collect::<Result<Vec<_>, _>>() {
+            Ok(results) => {
+                out += &format!(" {:>8}\n", speedup(&results));
+                for (total, m) in total.iter_mut().zip(&results) {
+                    total.instructions += m.instructions;
+                    total.code_bytes += m.code_bytes;
+                }
+            }
+            Err(_) => out += &format!(" {:>8}\n", "-"),
+        }
+        for (level, result) in levels.iter().zip(&row.results) {
+            if let Err(e) = result {
+                errors.push(format!("{} at -O{level}: {e}", row.program));
+            }
+        }
+    }
+    out += &format!("{:<width$}", "total");
+    for m in &total {
+        out += &format!(" {:>20}", cell(m));
+    }
+    out += &format!(" {:>8}\n", speedup(&total));
+    for error in errors {
+        out += &format!("\n{error}");
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+/// The results as a JSON array, one program per line, with an object per
+/// level that has the measurements or the error.
+fn json(rows: &[Row], levels: &[u8]) -> String {
+    let items = rows
+        .iter()
+        .map(|row| {
+            let levels = levels
+                .iter()
+                .zip(&row.results)
+                .map(|(level, result)| {
+                    let value = match result {
+                        Ok(m) => {
+                            json!({ "instructions": m.instructions, "code_bytes": m.code_bytes })
+                        }
+                        Err(e) => json!({ "error": e }),
+                    };
+                    (format!("O{level}"), value)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            format!("  {}", json!({ "program": row.program, "levels": levels }))
+        })
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
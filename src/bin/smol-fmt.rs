@@ -0,0 +1,86 @@
+//! Formats smol programs in the canonical style, keeping their comments.
+
+use std::io::IsTerminal;
+
+use clap::Parser;
+use smol::diagnostics::Diagnostic;
+use smol::front::format::format_source;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Format smol programs", long_about = None)]
+struct Args {
+    /// the files to format in place, or `-` to format stdin to stdout
+    #[arg(required = true)]
+    files: Vec<String>,
+    /// change no file, and list the ones that aren't formatted instead
+    #[arg(long, default_value_t = false)]
+    check: bool,
+}
+
+/// The exit status when a file can't be formatted, or with `--check`, isn't.
+const EXIT_UNFORMATTED: i32 = 1;
+
+/// The exit status when smol-fmt is used wrong.
+const EXIT_USAGE_ERROR: i32 = 2;
+
+fn main() {
+    let args = Args::parse();
+    let mut status = 0;
+    for file in &args.files {
+        let (name, source) = read_source(file);
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                report(
+                    Diagnostic::error(e.message)
+                        .with_code("syntax")
+                        .in_file(&name)
+                        .at(&source, e.span),
+                );
+                status = EXIT_UNFORMATTED;
+                continue;
+            }
+        };
+        if args.check {
+            if formatted != source {
+                println!("{name}");
+                status = EXIT_UNFORMATTED;
+            }
+        } else if file == "-" {
+            print!("{formatted}");
+        } else if formatted != source {
+            if let Err(e) = std::fs::write(file, formatted) {
+                report(Diagnostic::error(format!("Cannot write `{name}`: {e}")).with_code("usage"));
+                std::process::exit(EXIT_USAGE_ERROR);
+            }
+        }
+    }
+    std::process::exit(status);
+}
+
+/// The name and the text of a file.
+fn read_source(file: &str) -> (String, String) {
+    use std::io::Read;
+
+    let (name, text) = if file == "-" {
+        let mut text = String::new();
+        let read = std::io::stdin().read_to_string(&mut text);
+        ("<stdin>", read.map(|_| text))
+    } else {
+        (file, std::fs::read_to_string(file))
+    };
+    match text {
+        Ok(text) => (name.to_string(), text),
+        Err(e) => {
+            report(Diagnostic::error(format!("Cannot read `{name}`: {e}")).with_code("usage"));
+            std::process::exit(EXIT_USAGE_ERROR);
+        }
+    }
+}
+
+/// Report the diagnostic, in color on terminals unless `NO_COLOR` is set.
+fn report(diagnostic: Diagnostic) {
+    let color = std::io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    eprint!("{}", diagnostic.render(color));
+}
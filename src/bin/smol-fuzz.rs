@@ -0,0 +1,64 @@
+//! Differential fuzzing: runs random programs with the interpreter, and with
+//! the code of the backend without and with optimizations, and reports the
+//! first program where they don't agree, or where the compiler panics.  It
+//! shrinks that program as far as it still fails the same way, and prints
+//! it as source to reproduce the failure with.  See [smol::fuzz].
+
+use clap::Parser;
+use smol::front::ast::generate::random_program;
+use smol::fuzz::{check, random_input, shrink};
+use smol::middle::opt::OptLevel;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Fuzz the compiler with random programs", long_about = None)]
+struct Args {
+    /// the seed of the first program
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// the number of programs, with the seeds after the first one
+    #[arg(long, default_value_t = 1000)]
+    runs: u64,
+    /// the most statements at the top level of a program
+    #[arg(long, default_value_t = 12)]
+    max_stmts: usize,
+    /// the optimization level of the optimized code
+    #[arg(short = 'O', default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=3))]
+    opt_level: u8,
+}
+
+/// The exit status when a program fails.
+const EXIT_FAILED: i32 = 1;
+
+fn main() {
+    let args = Args::parse();
+    let level = OptLevel::new(args.opt_level).expect("clap checks the range");
+    for seed in args.seed..args.seed.saturating_add(args.runs) {
+        let program = random_program(seed, args.max_stmts);
+        let input = random_input(seed, &program);
+        let Some(failure) = check(&program, &input, level) else {
+            continue;
+        };
+        let kind = failure.kind();
+        let program = shrink(program, |p| {
+            check(p, &input, level).is_some_and(|f| f.kind() == kind)
+        });
+        let failure = check(&program, &input, level).expect("the shrunk program fails");
+        println!("The program of seed {seed} has {kind}.  Shrunk, it's:\n");
+        print!("{program}");
+        println!("\nWith the input `{}`:", input.trim_end());
+        for (path, outcome) in &failure.outcomes {
+            match outcome {
+                Ok((output, status)) => println!(
+                    "- {path} prints `{}` and exits with {status}.",
+                    output.trim_end().replace('\n', " ")
+                ),
+                Err(e) => println!("- {path}: {e}"),
+            }
+        }
+        std::process::exit(EXIT_FAILED);
+    }
+    println!(
+        "{} programs from seed {}, and no failures.",
+        args.runs, args.seed
+    );
+}
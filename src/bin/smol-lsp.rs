@@ -0,0 +1,268 @@
+//! A language server for smol, which talks the Language Server Protocol over
+//! stdin and stdout.
+//!
+//! It keeps the text of the open documents, and on each change publishes
+//! their syntax errors and the variables they use before assigning them.  It
+//! goes to the first definition of a variable, and hovering over a statement
+//! shows the tiny IR it lowers to.  Documents sync in full, and positions
+//! count UTF-16 code units, the default of the protocol.
+
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+use smol::common::{catch_panic, Map, Span};
+use smol::diagnostics::{Diagnostic, Severity};
+use smol::front::symbols::Symbols;
+use smol::front::*;
+
+/// The error code of the protocol for requests that the server doesn't
+/// know.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Default)]
+struct Server {
+    /// The text of the open documents, by URI.
+    documents: Map<String, String>,
+    shut_down: bool,
+}
+
+fn main() {
+    let mut server = Server::default();
+    let mut stdin = std::io::stdin().lock();
+    while let Some(message) = read_message(&mut stdin) {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": { "name": "smol-lsp", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                server.shut_down = true;
+                Some(Value::Null)
+            }
+            "exit" => std::process::exit(if server.shut_down { 0 } else { 1 }),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                server.update(uri(params), document["text"].as_str().unwrap_or(""));
+                None
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()?["text"].as_str()) {
+                    server.update(uri(params), text);
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                server.documents.remove(&uri(params));
+                publish(&uri(params), vec![]);
+                None
+            }
+            "textDocument/hover" => Some(server.hover(params)),
+            "textDocument/definition" => Some(server.definition(params)),
+            _ => None,
+        };
+        // Notifications have no id, and get no response.
+        let Some(id) = message.get("id") else {
+            continue;
+        };
+        let response = match result {
+            Some(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": METHOD_NOT_FOUND, "message": format!("Unknown method `{method}`.") },
+            }),
+        };
+        write_message(&response);
+    }
+}
+
+impl Server {
+    /// Keep the new text of a document, and publish its diagnostics.
+    fn update(&mut self, uri: String, text: &str) {
+        let diagnostics = diagnostics(text)
+            .iter()
+            .map(|d| lsp_diagnostic(text, d))
+            .collect();
+        publish(&uri, diagnostics);
+        self.documents.insert(uri, text.to_string());
+    }
+
+    /// The tiny IR of the statement at the position, without optimizations.
+    fn hover(&self, params: &Value) -> Value {
+        let Some((text, offset)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(span) = Symbols::new(text).statement_at(offset) else {
+            return Value::Null;
+        };
+        let statement = &text[span.start..span.end];
+        // A panic in the compiler is a bug, but it shouldn't take the server
+        // down or write to its log, so it just means no hover.
+        let tir = catch_panic(|| parse(statement).ok().map(|ast| lower(ast).to_string()));
+        match tir {
+            Ok(Some(tir)) => json!({
+                "contents": { "kind": "markdown", "value": format!("```\n{}```", tir) },
+                "range": range(text, span),
+            }),
+            _ => Value::Null,
+        }
+    }
+
+    /// The first definition of the variable at the position.
+    fn definition(&self, params: &Value) -> Value {
+        let Some((text, offset)) = self.position(params) else {
+            return Value::Null;
+        };
+        match Symbols::new(text).definition(offset) {
+            Some(span) => json!({ "uri": uri(params), "range": range(text, span) }),
+            None => Value::Null,
+        }
+    }
+
+    /// The text of the document of a request, and the offset of its
+    /// position.
+    fn position(&self, params: &Value) -> Option<(&str, usize)> {
+        let text = self.documents.get(&uri(params))?;
+        let position = &params["position"];
+        let line = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+        Some((text, offset(text, line, character)))
+    }
+}
+
+/// The syntax errors of a document, and the variables it uses before it
+/// assigns them.
+fn diagnostics(text: &str) -> Vec<Diagnostic> {
+    if let Err(e) = parse(text) {
+        return vec![Diagnostic::error(e.to_string())
+            .with_code("syntax")
+            .at(text, e.span)];
+    }
+    Symbols::new(text)
+        .used_before_definition()
+        .into_iter()
+        .map(|o| {
+            Diagnostic::warning(format!("`{}` is used before it's assigned.", o.name))
+                .with_code("unassigned")
+                .at(text, o.span)
+                .with_note("Variables start at 0.")
+        })
+        .collect()
+}
+
+/// A diagnostic in the format of the protocol.  Diagnostics without a span
+/// are about the start of the document.
+fn lsp_diagnostic(text: &str, diagnostic: &Diagnostic) -> Value {
+    let span = diagnostic
+        .location
+        .as_ref()
+        .map_or(Span::default(), |l| l.span);
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message += &format!("\nnote: {note}");
+    }
+    json!({
+        "range": range(text, span),
+        "severity": match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        },
+        "code": diagnostic.code,
+        "source": "smol",
+        "message": message,
+    })
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) {
+    write_message(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+fn uri(params: &Value) -> String {
+    params["textDocument"]["uri"]
+        .as_str()
+        .unwrap_or("")
+        .to_string()
+}
+
+// SECTION: positions
+
+/// The byte offset of a position, clamped to its line and to the text.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let line_start = text
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum::<usize>();
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The position of a byte offset.
+fn position(text: &str, offset: usize) -> Value {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": text[..offset].matches('\n').count(),
+        "character": text[line_start..offset].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+// SECTION: messages
+
+/// Read the next message, or `None` at the end of input.  Each message has
+/// headers, of which only `Content-Length` matters, then a blank line and a
+/// JSON object.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if input.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let mut body = vec![0; length?];
+        input.read_exact(&mut body).ok()?;
+        match serde_json::from_slice(&body) {
+            Ok(message) => return Some(message),
+            Err(e) => eprintln!("smol-lsp: Ignoring a message that isn't JSON: {e}"),
+        }
+    }
+}
+
+fn write_message(message: &Value) {
+    let body = message.to_string();
+    let mut stdout = std::io::stdout().lock();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{body}", body.len());
+    let _ = stdout.flush();
+}
@@ -0,0 +1,160 @@
+//! An interactive REPL for smol, which runs each input with the reference
+//! interpreter of the tiny IR and keeps the variables between inputs.
+//!
+//! The variables live on as assignments of their values at the start of the
+//! next input, so each input is a whole program for the rest of the
+//! compiler, and `:tir` and `:asm` show how it compiles.
+
+use std::io::{BufRead, Write};
+
+use clap::Parser;
+use smol::back::code_gen;
+use smol::common::{Id, Map};
+use smol::front::*;
+use smol::middle::interp;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "An interactive REPL for smol", long_about = None)]
+struct Args {}
+
+const HELP: &str = "\
+Type statements to run them, or an expression to print its value.  Inputs
+with an open `{` go on until it's closed.  The commands are:
+  :ast    the syntax tree of the last input
+  :tir    the tiny IR of the last input, with its variables set first
+  :asm    the assembly code of the last input
+  :env    the values of the variables
+  :reset  forget all the variables
+  :help   this help
+  :quit   leave, like end of input
+";
+
+/// What the REPL remembers between inputs.
+#[derive(Default)]
+struct Repl {
+    /// The values of the variables, by name.
+    env: Map<Id, i64>,
+    /// The syntax tree of the last input that parsed.
+    last: Option<ast::Program>,
+}
+
+fn main() {
+    Args::parse();
+    let mut repl = Repl::default();
+    while let Some(input) = read_input() {
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        match input {
+            ":quit" | ":q" => break,
+            ":help" | ":h" => print!("{HELP}"),
+            ":env" => {
+                for (name, value) in &repl.env {
+                    println!("{name} = {value}");
+                }
+            }
+            ":reset" => repl.env.clear(),
+            ":ast" | ":tir" | ":asm" => match &repl.last {
+                None => eprintln!("There is no input yet."),
+                Some(ast) => {
+                    let tir = lower(repl.with_env(ast.clone()));
+                    match input {
+                        ":ast" => println!("{ast:?}"),
+                        ":tir" => print!("{tir}"),
+                        _ => println!("{}", code_gen(tir).asm_code()),
+                    }
+                }
+            },
+            // `:=` starts an assignment, and commands are words.
+            _ if input
+                .strip_prefix(':')
+                .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_alphabetic())) =>
+            {
+                eprintln!("Unknown command `{input}`.  `:help` lists the commands.")
+            }
+            _ => repl.eval(input),
+        }
+    }
+}
+
+impl Repl {
+    /// Run the input, a program or an expression to print, and keep the
+    /// variables it sets.
+    fn eval(&mut self, input: &str) {
+        let ast = match parse(input) {
+            Ok(ast) => ast,
+            Err(e) => match parse(&format!("$print {input}")) {
+                Ok(ast) => ast,
+                // The error about the input as typed is the useful one.
+                Err(_) => return eprintln!("{e}"),
+            },
+        };
+        self.last = Some(ast.clone());
+
+        let mut names = self.env.keys().copied().collect::<Vec<_>>();
+        variables(&ast.stmts, &mut names);
+        let program = lower(self.with_env(ast));
+        let (stdin, mut stdout) = (std::io::stdin(), std::io::stdout());
+        match interp::run(&program, stdin.lock(), &mut stdout) {
+            Ok(env) => {
+                for name in names {
+                    if let Some(value) = env.get(&name) {
+                        self.env.insert(name, *value);
+                    }
+                }
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+        let _ = stdout.flush();
+    }
+
+    /// The program, after assignments of the values of the variables.
+    fn with_env(&self, program: ast::Program) -> ast::Program {
+        let mut stmts = self
+            .env
+            .iter()
+            .map(|(name, value)| ast::StmtKind::Assign(*name, ast::Expr::Const(*value)).into())
+            .collect::<Vec<_>>();
+        stmts.extend(program.stmts);
+        ast::Program { stmts }
+    }
+}
+
+/// Add the variables that the statements assign or read to `names`.
+fn variables(stmts: &[ast::Stmt], names: &mut Vec<Id>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            ast::StmtKind::Assign(x, _) | ast::StmtKind::Read(ast::Expr::Var(x)) => names.push(*x),
+            ast::StmtKind::If { tt, ff, .. } => {
+                variables(tt, names);
+                variables(ff, names);
+            }
+            ast::StmtKind::Print(_) | ast::StmtKind::Read(_) => {}
+        }
+    }
+}
+
+/// Read the next input, with the lines after the first one until the braces
+/// are balanced, or `None` at the end of input.
+fn read_input() -> Option<String> {
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "smol> " } else { "...   " });
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) if input.is_empty() => {
+                println!();
+                return None;
+            }
+            Ok(0) | Err(_) => return Some(input),
+            Ok(_) => input += &line,
+        }
+        let depth = input.matches('{').count() as isize - input.matches('}').count() as isize;
+        if depth <= 0 {
+            return Some(input);
+        }
+    }
+}
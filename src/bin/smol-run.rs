@@ -0,0 +1,110 @@
+//! Runs a smol program with the reference interpreter of the tiny IR,
+//! without generating any code, to check what a program should do.
+
+use std::io::{BufRead, IsTerminal, Write};
+
+use clap::Parser;
+use smol::diagnostics::Diagnostic;
+use smol::front::*;
+use smol::middle::interp::{InterpError, Interpreter, Status};
+use smol::middle::opt::{self, OptLevel, Pipeline};
+use smol::middle::tir::{self, Instruction};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Run a smol program with the interpreter", long_about = None)]
+struct Args {
+    /// the program, or `-` to read it from stdin, which then has no input
+    /// left for the program
+    file: String,
+    /// print each statement to stderr before it runs, with its line
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+    /// the optimization level, to check that optimizations keep what the
+    /// program does
+    #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+}
+
+/// The exit status when the program has errors, or fails at run time.
+const EXIT_ERROR: i32 = 1;
+
+/// The exit status when smol-run is used wrong.
+const EXIT_USAGE_ERROR: i32 = 2;
+
+fn main() {
+    let args = Args::parse();
+    let (name, source) = read_source(&args.file);
+    let ast = parse(&source).unwrap_or_else(|e| {
+        fail(
+            Diagnostic::error(e.to_string())
+                .with_code("syntax")
+                .in_file(&name),
+            EXIT_ERROR,
+        )
+    });
+    let level = OptLevel::new(args.opt_level).expect("clap checks the range");
+    let program = Pipeline::for_level(level).run(lower(ast), &opt::Options::default());
+
+    let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+    let trace = args.trace.then_some((name.as_str(), source.as_str()));
+    match run(&program, stdin.lock(), stdout.lock(), trace) {
+        Ok(status) => std::process::exit(status as i32),
+        Err(e) => fail(
+            Diagnostic::error(e.to_string()).with_code("run"),
+            EXIT_ERROR,
+        ),
+    }
+}
+
+/// Run the program to completion, and return its exit status.  With the name
+/// and the text of the source in `trace`, each statement is traced to stderr
+/// when the code that the `$loc` of its span starts runs, with the first
+/// line of the statement.
+fn run(
+    program: &tir::Program,
+    input: impl BufRead,
+    output: impl Write,
+    trace: Option<(&str, &str)>,
+) -> Result<i64, InterpError> {
+    let mut interp = Interpreter::new(program, input, output)?;
+    let mut stderr = std::io::stderr().lock();
+    loop {
+        if let (Some((name, source)), Some(Instruction::Loc(span))) = (trace, interp.current_insn())
+        {
+            let (line, text) = span.line(source);
+            let _ = writeln!(stderr, "{name}:{line}: {}", text.trim());
+        }
+        if interp.step()? == Status::Finished {
+            return Ok(interp.exit_status());
+        }
+    }
+}
+
+/// The name and the text of the program.
+fn read_source(file: &str) -> (String, String) {
+    use std::io::Read;
+
+    let (name, text) = if file == "-" {
+        let mut text = String::new();
+        let read = std::io::stdin().read_to_string(&mut text);
+        ("<stdin>", read.map(|_| text))
+    } else {
+        (file, std::fs::read_to_string(file))
+    };
+    match text {
+        Ok(text) => (name.to_string(), text),
+        Err(e) => fail(
+            Diagnostic::error(format!("Cannot read `{name}`: {e}")).with_code("usage"),
+            EXIT_USAGE_ERROR,
+        ),
+    }
+}
+
+/// Report the diagnostic, in color on terminals unless `NO_COLOR` is set,
+/// and exit with `status`.
+fn fail(diagnostic: Diagnostic, status: i32) -> ! {
+    let color = std::io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    eprint!("{}", diagnostic.render(color));
+    std::process::exit(status);
+}
@@ -0,0 +1,238 @@
+//! Runs golden tests of smol programs: each `prog.smol` with a
+//! `prog.expected` next to it is a test, which passes if the program prints
+//! exactly what `prog.expected` has.  A `prog.input` next to them is the
+//! input of the program, which reads nothing otherwise.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, ValueEnum};
+use smol::back::{code_gen, emu, runner, AsmOptions, Target};
+use smol::common::catch_panic;
+use smol::front::*;
+use smol::middle::interp;
+use smol::middle::opt::{self, OptLevel, Pipeline};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Run golden tests of smol programs", long_about = None)]
+struct Args {
+    /// the tests, and the directories to find them in, recursively
+    #[arg(default_value = "tests")]
+    paths: Vec<PathBuf>,
+    /// how to run the programs
+    #[arg(long, value_enum, default_value_t = Via::Emu)]
+    via: Via,
+    /// the optimization level to compile the programs at
+    #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+    /// the format of the report on stdout
+    #[arg(long, value_enum, default_value_t = Format::Tap)]
+    format: Format,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Via {
+    /// the reference interpreter of the tiny IR, without generating code
+    Interp,
+    /// the built-in RV64 emulator, which runs the generated code
+    Emu,
+    /// link with a RISC-V toolchain and run under QEMU's user-mode emulator
+    Qemu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// the Test Anything Protocol
+    Tap,
+    /// JUnit XML, for CI servers
+    Junit,
+}
+
+/// The exit status when a test fails.
+const EXIT_FAILED: i32 = 1;
+
+/// The exit status when smol-test is used wrong, or there are no tests.
+const EXIT_USAGE_ERROR: i32 = 2;
+
+/// A program, and what it should print.
+struct Test {
+    program: PathBuf,
+    expected: PathBuf,
+    input: Option<PathBuf>,
+}
+
+/// How a test went: `None` if it passed, and why it failed otherwise.
+struct Outcome {
+    name: String,
+    failure: Option<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut tests = vec![];
+    for path in &args.paths {
+        if let Err(e) = find_tests(path, &mut tests) {
+            eprintln!("error: Cannot read `{}`: {e}", path.display());
+            std::process::exit(EXIT_USAGE_ERROR);
+        }
+    }
+    if tests.is_empty() {
+        eprintln!("error: There are no `.smol` files with `.expected` files next to them.");
+        std::process::exit(EXIT_USAGE_ERROR);
+    }
+
+    let outcomes = tests
+        .iter()
+        .map(|test| Outcome {
+            name: test.program.display().to_string(),
+            failure: run_test(test, &args).err(),
+        })
+        .collect::<Vec<_>>();
+    let report = match args.format {
+        Format::Tap => tap(&outcomes),
+        Format::Junit => junit(&outcomes),
+    };
+    print!("{report}");
+    let _ = std::io::stdout().flush();
+    if outcomes.iter().any(|o| o.failure.is_some()) {
+        std::process::exit(EXIT_FAILED);
+    }
+}
+
+/// Add the test of `path`, or the tests in it if it's a directory, in order.
+fn find_tests(path: &Path, tests: &mut Vec<Test>) -> std::io::Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().is_some_and(|e| e == "smol") {
+                find_tests(&entry, tests)?;
+            }
+        }
+    } else {
+        let expected = path.with_extension("expected");
+        if !expected.is_file() {
+            // Programs without expected output aren't tests, but they exist.
+            std::fs::metadata(path)?;
+            return Ok(());
+        }
+        let input = path.with_extension("input");
+        tests.push(Test {
+            program: path.to_path_buf(),
+            expected,
+            input: input.is_file().then_some(input),
+        });
+    }
+    Ok(())
+}
+
+/// Compile and run the program of the test, and compare what it prints.
+fn run_test(test: &Test, args: &Args) -> Result<(), String> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path).map_err(|e| format!("Cannot read `{}`: {e}", path.display()))
+    };
+    let source = read(&test.program)?;
+    let expected = read(&test.expected)?;
+    let input = match &test.input {
+        Some(path) => read(path)?,
+        None => String::new(),
+    };
+    // Panics in the compiler fail the test, and the report says why.
+    let output = catch_panic(|| run(&source, input.as_bytes(), args))
+        .unwrap_or_else(|panic| Err(format!("Internal compiler error: {panic}")))?;
+    if output == expected {
+        return Ok(());
+    }
+    // Point at the first line that differs.
+    let (mut expected_lines, mut output_lines) = (expected.lines(), output.lines());
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), output_lines.next()) {
+            (Some(e), Some(o)) if e == o => line += 1,
+            (e, o) => {
+                return Err(format!(
+                    "The output differs at line {line}: expected {}, got {}.",
+                    e.map_or("the end".to_string(), |e| format!("`{e}`")),
+                    o.map_or("the end".to_string(), |o| format!("`{o}`"))
+                ))
+            }
+        }
+    }
+}
+
+/// What the program prints, run the way `args` says.
+fn run(source: &str, input: &[u8], args: &Args) -> Result<String, String> {
+    let ast = parse(source).map_err(|e| e.to_string())?;
+    let level = OptLevel::new(args.opt_level).expect("clap checks the range");
+    let ir = Pipeline::for_level(level).run(lower(ast), &opt::Options::default());
+    let mut output = vec![];
+    match args.via {
+        Via::Interp => {
+            interp::run_status(&ir, input, &mut output).map_err(|e| e.to_string())?;
+        }
+        Via::Emu => {
+            emu::run(&code_gen(ir), input, &mut output).map_err(|e| e.to_string())?;
+        }
+        Via::Qemu => {
+            let asm = code_gen(ir).asm_code();
+            let options = AsmOptions::default();
+            let outcome = runner::run_qemu(&asm, &[], Target::Riscv64, &options, &[], input)
+                .map_err(|e| e.to_string())?;
+            output = outcome.stdout;
+        }
+    }
+    String::from_utf8(output).map_err(|_| "The output isn't UTF-8.".to_string())
+}
+
+// SECTION: reports
+
+/// The report in the Test Anything Protocol, with why each test failed in a
+/// comment after it.
+fn tap(outcomes: &[Outcome]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", outcomes.len());
+    for (i, outcome) in outcomes.iter().enumerate() {
+        match &outcome.failure {
+            None => out += &format!("ok {} - {}\n", i + 1, outcome.name),
+            Some(failure) => {
+                out += &format!("not ok {} - {}\n", i + 1, outcome.name);
+                for line in failure.lines() {
+                    out += &format!("# {line}\n");
+                }
+            }
+        }
+    }
+    let failed = outcomes.iter().filter(|o| o.failure.is_some()).count();
+    out + &format!("# {} passed, {failed} failed\n", outcomes.len() - failed)
+}
+
+/// The report in JUnit's XML format.
+fn junit(outcomes: &[Outcome]) -> String {
+    let failed = outcomes.iter().filter(|o| o.failure.is_some()).count();
+    let mut out = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+    out += &format!(
+        "<testsuite name=\"smol\" tests=\"{}\" failures=\"{failed}\">\n",
+        outcomes.len()
+    );
+    for outcome in outcomes {
+        let name = xml_escape(&outcome.name);
+        match &outcome.failure {
+            None => out += &format!("  <testcase classname=\"smol\" name=\"{name}\"/>\n"),
+            Some(failure) => {
+                out += &format!("  <testcase classname=\"smol\" name=\"{name}\">\n");
+                out += &format!("    <failure message=\"{}\"/>\n", xml_escape(failure));
+                out += "  </testcase>\n";
+            }
+        }
+    }
+    out + "</testsuite>\n"
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+}
@@ -3,21 +3,180 @@
 //!
 //! run with `--help` for more info.
 
-use smol::{back::*, front::*, middle::*};
+use smol::common::{Arch, Backend, Os, TargetSpec};
+use smol::{back::*, diagnostics::Diagnostic, front::*, middle::opt::Pipeline, middle::*};
 
-use clap::{Parser, ValueEnum};
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 
 #[derive(Debug, Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    name = "smolc",
+    version,
+    about = "The smol compiler",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// the input file
-    file: String,
-    /// the output format
-    #[arg(value_enum, short, long, default_value_t = Output::Asm)]
-    out: Output,
-    /// turn on optimizations
-    #[arg(short = 'O', default_value_t = false)]
-    optimize: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// the input files, or `-` for stdin, which make one program with the
+    /// statements of each in order
+    #[arg(required_unless_present = "stdin")]
+    files: Vec<String>,
+    /// read the source from stdin
+    #[arg(long, default_value_t = false, conflicts_with = "files")]
+    stdin: bool,
+    /// the output format, by default the one of the backend of the target
+    #[arg(value_enum, long)]
+    out: Option<Output>,
+    /// log what the compiler does to stderr: `-v` for the stages, `-vv` for
+    /// the decisions of passes and of code generation, and `-vvv` for all
+    /// the details.  Without it, the level is the one of `SMOL_LOG`, like
+    /// `debug`
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// when to color diagnostics
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+    /// how to report diagnostics
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+    /// stop after this many errors, or never with 0
+    #[arg(long, default_value_t = 20, value_name = "N")]
+    max_errors: usize,
+    /// the file to write the output to, or `-` for stdout.  Text goes to
+    /// stdout by default, and an executable to a file named after the input
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// the optimization level: 0 for none, 1 for cheap ones, 2 for all, and 3
+    /// for all with another round of cleanups.  From 1 on, blocks are laid
+    /// out and instructions scheduled, and from 2 on, registers are allocated
+    /// by graph coloring.  `-O` alone means `-O2`
+    #[arg(
+        short = 'O',
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=3),
+    )]
+    opt_level: u8,
+    /// the passes to run instead of the ones of the optimization level,
+    /// separated by commas
+    #[arg(long, value_parser = Pipeline::parse)]
+    passes: Option<Pipeline>,
+    /// print how long each stage of the compilation took to stderr, and for
+    /// each optimization pass, how it changed the size of the program
+    #[arg(long, default_value_t = false)]
+    time_passes: bool,
+    /// print what each pass did to stderr, as text by default
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "text")]
+    remarks: Option<RemarkFormat>,
+    /// print the size and the instruction mix of the code of each function
+    /// to stderr, as a table by default
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "table")]
+    stats: Option<StatsFormat>,
+    /// count how many times each block runs, so that running the program
+    /// writes a profile
+    #[arg(long, default_value_t = false)]
+    profile_generate: bool,
+    /// lay out blocks so that the hot paths of this profile fall through, in
+    /// the tiny IR and, with `-O`, in the assembly
+    #[arg(long, value_parser = read_profile)]
+    profile_use: Option<Profile>,
+    /// verify the tiny IR after every pass, which debug builds always do
+    #[arg(long, default_value_t = false)]
+    verify_each: bool,
+    /// the most instructions a loop may have after unrolling, or 0 to turn
+    /// unrolling off
+    #[arg(long, default_value_t = opt::DEFAULT_UNROLL_THRESHOLD)]
+    unroll_threshold: usize,
+    /// the register allocator, instead of the one of the optimization level
+    #[arg(long, value_enum)]
+    regalloc: Option<RegAlloc>,
+    /// check in each function that the program uses at most this many bytes
+    /// of stack, and report an overflow otherwise
+    #[arg(long)]
+    stack_limit: Option<u32>,
+    /// generate position-independent code, for PIE executables and shared
+    /// objects
+    #[arg(long, default_value_t = false)]
+    pic: bool,
+    /// let the assembler use 16-bit compressed instructions where they fit
+    #[arg(long, default_value_t = false)]
+    compressed: bool,
+    /// multiply and divide without the M extension
+    #[arg(long, default_value_t = false)]
+    no_m: bool,
+    /// let the code use these optional extensions, like the ones `-march`
+    /// adds to the base ISA
+    #[arg(long, value_enum, value_delimiter = ',')]
+    ext: Vec<Ext>,
+    /// report divisions by zero and end the program, instead of giving -1
+    #[arg(long, default_value_t = false)]
+    trap_div_zero: bool,
+    /// report additions, subtractions, and multiplications that overflow and
+    /// end the program, instead of wrapping around
+    #[arg(long, default_value_t = false, conflicts_with = "no_m")]
+    trap_overflow: bool,
+    /// initialize the runtime's garbage collector, and emit stack maps for it
+    #[arg(long, default_value_t = false)]
+    gc: bool,
+    /// show the source line of each statement in a comment before its code
+    #[arg(long, default_value_t = false)]
+    asm_comments: bool,
+    /// add line-number information, so that debuggers can step through the
+    /// source
+    #[arg(short = 'g', default_value_t = false)]
+    debug_info: bool,
+    /// the target to generate code for, which picks the backend and the
+    /// default output: riscv64-linux (or riscv64), riscv32-linux (or
+    /// riscv32), riscv64-pk, riscv64-baremetal, riscv32-baremetal,
+    /// x86_64-linux for LLVM, or wasm32
+    #[arg(long, value_parser = TargetSpec::parse, default_value = "riscv64-linux")]
+    target: TargetSpec,
+    /// run the program instead of printing anything, and exit with its
+    /// status
+    #[arg(long, default_value_t = false)]
+    run: bool,
+    /// how `--run` runs the program, by default the first of `jit`, `emu`,
+    /// `qemu`, and `spike` that can run code for the target
+    #[arg(long, value_enum)]
+    via: Option<Via>,
+    /// label branch edges with their conditions in `cfg-dot` output
+    #[arg(long, default_value_t = false)]
+    edge_labels: bool,
+    /// write every stage of the compilation to numbered files in this
+    /// directory: the tokens, the AST, the tiny IR before and after each
+    /// pass, and the assembly code
+    #[arg(long, value_name = "DIR")]
+    emit_all: Option<PathBuf>,
+    /// the object files or sources of the runtime, which `exe` output links
+    /// with instead of the built-in one
+    #[arg(long)]
+    runtime: Vec<PathBuf>,
+    /// the arguments to run the program with, after `--`
+    #[arg(last = true, requires = "run")]
+    program_args: Vec<String>,
+}
+
+// Commands for packaging smolc, instead of compiling.  A doc comment would
+// be the `about` of smolc.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Subcommand)]
+enum Command {
+    /// print the completion script of smolc for the shell
+    #[command(hide = true)]
+    Completions { shell: Shell },
+    /// print the man page of smolc, in roff
+    #[command(hide = true)]
+    Man,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
@@ -26,44 +185,817 @@ enum Output {
     Tokens,
     /// the ast data structure
     Ast,
-    /// tiny IR in JSON format, after optimizations
+    /// the ast in Graphviz DOT format
+    AstDot,
+    /// tiny IR in text format, after optimizations
     Tir,
+    /// the control-flow graph of the tiny IR in Graphviz DOT format
+    CfgDot,
     /// the resulting assembly code
     Asm,
+    /// a WebAssembly module in the text format
+    Wat,
+    /// LLVM IR in the text format
+    Llvm,
+    /// an executable, linked with the runtime by a RISC-V toolchain
+    Exe,
+    /// an ELF object file, assembled by a RISC-V toolchain, to link with the
+    /// runtime in other builds
+    Obj,
+    /// the machine code that the crate encodes for the program, disassembled
+    Disasm,
+    /// the linker script for the bare-metal targets
+    LinkerScript,
+    /// the C source of the built-in runtime
+    Runtime,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum Via {
+    /// compile to native code in memory with Cranelift
+    #[cfg(feature = "cranelift")]
+    #[default]
+    Jit,
+    /// the built-in RV64 emulator, which stands in for the runtime
+    #[cfg_attr(not(feature = "cranelift"), default)]
+    Emu,
+    /// link with a RISC-V toolchain and run under QEMU's user-mode emulator
+    Qemu,
+    /// link with a RISC-V toolchain and run on Spike under the proxy kernel,
+    /// for `--target riscv64-pk`
+    Spike,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum Ext {
+    /// address generation: `sh1add`, `sh2add`, and `sh3add`
+    Zba,
+    /// basic bit manipulation, like `andn`, `min`, and `max`
+    Zbb,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum Color {
+    /// when stderr is a terminal, and `NO_COLOR` isn't set
+    Auto,
+    /// even when stderr isn't a terminal, or `NO_COLOR` is set
+    Always,
+    Never,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum MessageFormat {
+    /// as text on stderr
+    Human,
+    /// as JSON objects on stdout, one per line
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum RemarkFormat {
+    /// one remark per line
+    Text,
+    /// a JSON array of objects
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum StatsFormat {
+    /// one row per function, and one for the total
+    Table,
+    /// a JSON array of objects
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum RegAlloc {
+    /// callee-saved registers for the most used values, and the stack for
+    /// the rest
+    Simple,
+    /// graph coloring with coalescing, which shares registers between values
+    GraphColor,
+}
+
+fn read_profile(path: &str) -> Result<Profile, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read `{path}`: {e}"))?;
+    Profile::parse(&text)
+}
+
+/// The environment variable with the level of the log without `-v`.
+const LOG_VAR: &str = "SMOL_LOG";
+
+/// Writes the log of the library to stderr, one line per message.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {}: {}",
+                record.level().as_str().to_lowercase(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log at the level of `-v`, or of `SMOL_LOG`, or only warnings.
+fn init_log(args: &Args) {
+    let level = match args.verbose {
+        0 => match std::env::var(LOG_VAR) {
+            Ok(level) => level.parse().unwrap_or_else(|_| {
+                usage_error(format!(
+                    "`{LOG_VAR}` has to be one of off, error, warn, info, debug, and trace, \
+                     not `{level}`."
+                ))
+            }),
+            Err(_) => log::LevelFilter::Warn,
+        },
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    log::set_max_level(level);
+    log::set_logger(&Logger).expect("the log is only set up once");
+}
+
+/// How long each stage of the compilation took, in order, for
+/// `--time-passes`.
+static STAGES: Mutex<Vec<(&str, Duration)>> = Mutex::new(vec![]);
+
+/// Run a stage of the compilation, and add the time it took to the ones of
+/// the stage before.
+fn stage<T>(name: &'static str, run: impl FnOnce() -> T) -> T {
+    log::info!("Starting the {name} stage.");
+    let start = Instant::now();
+    let result = run();
+    record_stage(name, start.elapsed());
+    result
+}
+
+fn record_stage(name: &'static str, time: Duration) {
+    let mut stages = STAGES.lock().unwrap();
+    match stages.iter_mut().find(|(stage, _)| *stage == name) {
+        Some((_, total)) => *total += time,
+        None => stages.push((name, time)),
+    }
+}
+
+/// Print how long each stage took to stderr with `--time-passes`, like the
+/// statistics of the passes.  Optimization is the sum of the passes.
+fn report_stages(args: &Args) {
+    if !args.time_passes {
+        return;
+    }
+    let stages = STAGES.lock().unwrap();
+    let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+    eprintln!("{:<14} {:>10}", "stage", "time (ms)");
+    for (name, time) in stages.iter() {
+        eprintln!("{name:<14} {:>10.3}", ms(*time));
+    }
+    let total = stages.iter().map(|(_, time)| *time).sum();
+    eprintln!("{:<14} {:>10.3}", "total", ms(total));
+}
+
+/// Parse the sources into one program, with the statements of each in order.
+/// The errors of all sources are reported before smolc exits.
+fn get_ast(input: &[Source]) -> ast::Program {
+    let mut program = ast::Program { stmts: vec![] };
+    let mut errors = vec![];
+    for source in input {
+        if too_many_errors(&errors) {
+            break;
+        }
+        let tokens = stage("lex", || symbols::spanned_tokens(&source.text));
+        match stage("parse", || parse_tokens(tokens)) {
+            Ok(ast) => program.stmts.extend(ast.stmts),
+            Err(e) => errors.push(
+                Diagnostic::error(e.to_string())
+                    .with_code("syntax")
+                    .at(&source.text, e.span)
+                    .in_file(&source.name),
+            ),
+        }
+    }
+    if !errors.is_empty() {
+        compile_error(&errors);
+    }
+    program
+}
+
+/// The only source, if there is one.  Spans refer to it, so the code can
+/// only point at the source with one.
+fn single_source(input: &[Source]) -> Option<&Source> {
+    match input {
+        [source] => Some(source),
+        _ => None,
+    }
+}
+
+/// The tokens of each source, one per line.
+fn get_tokens(input: &[Source]) -> String {
+    let mut tokens = String::new();
+    for source in input {
+        for token in stage("lex", || lex::get_tokens(&source.text)) {
+            writeln!(tokens, "{token}").unwrap();
+        }
+    }
+    tokens
+}
+
+/// The passes to run, and their options.
+fn pipeline(args: &Args) -> (Pipeline, opt::Options) {
+    let options = opt::Options {
+        unroll_threshold: args.unroll_threshold,
+        verify_each: args.verify_each || cfg!(debug_assertions),
+        profile: args.profile_use.clone(),
+        traps: opt::Traps {
+            div_zero: args.trap_div_zero,
+            overflow: args.trap_overflow,
+        },
+    };
+    let mut pipeline = args.passes.clone().unwrap_or_else(|| {
+        Pipeline::for_level(opt::OptLevel::new(args.opt_level).expect("clap checks the range"))
+    });
+    if args.profile_generate {
+        pipeline = pipeline.then(opt::find_pass("instrument").unwrap());
+    }
+    (pipeline, options)
 }
 
-fn get_ir(input: &str, opt: bool) -> tir::Program {
-    let ast = parse(input).unwrap();
+/// Reject programs with constants that don't fit in the values of the
+/// target, instead of compiling them to something else.
+fn check_constants(ir: &tir::Program, args: &Args) {
+    let Some(target) = Target::of(&args.target) else {
+        return;
+    };
+    if let Some(c) = codegen::oversized_constant(ir, target) {
+        compile_error(&[Diagnostic::error(format!(
+            "The constant `{c}` doesn't fit in a value on `{}`.",
+            args.target
+        ))
+        .with_code("target")
+        .with_note(format!(
+            "Values are {} bits on `{}`.",
+            target.word_size() * 8,
+            args.target
+        ))]);
+    }
+}
+
+fn get_ir(input: &[Source], args: &Args) -> tir::Program {
+    let ast = get_ast(input);
+    let ir = stage("lower", || lower(ast));
+    check_constants(&ir, args);
+    let (pipeline, options) = pipeline(args);
+    let (ir, stats, remarks) = if args.remarks.is_some() {
+        pipeline.run_with_remarks(ir, &options)
+    } else {
+        let (ir, stats) = pipeline.run_with_stats(ir, &options);
+        (ir, stats, vec![])
+    };
+    record_stage("optimize", stats.total_time());
+    if args.time_passes {
+        eprint!("{stats}");
+    }
+    match args.remarks {
+        Some(RemarkFormat::Text) => remarks.iter().for_each(|r| eprintln!("{r}")),
+        Some(RemarkFormat::Json) => eprint!("{}", opt::remarks_to_json(&remarks)),
+        None => {}
+    }
+    ir
+}
+
+/// The RISC-V architecture and system of the target, for the outputs of the
+/// RISC-V backend.
+fn riscv(args: &Args) -> (Target, System) {
+    match (Target::of(&args.target), System::of(&args.target)) {
+        (Some(target), Some(system)) => (target, system),
+        _ => usage_error(format!(
+            "The `{}` target has no RISC-V code.  Its output is `{}`.",
+            args.target,
+            default_output(&args.target)
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+        )),
+    }
+}
+
+fn target(args: &Args) -> Target {
+    riscv(args).0
+}
+
+/// The output of the backend of a target, without `--out`.
+fn default_output(target: &TargetSpec) -> Output {
+    match target.backend {
+        Backend::Riscv => Output::Asm,
+        Backend::Llvm => Output::Llvm,
+        Backend::Wasm => Output::Wat,
+    }
+}
+
+fn extensions(args: &Args) -> Extensions {
+    Extensions {
+        zba: args.ext.contains(&Ext::Zba),
+        zbb: args.ext.contains(&Ext::Zbb),
+    }
+}
+
+fn code_options(input: &[Source], args: &Args) -> codegen::Options {
+    codegen::Options {
+        allocator: match args.regalloc.unwrap_or(if args.opt_level >= 2 {
+            RegAlloc::GraphColor
+        } else {
+            RegAlloc::Simple
+        }) {
+            RegAlloc::Simple => Allocator::Simple,
+            RegAlloc::GraphColor => Allocator::GraphColor,
+        },
+        layout: args.opt_level > 0,
+        profile: args.profile_use.clone(),
+        schedule: args.opt_level > 0,
+        stack_limit: args.stack_limit,
+        soft_mul_div: args.no_m,
+        extensions: extensions(args),
+        trap_div_zero: args.trap_div_zero,
+        trap_overflow: args.trap_overflow,
+        gc: args.gc,
+        source: single_source(input).map(|source| source.text.clone()),
+        asm_comments: args.asm_comments,
+        debug_file: single_source(input)
+            .filter(|_| args.debug_info)
+            .map(|source| source.name.clone()),
+        target: target(args),
+    }
+}
+
+fn get_code(input: &[Source], args: &Args) -> asm::Program {
+    let ir = get_ir(input, args);
+    let code = stage("codegen", || code_gen_with(ir, &code_options(input, args)));
+    match args.stats {
+        Some(StatsFormat::Table) => eprint!("{}", code.stats()),
+        Some(StatsFormat::Json) => eprint!("{}", code.stats().to_json()),
+        None => {}
+    }
+    code
+}
+
+fn asm_options(args: &Args) -> AsmOptions {
+    AsmOptions {
+        pic: args.pic,
+        compressed: args.compressed,
+        extensions: extensions(args),
+        system: riscv(args).1,
+    }
+}
+
+fn get_asm(input: &[Source], args: &Args) -> String {
+    let code = get_code(input, args);
+    stage("emit", || code.asm_code_with(&asm_options(args)))
+}
+
+/// Write every stage of the compilation to numbered files in `dir`, like
+/// `04-sccp.tir` for the tiny IR after SCCP, the fifth stage at `-O1`.
+fn emit_all(input: &[Source], args: &Args, dir: &Path) {
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        usage_error(format!("Cannot create `{}`: {e}", dir.display()));
+    }
+    let mut stage = 0;
+    let mut emit = |name: &str, extension: &str, text: &str| {
+        let name = name
+            .trim_matches(|c| c == '(' || c == ')')
+            .replace(' ', "-");
+        let path = dir.join(format!("{stage:02}-{name}.{extension}"));
+        if let Err(e) = std::fs::write(&path, text) {
+            usage_error(format!("Cannot write `{}`: {e}", path.display()));
+        }
+        stage += 1;
+    };
+    emit("tokens", "txt", &get_tokens(input));
+    let ast = get_ast(input);
+    emit("ast", "txt", &format!("{ast:#?}\n"));
     let ir = lower(ast);
-    if opt {
-        optimize(ir)
+    check_constants(&ir, args);
+    emit("lowered", "tir", &ir.to_string());
+    let (pipeline, options) = pipeline(args);
+    let (ir, _) = pipeline.run_observed(ir, &options, None, |pass, ir| {
+        emit(pass, "tir", &ir.to_string())
+    });
+    let code = code_gen_with(ir, &code_options(input, args));
+    emit(
+        "asm",
+        "s",
+        &format!("{}\n", code.asm_code_with(&asm_options(args))),
+    );
+}
+
+/// How `--run` runs the program: the way of `--via`, or the first one that
+/// runs code for the target.  The emulator only runs RV64 code, and the JIT
+/// compiles for the host, whose values are 64 bits like those of RV64, so it
+/// runs the code of x86-64 too.  The JIT doesn't check for the errors of
+/// `--trap-div-zero` and `--trap-overflow`, so it doesn't run programs that
+/// should.
+fn via(args: &Args) -> Via {
+    let traps = args.trap_div_zero || args.trap_overflow;
+    let via = args
+        .via
+        .unwrap_or_else(|| match (args.target.arch, args.target.os) {
+            (Arch::Riscv64, Os::Linux) if traps => Via::Emu,
+            (Arch::Riscv64, Os::Linux) => Via::default(),
+            (Arch::Riscv64, Os::ProxyKernel) => Via::Spike,
+            (_, Os::BareMetal) => usage_error("`--run` cannot run bare-metal programs."),
+            (Arch::Riscv32, _) => Via::Qemu,
+            #[cfg(feature = "cranelift")]
+            (Arch::X86_64, _) => Via::Jit,
+            _ => usage_error(format!(
+                "`--run` cannot run programs for `{}`.",
+                args.target
+            )),
+        });
+    #[cfg(feature = "cranelift")]
+    if via == Via::Jit && traps {
+        let flag = if args.trap_div_zero {
+            "--trap-div-zero"
+        } else {
+            "--trap-overflow"
+        };
+        usage_error(format!("The JIT cannot run programs with `{flag}`."));
+    }
+    via
+}
+
+/// Run the program the way [via] says, and exit with its status.
+fn run(input: &[Source], args: &Args) -> ! {
+    let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+    let result = match via(args) {
+        #[cfg(feature = "cranelift")]
+        Via::Jit => {
+            let ir = get_ir(input, args);
+            report_stages(args);
+            jit::run(&ir, stdin.lock(), stdout.lock()).map_err(|e| e.to_string())
+        }
+        Via::Emu => {
+            let code = get_code(input, args);
+            report_stages(args);
+            emu::run(&code, stdin.lock(), stdout.lock()).map_err(|e| e.to_string())
+        }
+        Via::Qemu => run_linked(runner::run_qemu, input, args),
+        Via::Spike => run_linked(runner::run_spike, input, args),
+    };
+    match result {
+        Ok(status) => std::process::exit(status as i32),
+        Err(e) => compile_error(&[Diagnostic::error(e).with_code("run")]),
+    }
+}
+
+/// Link the program and run it with `runner`, passing on its input and
+/// output.
+fn run_linked(
+    runner: impl FnOnce(
+        &str,
+        &[PathBuf],
+        Target,
+        &AsmOptions,
+        &[String],
+        &[u8],
+    ) -> Result<runner::Outcome, runner::RunError>,
+    input: &[Source],
+    args: &Args,
+) -> Result<i64, String> {
+    use std::io::{Read, Write};
+
+    let mut stdin = vec![];
+    std::io::stdin()
+        .read_to_end(&mut stdin)
+        .map_err(|e| format!("Cannot read the input: {e}"))?;
+    let asm = get_asm(input, args);
+    report_stages(args);
+    let outcome = runner(
+        &asm,
+        &args.runtime,
+        target(args),
+        &asm_options(args),
+        &args.program_args,
+        &stdin,
+    )
+    .map_err(|e| e.to_string())?;
+    let _ = std::io::stdout().write_all(&outcome.stdout);
+    let _ = std::io::stderr().write_all(&outcome.stderr);
+    outcome
+        .status
+        .map(i64::from)
+        .ok_or_else(|| "The program was killed by a signal.".to_string())
+}
+
+/// The source of an input file.
+struct Source {
+    /// The name of the file, for diagnostics and debug information, or
+    /// `<stdin>`.
+    name: String,
+    text: String,
+}
+
+/// Read the sources of the input files, and of stdin for `--stdin` or `-`.
+fn read_sources(args: &Args) -> Vec<Source> {
+    use std::io::Read;
+
+    let files = if args.stdin {
+        &["-".to_string()][..]
+    } else {
+        &args.files
+    };
+    files
+        .iter()
+        .map(|file| {
+            let (name, text) = if file == "-" {
+                let mut text = String::new();
+                let read = std::io::stdin().read_to_string(&mut text);
+                ("<stdin>", read.map(|_| text))
+            } else {
+                (file.as_str(), std::fs::read_to_string(file))
+            };
+            let text = text.unwrap_or_else(|e| usage_error(format!("Cannot read `{name}`: {e}")));
+            Source {
+                name: name.to_string(),
+                text,
+            }
+        })
+        .collect()
+}
+
+/// The file to write a binary output to: the one of `-o`, or one in the
+/// current directory named after the first input file, with `extension` instead of
+/// its own.  Executables have no extension, so one named after an input file
+/// without an extension would overwrite it, and goes to `a.out` instead, like
+/// the output of a source from stdin.
+fn binary_output(args: &Args, extension: &str) -> PathBuf {
+    if let Some(path) = &args.output {
+        return path.clone();
+    }
+    let Some(file) = args.files.first().filter(|file| *file != "-") else {
+        return PathBuf::from("a.out");
+    };
+    let mut name = Path::new(file)
+        .file_stem()
+        .unwrap_or("a".as_ref())
+        .to_os_string();
+    if !extension.is_empty() {
+        name.push(".");
+        name.push(extension);
+    }
+    let path = PathBuf::from(name);
+    if path == Path::new(file) {
+        PathBuf::from("a.out")
     } else {
-        ir
+        path
+    }
+}
+
+/// Write a text output to the file of `-o`, or to stdout.
+fn write_output(args: &Args, text: &str) {
+    use std::io::Write;
+
+    match &args.output {
+        Some(path) if path.as_os_str() != "-" => {
+            if let Err(e) = std::fs::write(path, text) {
+                usage_error(format!("Cannot write `{}`: {e}", path.display()));
+            }
+        }
+        _ => {
+            let _ = std::io::stdout().write_all(text.as_bytes());
+        }
     }
 }
 
+/// The exit status when the program has errors, or can't be built or run.
+const EXIT_COMPILE_ERROR: i32 = 1;
+
+/// The exit status when smolc is used wrong: with arguments it doesn't take,
+/// which clap reports with the same status, or with files it can't read or
+/// write.
+const EXIT_USAGE_ERROR: i32 = 2;
+
+/// The exit status when smolc has a bug, like Rust's for panics.
+const EXIT_INTERNAL_ERROR: i32 = 101;
+
+/// Whether diagnostics are colored.  Those before the arguments are parsed
+/// aren't.
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Whether diagnostics are JSON, for `--message-format json`.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// The most errors to report, or 0 for all of them, for `--max-errors`.
+static MAX_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether there are more errors than smolc reports, so looking for more is
+/// no use.
+fn too_many_errors(errors: &[Diagnostic]) -> bool {
+    let max = MAX_ERRORS.load(Ordering::Relaxed);
+    max != 0 && errors.len() > max
+}
+
+/// Report diagnostics the way `--color` and `--message-format` say.
+/// `NO_COLOR` turns colors off unless it's empty, like everywhere else.
+fn init_diagnostics(args: &Args) {
+    JSON.store(
+        args.message_format == MessageFormat::Json,
+        Ordering::Relaxed,
+    );
+    let color = match args.color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => {
+            std::io::stderr().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        }
+    };
+    COLOR.store(color, Ordering::Relaxed);
+    MAX_ERRORS.store(args.max_errors, Ordering::Relaxed);
+}
+
+fn report(diagnostic: &Diagnostic) {
+    if JSON.load(Ordering::Relaxed) {
+        println!("{}", diagnostic.to_json());
+    } else {
+        eprint!("{}", diagnostic.render(COLOR.load(Ordering::Relaxed)));
+    }
+}
+
+/// Print what a command for packaging smolc asks for, from the arguments
+/// that smolc takes, and exit.  It's generated first and written at once, so
+/// that a reader that stops early, like `head`, only ends the output.
+fn package(command: Command) -> ! {
+    use std::io::Write as _;
+
+    let mut cmd = Args::command();
+    let mut out = vec![];
+    match command {
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut cmd, "smolc", &mut out)
+        }
+        Command::Man => clap_mangen::Man::new(cmd)
+            .render(&mut out)
+            .expect("writing to a vector can't fail"),
+    }
+    let mut stdout = std::io::stdout().lock();
+    match stdout.write_all(&out).and_then(|()| stdout.flush()) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => usage_error(format!("Cannot write to stdout: {e}")),
+    }
+    std::process::exit(0);
+}
+
+/// Report errors in the program, or in building it, and exit.  After
+/// `--max-errors` of them, it says there are too many instead.
+fn compile_error(diagnostics: &[Diagnostic]) -> ! {
+    if too_many_errors(diagnostics) {
+        let max = MAX_ERRORS.load(Ordering::Relaxed);
+        diagnostics[..max].iter().for_each(report);
+        report(
+            &Diagnostic::error("Too many errors, stopping.").with_note(format!(
+                "smolc stops after {max} errors; see `--max-errors`."
+            )),
+        );
+    } else {
+        diagnostics.iter().for_each(report);
+    }
+    std::process::exit(EXIT_COMPILE_ERROR);
+}
+
+/// Report an error in how smolc is used, and exit.
+fn usage_error(msg: impl std::fmt::Display) -> ! {
+    report(&Diagnostic::error(msg.to_string()).with_code("usage"));
+    std::process::exit(EXIT_USAGE_ERROR);
+}
+
+/// Report panics as internal errors instead of with Rust's message, unless
+/// `RUST_BACKTRACE` asks for the backtrace, and exit.
+fn catch_internal_errors() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::env::var_os("RUST_BACKTRACE").is_some() {
+            default(info);
+        } else {
+            let payload = info.payload();
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            let mut diagnostic =
+                Diagnostic::error(format!("Internal compiler error: {msg}")).with_code("internal");
+            if let Some(location) = info.location() {
+                diagnostic = diagnostic.with_note(format!("smolc panicked at {location}."));
+            }
+            report(&diagnostic.with_note(
+                "This is a bug in smolc.  Set `RUST_BACKTRACE=1` to see how it got there.",
+            ));
+        }
+        std::process::exit(EXIT_INTERNAL_ERROR);
+    }));
+}
+
+/// The command-line arguments, with a bare `-O` spelled `-O2`.  Clap would
+/// take the argument after an optional value for the level, even a file.
+fn command_line() -> Vec<OsString> {
+    let mut operands = false;
+    std::env::args_os()
+        .map(|arg| {
+            operands |= arg == "--";
+            if arg == "-O" && !operands {
+                OsString::from("-O2")
+            } else {
+                arg
+            }
+        })
+        .collect()
+}
+
 fn main() {
     use Output::*;
-    let args = Args::parse();
+    catch_internal_errors();
+    let args = Args::parse_from(command_line());
+    init_diagnostics(&args);
+    init_log(&args);
+    if let Some(command) = args.command {
+        package(command);
+    }
 
-    let input = String::from_utf8(std::fs::read(&args.file).expect("file should be readable"))
-        .expect("input characters should be utf8");
+    let input = read_sources(&args);
+    if let Some(dir) = &args.emit_all {
+        emit_all(&input, &args, dir);
+        STAGES.lock().unwrap().clear();
+    }
+
+    if args.run {
+        run(&input, &args);
+    }
 
-    match args.out {
-        Tokens => {
-            let mut lexer = lex::Lexer::new(&input);
-            while let Some(token) = lexer.next() {
-                println!("{token}");
+    let text = match args.out.unwrap_or(default_output(&args.target)) {
+        Tokens => get_tokens(&input),
+        Ast => format!("{:?}\n", get_ast(&input)),
+        AstDot => ast_dot(&get_ast(&input)),
+        Tir => get_ir(&input, &args).to_string(),
+        CfgDot => cfg_dot(&get_ir(&input, &args), args.edge_labels),
+        Asm => format!("{}\n", get_asm(&input, &args)),
+        Exe => {
+            let (target, options) = (target(&args), asm_options(&args));
+            let asm = get_asm(&input, &args);
+            let linked = stage("link", || {
+                link::Toolchain::detect(target, options.system).and_then(|toolchain| {
+                    toolchain.link(
+                        &asm,
+                        &args.runtime,
+                        &binary_output(&args, ""),
+                        target,
+                        &options,
+                    )
+                })
+            });
+            report_stages(&args);
+            if let Err(e) = linked {
+                compile_error(&[Diagnostic::error(e.to_string()).with_code("link")]);
             }
+            return;
         }
-        Ast => {
-            println!("{:?}", parse(&input).unwrap());
+        Obj => {
+            let asm = get_asm(&input, &args);
+            let assembled = stage("assemble", || {
+                link::Toolchain::detect(target(&args), asm_options(&args).system).and_then(
+                    |toolchain| toolchain.assemble(&asm, &binary_output(&args, "o"), target(&args)),
+                )
+            });
+            report_stages(&args);
+            if let Err(e) = assembled {
+                compile_error(&[Diagnostic::error(e.to_string()).with_code("link")]);
+            }
+            return;
         }
-        Tir => {
-            println!("{:?}", get_ir(&input, args.optimize))
+        Disasm => stage("emit", || get_code(&input, &args).encode())
+            .map_err(|e| e.to_string())
+            .and_then(|binary| binary.disassemble(target(&args)).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| compile_error(&[Diagnostic::error(e).with_code("encode")])),
+        Wat => {
+            let ir = get_ir(&input, &args);
+            stage("emit", || wasm::wat_code(ir))
         }
-        Asm => {
-            println!("{}", code_gen(get_ir(&input, args.optimize)).asm_code())
+        Llvm => {
+            let ir = get_ir(&input, &args);
+            stage("emit", || llvm::llvm_code_for(ir, &args.target))
         }
-    }
+        LinkerScript => baremetal::LINKER_SCRIPT.to_string(),
+        Runtime => runtime::RUNTIME_SOURCE.to_string(),
+    };
+    report_stages(&args);
+    write_output(&args, &text);
 }
@@ -5,3 +5,259 @@ pub use std::collections::{BTreeMap as Map, BTreeSet as Set};
 
 /// Identifiers.
 pub type Id = internment::Intern<String>;
+
+/// A range of bytes in the source code.  The tiny IR marks where the code of
+/// each statement starts with its span, so that later stages can refer back
+/// to the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The number of the line where the span starts, counting from 1, and
+    /// the text of that line without the line break.
+    pub fn line<'a>(&self, source: &'a str) -> (usize, &'a str) {
+        let start = self.start.min(source.len());
+        let number = source[..start].matches('\n').count() + 1;
+        let line = source.lines().nth(number - 1).unwrap_or("");
+        (number, line)
+    }
+
+    /// The column where the span starts, counting characters from 1.
+    pub fn column(&self, source: &str) -> usize {
+        let start = self.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        source[line_start..start].chars().count() + 1
+    }
+}
+
+/// A panic that [catch_panic] caught.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Panic {
+    pub message: String,
+    /// The file, line, and column where it panicked.
+    pub location: Option<String>,
+}
+
+impl std::fmt::Display for Panic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} (at {location})", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+thread_local! {
+    /// Where the panics of this thread go while [catch_panic] runs: the
+    /// slot for the location of the last one.
+    static CAUGHT: std::cell::RefCell<Option<Option<String>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Run `f`, and return its panic instead if it panics, for tools that report
+/// panics in the compiler as the results of their programs.  The panic isn't
+/// printed, but the panics of other threads, and the ones outside of `f`, are.
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, Panic> {
+    static HOOK: std::sync::Once = std::sync::Once::new();
+    HOOK.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let caught = CAUGHT.with(|slot| match slot.borrow_mut().as_mut() {
+                Some(slot) => {
+                    *slot = info.location().map(|l| l.to_string());
+                    true
+                }
+                None => false,
+            });
+            if !caught {
+                default(info);
+            }
+        }));
+    });
+    let outer = CAUGHT.with(|slot| slot.replace(Some(None)));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    let location = CAUGHT.with(|slot| slot.replace(outer)).flatten();
+    result.map_err(|payload| Panic {
+        message: payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string()),
+        location,
+    })
+}
+
+/// The numbers of the input of a program, which are separated by whitespace.
+/// The interpreter, the emulator, and the JIT all read them this way.  They
+/// are read a line at a time, so that a program reading from a terminal gets
+/// each number as soon as its line is entered.
+pub struct Input<R> {
+    reader: R,
+    /// Words that have been read but not consumed yet, in reverse.
+    pending: Vec<String>,
+}
+
+impl<R: std::io::BufRead> Input<R> {
+    pub fn new(reader: R) -> Self {
+        Input {
+            reader,
+            pending: vec![],
+        }
+    }
+
+    /// Read the next number.  The error says why there isn't one.
+    pub fn read(&mut self) -> Result<i64, String> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            let n = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Cannot read the input: {e}"))?;
+            if n == 0 {
+                return Err("Unexpected end of input.".to_string());
+            }
+            self.pending = line.split_whitespace().rev().map(str::to_string).collect();
+        }
+        let word = self.pending.pop().unwrap();
+        word.parse()
+            .map_err(|_| format!("The input `{word}` is not a number."))
+    }
+}
+
+/// The architectures that code can be for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Arch {
+    Riscv64,
+    Riscv32,
+    X86_64,
+    Wasm32,
+}
+
+/// The systems that can run the code, which decide how it starts, exits,
+/// and does I/O.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Os {
+    /// Linux, or any system with a C library.
+    Linux,
+    /// The RISC-V proxy kernel, without a C library.
+    ProxyKernel,
+    /// Bare metal, under an SBI implementation like OpenSBI.
+    BareMetal,
+    /// A WebAssembly host, which provides the I/O.
+    WasmHost,
+}
+
+/// The backends that generate the code of a target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Backend {
+    /// RISC-V assembly, which the crate also encodes into machine code.
+    Riscv,
+    /// LLVM IR, for LLVM to compile.
+    Llvm,
+    /// A WebAssembly module.
+    Wasm,
+}
+
+/// A target that the compiler generates code for: the architecture, the
+/// system that runs the code, and the backend that generates it.  Each
+/// target has a name like a target triple, like `riscv64-linux`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TargetSpec {
+    pub name: &'static str,
+    pub arch: Arch,
+    pub os: Os,
+    pub backend: Backend,
+    /// The target triple of LLVM for the same target.
+    pub llvm_triple: &'static str,
+}
+
+/// All targets, the default first.
+pub const TARGETS: &[TargetSpec] = &[
+    TargetSpec {
+        name: "riscv64-linux",
+        arch: Arch::Riscv64,
+        os: Os::Linux,
+        backend: Backend::Riscv,
+        llvm_triple: "riscv64-unknown-linux-gnu",
+    },
+    TargetSpec {
+        name: "riscv32-linux",
+        arch: Arch::Riscv32,
+        os: Os::Linux,
+        backend: Backend::Riscv,
+        llvm_triple: "riscv32-unknown-linux-gnu",
+    },
+    TargetSpec {
+        name: "riscv64-pk",
+        arch: Arch::Riscv64,
+        os: Os::ProxyKernel,
+        backend: Backend::Riscv,
+        llvm_triple: "riscv64-unknown-elf",
+    },
+    TargetSpec {
+        name: "riscv64-baremetal",
+        arch: Arch::Riscv64,
+        os: Os::BareMetal,
+        backend: Backend::Riscv,
+        llvm_triple: "riscv64-unknown-elf",
+    },
+    TargetSpec {
+        name: "riscv32-baremetal",
+        arch: Arch::Riscv32,
+        os: Os::BareMetal,
+        backend: Backend::Riscv,
+        llvm_triple: "riscv32-unknown-elf",
+    },
+    TargetSpec {
+        name: "x86_64-linux",
+        arch: Arch::X86_64,
+        os: Os::Linux,
+        backend: Backend::Llvm,
+        llvm_triple: "x86_64-unknown-linux-gnu",
+    },
+    TargetSpec {
+        name: "wasm32",
+        arch: Arch::Wasm32,
+        os: Os::WasmHost,
+        backend: Backend::Wasm,
+        llvm_triple: "wasm32-unknown-unknown",
+    },
+];
+
+impl TargetSpec {
+    /// The target with the given name.  `riscv64` and `riscv32` are short
+    /// for the Linux targets.
+    pub fn parse(name: &str) -> Result<TargetSpec, String> {
+        let name = match name {
+            "riscv64" => "riscv64-linux",
+            "riscv32" => "riscv32-linux",
+            name => name,
+        };
+        TARGETS
+            .iter()
+            .find(|t| t.name == name)
+            .copied()
+            .ok_or_else(|| {
+                let known = TARGETS.iter().map(|t| t.name).collect::<Vec<_>>();
+                format!(
+                    "Unknown target `{name}`.  The targets are {}.",
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+impl Default for TargetSpec {
+    fn default() -> Self {
+        TARGETS[0]
+    }
+}
+
+impl std::fmt::Display for TargetSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name)
+    }
+}
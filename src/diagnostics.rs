@@ -0,0 +1,314 @@
+//! Diagnostics: the errors and warnings that the compiler reports to the
+//! people who use it.
+//!
+//! A diagnostic has a message, and points at the file it's about, if any,
+//! and at the span of source it's about, if it knows one.  It renders like
+//! the diagnostics of rustc: a line with the severity and the message, the
+//! place in the file, the line of source with the span underlined, and notes
+//! that explain more.  On terminals, it can color them the way rustc does,
+//! with ANSI escape codes.  For tools, it can also be a JSON object.
+//!
+//! ```text
+//! error: Unexpected token `)`.
+//!  --> prog.smol:2:7
+//!   |
+//! 2 | print )
+//!   |       ^
+//!   = note: A print statement needs an expression.
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use serde_json::json;
+
+use crate::common::Span;
+
+/// How bad a diagnostic is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The program can't be compiled.
+    Error,
+    /// The program compiles, but probably doesn't do what it should.
+    Warning,
+}
+
+impl Severity {
+    /// The ANSI escape code of the color of the severity, in bold.
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+/// The ANSI escape codes of the parts of colored diagnostics.
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// A place in a source file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// The bytes of the source it's about.
+    pub span: Span,
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column, counting characters from 1.
+    pub column: usize,
+    /// The text of the line, without the line break.
+    pub text: String,
+    /// The number of characters of the span on the line, at least 1.
+    pub width: usize,
+}
+
+impl Location {
+    /// The place of a span in `source`.  Spans over several lines are
+    /// underlined up to the end of their first line.
+    pub fn new(source: &str, span: Span) -> Location {
+        let (line, text) = span.line(source);
+        let column = span.column(source);
+        let start = span.start.min(source.len());
+        let end = span.end.clamp(start, source.len());
+        let rest = text.chars().count() + 1 - column;
+        let width = source[start..end]
+            .chars()
+            .take_while(|c| *c != '\n')
+            .count()
+            .clamp(1, rest.max(1));
+        Location {
+            span,
+            line,
+            column,
+            text: text.to_string(),
+            width,
+        }
+    }
+}
+
+/// Something to tell about the program being compiled.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    /// A short name of the kind of diagnostic, like `syntax`, for tools
+    /// that tell kinds apart.
+    pub code: Option<&'static str>,
+    pub severity: Severity,
+    pub message: String,
+    /// The name of the file it's about, if any.
+    pub file: Option<String>,
+    /// Where in the file, if the diagnostic knows.
+    pub location: Option<Location>,
+    /// More about the diagnostic, like how to fix it.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// An error that isn't about any file yet.
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            code: None,
+            severity: Severity::Error,
+            message: message.into(),
+            file: None,
+            location: None,
+            notes: vec![],
+        }
+    }
+
+    /// A warning that isn't about any file yet.
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message)
+        }
+    }
+
+    /// The diagnostic, of the kind `code`.
+    pub fn with_code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    /// The diagnostic, about the file called `name`.
+    pub fn in_file(mut self, name: impl Into<String>) -> Diagnostic {
+        self.file = Some(name.into());
+        self
+    }
+
+    /// The diagnostic, about `span` in `source`.
+    pub fn at(mut self, source: &str, span: Span) -> Diagnostic {
+        self.location = Some(Location::new(source, span));
+        self
+    }
+
+    /// The diagnostic, with another note.
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic as text, colored with ANSI escape codes if `color` is
+    /// set.  Without color, this is the same as displaying it.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (severity, bold, blue, reset) = (
+            paint(self.severity.color()),
+            paint(BOLD),
+            paint(BLUE),
+            paint(RESET),
+        );
+        let mut out = format!(
+            "{severity}{}{reset}{bold}: {}{reset}\n",
+            self.severity, self.message
+        );
+        // The gutter is as wide as the line number, so the bars line up.
+        let gutter = self
+            .location
+            .as_ref()
+            .map_or(0, |l| l.line.to_string().len());
+        let pad = " ".repeat(gutter);
+        let place = match (&self.file, &self.location) {
+            (Some(file), Some(l)) => Some(format!("{file}:{}:{}", l.line, l.column)),
+            (Some(file), None) => Some(file.clone()),
+            (None, Some(l)) => Some(format!("{}:{}", l.line, l.column)),
+            (None, None) => None,
+        };
+        if let Some(place) = place {
+            out += &format!("{pad}{blue}-->{reset} {place}\n");
+        }
+        if let Some(l) = &self.location {
+            out += &format!("{pad} {blue}|{reset}\n");
+            out += &format!("{blue}{} |{reset} {}\n", l.line, l.text);
+            out += &format!(
+                "{pad} {blue}|{reset} {}{severity}{}{reset}\n",
+                " ".repeat(l.column - 1),
+                "^".repeat(l.width)
+            );
+        }
+        for note in &self.notes {
+            out += &format!("{pad} {blue}={reset} {bold}note{reset}: {note}\n");
+        }
+        out
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic as a JSON object on one line.  The span is `null` if
+    /// the diagnostic doesn't know one, and its lines and columns count from
+    /// 1 like in the text.
+    pub fn to_json(&self) -> String {
+        let span = self.location.as_ref().map(|l| {
+            json!({
+                "start": l.span.start,
+                "end": l.span.end,
+                "line": l.line,
+                "column": l.column,
+            })
+        });
+        json!({
+            "code": self.code,
+            "severity": self.severity.to_string(),
+            "message": self.message,
+            "file": self.file,
+            "span": span,
+            "notes": self.notes,
+        })
+        .to_string()
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_span() {
+        let source = "x = 1\nprint x +\n";
+        let d = Diagnostic::error("Expected an expression after `+`.")
+            .in_file("prog.smol")
+            .at(source, Span { start: 14, end: 15 })
+            .with_note("Binary operators need two operands.");
+        assert_eq!(
+            d.to_string(),
+            "error: Expected an expression after `+`.\n \
+             --> prog.smol:2:9\n  \
+             |\n\
+             2 | print x +\n  \
+             |         ^\n  \
+             = note: Binary operators need two operands.\n"
+        );
+    }
+
+    #[test]
+    fn renders_without_a_span() {
+        let d = Diagnostic::error("Parse error: Unexpected end of input.").in_file("prog.smol");
+        assert_eq!(
+            d.to_string(),
+            "error: Parse error: Unexpected end of input.\n--> prog.smol\n"
+        );
+        assert_eq!(
+            Diagnostic::warning("Unused variable `x`.").to_string(),
+            "warning: Unused variable `x`.\n"
+        );
+    }
+
+    #[test]
+    fn renders_colors() {
+        let d = Diagnostic::warning("Unused variable `x`.")
+            .at("x = 1", Span { start: 0, end: 1 })
+            .with_note("Remove it.");
+        assert_eq!(
+            d.render(true),
+            "\x1b[1;33mwarning\x1b[0m\x1b[1m: Unused variable `x`.\x1b[0m\n \
+             \x1b[1;34m-->\x1b[0m 1:1\n  \
+             \x1b[1;34m|\x1b[0m\n\
+             \x1b[1;34m1 |\x1b[0m x = 1\n  \
+             \x1b[1;34m|\x1b[0m \x1b[1;33m^\x1b[0m\n  \
+             \x1b[1;34m=\x1b[0m \x1b[1mnote\x1b[0m: Remove it.\n"
+        );
+        assert_eq!(d.render(false), d.to_string());
+    }
+
+    #[test]
+    fn json() {
+        let d = Diagnostic::error("Unexpected `\"`.")
+            .with_code("syntax")
+            .in_file("prog.smol")
+            .at("x = \"", Span { start: 4, end: 5 })
+            .with_note("Strings aren't a thing.");
+        assert_eq!(
+            d.to_json(),
+            r#"{"code":"syntax","severity":"error","message":"Unexpected `\"`.","file":"prog.smol","span":{"start":4,"end":5,"line":1,"column":5},"notes":["Strings aren't a thing."]}"#
+        );
+        assert_eq!(
+            Diagnostic::warning("Careful.").to_json(),
+            r#"{"code":null,"severity":"warning","message":"Careful.","file":null,"span":null,"notes":[]}"#
+        );
+    }
+
+    #[test]
+    fn underlines_the_first_line_of_a_span() {
+        let source = "if x {\n  print x\n}";
+        let l = Location::new(source, Span { start: 3, end: 18 });
+        assert_eq!((l.line, l.column, l.width), (1, 4, 3));
+        let l = Location::new(source, Span { start: 17, end: 17 });
+        assert_eq!((l.line, l.column, l.width), (3, 1, 1));
+    }
+}
@@ -1,10 +1,12 @@
 //! The front-end of the compiler.
 
 pub mod ast;
+pub mod format;
 pub mod lex;
 pub mod lower;
 pub mod parse;
+pub mod symbols;
 
 pub use ast::*;
 pub use lower::lower;
-pub use parse::parse;
+pub use parse::{parse, parse_tokens};
@@ -1,14 +1,51 @@
 //! The abstract syntax tree.
 
-use crate::common::Id;
+use derive_more::Display;
 
-#[derive(Debug)]
+use crate::common::{Id, Span};
+
+mod dot;
+pub use dot::ast_dot;
+
+pub mod generate;
+
+mod print;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Program {
     pub stmts: Vec<Stmt>,
 }
 
-#[derive(Debug)]
-pub enum Stmt {
+/// A statement, and where it is in the source.  Spans don't take part in
+/// comparisons, so a program that is printed and parsed back is the same
+/// program.
+#[derive(Clone, Debug)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    /// From the first token of the statement to its last one.  Statements
+    /// that the parser didn't make have an empty span.
+    pub span: Span,
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Stmt {}
+
+impl From<StmtKind> for Stmt {
+    fn from(kind: StmtKind) -> Self {
+        Stmt {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StmtKind {
     Assign(Id, Expr),
     Print(Expr),
     Read(Expr),
@@ -19,7 +56,7 @@ pub enum Stmt {
     },
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Expr {
     Var(Id),
     Const(i64),
@@ -31,11 +68,16 @@ pub enum Expr {
     Negate(Box<Expr>),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum BOp {
+    #[display("*")]
     Mul,
+    #[display("/")]
     Div,
+    #[display("+")]
     Add,
+    #[display("-")]
     Sub,
+    #[display("<")]
     Lt,
 }
@@ -0,0 +1,153 @@
+//! Rendering the syntax tree of a program as a Graphviz DOT graph.
+//!
+//! Each statement and expression becomes a node, with edges to its parts.
+//! The branches of an `if` hang off `then` and `else` nodes, so the order of
+//! their statements stays visible.
+
+use std::fmt::Write;
+
+use super::*;
+
+/// Render the syntax tree of the program, from the `program` node down.
+pub fn ast_dot(program: &Program) -> String {
+    let mut dot = AstDot {
+        out: String::new(),
+        nodes: 0,
+    };
+    writeln!(dot.out, "digraph ast {{").unwrap();
+    writeln!(dot.out, "  node [shape=box, fontname=monospace];").unwrap();
+    let root = dot.node("program");
+    dot.stmts(root, &program.stmts);
+    writeln!(dot.out, "}}").unwrap();
+    dot.out
+}
+
+struct AstDot {
+    out: String,
+    /// The number of nodes so far, which names the next one.
+    nodes: usize,
+}
+
+impl AstDot {
+    /// Add a node with the label, and return its number.
+    fn node(&mut self, label: &str) -> usize {
+        let n = self.nodes;
+        self.nodes += 1;
+        writeln!(self.out, "  n{n} [label=\"{}\"];", escape(label)).unwrap();
+        n
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        write!(self.out, "  n{from} -> n{to}").unwrap();
+        if !label.is_empty() {
+            write!(self.out, " [label=\"{}\"]", escape(label)).unwrap();
+        }
+        writeln!(self.out, ";").unwrap();
+    }
+
+    fn stmts(&mut self, parent: usize, stmts: &[Stmt]) {
+        for stmt in stmts {
+            let n = self.stmt(stmt);
+            self.edge(parent, n, "");
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> usize {
+        match &stmt.kind {
+            StmtKind::Assign(x, e) => {
+                let n = self.node(&format!("{x} ="));
+                let e = self.expr(e);
+                self.edge(n, e, "");
+                n
+            }
+            StmtKind::Print(e) | StmtKind::Read(e) => {
+                let n = self.node(match stmt.kind {
+                    StmtKind::Print(_) => "print",
+                    _ => "read",
+                });
+                let e = self.expr(e);
+                self.edge(n, e, "");
+                n
+            }
+            StmtKind::If { guard, tt, ff } => {
+                let n = self.node("if");
+                let guard = self.expr(guard);
+                self.edge(n, guard, "guard");
+                let then = self.node("then");
+                self.edge(n, then, "");
+                self.stmts(then, tt);
+                if !ff.is_empty() {
+                    let els = self.node("else");
+                    self.edge(n, els, "");
+                    self.stmts(els, ff);
+                }
+                n
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> usize {
+        match expr {
+            Expr::Var(x) => self.node(x),
+            Expr::Const(c) => self.node(&c.to_string()),
+            Expr::BOp { op, lhs, rhs } => {
+                let n = self.node(&op.to_string());
+                let lhs = self.expr(lhs);
+                self.edge(n, lhs, "");
+                let rhs = self.expr(rhs);
+                self.edge(n, rhs, "");
+                n
+            }
+            Expr::Negate(e) => {
+                let n = self.node("-");
+                let e = self.expr(e);
+                self.edge(n, e, "");
+                n
+            }
+        }
+    }
+}
+
+/// Escape a string for use inside a quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_statements_and_expressions() {
+        let x = Id::from_ref("x");
+        let p = Program {
+            stmts: vec![
+                StmtKind::Read(Expr::Var(x)).into(),
+                StmtKind::If {
+                    guard: Expr::BOp {
+                        op: BOp::Lt,
+                        lhs: Box::new(Expr::Var(x)),
+                        rhs: Box::new(Expr::Const(10)),
+                    },
+                    tt: vec![StmtKind::Print(Expr::Negate(Box::new(Expr::Var(x)))).into()],
+                    ff: vec![],
+                }
+                .into(),
+            ],
+        };
+
+        let dot = ast_dot(&p);
+        assert!(dot.starts_with("digraph ast {"));
+        assert!(dot.contains(r#"n0 [label="program"];"#));
+        assert!(dot.contains(r#"n1 [label="read"];"#));
+        assert!(dot.contains("n0 -> n1;"));
+        assert!(dot.contains(r#"n3 [label="if"];"#));
+        assert!(dot.contains(r#"n3 -> n4 [label="guard"];"#));
+        assert!(dot.contains(r#"n4 [label="<"];"#));
+        assert!(dot.contains(r#"n6 [label="10"];"#));
+        assert!(dot.contains(r#"n7 [label="then"];"#));
+        assert!(!dot.contains("else"));
+        // A tree has one edge less than it has nodes.
+        assert_eq!(dot.matches("->").count(), dot.matches("label=").count() - 2);
+    }
+}
@@ -0,0 +1,125 @@
+//! Generating random well-formed programs, for fuzzing the compiler.
+//!
+//! The programs use a few variables, so that statements depend on each
+//! other, and small constants, so that the optimizer has something to fold.
+//! They read, print, and branch on what they compute, and nest `$if`s a
+//! couple of levels deep.
+//!
+//! The concrete syntax writes negation and subtraction with the same `-`, so
+//! some programs that negate print the same as others: `- - x y` is both
+//! -(x - y) and -x - y.  A random program is the one that the parser reads
+//! from how it prints, so that printing it and parsing it back gives it
+//! again.
+
+use super::*;
+use crate::front::parse;
+
+/// A small deterministic pseudo-random number generator, so programs are
+/// reproducible from their seeds.
+#[derive(Clone, Debug)]
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next_u64(&mut self) -> u64 {
+        // The constants are from Knuth's MMIX.
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        self.next_u64() as usize % n
+    }
+}
+
+const VARS: [&str; 4] = ["a", "b", "c", "d"];
+const OPS: [BOp; 5] = [BOp::Add, BOp::Sub, BOp::Mul, BOp::Div, BOp::Lt];
+
+/// The deepest `$if`s and expressions go.
+const MAX_DEPTH: usize = 3;
+
+/// A random program, the same one for the same seed.  It has up to
+/// `max_stmts` statements at the top level, and prints all of its variables
+/// at the end.
+pub fn random_program(seed: u64, max_stmts: usize) -> Program {
+    let mut rng = Rng(seed);
+    let n = 1 + rng.below(max_stmts.max(1));
+    let mut stmts = random_stmts(&mut rng, n, 0);
+    stmts.extend(VARS.map(|v| StmtKind::Print(Expr::Var(Id::from_ref(v))).into()));
+    parse(&Program { stmts }.to_string()).expect("printed programs parse")
+}
+
+fn random_stmts(rng: &mut Rng, n: usize, depth: usize) -> Vec<Stmt> {
+    (0..n).map(|_| random_stmt(rng, depth)).collect()
+}
+
+fn random_var(rng: &mut Rng) -> Id {
+    Id::from_ref(VARS[rng.below(VARS.len())])
+}
+
+fn random_stmt(rng: &mut Rng, depth: usize) -> Stmt {
+    let kind = match rng.below(if depth < MAX_DEPTH { 8 } else { 7 }) {
+        0..=3 => StmtKind::Assign(random_var(rng), random_expr(rng, 0)),
+        4 | 5 => StmtKind::Print(random_expr(rng, 0)),
+        6 => StmtKind::Read(Expr::Var(random_var(rng))),
+        _ => {
+            let guard = random_expr(rng, 0);
+            let (n_tt, n_ff) = (rng.below(4), rng.below(3));
+            StmtKind::If {
+                guard,
+                tt: random_stmts(rng, n_tt, depth + 1),
+                ff: random_stmts(rng, n_ff, depth + 1),
+            }
+        }
+    };
+    kind.into()
+}
+
+fn random_expr(rng: &mut Rng, depth: usize) -> Expr {
+    match rng.below(if depth < MAX_DEPTH { 6 } else { 3 }) {
+        0 | 1 => Expr::Var(random_var(rng)),
+        // Mostly small constants, and sometimes ones that overflow.
+        2 => Expr::Const(match rng.below(8) {
+            0 => i64::MAX,
+            _ => rng.below(5) as i64,
+        }),
+        3 => Expr::Negate(Box::new(random_expr(rng, depth + 1))),
+        _ => Expr::BOp {
+            op: OPS[rng.below(OPS.len())],
+            lhs: Box::new(random_expr(rng, depth + 1)),
+            rhs: Box::new(random_expr(rng, depth + 1)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::format::format_source;
+
+    #[test]
+    fn programs_are_reproducible() {
+        for seed in 0..20 {
+            let p = random_program(seed, 10);
+            assert_eq!(p, random_program(seed, 10));
+            assert!(p.stmts.len() <= 14);
+            let source = p.to_string();
+            assert_eq!(format_source(&source).unwrap(), source);
+        }
+        assert_ne!(random_program(0, 10), random_program(1, 10));
+        fn negates(e: &Expr) -> bool {
+            match e {
+                Expr::Var(_) | Expr::Const(_) => false,
+                Expr::BOp { lhs, rhs, .. } => negates(lhs) || negates(rhs),
+                Expr::Negate(_) => true,
+            }
+        }
+        let program = random_program(0, 10);
+        assert!(program.stmts.iter().any(|stmt| match &stmt.kind {
+            StmtKind::Assign(_, e) | StmtKind::Print(e) => negates(e),
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,91 @@
+//! Printing syntax trees as smol source, in the syntax of `doc/syntax.md`.
+//!
+//! The source is in the style of the formatter (see
+//! [crate::front::format]), so printing a program and formatting it give the
+//! same text, without the comments.
+
+use std::fmt::{Display, Formatter, Result};
+
+use super::*;
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for stmt in &self.stmts {
+            stmt.fmt_indented(f, 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Stmt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl Stmt {
+    /// Print the statement on its own lines, `depth` blocks deep.
+    fn fmt_indented(&self, f: &mut Formatter<'_>, depth: usize) -> Result {
+        let indent = "  ".repeat(depth);
+        match &self.kind {
+            StmtKind::Assign(x, e) => writeln!(f, "{indent}:= {x} {e}"),
+            StmtKind::Print(e) => writeln!(f, "{indent}$print {e}"),
+            StmtKind::Read(e) => writeln!(f, "{indent}$read {e}"),
+            StmtKind::If { guard, tt, ff } => {
+                writeln!(f, "{indent}$if {guard} {{")?;
+                for stmt in tt {
+                    stmt.fmt_indented(f, depth + 1)?;
+                }
+                writeln!(f, "{indent}}} {{")?;
+                for stmt in ff {
+                    stmt.fmt_indented(f, depth + 1)?;
+                }
+                writeln!(f, "{indent}}}")
+            }
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Expr::Var(x) => write!(f, "{x}"),
+            Expr::Const(c) => write!(f, "{c}"),
+            Expr::BOp { op, lhs, rhs } => write!(f, "{op} {lhs} {rhs}"),
+            Expr::Negate(e) => write!(f, "- {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::format::format_source;
+
+    #[test]
+    fn prints_formatted_source() {
+        let x = Id::from_ref("x");
+        let p = Program {
+            stmts: vec![
+                StmtKind::Read(Expr::Var(x)).into(),
+                StmtKind::If {
+                    guard: Expr::BOp {
+                        op: BOp::Lt,
+                        lhs: Box::new(Expr::Var(x)),
+                        rhs: Box::new(Expr::Negate(Box::new(Expr::Const(1)))),
+                    },
+                    tt: vec![StmtKind::Assign(x, Expr::Const(0)).into()],
+                    ff: vec![],
+                }
+                .into(),
+                StmtKind::Print(Expr::Var(x)).into(),
+            ],
+        };
+        let source = p.to_string();
+        assert_eq!(
+            source,
+            "$read x\n$if < x - 1 {\n  := x 0\n} {\n}\n$print x\n"
+        );
+        assert_eq!(format_source(&source).unwrap(), source);
+    }
+}
@@ -0,0 +1,197 @@
+//! The formatter, which prints smol programs in the canonical style of
+//! `doc/syntax.md`.
+//!
+//! Each statement goes on its own line, indented by two spaces per enclosing
+//! `{`, with its tokens one space apart.  The branches of an `$if` open on
+//! the line of the guard, and the second one opens on the line that closes
+//! the first, like `} {`.  Comments stay where they were: on their own lines,
+//! or at the end of the line of the token before them.  A statement that a
+//! comment splits goes on in the next line, indented a bit more.  Runs of
+//! blank lines between statements become one, and there are none at the
+//! start of a block.
+//!
+//! The formatter works on the tokens, not on the syntax tree, so it keeps
+//! the comments that the lexer skips.  It finds them in the source between
+//! the tokens.
+
+use derive_more::derive::Display;
+
+use super::lex::*;
+use super::symbols::spanned_tokens;
+use crate::common::Span;
+
+/// Why a program can't be formatted.
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+#[display("Format error: {message}")]
+pub struct FormatError {
+    pub message: String,
+    /// The part of the source that's the problem.
+    pub span: Span,
+}
+
+/// The source, formatted.  Programs whose tokens can't all be lexed, or
+/// whose braces don't match, aren't formatted, so that the formatter never
+/// changes what a program means.
+pub fn format_source(source: &str) -> Result<String, FormatError> {
+    let mut f = Formatter {
+        out: String::new(),
+        line: String::new(),
+        depth: 0,
+    };
+    let mut prev_end = 0;
+    for (token, span) in spanned_tokens(source) {
+        if token.kind == TokenKind::Error {
+            return Err(FormatError {
+                message: format!("Unrecognized character `{}`.", token.text),
+                span,
+            });
+        }
+        let blank = f.trivia(&source[prev_end..span.start]);
+        f.token(token, blank)
+            .map_err(|message| FormatError { message, span })?;
+        prev_end = span.end;
+    }
+    f.trivia(&source[prev_end..]);
+    f.end_line();
+    if f.depth > 0 {
+        return Err(FormatError {
+            message: "A `{` is never closed.".to_string(),
+            span: Span {
+                start: source.len(),
+                end: source.len(),
+            },
+        });
+    }
+    Ok(f.out)
+}
+
+struct Formatter {
+    out: String,
+    /// The line being written, with its indentation.
+    line: String,
+    /// The number of enclosing braces.
+    depth: usize,
+}
+
+impl Formatter {
+    fn indent(&self) -> String {
+        "  ".repeat(self.depth)
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            self.out += self.line.trim_end();
+            self.out.push('\n');
+            self.line.clear();
+        }
+    }
+
+    /// Start a new line, after a blank one if `blank` is set and the line
+    /// isn't the first one of the program or of a block.
+    fn new_line(&mut self, blank: bool) {
+        self.end_line();
+        if blank && !self.out.is_empty() && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+        self.line = self.indent();
+    }
+
+    /// Write the comments of the whitespace and comments before a token,
+    /// and return whether a blank line is left before the token.
+    fn trivia(&mut self, trivia: &str) -> bool {
+        let mut newlines = 0;
+        for line in trivia.split_inclusive('\n') {
+            if let Some(i) = line.find("//") {
+                let comment = line[i..].trim_end();
+                if newlines == 0 && !self.line.trim().is_empty() {
+                    // A comment after a token stays at the end of its line.
+                    self.line += " ";
+                    self.line += comment;
+                    self.end_line();
+                } else {
+                    self.new_line(newlines > 1);
+                    self.line += comment;
+                    self.end_line();
+                }
+                newlines = 0;
+            }
+            if line.ends_with('\n') {
+                newlines += 1;
+            }
+        }
+        newlines > 1
+    }
+
+    fn token(&mut self, token: Token<'_>, blank: bool) -> Result<(), String> {
+        use TokenKind::*;
+
+        match token.kind {
+            Assign | Print | Read | If => {
+                self.new_line(blank);
+                self.line += token.text;
+            }
+            LBrace => {
+                if self.line.trim().is_empty() {
+                    self.line = self.indent();
+                } else {
+                    self.line.push(' ');
+                }
+                self.line.push('{');
+                self.end_line();
+                self.depth += 1;
+            }
+            RBrace => {
+                if self.depth == 0 {
+                    return Err("This `}` closes no `{`.".to_string());
+                }
+                self.end_line();
+                self.depth -= 1;
+                // The next `{` opens on this line, if it's the second branch.
+                self.line = self.indent() + "}";
+            }
+            _ => {
+                if self.line.trim().is_empty() {
+                    // The statement goes on after a comment.
+                    self.line = self.indent() + "    ";
+                } else {
+                    self.line.push(' ');
+                }
+                self.line += token.text;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_statements_and_blocks() {
+        let source = "$read a   $read b\n$if < a b { $print a }\n{\n$print   b}";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "$read a\n$read b\n$if < a b {\n  $print a\n} {\n  $print b\n}\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments_and_blank_lines() {
+        let source = "// max\n\n\n$read a // first\n$if < a 0 {\n\n  // negative\n  := a - 0 // flip\n a\n}{}\n";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(
+            formatted,
+            "// max\n\n$read a // first\n$if < a 0 {\n  // negative\n  := a - 0 // flip\n      a\n} {\n}\n"
+        );
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn refuses_broken_programs() {
+        let e = format_source("$print 1 ?").unwrap_err();
+        assert_eq!(e.span, Span { start: 9, end: 10 });
+        assert!(format_source("$if x {").is_err());
+        assert!(format_source("}").is_err());
+    }
+}
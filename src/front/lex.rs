@@ -118,9 +118,9 @@ impl<'input> Lexer<'input> {
         let mut kind = Error;
         let mut len = 1;
 
-        for (re, kindForRe) in &self.matchers {
+        for (re, kind_for_re) in &self.matchers {
             if let Some(m) = re.find(&self.input[self.pos..]) {
-                kind = *kindForRe;
+                kind = *kind_for_re;
                 len = m.len();
                 break;
             }
@@ -138,7 +138,7 @@ impl<'input> Lexer<'input> {
 }
 
 /// Read all the tokens from input
-pub fn get_tokens(input: &str) -> Vec<Token> {
+pub fn get_tokens(input: &str) -> Vec<Token<'_>> {
     let mut lexer = Lexer::new(input);
 
     let mut tokens = vec![];
@@ -155,17 +155,17 @@ mod tests {
     // SECTION: helpers
 
     // Create an id token
-    fn id(text: &str) -> Token {
+    fn id(text: &str) -> Token<'_> {
         Token { kind: Id, text }
     }
 
     // Create a num token
-    fn num(text: &str) -> Token {
+    fn num(text: &str) -> Token<'_> {
         Token { kind: Num, text }
     }
 
     // Create an error token
-    fn error(text: &str) -> Token {
+    fn error(text: &str) -> Token<'_> {
         Token { kind: Error, text }
     }
 
@@ -1,8 +1,181 @@
 //! Lowering
+//!
+//! The program becomes the function `main`, whose variables are the ones of
+//! the program, under the same names, so they start at 0 like smol's.  Each
+//! expression is computed into temporaries named `$t0`, `$t1`, and so on,
+//! which can't clash with the program's variables, and negation subtracts
+//! from 0.  A `$if` ends its block with a branch on the guard, and both of its
+//! branches jump to a block after it, so the CFG has no cycles.
+//!
+//! The code of each statement starts with a `$loc` of the statement's span,
+//! so that the backends can point back at the source.  Statements that the
+//! parser didn't make have no span, and get no `$loc`.
 
 use super::ast;
-use crate::middle::tir;
+use super::ast::{BOp, Expr, Stmt, StmtKind};
+use crate::common::Span;
+use crate::middle::tir::{self, Builder};
 
-pub fn lower(_program: ast::Program) -> tir::Program {
-    todo!()
+pub fn lower(program: ast::Program) -> tir::Program {
+    let mut lowerer = Lowerer {
+        builder: Builder::new(),
+        temps: 0,
+        ifs: 0,
+    };
+    lowerer.stmts(&program.stmts);
+    lowerer.builder.exit();
+    lowerer.builder.finish()
+}
+
+struct Lowerer {
+    builder: Builder,
+    /// The number of temporaries so far.
+    temps: usize,
+    /// The number of `$if`s so far, which name their blocks.
+    ifs: usize,
+}
+
+impl Lowerer {
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        if stmt.span != Span::default() {
+            self.builder.loc(stmt.span);
+        }
+        match &stmt.kind {
+            StmtKind::Assign(x, e) => {
+                let src = self.expr(e);
+                self.builder.copy(x, &src);
+            }
+            StmtKind::Print(e) => {
+                let src = self.expr(e);
+                self.builder.print(&src);
+            }
+            StmtKind::Read(Expr::Var(x)) => self.builder.read(x),
+            StmtKind::Read(e) => panic!("cannot read into `{e:?}`, which is not a variable"),
+            StmtKind::If { guard, tt, ff } => {
+                let guard = self.expr(guard);
+                let n = self.ifs;
+                self.ifs += 1;
+                let (then, els, end) = (
+                    format!("if{n}.then"),
+                    format!("if{n}.else"),
+                    format!("if{n}.end"),
+                );
+                self.builder.branch(&guard, &then, &els);
+                for (name, stmts) in [(&then, tt), (&els, ff)] {
+                    self.builder.block(name);
+                    self.stmts(stmts);
+                    self.builder.jump(&end);
+                }
+                self.builder.block(&end);
+            }
+        }
+    }
+
+    /// Compute the expression, and return the variable that has its value.
+    fn expr(&mut self, e: &Expr) -> String {
+        match e {
+            Expr::Var(x) => x.to_string(),
+            Expr::Const(c) => {
+                let dst = self.temp();
+                self.builder.constant(&dst, *c);
+                dst
+            }
+            Expr::BOp { op, lhs, rhs } => {
+                let (lhs, rhs) = (self.expr(lhs), self.expr(rhs));
+                let dst = self.temp();
+                self.builder.arith(*op, &dst, &lhs, &rhs);
+                dst
+            }
+            Expr::Negate(e) => {
+                let src = self.expr(e);
+                let (zero, dst) = (self.temp(), self.temp());
+                self.builder.constant(&zero, 0);
+                self.builder.arith(BOp::Sub, &dst, &zero, &src);
+                dst
+            }
+        }
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("$t{}", self.temps - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::parse;
+    use crate::middle::{interp, verify::verify};
+
+    /// Lower the program, and run it with the interpreter on the input.
+    fn run(source: &str, input: &str) -> String {
+        let program = lower(parse(source).unwrap());
+        verify(&program).unwrap();
+        let mut output = vec![];
+        interp::run(&program, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn straight_line() {
+        assert_eq!(run("", ""), "");
+        assert_eq!(run(":= x + 40 2 $print x $print y", ""), "42\n0\n");
+        assert_eq!(run("$read a $print * a - a 1", "5"), "20\n");
+        assert_eq!(run("$print - 7 $print / 7 0", ""), "-7\n-1\n");
+    }
+
+    #[test]
+    fn branches() {
+        let source = "$read a $read b $if < a b { $print a } { $print b } $print 0";
+        assert_eq!(run(source, "1 2"), "1\n0\n");
+        assert_eq!(run(source, "2 1"), "1\n0\n");
+        let nested = "$read a $if a { $if - a 1 { $print 2 } { $print 1 } } { } $print a";
+        assert_eq!(run(nested, "0"), "0\n");
+        assert_eq!(run(nested, "1"), "1\n1\n");
+        assert_eq!(run(nested, "3"), "2\n3\n");
+    }
+
+    #[test]
+    fn names() {
+        let program = lower(parse(":= x - 1 $if x { } { }").unwrap());
+        let main = &program.func[&tir::Program::main()];
+        for name in ["x", "$t0", "$t1", "$t2"] {
+            main.var(name);
+        }
+        for name in ["if0.then", "if0.else", "if0.end"] {
+            main.block_named(name);
+        }
+    }
+
+    #[test]
+    fn locs() {
+        let source = ":= x 1\n$if x {\n  $print x\n} { }\n";
+        let program = lower(parse(source).unwrap());
+        let main = &program.func[&tir::Program::main()];
+        let lines = main
+            .block
+            .values()
+            .flat_map(|block| &block.insn)
+            .filter_map(|insn| match insn {
+                tir::Instruction::Loc(span) => Some(span.line(source)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(1, ":= x 1"), (2, "$if x {"), (3, "  $print x")]);
+        let program = lower(ast::Program {
+            stmts: vec![StmtKind::Print(Expr::Const(1)).into()],
+        });
+        let main = &program.func[&tir::Program::main()];
+        assert!(!main.block[&tir::BlockId::ENTRY]
+            .insn
+            .iter()
+            .any(|insn| matches!(insn, tir::Instruction::Loc(_))));
+    }
 }
@@ -6,10 +6,17 @@ use derive_more::derive::Display;
 
 use super::ast::*;
 use super::lex::*;
+use super::symbols::spanned_tokens;
+use crate::common::{Id, Span};
+use TokenKind::*;
 
 #[derive(Display)]
-#[display("Parse error: {}", self.0)]
-pub struct ParseError(String);
+#[display("Parse error: {message}")]
+pub struct ParseError {
+    pub message: String,
+    /// The token where the error is, or an empty span where the input ends.
+    pub span: Span,
+}
 
 impl Debug for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -20,12 +27,15 @@ impl Debug for ParseError {
 type ParseResult<T> = Result<T, ParseError>;
 
 pub fn parse(input: &str) -> Result<Program, ParseError> {
-    let mut parser = Parser::new(input);
+    parse_tokens(spanned_tokens(input))
+}
+
+/// Parse the tokens of a program, with their spans, in order.
+pub fn parse_tokens(tokens: Vec<(Token<'_>, Span)>) -> Result<Program, ParseError> {
+    let mut parser = Parser::new(tokens);
     let program = parser.parse_program()?;
-    if parser.tokens.is_empty() {
-        Err(ParseError(
-            "There are still leftover tokens after reading a whole program.".to_string(),
-        ))
+    if let Some(token) = parser.peek() {
+        Err(parser.unexpected(token, parser.peek_span(), "a statement"))
     } else {
         Ok(program)
     }
@@ -33,54 +43,348 @@ pub fn parse(input: &str) -> Result<Program, ParseError> {
 
 struct Parser<'input> {
     /// Rest of the input, ordered in reverse.
-    tokens: Vec<Token<'input>>,
+    tokens: Vec<(Token<'input>, Span)>,
+    /// The span of the last token read.
+    last: Span,
+    /// The number of expressions to parse after the one being parsed, to
+    /// finish the operands of the binary operators around it.
+    pending: usize,
 }
 
 impl<'a> Parser<'a> {
-    fn new(input: &'a str) -> Self {
-        let mut tokens = get_tokens(input);
+    fn new(mut tokens: Vec<(Token<'a>, Span)>) -> Self {
         tokens.reverse();
-        Parser { tokens }
+        Parser {
+            tokens,
+            last: Span::default(),
+            pending: 0,
+        }
     }
 
-    fn peek(&self) -> Option<Token> {
-        self.tokens.last().copied()
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.last().map(|(token, _)| *token)
     }
 
-    fn next(&mut self) -> ParseResult<Token> {
-        self.tokens
-            .pop()
-            .ok_or(ParseError("Unexpected end of input.".to_owned()))
+    /// The span of the next token, or an empty one after the last.
+    fn peek_span(&self) -> Span {
+        self.tokens.last().map_or(
+            Span {
+                start: self.last.end,
+                end: self.last.end,
+            },
+            |(_, span)| *span,
+        )
+    }
+
+    fn next(&mut self) -> ParseResult<Token<'a>> {
+        let (token, span) = self.tokens.pop().ok_or_else(|| ParseError {
+            message: "Unexpected end of input.".to_owned(),
+            span: self.peek_span(),
+        })?;
+        self.last = span;
+        Ok(token)
     }
 
     fn next_is(&self, kind: TokenKind) -> bool {
         self.peek().map(|t| t.kind == kind).unwrap_or(false)
     }
 
-    fn eat(&self, kind: TokenKind) -> ParseResult<()> {
+    /// Read the next token, which has to have the given kind.
+    fn eat(&mut self, kind: TokenKind) -> ParseResult<Token<'a>> {
         if self.next_is(kind) {
-            Ok(())
+            self.next()
         } else if let Some(actual) = self.peek() {
-            Err(ParseError(format!(
-                "Expected a token with kind {kind}, found a token with kind {} and text `{}`.",
-                actual.kind, actual.text
-            )))
+            Err(ParseError {
+                message: format!(
+                    "Expected a token with kind {kind}, found a token with kind {} and text `{}`.",
+                    actual.kind, actual.text
+                ),
+                span: self.peek_span(),
+            })
         } else {
-            Err(ParseError(format!(
-                "Expected a token with kind {kind} but reached the end of input."
-            )))
+            Err(ParseError {
+                message: format!("Expected a token with kind {kind} but reached the end of input."),
+                span: self.peek_span(),
+            })
+        }
+    }
+
+    /// The error for a token where the parser expected something else.
+    fn unexpected(&self, actual: Token<'_>, span: Span, expected: &str) -> ParseError {
+        ParseError {
+            message: format!(
+                "Expected {expected}, found a token with kind {} and text `{}`.",
+                actual.kind, actual.text
+            ),
+            span,
         }
     }
 
     fn parse_program(&mut self) -> ParseResult<Program> {
-        todo!()
+        Ok(Program {
+            stmts: self.parse_stmts()?,
+        })
+    }
+
+    /// Parse the statements up to the first token that can't start one.
+    fn parse_stmts(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut stmts = vec![];
+        while self
+            .peek()
+            .is_some_and(|t| matches!(t.kind, Assign | Print | Read | If))
+        {
+            stmts.push(self.parse_stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    /// Parse the statements of a block, in braces.
+    fn parse_block(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.eat(LBrace)?;
+        let stmts = self.parse_stmts()?;
+        self.eat(RBrace)?;
+        Ok(stmts)
     }
 
     fn parse_stmt(&mut self) -> ParseResult<Stmt> {
-        todo!()
+        let start = self.peek_span().start;
+        let token = self.next()?;
+        let kind = match token.kind {
+            Assign => {
+                let x = self.parse_id()?;
+                StmtKind::Assign(x, self.parse_expr()?)
+            }
+            Print => StmtKind::Print(self.parse_expr()?),
+            Read => StmtKind::Read(Expr::Var(self.parse_id()?)),
+            If => StmtKind::If {
+                guard: self.parse_expr()?,
+                tt: self.parse_block()?,
+                ff: self.parse_block()?,
+            },
+            _ => return Err(self.unexpected(token, self.last, "a statement")),
+        };
+        let span = Span {
+            start,
+            end: self.last.end,
+        };
+        Ok(Stmt { kind, span })
     }
 
+    fn parse_id(&mut self) -> ParseResult<Id> {
+        Ok(Id::from_ref(self.eat(TokenKind::Id)?.text))
+    }
+
+    /// Parse an expression.  `-` is both subtraction and negation, and which
+    /// one it is depends on how many operands the rest of the expression has:
+    /// it's a subtraction if they're enough for one, and a negation
+    /// otherwise.  So `- x 1` subtracts, `< x - 1` compares with -1, and
+    /// `+ - x y` adds -x and y.  When both would do, as in `- - x y`, the
+    /// first `-` subtracts.
     fn parse_expr(&mut self) -> ParseResult<Expr> {
-        todo!()
+        let token = self.next()?;
+        let op = match token.kind {
+            TokenKind::Id => return Ok(Expr::Var(Id::from_ref(token.text))),
+            Num => {
+                return token.text.parse().map(Expr::Const).map_err(|_| ParseError {
+                    message: format!("The number `{}` is too large.", token.text),
+                    span: self.last,
+                })
+            }
+            Minus if !self.subtracts() => return Ok(Expr::Negate(Box::new(self.parse_expr()?))),
+            Minus => BOp::Sub,
+            Mul => BOp::Mul,
+            Div => BOp::Div,
+            Plus => BOp::Add,
+            Lt => BOp::Lt,
+            _ => return Err(self.unexpected(token, self.last, "an expression")),
+        };
+        self.pending += 1;
+        let lhs = Box::new(self.parse_expr()?);
+        self.pending -= 1;
+        let rhs = Box::new(self.parse_expr()?);
+        Ok(Expr::BOp { op, lhs, rhs })
+    }
+
+    /// Whether the `-` just read is a subtraction.  Each operand the rest of
+    /// the expression has fills a place for an expression, each binary
+    /// operator makes one more place, and each `-` makes one more if it
+    /// subtracts.  A subtraction needs places for its two operands and the
+    /// pending expressions, so it's one if there are enough operands for
+    /// them, and not so many that the `-`s after it can't subtract the rest.
+    fn subtracts(&self) -> bool {
+        let (mut operands, mut bops, mut minuses) = (0, 0, 0);
+        for (token, _) in self.tokens.iter().rev() {
+            match token.kind {
+                TokenKind::Id | Num => operands += 1,
+                Plus | Mul | Div | Lt => bops += 1,
+                Minus => minuses += 1,
+                _ => break,
+            }
+        }
+        let spare = operands as isize - bops as isize - self.pending as isize - 2;
+        (0..=minuses).contains(&spare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: helpers
+
+    fn var(x: &str) -> Expr {
+        Expr::Var(Id::from_ref(x))
+    }
+
+    fn bop(op: BOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::BOp {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }
+    }
+
+    fn neg(e: Expr) -> Expr {
+        Expr::Negate(Box::new(e))
+    }
+
+    /// The statements of the source, without their spans.
+    fn stmts(source: &str) -> Vec<StmtKind> {
+        let program = parse(source).unwrap();
+        program.stmts.into_iter().map(|stmt| stmt.kind).collect()
+    }
+
+    fn parse_err(input: &str) -> String {
+        parse(input).unwrap_err().to_string()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn statements() {
+        let x = Id::from_ref("x");
+        assert_eq!(parse("").unwrap(), Program { stmts: vec![] });
+        assert_eq!(
+            stmts(":= x * 40 + 2 3 $print x $read y"),
+            vec![
+                StmtKind::Assign(
+                    x,
+                    bop(
+                        BOp::Mul,
+                        Expr::Const(40),
+                        bop(BOp::Add, Expr::Const(2), Expr::Const(3))
+                    )
+                ),
+                StmtKind::Print(var("x")),
+                StmtKind::Read(var("y")),
+            ]
+        );
+        assert_eq!(
+            stmts("$if < x 0 { $print 1 } { }"),
+            vec![StmtKind::If {
+                guard: bop(BOp::Lt, var("x"), Expr::Const(0)),
+                tt: vec![StmtKind::Print(Expr::Const(1)).into()],
+                ff: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn minus() {
+        let expr = |source: &str| match stmts(source).as_slice() {
+            [StmtKind::Print(e)] => e.clone(),
+            stmts => panic!("{stmts:?}"),
+        };
+        assert_eq!(
+            expr("$print - x 1"),
+            bop(BOp::Sub, var("x"), Expr::Const(1))
+        );
+        assert_eq!(expr("$print - x"), neg(var("x")));
+        assert_eq!(expr("$print - - x"), neg(neg(var("x"))));
+        assert_eq!(
+            expr("$print < x - 1"),
+            bop(BOp::Lt, var("x"), neg(Expr::Const(1)))
+        );
+        assert_eq!(
+            expr("$print + - x y z"),
+            bop(BOp::Add, bop(BOp::Sub, var("x"), var("y")), var("z"))
+        );
+        assert_eq!(
+            expr("$print + - x y"),
+            bop(BOp::Add, neg(var("x")), var("y"))
+        );
+        assert_eq!(
+            expr("$print - - x y"),
+            bop(BOp::Sub, neg(var("x")), var("y"))
+        );
+        assert_eq!(
+            expr("$print * - x - y z"),
+            bop(BOp::Mul, bop(BOp::Sub, var("x"), neg(var("y"))), var("z"))
+        );
+        assert_eq!(
+            stmts(":= a - x $print a")[0],
+            StmtKind::Assign(Id::from_ref("a"), neg(var("x")))
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let source = ":= x 1\n$if x {\n  $print - x 1\n} {\n}\n";
+        let program = parse(source).unwrap();
+        let text = |stmt: &Stmt| &source[stmt.span.start..stmt.span.end];
+        assert_eq!(text(&program.stmts[0]), ":= x 1");
+        assert_eq!(text(&program.stmts[1]), &source[7..source.len() - 1]);
+        match &program.stmts[1].kind {
+            StmtKind::If { tt, .. } => assert_eq!(text(&tt[0]), "$print - x 1"),
+            kind => panic!("{kind:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for seed in 0..20 {
+            let program = generate::random_program(seed, 10);
+            assert_eq!(parse(&program.to_string()).unwrap(), program);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_err("$print"), "Parse error: Unexpected end of input.");
+        assert_eq!(
+            parse_err("$print 1 2"),
+            "Parse error: Expected a statement, found a token with kind num and text `2`."
+        );
+        assert_eq!(
+            parse_err("$read 1"),
+            "Parse error: Expected a token with kind id, found a token with kind num and text `1`."
+        );
+        assert_eq!(
+            parse_err("$if x { } $print x"),
+            "Parse error: Expected a token with kind {, found a token with kind $print and text `$print`."
+        );
+        assert_eq!(
+            parse_err(":= x %"),
+            "Parse error: Expected an expression, found a token with kind error and text `%`."
+        );
+        assert_eq!(
+            parse_err("$print 9223372036854775808"),
+            "Parse error: The number `9223372036854775808` is too large."
+        );
+    }
+
+    #[test]
+    fn error_spans() {
+        fn at(source: &str) -> &str {
+            let span = parse(source).unwrap_err().span;
+            &source[span.start..span.end]
+        }
+        assert_eq!(at("$print 1 2"), "2");
+        assert_eq!(at(":= x 1\n$read 1"), "1");
+        assert_eq!(at("$if x { } $print x"), "$print");
+        assert_eq!(at(":= x %"), "%");
+        assert_eq!(at("$print 9223372036854775808"), "9223372036854775808");
+        let source = "$print + 1 ";
+        assert_eq!(parse(source).unwrap_err().span, Span { start: 10, end: 10 });
     }
 }
@@ -0,0 +1,198 @@
+//! The statements and variables of a source file, for editors.
+//!
+//! Editors ask about programs as they're typed, and these programs often
+//! don't parse, so this works on the tokens instead of the syntax tree.  It
+//! finds the statements the way the parser would, skipping tokens that can't
+//! start one, and the definitions of variables, which are the assignments
+//! and `$read`s of them.  Variables start at 0 (see `doc/semantics.md`), so
+//! using a variable before any definition is allowed, but probably a
+//! mistake.
+
+use super::lex::*;
+use crate::common::{Id, Span};
+
+/// The tokens of the source, with their spans.
+pub fn spanned_tokens(source: &str) -> Vec<(Token<'_>, Span)> {
+    get_tokens(source)
+        .into_iter()
+        .map(|token| {
+            // The lexer's tokens are slices of the source.
+            let start = token.text.as_ptr() as usize - source.as_ptr() as usize;
+            let end = start + token.text.len();
+            (token, Span { start, end })
+        })
+        .collect()
+}
+
+/// A use or a definition of a variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Occurrence {
+    pub name: Id,
+    pub span: Span,
+    pub is_definition: bool,
+    /// The span of the innermost statement it's in.
+    pub statement: Span,
+}
+
+/// What a source file has, by position.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// The statements, each before the ones inside it.
+    pub statements: Vec<Span>,
+    /// The variables, in order.
+    pub occurrences: Vec<Occurrence>,
+}
+
+impl Symbols {
+    pub fn new(source: &str) -> Symbols {
+        let tokens = spanned_tokens(source);
+        let mut symbols = Symbols::default();
+        let mut i = 0;
+        while i < tokens.len() {
+            i = symbols.stmts(&tokens, i);
+            // Skip a `}` that closes nothing.
+            i += 1;
+        }
+        for (j, (token, span)) in tokens.iter().enumerate() {
+            if token.kind != TokenKind::Id {
+                continue;
+            }
+            let is_definition =
+                j > 0 && matches!(tokens[j - 1].0.kind, TokenKind::Assign | TokenKind::Read);
+            symbols.occurrences.push(Occurrence {
+                name: Id::from_ref(token.text),
+                span: *span,
+                is_definition,
+                statement: symbols.statement_at(span.start).unwrap_or(*span),
+            });
+        }
+        symbols
+    }
+
+    /// The innermost statement at the offset.
+    pub fn statement_at(&self, offset: usize) -> Option<Span> {
+        self.statements
+            .iter()
+            .filter(|s| s.start <= offset && offset < s.end)
+            .min_by_key(|s| s.end - s.start)
+            .copied()
+    }
+
+    /// The variable at the offset.
+    pub fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|o| o.span.start <= offset && offset <= o.span.end)
+    }
+
+    /// The first definition of the variable at the offset.
+    pub fn definition(&self, offset: usize) -> Option<Span> {
+        let name = self.occurrence_at(offset)?.name;
+        self.occurrences
+            .iter()
+            .find(|o| o.is_definition && o.name == name)
+            .map(|o| o.span)
+    }
+
+    /// The uses of variables that no definition comes before.  A definition
+    /// counts from the end of its statement, so `:= x + x 1` uses `x` before
+    /// it's assigned.
+    pub fn used_before_definition(&self) -> Vec<&Occurrence> {
+        self.occurrences
+            .iter()
+            .filter(|o| {
+                !o.is_definition
+                    && !self.occurrences.iter().any(|d| {
+                        d.is_definition && d.name == o.name && d.statement.end <= o.span.start
+                    })
+            })
+            .collect()
+    }
+
+    // SECTION: helpers
+
+    /// Find the statements from `i` up to a `}` or the end, and return the
+    /// index of the token after them.
+    fn stmts(&mut self, tokens: &[(Token<'_>, Span)], mut i: usize) -> usize {
+        while i < tokens.len() && tokens[i].0.kind != TokenKind::RBrace {
+            i = self.stmt(tokens, i);
+        }
+        i
+    }
+
+    /// Find the statement at `i`, if a statement starts there, and return
+    /// the index of the token after it.
+    fn stmt(&mut self, tokens: &[(Token<'_>, Span)], i: usize) -> usize {
+        use TokenKind::*;
+
+        let starts_stmt = |kind| matches!(kind, Assign | Print | Read | If);
+        if !starts_stmt(tokens[i].0.kind) {
+            return i + 1;
+        }
+        let index = self.statements.len();
+        self.statements.push(tokens[i].1);
+        let mut j = i + 1;
+        while j < tokens.len()
+            && !starts_stmt(tokens[j].0.kind)
+            && !matches!(tokens[j].0.kind, LBrace | RBrace)
+        {
+            j += 1;
+        }
+        if tokens[i].0.kind == If {
+            for _ in 0..2 {
+                if j < tokens.len() && tokens[j].0.kind == LBrace {
+                    j = self.stmts(tokens, j + 1);
+                    j += usize::from(j < tokens.len());
+                }
+            }
+        }
+        self.statements[index].end = tokens[j - 1].1.end;
+        j
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "$read a\n$if < a 0 {\n  := a - 0 a\n} {\n  $print b\n}\n:= b a\n";
+
+    #[test]
+    fn statements() {
+        let symbols = Symbols::new(SOURCE);
+        let text = |s: Span| &SOURCE[s.start..s.end];
+        let statements = symbols
+            .statements
+            .iter()
+            .map(|s| text(*s))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statements,
+            [
+                "$read a",
+                "$if < a 0 {\n  := a - 0 a\n} {\n  $print b\n}",
+                ":= a - 0 a",
+                "$print b",
+                ":= b a"
+            ]
+        );
+        // Inside a branch, the innermost statement is the one in the branch.
+        assert_eq!(text(symbols.statement_at(26).unwrap()), ":= a - 0 a");
+        assert_eq!(text(symbols.statement_at(12).unwrap()), statements[1]);
+    }
+
+    #[test]
+    fn definitions() {
+        let symbols = Symbols::new(SOURCE);
+        // `a` in the guard of the `$if`, and `a` at the end.
+        assert_eq!(symbols.definition(14), Some(Span { start: 6, end: 7 }));
+        assert_eq!(symbols.definition(55), Some(Span { start: 6, end: 7 }));
+        assert_eq!(symbols.definition(3), None);
+        let early = symbols.used_before_definition();
+        assert_eq!(early.len(), 1);
+        assert_eq!(early[0].name, Id::from_ref("b"));
+
+        let symbols = Symbols::new(":= x + x 1");
+        assert_eq!(symbols.used_before_definition().len(), 1);
+    }
+}
@@ -0,0 +1,251 @@
+//! Differential fuzzing: runs a program with the interpreter, and with the
+//! code of the backend without and with optimizations, and says how it
+//! fails if they don't agree or the compiler panics.  `smol-fuzz` runs this
+//! on random programs, and shrinks the ones that fail.
+//!
+//! The front end comes first: the program is printed, parsed back, and
+//! lowered once, and all of the paths run what it lowers to.  So a panic or a
+//! bug in the front end is reported as one, instead of as a difference
+//! between the interpreter and the code that it's compared with.
+
+use crate::back::{code_gen, emu};
+use crate::common::{catch_panic, Panic};
+use crate::front::ast::generate::Rng;
+use crate::front::ast::{Expr, Program, Stmt, StmtKind};
+use crate::front::{lower, parse};
+use crate::middle::interp;
+use crate::middle::opt::{self, OptLevel, Pipeline};
+use crate::middle::tir;
+
+/// What a program printed and its exit status, or why it didn't run.
+pub type Outcome = Result<(String, i64), String>;
+
+/// How a program failed: the outcome of each path that ran, named, and the
+/// first panic, if any.
+#[derive(Debug)]
+pub struct Failure {
+    pub outcomes: Vec<(String, Outcome)>,
+    pub panic: Option<(String, Panic)>,
+}
+
+impl Failure {
+    /// How the program fails, to keep the failure the same while shrinking.
+    /// Panics are the same if they're in the same place.
+    pub fn kind(&self) -> String {
+        match &self.panic {
+            Some((path, panic)) => match &panic.location {
+                Some(location) => format!("a panic in {path} at {location}"),
+                None => format!("a panic in {path}"),
+            },
+            // Only the front end ran.
+            None if self.outcomes.len() == 1 => format!("an error in {}", self.outcomes[0].0),
+            None => "different outcomes".to_string(),
+        }
+    }
+}
+
+/// Input for all the `$read`s of the program.
+pub fn random_input(seed: u64, program: &Program) -> String {
+    fn reads(stmts: &[Stmt]) -> usize {
+        stmts
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Read(_) => 1,
+                StmtKind::If { tt, ff, .. } => reads(tt) + reads(ff),
+                _ => 0,
+            })
+            .sum()
+    }
+    let mut rng = Rng(!seed);
+    (0..reads(&program.stmts))
+        .map(|_| format!("{} ", rng.below(21) as i64 - 10))
+        .collect::<String>()
+        + "\n"
+}
+
+/// Run the program every way, and return how it fails, if it does.
+pub fn check(program: &Program, input: &str, level: OptLevel) -> Option<Failure> {
+    let ir = match catch_panic(|| front_end(program)) {
+        Ok(Ok(ir)) => ir,
+        Ok(Err(e)) => {
+            return Some(Failure {
+                outcomes: vec![("the front end".to_string(), Err(e))],
+                panic: None,
+            })
+        }
+        Err(panic) => {
+            return Some(Failure {
+                outcomes: vec![(
+                    "the front end".to_string(),
+                    Err(format!("Panicked: {panic}")),
+                )],
+                panic: Some(("the front end".to_string(), panic)),
+            })
+        }
+    };
+
+    let input = input.as_bytes();
+    let mut panic = None;
+    let mut run = |path: String, f: &dyn Fn(&mut Vec<u8>) -> Result<i64, String>| {
+        let mut output = vec![];
+        let outcome = match catch_panic(|| f(&mut output)) {
+            Ok(status) => {
+                status.map(|status| (String::from_utf8_lossy(&output).into_owned(), status))
+            }
+            Err(p) => {
+                let outcome = Err(format!("Panicked: {p}"));
+                panic.get_or_insert((path.clone(), p));
+                outcome
+            }
+        };
+        (path, outcome)
+    };
+    let outcomes = vec![
+        run("the interpreter".to_string(), &|out| {
+            interp::run_status(&ir, input, out).map_err(|e| e.to_string())
+        }),
+        run("unoptimized code".to_string(), &|out| {
+            emu::run(&code_gen(ir.clone()), input, out).map_err(|e| e.to_string())
+        }),
+        run(format!("code at -{level:?}"), &|out| {
+            let ir = Pipeline::for_level(level).run(ir.clone(), &opt::Options::default());
+            emu::run(&code_gen(ir), input, out).map_err(|e| e.to_string())
+        }),
+    ];
+    let failed = panic.is_some() || outcomes.iter().any(|(_, o)| o != &outcomes[0].1);
+    failed.then_some(Failure { outcomes, panic })
+}
+
+/// Print the program, parse it back, and lower it, the way the compiler
+/// would compile its source.
+fn front_end(program: &Program) -> Result<tir::Program, String> {
+    let source = program.to_string();
+    let parsed = parse(&source).map_err(|e| e.to_string())?;
+    if parsed != *program {
+        return Err("The parser reads the printed program as another one.".to_string());
+    }
+    Ok(lower(parsed))
+}
+
+// SECTION: shrinking
+
+/// The smallest program that `fails` that shrinking the program step by step
+/// finds.  Each step takes the first smaller program that still fails.
+pub fn shrink(mut program: Program, fails: impl Fn(&Program) -> bool) -> Program {
+    while let Some(smaller) = shrink_stmts(&program.stmts)
+        .into_iter()
+        .map(|stmts| Program { stmts })
+        .find(&fails)
+    {
+        program = smaller;
+    }
+    program
+}
+
+/// The statements with one thing removed or simplified, each way there is,
+/// removing whole statements first.
+fn shrink_stmts(stmts: &[Stmt]) -> Vec<Vec<Stmt>> {
+    let with = |i: usize, replacement: Vec<Stmt>| {
+        let mut smaller = stmts[..i].to_vec();
+        smaller.extend(replacement);
+        smaller.extend_from_slice(&stmts[i + 1..]);
+        smaller
+    };
+    let mut out = (0..stmts.len())
+        .map(|i| with(i, vec![]))
+        .collect::<Vec<_>>();
+    for (i, stmt) in stmts.iter().enumerate() {
+        match &stmt.kind {
+            StmtKind::Assign(x, e) => out.extend(
+                shrink_expr(e)
+                    .into_iter()
+                    .map(|e| with(i, vec![StmtKind::Assign(*x, e).into()])),
+            ),
+            StmtKind::Print(e) => out.extend(
+                shrink_expr(e)
+                    .into_iter()
+                    .map(|e| with(i, vec![StmtKind::Print(e).into()])),
+            ),
+            StmtKind::Read(_) => {}
+            StmtKind::If { guard, tt, ff } => {
+                out.push(with(i, tt.clone()));
+                out.push(with(i, ff.clone()));
+                let rebuild = |guard: &Expr, tt: &[Stmt], ff: &[Stmt]| {
+                    with(
+                        i,
+                        vec![StmtKind::If {
+                            guard: guard.clone(),
+                            tt: tt.to_vec(),
+                            ff: ff.to_vec(),
+                        }
+                        .into()],
+                    )
+                };
+                out.extend(shrink_expr(guard).iter().map(|g| rebuild(g, tt, ff)));
+                out.extend(shrink_stmts(tt).iter().map(|tt| rebuild(guard, tt, ff)));
+                out.extend(shrink_stmts(ff).iter().map(|ff| rebuild(guard, tt, ff)));
+            }
+        }
+    }
+    out
+}
+
+/// The expression with one part replaced by a simpler one, each way there
+/// is.
+fn shrink_expr(e: &Expr) -> Vec<Expr> {
+    match e {
+        Expr::Var(_) => vec![Expr::Const(0)],
+        Expr::Const(0) => vec![],
+        Expr::Const(_) => vec![Expr::Const(0)],
+        Expr::BOp { op, lhs, rhs } => {
+            let mut out = vec![(**lhs).clone(), (**rhs).clone()];
+            out.extend(shrink_expr(lhs).into_iter().map(|lhs| Expr::BOp {
+                op: *op,
+                lhs: Box::new(lhs),
+                rhs: rhs.clone(),
+            }));
+            out.extend(shrink_expr(rhs).into_iter().map(|rhs| Expr::BOp {
+                op: *op,
+                lhs: lhs.clone(),
+                rhs: Box::new(rhs),
+            }));
+            out
+        }
+        Expr::Negate(inner) => vec![(**inner).clone()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::generate::random_program;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn random_programs_agree() {
+        for seed in 0..20 {
+            let program = random_program(seed, 12);
+            let input = random_input(seed, &program);
+            for level in 1..=3 {
+                let failure = check(&program, &input, OptLevel::new(level).unwrap());
+                assert!(failure.is_none(), "seed {seed} at -O{level}: {failure:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn shrinks_to_what_fails() {
+        let program = random_program(7, 12);
+        let divides = |e: &Expr| matches!(e, Expr::BOp { op: BOp::Div, .. });
+        fn any_expr(stmts: &[Stmt], f: &dyn Fn(&Expr) -> bool) -> bool {
+            stmts.iter().any(|stmt| match &stmt.kind {
+                StmtKind::Assign(_, e) | StmtKind::Print(e) => f(e),
+                StmtKind::Read(_) => false,
+                StmtKind::If { guard, tt, ff } => f(guard) || any_expr(tt, f) || any_expr(ff, f),
+            })
+        }
+        assert!(any_expr(&program.stmts, &divides));
+        let shrunk = shrink(program, |p| any_expr(&p.stmts, &divides));
+        assert_eq!(shrunk.to_string(), ":= b / 0 0\n");
+    }
+}
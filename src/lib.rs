@@ -7,5 +7,7 @@
 
 pub mod back;
 pub mod common;
+pub mod diagnostics;
 pub mod front;
+pub mod fuzz;
 pub mod middle;
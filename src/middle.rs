@@ -1,5 +1,30 @@
 pub mod tir;
 pub use tir::*;
 
-mod opt;
-pub use opt::optimize;
+pub mod alias;
+pub use alias::{Alias, AliasAnalysis};
+
+pub mod dom;
+pub use dom::DomTree;
+
+pub mod liveness;
+pub use liveness::Liveness;
+
+pub mod interp;
+
+pub mod loops;
+pub use loops::{Loop, LoopInfo};
+
+pub mod profile;
+pub use profile::Profile;
+
+pub mod range;
+pub use range::{Range, RangeAnalysis};
+
+pub mod ssa;
+
+pub mod verify;
+pub use verify::{verify, verify_ssa, VerifyError};
+
+pub mod opt;
+pub use opt::{optimize, optimize_with};
@@ -0,0 +1,169 @@
+//! Alias analysis for memory instructions.
+//!
+//! Two accesses alias if they may touch the same word.  Pointers only come
+//! from allocations, so we follow each address through copies to the value
+//! it came from, its base.  Accesses through the same base alias exactly if
+//! their offsets are equal, since accesses are aligned words.  Two different
+//! allocations never alias each other.  Any other base, like a parameter, a
+//! phi, or a loaded pointer, may point anywhere, including into an
+//! allocation of this function.
+//!
+//! The function has to be in SSA form, so that a variable holds the same
+//! address wherever it is used.
+
+use super::*;
+use crate::common::*;
+
+/// The word at `addr + offset`, which a load or a store accesses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    pub addr: ValueId,
+    pub offset: i64,
+}
+
+impl Location {
+    /// The word the given instruction accesses, if it is a load or a store.
+    pub fn of(insn: &Instruction) -> Option<Location> {
+        match insn {
+            Instruction::Load { addr, offset, .. } | Instruction::Store { addr, offset, .. } => {
+                Some(Location {
+                    addr: *addr,
+                    offset: *offset,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether two locations are the same word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alias {
+    No,
+    May,
+    Must,
+}
+
+/// The bases of the pointers of a function.
+#[derive(Debug)]
+pub struct AliasAnalysis {
+    /// The value each copied variable was copied from, transitively.
+    base: Map<ValueId, ValueId>,
+    /// The variables that hold the results of allocations.
+    allocs: Set<ValueId>,
+}
+
+impl AliasAnalysis {
+    /// Compute the bases for the given function.
+    pub fn new(func: &Function) -> Self {
+        let mut copies = Map::new();
+        let mut allocs = Set::new();
+        for insn in func.block.values().flat_map(|b| &b.insn) {
+            match insn {
+                Instruction::Copy { dst, src } => {
+                    copies.insert(*dst, *src);
+                }
+                Instruction::Alloc { dst, .. } => {
+                    allocs.insert(*dst);
+                }
+                _ => {}
+            }
+        }
+        let base = copies
+            .keys()
+            .map(|v| {
+                // Copies can't form a cycle in SSA form, since each definition
+                // dominates its uses, but don't rely on that.
+                let mut b = *v;
+                let mut seen = Set::new();
+                while let Some(src) = copies.get(&b).filter(|_| seen.insert(b)) {
+                    b = *src;
+                }
+                (*v, b)
+            })
+            .collect();
+        AliasAnalysis { base, allocs }
+    }
+
+    /// The value the given pointer was copied from.
+    pub fn base(&self, addr: ValueId) -> ValueId {
+        self.base.get(&addr).copied().unwrap_or(addr)
+    }
+
+    /// Does the given pointer come from an allocation of this function?
+    pub fn is_alloc(&self, addr: ValueId) -> bool {
+        self.allocs.contains(&self.base(addr))
+    }
+
+    /// Whether the two locations are the same word.
+    pub fn alias(&self, a: Location, b: Location) -> Alias {
+        let (x, y) = (self.base(a.addr), self.base(b.addr));
+        if x == y {
+            if a.offset == b.offset {
+                Alias::Must
+            } else {
+                Alias::No
+            }
+        } else if self.allocs.contains(&x) && self.allocs.contains(&y) {
+            Alias::No
+        } else {
+            Alias::May
+        }
+    }
+
+    /// May the two locations be the same word?
+    pub fn may_alias(&self, a: Location, b: Location) -> bool {
+        self.alias(a, b) != Alias::No
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_and_offsets() {
+        let mut b = Builder::new();
+        b.constant("n", 2);
+        b.alloc("p", "n");
+        b.alloc("q", "n");
+        b.copy("r", "p");
+        b.copy("s", "r");
+        b.load("x", "s", 8);
+        b.exit();
+        let f = b.finish_main();
+        let v = |s| f.var(s);
+        let at = |s, offset| Location { addr: v(s), offset };
+        let aa = AliasAnalysis::new(&f);
+        assert_eq!(aa.base(v("s")), v("p"));
+        assert!(aa.is_alloc(v("s")));
+        assert_eq!(aa.alias(at("p", 8), at("s", 8)), Alias::Must);
+        assert_eq!(aa.alias(at("p", 0), at("s", 8)), Alias::No);
+        assert_eq!(aa.alias(at("p", 0), at("q", 0)), Alias::No);
+        assert_eq!(aa.alias(at("r", 8), at("q", 8)), Alias::No);
+        let load = &f.block[&Function::entry()].insn[5];
+        assert_eq!(Location::of(load), Some(at("s", 8)));
+    }
+
+    #[test]
+    fn unknown_pointers() {
+        let mut b = Builder::new();
+        b.exit();
+        b.function("f", &["a"], Type::I64);
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.load("x", "p", 0);
+        b.ret("x");
+        let p = b.finish();
+        let f = &p.func[&Id::from_ref("f")];
+        let at = |s, offset| Location {
+            addr: f.var(s),
+            offset,
+        };
+        let aa = AliasAnalysis::new(f);
+        assert!(!aa.is_alloc(f.var("a")));
+        assert_eq!(aa.alias(at("a", 0), at("p", 0)), Alias::May);
+        assert_eq!(aa.alias(at("a", 0), at("a", 8)), Alias::No);
+        assert!(aa.may_alias(at("a", 8), at("p", 0)));
+    }
+}
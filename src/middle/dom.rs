@@ -0,0 +1,289 @@
+//! Dominator analysis.
+//!
+//! We use the iterative algorithm from Cooper, Harvey, and Kennedy, "A Simple,
+//! Fast Dominance Algorithm".  It is quadratic in the worst case, but our
+//! CFGs are small and it is much simpler than Lengauer-Tarjan.
+//!
+//! Only the blocks reachable from the entry block take part in the analysis.
+//! Unreachable blocks are not dominated by anything, and they don't dominate
+//! anything either.
+
+use super::*;
+use crate::common::*;
+
+/// The dominator tree and the dominance frontiers of a func.
+#[derive(Debug)]
+pub struct DomTree {
+    /// Reachable blocks in reverse postorder, starting with the entry block.
+    rpo: Vec<BlockId>,
+    /// Position of each reachable block in `rpo`.
+    order: Map<BlockId, usize>,
+    /// The immediate dominator of each reachable block except the entry.
+    idom: Map<BlockId, BlockId>,
+    /// The children of each reachable block in the dominator tree.
+    children: Map<BlockId, Vec<BlockId>>,
+    /// The dominance frontier of each reachable block.
+    frontier: Map<BlockId, Set<BlockId>>,
+}
+
+impl DomTree {
+    /// Compute the dominator tree of the given func.
+    pub fn new(func: &Function) -> Self {
+        let entry = Function::entry();
+        let rpo = reverse_postorder(func, entry);
+        let order: Map<BlockId, usize> = rpo.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+        let preds = func.predecessors();
+
+        // The working copy of the idom relation as indices into `rpo`.  The
+        // entry is its own idom here to make `intersect` terminate.
+        let mut doms: Vec<Option<usize>> = vec![None; rpo.len()];
+        if !rpo.is_empty() {
+            doms[0] = Some(0);
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, b) in rpo.iter().enumerate().skip(1) {
+                let mut new_idom = None;
+                for p in &preds[b] {
+                    let Some(&p) = order.get(p) else {
+                        continue;
+                    };
+                    if doms[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(other) => intersect(&doms, p, other),
+                    });
+                }
+                if doms[i] != new_idom {
+                    doms[i] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut idom = Map::new();
+        let mut children: Map<BlockId, Vec<BlockId>> = rpo.iter().map(|b| (*b, vec![])).collect();
+        for (i, b) in rpo.iter().enumerate().skip(1) {
+            let parent = rpo[doms[i].expect("reachable blocks should have an idom")];
+            idom.insert(*b, parent);
+            children.get_mut(&parent).unwrap().push(*b);
+        }
+
+        let mut frontier: Map<BlockId, Set<BlockId>> =
+            rpo.iter().map(|b| (*b, Set::new())).collect();
+        for b in &rpo {
+            let reachable_preds = preds[b]
+                .iter()
+                .filter(|p| order.contains_key(p))
+                .collect::<Vec<_>>();
+            if reachable_preds.len() < 2 {
+                continue;
+            }
+            for p in reachable_preds {
+                let mut runner = *p;
+                while Some(&runner) != idom.get(b) {
+                    frontier.get_mut(&runner).unwrap().insert(*b);
+                    match idom.get(&runner) {
+                        Some(up) => runner = *up,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        DomTree {
+            rpo,
+            order,
+            idom,
+            children,
+            frontier,
+        }
+    }
+
+    /// The immediate dominator of the given block.  This is `None` for the
+    /// entry block and for unreachable blocks.
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        self.idom.get(&block).copied()
+    }
+
+    /// Is the given block reachable from the entry block?
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.order.contains_key(&block)
+    }
+
+    /// Does `a` dominate `b`?  Every reachable block dominates itself.
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut cur = b;
+        loop {
+            if cur == a {
+                return true;
+            }
+            match self.idom(cur) {
+                Some(up) => cur = up,
+                None => return false,
+            }
+        }
+    }
+
+    /// Does `a` dominate `b`, and are they different blocks?
+    pub fn strictly_dominates(&self, a: BlockId, b: BlockId) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// The blocks immediately dominated by the given block.
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        self.children.get(&block).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// The dominance frontier of the given block: the blocks where the
+    /// dominance of this block ends.
+    pub fn frontier(&self, block: BlockId) -> Set<BlockId> {
+        self.frontier.get(&block).cloned().unwrap_or_default()
+    }
+
+    /// The reachable blocks in reverse postorder.  Every block comes after its
+    /// dominators.
+    pub fn reverse_postorder(&self) -> &[BlockId] {
+        &self.rpo
+    }
+
+    /// The reachable blocks in dominator tree preorder.
+    pub fn preorder(&self) -> Vec<BlockId> {
+        let mut result = vec![];
+        let mut stack = self.rpo.first().copied().into_iter().collect::<Vec<_>>();
+        while let Some(b) = stack.pop() {
+            result.push(b);
+            stack.extend(self.children(b).iter().rev());
+        }
+        result
+    }
+}
+
+/// Walk up the (partial) dominator tree from two blocks until they meet.
+fn intersect(doms: &[Option<usize>], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while a > b {
+            a = doms[a].unwrap();
+        }
+        while b > a {
+            b = doms[b].unwrap();
+        }
+    }
+    a
+}
+
+/// The blocks reachable from `start` in reverse postorder.
+pub fn reverse_postorder(func: &Function, start: BlockId) -> Vec<BlockId> {
+    let mut visited = Set::new();
+    let mut postorder = vec![];
+    if !func.block.contains_key(&start) {
+        return postorder;
+    }
+    // Each stack entry is a block and the successors we haven't visited yet.
+    let mut stack = vec![(start, func.block[&start].successors().into_iter())];
+    visited.insert(start);
+    while let Some((block, succs)) = stack.last_mut() {
+        match succs.next() {
+            Some(s) if func.block.contains_key(&s) && visited.insert(s) => {
+                let next = func.block[&s].successors().into_iter();
+                stack.push((s, next));
+            }
+            Some(_) => {}
+            None => {
+                postorder.push(*block);
+                stack.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: helpers
+
+    // Create the main function from a list of blocks and their successors.
+    // Blocks with two successors branch on `c`.
+    fn cfg(edges: &[(&str, &[&str])]) -> Function {
+        let mut b = Builder::new();
+        for (name, succs) in edges {
+            if *name == ENTRY {
+                b.switch_to(name);
+            } else {
+                b.block(name);
+            }
+            match succs {
+                [] => b.exit(),
+                [t] => b.jump(t),
+                [tt, ff] => b.branch("c", tt, ff),
+                _ => unreachable!(),
+            }
+        }
+        b.finish_main()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn diamond() {
+        let p = cfg(&[
+            ("$entry", &["a", "b"]),
+            ("a", &["join"]),
+            ("b", &["join"]),
+            ("join", &[]),
+        ]);
+        let id = |s: &str| p.block_named(s);
+        let dom = DomTree::new(&p);
+        assert_eq!(dom.idom(id("$entry")), None);
+        assert_eq!(dom.idom(id("a")), Some(id("$entry")));
+        assert_eq!(dom.idom(id("b")), Some(id("$entry")));
+        assert_eq!(dom.idom(id("join")), Some(id("$entry")));
+        assert!(dom.dominates(id("$entry"), id("join")));
+        assert!(!dom.dominates(id("a"), id("join")));
+        assert!(dom.dominates(id("a"), id("a")));
+        assert!(!dom.strictly_dominates(id("a"), id("a")));
+        assert_eq!(dom.frontier(id("a")), [id("join")].into());
+        assert_eq!(dom.frontier(id("b")), [id("join")].into());
+        assert_eq!(dom.frontier(id("$entry")), Set::new());
+        assert_eq!(dom.preorder()[0], id("$entry"));
+    }
+
+    #[test]
+    fn loop_frontier() {
+        let p = cfg(&[
+            ("$entry", &["head"]),
+            ("head", &["body", "exit"]),
+            ("body", &["head"]),
+            ("exit", &[]),
+        ]);
+        let id = |s: &str| p.block_named(s);
+        let dom = DomTree::new(&p);
+        assert_eq!(dom.idom(id("body")), Some(id("head")));
+        assert_eq!(dom.idom(id("exit")), Some(id("head")));
+        assert_eq!(dom.frontier(id("body")), [id("head")].into());
+        assert_eq!(dom.frontier(id("head")), [id("head")].into());
+        assert_eq!(dom.children(id("head")).len(), 2);
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let p = cfg(&[("$entry", &[]), ("dead", &["$entry"])]);
+        let id = |s: &str| p.block_named(s);
+        let dom = DomTree::new(&p);
+        assert!(!dom.is_reachable(id("dead")));
+        assert!(!dom.dominates(id("dead"), id("$entry")));
+        assert!(!dom.dominates(id("$entry"), id("dead")));
+        assert_eq!(dom.reverse_postorder(), &[id("$entry")]);
+        assert_eq!(dom.frontier(id("$entry")), Set::new());
+    }
+}
@@ -0,0 +1,540 @@
+//! The reference interpreter for tiny IR.
+//!
+//! This executes a program directly, following the semantics in `doc/ir.md`.
+//! Everything else in the compiler (the optimizer and the backends) is tested
+//! against it.
+//!
+//! The interpreter is a state machine that executes one instruction per
+//! `step`, keeping the call stack explicitly, so deep recursion in the
+//! interpreted program doesn't overflow the native stack.
+//!
+//! Heap addresses are plain numbers starting at `HEAP_BASE`.  Each allocation
+//! is remembered, and accessing memory outside of an allocation (which is
+//! undefined behavior in tiny IR) is reported as an error.
+
+use std::fmt::Debug;
+use std::io::{BufRead, Write};
+
+use derive_more::derive::Display;
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::profile::Profile;
+
+mod debug;
+pub use debug::{Breakpoint, Location, Stop};
+
+/// The address of the first word of heap memory.
+pub const HEAP_BASE: i64 = 0x1000_0000;
+
+/// The maximum number of nested calls before we report a stack overflow.
+pub const MAX_CALL_DEPTH: usize = 100_000;
+
+/// The maximum number of words on the heap, 128 MiB, before we report that
+/// the program is out of memory.
+pub const MAX_HEAP_WORDS: usize = 1 << 24;
+
+/// The values of the variables of a function, by name.
+pub type Env = Map<Id, i64>;
+
+#[derive(Display)]
+#[display("Runtime error: {}", self.0)]
+pub struct InterpError(String);
+
+impl Debug for InterpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+fn error<T>(msg: String) -> Result<T, InterpError> {
+    Err(InterpError(msg))
+}
+
+/// Run a program to completion, reading from `input` and writing to `output`.
+/// Returns the final values of the variables of `main`.
+pub fn run(program: &Program, input: impl BufRead, output: impl Write) -> Result<Env, InterpError> {
+    let mut interp = Interpreter::new(program, input, output)?;
+    while interp.step()? == Status::Running {}
+    Ok(interp.main_env())
+}
+
+/// Run a program to completion like `run`, and return its exit status.
+pub fn run_status(
+    program: &Program,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<i64, InterpError> {
+    let mut interp = Interpreter::new(program, input, output)?;
+    while interp.step()? == Status::Running {}
+    Ok(interp.exit_status())
+}
+
+/// Run an instrumented program to completion like `run`, and return its
+/// profile.
+pub fn run_profiled(
+    program: &Program,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<Profile, InterpError> {
+    let mut interp = Interpreter::new(program, input, output)?;
+    while interp.step()? == Status::Running {}
+    Ok(interp.profile())
+}
+
+/// Whether the program is still running after a step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Finished,
+}
+
+/// The state of a call.
+#[derive(Clone, Debug)]
+struct Frame {
+    func: Id,
+    block: BlockId,
+    /// The index of the next instruction in the block.  It is the length of
+    /// the block when the terminator is next.
+    pos: usize,
+    /// The values of the variables, indexed by `ValueId`.
+    vars: Vec<i64>,
+    /// Where to store the returned value in the caller's frame.
+    ret_dst: Option<ValueId>,
+}
+
+pub struct Interpreter<'a, R, W> {
+    program: &'a Program,
+    input: Input<R>,
+    output: W,
+    frames: Vec<Frame>,
+    heap: Vec<i64>,
+    /// The allocations as (first word, number of words), by first word.
+    allocations: Map<usize, usize>,
+    finished: bool,
+    /// The value `main` returns, or the status of `$exit`.
+    status: i64,
+    /// The breakpoints of the debugger.
+    breakpoints: Set<Breakpoint>,
+    /// Whether the last step entered a block.
+    entered: bool,
+    /// The variables the last step wrote to, with their functions.
+    writes: Vec<(Id, ValueId)>,
+    /// The profile counters of instrumented programs.
+    counters: Vec<u64>,
+}
+
+impl<'a, R: BufRead, W: Write> Interpreter<'a, R, W> {
+    /// Prepare to run the program from the start of `main`.
+    pub fn new(program: &'a Program, input: R, output: W) -> Result<Self, InterpError> {
+        let mut interp = Interpreter {
+            program,
+            input: Input::new(input),
+            output,
+            frames: vec![],
+            heap: vec![],
+            allocations: Map::new(),
+            finished: false,
+            status: 0,
+            breakpoints: Set::new(),
+            entered: false,
+            writes: vec![],
+            counters: vec![],
+        };
+        interp.enter(Program::main(), &[], None)?;
+        Ok(interp)
+    }
+
+    /// Has the program finished?
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The exit status of the program, which is 0 until it finishes.
+    pub fn exit_status(&self) -> i64 {
+        self.status
+    }
+
+    /// The final values of the variables of `main`, or their current values
+    /// if the program is still running.
+    pub fn main_env(&self) -> Env {
+        self.env_of(&self.frames[0])
+    }
+
+    /// The profile of the run so far, if the program is instrumented.
+    pub fn profile(&self) -> Profile {
+        Profile::from_counters(self.program, &self.counters)
+    }
+
+    /// Execute the next instruction or terminator.
+    pub fn step(&mut self) -> Result<Status, InterpError> {
+        if self.finished {
+            return Ok(Status::Finished);
+        }
+        self.entered = false;
+        self.writes.clear();
+        let program = self.program;
+        let frame = self.frames.last().unwrap();
+        let block = &program.func[&frame.func].block[&frame.block];
+        match block.insn.get(frame.pos) {
+            Some(insn) => self.execute(insn)?,
+            None => self.terminate(&block.term[0])?,
+        }
+        Ok(if self.finished {
+            Status::Finished
+        } else {
+            Status::Running
+        })
+    }
+
+    // SECTION: helpers
+
+    fn env_of(&self, frame: &Frame) -> Env {
+        let func = &self.program.func[&frame.func];
+        func.decl
+            .keys()
+            .map(|v| (func.names.value(*v), frame.vars[v.0 as usize]))
+            .collect()
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn get(&self, var: ValueId) -> i64 {
+        self.frames.last().unwrap().vars[var.0 as usize]
+    }
+
+    fn set(&mut self, var: ValueId, value: i64) {
+        let frame = self.frame();
+        frame.vars[var.0 as usize] = value;
+        let func = frame.func;
+        self.writes.push((func, var));
+    }
+
+    /// Start a call to the given function.
+    fn enter(
+        &mut self,
+        name: Id,
+        args: &[i64],
+        ret_dst: Option<ValueId>,
+    ) -> Result<(), InterpError> {
+        let Some(func) = self.program.func.get(&name) else {
+            return error(format!("There is no function named `{name}`."));
+        };
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return error(format!("Stack overflow when calling `{name}`."));
+        }
+        let mut vars = vec![0; func.names.num_values()];
+        for (param, arg) in func.params.iter().zip(args) {
+            vars[param.0 as usize] = *arg;
+        }
+        self.frames.push(Frame {
+            func: name,
+            block: Function::entry(),
+            pos: 0,
+            vars,
+            ret_dst,
+        });
+        self.entered = true;
+        Ok(())
+    }
+
+    /// Jump to a block of the current function, running its phis.
+    fn jump(&mut self, target: BlockId) {
+        let frame = self.frames.last().unwrap();
+        let func = &self.program.func[&frame.func];
+        let from = frame.block;
+        let phis = func.block[&target]
+            .insn
+            .iter()
+            .map_while(|insn| match insn {
+                Instruction::Phi { dst, args } => Some((*dst, args[&from])),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // All phis read their arguments before any of them is written.
+        let values = phis
+            .iter()
+            .map(|(_, src)| self.get(*src))
+            .collect::<Vec<_>>();
+        for ((dst, _), value) in phis.iter().zip(values) {
+            self.set(*dst, value);
+        }
+        let frame = self.frame();
+        frame.block = target;
+        frame.pos = phis.len();
+        self.entered = true;
+    }
+
+    fn execute(&mut self, insn: &Instruction) -> Result<(), InterpError> {
+        use Instruction::*;
+        match insn {
+            Copy { dst, src } => self.set(*dst, self.get(*src)),
+            Const { dst, src } => self.set(*dst, *src),
+            Arith { op, dst, lhs, rhs } => {
+                self.set(*dst, arith(*op, self.get(*lhs), self.get(*rhs)))
+            }
+            Shift {
+                op,
+                dst,
+                src,
+                amount,
+            } => self.set(*dst, shift(*op, self.get(*src), *amount)),
+            Read(dst) => {
+                let value = self.input.read().or_else(error)?;
+                self.set(*dst, value);
+            }
+            Print(src) => {
+                let value = self.get(*src);
+                writeln!(self.output, "{value}")
+                    .map_err(|e| InterpError(format!("Cannot write the output: {e}")))?;
+            }
+            Phi { .. } => unreachable!("phis are run when entering their block"),
+            Call { dst, callee, args } => {
+                let args = args.iter().map(|a| self.get(*a)).collect::<Vec<_>>();
+                self.frame().pos += 1;
+                return self.enter(*callee, &args, Some(*dst));
+            }
+            Alloc { dst, size } => {
+                let size = self.get(*size);
+                let Ok(words) = usize::try_from(size) else {
+                    return error(format!("Cannot allocate {size} words."));
+                };
+                let start = self.heap.len();
+                let Some(end) = start
+                    .checked_add(words)
+                    .filter(|end| *end <= MAX_HEAP_WORDS)
+                else {
+                    return error(format!("Out of memory when allocating {size} words."));
+                };
+                self.heap.resize(end, 0);
+                self.allocations.insert(start, words);
+                self.set(*dst, HEAP_BASE + 8 * start as i64);
+            }
+            Load { dst, addr, offset } => {
+                let word = self.word(self.get(*addr), *offset)?;
+                self.set(*dst, self.heap[word]);
+            }
+            Store { addr, offset, src } => {
+                let word = self.word(self.get(*addr), *offset)?;
+                self.heap[word] = self.get(*src);
+            }
+            Count(k) => {
+                let k = *k as usize;
+                if self.counters.len() <= k {
+                    self.counters.resize(k + 1, 0);
+                }
+                self.counters[k] += 1;
+            }
+            Loc(_) => {}
+        }
+        self.frame().pos += 1;
+        Ok(())
+    }
+
+    fn terminate(&mut self, term: &Terminator) -> Result<(), InterpError> {
+        match term {
+            Terminator::Exit(status) => {
+                self.status = status.map_or(0, |status| self.get(status));
+                self.finished = true;
+            }
+            Terminator::Jump(target) => self.jump(*target),
+            Terminator::Branch { guard, tt, ff } => {
+                let target = if self.get(*guard) != 0 { tt } else { ff };
+                self.jump(*target);
+            }
+            Terminator::Return(value) => {
+                let value = self.get(*value);
+                let frame = self.frames.pop().unwrap();
+                match frame.ret_dst {
+                    Some(dst) => self.set(dst, value),
+                    // Returning from `main` ends the program.
+                    None => {
+                        self.frames.push(frame);
+                        self.status = value;
+                        self.finished = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the heap word at `addr + offset`.
+    fn word(&self, addr: i64, offset: i64) -> Result<usize, InterpError> {
+        let bad = || error(format!("Invalid memory access at {addr} + {offset}."));
+        let byte = addr.wrapping_add(offset).wrapping_sub(HEAP_BASE);
+        if byte < 0 || byte % 8 != 0 {
+            return bad();
+        }
+        let word = (byte / 8) as usize;
+        match self.allocations.range(..=word).next_back() {
+            Some((start, len)) if word < start + len => Ok(word),
+            _ => bad(),
+        }
+    }
+}
+
+/// Apply a binary operator the way 64-bit RISC-V does.
+pub fn arith(op: BOp, lhs: i64, rhs: i64) -> i64 {
+    match op {
+        BOp::Add => lhs.wrapping_add(rhs),
+        BOp::Sub => lhs.wrapping_sub(rhs),
+        BOp::Mul => lhs.wrapping_mul(rhs),
+        BOp::Div if rhs == 0 => -1,
+        BOp::Div => lhs.wrapping_div(rhs),
+        BOp::Lt => (lhs < rhs) as i64,
+    }
+}
+
+/// Shift a value by less than 64 bits.
+pub fn shift(op: ShiftOp, value: i64, amount: u32) -> i64 {
+    match op {
+        ShiftOp::Left => value << amount,
+        ShiftOp::Arith => value >> amount,
+        ShiftOp::Logical => ((value as u64) >> amount) as i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: helpers
+
+    // Run a program and return the final environment of `main` and the output.
+    fn run_with(program: &Program, input: &str) -> Result<(Env, String), InterpError> {
+        let mut output = vec![];
+        let env = run(program, input.as_bytes(), &mut output)?;
+        Ok((env, String::from_utf8(output).unwrap()))
+    }
+
+    fn id(s: &str) -> Id {
+        Id::from_ref(s)
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn reads_numbers() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.read("y");
+        b.read("z");
+        b.print("z");
+        b.exit();
+        let p = b.finish();
+        assert_eq!(run_with(&p, " 1\n\n2   3 4\n").unwrap().1, "3\n");
+        let err = |input| run_with(&p, input).unwrap_err().to_string();
+        assert!(err("1 2").contains("Unexpected end of input."));
+        assert!(err("1 two 3").contains("The input `two` is not a number."));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(arith(BOp::Div, 7, 0), -1);
+        assert_eq!(arith(BOp::Div, i64::MIN, -1), i64::MIN);
+        assert_eq!(arith(BOp::Add, i64::MAX, 1), i64::MIN);
+        assert_eq!(arith(BOp::Lt, -1, 0), 1);
+        assert_eq!(arith(BOp::Div, -7, 2), -3);
+    }
+
+    #[test]
+    fn io_and_branches() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.read("y");
+        b.arith(BOp::Lt, "c", "x", "y");
+        b.branch("c", "less", "end");
+        b.block("less");
+        b.print("x");
+        b.jump("end");
+        b.block("end");
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+
+        let (env, out) = run_with(&p, "1\n 2").unwrap();
+        assert_eq!(out, "1\n2\n");
+        assert_eq!(env, [(id("x"), 1), (id("y"), 2), (id("c"), 1)].into());
+        let (_, out) = run_with(&p, "5 -3").unwrap();
+        assert_eq!(out, "-3\n");
+        assert!(run_with(&p, "5").is_err());
+        assert!(run_with(&p, "5 five").is_err());
+    }
+
+    #[test]
+    fn phis_read_simultaneously() {
+        // Swap a and b with phis.
+        let mut b = Builder::new();
+        b.constant("a", 1);
+        b.constant("b", 2);
+        b.jump("swap");
+        b.block("swap");
+        b.phi("a", &[(ENTRY, "b")]);
+        b.phi("b", &[(ENTRY, "a")]);
+        b.exit();
+        let (env, _) = run_with(&b.finish(), "").unwrap();
+        assert_eq!((env[&id("a")], env[&id("b")]), (2, 1));
+    }
+
+    #[test]
+    fn calls() {
+        // Recursive factorial.
+        let mut b = Builder::new();
+        b.read("n");
+        b.call("r", "fact", &["n"]);
+        b.print("r");
+        b.exit();
+        b.function("fact", &["n"], Type::I64);
+        b.constant("one", 1);
+        b.arith(BOp::Lt, "base", "n", "one");
+        b.branch("base", "zero", "rec");
+        b.block("zero");
+        b.ret("one");
+        b.block("rec");
+        b.arith(BOp::Sub, "m", "n", "one");
+        b.call("r", "fact", &["m"]);
+        b.arith(BOp::Mul, "r", "r", "n");
+        b.ret("r");
+        let p = b.finish();
+        verify(&p).unwrap();
+        let (env, out) = run_with(&p, "10").unwrap();
+        assert_eq!(out, "3628800\n");
+        assert_eq!(env[&id("n")], 10);
+    }
+
+    #[test]
+    fn memory() {
+        let mut b = Builder::new();
+        b.constant("n", 2);
+        b.alloc("p", "n");
+        b.store("p", 8, "n");
+        b.load("x", "p", 8);
+        b.load("y", "p", 0);
+        b.print("x");
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+        let (_, out) = run_with(&p, "").unwrap();
+        assert_eq!(out, "2\n0\n");
+
+        let mut b = Builder::new();
+        b.constant("n", 2);
+        b.alloc("p", "n");
+        b.load("x", "p", 16);
+        b.exit();
+        assert!(run_with(&b.finish(), "").is_err());
+
+        let mut b = Builder::new();
+        b.constant("small", 1);
+        b.alloc("p", "small");
+        b.constant("huge", i64::MAX);
+        b.alloc("q", "huge");
+        b.exit();
+        let err = run_with(&b.finish(), "").unwrap_err().to_string();
+        assert!(err.contains("Out of memory"), "{err}");
+    }
+}
@@ -0,0 +1,251 @@
+//! A stepping interface over the interpreter, for debuggers and visualizers.
+//!
+//! A frontend drives the interpreter with `step` (one instruction at a time)
+//! or `resume` (until a breakpoint or the end of the program), and inspects
+//! the state in between: the current location, the call stack, and the
+//! values of the variables.
+//!
+//! ```
+//! use smol::common::Id;
+//! use smol::middle::interp::*;
+//! use smol::middle::tir::*;
+//!
+//! let mut b = Builder::new();
+//! b.constant("x", 1);
+//! b.jump("end");
+//! b.block("end");
+//! b.print("x");
+//! b.exit();
+//! let program = b.finish();
+//!
+//! let mut output = vec![];
+//! let mut interp = Interpreter::new(&program, &b""[..], &mut output).unwrap();
+//! let end = Breakpoint::block(MAIN, "end");
+//! interp.add_breakpoint(end.clone());
+//! assert_eq!(interp.resume().unwrap(), Stop::Breakpoint(end));
+//! assert_eq!(interp.location().block, Id::from_ref("end"));
+//! assert_eq!(interp.env()[&Id::from_ref("x")], 1);
+//! assert_eq!(interp.current_text().unwrap(), "$print x");
+//! assert_eq!(interp.resume().unwrap(), Stop::Finished);
+//! ```
+
+use super::*;
+
+/// A place in the program: an instruction of a block in a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub func: Id,
+    pub block: Id,
+    /// The index of the instruction in the block.  This is the number of
+    /// instructions when the terminator is next.
+    pub index: usize,
+}
+
+/// A condition that pauses `resume`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Breakpoint {
+    /// Stop when control enters the block, after its phis ran.
+    Block { func: Id, block: Id },
+    /// Stop after the variable is written to.
+    Variable { func: Id, var: Id },
+}
+
+impl Breakpoint {
+    pub fn block(func: &str, block: &str) -> Self {
+        Breakpoint::Block {
+            func: Id::from_ref(func),
+            block: Id::from_ref(block),
+        }
+    }
+
+    pub fn variable(func: &str, var: &str) -> Self {
+        Breakpoint::Variable {
+            func: Id::from_ref(func),
+            var: Id::from_ref(var),
+        }
+    }
+}
+
+/// Why `resume` returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Finished,
+    Breakpoint(Breakpoint),
+}
+
+impl<R: BufRead, W: Write> Interpreter<'_, R, W> {
+    pub fn add_breakpoint(&mut self, bp: Breakpoint) {
+        self.breakpoints.insert(bp);
+    }
+
+    /// Remove a breakpoint.  Returns whether it was set.
+    pub fn remove_breakpoint(&mut self, bp: &Breakpoint) -> bool {
+        self.breakpoints.remove(bp)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// Run at least one step, then keep running until a breakpoint is hit or
+    /// the program finishes.
+    pub fn resume(&mut self) -> Result<Stop, InterpError> {
+        loop {
+            if self.step()? == Status::Finished {
+                return Ok(Stop::Finished);
+            }
+            if let Some(bp) = self.hit_breakpoint() {
+                return Ok(Stop::Breakpoint(bp));
+            }
+        }
+    }
+
+    /// The instruction or terminator that runs next.
+    pub fn location(&self) -> Location {
+        self.location_of(self.frames.last().unwrap())
+    }
+
+    /// The locations of all active calls, starting with `main`.  Callers are
+    /// at the instruction after their call.
+    pub fn backtrace(&self) -> Vec<Location> {
+        self.frames.iter().map(|f| self.location_of(f)).collect()
+    }
+
+    /// The values of the variables of the current function.
+    pub fn env(&self) -> Env {
+        self.env_of(self.frames.last().unwrap())
+    }
+
+    /// The instruction that runs next, or `None` if a terminator runs next.
+    pub fn current_insn(&self) -> Option<&Instruction> {
+        let frame = self.frames.last().unwrap();
+        let block = &self.program.func[&frame.func].block[&frame.block];
+        block.insn.get(frame.pos)
+    }
+
+    /// The instruction or terminator that runs next in the syntax of
+    /// `doc/ir.md`, or `None` if the program has finished.
+    pub fn current_text(&self) -> Option<String> {
+        if self.finished {
+            return None;
+        }
+        let frame = self.frames.last().unwrap();
+        let func = &self.program.func[&frame.func];
+        let block = &func.block[&frame.block];
+        Some(match block.insn.get(frame.pos) {
+            Some(insn) => insn.display(&func.names).to_string(),
+            None => block.term[0].display(&func.names).to_string(),
+        })
+    }
+
+    // SECTION: helpers
+
+    fn location_of(&self, frame: &Frame) -> Location {
+        let func = &self.program.func[&frame.func];
+        Location {
+            func: frame.func,
+            block: func.names.block(frame.block),
+            index: frame.pos,
+        }
+    }
+
+    /// The first breakpoint the last step triggered, if any.
+    fn hit_breakpoint(&self) -> Option<Breakpoint> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        if self.entered {
+            let loc = self.location();
+            let bp = Breakpoint::Block {
+                func: loc.func,
+                block: loc.block,
+            };
+            if self.breakpoints.contains(&bp) {
+                return Some(bp);
+            }
+        }
+        self.writes.iter().find_map(|(func, var)| {
+            let bp = Breakpoint::Variable {
+                func: *func,
+                var: self.program.func[func].names.value(*var),
+            };
+            self.breakpoints.contains(&bp).then_some(bp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(s: &str) -> Id {
+        Id::from_ref(s)
+    }
+
+    // main reads n, calls `inc`, and prints the result.
+    fn program() -> Program {
+        let mut b = Builder::new();
+        b.read("n");
+        b.call("r", "inc", &["n"]);
+        b.print("r");
+        b.exit();
+        b.function("inc", &["x"], Type::I64);
+        b.constant("one", 1);
+        b.arith(BOp::Add, "y", "x", "one");
+        b.ret("y");
+        b.finish()
+    }
+
+    #[test]
+    fn stepping() {
+        let p = program();
+        let mut out = vec![];
+        let mut interp = Interpreter::new(&p, &b"41"[..], &mut out).unwrap();
+        assert_eq!(interp.current_text().unwrap(), "$read n");
+        interp.step().unwrap();
+        assert_eq!(interp.env()[&id("n")], 41);
+        interp.step().unwrap();
+        let trace = interp.backtrace();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].index, 2);
+        assert_eq!(
+            trace[1],
+            Location {
+                func: id("inc"),
+                block: id(ENTRY),
+                index: 0
+            }
+        );
+        assert_eq!(interp.env()[&id("x")], 41);
+        assert!(interp.current_insn().is_some());
+        interp.step().unwrap();
+        interp.step().unwrap();
+        assert!(interp.current_insn().is_none());
+        assert_eq!(interp.current_text().unwrap(), "$return y");
+        interp.step().unwrap();
+        assert_eq!(interp.location().func, id(MAIN));
+        assert_eq!(interp.env()[&id("r")], 42);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let p = program();
+        let mut out = vec![];
+        let mut interp = Interpreter::new(&p, &b"1"[..], &mut out).unwrap();
+        let entry = Breakpoint::block("inc", ENTRY);
+        let y = Breakpoint::variable("inc", "y");
+        let r = Breakpoint::variable(MAIN, "r");
+        for bp in [&entry, &y, &r] {
+            interp.add_breakpoint(bp.clone());
+        }
+        assert_eq!(interp.resume().unwrap(), Stop::Breakpoint(entry.clone()));
+        assert_eq!(interp.resume().unwrap(), Stop::Breakpoint(y.clone()));
+        assert_eq!(interp.env()[&id("y")], 2);
+        assert!(interp.remove_breakpoint(&r));
+        assert!(!interp.remove_breakpoint(&r));
+        assert_eq!(interp.resume().unwrap(), Stop::Finished);
+        assert!(interp.current_text().is_none());
+        drop(interp);
+        assert_eq!(out, b"2\n");
+    }
+}
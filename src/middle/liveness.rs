@@ -0,0 +1,154 @@
+//! Liveness analysis over tiny IR variables.
+//!
+//! A variable is live at a point if its current value may be read later.  We
+//! compute the live variables at the start and the end of each block with the
+//! usual backwards fixed-point iteration.
+//!
+//! Phi arguments are read on the edge from the corresponding predecessor, so
+//! they are live at the end of that predecessor rather than at the start of
+//! the block containing the phi.
+
+use super::*;
+use crate::common::*;
+
+/// Live variables at block boundaries.
+#[derive(Debug)]
+pub struct Liveness {
+    pub live_in: Map<BlockId, Set<ValueId>>,
+    pub live_out: Map<BlockId, Set<ValueId>>,
+}
+
+impl Liveness {
+    /// Compute liveness for the given func.
+    pub fn new(func: &Function) -> Self {
+        let mut live_in: Map<BlockId, Set<ValueId>> =
+            func.block.keys().map(|b| (*b, Set::new())).collect();
+        let mut live_out = live_in.clone();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (name, block) in func.block.iter().rev() {
+                let mut out = Set::new();
+                for succ in block.successors() {
+                    let Some(succ_block) = func.block.get(&succ) else {
+                        continue;
+                    };
+                    out.extend(live_in[&succ].iter().copied());
+                    out.extend(phi_uses_from(succ_block, *name));
+                }
+
+                let live = live_before(block, out.clone());
+                if live != live_in[name] || out != live_out[name] {
+                    live_in.insert(*name, live);
+                    live_out.insert(*name, out);
+                    changed = true;
+                }
+            }
+        }
+
+        Liveness { live_in, live_out }
+    }
+
+    /// Is the variable live at the start of the block?
+    pub fn is_live_in(&self, block: BlockId, var: ValueId) -> bool {
+        self.live_in.get(&block).is_some_and(|s| s.contains(&var))
+    }
+
+    /// Is the variable live at the end of the block?
+    pub fn is_live_out(&self, block: BlockId, var: ValueId) -> bool {
+        self.live_out.get(&block).is_some_and(|s| s.contains(&var))
+    }
+
+    /// The variables live right after each instruction of the given block.
+    pub fn live_after_each(&self, func: &Function, block: BlockId) -> Vec<Set<ValueId>> {
+        let block_data = &func.block[&block];
+        let mut live = self.live_out[&block].clone();
+        for t in &block_data.term {
+            live.extend(t.uses());
+        }
+        let mut result = vec![Set::new(); block_data.insn.len()];
+        for (i, insn) in block_data.insn.iter().enumerate().rev() {
+            result[i] = live.clone();
+            transfer(insn, &mut live);
+        }
+        result
+    }
+}
+
+/// The values the phis in `block` read when control comes from `pred`.
+fn phi_uses_from(block: &Block, pred: BlockId) -> impl Iterator<Item = ValueId> + '_ {
+    block.insn.iter().filter_map(move |insn| match insn {
+        Instruction::Phi { args, .. } => args.get(&pred).copied(),
+        _ => None,
+    })
+}
+
+/// Propagate liveness backwards through one instruction.  Phi uses are
+/// handled on the incoming edges, so only their definitions matter here.
+fn transfer(insn: &Instruction, live: &mut Set<ValueId>) {
+    if let Some(dst) = insn.def() {
+        live.remove(&dst);
+    }
+    if !insn.is_phi() {
+        live.extend(insn.uses());
+    }
+}
+
+/// Compute the variables live at the start of a block given the variables
+/// live at its end.
+fn live_before(block: &Block, mut live: Set<ValueId>) -> Set<ValueId> {
+    for t in &block.term {
+        live.extend(t.uses());
+    }
+    for insn in block.insn.iter().rev() {
+        transfer(insn, &mut live);
+    }
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn straight_line() {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.copy("y", "z");
+        b.print("x");
+        b.exit();
+        let p = b.finish_main();
+        let (v, entry) = (|s| p.var(s), Function::entry());
+        let live = Liveness::new(&p);
+        assert_eq!(live.live_in[&entry], [v("z")].into());
+        assert_eq!(live.live_out[&entry], Set::new());
+        let after = live.live_after_each(&p, entry);
+        assert_eq!(after[0], [v("x"), v("z")].into());
+        assert_eq!(after[1], [v("x")].into());
+        assert_eq!(after[2], Set::new());
+    }
+
+    #[test]
+    fn across_blocks_and_phis() {
+        let mut b = Builder::new();
+        b.branch("c", "l", "r");
+        b.block("l");
+        b.jump("join");
+        b.block("r");
+        b.jump("join");
+        b.block("join");
+        b.phi("x", &[("l", "a"), ("r", "b")]);
+        b.print("x");
+        b.exit();
+        let p = b.finish_main();
+        let (v, id) = (|s| p.var(s), |s| p.block_named(s));
+        let live = Liveness::new(&p);
+        assert_eq!(live.live_out[&id("l")], [v("a")].into());
+        assert_eq!(live.live_out[&id("r")], [v("b")].into());
+        assert_eq!(live.live_in[&id("join")], Set::new());
+        assert_eq!(live.live_in[&id("$entry")], [v("a"), v("b"), v("c")].into());
+        assert!(live.is_live_in(id("l"), v("a")));
+        assert!(!live.is_live_in(id("l"), v("b")));
+    }
+}
@@ -0,0 +1,268 @@
+//! Natural loop analysis.
+//!
+//! A back edge is an edge `tail -> header` where the header dominates the
+//! tail.  The natural loop of a header is the header together with all blocks
+//! that can reach one of its back edges' tails without going through the
+//! header.  Back edges sharing a header are merged into a single loop.
+//!
+//! The analysis works on any CFG.  Whether a CFG may have cycles at all is
+//! up to the well-formedness constraints in `doc/ir.md`.
+
+use super::*;
+use crate::common::*;
+
+/// A natural loop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    /// The only block in the loop that is entered from outside.
+    pub header: BlockId,
+    /// All blocks in the loop, including the header and the blocks of nested
+    /// loops.
+    pub body: Set<BlockId>,
+    /// The sources of the back edges into the header.
+    pub latches: Set<BlockId>,
+    /// Edges `(from, to)` that leave the loop.
+    pub exits: Set<(BlockId, BlockId)>,
+    /// The index of the innermost loop containing this one.
+    pub parent: Option<usize>,
+    /// The indices of the loops immediately nested in this one.
+    pub children: Vec<usize>,
+    /// The nesting depth of this loop.  Outermost loops have depth 1.
+    pub depth: usize,
+}
+
+/// All natural loops in a program, arranged in a nesting forest.
+#[derive(Debug)]
+pub struct LoopInfo {
+    /// The loops, outer loops before inner ones.
+    loops: Vec<Loop>,
+    /// The innermost loop each block belongs to.
+    innermost: Map<BlockId, usize>,
+}
+
+impl LoopInfo {
+    /// Find the loops in the given func.
+    pub fn new(func: &Function, dom: &DomTree) -> Self {
+        let preds = func.predecessors();
+
+        // Collect the back edges grouped by header.
+        let mut latches: Map<BlockId, Set<BlockId>> = Map::new();
+        for tail in dom.reverse_postorder() {
+            for head in func.block[tail].successors() {
+                if dom.dominates(head, *tail) {
+                    latches.entry(head).or_default().insert(*tail);
+                }
+            }
+        }
+
+        let mut loops = latches
+            .into_iter()
+            .map(|(header, latches)| {
+                let body = loop_body(&preds, dom, header, &latches);
+                let exits = body
+                    .iter()
+                    .flat_map(|b| {
+                        func.block[b]
+                            .successors()
+                            .into_iter()
+                            .filter(|s| !body.contains(s))
+                            .map(|s| (*b, s))
+                    })
+                    .collect();
+                Loop {
+                    header,
+                    body,
+                    latches,
+                    exits,
+                    parent: None,
+                    children: vec![],
+                    depth: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Outer loops are strictly larger than the loops they contain, so
+        // sorting by size puts parents before their children.
+        loops.sort_by(|a, b| {
+            b.body
+                .len()
+                .cmp(&a.body.len())
+                .then(a.header.cmp(&b.header))
+        });
+
+        for i in 0..loops.len() {
+            // The parent is the smallest earlier loop that contains this one.
+            let parent = (0..i)
+                .rev()
+                .find(|j| loops[*j].body.contains(&loops[i].header));
+            loops[i].parent = parent;
+            loops[i].depth = parent.map(|p| loops[p].depth).unwrap_or(0) + 1;
+            if let Some(p) = parent {
+                loops[p].children.push(i);
+            }
+        }
+
+        let mut innermost = Map::new();
+        for (i, l) in loops.iter().enumerate() {
+            for b in &l.body {
+                // Later loops are nested deeper, so they win.
+                innermost.insert(*b, i);
+            }
+        }
+
+        LoopInfo { loops, innermost }
+    }
+
+    /// All loops, outer loops before inner ones.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// The outermost loops.
+    pub fn roots(&self) -> impl Iterator<Item = &Loop> {
+        self.loops.iter().filter(|l| l.parent.is_none())
+    }
+
+    /// The innermost loop containing the given block.
+    pub fn loop_of(&self, block: BlockId) -> Option<&Loop> {
+        self.innermost.get(&block).map(|i| &self.loops[*i])
+    }
+
+    /// The loop whose header is the given block.
+    pub fn loop_with_header(&self, header: BlockId) -> Option<&Loop> {
+        self.loops.iter().find(|l| l.header == header)
+    }
+
+    /// How many loops contain the given block.
+    pub fn depth(&self, block: BlockId) -> usize {
+        self.loop_of(block).map(|l| l.depth).unwrap_or(0)
+    }
+
+    /// Is the given block a loop header?
+    pub fn is_header(&self, block: BlockId) -> bool {
+        self.loop_with_header(block).is_some()
+    }
+}
+
+/// Compute the natural loop of a header and its latches by walking the
+/// predecessors backwards from the latches until the header.
+fn loop_body(
+    preds: &Map<BlockId, Set<BlockId>>,
+    dom: &DomTree,
+    header: BlockId,
+    latches: &Set<BlockId>,
+) -> Set<BlockId> {
+    let mut body = Set::from([header]);
+    let mut worklist = latches.iter().copied().collect::<Vec<_>>();
+    while let Some(b) = worklist.pop() {
+        if !dom.is_reachable(b) || !body.insert(b) {
+            continue;
+        }
+        worklist.extend(preds[&b].iter().copied());
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: helpers
+
+    // Create a program from a list of blocks and their successors.  Blocks
+    // with two successors branch on `c`.
+    fn cfg(edges: &[(&str, &[&str])]) -> Function {
+        let mut b = Builder::new();
+        for (name, succs) in edges {
+            if *name == ENTRY {
+                b.switch_to(name);
+            } else {
+                b.block(name);
+            }
+            match succs {
+                [] => b.exit(),
+                [t] => b.jump(t),
+                [tt, ff] => b.branch("c", tt, ff),
+                _ => unreachable!(),
+            }
+        }
+        b.finish_main()
+    }
+
+    fn analyze(p: &Function) -> LoopInfo {
+        LoopInfo::new(p, &DomTree::new(p))
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn acyclic() {
+        let p = cfg(&[("$entry", &["a", "b"]), ("a", &["b"]), ("b", &[])]);
+        let id = |s: &str| p.block_named(s);
+        let info = analyze(&p);
+        assert!(info.loops().is_empty());
+        assert_eq!(info.depth(id("a")), 0);
+    }
+
+    #[test]
+    fn single_loop() {
+        let p = cfg(&[
+            ("$entry", &["head"]),
+            ("head", &["body", "exit"]),
+            ("body", &["head"]),
+            ("exit", &[]),
+        ]);
+        let id = |s: &str| p.block_named(s);
+        let info = analyze(&p);
+        assert_eq!(info.loops().len(), 1);
+        let l = &info.loops()[0];
+        assert_eq!(l.header, id("head"));
+        assert_eq!(l.body, [id("head"), id("body")].into());
+        assert_eq!(l.latches, [id("body")].into());
+        assert_eq!(l.exits, [(id("head"), id("exit"))].into());
+        assert_eq!(info.depth(id("body")), 1);
+        assert!(info.is_header(id("head")));
+        assert!(!info.is_header(id("body")));
+    }
+
+    #[test]
+    fn nested_loops() {
+        let p = cfg(&[
+            ("$entry", &["outer"]),
+            ("outer", &["inner", "exit"]),
+            ("inner", &["inner_body", "outer_latch"]),
+            ("inner_body", &["inner"]),
+            ("outer_latch", &["outer"]),
+            ("exit", &[]),
+        ]);
+        let id = |s: &str| p.block_named(s);
+        let info = analyze(&p);
+        assert_eq!(info.loops().len(), 2);
+        assert_eq!(info.roots().count(), 1);
+        let outer = info.loop_with_header(id("outer")).unwrap();
+        let inner = info.loop_with_header(id("inner")).unwrap();
+        assert_eq!(outer.depth, 1);
+        assert_eq!(inner.depth, 2);
+        assert_eq!(outer.children, vec![1]);
+        assert_eq!(inner.parent, Some(0));
+        assert!(outer.body.is_superset(&inner.body));
+        assert_eq!(info.loop_of(id("inner_body")).unwrap().header, id("inner"));
+        assert_eq!(info.loop_of(id("outer_latch")).unwrap().header, id("outer"));
+        assert_eq!(info.loop_of(id("exit")), None);
+    }
+
+    #[test]
+    fn shared_header() {
+        let p = cfg(&[
+            ("$entry", &["head"]),
+            ("head", &["a", "b"]),
+            ("a", &["head"]),
+            ("b", &["head", "exit"]),
+            ("exit", &[]),
+        ]);
+        let id = |s: &str| p.block_named(s);
+        let info = analyze(&p);
+        assert_eq!(info.loops().len(), 1);
+        assert_eq!(info.loops()[0].latches, [id("a"), id("b")].into());
+    }
+}
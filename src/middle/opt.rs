@@ -1,7 +1,127 @@
 //! Optimizations
+//!
+//! Each pass is a function over programs.  The pass manager runs them as
+//! pipelines, either the predefined ones of the optimization levels or lists
+//! of pass names, and converts the program into SSA form and back as the
+//! passes need.
 
 use super::*;
+use crate::front::ast::BOp;
 
+mod branches;
+pub use branches::{simplify_branches, simplify_branches_function};
+
+mod copy_prop;
+pub use copy_prop::{copy_prop, copy_prop_function};
+
+mod dce;
+pub use dce::{dce, dce_function};
+
+mod dse;
+pub use dse::{dse, dse_function, DseStats};
+
+mod gvn;
+pub use gvn::{gvn, gvn_function, GvnStats};
+
+mod instrument;
+pub use instrument::{instrument, instrument_function};
+
+mod ivs;
+pub use ivs::{simplify_ivs, simplify_ivs_function};
+
+mod layout;
+pub use layout::{layout, layout_function};
+
+mod lvn;
+pub use lvn::{lvn, lvn_function, LvnStats};
+
+mod manager;
+pub use manager::{find_pass, Form, OptLevel, Pass, PassStats, Pipeline, PipelineStats, PASSES};
+
+mod peephole;
+pub use peephole::{apply_rules, default_rules, peephole, peephole_function, Rule};
+
+mod remarks;
+pub use remarks::{remarks_to_json, Remark};
+
+mod sccp;
+pub use sccp::{sccp, sccp_function};
+
+mod simplify_cfg;
+pub use simplify_cfg::{simplify_cfg, simplify_cfg_function};
+
+mod strength;
+pub use strength::{strength_reduce, strength_reduce_function};
+
+mod unreachable;
+pub use unreachable::{remove_unreachable, remove_unreachable_function};
+
+mod unroll;
+pub use unroll::{unroll, unroll_function, DEFAULT_UNROLL_THRESHOLD};
+
+/// Settings for the optimizer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// The most instructions an unrolled loop may have.  Zero turns unrolling
+    /// off.
+    pub unroll_threshold: usize,
+    /// Verify the program after every pass, and panic naming the pass that
+    /// broke it.  This is on by default in builds with debug assertions.
+    pub verify_each: bool,
+    /// The profile that guides block layout, if there is one.
+    pub profile: Option<Profile>,
+    /// The errors in arithmetic that the code reports, which the passes
+    /// mustn't fold away.
+    pub traps: Traps,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            unroll_threshold: DEFAULT_UNROLL_THRESHOLD,
+            verify_each: cfg!(debug_assertions),
+            profile: None,
+            traps: Traps::default(),
+        }
+    }
+}
+
+/// The errors in arithmetic that end the program, like the code of
+/// `--trap-div-zero` and `--trap-overflow` does.  By default there are none,
+/// and arithmetic always gives a value, like the interpreter's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traps {
+    /// Dividing by zero is an error, instead of giving -1.
+    pub div_zero: bool,
+    /// Additions, subtractions, and multiplications that overflow are
+    /// errors, instead of wrapping around.  Passes that only work because
+    /// arithmetic wraps around leave such operations alone.
+    pub overflow: bool,
+}
+
+impl Traps {
+    /// The result of an operation on constants, or `None` if it is an error,
+    /// which only the program can report.
+    pub fn fold(self, op: BOp, lhs: i64, rhs: i64) -> Option<i64> {
+        match op {
+            BOp::Div if rhs == 0 && self.div_zero => None,
+            BOp::Add if self.overflow => lhs.checked_add(rhs),
+            BOp::Sub if self.overflow => lhs.checked_sub(rhs),
+            BOp::Mul if self.overflow => lhs.checked_mul(rhs),
+            _ => Some(interp::arith(op, lhs, rhs)),
+        }
+    }
+}
+
+/// Optimize a program with the default settings.
 pub fn optimize(program: Program) -> Program {
-    program
+    optimize_with(program, &Options::default())
 }
+
+/// Optimize a program with the given settings, using all optimizations.
+pub fn optimize_with(program: Program, options: &Options) -> Program {
+    Pipeline::for_level(OptLevel::O2).run(program, options)
+}
+
+#[cfg(test)]
+mod tests;
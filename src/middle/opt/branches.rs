@@ -0,0 +1,307 @@
+//! Branch simplification.
+//!
+//! smol only has `<` to compute conditions, so lowering boolean expressions
+//! produces comparisons of comparisons, like `< (< a b) 1` for "not `a < b`".
+//! This pass looks through those patterns to the condition they test, and
+//! branches on it directly, swapping the targets if the pattern negates it.
+//!
+//! It also knows the conditions that hold because of earlier branches: a
+//! block that can only be entered through the true edge of a branch on `c`,
+//! and the blocks it dominates, know that `c` is nonzero.  Branches whose
+//! guard is known, constant, or irrelevant because both targets are the same
+//! become jumps.
+//!
+//! The function has to be in SSA form.
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::dom::DomTree;
+
+/// What we know about a guard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    /// The guard is always nonzero, or always zero.
+    Const(bool),
+    /// The guard is nonzero exactly when the variable is, or exactly when it
+    /// is zero if the flag is false.
+    Var(ValueId, bool),
+}
+
+/// Simplify the branches of every function of an SSA program.
+pub fn simplify_branches(program: Program) -> Program {
+    program.map_functions(simplify_branches_function)
+}
+
+/// Simplify the branches of a function in SSA form.
+pub fn simplify_branches_function(mut func: Function) -> Function {
+    let dom = DomTree::new(&func);
+    let preds = func.predecessors();
+    let mut def = vec![None; func.names.num_values()];
+    for insn in func.block.values().flat_map(|b| &b.insn) {
+        if let Some(dst) = insn.def() {
+            def[dst.0 as usize] = Some(insn.clone());
+        }
+    }
+    let mut s = Simplifier {
+        def,
+        known: Map::new(),
+    };
+
+    let mut work = vec![Walk::Enter(Function::entry(), None)];
+    while let Some(step) = work.pop() {
+        match step {
+            Walk::Enter(name, fact) => {
+                if let Some((var, value)) = fact {
+                    work.push(Walk::Leave(var, s.known.insert(var, value)));
+                }
+                let block = func.block.get_mut(&name).unwrap();
+                for t in &mut block.term {
+                    s.simplify(t);
+                }
+                for child in dom.children(name) {
+                    // Only a block that has no other way in knows which way
+                    // the branch went.
+                    let fact = match block.term[..] {
+                        [Terminator::Branch { guard, tt, ff }]
+                            if tt != ff && preds[child].len() == 1 =>
+                        {
+                            (*child == tt)
+                                .then_some((guard, true))
+                                .or((*child == ff).then_some((guard, false)))
+                        }
+                        _ => None,
+                    };
+                    work.push(Walk::Enter(*child, fact));
+                }
+            }
+            Walk::Leave(var, Some(value)) => {
+                s.known.insert(var, value);
+            }
+            Walk::Leave(var, None) => {
+                s.known.remove(&var);
+            }
+        }
+    }
+
+    // Drop the phi arguments from edges that are gone.
+    let preds = func.predecessors();
+    for (name, block) in &mut func.block {
+        for insn in &mut block.insn {
+            if let Instruction::Phi { args, .. } = insn {
+                args.retain(|pred, _| preds[name].contains(pred));
+            }
+        }
+    }
+    remove_unreachable_function(func)
+}
+
+/// A step of the dominator tree walk.
+enum Walk {
+    /// Simplify the block's branch and visit its children, knowing the value
+    /// of a condition in the block and the blocks it dominates.
+    Enter(BlockId, Option<(ValueId, bool)>),
+    /// Restore what we knew about a condition before entering a block.
+    Leave(ValueId, Option<bool>),
+}
+
+struct Simplifier {
+    /// The instruction defining each variable.
+    def: Vec<Option<Instruction>>,
+    /// Whether conditions are nonzero where we are in the walk.
+    known: Map<ValueId, bool>,
+}
+
+impl Simplifier {
+    fn simplify(&self, t: &mut Terminator) {
+        let Terminator::Branch { guard, tt, ff } = *t else {
+            return;
+        };
+        *t = match self.cond(guard) {
+            _ if tt == ff => Terminator::Jump(tt),
+            Cond::Const(true) => Terminator::Jump(tt),
+            Cond::Const(false) => Terminator::Jump(ff),
+            Cond::Var(guard, true) => Terminator::Branch { guard, tt, ff },
+            Cond::Var(guard, false) => Terminator::Branch {
+                guard,
+                tt: ff,
+                ff: tt,
+            },
+        };
+    }
+
+    /// Look through negations and copies to the condition a guard tests.
+    fn cond(&self, guard: ValueId) -> Cond {
+        let (mut var, mut positive) = (guard, true);
+        loop {
+            if let Some(value) = self.known.get(&var) {
+                return Cond::Const(*value == positive);
+            }
+            // `var` is always nonzero or always zero.
+            let always = |nonzero: bool| Cond::Const(nonzero == positive);
+            match self.def[var.0 as usize] {
+                Some(Instruction::Const { src, .. }) => return always(src != 0),
+                Some(Instruction::Copy { src, .. }) => var = src,
+                Some(Instruction::Arith { op, lhs, rhs, .. }) => {
+                    match (op, self.constant(lhs), self.constant(rhs)) {
+                        // `c < k` for a boolean `c`
+                        (BOp::Lt, _, Some(k)) if self.is_bool(lhs) => match k {
+                            ..=0 => return always(false),
+                            1 => (var, positive) = (lhs, !positive),
+                            _ => return always(true),
+                        },
+                        // `k < c` for a boolean `c`
+                        (BOp::Lt, Some(k), _) if self.is_bool(rhs) => match k {
+                            ..=-1 => return always(true),
+                            0 => var = rhs,
+                            _ => return always(false),
+                        },
+                        // `1 - c` for a boolean `c`
+                        (BOp::Sub, Some(1), _) if self.is_bool(rhs) => {
+                            (var, positive) = (rhs, !positive)
+                        }
+                        // `c - 0` and `c + 0`
+                        (BOp::Sub | BOp::Add, _, Some(0)) => var = lhs,
+                        (BOp::Add, Some(0), _) => var = rhs,
+                        _ => return Cond::Var(var, positive),
+                    }
+                }
+                _ => return Cond::Var(var, positive),
+            }
+        }
+    }
+
+    fn constant(&self, var: ValueId) -> Option<i64> {
+        match self.def[var.0 as usize] {
+            Some(Instruction::Const { src, .. }) => Some(src),
+            _ => None,
+        }
+    }
+
+    /// Is the variable always zero or one?
+    fn is_bool(&self, var: ValueId) -> bool {
+        matches!(
+            self.def[var.0 as usize],
+            Some(Instruction::Arith { op: BOp::Lt, .. } | Instruction::Const { src: 0 | 1, .. })
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::interp;
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn negations() {
+        // $if < (< a b) 1 { $print a } { $print b }
+        let mut b = Builder::new();
+        b.read("a");
+        b.read("b");
+        b.constant("one", 1);
+        b.arith(BOp::Lt, "c", "a", "b");
+        b.arith(BOp::Lt, "not", "c", "one");
+        b.branch("not", "then", "else");
+        b.block("then");
+        b.print("a");
+        b.exit();
+        b.block("else");
+        b.print("b");
+        b.exit();
+        let p = b.finish();
+        let q = simplify_branches(p.clone());
+        verify_ssa(&q).unwrap();
+        let main = &q.func[&Program::main()];
+        assert_eq!(
+            main.block[&Function::entry()].term,
+            vec![Terminator::Branch {
+                guard: main.var("c"),
+                tt: main.block_named("else"),
+                ff: main.block_named("then")
+            }]
+        );
+        for input in ["1 2", "2 1", "3 3"] {
+            assert_eq!(output(&p, input), output(&q, input));
+        }
+    }
+
+    #[test]
+    fn known_conditions() {
+        // The inner branch tests the negation of the outer guard, which is
+        // known to be nonzero there.
+        let mut b = Builder::new();
+        b.read("a");
+        b.constant("zero", 0);
+        b.constant("one", 1);
+        b.arith(BOp::Lt, "c", "zero", "a");
+        b.branch("c", "pos", "rest");
+        b.block("pos");
+        b.arith(BOp::Sub, "not", "one", "c");
+        b.branch("not", "never", "rest");
+        b.block("never");
+        b.print("zero");
+        b.jump("rest");
+        b.block("rest");
+        b.print("a");
+        b.exit();
+        let p = b.finish();
+        let q = simplify_branches(p.clone());
+        verify_ssa(&q).unwrap();
+        let main = &q.func[&Program::main()];
+        assert_eq!(main.names.find_block("never"), None);
+        assert_eq!(
+            main.block[&main.block_named("pos")].term,
+            vec![Terminator::Jump(main.block_named("rest"))]
+        );
+        for input in ["-1", "0", "5"] {
+            assert_eq!(output(&p, input), output(&q, input));
+        }
+    }
+
+    #[test]
+    fn shared_targets_know_nothing() {
+        // `join` can be entered both ways, so it can't assume `c`.
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "l", "join");
+        b.block("l");
+        b.jump("join");
+        b.block("join");
+        b.branch("c", "x", "y");
+        b.block("x");
+        b.exit();
+        b.block("y");
+        b.exit();
+        let p = b.finish();
+        assert_eq!(simplify_branches(p.clone()), p);
+    }
+
+    #[test]
+    fn constant_guards() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.constant("two", 2);
+        b.arith(BOp::Lt, "c", "a", "a");
+        // A boolean is always less than two.
+        b.arith(BOp::Lt, "g", "c", "two");
+        b.branch("g", "yes", "no");
+        b.block("yes");
+        b.exit();
+        b.block("no");
+        b.phi("x", &[(ENTRY, "a")]);
+        b.print("x");
+        b.exit();
+        let main = simplify_branches_function(b.finish_main());
+        assert_eq!(main.block.len(), 2);
+        assert_eq!(
+            main.block[&Function::entry()].term,
+            vec![Terminator::Jump(main.block_named("yes"))]
+        );
+    }
+}
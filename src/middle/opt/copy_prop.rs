@@ -0,0 +1,149 @@
+//! Copy propagation.
+//!
+//! In SSA form, a copy `$copy x y` means that `x` and `y` hold the same value
+//! everywhere `x` is available, so every use of `x` can read `y` instead and
+//! the copy goes away.  The same holds for phis whose arguments are all the
+//! same variable (ignoring the phi itself), which SCCP leaves behind when it
+//! removes edges.
+//!
+//! The function has to be in SSA form.
+
+use super::*;
+use crate::common::*;
+
+/// Run copy propagation on every function of an SSA program.
+pub fn copy_prop(program: Program) -> Program {
+    program.map_functions(copy_prop_function)
+}
+
+/// Run copy propagation on a function in SSA form.
+pub fn copy_prop_function(mut func: Function) -> Function {
+    // The variable each copied variable is replaced with.
+    let mut repl: Vec<Option<ValueId>> = vec![None; func.names.num_values()];
+
+    for insn in func.block.values().flat_map(|b| &b.insn) {
+        if let Instruction::Copy { dst, src } = insn {
+            repl[dst.0 as usize] = Some(*src);
+        }
+    }
+    // Replacing variables can make more phis trivial, so repeat until
+    // nothing changes.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for insn in func.block.values().flat_map(|b| &b.insn) {
+            let Instruction::Phi { dst, args } = insn else {
+                continue;
+            };
+            if repl[dst.0 as usize].is_some() {
+                continue;
+            }
+            let mut sources = args
+                .values()
+                .map(|v| find(&repl, *v))
+                .filter(|v| v != dst)
+                .collect::<Set<_>>()
+                .into_iter();
+            if let (Some(only), None) = (sources.next(), sources.next()) {
+                repl[dst.0 as usize] = Some(only);
+                changed = true;
+            }
+        }
+    }
+
+    for block in func.block.values_mut() {
+        block
+            .insn
+            .retain(|insn| insn.def().is_none_or(|dst| repl[dst.0 as usize].is_none()));
+        for insn in &mut block.insn {
+            insn.rename_uses(|v| find(&repl, v));
+        }
+        for t in &mut block.term {
+            t.rename_uses(|v| find(&repl, v));
+        }
+    }
+    func
+}
+
+/// Follow the replacements of a variable to the end.
+fn find(repl: &[Option<ValueId>], mut v: ValueId) -> ValueId {
+    while let Some(next) = repl[v.0 as usize] {
+        v = next;
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn chains() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.copy("b", "a");
+        b.copy("c", "b");
+        b.arith(BOp::Add, "d", "c", "b");
+        b.print("d");
+        b.exit();
+        let main = copy_prop_function(b.finish_main());
+        let (a, d) = (main.var("a"), main.var("d"));
+        assert_eq!(
+            main.block[&Function::entry()].insn,
+            vec![
+                Instruction::Read(a),
+                Instruction::Arith {
+                    op: BOp::Add,
+                    dst: d,
+                    lhs: a,
+                    rhs: a
+                },
+                Instruction::Print(d),
+            ]
+        );
+    }
+
+    #[test]
+    fn trivial_phis() {
+        // Both arguments of `x` are copies of `a`, and then `y` merges `x` and
+        // `a`, so both are `a`.
+        let mut b = Builder::new();
+        b.read("a");
+        b.copy("b", "a");
+        b.branch("a", "l", "j1");
+        b.block("l");
+        b.jump("j1");
+        b.block("j1");
+        b.phi("x", &[(ENTRY, "a"), ("l", "b")]);
+        b.branch("a", "m", "j2");
+        b.block("m");
+        b.jump("j2");
+        b.block("j2");
+        b.phi("y", &[("j1", "x"), ("m", "a")]);
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+        verify_ssa(&p).unwrap();
+        let main = copy_prop_function(p.func[&Program::main()].clone());
+        assert!(main.block[&main.block_named("j1")].insn.is_empty());
+        let j2 = &main.block[&main.block_named("j2")];
+        assert_eq!(j2.insn, vec![Instruction::Print(main.var("a"))]);
+    }
+
+    #[test]
+    fn keeps_real_phis() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.read("b");
+        b.branch("a", "l", "join");
+        b.block("l");
+        b.jump("join");
+        b.block("join");
+        b.phi("x", &[(ENTRY, "a"), ("l", "b")]);
+        b.print("x");
+        b.exit();
+        let main = b.finish_main();
+        assert_eq!(copy_prop_function(main.clone()), main);
+    }
+}
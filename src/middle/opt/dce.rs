@@ -0,0 +1,133 @@
+//! Dead code elimination.
+//!
+//! An instruction is dead if the variable it writes is not live afterwards and
+//! it has no other effect.  Removing a dead instruction can make the
+//! instructions computing its operands dead too, so we repeat until nothing
+//! changes.  This works both in and out of SSA form.
+//!
+//! Then the declarations of the variables that no instruction uses anymore
+//! are removed, so that backends don't make room for them.
+
+use super::*;
+
+/// Remove dead instructions from every function.
+pub fn dce(program: Program) -> Program {
+    program.map_functions(dce_function)
+}
+
+/// Remove dead instructions from a function.
+pub fn dce_function(mut func: Function) -> Function {
+    loop {
+        let liveness = Liveness::new(&func);
+        let mut removed = false;
+        let names = func.block.keys().copied().collect::<Vec<_>>();
+        for name in names {
+            let live_after = liveness.live_after_each(&func, name);
+            let block = func.block.get_mut(&name).unwrap();
+            let mut i = 0;
+            block.insn.retain(|insn| {
+                let dead = !insn.has_side_effects()
+                    && insn.def().is_some_and(|dst| !live_after[i].contains(&dst));
+                i += 1;
+                removed |= dead;
+                !dead
+            });
+        }
+        if !removed {
+            func.prune_vars();
+            return func;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn removes_unused_chains() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.constant("one", 1);
+        b.arith(BOp::Add, "b", "a", "one");
+        b.arith(BOp::Mul, "c", "b", "b");
+        b.read("unused");
+        b.print("a");
+        b.exit();
+        let main = dce_function(b.finish_main());
+        let (a, unused) = (main.var("a"), main.var("unused"));
+        // Reads consume input, so they stay even if their result is unused.
+        assert_eq!(
+            main.block[&Function::entry()].insn,
+            vec![
+                Instruction::Read(a),
+                Instruction::Read(unused),
+                Instruction::Print(a)
+            ]
+        );
+    }
+
+    #[test]
+    fn across_blocks() {
+        // `x` is only used in one successor, and `y` is overwritten before
+        // any use.
+        let mut b = Builder::new();
+        b.read("c");
+        b.constant("x", 1);
+        b.constant("y", 2);
+        b.branch("c", "l", "r");
+        b.block("l");
+        b.print("x");
+        b.exit();
+        b.block("r");
+        b.constant("y", 3);
+        b.print("y");
+        b.exit();
+        let main = dce_function(b.finish_main());
+        let entry = &main.block[&Function::entry()];
+        assert_eq!(entry.insn.len(), 2);
+        assert!(!entry.insn.iter().any(|i| i.def() == Some(main.var("y"))));
+    }
+
+    #[test]
+    fn removes_unused_declarations() {
+        let mut b = Builder::new();
+        b.declare("never", Type::I64);
+        b.constant("x", 1);
+        b.constant("y", 2);
+        b.print("y");
+        b.exit();
+        let main = dce_function(b.finish_main());
+        assert_eq!(main.decl.len(), 1);
+        assert_eq!(main.names.num_values(), 1);
+        assert_eq!(main.names.find_value("x"), None);
+        let y = main.var("y");
+        assert_eq!(
+            main.block[&Function::entry()].insn,
+            vec![Instruction::Const { dst: y, src: 2 }, Instruction::Print(y)]
+        );
+    }
+
+    #[test]
+    fn keeps_effects() {
+        let mut b = Builder::new();
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.store("p", 0, "n");
+        b.call("r", "f", &[]);
+        b.load("unused", "p", 0);
+        b.exit();
+        b.function("f", &[], Type::I64);
+        b.constant("z", 0);
+        b.ret("z");
+        let p = dce(b.finish());
+        let entry = &p.func[&Program::main()].block[&Function::entry()];
+        // Only the load goes away: the store needs `p` and `n`.
+        assert_eq!(entry.insn.len(), 4);
+        assert!(!entry
+            .insn
+            .iter()
+            .any(|i| matches!(i, Instruction::Load { .. })));
+    }
+}
@@ -0,0 +1,203 @@
+//! Dead store and redundant load elimination.
+//!
+//! A store is dead if the same word is stored to again before anything may
+//! load it, so we walk each block backwards and remember the words that are
+//! about to be overwritten.  A load is redundant if we already know the word
+//! it reads, because an earlier load read it or an earlier store wrote it, so
+//! we walk each block forwards and remember the known words.  The redundant
+//! load becomes a copy of the known value, which copy propagation removes.
+//!
+//! Both walks use the alias analysis: a store only kills a store to a word
+//! that must be the same, while a load or store that may touch a word makes
+//! us forget about it.  Calls may access any memory, so we forget everything
+//! at a call.  This only looks within blocks, and the function has to be in
+//! SSA form.
+
+use derive_more::{Add, AddAssign};
+
+use super::*;
+use crate::common::*;
+use crate::middle::alias::{Alias, AliasAnalysis, Location};
+
+/// What dead store elimination did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Add, AddAssign)]
+pub struct DseStats {
+    /// The number of stores that were removed because the word is stored to
+    /// again before any load.
+    pub dead_stores: usize,
+    /// The number of loads that were replaced with copies of a known value.
+    pub redundant_loads: usize,
+}
+
+/// Remove dead stores and redundant loads from every function of an SSA
+/// program.
+pub fn dse(program: Program) -> (Program, DseStats) {
+    let mut stats = DseStats::default();
+    let program = program.map_functions(|func| {
+        let (func, s) = dse_function(func);
+        stats += s;
+        func
+    });
+    (program, stats)
+}
+
+/// Remove dead stores and redundant loads from a function in SSA form.
+pub fn dse_function(mut func: Function) -> (Function, DseStats) {
+    let aa = AliasAnalysis::new(&func);
+    let mut stats = DseStats::default();
+    for block in func.block.values_mut() {
+        stats.dead_stores += remove_dead_stores(block, &aa);
+        stats.redundant_loads += forward_loads(block, &aa, &func.decl);
+    }
+    (func, stats)
+}
+
+/// Remove the stores of the block that are overwritten before any load, and
+/// return how many there were.
+fn remove_dead_stores(block: &mut Block, aa: &AliasAnalysis) -> usize {
+    // The words that are stored to later, with no possible load in between.
+    let mut overwritten: Vec<Location> = vec![];
+    let mut dead = vec![false; block.insn.len()];
+    for (i, insn) in block.insn.iter().enumerate().rev() {
+        match (insn, Location::of(insn)) {
+            (Instruction::Store { .. }, Some(loc)) => {
+                if overwritten.iter().any(|l| aa.alias(*l, loc) == Alias::Must) {
+                    dead[i] = true;
+                } else {
+                    overwritten.push(loc);
+                }
+            }
+            (Instruction::Load { .. }, Some(loc)) => {
+                overwritten.retain(|l| !aa.may_alias(*l, loc));
+            }
+            (Instruction::Call { .. }, _) => overwritten.clear(),
+            _ => {}
+        }
+    }
+    let mut i = 0;
+    block.insn.retain(|_| {
+        i += 1;
+        !dead[i - 1]
+    });
+    dead.iter().filter(|d| **d).count()
+}
+
+/// Replace the loads of the block whose word is already known with copies,
+/// and return how many there were.  The copy has to have operands of the
+/// same type, so a word loaded with another type than it was stored with
+/// stays a load.
+fn forward_loads(block: &mut Block, aa: &AliasAnalysis, decl: &Map<ValueId, Type>) -> usize {
+    // The words whose values we know, and the variables holding them.
+    let mut known: Vec<(Location, ValueId)> = vec![];
+    let mut replaced = 0;
+    for insn in &mut block.insn {
+        match *insn {
+            Instruction::Load { dst, .. } => {
+                let loc = Location::of(insn).unwrap();
+                let same = |(l, v): &&(Location, ValueId)| {
+                    aa.alias(*l, loc) == Alias::Must && decl.get(v) == decl.get(&dst)
+                };
+                match known.iter().find(same) {
+                    Some((_, src)) => {
+                        *insn = Instruction::Copy { dst, src: *src };
+                        replaced += 1;
+                    }
+                    None => known.push((loc, dst)),
+                }
+            }
+            Instruction::Store { src, .. } => {
+                let loc = Location::of(insn).unwrap();
+                known.retain(|(l, _)| !aa.may_alias(*l, loc));
+                known.push((loc, src));
+            }
+            Instruction::Call { .. } => known.clear(),
+            _ => {}
+        }
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+    use crate::middle::interp;
+
+    fn run(p: &Program) -> Vec<u8> {
+        let mut out = vec![];
+        interp::run(p, "5".as_bytes(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn overwritten_stores() {
+        let mut b = Builder::new();
+        b.constant("n", 2);
+        b.alloc("p", "n");
+        b.alloc("q", "n");
+        b.read("x");
+        b.store("p", 0, "x");
+        b.store("p", 8, "x");
+        b.store("q", 0, "n");
+        b.store("p", 0, "n");
+        b.load("y", "p", 0);
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+        let (q, stats) = dse(p.clone());
+        // Only the first store is dead: the others store to different words.
+        assert_eq!(stats.dead_stores, 1);
+        assert_eq!(stats.redundant_loads, 1);
+        assert_eq!(run(&p), run(&q));
+    }
+
+    #[test]
+    fn loads_in_between() {
+        let mut b = Builder::new();
+        b.exit();
+        b.function("f", &["a"], Type::I64);
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.store("p", 0, "a");
+        // `a` isn't a pointer, but the analysis doesn't know what it holds.
+        b.load("x", "a", 0);
+        b.store("p", 0, "n");
+        b.load("y", "p", 0);
+        b.ret("y");
+        let f = b.finish().func[&Id::from_ref("f")].clone();
+        let (f, stats) = dse_function(f);
+        assert_eq!(stats.dead_stores, 0);
+        assert_eq!(stats.redundant_loads, 1);
+        let entry = &f.block[&Function::entry()];
+        assert_eq!(
+            entry.insn[5],
+            Instruction::Copy {
+                dst: f.var("y"),
+                src: f.var("n")
+            }
+        );
+    }
+
+    #[test]
+    fn back_to_back_loads() {
+        let mut b = Builder::new();
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.read("x");
+        b.store("p", 0, "x");
+        b.call("r", "f", &["n"]);
+        b.load("y", "p", 0);
+        b.load("z", "p", 0);
+        b.arith(BOp::Add, "s", "y", "z");
+        b.print("s");
+        b.exit();
+        b.function("f", &["a"], Type::I64);
+        b.ret("a");
+        let p = b.finish();
+        let (q, stats) = dse(p.clone());
+        // The call may change the word, so only the second load goes.
+        assert_eq!(stats.dead_stores, 0);
+        assert_eq!(stats.redundant_loads, 1);
+        assert_eq!(run(&p), run(&q));
+    }
+}
@@ -0,0 +1,284 @@
+//! Dominator-based global value numbering.
+//!
+//! This is the dominator tree walk from Briggs, Cooper, and Simpson, "Value
+//! Numbering".  In SSA form, a variable holds the same value wherever it is
+//! available, so a computation can reuse the result of an equal computation in
+//! any dominating block, not just earlier in the same block.  That catches
+//! the redundancies between the two sides of an `if` and the code after it,
+//! which local value numbering can't.
+//!
+//! Each variable is numbered by the variable that first computed its value.
+//! Copies, constants that were already defined, arithmetic that was already
+//! computed, and phis that select the same value on every edge or that match
+//! another phi of the same block are removed, and their uses read the earlier
+//! variable instead.
+//!
+//! The function has to be in SSA form.
+
+use std::collections::HashMap;
+
+use derive_more::{Add, AddAssign};
+
+use super::*;
+use crate::front::ast::BOp;
+use crate::middle::dom::DomTree;
+
+/// What global value numbering did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Add, AddAssign)]
+pub struct GvnStats {
+    /// The number of instructions that were removed because an earlier
+    /// variable holds the same value.
+    pub redundant: usize,
+}
+
+/// A computation, with its operands given by their value numbers.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Expr {
+    Const(i64),
+    Arith(BOp, ValueId, ValueId),
+    /// Phis are only equal to phis of the same block.
+    Phi(BlockId, Vec<(BlockId, ValueId)>),
+}
+
+/// Run GVN on every function of an SSA program.
+pub fn gvn(program: Program) -> (Program, GvnStats) {
+    let mut stats = GvnStats::default();
+    let program = program.map_functions(|func| {
+        let (func, s) = gvn_function(func);
+        stats += s;
+        func
+    });
+    (program, stats)
+}
+
+/// Run GVN on a function in SSA form.
+pub fn gvn_function(mut func: Function) -> (Function, GvnStats) {
+    let dom = DomTree::new(&func);
+    let rpo = dom
+        .reverse_postorder()
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (*b, i))
+        .collect::<HashMap<_, _>>();
+    let mut numbering = Numbering {
+        vn: (0..func.names.num_values() as u32).map(ValueId).collect(),
+        table: HashMap::new(),
+        undo: vec![],
+    };
+
+    // Visit the children of each block in reverse postorder, so the phi
+    // arguments from forward edges are numbered before the phis.
+    let mut work = vec![Walk::Enter(Function::entry())];
+    while let Some(step) = work.pop() {
+        match step {
+            Walk::Enter(name) => {
+                work.push(Walk::Leave(numbering.undo.len()));
+                numbering.visit(name, &func.block[&name]);
+                let mut children = dom.children(name).to_vec();
+                children.sort_by_key(|c| std::cmp::Reverse(rpo[c]));
+                work.extend(children.into_iter().map(Walk::Enter));
+            }
+            Walk::Leave(len) => {
+                for expr in numbering.undo.drain(len..) {
+                    numbering.table.remove(&expr);
+                }
+            }
+        }
+    }
+
+    let vn = numbering.vn;
+    let mut stats = GvnStats::default();
+    for block in func.block.values_mut() {
+        block.insn.retain(|insn| {
+            let redundant = insn.def().is_some_and(|dst| vn[dst.0 as usize] != dst);
+            stats.redundant += redundant as usize;
+            !redundant
+        });
+        for insn in &mut block.insn {
+            insn.rename_uses(|v| vn[v.0 as usize]);
+        }
+        for t in &mut block.term {
+            t.rename_uses(|v| vn[v.0 as usize]);
+        }
+    }
+    (func, stats)
+}
+
+/// A step of the dominator tree walk.
+enum Walk {
+    /// Number the block and visit its children.
+    Enter(BlockId),
+    /// Forget the computations a block added to the table, which are the
+    /// ones after the given length of the undo log.
+    Leave(usize),
+}
+
+struct Numbering {
+    /// The value number of each variable.  Variables that haven't been
+    /// visited are their own number.
+    vn: Vec<ValueId>,
+    /// The computations in the current block and its dominators.
+    table: HashMap<Expr, ValueId>,
+    /// The computations added to the table, in order.
+    undo: Vec<Expr>,
+}
+
+impl Numbering {
+    fn visit(&mut self, name: BlockId, block: &Block) {
+        for insn in &block.insn {
+            let Some(dst) = insn.def() else {
+                continue;
+            };
+            let get = |v: &ValueId| self.vn[v.0 as usize];
+            let expr = match insn {
+                Instruction::Copy { src, .. } => {
+                    self.vn[dst.0 as usize] = get(src);
+                    continue;
+                }
+                Instruction::Const { src, .. } => Expr::Const(*src),
+                Instruction::Arith { op, lhs, rhs, .. } => {
+                    let (mut l, mut r) = (get(lhs), get(rhs));
+                    if matches!(op, BOp::Add | BOp::Mul) && r < l {
+                        (l, r) = (r, l);
+                    }
+                    Expr::Arith(*op, l, r)
+                }
+                Instruction::Phi { args, .. } => {
+                    let args = args
+                        .iter()
+                        .map(|(pred, v)| (*pred, get(v)))
+                        .collect::<Vec<_>>();
+                    let mut values = args.iter().map(|(_, v)| *v).filter(|v| *v != dst);
+                    if let Some(first) = values.next() {
+                        if values.all(|v| v == first) {
+                            self.vn[dst.0 as usize] = first;
+                            continue;
+                        }
+                    }
+                    Expr::Phi(name, args)
+                }
+                _ => continue,
+            };
+            match self.table.get(&expr) {
+                Some(earlier) => self.vn[dst.0 as usize] = *earlier,
+                None => {
+                    self.table.insert(expr.clone(), dst);
+                    self.undo.push(expr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn across_blocks() {
+        // The sum is computed before the branch and again on one side and
+        // after the join.
+        let mut b = Builder::new();
+        b.read("a");
+        b.read("b");
+        b.arith(BOp::Add, "x", "a", "b");
+        b.branch("x", "l", "join");
+        b.block("l");
+        b.arith(BOp::Add, "y", "b", "a");
+        b.print("y");
+        b.jump("join");
+        b.block("join");
+        b.arith(BOp::Add, "z", "a", "b");
+        b.print("z");
+        b.exit();
+        let p = b.finish();
+        verify_ssa(&p).unwrap();
+        let (main, stats) = gvn_function(p.func[&Program::main()].clone());
+        assert_eq!(stats, GvnStats { redundant: 2 });
+        let x = main.var("x");
+        assert_eq!(
+            main.block[&main.block_named("l")].insn,
+            vec![Instruction::Print(x)]
+        );
+        assert_eq!(
+            main.block[&main.block_named("join")].insn,
+            vec![Instruction::Print(x)]
+        );
+    }
+
+    #[test]
+    fn only_dominating_computations() {
+        // Each side computes the product, but neither dominates the join,
+        // so the one there stays.
+        let mut b = Builder::new();
+        b.read("a");
+        b.branch("a", "l", "r");
+        b.block("l");
+        b.arith(BOp::Mul, "x", "a", "a");
+        b.print("x");
+        b.jump("join");
+        b.block("r");
+        b.arith(BOp::Mul, "y", "a", "a");
+        b.print("y");
+        b.jump("join");
+        b.block("join");
+        b.arith(BOp::Mul, "z", "a", "a");
+        b.print("z");
+        b.exit();
+        let p = b.finish();
+        verify_ssa(&p).unwrap();
+        let (q, stats) = gvn(p.clone());
+        assert_eq!(stats, GvnStats::default());
+        assert_eq!(p, q);
+    }
+
+    #[test]
+    fn phis_and_constants() {
+        // `p` and `q` select the same values, and `one` and `uno` are the
+        // same constant, so `s` and `t` are the same sum.
+        let mut b = Builder::new();
+        b.read("a");
+        b.read("b");
+        b.constant("one", 1);
+        b.branch("a", "l", "join");
+        b.block("l");
+        b.constant("uno", 1);
+        b.jump("join");
+        b.block("join");
+        b.phi("p", &[(ENTRY, "a"), ("l", "b")]);
+        b.phi("q", &[(ENTRY, "a"), ("l", "b")]);
+        b.phi("r", &[(ENTRY, "one"), ("l", "uno")]);
+        b.arith(BOp::Sub, "s", "p", "r");
+        b.arith(BOp::Sub, "t", "q", "one");
+        b.print("s");
+        b.print("t");
+        b.exit();
+        let p = b.finish();
+        verify_ssa(&p).unwrap();
+        let (main, stats) = gvn_function(p.func[&Program::main()].clone());
+        assert_eq!(stats, GvnStats { redundant: 4 });
+        verify_ssa(&Program::from_main(main.clone())).unwrap();
+        let (s, p) = (main.var("s"), main.var("p"));
+        assert_eq!(
+            main.block[&main.block_named("join")].insn,
+            vec![
+                Instruction::Phi {
+                    dst: p,
+                    args: [
+                        (Function::entry(), main.var("a")),
+                        (main.block_named("l"), main.var("b"))
+                    ]
+                    .into()
+                },
+                Instruction::Arith {
+                    op: BOp::Sub,
+                    dst: s,
+                    lhs: p,
+                    rhs: main.var("one")
+                },
+                Instruction::Print(s),
+                Instruction::Print(s),
+            ]
+        );
+    }
+}
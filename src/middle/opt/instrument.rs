@@ -0,0 +1,89 @@
+//! Profile instrumentation.
+//!
+//! Each block gets a `$count` instruction right after its phis, with a
+//! counter of its own.  Running the program then counts how many times each
+//! block is entered, and `Profile::from_counters` names the counts by block.
+//!
+//! This works both in and out of SSA form.  It should run after the other
+//! passes, so that the profile describes the blocks the backend sees.
+
+use super::*;
+
+/// Instrument every function, numbering the counters across the program.
+pub fn instrument(program: Program) -> Program {
+    let mut next = 0;
+    program.map_functions(|func| {
+        let (func, end) = instrument_function(func, next);
+        next = end;
+        func
+    })
+}
+
+/// Instrument a function with the counters from `first` on.  Returns the
+/// first counter it didn't use.
+pub fn instrument_function(mut func: Function, first: u32) -> (Function, u32) {
+    let mut next = first;
+    for block in func.block.values_mut() {
+        let pos = block.insn.iter().take_while(|i| i.is_phi()).count();
+        block.insn.insert(pos, Instruction::Count(next));
+        next += 1;
+    }
+    (func, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+    use crate::middle::interp;
+    use crate::middle::profile::Profile;
+
+    #[test]
+    fn counts_blocks() {
+        // The loop runs as many times as the input says.
+        let mut b = Builder::new();
+        b.read("n");
+        b.constant("one", 1);
+        b.jump("head");
+        b.block("head");
+        b.arith(BOp::Lt, "c", "i", "n");
+        b.branch("c", "body", "done");
+        b.block("body");
+        b.arith(BOp::Add, "i", "i", "one");
+        b.jump("head");
+        b.block("done");
+        b.print("i");
+        b.exit();
+        let p = instrument(b.finish());
+        let main = &p.func[&Program::main()];
+        assert_eq!(
+            main.block[&main.block_named("body")].insn[0],
+            Instruction::Count(2)
+        );
+
+        let mut out = vec![];
+        let profile = interp::run_profiled(&p, "3".as_bytes(), &mut out).unwrap();
+        assert_eq!(out, b"3\n");
+        let text = "# smol profile\nmain $entry 1\nmain head 4\nmain body 3\nmain done 1\n";
+        assert_eq!(profile, Profile::parse(text).unwrap());
+    }
+
+    #[test]
+    fn after_phis() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.branch("x", "l", "join");
+        b.block("l");
+        b.jump("join");
+        b.block("join");
+        b.phi("y", &[(ENTRY, "x"), ("l", "x")]);
+        b.print("y");
+        b.exit();
+        let (main, next) = instrument_function(b.finish_main(), 5);
+        assert_eq!(next, 8);
+        let join = &main.block[&main.block_named("join")];
+        assert!(join.insn[0].is_phi());
+        assert_eq!(join.insn[1], Instruction::Count(7));
+        verify_ssa(&Program::from_main(main)).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! Induction variable simplification.
+//!
+//! A basic induction variable is a phi at a loop header that starts with some
+//! value `init` and grows by a constant `c` on every iteration:
+//!
+//! ```text
+//! head:
+//!   $phi i pre init latch next
+//!   ...
+//!   $arith + next i c
+//! ```
+//!
+//! A product `d = i * s` with a constant `s` inside the loop then grows by
+//! `c * s` on every iteration, so it can be computed with an addition instead
+//! of a multiplication, by a new induction variable that starts with
+//! `init * s`.  Arithmetic wraps around, so this holds even if the values
+//! overflow.  With `--trap-overflow` it doesn't, and the pass manager skips
+//! this pass.
+//!
+//! Only loops with one latch and one predecessor outside the loop are
+//! simplified.  The function has to be in SSA form.  Copy propagation and
+//! dead code elimination clean up afterwards.
+//!
+//! Well-formed tiny IR programs have no loops, so like unrolling, this only
+//! does something for programs from other sources, which
+//! `verify_ssa_with_cycles` checks.
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::dom::DomTree;
+use crate::middle::loops::{Loop, LoopInfo};
+
+/// A basic induction variable.
+struct Basic {
+    /// The value before the first iteration.
+    init: ValueId,
+    /// The value for the next iteration.
+    next: ValueId,
+    step: i64,
+}
+
+/// Simplify the induction variables of every function of an SSA program.
+pub fn simplify_ivs(program: Program) -> Program {
+    program.map_functions(simplify_ivs_function)
+}
+
+/// Simplify the induction variables of a function in SSA form.
+pub fn simplify_ivs_function(mut func: Function) -> Function {
+    let info = LoopInfo::new(&func, &DomTree::new(&func));
+    for l in info.loops() {
+        simplify_loop(&mut func, l);
+    }
+    func
+}
+
+fn simplify_loop(func: &mut Function, l: &Loop) {
+    let preds = func.predecessors();
+    let outside = preds[&l.header]
+        .iter()
+        .filter(|p| !l.body.contains(p))
+        .copied()
+        .collect::<Vec<_>>();
+    let ([pre], [latch]) = (
+        &outside[..],
+        &l.latches.iter().copied().collect::<Vec<_>>()[..],
+    ) else {
+        return;
+    };
+    let (pre, latch) = (*pre, *latch);
+
+    let mut constant = vec![None; func.names.num_values()];
+    let mut def_block = vec![None; func.names.num_values()];
+    for (name, block) in &func.block {
+        for insn in &block.insn {
+            if let Some(dst) = insn.def() {
+                def_block[dst.0 as usize] = Some(*name);
+            }
+            if let Instruction::Const { dst, src } = insn {
+                constant[dst.0 as usize] = Some(*src);
+            }
+        }
+    }
+    let constant = |v: ValueId| constant[v.0 as usize];
+
+    // Find the basic induction variables.
+    let mut basic = Map::new();
+    for insn in func.block[&l.header].insn.iter().take_while(|i| i.is_phi()) {
+        let Instruction::Phi { dst, args } = insn else {
+            unreachable!()
+        };
+        let (Some(init), Some(next)) = (args.get(&pre), args.get(&latch)) else {
+            continue;
+        };
+        let step = func
+            .block
+            .values()
+            .flat_map(|b| &b.insn)
+            .find_map(|i| match i {
+                Instruction::Arith {
+                    op,
+                    dst: d,
+                    lhs,
+                    rhs,
+                } if d == next => match (op, constant(*lhs), constant(*rhs)) {
+                    (BOp::Add, _, Some(c)) if lhs == dst => Some(c),
+                    (BOp::Add, Some(c), _) if rhs == dst => Some(c),
+                    (BOp::Sub, _, Some(c)) if lhs == dst => Some(c.wrapping_neg()),
+                    _ => None,
+                },
+                _ => None,
+            });
+        if let Some(step) = step {
+            let (init, next) = (*init, *next);
+            basic.insert(*dst, Basic { init, next, step });
+        }
+    }
+
+    // Replace the products of basic induction variables and constants with
+    // new induction variables, one per pair.
+    let mut derived: Map<(ValueId, i64), ValueId> = Map::new();
+    for name in l.body.iter().copied().collect::<Vec<_>>() {
+        // Adding an induction variable may insert instructions into this
+        // block, so we don't keep positions across that.
+        let mut pos = 0;
+        while pos < func.block[&name].insn.len() {
+            pos += 1;
+            let Instruction::Arith {
+                op: BOp::Mul,
+                dst,
+                lhs,
+                rhs,
+            } = func.block[&name].insn[pos - 1]
+            else {
+                continue;
+            };
+            let (iv, scale) = match (constant(lhs), constant(rhs)) {
+                (_, Some(s)) if basic.contains_key(&lhs) => (lhs, s),
+                (Some(s), _) if basic.contains_key(&rhs) => (rhs, s),
+                _ => continue,
+            };
+            let j = match derived.get(&(iv, scale)) {
+                Some(j) => *j,
+                None => {
+                    let b = &basic[&iv];
+                    let Some(next_block) = def_block[b.next.0 as usize] else {
+                        continue;
+                    };
+                    let j = add_iv(func, b, scale, pre, latch, l.header, next_block);
+                    derived.insert((iv, scale), j);
+                    j
+                }
+            };
+            let block = func.block.get_mut(&name).unwrap();
+            let insn = block.insn.iter_mut().find(|i| i.def() == Some(dst));
+            *insn.unwrap() = Instruction::Copy { dst, src: j };
+        }
+    }
+}
+
+/// Create the induction variable `iv * scale`, and return it.
+fn add_iv(
+    func: &mut Function,
+    iv: &Basic,
+    scale: i64,
+    pre: BlockId,
+    latch: BlockId,
+    header: BlockId,
+    next_block: BlockId,
+) -> ValueId {
+    let scale_var = func.new_var("$tmp", Type::I64);
+    let init = func.new_var("$tmp", Type::I64);
+    let j = func.new_var("$tmp", Type::I64);
+    let step = func.new_var("$tmp", Type::I64);
+    let next = func.new_var("$tmp", Type::I64);
+
+    func.block.get_mut(&pre).unwrap().insn.extend([
+        Instruction::Const {
+            dst: scale_var,
+            src: scale,
+        },
+        Instruction::Arith {
+            op: BOp::Mul,
+            dst: init,
+            lhs: iv.init,
+            rhs: scale_var,
+        },
+    ]);
+    let args = [(pre, init), (latch, next)].into();
+    let header = func.block.get_mut(&header).unwrap();
+    header.insn.insert(0, Instruction::Phi { dst: j, args });
+
+    // Step right after the basic induction variable does.
+    let block = func.block.get_mut(&next_block).unwrap();
+    let pos = block
+        .insn
+        .iter()
+        .position(|i| i.def() == Some(iv.next))
+        .unwrap();
+    block.insn.splice(
+        pos + 1..pos + 1,
+        [
+            Instruction::Const {
+                dst: step,
+                src: iv.step.wrapping_mul(scale),
+            },
+            Instruction::Arith {
+                op: BOp::Add,
+                dst: next,
+                lhs: j,
+                rhs: step,
+            },
+        ],
+    );
+    j
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::interp;
+    use crate::middle::verify::verify_ssa_with_cycles;
+
+    // SECTION: helpers
+
+    /// Print multiples of `i` for `i` counting down from the input by two.
+    fn strided() -> Program {
+        let mut b = Builder::new();
+        b.read("n");
+        b.constant("zero", 0);
+        b.constant("two", 2);
+        b.constant("eight", 8);
+        b.constant("three", 3);
+        b.jump("head");
+        b.block("head");
+        b.phi("i", &[(ENTRY, "n"), ("body", "i2")]);
+        b.arith(BOp::Lt, "c", "zero", "i");
+        b.branch("c", "body", "done");
+        b.block("body");
+        b.arith(BOp::Mul, "off", "i", "eight");
+        b.print("off");
+        b.arith(BOp::Mul, "again", "eight", "i");
+        b.print("again");
+        b.arith(BOp::Mul, "other", "i", "three");
+        b.print("other");
+        b.arith(BOp::Sub, "i2", "i", "two");
+        b.arith(BOp::Mul, "last", "three", "i");
+        b.print("last");
+        b.jump("head");
+        b.block("done");
+        b.exit();
+        b.finish()
+    }
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn products_become_additions() {
+        let p = strided();
+        verify_ssa_with_cycles(&p).unwrap();
+        let q = simplify_ivs(p.clone());
+        verify_ssa_with_cycles(&q).unwrap();
+        let main = &q.func[&Program::main()];
+        let body = &main.block[&main.block_named("body")];
+        assert!(!body
+            .insn
+            .iter()
+            .any(|i| matches!(i, Instruction::Arith { op: BOp::Mul, .. })));
+        // One new induction variable for each scale.
+        let head = &main.block[&main.block_named("head")];
+        assert_eq!(head.insn.iter().filter(|i| i.is_phi()).count(), 3);
+        for input in ["0", "1", "6", "7", "-3"] {
+            assert_eq!(output(&p, input), output(&q, input));
+        }
+    }
+
+    #[test]
+    fn needs_constant_steps() {
+        // `i` grows by a variable amount, so it isn't an induction variable
+        // we know how to handle.
+        let mut b = Builder::new();
+        b.read("n");
+        b.constant("four", 4);
+        b.jump("head");
+        b.block("head");
+        b.phi("i", &[(ENTRY, "n"), ("body", "i2")]);
+        b.branch("i", "body", "done");
+        b.block("body");
+        b.arith(BOp::Mul, "off", "i", "four");
+        b.print("off");
+        b.arith(BOp::Sub, "i2", "i", "n");
+        b.jump("head");
+        b.block("done");
+        b.exit();
+        let p = b.finish();
+        assert_eq!(simplify_ivs(p.clone()), p);
+    }
+}
@@ -0,0 +1,107 @@
+//! Profile-guided block layout.
+//!
+//! The backend emits blocks in the order of their IDs, and a branch jumps to
+//! one target and falls through to the other if it comes next.  Taken jumps
+//! are slower than falling through, so we order the blocks along the hot
+//! paths of the profile: after each block comes its hottest successor that
+//! isn't placed yet, and when there is none, the hottest block left.  The
+//! backend can then pick the polarity of each branch so that the target that
+//! comes next, which is the hot one, falls through.
+//!
+//! Blocks the profile doesn't know count as never entered, so without a
+//! profile the order stays as it is.  This works both in and out of SSA form.
+
+use super::*;
+use crate::common::*;
+use crate::middle::profile::Profile;
+
+/// Lay out the blocks of every function by the profile.
+pub fn layout(mut program: Program, profile: &Profile) -> Program {
+    program.func = std::mem::take(&mut program.func)
+        .into_iter()
+        .map(|(name, func)| (name, layout_function(func, name, profile)))
+        .collect();
+    program
+}
+
+/// Lay out the blocks of the function `name` by the profile.
+pub fn layout_function(mut func: Function, name: Id, profile: &Profile) -> Function {
+    let count = |b: BlockId| profile.count(name, func.names.block(b)).unwrap_or(0);
+    // The hottest block, preferring earlier ones.
+    let hottest = |blocks: &mut dyn Iterator<Item = BlockId>| {
+        blocks.max_by_key(|b| (count(*b), std::cmp::Reverse(*b)))
+    };
+
+    let mut placed = Set::new();
+    let mut order = vec![];
+    let mut next = Some(Function::entry());
+    while order.len() < func.block.len() {
+        let block = next.take().unwrap_or_else(|| {
+            let mut rest = func.block.keys().copied().filter(|b| !placed.contains(b));
+            hottest(&mut rest).unwrap()
+        });
+        placed.insert(block);
+        order.push(block);
+        let mut successors = func.block[&block]
+            .successors()
+            .into_iter()
+            .filter(|s| !placed.contains(s) && count(*s) > 0);
+        next = hottest(&mut successors);
+    }
+    func.reorder_blocks(&order);
+    func
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::interp;
+
+    // SECTION: helpers
+
+    /// `$entry` branches to `cold` or `hot`, which both go to `done`.
+    fn diamond() -> Program {
+        let mut b = Builder::new();
+        b.read("x");
+        b.branch("x", "cold", "hot");
+        b.block("cold");
+        b.print("x");
+        b.jump("done");
+        b.block("hot");
+        b.jump("done");
+        b.block("done");
+        b.exit();
+        b.finish()
+    }
+
+    fn names(p: &Program) -> Vec<String> {
+        let main = &p.func[&Program::main()];
+        main.block
+            .keys()
+            .map(|b| main.names.block(*b).to_string())
+            .collect()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn hot_paths_fall_through() {
+        let p = diamond();
+        let profile = interp::run_profiled(&instrument(p.clone()), "0".as_bytes(), vec![]).unwrap();
+        let q = layout(p.clone(), &profile);
+        assert_eq!(names(&q), ["$entry", "hot", "done", "cold"]);
+        verify(&q).unwrap();
+        for input in ["0", "1"] {
+            let (mut a, mut b) = (vec![], vec![]);
+            interp::run(&p, input.as_bytes(), &mut a).unwrap();
+            interp::run(&q, input.as_bytes(), &mut b).unwrap();
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn no_profile() {
+        let p = diamond();
+        assert_eq!(layout(p.clone(), &Profile::default()), p);
+    }
+}
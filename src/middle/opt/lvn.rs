@@ -0,0 +1,245 @@
+//! Local value numbering.
+//!
+//! Within a block, every value a variable may hold gets a number, and so does
+//! every computation over numbered values.  A computation whose number some
+//! variable still holds is redundant, so it becomes a copy of that variable.
+//! Numbers follow copies, and the operands of commutative operators are
+//! sorted, so `a + b` and `b + a'` match if `a'` is a copy of `a`.
+//!
+//! Variables are tracked through redefinitions, so this works both in and out
+//! of SSA form.  In SSA form, copy propagation removes the copies afterwards.
+
+use std::collections::HashMap;
+
+use derive_more::{Add, AddAssign};
+
+use super::*;
+use crate::front::ast::BOp;
+
+/// What local value numbering did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Add, AddAssign)]
+pub struct LvnStats {
+    /// The number of redundant computations that were removed or replaced
+    /// with copies.
+    pub redundant: usize,
+}
+
+/// A computation, with its operands given by their value numbers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Expr {
+    Const(i64),
+    Arith(BOp, u32, u32),
+}
+
+/// Run local value numbering on every block of every function.
+pub fn lvn(program: Program) -> (Program, LvnStats) {
+    let mut stats = LvnStats::default();
+    let program = program.map_functions(|func| {
+        let (func, s) = lvn_function(func);
+        stats += s;
+        func
+    });
+    (program, stats)
+}
+
+/// Run local value numbering on every block of a function.
+pub fn lvn_function(mut func: Function) -> (Function, LvnStats) {
+    let mut stats = LvnStats::default();
+    for block in func.block.values_mut() {
+        let mut numbering = Numbering::default();
+        let mut insns = vec![];
+        for insn in std::mem::take(&mut block.insn) {
+            match numbering.visit(insn) {
+                Visited::Kept(insn) => insns.push(insn),
+                Visited::Copy(insn) => {
+                    insns.push(insn);
+                    stats.redundant += 1;
+                }
+                Visited::Removed => stats.redundant += 1,
+            }
+        }
+        block.insn = insns;
+    }
+    (func, stats)
+}
+
+/// What became of an instruction.
+enum Visited {
+    Kept(Instruction),
+    /// A redundant computation, replaced with a copy.
+    Copy(Instruction),
+    /// A redundant computation that assigned a variable the value it already
+    /// held.
+    Removed,
+}
+
+/// The value numbers of one block.
+#[derive(Default)]
+struct Numbering {
+    /// The number of the value each variable holds.
+    var: HashMap<ValueId, u32>,
+    expr: HashMap<Expr, u32>,
+    /// A variable that held each number when it was computed.  It may have
+    /// been overwritten since.
+    holder: HashMap<u32, ValueId>,
+    next: u32,
+}
+
+impl Numbering {
+    fn fresh(&mut self) -> u32 {
+        self.next += 1;
+        self.next - 1
+    }
+
+    fn of(&mut self, v: ValueId) -> u32 {
+        if let Some(n) = self.var.get(&v) {
+            return *n;
+        }
+        let n = self.fresh();
+        self.var.insert(v, n);
+        self.holder.insert(n, v);
+        n
+    }
+
+    /// A variable that still holds the given number.
+    fn held(&self, n: u32) -> Option<ValueId> {
+        self.holder
+            .get(&n)
+            .copied()
+            .filter(|v| self.var.get(v) == Some(&n))
+    }
+
+    fn define(&mut self, dst: ValueId, n: u32) {
+        self.var.insert(dst, n);
+        if self.held(n).is_none() {
+            self.holder.insert(n, dst);
+        }
+    }
+
+    /// Number the value an instruction computes, and check whether a
+    /// variable already holds it.
+    fn visit(&mut self, insn: Instruction) -> Visited {
+        let (dst, expr) = match insn {
+            Instruction::Copy { dst, src } => {
+                let n = self.of(src);
+                self.define(dst, n);
+                return Visited::Kept(insn);
+            }
+            Instruction::Const { dst, src } => (dst, Expr::Const(src)),
+            Instruction::Arith { op, dst, lhs, rhs } => {
+                let (mut l, mut r) = (self.of(lhs), self.of(rhs));
+                if matches!(op, BOp::Add | BOp::Mul) && r < l {
+                    (l, r) = (r, l);
+                }
+                (dst, Expr::Arith(op, l, r))
+            }
+            _ => {
+                if let Some(dst) = insn.def() {
+                    let n = self.fresh();
+                    self.define(dst, n);
+                }
+                return Visited::Kept(insn);
+            }
+        };
+
+        let n = match self.expr.get(&expr) {
+            Some(n) => *n,
+            None => {
+                let n = self.fresh();
+                self.expr.insert(expr, n);
+                n
+            }
+        };
+        // Constants are as cheap as copies, so they stay, but they share
+        // their number so that computations over them match.
+        let src = match self.var.get(&dst) {
+            Some(m) if *m == n => Some(dst),
+            _ => self.held(n),
+        }
+        .filter(|_| matches!(expr, Expr::Arith(..)));
+        self.define(dst, n);
+        match src {
+            Some(src) if src == dst => Visited::Removed,
+            Some(src) => Visited::Copy(Instruction::Copy { dst, src }),
+            None => Visited::Kept(insn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_computations() {
+        // The second sum has its operands swapped and reads copies, and the
+        // constants are different variables with the same value.
+        let mut b = Builder::new();
+        b.read("a");
+        b.constant("one", 1);
+        b.arith(BOp::Add, "x", "a", "one");
+        b.copy("a2", "a");
+        b.constant("uno", 1);
+        b.arith(BOp::Add, "y", "uno", "a2");
+        b.arith(BOp::Sub, "z", "a", "one");
+        b.print("y");
+        b.exit();
+        let (main, stats) = lvn_function(b.finish_main());
+        assert_eq!(stats, LvnStats { redundant: 1 });
+        let insn = &main.block[&Function::entry()].insn;
+        assert_eq!(
+            insn[5],
+            Instruction::Copy {
+                dst: main.var("y"),
+                src: main.var("x")
+            }
+        );
+        assert!(matches!(insn[6], Instruction::Arith { op: BOp::Sub, .. }));
+    }
+
+    #[test]
+    fn respects_redefinitions() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.read("b");
+        b.arith(BOp::Mul, "x", "a", "b");
+        // `a` changes, so the product has to be computed again.
+        b.read("a");
+        b.arith(BOp::Mul, "y", "a", "b");
+        // `x` is overwritten, but `y` holds the same product.
+        b.read("x");
+        b.arith(BOp::Mul, "z", "a", "b");
+        // This assigns `z` the value it already holds.
+        b.arith(BOp::Mul, "z", "b", "a");
+        b.print("z");
+        b.exit();
+        let (main, stats) = lvn_function(b.finish_main());
+        assert_eq!(stats.redundant, 2);
+        let insn = &main.block[&Function::entry()].insn;
+        assert_eq!(insn.len(), 8);
+        assert!(matches!(insn[4], Instruction::Arith { .. }));
+        assert_eq!(
+            insn[6],
+            Instruction::Copy {
+                dst: main.var("z"),
+                src: main.var("y")
+            }
+        );
+    }
+
+    #[test]
+    fn stays_within_blocks() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.arith(BOp::Add, "x", "a", "a");
+        b.jump("next");
+        b.block("next");
+        b.arith(BOp::Add, "y", "a", "a");
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+        let (q, stats) = lvn(p.clone());
+        assert_eq!(stats, LvnStats::default());
+        assert_eq!(p, q);
+    }
+}
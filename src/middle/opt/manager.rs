@@ -0,0 +1,688 @@
+//! The pass manager.
+//!
+//! A pipeline is a list of named passes.  Some passes need the program in SSA
+//! form and some need it out of SSA form, so the manager converts it between
+//! passes as needed, and out of SSA form at the end.  Passes that may enable
+//! more of their own work ask to be repeated until the program stops
+//! changing.  A pass that keeps changing it is stopped after a few runs, with
+//! a warning.
+//!
+//! With `verify_each`, the manager verifies the program after each pass, so
+//! that a pass that breaks the program is caught right away.  This only
+//! starts once the program is well-formed, so that a pass is never blamed
+//! for a program that was malformed before it ran.
+//!
+//! The manager can also measure each pass: how long it took, and how many
+//! instructions and blocks the program had before and after it, and show the
+//! program after each pass to an observer, which can dump it.
+
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// How many times a pass that iterates to a fixed point may run in a row.
+const MAX_ITERATIONS: usize = 10;
+
+/// Which form of the program a pass works on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Form {
+    Ssa,
+    NotSsa,
+    Either,
+}
+
+/// An optimization pass.
+#[derive(Clone, Copy, Debug)]
+pub struct Pass {
+    pub name: &'static str,
+    pub form: Form,
+    /// Run the pass again while it changes the program.
+    pub fixed_point: bool,
+    run: fn(Program, &Options) -> Program,
+}
+
+impl PartialEq for Pass {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Pass {}
+
+/// All passes, by name.
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "branches",
+        form: Form::Ssa,
+        fixed_point: false,
+        run: |p, _| simplify_branches(p),
+    },
+    Pass {
+        name: "copy-prop",
+        form: Form::Ssa,
+        fixed_point: true,
+        run: |p, _| copy_prop(p),
+    },
+    Pass {
+        name: "dce",
+        form: Form::Either,
+        fixed_point: true,
+        run: |p, _| dce(p),
+    },
+    Pass {
+        name: "dse",
+        form: Form::Ssa,
+        fixed_point: false,
+        run: |p, _| dse(p).0,
+    },
+    Pass {
+        name: "gvn",
+        form: Form::Ssa,
+        fixed_point: false,
+        run: |p, _| gvn(p).0,
+    },
+    Pass {
+        name: "instrument",
+        form: Form::Either,
+        fixed_point: false,
+        run: |p, _| instrument(p),
+    },
+    Pass {
+        name: "ivs",
+        form: Form::Ssa,
+        fixed_point: false,
+        // The new induction variables may overflow where the products
+        // didn't, which only doesn't matter if overflows wrap around.
+        run: |p, options| {
+            if options.traps.overflow {
+                p
+            } else {
+                simplify_ivs(p)
+            }
+        },
+    },
+    Pass {
+        name: "layout",
+        form: Form::Either,
+        fixed_point: false,
+        run: |p, options| match &options.profile {
+            Some(profile) => layout(p, profile),
+            None => p,
+        },
+    },
+    Pass {
+        name: "lvn",
+        form: Form::Either,
+        fixed_point: false,
+        run: |p, _| lvn(p).0,
+    },
+    Pass {
+        name: "peephole",
+        form: Form::Ssa,
+        fixed_point: false,
+        run: |p, options| peephole(p, options.traps),
+    },
+    Pass {
+        name: "sccp",
+        form: Form::Ssa,
+        fixed_point: true,
+        run: |p, options| sccp(p, options.traps),
+    },
+    Pass {
+        name: "simplify-cfg",
+        form: Form::Either,
+        fixed_point: true,
+        run: |p, _| simplify_cfg(p),
+    },
+    Pass {
+        name: "strength",
+        form: Form::Ssa,
+        fixed_point: false,
+        run: |p, options| strength_reduce(p, options.traps),
+    },
+    Pass {
+        name: "unreachable",
+        form: Form::Either,
+        fixed_point: false,
+        run: |p, _| remove_unreachable(p),
+    },
+    Pass {
+        name: "unroll",
+        form: Form::NotSsa,
+        fixed_point: false,
+        run: |p, options| unroll(p, options.unroll_threshold),
+    },
+];
+
+/// Find a pass by its name.
+pub fn find_pass(name: &str) -> Option<&'static Pass> {
+    PASSES.iter().find(|p| p.name == name)
+}
+
+/// The predefined pipelines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    /// No optimizations.
+    #[default]
+    O0,
+    /// Cheap optimizations.
+    O1,
+    /// All optimizations.
+    O2,
+    /// All optimizations, and another round of the ones that clean up after
+    /// the others, for the best code at the most compile time.
+    O3,
+}
+
+impl OptLevel {
+    /// The level with the given number, from 0 to 3.
+    pub fn new(level: u8) -> Option<OptLevel> {
+        match level {
+            0 => Some(OptLevel::O0),
+            1 => Some(OptLevel::O1),
+            2 => Some(OptLevel::O2),
+            3 => Some(OptLevel::O3),
+            _ => None,
+        }
+    }
+
+    /// The names of the passes of this level, in order.
+    fn pass_names(self) -> &'static [&'static str] {
+        match self {
+            OptLevel::O0 => &[],
+            OptLevel::O1 => &[
+                "sccp",
+                "simplify-cfg",
+                "copy-prop",
+                "dce",
+                "simplify-cfg",
+                "layout",
+            ],
+            OptLevel::O2 => &[
+                "unroll",
+                "sccp",
+                "branches",
+                "simplify-cfg",
+                "gvn",
+                "dse",
+                "peephole",
+                "ivs",
+                "strength",
+                "copy-prop",
+                "dce",
+                "simplify-cfg",
+                "layout",
+            ],
+            // Strength reduction and the induction variables leave constants
+            // and redundant arithmetic behind, which a second round folds.
+            OptLevel::O3 => &[
+                "unroll",
+                "sccp",
+                "branches",
+                "simplify-cfg",
+                "gvn",
+                "dse",
+                "peephole",
+                "ivs",
+                "strength",
+                "sccp",
+                "branches",
+                "gvn",
+                "peephole",
+                "copy-prop",
+                "dce",
+                "simplify-cfg",
+                "layout",
+            ],
+        }
+    }
+}
+
+/// A list of passes to run in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pipeline {
+    passes: Vec<&'static Pass>,
+}
+
+impl Pipeline {
+    /// The pipeline of an optimization level.
+    pub fn for_level(level: OptLevel) -> Pipeline {
+        Pipeline {
+            passes: level
+                .pass_names()
+                .iter()
+                .map(|name| find_pass(name).unwrap())
+                .collect(),
+        }
+    }
+
+    /// Parse a comma-separated list of pass names.
+    pub fn parse(spec: &str) -> Result<Pipeline, String> {
+        let passes = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                find_pass(name).ok_or_else(|| {
+                    let known = PASSES.iter().map(|p| p.name).collect::<Vec<_>>();
+                    format!(
+                        "Unknown pass `{name}`.  The passes are {}.",
+                        known.join(", ")
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Pipeline { passes })
+    }
+
+    /// Add a pass to the end of the pipeline.
+    pub fn then(mut self, pass: &'static Pass) -> Pipeline {
+        self.passes.push(pass);
+        self
+    }
+
+    /// The names of the passes, in order.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|p| p.name).collect()
+    }
+
+    /// Run the passes on a program that is not in SSA form.
+    pub fn run(&self, program: Program, options: &Options) -> Program {
+        self.run_with_stats(program, options).0
+    }
+
+    /// Run the passes on a program that is not in SSA form, and measure each
+    /// of them.  Conversions into SSA form and out of it are measured too.
+    pub fn run_with_stats(&self, program: Program, options: &Options) -> (Program, PipelineStats) {
+        self.run_observed(program, options, None, |_, _| {})
+    }
+
+    /// Run the passes on a program that is not in SSA form, measure them,
+    /// and collect remarks about what each of them did.
+    pub fn run_with_remarks(
+        &self,
+        program: Program,
+        options: &Options,
+    ) -> (Program, PipelineStats, Vec<Remark>) {
+        let mut remarks = vec![];
+        let (program, stats) = self.run_observed(program, options, Some(&mut remarks), |_, _| {});
+        (program, stats, remarks)
+    }
+
+    /// Run the passes on a program that is not in SSA form, measure them,
+    /// collect remarks if there is somewhere to put them, and show the
+    /// program to `observe` after each pass and each conversion between
+    /// forms, with the name it has in the statistics.
+    pub fn run_observed(
+        &self,
+        mut program: Program,
+        options: &Options,
+        mut remarks: Option<&mut Vec<Remark>>,
+        mut observe: impl FnMut(&'static str, &Program),
+    ) -> (Program, PipelineStats) {
+        let mut stats = PipelineStats::default();
+        let mut in_ssa = false;
+        let mut checker = Checker {
+            on: options.verify_each,
+            well_formed: false,
+        };
+        checker.check(None, &program, false);
+        log::debug!("Running the passes {}.", self.names().join(", "));
+        for pass in &self.passes {
+            match pass.form {
+                Form::Ssa if !in_ssa => {
+                    program = stats.measure(INTO_SSA, program, ssa::construct);
+                    in_ssa = true;
+                    checker.check(Some(INTO_SSA), &program, in_ssa);
+                    observe(INTO_SSA, &program);
+                }
+                Form::NotSsa if in_ssa => {
+                    program = stats.measure(OUT_OF_SSA, program, ssa::destruct);
+                    in_ssa = false;
+                    checker.check(Some(OUT_OF_SSA), &program, in_ssa);
+                    observe(OUT_OF_SSA, &program);
+                }
+                _ => {}
+            }
+            let before = remarks.is_some().then(|| program.clone());
+            program = stats.measure(pass.name, program, |p| run_pass(pass, p, options));
+            if let Some(s) = stats.passes.last() {
+                log::debug!(
+                    "Pass `{}` took {:?}, and left {} of {} instructions.",
+                    s.name,
+                    s.time,
+                    s.insns_after,
+                    s.insns_before
+                );
+            }
+            checker.check(Some(pass.name), &program, in_ssa);
+            if let (Some(remarks), Some(before)) = (remarks.as_deref_mut(), before) {
+                remarks.extend(remarks::diff(pass.name, &before, &program));
+            }
+            observe(pass.name, &program);
+        }
+        if in_ssa {
+            program = stats.measure(OUT_OF_SSA, program, ssa::destruct);
+            checker.check(Some(OUT_OF_SSA), &program, false);
+            observe(OUT_OF_SSA, &program);
+        }
+        (program, stats)
+    }
+}
+
+/// The names conversions between forms have in statistics.
+const INTO_SSA: &str = "(into ssa)";
+const OUT_OF_SSA: &str = "(out of ssa)";
+
+/// What a pass did, and how long it took.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: &'static str,
+    pub time: Duration,
+    pub insns_before: usize,
+    pub insns_after: usize,
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+}
+
+impl PassStats {
+    pub fn blocks_removed(&self) -> usize {
+        self.blocks_before.saturating_sub(self.blocks_after)
+    }
+}
+
+/// The statistics of each pass a pipeline ran, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub passes: Vec<PassStats>,
+}
+
+impl PipelineStats {
+    pub fn total_time(&self) -> Duration {
+        self.passes.iter().map(|p| p.time).sum()
+    }
+
+    /// Run a pass and record its statistics.
+    fn measure(
+        &mut self,
+        name: &'static str,
+        program: Program,
+        pass: impl FnOnce(Program) -> Program,
+    ) -> Program {
+        let (insns_before, blocks_before) = size(&program);
+        let start = Instant::now();
+        let program = pass(program);
+        let time = start.elapsed();
+        let (insns_after, blocks_after) = size(&program);
+        self.passes.push(PassStats {
+            name,
+            time,
+            insns_before,
+            insns_after,
+            blocks_before,
+            blocks_after,
+        });
+        program
+    }
+}
+
+impl Display for PipelineStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<14} {:>10} {:>13} {:>12} {:>14}",
+            "pass", "time (ms)", "insns before", "insns after", "blocks removed"
+        )?;
+        for p in &self.passes {
+            writeln!(
+                f,
+                "{:<14} {:>10.3} {:>13} {:>12} {:>14}",
+                p.name,
+                p.time.as_secs_f64() * 1000.0,
+                p.insns_before,
+                p.insns_after,
+                p.blocks_removed()
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<14} {:>10.3}",
+            "total",
+            self.total_time().as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// The number of instructions and blocks of a program.  Terminators count as
+/// instructions.
+fn size(program: &Program) -> (usize, usize) {
+    let blocks = program.func.values().flat_map(|f| f.block.values());
+    blocks.fold((0, 0), |(insns, blocks), b| {
+        (insns + b.insn.len() + b.term.len(), blocks + 1)
+    })
+}
+
+/// Verifies the program between passes.
+struct Checker {
+    on: bool,
+    /// Whether the program was well-formed after the last check.
+    well_formed: bool,
+}
+
+impl Checker {
+    /// Check the program after a pass, or before the first pass if `pass` is
+    /// `None`.  Panics if the pass broke a well-formed program.
+    fn check(&mut self, pass: Option<&str>, program: &Program, in_ssa: bool) {
+        if !self.on {
+            return;
+        }
+        let result = if in_ssa {
+            verify_ssa(program)
+        } else {
+            verify(program)
+        };
+        match (result, pass) {
+            (Err(e), Some(pass)) if self.well_formed => {
+                panic!("Pass `{pass}` produced malformed tiny IR.\n{e}")
+            }
+            (result, _) => self.well_formed = result.is_ok(),
+        }
+    }
+}
+
+/// Run a pass, repeating it if it asks for a fixed point.
+fn run_pass(pass: &Pass, program: Program, options: &Options) -> Program {
+    if !pass.fixed_point {
+        return (pass.run)(program, options);
+    }
+    let (program, runs) = iterate(pass, program, options);
+    match runs {
+        Some(i) => log::trace!(
+            "Pass `{}` stopped changing the program after {i} runs.",
+            pass.name
+        ),
+        None => log::warn!(
+            "Pass `{}` was still changing the program after {MAX_ITERATIONS} runs, so it \
+             didn't reach a fixed point.",
+            pass.name
+        ),
+    }
+    program
+}
+
+/// Run a pass until it stops changing the program, at most `MAX_ITERATIONS`
+/// times.  Also returns how many runs that took, or `None` if the pass never
+/// stopped.
+fn iterate(pass: &Pass, mut program: Program, options: &Options) -> (Program, Option<usize>) {
+    for i in 1..=MAX_ITERATIONS {
+        let next = (pass.run)(program.clone(), options);
+        if next == program {
+            return (program, Some(i));
+        }
+        program = next;
+    }
+    (program, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn levels_name_known_passes() {
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            assert_eq!(
+                Pipeline::for_level(level).names(),
+                level.pass_names().to_vec()
+            );
+        }
+        assert_eq!(OptLevel::new(3), Some(OptLevel::O3));
+        assert_eq!(OptLevel::new(4), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Pipeline::parse("sccp, dce,").unwrap().names(),
+            ["sccp", "dce"]
+        );
+        assert_eq!(Pipeline::parse("").unwrap().names(), Vec::<&str>::new());
+        assert!(Pipeline::parse("sccp,constfold")
+            .unwrap_err()
+            .starts_with("Unknown pass `constfold`."));
+    }
+
+    #[test]
+    fn stats() {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.branch("x", "yes", "no");
+        b.block("yes");
+        b.print("x");
+        b.exit();
+        b.block("no");
+        b.exit();
+        let p = b.finish();
+        let (_, stats) = Pipeline::parse("sccp,simplify-cfg,dce")
+            .unwrap()
+            .run_with_stats(p, &Options::default());
+        let names = stats.passes.iter().map(|p| p.name).collect::<Vec<_>>();
+        assert_eq!(names, [INTO_SSA, "sccp", "simplify-cfg", "dce", OUT_OF_SSA]);
+        // SCCP removes the block that is never reached, and the CFG
+        // simplification merges the blocks that are left.
+        let (sccp, cfg) = (&stats.passes[1], &stats.passes[2]);
+        assert_eq!((sccp.blocks_before, sccp.blocks_after), (3, 2));
+        assert_eq!(cfg.blocks_removed(), 1);
+        assert_eq!(stats.passes[0].insns_before, 5);
+        assert_eq!(stats.to_string().lines().count(), 7);
+    }
+
+    #[test]
+    fn observe() {
+        let mut seen = vec![];
+        let (q, _) = Pipeline::parse("sccp,unroll").unwrap().run_observed(
+            small(),
+            &Options::default(),
+            None,
+            |name, p| seen.push((name, p.clone())),
+        );
+        let names = seen.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(names, [INTO_SSA, "sccp", OUT_OF_SSA, "unroll"]);
+        assert_eq!(seen.last().unwrap().1, q);
+    }
+
+    /// Jump to a block that doesn't exist.
+    static BROKEN: Pass = Pass {
+        name: "broken",
+        form: Form::Either,
+        fixed_point: false,
+        run: |mut p, _| {
+            let main = p.func.get_mut(&Program::main()).unwrap();
+            let entry = main.block.get_mut(&Function::entry()).unwrap();
+            entry.term = vec![Terminator::Jump(BlockId(99))];
+            p
+        },
+    };
+
+    fn small() -> Program {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.print("x");
+        b.exit();
+        b.finish()
+    }
+
+    #[test]
+    #[should_panic(expected = "Pass `broken` produced malformed tiny IR.")]
+    fn verify_each() {
+        let pipeline = Pipeline {
+            passes: vec![find_pass("dce").unwrap(), &BROKEN],
+        };
+        let options = Options {
+            verify_each: true,
+            ..Options::default()
+        };
+        pipeline.run(small(), &options);
+    }
+
+    #[test]
+    fn verify_each_off() {
+        let pipeline = Pipeline {
+            passes: vec![&BROKEN],
+        };
+        let options = Options {
+            verify_each: false,
+            ..Options::default()
+        };
+        assert!(verify(&pipeline.run(small(), &options)).is_err());
+    }
+
+    #[test]
+    fn fixed_points() {
+        let options = Options::default();
+        for seed in 0..100 {
+            let p = crate::middle::opt::tests::random_program(seed);
+            let ssa = ssa::construct(p.clone());
+            for pass in PASSES.iter().filter(|pass| pass.fixed_point) {
+                let p = match pass.form {
+                    Form::Ssa | Form::Either => ssa.clone(),
+                    Form::NotSsa => p.clone(),
+                };
+                let (_, runs) = iterate(pass, p, &options);
+                assert!(
+                    runs.is_some(),
+                    "`{}` doesn't settle on seed {seed}",
+                    pass.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn converts_between_forms() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("two", 2);
+        b.arith(BOp::Mul, "y", "x", "two");
+        b.arith(BOp::Mul, "y", "y", "two");
+        b.print("y");
+        b.exit();
+        let p = b.finish();
+        let q = Pipeline::parse("strength,unroll,sccp")
+            .unwrap()
+            .run(p.clone(), &Options::default());
+        // The result is out of SSA form, so this would fail on phis.
+        verify(&q).unwrap();
+        let main = &q.func[&Program::main()];
+        assert!(main.block[&Function::entry()]
+            .insn
+            .iter()
+            .any(|i| matches!(i, Instruction::Shift { .. })));
+        assert_eq!(
+            Pipeline::for_level(OptLevel::O0).run(p.clone(), &Options::default()),
+            p
+        );
+    }
+}
@@ -0,0 +1,881 @@
+//! Peephole optimization with declarative rules.
+//!
+//! A rule replaces a short run of consecutive instructions with others.  Both
+//! sides are written like tiny IR instructions separated by `;`, with names
+//! standing for variables:
+//!
+//! ```text
+//! $arith + t x #a; $arith + d t #b  =>  $const k a + b; $arith + d x k
+//! ```
+//!
+//! An operand `#5` matches a variable defined as the constant 5, and `#a`
+//! matches a variable defined as any constant, whose value is then called
+//! `a`.  The value of a `$const` can be a number, a constant bound by the
+//! pattern, or, in replacements, two of those combined with an operator.  A
+//! name used the same way twice has to match the same variable.
+//!
+//! The replacement assigns the variables it shares with the pattern, and new
+//! variables for its other names.  Variables the pattern assigns and the
+//! replacement doesn't are removed, so the rule only applies if they aren't
+//! used outside the window.
+//!
+//! Rules are applied until none matches, so every rule has to make the program
+//! simpler.  The function has to be in SSA form.  Copy propagation and dead
+//! code elimination clean up afterwards.
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::BOp;
+use crate::middle::interp;
+
+/// The rules `peephole` applies: a name, a pattern, and a replacement.
+const RULES: &[(&str, &str, &str)] = &[
+    ("add-zero", "$arith + d x #0", "$copy d x"),
+    ("zero-add", "$arith + d #0 x", "$copy d x"),
+    ("sub-zero", "$arith - d x #0", "$copy d x"),
+    ("sub-self", "$arith - d x x", "$const d 0"),
+    ("mul-one", "$arith * d x #1", "$copy d x"),
+    ("one-mul", "$arith * d #1 x", "$copy d x"),
+    ("mul-zero", "$arith * d x #0", "$const d 0"),
+    ("zero-mul", "$arith * d #0 x", "$const d 0"),
+    ("mul-minus-one", "$arith * d x #-1", "$arith - d #0 x"),
+    ("div-one", "$arith / d x #1", "$copy d x"),
+    ("div-minus-one", "$arith / d x #-1", "$arith - d #0 x"),
+    ("lt-self", "$arith < d x x", "$const d 0"),
+    (
+        "add-add",
+        "$arith + t x #a; $arith + d t #b",
+        "$const k a + b; $arith + d x k",
+    ),
+    ("neg-neg", "$arith - t #0 x; $arith - d #0 t", "$copy d x"),
+    (
+        "add-neg",
+        "$arith - t #0 y; $arith + d x t",
+        "$arith - d x y",
+    ),
+];
+
+/// The rules that turn arithmetic into other arithmetic that only gives the
+/// same result because overflows wrap around.  With `--trap-overflow`, they
+/// could add an error or take one away, so they're left out.
+const WRAPPING_RULES: &[&str] = &[
+    "mul-minus-one",
+    "div-minus-one",
+    "add-add",
+    "neg-neg",
+    "add-neg",
+];
+
+/// An operand of an instruction pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Var(String),
+    /// A variable defined as this constant.
+    Lit(i64),
+    /// A variable defined as some constant, whose value gets this name.
+    Const(String),
+}
+
+/// The value of a `$const` pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Lit(i64),
+    Bound(String),
+    /// Two constants combined, wrapping around like the program would.
+    Fold(BOp, Box<Value>, Box<Value>),
+}
+
+/// An instruction pattern.  The first name is the variable it assigns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pat {
+    Copy(String, Operand),
+    Const(String, Value),
+    Arith(BOp, String, Operand, Operand),
+    Shift(ShiftOp, String, Operand, u32),
+}
+
+/// A rewrite rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    pattern: Vec<Pat>,
+    replacement: Vec<Pat>,
+}
+
+impl Rule {
+    /// Parse a rule from its pattern and replacement.
+    pub fn new(name: &str, pattern: &str, replacement: &str) -> Result<Rule, String> {
+        let rule = Rule {
+            name: name.to_string(),
+            pattern: parse_pats(pattern, false)?,
+            replacement: parse_pats(replacement, true)?,
+        };
+        rule.check()
+            .map_err(|e| format!("Rule `{name}` is invalid: {e}"))?;
+        Ok(rule)
+    }
+
+    /// Make sure the replacement only uses what it has.
+    fn check(&self) -> Result<(), String> {
+        if self.pattern.is_empty() {
+            return Err("the pattern is empty".to_string());
+        }
+        let defs = self.pattern.iter().map(Pat::dst).collect::<Set<_>>();
+        let mut vars = Set::new();
+        let mut consts = Set::new();
+        for pat in &self.pattern {
+            for op in pat.operands() {
+                match op {
+                    Operand::Var(v) => vars.insert(v.as_str()),
+                    Operand::Const(c) => consts.insert(c.as_str()),
+                    Operand::Lit(_) => true,
+                };
+            }
+            if let Pat::Const(_, Value::Bound(c)) = pat {
+                consts.insert(c);
+            }
+        }
+        // Variables assigned in the window are gone unless the replacement
+        // assigns them again first.
+        let mut available = vars.difference(&defs).copied().collect::<Set<_>>();
+        for pat in &self.replacement {
+            for op in pat.operands() {
+                match op {
+                    Operand::Var(v) if !available.contains(v.as_str()) => {
+                        return Err(format!("`{v}` is used before it is assigned"))
+                    }
+                    Operand::Const(c) if !consts.contains(c.as_str()) => {
+                        return Err(format!("`#{c}` is not bound"))
+                    }
+                    _ => {}
+                }
+            }
+            if let Pat::Const(_, value) = pat {
+                value.check(&consts)?;
+            }
+            if vars.contains(pat.dst()) && !defs.contains(pat.dst()) {
+                return Err(format!("`{}` is assigned twice", pat.dst()));
+            }
+            available.insert(pat.dst());
+        }
+        Ok(())
+    }
+}
+
+/// The built-in rules that keep the errors of `traps`.
+pub fn default_rules(traps: Traps) -> Vec<Rule> {
+    RULES
+        .iter()
+        .filter(|(name, ..)| !(traps.overflow && WRAPPING_RULES.contains(name)))
+        .map(|(name, pattern, replacement)| Rule::new(name, pattern, replacement).unwrap())
+        .collect()
+}
+
+/// Apply the built-in rules to every function of an SSA program.
+pub fn peephole(program: Program, traps: Traps) -> Program {
+    let rules = default_rules(traps);
+    program.map_functions(|func| apply_rules(func, &rules))
+}
+
+/// Apply the built-in rules to a function in SSA form.
+pub fn peephole_function(func: Function, traps: Traps) -> Function {
+    apply_rules(func, &default_rules(traps))
+}
+
+/// Apply the given rules to a function in SSA form until none matches.
+/// Earlier rules take precedence.
+pub fn apply_rules(func: Function, rules: &[Rule]) -> Function {
+    let n = func.names.num_values();
+    let mut p = Peephole {
+        func,
+        constant: vec![None; n],
+        uses: vec![0; n],
+    };
+    for block in p.func.block.values() {
+        for insn in &block.insn {
+            if let Instruction::Const { dst, src } = insn {
+                p.constant[dst.0 as usize] = Some(*src);
+            }
+            for v in insn.uses() {
+                p.uses[v.0 as usize] += 1;
+            }
+        }
+        for v in block.term.iter().flat_map(Terminator::uses) {
+            p.uses[v.0 as usize] += 1;
+        }
+    }
+
+    let longest = rules.iter().map(|r| r.pattern.len()).max().unwrap_or(1);
+    let names = p.func.block.keys().copied().collect::<Vec<_>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for name in &names {
+            let mut pos = 0;
+            while pos < p.func.block[name].insn.len() {
+                let found = rules
+                    .iter()
+                    .find_map(|r| p.matches(r, *name, pos).map(|m| (r, m)));
+                match found {
+                    Some((rule, m)) => {
+                        p.rewrite(rule, m, *name, pos);
+                        changed = true;
+                        // The new instructions may complete earlier windows.
+                        pos = pos.saturating_sub(longest - 1);
+                    }
+                    None => pos += 1,
+                }
+            }
+        }
+    }
+    p.func
+}
+
+/// What the names of a pattern matched.
+#[derive(Default)]
+struct Match {
+    vars: Map<String, ValueId>,
+    consts: Map<String, i64>,
+    /// The variables matched by `#name` operands.
+    const_vars: Map<String, ValueId>,
+}
+
+struct Peephole {
+    func: Function,
+    /// The value of each variable defined as a constant.
+    constant: Vec<Option<i64>>,
+    /// The number of times each variable is used.
+    uses: Vec<usize>,
+}
+
+impl Peephole {
+    /// Match a rule against the instructions of a block starting at `pos`.
+    fn matches(&self, rule: &Rule, name: BlockId, pos: usize) -> Option<Match> {
+        let window = self.func.block[&name]
+            .insn
+            .get(pos..pos + rule.pattern.len())?;
+        let mut m = Match::default();
+        for (pat, insn) in rule.pattern.iter().zip(window) {
+            self.match_insn(pat, insn, &mut m)?;
+        }
+
+        // Variables that disappear can't be used outside the window.
+        let kept = rule
+            .replacement
+            .iter()
+            .map(|pat| pat.dst())
+            .collect::<Set<_>>();
+        for pat in &rule.pattern {
+            if kept.contains(pat.dst()) {
+                continue;
+            }
+            let v = m.vars[pat.dst()];
+            let inside = window
+                .iter()
+                .flat_map(|i| i.uses())
+                .filter(|u| *u == v)
+                .count();
+            if self.uses[v.0 as usize] != inside {
+                return None;
+            }
+        }
+        Some(m)
+    }
+
+    fn match_insn(&self, pat: &Pat, insn: &Instruction, m: &mut Match) -> Option<()> {
+        use Instruction as I;
+        match (pat, insn) {
+            (Pat::Copy(d, s), I::Copy { dst, src }) => {
+                self.bind(m, d, *dst)?;
+                self.match_operand(m, s, *src)
+            }
+            (Pat::Const(d, value), I::Const { dst, src }) => {
+                self.bind(m, d, *dst)?;
+                match value {
+                    Value::Lit(c) => (c == src).then_some(()),
+                    Value::Bound(c) => bind_const(m, c, *src),
+                    Value::Fold(..) => None,
+                }
+            }
+            (Pat::Arith(o, d, l, r), I::Arith { op, dst, lhs, rhs }) if o == op => {
+                self.bind(m, d, *dst)?;
+                self.match_operand(m, l, *lhs)?;
+                self.match_operand(m, r, *rhs)
+            }
+            (
+                Pat::Shift(o, d, s, a),
+                I::Shift {
+                    op,
+                    dst,
+                    src,
+                    amount,
+                },
+            ) if o == op && a == amount => {
+                self.bind(m, d, *dst)?;
+                self.match_operand(m, s, *src)
+            }
+            _ => None,
+        }
+    }
+
+    fn match_operand(&self, m: &mut Match, op: &Operand, v: ValueId) -> Option<()> {
+        match op {
+            Operand::Var(name) => self.bind(m, name, v),
+            Operand::Lit(c) => (self.constant[v.0 as usize] == Some(*c)).then_some(()),
+            Operand::Const(c) => {
+                bind_const(m, c, self.constant[v.0 as usize]?)?;
+                m.const_vars.entry(c.clone()).or_insert(v);
+                Some(())
+            }
+        }
+    }
+
+    fn bind(&self, m: &mut Match, name: &str, v: ValueId) -> Option<()> {
+        let bound = *m.vars.entry(name.to_string()).or_insert(v);
+        (bound == v).then_some(())
+    }
+
+    /// Replace the window a rule matched.
+    fn rewrite(&mut self, rule: &Rule, mut m: Match, name: BlockId, pos: usize) {
+        let mut insn = vec![];
+        for pat in &rule.replacement {
+            let dst = match m.vars.get(pat.dst()) {
+                Some(v) => *v,
+                None => {
+                    let v = self.fresh();
+                    m.vars.insert(pat.dst().to_string(), v);
+                    v
+                }
+            };
+            let new = match pat {
+                Pat::Copy(_, s) => Instruction::Copy {
+                    dst,
+                    src: self.operand(&m, s, &mut insn),
+                },
+                Pat::Const(_, value) => Instruction::Const {
+                    dst,
+                    src: value.eval(&m.consts),
+                },
+                Pat::Arith(op, _, l, r) => Instruction::Arith {
+                    op: *op,
+                    dst,
+                    lhs: self.operand(&m, l, &mut insn),
+                    rhs: self.operand(&m, r, &mut insn),
+                },
+                Pat::Shift(op, _, s, amount) => Instruction::Shift {
+                    op: *op,
+                    dst,
+                    src: self.operand(&m, s, &mut insn),
+                    amount: *amount,
+                },
+            };
+            insn.push(new);
+        }
+
+        let block = self.func.block.get_mut(&name).unwrap();
+        let old = block
+            .insn
+            .splice(pos..pos + rule.pattern.len(), insn.iter().cloned())
+            .collect::<Vec<_>>();
+        for i in &old {
+            for v in i.uses() {
+                self.uses[v.0 as usize] -= 1;
+            }
+            if let Some(dst) = i.def() {
+                self.constant[dst.0 as usize] = None;
+            }
+        }
+        for i in &insn {
+            for v in i.uses() {
+                self.uses[v.0 as usize] += 1;
+            }
+            if let Instruction::Const { dst, src } = i {
+                self.constant[dst.0 as usize] = Some(*src);
+            }
+        }
+    }
+
+    /// The variable for an operand of a replacement.  Constants that the
+    /// pattern didn't match a variable for are added before the instruction.
+    fn operand(&mut self, m: &Match, op: &Operand, insn: &mut Vec<Instruction>) -> ValueId {
+        let value = match op {
+            Operand::Var(name) => return m.vars[name],
+            Operand::Const(c) if m.const_vars.contains_key(c) => return m.const_vars[c],
+            Operand::Const(c) => m.consts[c],
+            Operand::Lit(c) => *c,
+        };
+        let dst = self.fresh();
+        insn.push(Instruction::Const { dst, src: value });
+        dst
+    }
+
+    fn fresh(&mut self) -> ValueId {
+        self.constant.push(None);
+        self.uses.push(0);
+        self.func.new_var("$tmp", Type::I64)
+    }
+}
+
+fn bind_const(m: &mut Match, name: &str, c: i64) -> Option<()> {
+    let bound = *m.consts.entry(name.to_string()).or_insert(c);
+    (bound == c).then_some(())
+}
+
+impl Pat {
+    fn dst(&self) -> &str {
+        match self {
+            Pat::Copy(d, _)
+            | Pat::Const(d, _)
+            | Pat::Arith(_, d, _, _)
+            | Pat::Shift(_, d, _, _) => d,
+        }
+    }
+
+    fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Pat::Copy(_, s) | Pat::Shift(_, _, s, _) => vec![s],
+            Pat::Const(..) => vec![],
+            Pat::Arith(_, _, l, r) => vec![l, r],
+        }
+    }
+}
+
+impl Value {
+    fn eval(&self, consts: &Map<String, i64>) -> i64 {
+        match self {
+            Value::Lit(c) => *c,
+            Value::Bound(c) => consts[c],
+            Value::Fold(op, l, r) => interp::arith(*op, l.eval(consts), r.eval(consts)),
+        }
+    }
+
+    fn check(&self, consts: &Set<&str>) -> Result<(), String> {
+        match self {
+            Value::Lit(_) => Ok(()),
+            Value::Bound(c) if consts.contains(c.as_str()) => Ok(()),
+            Value::Bound(c) => Err(format!("`{c}` is not bound")),
+            Value::Fold(_, l, r) => l.check(consts).and(r.check(consts)),
+        }
+    }
+}
+
+/// Parse instruction patterns separated by `;`.  Only replacements can
+/// combine constants.
+fn parse_pats(text: &str, replacement: bool) -> Result<Vec<Pat>, String> {
+    text.split(';')
+        .map(|p| parse_pat(&p.split_whitespace().collect::<Vec<_>>(), replacement))
+        .collect()
+}
+
+fn parse_pat(tokens: &[&str], replacement: bool) -> Result<Pat, String> {
+    let name = |t: &str| -> Result<String, String> {
+        match t.chars().next() {
+            Some(c) if c.is_alphabetic() || c == '_' => Ok(t.to_string()),
+            _ => Err(format!("`{t}` is not a name")),
+        }
+    };
+    let value = |t: &str| match t.parse() {
+        Ok(c) => Ok(Value::Lit(c)),
+        Err(_) => name(t).map(Value::Bound),
+    };
+    Ok(match tokens {
+        ["$copy", d, s] => Pat::Copy(name(d)?, parse_operand(s)?),
+        ["$const", d, c] => Pat::Const(name(d)?, value(c)?),
+        ["$const", d, l, op, r] if replacement => Pat::Const(
+            name(d)?,
+            Value::Fold(parse_bop(op)?, value(l)?.into(), value(r)?.into()),
+        ),
+        ["$arith", op, d, l, r] => Pat::Arith(
+            parse_bop(op)?,
+            name(d)?,
+            parse_operand(l)?,
+            parse_operand(r)?,
+        ),
+        ["$shift", op, d, s, a] => {
+            let op = match *op {
+                "<<" => ShiftOp::Left,
+                ">>" => ShiftOp::Arith,
+                ">>>" => ShiftOp::Logical,
+                _ => return Err(format!("`{op}` is not a shift")),
+            };
+            let amount = a
+                .parse()
+                .ok()
+                .filter(|a| *a < 64)
+                .ok_or(format!("`{a}` is not a shift amount"))?;
+            Pat::Shift(op, name(d)?, parse_operand(s)?, amount)
+        }
+        _ => {
+            return Err(format!(
+                "`{}` is not an instruction pattern",
+                tokens.join(" ")
+            ))
+        }
+    })
+}
+
+fn parse_operand(t: &str) -> Result<Operand, String> {
+    match t.strip_prefix('#') {
+        Some(c) => match c.parse() {
+            Ok(c) => Ok(Operand::Lit(c)),
+            Err(_) if !c.is_empty() => Ok(Operand::Const(c.to_string())),
+            Err(_) => Err("`#` needs a constant or a name".to_string()),
+        },
+        None if t.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+            Ok(Operand::Var(t.to_string()))
+        }
+        None => Err(format!("`{t}` is not an operand")),
+    }
+}
+
+fn parse_bop(t: &str) -> Result<BOp, String> {
+    Ok(match t {
+        "*" => BOp::Mul,
+        "/" => BOp::Div,
+        "+" => BOp::Add,
+        "-" => BOp::Sub,
+        "<" => BOp::Lt,
+        _ => return Err(format!("`{t}` is not an operator")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: helpers
+
+    fn rule(name: &str) -> Rule {
+        default_rules(Traps::default())
+            .into_iter()
+            .find(|r| r.name == name)
+            .unwrap()
+    }
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Read `x` and `y`, compute `d` with the instructions `body` adds, print
+    /// it, and apply a single rule.  Checks that the rule keeps the output
+    /// the same on the given inputs, and returns the instructions before the
+    /// `$print`.
+    fn check(name: &str, body: impl FnOnce(&mut Builder), inputs: &[&str]) -> Vec<String> {
+        let mut b = Builder::new();
+        b.read("x");
+        b.read("y");
+        body(&mut b);
+        b.print("d");
+        b.exit();
+        let p = b.finish();
+        let q = p.clone().map_functions(|f| apply_rules(f, &[rule(name)]));
+        verify_ssa(&q).unwrap();
+        for input in inputs {
+            assert_eq!(output(&p, input), output(&q, input), "input {input}");
+        }
+        let main = &q.func[&Program::main()];
+        main.block[&Function::entry()]
+            .insn
+            .iter()
+            .filter(|i| !matches!(i, Instruction::Print(_)))
+            .map(|i| i.display(&main.names).to_string())
+            .collect()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn builtin_rules_parse() {
+        assert_eq!(default_rules(Traps::default()).len(), RULES.len());
+    }
+
+    #[test]
+    fn overflows_stay_checked() {
+        let traps = Traps {
+            overflow: true,
+            ..Traps::default()
+        };
+        let names = default_rules(traps)
+            .into_iter()
+            .map(|r| r.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), RULES.len() - WRAPPING_RULES.len());
+        for name in WRAPPING_RULES {
+            assert!(!names.iter().any(|n| n == name), "{name}");
+        }
+        assert!(names.iter().any(|n| n == "mul-one"));
+
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("m", -1);
+        b.arith(BOp::Mul, "d", "x", "m");
+        b.print("d");
+        b.exit();
+        let main = peephole_function(b.finish_main(), traps);
+        assert!(matches!(
+            main.block[&Function::entry()].insn[2],
+            Instruction::Arith { op: BOp::Mul, .. }
+        ));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let err = |p, r| Rule::new("bad", p, r).unwrap_err();
+        assert_eq!(
+            err("$arith + t x #a; $arith + d t #b", "$copy d t"),
+            "Rule `bad` is invalid: `t` is used before it is assigned"
+        );
+        assert_eq!(
+            err("$arith + d x #0", "$const d k"),
+            "Rule `bad` is invalid: `k` is not bound"
+        );
+        assert_eq!(
+            err("$arith + d x #0", "$copy x d"),
+            "Rule `bad` is invalid: `d` is used before it is assigned"
+        );
+        assert_eq!(
+            err("$arith + d x #0", "$copy d x; $const x 1"),
+            "Rule `bad` is invalid: `x` is assigned twice"
+        );
+        assert_eq!(
+            err("$const d 1 + 2", "$copy d d"),
+            "`$const d 1 + 2` is not an instruction pattern"
+        );
+        assert_eq!(err("$arith % d x y", "$copy d x"), "`%` is not an operator");
+    }
+
+    #[test]
+    fn add_zero() {
+        let insn = check(
+            "add-zero",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Add, "d", "x", "zero");
+            },
+            &["5 0", "-3 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn zero_add() {
+        let insn = check(
+            "zero-add",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Add, "d", "zero", "x");
+            },
+            &["5 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn sub_zero() {
+        let insn = check(
+            "sub-zero",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Sub, "d", "x", "zero");
+            },
+            &["5 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn sub_self() {
+        let insn = check(
+            "sub-self",
+            |b| b.arith(BOp::Sub, "d", "x", "x"),
+            &["5 0", "-9 0"],
+        );
+        assert_eq!(insn[2], "$const d 0");
+    }
+
+    #[test]
+    fn mul_one() {
+        let insn = check(
+            "mul-one",
+            |b| {
+                b.constant("one", 1);
+                b.arith(BOp::Mul, "d", "x", "one");
+            },
+            &["7 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn one_mul() {
+        let insn = check(
+            "one-mul",
+            |b| {
+                b.constant("one", 1);
+                b.arith(BOp::Mul, "d", "one", "x");
+            },
+            &["7 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn mul_zero() {
+        let insn = check(
+            "mul-zero",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Mul, "d", "x", "zero");
+            },
+            &["7 0"],
+        );
+        assert_eq!(insn[3], "$const d 0");
+    }
+
+    #[test]
+    fn zero_mul() {
+        let insn = check(
+            "zero-mul",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Mul, "d", "zero", "x");
+            },
+            &["7 0"],
+        );
+        assert_eq!(insn[3], "$const d 0");
+    }
+
+    #[test]
+    fn mul_minus_one() {
+        let min = format!("{} 0", i64::MIN);
+        let insn = check(
+            "mul-minus-one",
+            |b| {
+                b.constant("m", -1);
+                b.arith(BOp::Mul, "d", "x", "m");
+            },
+            &["7 0", "-4 0", &min],
+        );
+        assert_eq!(insn[3..], ["$const $tmp 0", "$arith - d $tmp x"]);
+    }
+
+    #[test]
+    fn div_one() {
+        let insn = check(
+            "div-one",
+            |b| {
+                b.constant("one", 1);
+                b.arith(BOp::Div, "d", "x", "one");
+            },
+            &["7 0"],
+        );
+        assert_eq!(insn[3], "$copy d x");
+    }
+
+    #[test]
+    fn div_minus_one() {
+        // Dividing the smallest number by -1 overflows to itself.
+        let min = format!("{} 0", i64::MIN);
+        let insn = check(
+            "div-minus-one",
+            |b| {
+                b.constant("m", -1);
+                b.arith(BOp::Div, "d", "x", "m");
+            },
+            &["7 0", &min],
+        );
+        assert_eq!(insn[4], "$arith - d $tmp x");
+    }
+
+    #[test]
+    fn lt_self() {
+        let insn = check("lt-self", |b| b.arith(BOp::Lt, "d", "x", "x"), &["7 0"]);
+        assert_eq!(insn[2], "$const d 0");
+    }
+
+    #[test]
+    fn add_add() {
+        let max = format!("{} 0", i64::MAX);
+        let insn = check(
+            "add-add",
+            |b| {
+                b.constant("a", 3);
+                b.constant("b", 4);
+                b.arith(BOp::Add, "t", "x", "a");
+                b.arith(BOp::Add, "d", "t", "b");
+            },
+            &["1 0", &max],
+        );
+        assert_eq!(insn[4..], ["$const $tmp 7", "$arith + d x $tmp"]);
+    }
+
+    #[test]
+    fn add_add_needs_dead_intermediate() {
+        // `t` is printed too, so it has to stay.
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("a", 3);
+        b.arith(BOp::Add, "t", "x", "a");
+        b.arith(BOp::Add, "d", "t", "a");
+        b.print("t");
+        b.print("d");
+        b.exit();
+        let main = b.finish_main();
+        assert_eq!(apply_rules(main.clone(), &[rule("add-add")]), main);
+    }
+
+    #[test]
+    fn neg_neg() {
+        let insn = check(
+            "neg-neg",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Sub, "t", "zero", "x");
+                b.arith(BOp::Sub, "d", "zero", "t");
+            },
+            &["5 0", "-5 0"],
+        );
+        assert_eq!(insn[3..], ["$copy d x"]);
+    }
+
+    #[test]
+    fn add_neg() {
+        let insn = check(
+            "add-neg",
+            |b| {
+                b.constant("zero", 0);
+                b.arith(BOp::Sub, "t", "zero", "y");
+                b.arith(BOp::Add, "d", "x", "t");
+            },
+            &["5 2", "-5 7"],
+        );
+        assert_eq!(insn[3..], ["$arith - d x y"]);
+    }
+
+    #[test]
+    fn chains() {
+        // `x + 1 + 2 + 3` becomes `x + 6`, with the later rules seeing the
+        // results of the earlier ones.
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("one", 1);
+        b.constant("two", 2);
+        b.constant("three", 3);
+        b.arith(BOp::Add, "a", "x", "one");
+        b.arith(BOp::Add, "b", "a", "two");
+        b.arith(BOp::Add, "c", "b", "three");
+        b.print("c");
+        b.exit();
+        let main = peephole_function(b.finish_main(), Traps::default());
+        let insn = &main.block[&Function::entry()].insn;
+        assert_eq!(insn.len(), 8);
+        assert_eq!(
+            insn[6].display(&main.names).to_string(),
+            "$arith + c x $tmp.1"
+        );
+        let p = Program::from_main(main);
+        assert_eq!(output(&p, "10"), "16\n");
+    }
+}
@@ -0,0 +1,174 @@
+//! Optimization remarks: what each pass did, and where.
+//!
+//! The pass manager compares the program before and after each pass, and
+//! reports the blocks the pass removed or added and the blocks whose
+//! instructions it changed.  Programs don't carry source locations yet, so
+//! remarks point at functions and blocks.
+
+use std::fmt::{Display, Formatter};
+
+use serde_json::json;
+
+use super::*;
+use crate::common::*;
+
+/// Something a pass did to a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remark {
+    pub pass: &'static str,
+    pub function: Id,
+    /// The block the remark is about, if it is about one.
+    pub block: Option<Id>,
+    pub message: String,
+}
+
+impl Display for Remark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)?;
+        if let Some(block) = self.block {
+            write!(f, ":{block}")?;
+        }
+        write!(f, ": [{}] {}", self.pass, self.message)
+    }
+}
+
+impl Remark {
+    /// The remark as a JSON object.
+    pub fn to_json(&self) -> String {
+        json!({
+            "pass": self.pass,
+            "function": self.function.to_string(),
+            "block": self.block.map(|b| b.to_string()),
+            "message": self.message,
+        })
+        .to_string()
+    }
+}
+
+/// Remarks as a JSON array, one per line.
+pub fn remarks_to_json(remarks: &[Remark]) -> String {
+    if remarks.is_empty() {
+        return "[]\n".to_string();
+    }
+    let items = remarks
+        .iter()
+        .map(|r| format!("  {}", r.to_json()))
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
+
+/// The remarks for what a pass changed between two versions of a program.
+pub(super) fn diff(pass: &'static str, before: &Program, after: &Program) -> Vec<Remark> {
+    let mut remarks = vec![];
+    for (name, old) in &before.func {
+        let Some(new) = after.func.get(name) else {
+            continue;
+        };
+        let mut remark = |block: Id, message: String| {
+            remarks.push(Remark {
+                pass,
+                function: *name,
+                block: Some(block),
+                message,
+            })
+        };
+        let old_blocks = by_name(old);
+        let new_blocks = by_name(new);
+        let old_map = old_blocks.iter().cloned().collect::<Map<_, _>>();
+        let new_map = new_blocks.iter().cloned().collect::<Map<_, _>>();
+        for (block, insn) in &old_blocks {
+            match new_map.get(block) {
+                None => remark(*block, format!("removed the block ({})", count(insn.len()))),
+                Some(new_insn) if new_insn.len() < insn.len() => remark(
+                    *block,
+                    format!("removed {}", count(insn.len() - new_insn.len())),
+                ),
+                Some(new_insn) if new_insn.len() > insn.len() => remark(
+                    *block,
+                    format!("added {}", count(new_insn.len() - insn.len())),
+                ),
+                Some(new_insn) if new_insn != insn => {
+                    remark(*block, "rewrote instructions".to_string())
+                }
+                Some(_) => {}
+            }
+        }
+        for (block, insn) in &new_blocks {
+            if !old_map.contains_key(block) {
+                remark(*block, format!("added the block ({})", count(insn.len())));
+            }
+        }
+    }
+    remarks
+}
+
+/// The name and printed instructions of each block, in order.  Printing makes
+/// blocks comparable across passes that renumber variables.
+fn by_name(func: &Function) -> Vec<(Id, Vec<String>)> {
+    func.block
+        .iter()
+        .map(|(id, block)| {
+            let insn = block
+                .insn
+                .iter()
+                .map(|i| i.display(&func.names).to_string())
+                .collect();
+            (func.names.block(*id), insn)
+        })
+        .collect()
+}
+
+fn count(n: usize) -> String {
+    match n {
+        1 => "1 instruction".to_string(),
+        n => format!("{n} instructions"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn blocks_and_instructions() {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.constant("y", 2);
+        b.arith(BOp::Add, "z", "x", "y");
+        b.branch("x", "yes", "no");
+        b.block("yes");
+        b.print("z");
+        b.exit();
+        b.block("no");
+        b.exit();
+        let p = b.finish();
+        let (_, _, remarks) = Pipeline::parse("sccp,dce")
+            .unwrap()
+            .run_with_remarks(p, &Options::default());
+        let text = remarks.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [
+                "main:$entry: [sccp] rewrote instructions",
+                "main:no: [sccp] removed the block (0 instructions)",
+                "main:$entry: [dce] removed 2 instructions",
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let remark = Remark {
+            pass: "dce",
+            function: Id::from_ref("f"),
+            block: None,
+            message: "said \"hi\"".to_string(),
+        };
+        assert_eq!(
+            remarks_to_json(&[remark]),
+            "[\n  {\"pass\":\"dce\",\"function\":\"f\",\"block\":null,\"message\":\"said \\\"hi\\\"\"}\n]\n"
+        );
+        assert_eq!(remarks_to_json(&[]), "[]\n");
+    }
+}
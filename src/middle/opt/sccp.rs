@@ -0,0 +1,434 @@
+//! Sparse conditional constant propagation.
+//!
+//! This is the algorithm from Wegman and Zadeck, "Constant Propagation with
+//! Conditional Branches".  It finds the variables that hold the same constant
+//! on every path, and at the same time the CFG edges that can never be taken
+//! because they depend on a constant guard.  Propagating both at once finds
+//! more constants than doing either alone, since values coming from dead
+//! edges don't count at phis.
+//!
+//! The function has to be in SSA form.  Afterwards, constant variables are
+//! defined by `$const` instructions, branches on constants become jumps, and
+//! blocks that can never run are removed.
+//!
+//! Operations on constants that are errors, like dividing by zero with
+//! `--trap-div-zero` or overflowing with `--trap-overflow`, are left to the
+//! program, so that it reports them.
+
+use super::*;
+use crate::common::*;
+use crate::middle::interp::shift;
+
+/// What we know about the value of a variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    /// We haven't seen a definition that runs yet.
+    Unknown,
+    Const(i64),
+    /// The variable may hold different values.
+    Varying,
+}
+
+impl Value {
+    fn meet(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Unknown, v) | (v, Value::Unknown) => v,
+            (Value::Const(a), Value::Const(b)) if a == b => Value::Const(a),
+            _ => Value::Varying,
+        }
+    }
+}
+
+/// Run SCCP on every function of an SSA program.
+pub fn sccp(program: Program, traps: Traps) -> Program {
+    program.map_functions(|func| sccp_function(func, traps))
+}
+
+/// Run SCCP on a function in SSA form.
+pub fn sccp_function(mut func: Function, traps: Traps) -> Function {
+    let mut s = Solver::new(&func, traps);
+    s.solve();
+    let Solver {
+        values, executable, ..
+    } = s;
+
+    func.block.retain(|b, _| executable.contains(b));
+    for block in func.block.values_mut() {
+        // Constant phis become constants after the remaining phis.
+        let n_phis = block.insn.iter().take_while(|i| i.is_phi()).count();
+        let mut consts = vec![];
+        let mut insn = vec![];
+        for (i, old) in block.insn.drain(..).enumerate() {
+            let constant = old.def().and_then(|dst| match values[dst.0 as usize] {
+                Value::Const(c) if !old.has_side_effects() => Some((dst, c)),
+                _ => None,
+            });
+            match constant {
+                Some((dst, src)) if i < n_phis => consts.push(Instruction::Const { dst, src }),
+                Some((dst, src)) => insn.push(Instruction::Const { dst, src }),
+                None => insn.push(old),
+            }
+            if i + 1 == n_phis {
+                insn.append(&mut consts);
+            }
+        }
+        block.insn = insn;
+
+        for t in &mut block.term {
+            if let Terminator::Branch { guard, tt, ff } = t {
+                if let Value::Const(c) = values[guard.0 as usize] {
+                    *t = Terminator::Jump(if c != 0 { *tt } else { *ff });
+                }
+            }
+        }
+    }
+
+    // Drop the phi arguments from edges that are gone.
+    let preds = func.predecessors();
+    for (name, block) in &mut func.block {
+        for insn in &mut block.insn {
+            if let Instruction::Phi { args, .. } = insn {
+                args.retain(|pred, _| preds[name].contains(pred));
+            }
+        }
+    }
+    func
+}
+
+/// Where a variable is used: a block and the index of the instruction, or
+/// `None` for the terminator.
+type Use = (BlockId, Option<usize>);
+
+struct Solver<'a> {
+    func: &'a Function,
+    traps: Traps,
+    values: Vec<Value>,
+    uses: Vec<Vec<Use>>,
+    executable: Set<BlockId>,
+    edges: Set<(BlockId, BlockId)>,
+    /// The targets of edges that became executable, which we have to visit.
+    flow_worklist: Vec<BlockId>,
+    /// Variables whose value changed, and whose uses we have to revisit.
+    value_worklist: Vec<ValueId>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(func: &'a Function, traps: Traps) -> Self {
+        let n = func.names.num_values();
+        // Variables that are never defined keep their initial value, which is
+        // zero for everything but the parameters.
+        let mut values = vec![Value::Const(0); n];
+        let mut uses = vec![vec![]; n];
+        for (name, block) in &func.block {
+            for (i, insn) in block.insn.iter().enumerate() {
+                if let Some(dst) = insn.def() {
+                    values[dst.0 as usize] = Value::Unknown;
+                }
+                for u in insn.uses() {
+                    uses[u.0 as usize].push((*name, Some(i)));
+                }
+            }
+            for u in block.term.iter().flat_map(Terminator::uses) {
+                uses[u.0 as usize].push((*name, None));
+            }
+        }
+        for p in &func.params {
+            values[p.0 as usize] = Value::Varying;
+        }
+        Solver {
+            func,
+            traps,
+            values,
+            uses,
+            executable: Set::new(),
+            edges: Set::new(),
+            flow_worklist: vec![],
+            value_worklist: vec![],
+        }
+    }
+
+    fn solve(&mut self) {
+        self.visit_block(Function::entry());
+        loop {
+            if let Some(to) = self.flow_worklist.pop() {
+                if self.executable.contains(&to) {
+                    // Only the phis depend on which edges are executable.
+                    self.visit_phis(to);
+                } else {
+                    self.visit_block(to);
+                }
+            } else if let Some(var) = self.value_worklist.pop() {
+                for (block, pos) in self.uses[var.0 as usize].clone() {
+                    if !self.executable.contains(&block) {
+                        continue;
+                    }
+                    match pos {
+                        Some(i) => self.visit_insn(block, i),
+                        None => self.visit_term(block),
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn visit_block(&mut self, name: BlockId) {
+        self.executable.insert(name);
+        for i in 0..self.func.block[&name].insn.len() {
+            self.visit_insn(name, i);
+        }
+        self.visit_term(name);
+    }
+
+    fn visit_phis(&mut self, name: BlockId) {
+        let block = &self.func.block[&name];
+        for i in 0..block.insn.iter().take_while(|i| i.is_phi()).count() {
+            self.visit_insn(name, i);
+        }
+    }
+
+    fn visit_insn(&mut self, name: BlockId, i: usize) {
+        use Instruction::*;
+        let insn = &self.func.block[&name].insn[i];
+        let get = |v: &ValueId| self.values[v.0 as usize];
+        let new = match insn {
+            Const { src, .. } => Value::Const(*src),
+            Copy { src, .. } => get(src),
+            Arith { op, lhs, rhs, .. } => match (get(lhs), get(rhs)) {
+                (Value::Const(a), Value::Const(b)) => match self.traps.fold(*op, a, b) {
+                    Some(c) => Value::Const(c),
+                    None => Value::Varying,
+                },
+                (Value::Unknown, _) | (_, Value::Unknown) => Value::Unknown,
+                _ => Value::Varying,
+            },
+            Shift {
+                op, src, amount, ..
+            } => match get(src) {
+                Value::Const(c) => Value::Const(shift(*op, c, *amount)),
+                v => v,
+            },
+            Phi { args, .. } => args
+                .iter()
+                .filter(|(pred, _)| self.edges.contains(&(**pred, name)))
+                .fold(Value::Unknown, |acc, (_, v)| acc.meet(get(v))),
+            Read(_) | Call { .. } | Alloc { .. } | Load { .. } => Value::Varying,
+            Print(_) | Store { .. } | Count(_) | Loc(_) => return,
+        };
+        let dst = insn.def().unwrap();
+        self.update(dst, new);
+    }
+
+    fn visit_term(&mut self, name: BlockId) {
+        let term = &self.func.block[&name].term[0];
+        let targets = match term {
+            Terminator::Branch { guard, tt, ff } => match self.values[guard.0 as usize] {
+                Value::Unknown => vec![],
+                Value::Const(c) if c != 0 => vec![*tt],
+                Value::Const(_) => vec![*ff],
+                Value::Varying => vec![*tt, *ff],
+            },
+            _ => term.successors(),
+        };
+        for to in targets {
+            if self.edges.insert((name, to)) {
+                self.flow_worklist.push(to);
+            }
+        }
+    }
+
+    /// Lower the value of a variable, and revisit its uses if it changed.
+    fn update(&mut self, var: ValueId, new: Value) {
+        let old = self.values[var.0 as usize];
+        // Values only go down the lattice, so meeting keeps the analysis
+        // monotone even if an instruction is visited out of order.
+        let new = old.meet(new);
+        if new != old {
+            self.values[var.0 as usize] = new;
+            self.value_worklist.push(var);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+    use crate::middle::interp;
+
+    // SECTION: helpers
+
+    fn optimize(p: Program) -> Program {
+        optimize_with(p, Traps::default())
+    }
+
+    fn optimize_with(p: Program, traps: Traps) -> Program {
+        let p = sccp(ssa::construct(p), traps);
+        verify_ssa(&p).unwrap();
+        p
+    }
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn folds_constant_branches() {
+        // := x 2; $if (< x 3) { := y (* x 10) } { $read y }; $print y
+        let mut b = Builder::new();
+        b.constant("x", 2);
+        b.constant("three", 3);
+        b.arith(BOp::Lt, "c", "x", "three");
+        b.branch("c", "then", "else");
+        b.block("then");
+        b.constant("ten", 10);
+        b.arith(BOp::Mul, "y", "x", "ten");
+        b.jump("join");
+        b.block("else");
+        b.read("y");
+        b.jump("join");
+        b.block("join");
+        b.print("y");
+        b.exit();
+        let p = optimize(b.finish());
+        let main = &p.func[&Program::main()];
+
+        assert_eq!(main.block.len(), 3);
+        assert!(!main.block.contains_key(&main.block_named("else")));
+        let entry = &main.block[&Function::entry()];
+        assert!(matches!(entry.term[0], Terminator::Jump(_)));
+        // The phi at the join only has one incoming value left, which is 20.
+        let join = &main.block[&main.block_named("join")];
+        let Instruction::Const { src, .. } = join.insn[0] else {
+            panic!("expected a constant, found {:?}", join.insn[0]);
+        };
+        assert_eq!(src, 20);
+        assert_eq!(output(&p, ""), "20\n");
+    }
+
+    #[test]
+    fn constants_through_phis() {
+        // Both sides assign the same constant, so the phi is constant too.
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "l", "r");
+        b.block("l");
+        b.constant("x", 4);
+        b.jump("join");
+        b.block("r");
+        b.constant("x", 4);
+        b.jump("join");
+        b.block("join");
+        b.constant("one", 1);
+        b.arith(BOp::Add, "y", "x", "one");
+        b.print("y");
+        b.exit();
+        let p = optimize(b.finish());
+        let main = &p.func[&Program::main()];
+        let join = &main.block[&main.block_named("join")];
+        assert!(join.insn.iter().all(|i| !i.is_phi()));
+        assert!(join.insn.contains(&Instruction::Const {
+            dst: main.var("y.1"),
+            src: 5
+        }));
+        assert_eq!(output(&p, "0"), "5\n");
+        assert_eq!(output(&p, "1"), "5\n");
+    }
+
+    #[test]
+    fn keeps_unknown_values() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.branch("x", "l", "r");
+        b.block("l");
+        b.print("x");
+        b.exit();
+        b.block("r");
+        // `u` is never assigned, so it is zero.
+        b.print("u");
+        b.exit();
+        let p = optimize(b.finish());
+        let main = &p.func[&Program::main()];
+        assert_eq!(main.block.len(), 3);
+        assert!(matches!(
+            main.block[&Function::entry()].term[0],
+            Terminator::Branch { .. }
+        ));
+        assert_eq!(output(&p, "3"), "3\n");
+        assert_eq!(output(&p, "0"), "0\n");
+    }
+
+    #[test]
+    fn parameters_vary() {
+        let mut b = Builder::new();
+        b.constant("a", 1);
+        b.call("r", "f", &["a"]);
+        b.print("r");
+        b.exit();
+        b.function("f", &["n"], Type::I64);
+        b.copy("m", "n");
+        b.ret("m");
+        let p = optimize(b.finish());
+        let f = &p.func[&Id::from_ref("f")];
+        assert!(matches!(
+            f.block[&Function::entry()].insn[0],
+            Instruction::Copy { .. }
+        ));
+        assert_eq!(output(&p, ""), "1\n");
+    }
+
+    #[test]
+    fn leaves_errors_to_the_program() {
+        let mut b = Builder::new();
+        b.constant("ten", 10);
+        b.constant("zero", 0);
+        b.arith(BOp::Div, "q", "ten", "zero");
+        b.print("q");
+        b.exit();
+        let p = b.finish();
+        let is_div = |i: &Instruction| matches!(i, Instruction::Arith { op: BOp::Div, .. });
+
+        let folded = optimize(p.clone());
+        let main = &folded.func[&Program::main()];
+        assert!(!main.block[&Function::entry()].insn.iter().any(is_div));
+        assert_eq!(output(&folded, ""), "-1\n");
+
+        let kept = optimize_with(
+            p,
+            Traps {
+                div_zero: true,
+                ..Traps::default()
+            },
+        );
+        let main = &kept.func[&Program::main()];
+        assert!(main.block[&Function::entry()].insn.iter().any(is_div));
+
+        let mut b = Builder::new();
+        b.constant("max", i64::MAX);
+        b.constant("two", 2);
+        b.arith(BOp::Mul, "p", "max", "two");
+        b.arith(BOp::Add, "q", "two", "two");
+        b.print("p");
+        b.print("q");
+        b.exit();
+        let kept = optimize_with(
+            b.finish(),
+            Traps {
+                overflow: true,
+                ..Traps::default()
+            },
+        );
+        let main = &kept.func[&Program::main()];
+        let entry = &main.block[&Function::entry()];
+        assert!(matches!(
+            entry.insn[2],
+            Instruction::Arith { op: BOp::Mul, .. }
+        ));
+        assert!(matches!(entry.insn[3], Instruction::Const { src: 4, .. }));
+    }
+}
@@ -0,0 +1,267 @@
+//! CFG cleanup.
+//!
+//! Other passes leave behind blocks that only jump somewhere else, branches
+//! whose targets are the same, and chains of blocks that always run one after
+//! the other.  This pass repeats three rewrites until none applies:
+//!
+//! - A branch to the same block on both sides becomes a jump.
+//! - Edges into an empty block that only jumps on go straight to where it
+//!   jumps to.
+//! - A block is merged into its predecessor if that is its only predecessor
+//!   and the predecessor only jumps to it.  Its phis then have one argument
+//!   each, so they become copies.
+//!
+//! Blocks that end up unreachable are removed at the end.  This works both in
+//! and out of SSA form.
+
+use super::*;
+use crate::common::*;
+
+/// Clean up the CFG of every function.
+pub fn simplify_cfg(program: Program) -> Program {
+    program.map_functions(simplify_cfg_function)
+}
+
+/// Clean up the CFG of a function.
+pub fn simplify_cfg_function(mut func: Function) -> Function {
+    loop {
+        let mut changed = fold_branches(&mut func);
+        changed |= thread_jumps(&mut func);
+        changed |= merge_blocks(&mut func);
+        if !changed {
+            return remove_unreachable_function(func);
+        }
+    }
+}
+
+/// Turn branches with the same target on both sides into jumps.
+fn fold_branches(func: &mut Function) -> bool {
+    let mut changed = false;
+    for t in func.block.values_mut().flat_map(|b| &mut b.term) {
+        if let Terminator::Branch { tt, ff, .. } = t {
+            if tt == ff {
+                *t = Terminator::Jump(*tt);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// The target of a block that does nothing but jump.  The entry block is
+/// never skipped, since it has to stay where execution starts.
+fn forwards_to(func: &Function, name: BlockId) -> Option<BlockId> {
+    let block = &func.block[&name];
+    match block.term[..] {
+        [Terminator::Jump(to)] if block.insn.is_empty() && name != Function::entry() => Some(to),
+        _ => None,
+    }
+}
+
+/// Retarget edges into forwarding blocks to the end of the forwarding chain.
+fn thread_jumps(func: &mut Function) -> bool {
+    let mut preds = func.predecessors();
+    let mut changed = false;
+    let names = func.block.keys().copied().collect::<Vec<_>>();
+    for from in names {
+        if forwards_to(func, from).is_none() {
+            continue;
+        }
+        // Follow the chain to a block that does something.  `last` is the
+        // forwarding block that jumps to it, which its phis refer to.
+        let mut seen = Set::new();
+        let (mut last, mut to) = (from, from);
+        while let Some(next) = forwards_to(func, to) {
+            if !seen.insert(to) {
+                break;
+            }
+            (last, to) = (to, next);
+        }
+        if seen.contains(&to) {
+            // The chain is an empty infinite loop.
+            continue;
+        }
+        let has_phis = func.block[&to].insn.first().is_some_and(|i| i.is_phi());
+        for pred in preds[&from].clone() {
+            // Phis have one argument per predecessor, so we can't add a
+            // second edge from the same block.
+            if has_phis && preds[&to].contains(&pred) {
+                continue;
+            }
+            for t in &mut func.block.get_mut(&pred).unwrap().term {
+                t.rename_targets(|b| if b == from { to } else { b });
+            }
+            for insn in &mut func.block.get_mut(&to).unwrap().insn {
+                if let Instruction::Phi { args, .. } = insn {
+                    let v = args[&last];
+                    args.insert(pred, v);
+                }
+            }
+            preds.get_mut(&from).unwrap().remove(&pred);
+            preds.get_mut(&to).unwrap().insert(pred);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Merge blocks into their only predecessor when it only jumps to them.
+fn merge_blocks(func: &mut Function) -> bool {
+    let mut preds = func.predecessors();
+    let mut changed = false;
+    let names = func.block.keys().copied().collect::<Vec<_>>();
+    for pred in names {
+        // `pred` may have been merged into an earlier block already.
+        while func.block.contains_key(&pred) {
+            let [Terminator::Jump(name)] = func.block[&pred].term[..] else {
+                break;
+            };
+            if name == pred || name == Function::entry() || preds[&name].len() != 1 {
+                break;
+            }
+            let Block { insn, term } = func.block.remove(&name).unwrap();
+            let insn = insn.into_iter().map(|insn| match insn {
+                Instruction::Phi { dst, args } => Instruction::Copy {
+                    dst,
+                    src: args[&pred],
+                },
+                insn => insn,
+            });
+            let block = func.block.get_mut(&pred).unwrap();
+            block.insn.extend(insn);
+            block.term = term;
+
+            for succ in block.successors() {
+                let succ_preds = preds.get_mut(&succ).unwrap();
+                succ_preds.remove(&name);
+                succ_preds.insert(pred);
+                for insn in &mut func.block.get_mut(&succ).unwrap().insn {
+                    if let Instruction::Phi { args, .. } = insn {
+                        if let Some(v) = args.remove(&name) {
+                            args.insert(pred, v);
+                        }
+                    }
+                }
+            }
+            preds.remove(&name);
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    #[test]
+    fn merges_chains() {
+        let mut b = Builder::new();
+        b.read("a");
+        b.jump("b");
+        b.block("b");
+        b.constant("one", 1);
+        b.jump("c");
+        b.block("c");
+        b.arith(BOp::Add, "x", "a", "one");
+        b.print("x");
+        b.exit();
+        let main = simplify_cfg_function(b.finish_main());
+        assert_eq!(main.block.len(), 1);
+        assert_eq!(main.names.num_blocks(), 1);
+        let entry = &main.block[&Function::entry()];
+        assert_eq!(entry.insn.len(), 4);
+        assert_eq!(entry.term, vec![Terminator::Exit(None)]);
+    }
+
+    #[test]
+    fn threads_through_forwarding_blocks() {
+        // `l` and `f` do nothing, so the branch goes straight to `join`, and
+        // the phi takes its argument from the entry block instead.
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "l", "r");
+        b.block("l");
+        b.jump("f");
+        b.block("f");
+        b.jump("join");
+        b.block("r");
+        b.read("z");
+        b.jump("join");
+        b.block("join");
+        b.phi("y", &[("f", "c"), ("r", "z")]);
+        b.print("y");
+        b.exit();
+        let main = simplify_cfg_function(b.finish_main());
+        verify_ssa(&Program::from_main(main.clone())).unwrap();
+        assert_eq!(main.block.len(), 3);
+        assert_eq!(main.names.find_block("l"), None);
+        assert_eq!(main.names.find_block("f"), None);
+        let join = main.block_named("join");
+        assert_eq!(
+            main.block[&Function::entry()].successors(),
+            vec![join, main.block_named("r")]
+        );
+        let Instruction::Phi { args, .. } = &main.block[&join].insn[0] else {
+            panic!("expected a phi");
+        };
+        assert_eq!(args[&Function::entry()], main.var("c"));
+    }
+
+    #[test]
+    fn keeps_edges_phis_tell_apart() {
+        // Both edges of the branch end up at `join`, but with different
+        // values, so one of them has to keep going through `l`.
+        let mut b = Builder::new();
+        b.read("c");
+        b.read("x");
+        b.branch("c", "l", "join");
+        b.block("l");
+        b.jump("join");
+        b.block("join");
+        b.phi("y", &[(ENTRY, "c"), ("l", "x")]);
+        b.print("y");
+        b.exit();
+        let main = b.finish_main();
+        assert_eq!(simplify_cfg_function(main.clone()), main);
+    }
+
+    #[test]
+    fn folds_branches_and_merges_phis() {
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "join", "join");
+        b.block("join");
+        b.phi("y", &[(ENTRY, "c")]);
+        b.print("y");
+        b.exit();
+        let main = simplify_cfg_function(b.finish_main());
+        let (c, y) = (main.var("c"), main.var("y"));
+        assert_eq!(main.block.len(), 1);
+        assert_eq!(
+            main.block[&Function::entry()].insn,
+            vec![
+                Instruction::Read(c),
+                Instruction::Copy { dst: y, src: c },
+                Instruction::Print(y)
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_empty_loops() {
+        let mut b = Builder::new();
+        b.jump("a");
+        b.block("a");
+        b.jump("b");
+        b.block("b");
+        b.jump("a");
+        // The verifier rejects loops, but the pass mustn't get stuck on them.
+        let main = simplify_cfg_function(b.finish_main());
+        let a = main.block_named("a");
+        assert_eq!(main.block.len(), 2);
+        assert_eq!(main.block[&Function::entry()].successors(), vec![a]);
+        assert_eq!(main.block[&a].successors(), vec![a]);
+    }
+}
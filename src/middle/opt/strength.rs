@@ -0,0 +1,210 @@
+//! Strength reduction of multiplications and divisions by powers of two.
+//!
+//! `mul` and `div` take many cycles on simple RISC-V cores, while shifts take
+//! one.  Multiplying by `2^k` is shifting left by `k`.  Dividing by `2^k` is
+//! an arithmetic shift right by `k` for nonnegative numbers, but division
+//! rounds towards zero, so negative numbers have to be biased by `2^k - 1`
+//! first:
+//!
+//! ```text
+//! sign = x >> 63         // -1 if x is negative, 0 otherwise
+//! bias = sign >>> 64 - k // 2^k - 1 if x is negative, 0 otherwise
+//! q = (x + bias) >> k
+//! ```
+//!
+//! Multiplying or dividing by one becomes a copy.  The function has to be in
+//! SSA form, so that each constant operand has a single definition.
+//!
+//! Shifts don't overflow, so with `--trap-overflow` multiplications stay
+//! as they are, for the program to check.
+
+use super::*;
+use crate::front::ast::BOp;
+
+/// Reduce the strength of arithmetic in every function of an SSA program.
+pub fn strength_reduce(program: Program, traps: Traps) -> Program {
+    program.map_functions(|func| strength_reduce_function(func, traps))
+}
+
+/// Reduce the strength of arithmetic in a function in SSA form.
+pub fn strength_reduce_function(mut func: Function, traps: Traps) -> Function {
+    let mut constant = vec![None; func.names.num_values()];
+    for insn in func.block.values().flat_map(|b| &b.insn) {
+        if let Instruction::Const { dst, src } = insn {
+            constant[dst.0 as usize] = Some(*src);
+        }
+    }
+    let power_of_two = |v: &ValueId| {
+        constant[v.0 as usize]
+            .filter(|c| *c > 0 && (*c as u64).is_power_of_two())
+            .map(|c| c.trailing_zeros())
+    };
+
+    let names = func.block.keys().copied().collect::<Vec<_>>();
+    for name in names {
+        let old = std::mem::take(&mut func.block.get_mut(&name).unwrap().insn);
+        let mut insn = Vec::with_capacity(old.len());
+        for i in old {
+            match i {
+                Instruction::Arith {
+                    op: BOp::Mul,
+                    dst,
+                    lhs,
+                    rhs,
+                } => match (power_of_two(&lhs), power_of_two(&rhs)) {
+                    (_, Some(0)) => insn.push(Instruction::Copy { dst, src: lhs }),
+                    (Some(0), None) => insn.push(Instruction::Copy { dst, src: rhs }),
+                    _ if traps.overflow => insn.push(i),
+                    (_, Some(k)) => insn.push(shift_left(dst, lhs, k)),
+                    (Some(k), None) => insn.push(shift_left(dst, rhs, k)),
+                    (None, None) => insn.push(i),
+                },
+                Instruction::Arith {
+                    op: BOp::Div,
+                    dst,
+                    lhs,
+                    rhs,
+                } => match power_of_two(&rhs) {
+                    Some(0) => insn.push(Instruction::Copy { dst, src: lhs }),
+                    Some(k) => {
+                        let sign = func.new_var("$tmp", Type::I64);
+                        let bias = func.new_var("$tmp", Type::I64);
+                        let biased = func.new_var("$tmp", Type::I64);
+                        insn.extend([
+                            Instruction::Shift {
+                                op: ShiftOp::Arith,
+                                dst: sign,
+                                src: lhs,
+                                amount: 63,
+                            },
+                            Instruction::Shift {
+                                op: ShiftOp::Logical,
+                                dst: bias,
+                                src: sign,
+                                amount: 64 - k,
+                            },
+                            Instruction::Arith {
+                                op: BOp::Add,
+                                dst: biased,
+                                lhs,
+                                rhs: bias,
+                            },
+                            Instruction::Shift {
+                                op: ShiftOp::Arith,
+                                dst,
+                                src: biased,
+                                amount: k,
+                            },
+                        ]);
+                    }
+                    None => insn.push(i),
+                },
+                i => insn.push(i),
+            }
+        }
+        func.block.get_mut(&name).unwrap().insn = insn;
+    }
+    func
+}
+
+/// `dst = src * 2^k`, for `k > 0`
+fn shift_left(dst: ValueId, src: ValueId, k: u32) -> Instruction {
+    Instruction::Shift {
+        op: ShiftOp::Left,
+        dst,
+        src,
+        amount: k,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::interp;
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn multiplications() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("eight", 8);
+        b.constant("one", 1);
+        b.constant("six", 6);
+        b.arith(BOp::Mul, "a", "eight", "x");
+        b.arith(BOp::Mul, "b", "x", "one");
+        b.arith(BOp::Mul, "c", "x", "six");
+        b.print("a");
+        b.print("b");
+        b.print("c");
+        b.exit();
+        let main = strength_reduce_function(b.finish_main(), Traps::default());
+        let (x, a, b) = (main.var("x"), main.var("a"), main.var("b"));
+        let insn = &main.block[&Function::entry()].insn;
+        assert_eq!(
+            insn[4],
+            Instruction::Shift {
+                op: ShiftOp::Left,
+                dst: a,
+                src: x,
+                amount: 3
+            }
+        );
+        assert_eq!(insn[5], Instruction::Copy { dst: b, src: x });
+        assert!(matches!(insn[6], Instruction::Arith { op: BOp::Mul, .. }));
+        let p = Program::from_main(main);
+        verify_ssa(&p).unwrap();
+        assert_eq!(output(&p, "-5"), "-40\n-5\n-30\n");
+    }
+
+    #[test]
+    fn divisions_round_towards_zero() {
+        let mut b = Builder::new();
+        b.constant("four", 4);
+        for _ in 0..7 {
+            b.read("x");
+            b.arith(BOp::Div, "q", "x", "four");
+            b.print("q");
+        }
+        b.exit();
+        let p = b.finish();
+        let input = format!("7 -7 8 -8 1 -1 {}", i64::MIN);
+        let expected = output(&p, &input);
+        assert_eq!(expected, "1\n-1\n2\n-2\n0\n0\n-2305843009213693952\n");
+
+        let p = strength_reduce(ssa::construct(p), Traps::default());
+        verify_ssa(&p).unwrap();
+        let entry = &p.func[&Program::main()].block[&Function::entry()];
+        assert!(!entry
+            .insn
+            .iter()
+            .any(|i| matches!(i, Instruction::Arith { op: BOp::Div, .. })));
+        assert_eq!(output(&p, &input), expected);
+    }
+
+    #[test]
+    fn overflows_stay_checked() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("four", 4);
+        b.constant("one", 1);
+        b.arith(BOp::Mul, "a", "x", "four");
+        b.arith(BOp::Mul, "b", "one", "x");
+        b.print("a");
+        b.print("b");
+        b.exit();
+        let traps = Traps {
+            overflow: true,
+            ..Traps::default()
+        };
+        let main = strength_reduce_function(b.finish_main(), traps);
+        let (x, a, b) = (main.var("x"), main.var("a"), main.var("b"));
+        let insn = &main.block[&Function::entry()].insn;
+        assert!(matches!(insn[3], Instruction::Arith { op: BOp::Mul, dst, .. } if dst == a));
+        assert_eq!(insn[4], Instruction::Copy { dst: b, src: x });
+    }
+}
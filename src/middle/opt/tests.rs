@@ -0,0 +1,122 @@
+//! Tests for the optimizer as a whole: optimized programs have to behave like
+//! the originals under the reference interpreter.
+
+use super::*;
+use crate::front::ast::BOp;
+use crate::middle::interp;
+
+// SECTION: helpers
+
+/// A small deterministic pseudo-random number generator, so failures are
+/// reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // The constants are from Knuth's MMIX.
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() as usize % n
+    }
+}
+
+const VARS: [&str; 4] = ["a", "b", "c", "d"];
+const OPS: [BOp; 5] = [BOp::Add, BOp::Sub, BOp::Mul, BOp::Div, BOp::Lt];
+
+/// Generate a random acyclic program over a few variables.  Blocks only jump
+/// forward, and constants are common so that the optimizer has something to
+/// do.
+pub(super) fn random_program(seed: u64) -> Program {
+    let mut rng = Rng(seed);
+    let n_blocks = 2 + rng.below(6);
+    let block_name = |i: usize| {
+        if i == 0 {
+            ENTRY.to_string()
+        } else {
+            format!("b{i}")
+        }
+    };
+    let mut b = Builder::new();
+    for i in 0..n_blocks {
+        if i > 0 {
+            b.block(&block_name(i));
+        }
+        for _ in 0..rng.below(5) {
+            let dst = VARS[rng.below(VARS.len())];
+            match rng.below(6) {
+                0 | 1 => b.constant(dst, rng.below(5) as i64 - 1),
+                2 => b.copy(dst, VARS[rng.below(VARS.len())]),
+                3 => b.read(dst),
+                4 => b.print(VARS[rng.below(VARS.len())]),
+                _ => {
+                    let op = OPS[rng.below(OPS.len())];
+                    let lhs = VARS[rng.below(VARS.len())];
+                    let rhs = VARS[rng.below(VARS.len())];
+                    b.arith(op, dst, lhs, rhs);
+                }
+            }
+        }
+        let remaining = n_blocks - i - 1;
+        if remaining == 0 {
+            for v in VARS {
+                b.print(v);
+            }
+            b.exit();
+        } else if rng.below(2) == 0 {
+            b.jump(&block_name(i + 1 + rng.below(remaining)));
+        } else {
+            let guard = VARS[rng.below(VARS.len())];
+            let tt = block_name(i + 1 + rng.below(remaining));
+            let ff = block_name(i + 1 + rng.below(remaining));
+            b.branch(guard, &tt, &ff);
+        }
+    }
+    b.finish()
+}
+
+fn output(p: &Program, input: &str) -> String {
+    let mut out = vec![];
+    interp::run(p, input.as_bytes(), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+// SECTION: tests
+
+#[test]
+fn optimize_preserves_behavior() {
+    let inputs = [
+        "0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0",
+        "1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10",
+    ];
+    for seed in 0..300 {
+        let p = random_program(seed);
+        verify(&p).unwrap();
+        let opt = optimize(p.clone());
+        verify(&opt).unwrap_or_else(|e| panic!("seed {seed}: {e}\n{opt}"));
+        for input in inputs {
+            assert_eq!(
+                output(&p, input),
+                output(&opt, input),
+                "seed {seed} changed the behavior of\n{p}\ninto\n{opt}"
+            );
+        }
+    }
+}
+
+#[test]
+fn o3_preserves_behavior() {
+    let input = "1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10 1 2 3 -4 5 0 7 8 -9 10";
+    let pipeline = Pipeline::for_level(OptLevel::O3);
+    for seed in 0..100 {
+        let p = random_program(seed);
+        let opt = pipeline.run(p.clone(), &Options::default());
+        verify(&opt).unwrap_or_else(|e| panic!("seed {seed}: {e}\n{opt}"));
+        assert_eq!(output(&p, input), output(&opt, input), "seed {seed}");
+    }
+}
@@ -0,0 +1,94 @@
+//! Unreachable block elimination.
+//!
+//! Blocks that can't be reached from the entry block never run, so they can
+//! go, along with their names and the phi arguments coming from them.  The
+//! remaining blocks are renumbered, so later stages don't see labels for
+//! blocks that no longer exist.  This works both in and out of SSA form.
+
+use super::*;
+use crate::common::*;
+use crate::middle::dom::reverse_postorder;
+
+/// Remove unreachable blocks from every function.
+pub fn remove_unreachable(program: Program) -> Program {
+    program.map_functions(remove_unreachable_function)
+}
+
+/// Remove unreachable blocks from a function.
+pub fn remove_unreachable_function(mut func: Function) -> Function {
+    let reachable = reverse_postorder(&func, Function::entry())
+        .into_iter()
+        .collect::<Set<_>>();
+    func.retain_blocks(|b| reachable.contains(&b));
+    func
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_blocks_and_names() {
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "l", "join");
+        b.block("dead");
+        b.constant("x", 7);
+        b.jump("join");
+        b.block("l");
+        b.constant("x", 1);
+        b.jump("join");
+        b.block("join");
+        b.phi("y", &[(ENTRY, "c"), ("l", "x"), ("dead", "x")]);
+        b.print("y");
+        b.exit();
+        let main = remove_unreachable_function(b.finish_main());
+
+        assert_eq!(main.block.len(), 3);
+        assert_eq!(main.names.num_blocks(), 3);
+        assert_eq!(main.names.find_block("dead"), None);
+        let (l, join) = (main.block_named("l"), main.block_named("join"));
+        assert_eq!((l, join), (BlockId(1), BlockId(2)));
+        assert_eq!(main.block[&Function::entry()].successors(), vec![l, join]);
+        let Instruction::Phi { args, .. } = &main.block[&join].insn[0] else {
+            panic!("expected a phi");
+        };
+        assert_eq!(
+            args.keys().copied().collect::<Vec<_>>(),
+            vec![Function::entry(), l]
+        );
+        verify(&Program::from_main(main)).unwrap();
+    }
+
+    #[test]
+    fn prunes_names_of_removed_blocks() {
+        // SCCP drops blocks without touching the names table.
+        let mut b = Builder::new();
+        b.jump("end");
+        b.block("gone");
+        b.exit();
+        b.block("end");
+        b.exit();
+        let mut main = b.finish_main();
+        main.block.remove(&main.block_named("gone"));
+        let main = remove_unreachable_function(main);
+        assert_eq!(main.names.num_blocks(), 2);
+        assert_eq!(
+            main.to_string(),
+            "$fun _() i64\n;\n$entry:\n  $jump end\nend:\n  $exit\n"
+        );
+    }
+
+    #[test]
+    fn keeps_loops() {
+        let mut b = Builder::new();
+        b.jump("head");
+        b.block("head");
+        b.read("c");
+        b.branch("c", "head", "end");
+        b.block("end");
+        b.exit();
+        let main = b.finish_main();
+        assert_eq!(remove_unreachable_function(main.clone()), main);
+    }
+}
@@ -0,0 +1,197 @@
+//! Loop unrolling.
+//!
+//! Unrolling a loop `k` times chains `k` copies of its body, where the back
+//! edges of each copy go to the header of the next one, and the back edges
+//! of the last copy go to the original header.  Every copy keeps the exit
+//! tests of the original, so this is correct whatever the trip count is.  If
+//! the trip count is a small constant, constant propagation then proves the
+//! back edge of the last copy dead, and the loop is gone.
+//!
+//! For each innermost loop, we first try as many copies as the size budget
+//! allows, and keep them if the loop folds away.  Otherwise, we unroll the
+//! loop a few times, which saves jumps and gives the other passes longer
+//! blocks to work on.
+//!
+//! Well-formed tiny IR programs have no loops, so this only does something
+//! for programs from other sources, like the ones the tests build, which
+//! `verify_with_cycles` checks.  The function must not be in SSA form.
+
+use super::*;
+use crate::common::*;
+use crate::middle::dom::DomTree;
+use crate::middle::loops::{Loop, LoopInfo};
+
+/// The default size budget for unrolled loops, in instructions.
+pub const DEFAULT_UNROLL_THRESHOLD: usize = 64;
+
+/// How many copies of a loop partial unrolling makes.
+const PARTIAL_COPIES: usize = 4;
+
+/// Unroll the innermost loops of every function.  Unrolled loops have at most
+/// `threshold` instructions, counting terminators.
+pub fn unroll(program: Program, threshold: usize) -> Program {
+    program.map_functions(|func| unroll_function(func, threshold))
+}
+
+/// Unroll the innermost loops of a function that is not in SSA form.
+pub fn unroll_function(mut func: Function, threshold: usize) -> Function {
+    if func
+        .block
+        .values()
+        .flat_map(|b| &b.insn)
+        .any(Instruction::is_phi)
+    {
+        return func;
+    }
+    let info = LoopInfo::new(&func, &DomTree::new(&func));
+    // Innermost loops don't overlap, so unrolling one leaves the blocks of
+    // the others alone.
+    let innermost = info
+        .loops()
+        .iter()
+        .filter(|l| l.children.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    for l in innermost {
+        let size = l
+            .body
+            .iter()
+            .map(|b| func.block[b].insn.len() + 1)
+            .sum::<usize>();
+        let copies = threshold / size;
+        let header = func.names.block(l.header);
+        if copies < 2 {
+            log::trace!("Not unrolling the loop at `{header}`: {size} instructions is too many.");
+            continue;
+        }
+        let full = replicate(func.clone(), &l, copies);
+        func = if num_loops(&full) < num_loops(&func) {
+            log::debug!("Unrolling the loop at `{header}` fully, {copies} times.");
+            full
+        } else {
+            let copies = copies.min(PARTIAL_COPIES);
+            log::debug!("Unrolling the loop at `{header}` {copies} times.");
+            replicate(func, &l, copies)
+        };
+    }
+    func
+}
+
+/// Chain `copies` copies of a loop, counting the original.
+fn replicate(mut func: Function, l: &Loop, copies: usize) -> Function {
+    let original = l
+        .body
+        .iter()
+        .map(|b| (*b, func.block[b].clone()))
+        .collect::<Map<_, _>>();
+    // The blocks of each copy, where the first copy is the original.
+    let mut ids = vec![l.body.iter().map(|b| (*b, *b)).collect::<Map<_, _>>()];
+    for _ in 1..copies {
+        let copy = l
+            .body
+            .iter()
+            .map(|b| {
+                let name = func.names.block(*b);
+                (*b, func.names.new_block(&name))
+            })
+            .collect();
+        ids.push(copy);
+    }
+    for (j, copy) in ids.iter().enumerate() {
+        let next = ids[(j + 1) % copies][&l.header];
+        for (b, block) in &original {
+            let mut block = block.clone();
+            for t in &mut block.term {
+                t.rename_targets(|to| match copy.get(&to) {
+                    _ if to == l.header => next,
+                    Some(c) => *c,
+                    None => to,
+                });
+            }
+            func.block.insert(copy[b], block);
+        }
+    }
+    func
+}
+
+/// The number of loops that are left after constant propagation.
+fn num_loops(func: &Function) -> usize {
+    let func = ssa::construct_function(func.clone());
+    let func = simplify_cfg_function(sccp_function(func, Traps::default()));
+    LoopInfo::new(&func, &DomTree::new(&func)).loops().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+    use crate::middle::interp;
+    use crate::middle::verify::verify_with_cycles;
+
+    // SECTION: helpers
+
+    /// Print the numbers from zero up to the bound.
+    fn counting(bound: Option<i64>) -> Program {
+        let mut b = Builder::new();
+        match bound {
+            Some(n) => b.constant("n", n),
+            None => b.read("n"),
+        }
+        b.constant("one", 1);
+        b.jump("head");
+        b.block("head");
+        b.arith(BOp::Lt, "c", "i", "n");
+        b.branch("c", "body", "done");
+        b.block("body");
+        b.print("i");
+        b.arith(BOp::Add, "i", "i", "one");
+        b.jump("head");
+        b.block("done");
+        b.exit();
+        b.finish()
+    }
+
+    fn output(p: &Program, input: &str) -> String {
+        let mut out = vec![];
+        interp::run(p, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn full() {
+        let p = counting(Some(3));
+        let q = unroll(p.clone(), DEFAULT_UNROLL_THRESHOLD);
+        verify_with_cycles(&q).unwrap();
+        assert_eq!(output(&q, ""), "0\n1\n2\n");
+        let main = &q.func[&Program::main()];
+        assert_eq!(num_loops(main), 0);
+        // The rest of the optimizer removes the copies that never run.
+        let opt = optimize(p);
+        verify(&opt).unwrap();
+        assert_eq!(output(&opt, ""), "0\n1\n2\n");
+    }
+
+    #[test]
+    fn partial() {
+        let p = counting(None);
+        let q = unroll(p.clone(), DEFAULT_UNROLL_THRESHOLD);
+        verify_with_cycles(&q).unwrap();
+        let main = &q.func[&Program::main()];
+        // `head` and `body` are copied three times.
+        assert_eq!(main.block.len(), 4 + 2 * 3);
+        assert_eq!(num_loops(main), 1);
+        for input in ["0", "1", "4", "9"] {
+            assert_eq!(output(&p, input), output(&q, input));
+        }
+    }
+
+    #[test]
+    fn budget() {
+        // The loop has 5 instructions, so it doesn't fit twice in 9.
+        let p = counting(Some(3));
+        assert_eq!(unroll(p.clone(), 9), p);
+        assert_ne!(unroll(p.clone(), 10), p);
+    }
+}
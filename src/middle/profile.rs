@@ -0,0 +1,122 @@
+//! Execution profiles.
+//!
+//! The instrumentation pass gives each block a `$count` instruction with its
+//! own counter.  A run of the instrumented program produces the values of the
+//! counters, and we turn them into the number of times each block was entered
+//! by finding the `$count` instructions again.  Profiles name blocks rather
+//! than counters, so they don't depend on how the counters were numbered.
+//!
+//! Profile files have one line per block with the function, the block, and
+//! the count:
+//!
+//! ```text
+//! # smol profile
+//! main $entry 1
+//! main body 10
+//! ```
+//!
+//! Programs don't carry source locations yet, so blocks are only named.
+
+use std::fmt::{Display, Formatter};
+
+use super::*;
+use crate::common::*;
+
+/// The first line of profile files.
+const HEADER: &str = "# smol profile";
+
+/// How many times each block was entered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// The counts by function and block name.
+    counts: Map<(Id, Id), u64>,
+}
+
+impl Profile {
+    /// The profile of an instrumented program, given the values of its
+    /// counters.  Counters past the end of `counters` are zero.
+    pub fn from_counters(program: &Program, counters: &[u64]) -> Profile {
+        let mut counts = Map::new();
+        for (name, func) in &program.func {
+            for (id, block) in &func.block {
+                for insn in &block.insn {
+                    if let Instruction::Count(k) = insn {
+                        let count = counters.get(*k as usize).copied().unwrap_or(0);
+                        *counts.entry((*name, func.names.block(*id))).or_default() += count;
+                    }
+                }
+            }
+        }
+        Profile { counts }
+    }
+
+    /// The number of times the block was entered, or `None` if the profile
+    /// doesn't know the block.
+    pub fn count(&self, function: Id, block: Id) -> Option<u64> {
+        self.counts.get(&(function, block)).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Parse a profile file.
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err(format!("A profile should start with `{HEADER}`.")),
+        }
+        let mut counts = Map::new();
+        for (i, line) in lines {
+            let bad = || format!("Line {} of the profile is malformed: `{line}`", i + 1);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let [function, block, count] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(bad());
+            };
+            let count = count.parse().map_err(|_| bad())?;
+            counts.insert((Id::from_ref(function), Id::from_ref(block)), count);
+        }
+        Ok(Profile { counts })
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        for ((function, block), count) in &self.counts {
+            writeln!(f, "{function} {block} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = "# smol profile\nmain $entry 1\nmain body 10\n";
+        let profile = Profile::parse(text).unwrap();
+        let main = Program::main();
+        assert_eq!(profile.count(main, Id::from_ref("body")), Some(10));
+        assert_eq!(profile.count(main, Id::from_ref("other")), None);
+        let printed = profile.to_string();
+        assert_eq!(Profile::parse(&printed).unwrap(), profile);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(
+            Profile::parse("main $entry 1").unwrap_err(),
+            "A profile should start with `# smol profile`."
+        );
+        assert_eq!(
+            Profile::parse("# smol profile\nmain $entry many").unwrap_err(),
+            "Line 2 of the profile is malformed: `main $entry many`"
+        );
+    }
+}
@@ -0,0 +1,573 @@
+//! Integer range analysis.
+//!
+//! We compute an interval of values each variable may hold at the start of
+//! each block with a forward fixed-point iteration.  Arithmetic is evaluated
+//! on intervals, and each edge out of a branch narrows the intervals with what
+//! taking that edge implies: the guard is zero or not, and if the guard was
+//! computed by `a < b` in the same block, `a` and `b` are ordered.  An edge
+//! whose condition can't hold is infeasible, and blocks that are only reached
+//! through infeasible edges are unreachable.
+//!
+//! Loops would make the iteration take as many steps as there are integers,
+//! so after a back edge grew the intervals of its target a few times, bounds
+//! that keep growing jump to the end of the range.
+//!
+//! This works both in and out of SSA form.  The results can prove branches
+//! dead, show that checks are redundant, and point at arithmetic that may
+//! wrap around.
+
+use std::fmt::{Display, Formatter};
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::BOp;
+
+/// How many times a back edge may grow the state at the start of a block
+/// before we widen it.
+const WIDEN_AFTER: usize = 2;
+
+/// The values a variable may hold, `lo` and `hi` included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+impl Range {
+    pub const FULL: Range = Range {
+        lo: i64::MIN,
+        hi: i64::MAX,
+    };
+
+    pub fn constant(c: i64) -> Range {
+        Range { lo: c, hi: c }
+    }
+
+    pub fn contains(self, c: i64) -> bool {
+        self.lo <= c && c <= self.hi
+    }
+
+    /// The smallest range containing both.
+    pub fn hull(self, other: Range) -> Range {
+        Range {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    /// The values in both ranges, if there are any.
+    fn meet(self, other: Range) -> Option<Range> {
+        let r = Range {
+            lo: self.lo.max(other.lo),
+            hi: self.hi.min(other.hi),
+        };
+        (r.lo <= r.hi).then_some(r)
+    }
+
+    /// The range with the given bounds, or `None` if they don't fit in 64
+    /// bits, which means the operation computing them may wrap around.
+    fn fit(lo: i128, hi: i128) -> Option<Range> {
+        Some(Range {
+            lo: lo.try_into().ok()?,
+            hi: hi.try_into().ok()?,
+        })
+    }
+
+    /// The range spanned by the given bounds.
+    fn span(bounds: impl IntoIterator<Item = i128>) -> Option<Range> {
+        let bounds = bounds.into_iter().collect::<Vec<_>>();
+        Range::fit(*bounds.iter().min()?, *bounds.iter().max()?)
+    }
+}
+
+/// A place where arithmetic may wrap around: an instruction of a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Overflow {
+    pub block: BlockId,
+    pub index: usize,
+}
+
+/// The range of each variable at one point.
+type State = Vec<Range>;
+
+/// Value ranges at block boundaries, and what follows from them.
+#[derive(Debug)]
+pub struct RangeAnalysis {
+    /// The state at the start of each reachable block.
+    entry: Map<BlockId, State>,
+    feasible: Set<(BlockId, BlockId)>,
+    /// The values each variable may be assigned, if it is assigned anywhere.
+    defs: Vec<Option<Range>>,
+    overflows: Vec<Overflow>,
+}
+
+impl RangeAnalysis {
+    /// Compute the ranges for the given function.
+    pub fn new(func: &Function) -> Self {
+        let rpo = dom::reverse_postorder(func, Function::entry());
+        let order = rpo
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (*b, i))
+            .collect::<Map<_, _>>();
+
+        // Variables start out as zero, and parameters as anything.
+        let mut start = vec![Range::constant(0); func.names.num_values()];
+        for p in &func.params {
+            start[p.0 as usize] = Range::FULL;
+        }
+        let mut entry = Map::from([(Function::entry(), start)]);
+        let mut grown: Map<BlockId, usize> = Map::new();
+
+        // The worklist is ordered by reverse postorder, so that each block
+        // sees all of its forward predecessors first.
+        let mut work = Set::from([(0, Function::entry())]);
+        while let Some((_, name)) = work.pop_first() {
+            let mut state = entry[&name].clone();
+            let block = &func.block[&name];
+            for insn in &block.insn {
+                if let Some((dst, range, _)) = eval(insn, &state) {
+                    state[dst.0 as usize] = range;
+                }
+            }
+            for (to, out) in successors(func, name, &state) {
+                let Some(out) = out else {
+                    continue;
+                };
+                let old = entry.get(&to);
+                let new = match old {
+                    None => out,
+                    Some(old) => {
+                        let mut new = old.iter().zip(&out).map(|(a, b)| a.hull(*b)).collect();
+                        if &new == old {
+                            continue;
+                        }
+                        // Only widen along back edges, so that the blocks in
+                        // the loop still narrow what they get from the header.
+                        if order[&to] <= order[&name] {
+                            let count = grown.entry(to).or_default();
+                            *count += 1;
+                            if *count > WIDEN_AFTER {
+                                widen(old, &mut new);
+                            }
+                        }
+                        new
+                    }
+                };
+                entry.insert(to, new);
+                work.insert((order[&to], to));
+            }
+        }
+
+        // Collect the results with the final states.
+        let mut analysis = RangeAnalysis {
+            entry,
+            feasible: Set::new(),
+            defs: vec![None; func.names.num_values()],
+            overflows: vec![],
+        };
+        for name in &rpo {
+            let Some(mut state) = analysis.entry.get(name).cloned() else {
+                continue;
+            };
+            for (index, insn) in func.block[name].insn.iter().enumerate() {
+                let Some((dst, range, overflow)) = eval(insn, &state) else {
+                    continue;
+                };
+                state[dst.0 as usize] = range;
+                let def = &mut analysis.defs[dst.0 as usize];
+                *def = Some(def.map_or(range, |d| d.hull(range)));
+                if overflow {
+                    analysis.overflows.push(Overflow {
+                        block: *name,
+                        index,
+                    });
+                }
+            }
+            // Phis are assigned on the edges into their block.
+            for insn in &func.block[name].insn {
+                if let Instruction::Phi { dst, .. } = insn {
+                    let range = analysis.entry[name][dst.0 as usize];
+                    let def = &mut analysis.defs[dst.0 as usize];
+                    *def = Some(def.map_or(range, |d| d.hull(range)));
+                }
+            }
+            for (to, out) in successors(func, *name, &state) {
+                if out.is_some() {
+                    analysis.feasible.insert((*name, to));
+                }
+            }
+        }
+        analysis.overflows.sort();
+        analysis
+    }
+
+    /// Can control reach the block?
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.entry.contains_key(&block)
+    }
+
+    /// Can control go along the edge?  This is false for edges of branches
+    /// whose guard is known, and for edges out of unreachable blocks.
+    pub fn is_feasible(&self, from: BlockId, to: BlockId) -> bool {
+        self.feasible.contains(&(from, to))
+    }
+
+    /// The range of a variable at the start of a block, or `None` if the
+    /// block is unreachable.
+    pub fn at_entry(&self, block: BlockId, var: ValueId) -> Option<Range> {
+        self.entry.get(&block).map(|s| s[var.0 as usize])
+    }
+
+    /// The ranges of all variables right before each instruction of the
+    /// block, or `None` if it is unreachable.
+    pub fn before_each(&self, func: &Function, block: BlockId) -> Option<Vec<Vec<Range>>> {
+        let mut state = self.entry.get(&block)?.clone();
+        let mut result = vec![];
+        for insn in &func.block[&block].insn {
+            result.push(state.clone());
+            if let Some((dst, range, _)) = eval(insn, &state) {
+                state[dst.0 as usize] = range;
+            }
+        }
+        Some(result)
+    }
+
+    /// The values a variable may be assigned anywhere.  In SSA form, this is
+    /// the range of the variable wherever it is available.
+    pub fn assigned(&self, var: ValueId) -> Option<Range> {
+        self.defs[var.0 as usize]
+    }
+
+    /// The instructions whose arithmetic may wrap around, in order.
+    pub fn overflows(&self) -> &[Overflow] {
+        &self.overflows
+    }
+}
+
+/// Evaluate an instruction on ranges.  Returns the variable it assigns, its
+/// new range, and whether the computation may wrap around.  Phis are handled
+/// on the edges into their block.
+fn eval(insn: &Instruction, state: &State) -> Option<(ValueId, Range, bool)> {
+    use Instruction::*;
+    let get = |v: &ValueId| state[v.0 as usize];
+    let dst = insn.def()?;
+    let result = match insn {
+        Phi { .. } => return None,
+        Const { src, .. } => Some(Range::constant(*src)),
+        Copy { src, .. } => Some(get(src)),
+        Arith { op, lhs, rhs, .. } => arith(*op, get(lhs), get(rhs), lhs == rhs),
+        Shift {
+            op, src, amount, ..
+        } => shift(*op, get(src), *amount),
+        Read(_) | Call { .. } | Alloc { .. } | Load { .. } => Some(Range::FULL),
+        Print(_) | Store { .. } | Count(_) | Loc(_) => unreachable!("these don't assign"),
+    };
+    Some(match result {
+        Some(range) => (dst, range, false),
+        None => (dst, Range::FULL, !matches!(insn, Read(_) | Call { .. })),
+    })
+}
+
+/// Apply a binary operator to ranges.  `same` tells whether both operands are
+/// the same variable.  Returns `None` if the result may wrap around.
+fn arith(op: BOp, a: Range, b: Range, same: bool) -> Option<Range> {
+    let (al, ah, bl, bh) = (a.lo as i128, a.hi as i128, b.lo as i128, b.hi as i128);
+    match op {
+        BOp::Add => Range::fit(al + bl, ah + bh),
+        BOp::Sub if same => Some(Range::constant(0)),
+        BOp::Sub => Range::fit(al - bh, ah - bl),
+        BOp::Mul => Range::span([al * bl, al * bh, ah * bl, ah * bh]),
+        BOp::Div => {
+            // Quotients are extreme at the corners of each part of the
+            // divisor with a single sign.  Dividing by zero gives -1.
+            let mut bounds = vec![];
+            let negative = Range {
+                lo: i64::MIN,
+                hi: -1,
+            };
+            let positive = Range {
+                lo: 1,
+                hi: i64::MAX,
+            };
+            for part in [negative, positive] {
+                if let Some(p) = part.meet(b) {
+                    let (pl, ph) = (p.lo as i128, p.hi as i128);
+                    bounds.extend([al / pl, al / ph, ah / pl, ah / ph]);
+                }
+            }
+            if b.contains(0) {
+                bounds.push(-1);
+            }
+            Range::span(bounds)
+        }
+        BOp::Lt if same => Some(Range::constant(0)),
+        BOp::Lt if a.hi < b.lo => Some(Range::constant(1)),
+        BOp::Lt if a.lo >= b.hi => Some(Range::constant(0)),
+        BOp::Lt => Some(Range { lo: 0, hi: 1 }),
+    }
+}
+
+/// Shift a range.  Returns `None` if the result may wrap around.
+fn shift(op: ShiftOp, a: Range, amount: u32) -> Option<Range> {
+    match op {
+        ShiftOp::Left => Range::fit((a.lo as i128) << amount, (a.hi as i128) << amount),
+        ShiftOp::Arith => Some(Range {
+            lo: a.lo >> amount,
+            hi: a.hi >> amount,
+        }),
+        // Negative numbers are large unsigned numbers, so the order only
+        // changes if the range has both signs.
+        ShiftOp::Logical if amount == 0 || a.lo >= 0 || a.hi < 0 => Some(Range {
+            lo: ((a.lo as u64) >> amount) as i64,
+            hi: ((a.hi as u64) >> amount) as i64,
+        }),
+        ShiftOp::Logical => Some(Range {
+            lo: 0,
+            hi: (u64::MAX >> amount) as i64,
+        }),
+    }
+}
+
+/// The states on the edges out of a block, given the state at its end.  The
+/// phis of the successors are applied, and `None` marks infeasible edges.
+fn successors(func: &Function, name: BlockId, state: &State) -> Vec<(BlockId, Option<State>)> {
+    let block = &func.block[&name];
+    let edges = match block.term[..] {
+        [Terminator::Branch { guard, tt, ff }] => vec![
+            (tt, refine(block, state, guard, true)),
+            (ff, refine(block, state, guard, false)),
+        ],
+        _ => block
+            .successors()
+            .into_iter()
+            .map(|s| (s, Some(state.clone())))
+            .collect(),
+    };
+    edges
+        .into_iter()
+        .filter(|(to, _)| func.block.contains_key(to))
+        .map(|(to, out)| {
+            let out = out.map(|out| {
+                let mut next = out.clone();
+                for insn in &func.block[&to].insn {
+                    if let Instruction::Phi { dst, args } = insn {
+                        next[dst.0 as usize] =
+                            args.get(&name).map_or(Range::FULL, |v| out[v.0 as usize]);
+                    }
+                }
+                next
+            });
+            (to, out)
+        })
+        .collect()
+}
+
+/// Narrow the state at the end of a block by whether its branch was taken.
+fn refine(block: &Block, state: &State, guard: ValueId, taken: bool) -> Option<State> {
+    let mut state = state.clone();
+    let g = &mut state[guard.0 as usize];
+    if taken {
+        // Only the bounds of a range can be cut off.
+        *g = match (g.lo, g.hi) {
+            (0, 0) => return None,
+            (0, hi) => Range { lo: 1, hi },
+            (lo, 0) => Range { lo, hi: -1 },
+            _ => *g,
+        };
+    } else {
+        *g = g.meet(Range::constant(0))?;
+    }
+
+    // If the guard is `a < b` computed in this block, and neither operand
+    // changed since, the operands are ordered.
+    let Some(pos) = block.insn.iter().rposition(|i| i.def() == Some(guard)) else {
+        return Some(state);
+    };
+    let Instruction::Arith {
+        op: BOp::Lt,
+        lhs,
+        rhs,
+        ..
+    } = block.insn[pos]
+    else {
+        return Some(state);
+    };
+    if block.insn[pos + 1..]
+        .iter()
+        .any(|i| i.def() == Some(lhs) || i.def() == Some(rhs))
+    {
+        return Some(state);
+    }
+    let (a, b) = (state[lhs.0 as usize], state[rhs.0 as usize]);
+    let (a, b) = if taken {
+        // a < b
+        let a = a.meet(Range {
+            lo: i64::MIN,
+            hi: b.hi.checked_sub(1)?,
+        })?;
+        let b = b.meet(Range {
+            lo: a.lo + 1,
+            hi: i64::MAX,
+        })?;
+        (a, b)
+    } else {
+        // a >= b
+        let a = a.meet(Range {
+            lo: b.lo,
+            hi: i64::MAX,
+        })?;
+        let b = b.meet(Range {
+            lo: i64::MIN,
+            hi: a.hi,
+        })?;
+        (a, b)
+    };
+    state[lhs.0 as usize] = a;
+    state[rhs.0 as usize] = b;
+    Some(state)
+}
+
+/// Push the bounds that grew since the last state to the end of the range.
+fn widen(old: &State, new: &mut State) {
+    for (o, n) in old.iter().zip(new.iter_mut()) {
+        if n.lo < o.lo {
+            n.lo = i64::MIN;
+        }
+        if n.hi > o.hi {
+            n.hi = i64::MAX;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(lo: i64, hi: i64) -> Range {
+        Range { lo, hi }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            arith(BOp::Mul, range(-2, 3), range(-5, 4), false),
+            Some(range(-15, 12))
+        );
+        assert_eq!(
+            arith(BOp::Div, range(-8, 9), range(-2, 3), false),
+            Some(range(-9, 9))
+        );
+        // Dividing by zero gives -1.
+        assert_eq!(
+            arith(BOp::Div, range(4, 8), range(0, 2), false),
+            Some(range(-1, 8))
+        );
+        assert_eq!(arith(BOp::Div, Range::FULL, range(-1, -1), false), None);
+        assert_eq!(
+            arith(BOp::Add, range(0, i64::MAX), range(0, 1), false),
+            None
+        );
+        assert_eq!(
+            arith(BOp::Lt, range(0, 2), range(3, 5), false),
+            Some(range(1, 1))
+        );
+        assert_eq!(
+            shift(ShiftOp::Logical, range(-1, 0), 60),
+            Some(range(0, 15))
+        );
+        assert_eq!(shift(ShiftOp::Left, range(1, 4), 62), None);
+    }
+
+    #[test]
+    fn branches_narrow() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.constant("ten", 10);
+        b.arith(BOp::Lt, "c", "x", "ten");
+        b.branch("c", "small", "big");
+        b.block("small");
+        b.constant("one", 1);
+        b.arith(BOp::Add, "y", "x", "one");
+        b.print("y");
+        b.exit();
+        b.block("big");
+        b.constant("one", 1);
+        b.arith(BOp::Add, "z", "x", "one");
+        b.print("z");
+        b.exit();
+        let main = b.finish_main();
+        let r = RangeAnalysis::new(&main);
+        let (x, small, big) = (
+            main.var("x"),
+            main.block_named("small"),
+            main.block_named("big"),
+        );
+        assert_eq!(r.at_entry(small, x), Some(range(i64::MIN, 9)));
+        assert_eq!(r.at_entry(big, x), Some(range(10, i64::MAX)));
+        assert_eq!(r.assigned(main.var("y")), Some(range(i64::MIN + 1, 10)));
+        // Only `x + 1` with a large `x` may overflow.
+        assert_eq!(
+            r.overflows(),
+            &[Overflow {
+                block: big,
+                index: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn dead_branches() {
+        let mut b = Builder::new();
+        b.constant("x", 5);
+        b.constant("ten", 10);
+        b.arith(BOp::Lt, "c", "x", "ten");
+        b.branch("c", "yes", "no");
+        b.block("yes");
+        b.exit();
+        b.block("no");
+        b.exit();
+        let main = b.finish_main();
+        let r = RangeAnalysis::new(&main);
+        let (yes, no) = (main.block_named("yes"), main.block_named("no"));
+        assert!(r.is_feasible(Function::entry(), yes));
+        assert!(!r.is_feasible(Function::entry(), no));
+        assert!(!r.is_reachable(no));
+        assert_eq!(r.at_entry(no, main.var("x")), None);
+    }
+
+    #[test]
+    fn loops_terminate() {
+        // i := 0; while i < n { i := i + 1 }
+        let mut b = Builder::new();
+        b.read("n");
+        b.constant("one", 1);
+        b.jump("head");
+        b.block("head");
+        b.arith(BOp::Lt, "c", "i", "n");
+        b.branch("c", "body", "done");
+        b.block("body");
+        b.arith(BOp::Add, "i", "i", "one");
+        b.jump("head");
+        b.block("done");
+        b.print("i");
+        b.exit();
+        let main = b.finish_main();
+        let r = RangeAnalysis::new(&main);
+        let i = main.var("i");
+        assert_eq!(
+            r.at_entry(main.block_named("head"), i),
+            Some(range(0, i64::MAX))
+        );
+        // `i < n` in the body, so `i + 1` can't overflow.
+        assert_eq!(r.overflows(), &[]);
+        let before = r.before_each(&main, main.block_named("body")).unwrap();
+        assert_eq!(before[0][i.0 as usize], range(0, i64::MAX - 1));
+    }
+}
@@ -0,0 +1,484 @@
+//! Conversion into and out of static single assignment (SSA) form.
+//!
+//! # Construction
+//!
+//! We build pruned SSA with the classic algorithm from Cytron et al.: phi
+//! instructions are placed on the iterated dominance frontier of each
+//! variable's definitions (but only where the variable is live), then the
+//! variables are renamed by walking the dominator tree.
+//!
+//! Each definition of a variable `x` gets a fresh variable named `x.N`.  Uses
+//! that are not reached by any definition keep the original variable `x`,
+//! which is never assigned in the SSA program, so it still holds its initial
+//! value.
+//!
+//! # Destruction
+//!
+//! To go back to regular tiny IR, each phi is replaced with copies at the end
+//! of its predecessors.  All phis of a block read their arguments at the same
+//! time, so the copies on each edge form a *parallel copy* that we have to
+//! sequentialize carefully:
+//!
+//! - The *lost-copy problem*: a copy placed at the end of a predecessor with
+//!   several successors would also run when control goes elsewhere, clobbering
+//!   a value that is still live there.  We avoid this by splitting such
+//!   critical edges with a new block that holds the copies.
+//! - The *swap problem*: phis whose arguments are each other's results (e.g.
+//!   `a, b := b, a`) cannot be done in any order without losing a value.  We
+//!   order the copies so that no variable is written before it is read, and
+//!   break the remaining cycles with a temporary.
+
+use super::*;
+use crate::common::*;
+
+/// Convert a program into pruned SSA form.
+pub fn construct(program: Program) -> Program {
+    program.map_functions(construct_function)
+}
+
+/// Convert a function into pruned SSA form.  Blocks that are unreachable from
+/// the entry are dropped since they can never run.  Parameters that are
+/// reassigned keep their original name for the value passed by the caller.
+pub fn construct_function(mut func: Function) -> Function {
+    let dom = DomTree::new(&func);
+    func.block.retain(|name, _| dom.is_reachable(*name));
+
+    let liveness = Liveness::new(&func);
+    let preds = func.predecessors();
+
+    // SECTION: phi placement
+
+    let mut def_sites: Map<ValueId, Set<BlockId>> = Map::new();
+    for (name, block) in &func.block {
+        for dst in block.insn.iter().filter_map(Instruction::def) {
+            def_sites.entry(dst).or_default().insert(*name);
+        }
+    }
+
+    // The original variable of each phi we insert, in the order the phis
+    // appear in their blocks.
+    let mut phi_vars: Map<BlockId, Vec<ValueId>> = Map::new();
+    for (var, sites) in &def_sites {
+        let mut has_phi = Set::new();
+        let mut worklist = sites.iter().copied().collect::<Vec<_>>();
+        while let Some(b) = worklist.pop() {
+            for f in dom.frontier(b) {
+                if !liveness.is_live_in(f, *var) || !has_phi.insert(f) {
+                    continue;
+                }
+                phi_vars.entry(f).or_default().push(*var);
+                if !sites.contains(&f) {
+                    worklist.push(f);
+                }
+            }
+        }
+    }
+
+    for (name, vars) in &phi_vars {
+        let phis = vars.iter().map(|var| Instruction::Phi {
+            dst: *var,
+            args: preds[name].iter().map(|p| (*p, *var)).collect(),
+        });
+        let block = func.block.get_mut(name).unwrap();
+        block.insn.splice(0..0, phis);
+    }
+
+    // SECTION: renaming
+
+    let mut renamer = Renamer {
+        func: &mut func,
+        phi_vars: &phi_vars,
+        stacks: Map::new(),
+    };
+    // Walk the dominator tree with an explicit stack, since it can be as
+    // deep as the function is long.
+    let mut work = vec![Walk::Enter(Function::entry())];
+    while let Some(step) = work.pop() {
+        match step {
+            Walk::Enter(name) => {
+                let pushed = renamer.rename(name);
+                work.push(Walk::Leave(pushed));
+                work.extend(dom.children(name).iter().rev().map(|c| Walk::Enter(*c)));
+            }
+            Walk::Leave(pushed) => {
+                for var in pushed {
+                    renamer.stacks.get_mut(&var).unwrap().pop();
+                }
+            }
+        }
+    }
+
+    func
+}
+
+/// A step of the dominator tree walk during renaming.
+enum Walk {
+    /// Rename the block and visit its children.
+    Enter(BlockId),
+    /// Forget the versions a block pushed, after all its children are done.
+    Leave(Vec<ValueId>),
+}
+
+struct Renamer<'a> {
+    func: &'a mut Function,
+    phi_vars: &'a Map<BlockId, Vec<ValueId>>,
+    /// The current version of each original variable.
+    stacks: Map<ValueId, Vec<ValueId>>,
+}
+
+impl Renamer<'_> {
+    fn current(&self, var: ValueId) -> ValueId {
+        self.stacks
+            .get(&var)
+            .and_then(|s| s.last())
+            .copied()
+            .unwrap_or(var)
+    }
+
+    fn fresh(&mut self, var: ValueId) -> ValueId {
+        let ty = self
+            .func
+            .type_of(var)
+            .expect("variables should be declared");
+        let name = self.func.names.value(var);
+        let new = self.func.new_var(&name, ty);
+        self.stacks.entry(var).or_default().push(new);
+        new
+    }
+
+    /// Rename the variables in a block and the phi arguments coming from it.
+    /// Return the original variables we pushed new versions for.
+    fn rename(&mut self, name: BlockId) -> Vec<ValueId> {
+        let mut pushed = vec![];
+
+        let mut block = std::mem::replace(
+            self.func.block.get_mut(&name).unwrap(),
+            Block {
+                insn: vec![],
+                term: vec![],
+            },
+        );
+        for insn in &mut block.insn {
+            if !insn.is_phi() {
+                insn.rename_uses(|v| self.current(v));
+            }
+            if let Some(var) = insn.def() {
+                let new = self.fresh(var);
+                insn.rename_def(|_| new);
+                pushed.push(var);
+            }
+        }
+        for t in &mut block.term {
+            t.rename_uses(|v| self.current(v));
+        }
+        let succs = block.successors();
+        self.func.block.insert(name, block);
+
+        // Fill in the phi arguments coming from this block.
+        for succ in succs {
+            let Some(vars) = self.phi_vars.get(&succ) else {
+                continue;
+            };
+            let values = vars.iter().map(|v| self.current(*v)).collect::<Vec<_>>();
+            let succ_block = self.func.block.get_mut(&succ).unwrap();
+            for (insn, value) in succ_block.insn.iter_mut().zip(values) {
+                if let Instruction::Phi { args, .. } = insn {
+                    args.insert(name, value);
+                }
+            }
+        }
+
+        pushed
+    }
+}
+
+/// Convert a func out of SSA form by replacing phi instructions with
+/// copies.
+pub fn destruct(program: Program) -> Program {
+    program.map_functions(destruct_function)
+}
+
+/// Convert a function out of SSA form by replacing phi instructions with
+/// copies.
+pub fn destruct_function(mut func: Function) -> Function {
+    let preds = func.predecessors();
+    let phi_blocks = func
+        .block
+        .iter()
+        .filter(|(_, b)| b.insn.first().is_some_and(Instruction::is_phi))
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+    for name in phi_blocks {
+        let block = func.block.get_mut(&name).unwrap();
+        let n_phis = block.insn.iter().take_while(|i| i.is_phi()).count();
+        let phis = block.insn.drain(..n_phis).collect::<Vec<_>>();
+
+        for pred in &preds[&name] {
+            let copies = phis
+                .iter()
+                .map(|phi| match phi {
+                    Instruction::Phi { dst, args } => (*dst, args[pred]),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            let copies = sequentialize(copies, |v| {
+                let ty = func.type_of(v).expect("variables should be declared");
+                func.new_var(TMP, ty)
+            })
+            .into_iter()
+            .map(|(dst, src)| Instruction::Copy { dst, src });
+
+            // Split the edge if the copies would also run on other paths.
+            if func.block[pred].successors().len() > 1 {
+                let split_name = format!("{}.{}", func.names.block(*pred), func.names.block(name));
+                let split = func.names.new_block(&split_name);
+                for t in &mut func.block.get_mut(pred).unwrap().term {
+                    t.rename_targets(|b| if b == name { split } else { b });
+                }
+                func.block.insert(
+                    split,
+                    Block {
+                        insn: copies.collect(),
+                        term: vec![Terminator::Jump(name)],
+                    },
+                );
+            } else {
+                func.block.get_mut(pred).unwrap().insn.extend(copies);
+            }
+        }
+    }
+
+    // The variables from before SSA construction are unused now.
+    func.prune_vars();
+    func
+}
+
+/// The name of the temporaries we use to break copy cycles.
+const TMP: &str = "$tmp";
+
+/// Order the parallel copy `dsts := srcs` into a sequence of copies with the
+/// same effect.  `fresh` creates a temporary to hold the value of the given
+/// variable, which we use to break cycles.
+pub fn sequentialize(
+    mut copies: Vec<(ValueId, ValueId)>,
+    mut fresh: impl FnMut(ValueId) -> ValueId,
+) -> Vec<(ValueId, ValueId)> {
+    copies.retain(|(dst, src)| dst != src);
+    let mut result = vec![];
+    while !copies.is_empty() {
+        // A copy is safe to do if no other pending copy still needs the old
+        // value of its destination.
+        let safe = copies
+            .iter()
+            .position(|(dst, _)| !copies.iter().any(|(_, src)| src == dst));
+        match safe {
+            Some(i) => result.push(copies.remove(i)),
+            None => {
+                // Every destination is still needed, so we are in a cycle.
+                // Save one destination and read it from the temporary instead.
+                let (saved, _) = copies[0];
+                let tmp = fresh(saved);
+                result.push((tmp, saved));
+                for (_, src) in &mut copies {
+                    if *src == saved {
+                        *src = tmp;
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    // Values for testing `sequentialize` on its own.
+    fn v(n: u32) -> ValueId {
+        ValueId(n)
+    }
+
+    // $read c; $if c { := x 1 } { := x 2 }; $print x
+    fn diamond() -> Function {
+        let mut b = Builder::new();
+        b.read("c");
+        b.branch("c", "l", "r");
+        b.block("l");
+        b.constant("x", 1);
+        b.jump("join");
+        b.block("r");
+        b.constant("x", 2);
+        b.jump("join");
+        b.block("join");
+        b.print("x");
+        b.exit();
+        b.finish_main()
+    }
+
+    #[test]
+    fn inserts_phi_at_join() {
+        let ssa = construct_function(diamond());
+        verify::verify_ssa(&Program::from_main(ssa.clone())).unwrap();
+        let id = |s| ssa.block_named(s);
+        let join = &ssa.block[&id("join")];
+        assert_eq!(join.insn.len(), 2);
+        let Instruction::Phi { dst, args } = &join.insn[0] else {
+            panic!("expected a phi, found {:?}", join.insn[0]);
+        };
+        assert_eq!(join.insn[1], Instruction::Print(*dst));
+        assert_eq!(args.len(), 2);
+        assert_ne!(args[&id("l")], args[&id("r")]);
+    }
+
+    #[test]
+    fn pruned() {
+        // x is dead after the join, so there should be no phi.
+        let mut p = diamond();
+        let join = p.block_named("join");
+        p.block.get_mut(&join).unwrap().insn.clear();
+        let ssa = construct_function(p);
+        verify::verify_ssa(&Program::from_main(ssa.clone())).unwrap();
+        assert!(ssa.block[&join].insn.is_empty());
+    }
+
+    #[test]
+    fn renames_redefinitions() {
+        let mut b = Builder::new();
+        b.print("x");
+        b.read("x");
+        b.arith(BOp::Add, "x", "x", "x");
+        b.print("x");
+        b.exit();
+        let p = b.finish_main();
+        let ssa = construct_function(p);
+        verify::verify_ssa(&Program::from_main(ssa.clone())).unwrap();
+        let id = |s| ssa.var(s);
+        let insn = &ssa.block[&Function::entry()].insn;
+        assert_eq!(insn[0], Instruction::Print(id("x")));
+        assert_eq!(insn[1], Instruction::Read(id("x.1")));
+        assert_eq!(
+            insn[2],
+            Instruction::Arith {
+                op: BOp::Add,
+                dst: id("x.2"),
+                lhs: id("x.1"),
+                rhs: id("x.1"),
+            }
+        );
+        assert_eq!(insn[3], Instruction::Print(id("x.2")));
+    }
+
+    #[test]
+    fn loops() {
+        // x := 0; loop { x := x + 1; print x }
+        let mut b = Builder::new();
+        b.constant("one", 1);
+        b.jump("head");
+        b.block("head");
+        b.arith(BOp::Add, "x", "x", "one");
+        b.print("x");
+        b.branch("c", "head", "exit");
+        b.block("exit");
+        b.exit();
+        let p = b.finish_main();
+        let ssa = construct_function(p);
+        let id = |s| ssa.block_named(s);
+        let head = &ssa.block[&id("head")];
+        let Instruction::Phi { dst, args } = &head.insn[0] else {
+            panic!("expected a phi, found {:?}", head.insn[0]);
+        };
+        assert_eq!(args[&id("$entry")], ssa.var("x"));
+        let Instruction::Arith { dst: sum, lhs, .. } = &head.insn[1] else {
+            panic!("expected an addition, found {:?}", head.insn[1]);
+        };
+        assert_eq!(lhs, dst);
+        assert_eq!(args[&id("head")], *sum);
+    }
+
+    // Run the copies one by one.
+    fn run_sequential(copies: &[(ValueId, ValueId)], env: &mut Map<ValueId, i64>) {
+        for (dst, src) in copies {
+            let v = env.get(src).copied().unwrap_or(0);
+            env.insert(*dst, v);
+        }
+    }
+
+    #[test]
+    fn sequentialize_swap() {
+        let mut n = 10;
+        let fresh = |_| {
+            n += 1;
+            v(n)
+        };
+        let copies = vec![(v(0), v(1)), (v(1), v(0)), (v(2), v(0))];
+        let seq = sequentialize(copies, fresh);
+        let mut env: Map<ValueId, i64> = [(v(0), 1), (v(1), 2), (v(2), 3)].into();
+        run_sequential(&seq, &mut env);
+        assert_eq!(env[&v(0)], 2);
+        assert_eq!(env[&v(1)], 1);
+        assert_eq!(env[&v(2)], 1);
+    }
+
+    #[test]
+    fn sequentialize_chain() {
+        let copies = vec![(v(1), v(0)), (v(2), v(1)), (v(3), v(2))];
+        let seq = sequentialize(copies, |_| unreachable!("no cycles here"));
+        assert_eq!(seq, vec![(v(3), v(2)), (v(2), v(1)), (v(1), v(0))]);
+    }
+
+    #[test]
+    fn destruct_diamond() {
+        let p = destruct_function(construct_function(diamond()));
+        verify::verify(&Program::from_main(p.clone())).unwrap();
+        let id = |s| p.block_named(s);
+        assert!(p.block.values().flat_map(|b| &b.insn).all(|i| !i.is_phi()));
+        let Instruction::Print(x) = p.block[&id("join")].insn[0] else {
+            panic!("expected a print");
+        };
+        for side in ["l", "r"] {
+            let insn = &p.block[&id(side)].insn;
+            let Instruction::Const { dst, .. } = insn[0] else {
+                panic!("expected a constant");
+            };
+            assert_eq!(insn[1], Instruction::Copy { dst: x, src: dst });
+        }
+    }
+
+    #[test]
+    fn destruct_splits_critical_edges() {
+        // $entry branches to join directly or through `mid`, and join has a
+        // phi, so the edge $entry -> join is critical.
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.branch("c", "mid", "join");
+        b.block("mid");
+        b.constant("y", 2);
+        b.jump("join");
+        b.block("join");
+        b.phi("z", &[("$entry", "x"), ("mid", "y")]);
+        b.print("z");
+        b.exit();
+        let p = b.finish_main();
+        let p = destruct_function(p);
+        verify::verify(&Program::from_main(p.clone())).unwrap();
+        let id = |s| p.block_named(s);
+        assert_eq!(p.block.len(), 4);
+        let Terminator::Branch { ff, .. } = p.block[&id("$entry")].term[0] else {
+            panic!("expected a branch");
+        };
+        assert_ne!(ff, id("join"));
+        assert_eq!(p.names.block(ff).as_str(), "$entry.join");
+        assert_eq!(
+            p.block[&ff].insn,
+            vec![Instruction::Copy {
+                dst: p.var("z"),
+                src: p.var("x")
+            }]
+        );
+        assert_eq!(p.block[&ff].term, vec![Terminator::Jump(id("join"))]);
+        assert_eq!(p.block[&id("$entry")].insn.len(), 1);
+    }
+}
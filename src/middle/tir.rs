@@ -1,32 +1,682 @@
 //! The tiny IR.
+//!
+//! Values and blocks are referred to by small numeric IDs that are local to
+//! their function, so the optimizer can compare and look them up cheaply.
+//! Each function keeps a side table of their original names for printing.
+
+use std::collections::HashMap;
+
+use derive_more::Display;
 
 use crate::common::*;
 use crate::front::ast::BOp;
 
-#[derive(Debug)]
+mod builder;
+pub use builder::Builder;
+
+mod cursor;
+pub use cursor::Cursor;
+
+mod dot;
+pub use dot::cfg_dot;
+
+mod equiv;
+pub use equiv::{equiv, equiv_function};
+
+mod print;
+
+/// The name of the start block of every function.
+pub const ENTRY: &str = "$entry";
+
+/// The name of the function where execution starts.
+pub const MAIN: &str = "main";
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub struct Program {
-    pub decl: Set<Id>,
-    pub block: Map<Id, Block>,
+    /// The functions in the program, including `main`.
+    pub func: Map<Id, Function>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Function {
+    /// The parameters, in order.  These are also declared in `decl`.
+    pub params: Vec<ValueId>,
+    /// The type of the returned value.
+    pub ret: Type,
+    /// The declared variables and their types.
+    pub decl: Map<ValueId, Type>,
+    pub block: Map<BlockId, Block>,
+    /// The names of the values and blocks.
+    pub names: Names,
+}
+
+/// A variable in a function.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Display)]
+#[display("%{_0}")]
+pub struct ValueId(pub u32);
+
+/// A basic block in a function.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Display)]
+#[display("@{_0}")]
+pub struct BlockId(pub u32);
+
+impl BlockId {
+    /// The start block of every function.
+    pub const ENTRY: BlockId = BlockId(0);
+}
+
+impl std::fmt::Debug for ValueId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl std::fmt::Debug for BlockId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// The names of the values and blocks of a function.  Each ID has exactly one
+/// name, and different IDs have different names.  The entry block is always
+/// named `$entry`.
+#[derive(Clone, Debug)]
+pub struct Names {
+    values: Vec<Id>,
+    blocks: Vec<Id>,
+    value_ids: HashMap<Id, ValueId>,
+    block_ids: HashMap<Id, BlockId>,
+    /// The next suffix to try for each base name, so that creating many
+    /// versions of a name doesn't take quadratic time.
+    suffix: HashMap<Id, usize>,
+}
+
+impl PartialEq for Names {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values && self.blocks == other.blocks
+    }
+}
+
+impl Eq for Names {}
+
+impl Default for Names {
+    fn default() -> Self {
+        let entry = Id::from_ref(ENTRY);
+        Names {
+            values: vec![],
+            blocks: vec![entry],
+            value_ids: HashMap::new(),
+            block_ids: [(entry, BlockId::ENTRY)].into(),
+            suffix: HashMap::new(),
+        }
+    }
+}
+
+impl Names {
+    /// The name of a value.
+    pub fn value(&self, v: ValueId) -> Id {
+        self.values[v.0 as usize]
+    }
+
+    /// The name of a block.
+    pub fn block(&self, b: BlockId) -> Id {
+        self.blocks[b.0 as usize]
+    }
+
+    /// The name of a value, if it is in the table.
+    pub fn get_value(&self, v: ValueId) -> Option<Id> {
+        self.values.get(v.0 as usize).copied()
+    }
+
+    /// The name of a block, if it is in the table.
+    pub fn get_block(&self, b: BlockId) -> Option<Id> {
+        self.blocks.get(b.0 as usize).copied()
+    }
+
+    /// Find the value with the given name.
+    pub fn find_value(&self, name: &str) -> Option<ValueId> {
+        self.value_ids.get(&Id::from_ref(name)).copied()
+    }
+
+    /// Find the block with the given name.
+    pub fn find_block(&self, name: &str) -> Option<BlockId> {
+        self.block_ids.get(&Id::from_ref(name)).copied()
+    }
+
+    /// How many value IDs have been created.
+    pub fn num_values(&self) -> usize {
+        self.values.len()
+    }
+
+    /// How many block IDs have been created.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Create a new value ID.  It gets the given name if it is not taken, or
+    /// the name with a numeric suffix otherwise.
+    pub fn new_value(&mut self, name: &str) -> ValueId {
+        let name = unique_name(name, &mut self.suffix, |n| self.value_ids.contains_key(n));
+        let v = ValueId(self.values.len() as u32);
+        self.values.push(name);
+        self.value_ids.insert(name, v);
+        v
+    }
+
+    /// Create a new block ID.  It gets the given name if it is not taken, or
+    /// the name with a numeric suffix otherwise.
+    pub fn new_block(&mut self, name: &str) -> BlockId {
+        let name = unique_name(name, &mut self.suffix, |n| self.block_ids.contains_key(n));
+        let b = BlockId(self.blocks.len() as u32);
+        self.blocks.push(name);
+        self.block_ids.insert(name, b);
+        b
+    }
+
+    /// Keep only the names of the given values, and number them in the given
+    /// order.
+    fn retain_values(&mut self, kept: &[ValueId]) {
+        self.values = kept.iter().map(|v| self.value(*v)).collect();
+        self.value_ids = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, ValueId(i as u32)))
+            .collect();
+    }
+
+    /// Keep only the names of the given blocks, and number them in the given
+    /// order.
+    fn retain_blocks(&mut self, kept: &[BlockId]) {
+        self.blocks = kept.iter().map(|b| self.block(*b)).collect();
+        self.block_ids = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, BlockId(i as u32)))
+            .collect();
+    }
 }
 
-#[derive(Debug)]
+/// Find a name based on `base` for which `taken` is false.
+fn unique_name(base: &str, suffix: &mut HashMap<Id, usize>, taken: impl Fn(&Id) -> bool) -> Id {
+    let name = Id::from_ref(base);
+    if !taken(&name) {
+        return name;
+    }
+    let next = suffix.entry(name).or_insert(1);
+    loop {
+        let candidate = Id::new(format!("{base}.{next}"));
+        *next += 1;
+        if !taken(&candidate) {
+            return candidate;
+        }
+    }
+}
+
+/// Types of tiny IR values.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Type {
+    /// 64-bit signed integers.
+    #[default]
+    #[display("i64")]
+    I64,
+    /// Addresses of heap memory.
+    #[display("ptr")]
+    Ptr,
+    /// Truth values, which are 0 or 1.
+    #[display("bool")]
+    Bool,
+    /// 64-bit floating point numbers.  There are no instructions on them
+    /// yet, so they are only copied, passed and returned.
+    #[display("f64")]
+    F64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Block {
     pub insn: Vec<Instruction>,
     pub term: Vec<Terminator>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Instruction {
-    Copy { dst: Id, src: Id },
-    Const { dst: Id, src: i64 },
-    Arith { op: BOp, dst: Id, lhs: Id, rhs: Id },
-    Read(Id),
-    Print(Id),
+    Copy {
+        dst: ValueId,
+        src: ValueId,
+    },
+    Const {
+        dst: ValueId,
+        src: i64,
+    },
+    Arith {
+        op: BOp,
+        dst: ValueId,
+        lhs: ValueId,
+        rhs: ValueId,
+    },
+    /// Shift `src` by a constant number of bits, which is less than 64.
+    Shift {
+        op: ShiftOp,
+        dst: ValueId,
+        src: ValueId,
+        amount: u32,
+    },
+    Read(ValueId),
+    Print(ValueId),
+    /// Select a value based on which predecessor control came from.  Phi
+    /// instructions only appear at the start of a block, and they have one
+    /// argument per predecessor.
+    Phi {
+        dst: ValueId,
+        args: Map<BlockId, ValueId>,
+    },
+    /// Call a function and store its return value to `dst`.
+    Call {
+        dst: ValueId,
+        callee: Id,
+        args: Vec<ValueId>,
+    },
+    /// Allocate `size` words of zero-initialized heap memory, and store its
+    /// address to `dst`.
+    Alloc {
+        dst: ValueId,
+        size: ValueId,
+    },
+    /// Read the word at `addr + offset` to `dst`.  The offset is in bytes.
+    Load {
+        dst: ValueId,
+        addr: ValueId,
+        offset: i64,
+    },
+    /// Write `src` to the word at `addr + offset`.  The offset is in bytes.
+    Store {
+        addr: ValueId,
+        offset: i64,
+        src: ValueId,
+    },
+    /// Add one to a profile counter.  Only instrumented programs count.
+    Count(u32),
+    /// The code of the source statement at the given span starts here.  This
+    /// does nothing, but the backend can show the statement in its output.
+    Loc(Span),
+}
+
+/// The kinds of shifts.  Source programs can't shift, but the optimizer uses
+/// shifts to replace multiplications and divisions by powers of two.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShiftOp {
+    #[display("<<")]
+    Left,
+    /// Shift right, filling with copies of the sign bit.
+    #[display(">>")]
+    Arith,
+    /// Shift right, filling with zeros.
+    #[display(">>>")]
+    Logical,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Terminator {
-    Exit,
-    Jump(Id),
-    Branch { guard: Id, tt: Id, ff: Id },
+    /// End the program with the given exit status, or 0 without one.
+    Exit(Option<ValueId>),
+    Jump(BlockId),
+    Branch {
+        guard: ValueId,
+        tt: BlockId,
+        ff: BlockId,
+    },
+    /// Return the given value to the caller.
+    Return(ValueId),
+}
+
+impl Program {
+    /// The name of the function where execution starts.
+    pub fn main() -> Id {
+        Id::from_ref(MAIN)
+    }
+
+    /// Create a program that consists of only the given main function.
+    pub fn from_main(main: Function) -> Program {
+        Program {
+            func: [(Program::main(), main)].into(),
+        }
+    }
+
+    /// The number of profile counters, which is one more than the highest
+    /// counter that the program counts with.
+    pub fn num_counters(&self) -> u32 {
+        self.func
+            .values()
+            .flat_map(|f| f.block.values())
+            .flat_map(|b| &b.insn)
+            .filter_map(|i| match i {
+                Instruction::Count(k) => Some(*k + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Apply the given transformation to every function.
+    pub fn map_functions(self, mut f: impl FnMut(Function) -> Function) -> Program {
+        Program {
+            func: self
+                .func
+                .into_iter()
+                .map(|(n, func)| (n, f(func)))
+                .collect(),
+        }
+    }
+}
+
+impl Function {
+    /// The start block.
+    pub fn entry() -> BlockId {
+        BlockId::ENTRY
+    }
+
+    /// The declared type of the given variable.
+    pub fn type_of(&self, var: ValueId) -> Option<Type> {
+        self.decl.get(&var).copied()
+    }
+
+    /// Create and declare a new variable.  It gets the given name if it is
+    /// not taken.
+    pub fn new_var(&mut self, name: &str, ty: Type) -> ValueId {
+        let v = self.names.new_value(name);
+        self.decl.insert(v, ty);
+        v
+    }
+
+    /// The variable with the given name.  This panics if there is no such
+    /// variable, so it is meant for tests and tools rather than passes.
+    pub fn var(&self, name: &str) -> ValueId {
+        self.names
+            .find_value(name)
+            .unwrap_or_else(|| panic!("there is no variable named `{name}`"))
+    }
+
+    /// The block with the given name.  This panics if there is no such block,
+    /// so it is meant for tests and tools rather than passes.
+    pub fn block_named(&self, name: &str) -> BlockId {
+        self.names
+            .find_block(name)
+            .unwrap_or_else(|| panic!("there is no block named `{name}`"))
+    }
+
+    /// Compute the predecessors of each block.  Every block in the function has
+    /// an entry, even if it has no predecessors.
+    pub fn predecessors(&self) -> Map<BlockId, Set<BlockId>> {
+        let mut preds: Map<BlockId, Set<BlockId>> =
+            self.block.keys().map(|b| (*b, Set::new())).collect();
+        for (name, block) in &self.block {
+            for succ in block.successors() {
+                preds.entry(succ).or_default().insert(*name);
+            }
+        }
+        preds
+    }
+
+    /// Remove the blocks for which `keep` is false, together with their names
+    /// and the phi arguments coming from them, and renumber the remaining
+    /// blocks.  Names of block IDs that have no block are removed too.  The
+    /// entry block has to be kept, and no kept block may jump to a removed
+    /// one.
+    pub fn retain_blocks(&mut self, mut keep: impl FnMut(BlockId) -> bool) {
+        let kept = self
+            .block
+            .keys()
+            .copied()
+            .filter(|b| keep(*b))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kept.first(),
+            Some(&BlockId::ENTRY),
+            "the entry block was removed"
+        );
+        self.renumber_blocks(&kept);
+    }
+
+    /// Number the blocks in the given order, which lists each block once and
+    /// starts with the entry block.
+    pub fn reorder_blocks(&mut self, order: &[BlockId]) {
+        assert_eq!(
+            order.iter().copied().collect::<Set<_>>(),
+            self.block.keys().copied().collect::<Set<_>>(),
+            "the order should list each block once"
+        );
+        assert_eq!(order.len(), self.block.len(), "a block is listed twice");
+        assert_eq!(order[0], BlockId::ENTRY, "the entry block should be first");
+        self.renumber_blocks(order);
+    }
+
+    /// Remove the declarations of the variables that no instruction or
+    /// terminator reads or writes, together with their names, and renumber
+    /// the remaining variables.  Parameters are kept even if they're unused.
+    pub fn prune_vars(&mut self) {
+        let mut used = self.params.iter().copied().collect::<Set<_>>();
+        for block in self.block.values() {
+            for insn in &block.insn {
+                used.extend(insn.def());
+                used.extend(insn.uses());
+            }
+            for t in &block.term {
+                used.extend(t.uses());
+            }
+        }
+        let kept = self
+            .decl
+            .keys()
+            .copied()
+            .filter(|v| used.contains(v))
+            .collect::<Vec<_>>();
+        if kept.len() == self.names.num_values() {
+            return;
+        }
+        let renumber = kept
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (*v, ValueId(i as u32)))
+            .collect::<Map<_, _>>();
+        self.names.retain_values(&kept);
+        self.params.iter_mut().for_each(|v| *v = renumber[v]);
+        self.decl = std::mem::take(&mut self.decl)
+            .into_iter()
+            .filter_map(|(v, ty)| Some((*renumber.get(&v)?, ty)))
+            .collect();
+        for block in self.block.values_mut() {
+            for insn in &mut block.insn {
+                insn.rename_def(|v| renumber[&v]);
+                insn.rename_uses(|v| renumber[&v]);
+            }
+            for t in &mut block.term {
+                t.rename_uses(|v| renumber[&v]);
+            }
+        }
+    }
+
+    /// Keep the given blocks and give them consecutive IDs in order.
+    fn renumber_blocks(&mut self, kept: &[BlockId]) {
+        let renumber = kept
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (*b, BlockId(i as u32)))
+            .collect::<Map<_, _>>();
+        self.names.retain_blocks(kept);
+        self.block = std::mem::take(&mut self.block)
+            .into_iter()
+            .filter_map(|(b, mut block)| {
+                let b = *renumber.get(&b)?;
+                for insn in &mut block.insn {
+                    if let Instruction::Phi { args, .. } = insn {
+                        *args = std::mem::take(args)
+                            .into_iter()
+                            .filter_map(|(pred, v)| Some((*renumber.get(&pred)?, v)))
+                            .collect();
+                    }
+                }
+                for t in &mut block.term {
+                    t.rename_targets(|to| renumber[&to]);
+                }
+                Some((b, block))
+            })
+            .collect();
+    }
+}
+
+impl Block {
+    /// The blocks this block may jump to, in the order they appear in the
+    /// terminators.
+    pub fn successors(&self) -> Vec<BlockId> {
+        self.term.iter().flat_map(Terminator::successors).collect()
+    }
+}
+
+impl Type {
+    /// The operand type and the result type of a binary operator.
+    pub fn of_bop(op: BOp) -> (Type, Type) {
+        match op {
+            BOp::Mul | BOp::Div | BOp::Add | BOp::Sub | BOp::Lt => (Type::I64, Type::I64),
+        }
+    }
+}
+
+impl Instruction {
+    /// The variable this instruction writes to, if any.
+    pub fn def(&self) -> Option<ValueId> {
+        use Instruction::*;
+        match self {
+            Copy { dst, .. }
+            | Const { dst, .. }
+            | Arith { dst, .. }
+            | Shift { dst, .. }
+            | Phi { dst, .. }
+            | Call { dst, .. }
+            | Alloc { dst, .. }
+            | Load { dst, .. } => Some(*dst),
+            Read(dst) => Some(*dst),
+            Print(_) | Store { .. } | Count(_) | Loc(_) => None,
+        }
+    }
+
+    /// The variables this instruction reads.  For phi instructions, these are
+    /// the values coming from all predecessors.
+    pub fn uses(&self) -> Vec<ValueId> {
+        use Instruction::*;
+        match self {
+            Copy { src, .. } | Shift { src, .. } => vec![*src],
+            Const { .. } | Read(_) | Count(_) | Loc(_) => vec![],
+            Arith { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Print(src) => vec![*src],
+            Phi { args, .. } => args.values().copied().collect(),
+            Call { args, .. } => args.clone(),
+            Alloc { size, .. } => vec![*size],
+            Load { addr, .. } => vec![*addr],
+            Store { addr, src, .. } => vec![*addr, *src],
+        }
+    }
+
+    /// Apply the given renaming to the variables this instruction reads.
+    pub fn rename_uses(&mut self, mut f: impl FnMut(ValueId) -> ValueId) {
+        use Instruction::*;
+        match self {
+            Copy { src, .. } | Shift { src, .. } => *src = f(*src),
+            Const { .. } | Read(_) | Count(_) | Loc(_) => {}
+            Arith { lhs, rhs, .. } => {
+                *lhs = f(*lhs);
+                *rhs = f(*rhs);
+            }
+            Print(src) => *src = f(*src),
+            Phi { args, .. } => args.values_mut().for_each(|v| *v = f(*v)),
+            Call { args, .. } => args.iter_mut().for_each(|v| *v = f(*v)),
+            Alloc { size, .. } => *size = f(*size),
+            Load { addr, .. } => *addr = f(*addr),
+            Store { addr, src, .. } => {
+                *addr = f(*addr);
+                *src = f(*src);
+            }
+        }
+    }
+
+    /// Apply the given renaming to the variable this instruction writes to.
+    pub fn rename_def(&mut self, f: impl FnOnce(ValueId) -> ValueId) {
+        use Instruction::*;
+        match self {
+            Copy { dst, .. }
+            | Const { dst, .. }
+            | Arith { dst, .. }
+            | Shift { dst, .. }
+            | Phi { dst, .. }
+            | Call { dst, .. }
+            | Alloc { dst, .. }
+            | Load { dst, .. } => *dst = f(*dst),
+            Read(dst) => *dst = f(*dst),
+            Print(_) | Store { .. } | Count(_) | Loc(_) => {}
+        }
+    }
+
+    /// Does this instruction have an effect other than writing to its
+    /// destination?  We assume that all calls do.  Allocations and loads
+    /// don't, so they can be removed if their results are unused.
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Read(_)
+                | Instruction::Print(_)
+                | Instruction::Call { .. }
+                | Instruction::Store { .. }
+                | Instruction::Count(_)
+        )
+    }
+
+    /// Does this instruction access memory?
+    pub fn is_memory_access(&self) -> bool {
+        matches!(self, Instruction::Load { .. } | Instruction::Store { .. })
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Instruction::Phi { .. })
+    }
+}
+
+impl Terminator {
+    /// The variables this terminator reads.
+    pub fn uses(&self) -> Vec<ValueId> {
+        match self {
+            Terminator::Branch { guard, .. } => vec![*guard],
+            Terminator::Return(value) | Terminator::Exit(Some(value)) => vec![*value],
+            Terminator::Exit(None) | Terminator::Jump(_) => vec![],
+        }
+    }
+
+    /// Apply the given renaming to the variables this terminator reads.
+    pub fn rename_uses(&mut self, f: impl FnOnce(ValueId) -> ValueId) {
+        match self {
+            Terminator::Branch { guard, .. } => *guard = f(*guard),
+            Terminator::Return(value) | Terminator::Exit(Some(value)) => *value = f(*value),
+            Terminator::Exit(None) | Terminator::Jump(_) => {}
+        }
+    }
+
+    /// Apply the given renaming to the blocks this terminator jumps to.
+    pub fn rename_targets(&mut self, mut f: impl FnMut(BlockId) -> BlockId) {
+        match self {
+            Terminator::Exit(_) | Terminator::Return(_) => {}
+            Terminator::Jump(target) => *target = f(*target),
+            Terminator::Branch { tt, ff, .. } => {
+                *tt = f(*tt);
+                *ff = f(*ff);
+            }
+        }
+    }
+
+    /// The blocks this terminator may jump to.
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Exit(_) | Terminator::Return(_) => vec![],
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { tt, ff, .. } => vec![*tt, *ff],
+        }
+    }
 }
@@ -0,0 +1,394 @@
+//! A builder for constructing tiny IR programs programmatically.
+//!
+//! The builder keeps track of a current function and a current block, and
+//! appends instructions to the current block.  Misusing it (appending to a
+//! block that already has a terminator, creating the same block twice, ...)
+//! is a bug in the caller, so the builder panics in these cases.
+//!
+//! Variables are declared as `i64` the first time they are used unless they
+//! were declared with another type before.
+//!
+//! ```
+//! use smol::middle::tir::*;
+//!
+//! let mut b = Builder::new();
+//! b.read("x");
+//! b.branch("x", "then", "else");
+//! b.block("then");
+//! b.print("x");
+//! b.jump("else");
+//! b.block("else");
+//! b.exit();
+//! let program = b.finish();
+//! assert_eq!(program.func[&Program::main()].block.len(), 3);
+//! ```
+
+use super::*;
+use crate::front::ast::BOp;
+
+pub struct Builder {
+    program: Program,
+    /// The function we are adding blocks to.
+    func: Id,
+    /// The block we are adding instructions to.
+    block: BlockId,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    /// Create a builder whose current block is the entry block of `main`.
+    pub fn new() -> Self {
+        let mut builder = Builder {
+            program: Program::default(),
+            func: Program::main(),
+            block: Function::entry(),
+        };
+        builder.function(MAIN, &[], Type::I64);
+        builder
+    }
+
+    /// Start a new function with the given parameters, all of type `i64`.  The
+    /// current block becomes the entry block of the new function.
+    pub fn function(&mut self, name: &str, params: &[&str], ret: Type) -> Id {
+        let name = Id::from_ref(name);
+        let mut func = Function {
+            ret,
+            ..Function::default()
+        };
+        for p in params {
+            let p = func.new_var(p, Type::I64);
+            func.params.push(p);
+        }
+        assert!(
+            self.program.func.insert(name, func).is_none(),
+            "the function `{name}` is defined twice"
+        );
+        self.func = name;
+        self.block(ENTRY);
+        name
+    }
+
+    /// Switch to an existing function.  The current block becomes its entry
+    /// block.
+    pub fn switch_function(&mut self, name: &str) {
+        let name = Id::from_ref(name);
+        assert!(
+            self.program.func.contains_key(&name),
+            "there is no function named `{name}`"
+        );
+        self.func = name;
+        self.block = Function::entry();
+    }
+
+    /// Declare a variable in the current function with the given type.
+    pub fn declare(&mut self, name: &str, ty: Type) -> ValueId {
+        let func = self.current_function();
+        match func.names.find_value(name) {
+            Some(var) => {
+                let old = func.decl.insert(var, ty);
+                assert_eq!(
+                    old,
+                    Some(ty),
+                    "the variable `{name}` is declared with two types"
+                );
+                var
+            }
+            None => func.new_var(name, ty),
+        }
+    }
+
+    /// Create a new empty block in the current function and make it the
+    /// current block.
+    pub fn block(&mut self, name: &str) -> BlockId {
+        let id = self.label(name);
+        let empty = Block {
+            insn: vec![],
+            term: vec![],
+        };
+        assert!(
+            self.current_function().block.insert(id, empty).is_none(),
+            "the block `{name}` is created twice"
+        );
+        self.block = id;
+        id
+    }
+
+    /// Make an existing block the current block.
+    pub fn switch_to(&mut self, name: &str) {
+        let func = self.current_function();
+        let id = func
+            .names
+            .find_block(name)
+            .filter(|b| func.block.contains_key(b));
+        self.block = id.unwrap_or_else(|| panic!("there is no block named `{name}`"));
+    }
+
+    /// The current block.
+    pub fn current_block(&self) -> BlockId {
+        self.block
+    }
+
+    /// The ID of the block with the given name in the current function.  The
+    /// block does not have to be created yet.
+    pub fn label(&mut self, name: &str) -> BlockId {
+        let names = &mut self.current_function().names;
+        names
+            .find_block(name)
+            .unwrap_or_else(|| names.new_block(name))
+    }
+
+    /// Get a variable, declaring it as an `i64` if it is not declared yet.
+    pub fn var(&mut self, name: &str) -> ValueId {
+        let func = self.current_function();
+        func.names
+            .find_value(name)
+            .unwrap_or_else(|| func.new_var(name, Type::I64))
+    }
+
+    /// Append an instruction to the current block.  The variables in the
+    /// instruction have to be declared already.
+    pub fn insn(&mut self, insn: Instruction) {
+        let name = self.block_name();
+        let block = self.current_block_mut();
+        assert!(
+            block.term.is_empty(),
+            "cannot add instructions to block `{name}` after its terminator"
+        );
+        block.insn.push(insn);
+    }
+
+    /// Set the terminator of the current block.  The variables in the
+    /// terminator have to be declared already.
+    pub fn terminate(&mut self, term: Terminator) {
+        let name = self.block_name();
+        let block = self.current_block_mut();
+        assert!(
+            block.term.is_empty(),
+            "the block `{name}` already has a terminator"
+        );
+        block.term.push(term);
+    }
+
+    // SECTION: shorthands for instructions
+
+    pub fn copy(&mut self, dst: &str, src: &str) {
+        let (dst, src) = (self.var(dst), self.var(src));
+        self.insn(Instruction::Copy { dst, src });
+    }
+
+    pub fn constant(&mut self, dst: &str, src: i64) {
+        let dst = self.var(dst);
+        self.insn(Instruction::Const { dst, src });
+    }
+
+    pub fn arith(&mut self, op: BOp, dst: &str, lhs: &str, rhs: &str) {
+        let (dst, lhs, rhs) = (self.var(dst), self.var(lhs), self.var(rhs));
+        self.insn(Instruction::Arith { op, dst, lhs, rhs });
+    }
+
+    pub fn shift(&mut self, op: ShiftOp, dst: &str, src: &str, amount: u32) {
+        let (dst, src) = (self.var(dst), self.var(src));
+        self.insn(Instruction::Shift {
+            op,
+            dst,
+            src,
+            amount,
+        });
+    }
+
+    pub fn read(&mut self, dst: &str) {
+        let dst = self.var(dst);
+        self.insn(Instruction::Read(dst));
+    }
+
+    pub fn print(&mut self, src: &str) {
+        let src = self.var(src);
+        self.insn(Instruction::Print(src));
+    }
+
+    pub fn count(&mut self, counter: u32) {
+        self.insn(Instruction::Count(counter));
+    }
+
+    /// Mark where the code of the source statement at `span` starts.
+    pub fn loc(&mut self, span: Span) {
+        self.insn(Instruction::Loc(span));
+    }
+
+    /// Add a phi instruction with (predecessor, value) pairs.
+    pub fn phi(&mut self, dst: &str, args: &[(&str, &str)]) {
+        let dst = self.var(dst);
+        let args = args
+            .iter()
+            .map(|(pred, val)| (self.label(pred), self.var(val)))
+            .collect();
+        self.insn(Instruction::Phi { dst, args });
+    }
+
+    pub fn call(&mut self, dst: &str, callee: &str, args: &[&str]) {
+        let dst = self.var(dst);
+        let args = args.iter().map(|a| self.var(a)).collect();
+        let callee = Id::from_ref(callee);
+        self.insn(Instruction::Call { dst, callee, args });
+    }
+
+    /// Allocate memory, declaring `dst` as a pointer.
+    pub fn alloc(&mut self, dst: &str, size: &str) {
+        let dst = self.declare(dst, Type::Ptr);
+        let size = self.var(size);
+        self.insn(Instruction::Alloc { dst, size });
+    }
+
+    pub fn load(&mut self, dst: &str, addr: &str, offset: i64) {
+        let (dst, addr) = (self.var(dst), self.var(addr));
+        self.insn(Instruction::Load { dst, addr, offset });
+    }
+
+    pub fn store(&mut self, addr: &str, offset: i64, src: &str) {
+        let (addr, src) = (self.var(addr), self.var(src));
+        self.insn(Instruction::Store { addr, offset, src });
+    }
+
+    // SECTION: shorthands for terminators
+
+    pub fn exit(&mut self) {
+        self.terminate(Terminator::Exit(None));
+    }
+
+    pub fn exit_with(&mut self, status: &str) {
+        let status = self.var(status);
+        self.terminate(Terminator::Exit(Some(status)));
+    }
+
+    pub fn jump(&mut self, target: &str) {
+        let target = self.label(target);
+        self.terminate(Terminator::Jump(target));
+    }
+
+    pub fn branch(&mut self, guard: &str, tt: &str, ff: &str) {
+        let guard = self.var(guard);
+        let (tt, ff) = (self.label(tt), self.label(ff));
+        self.terminate(Terminator::Branch { guard, tt, ff });
+    }
+
+    pub fn ret(&mut self, value: &str) {
+        let value = self.var(value);
+        self.terminate(Terminator::Return(value));
+    }
+
+    /// Finish building the program.  This checks that every block has a
+    /// terminator and every jump target exists, but it does not run the full
+    /// verifier.
+    pub fn finish(self) -> Program {
+        for (fname, func) in &self.program.func {
+            for (id, block) in &func.block {
+                let name = func.names.block(*id);
+                assert!(
+                    !block.term.is_empty(),
+                    "the block `{name}` in function `{fname}` has no terminator"
+                );
+                for succ in block.successors() {
+                    let succ_name = func.names.block(succ);
+                    assert!(
+                        func.block.contains_key(&succ),
+                        "the block `{name}` in function `{fname}` jumps to a missing block `{succ_name}`"
+                    );
+                }
+            }
+        }
+        self.program
+    }
+
+    /// Finish building a program with only `main`, and return `main`.
+    pub fn finish_main(self) -> Function {
+        let mut program = self.finish();
+        assert_eq!(program.func.len(), 1, "there are functions other than main");
+        program.func.remove(&Program::main()).unwrap()
+    }
+
+    // SECTION: helpers
+
+    fn block_name(&self) -> Id {
+        self.program.func[&self.func].names.block(self.block)
+    }
+
+    fn current_function(&mut self) -> &mut Function {
+        self.program.func.get_mut(&self.func).unwrap()
+    }
+
+    fn current_block_mut(&mut self) -> &mut Block {
+        let block = self.block;
+        self.current_function().block.get_mut(&block).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_functions() {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.call("y", "double", &["x"]);
+        b.print("y");
+        b.exit();
+        b.function("double", &["a"], Type::I64);
+        b.arith(BOp::Add, "r", "a", "a");
+        b.ret("r");
+        let p = b.finish();
+        crate::middle::verify(&p).unwrap();
+        let double = &p.func[&Id::from_ref("double")];
+        assert_eq!(double.params, vec![double.var("a")]);
+        assert_eq!(double.decl.len(), 2);
+        assert_eq!(
+            p.func[&Program::main()].block[&Function::entry()]
+                .insn
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn declares_pointers() {
+        let mut b = Builder::new();
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.store("p", 0, "n");
+        b.load("m", "p", 0);
+        b.exit();
+        let main = b.finish_main();
+        assert_eq!(main.type_of(main.var("p")), Some(Type::Ptr));
+        assert_eq!(main.type_of(main.var("m")), Some(Type::I64));
+    }
+
+    #[test]
+    #[should_panic(expected = "after its terminator")]
+    fn append_after_terminator() {
+        let mut b = Builder::new();
+        b.exit();
+        b.print("x");
+    }
+
+    #[test]
+    #[should_panic(expected = "missing block")]
+    fn missing_target() {
+        let mut b = Builder::new();
+        b.jump("nowhere");
+        b.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "has no terminator")]
+    fn unterminated_block() {
+        let mut b = Builder::new();
+        b.exit();
+        b.block("open");
+        b.finish();
+    }
+}
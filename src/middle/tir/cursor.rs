@@ -0,0 +1,187 @@
+//! A cursor for rewriting the instructions of a block in place.
+//!
+//! Transformation passes often walk over a block and insert, replace, or
+//! delete instructions as they go.  Doing this with indices is error-prone
+//! since every insertion and removal shifts the rest of the instructions.  The
+//! cursor does this bookkeeping instead.
+//!
+//! ```
+//! use smol::middle::tir::*;
+//!
+//! # let x = ValueId(0);
+//! let mut block = Block {
+//!     insn: vec![Instruction::Read(x), Instruction::Print(x)],
+//!     term: vec![Terminator::Exit(None)],
+//! };
+//! let mut cursor = block.cursor();
+//! while let Some(insn) = cursor.next() {
+//!     if let Instruction::Read(_) = insn {
+//!         cursor.insert_after(Instruction::Print(x));
+//!     }
+//! }
+//! assert_eq!(block.insn.len(), 3);
+//! ```
+
+use super::*;
+
+pub struct Cursor<'a> {
+    insn: &'a mut Vec<Instruction>,
+    /// The index of the current instruction.  This is `None` before the first
+    /// call to `next`, and after the current instruction is removed.
+    current: Option<usize>,
+    /// The index of the instruction `next` moves to.
+    next: usize,
+}
+
+impl Block {
+    /// Create a cursor over the instructions of this block.  The cursor starts
+    /// before the first instruction.
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(&mut self.insn)
+    }
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(insn: &'a mut Vec<Instruction>) -> Self {
+        Cursor {
+            insn,
+            current: None,
+            next: 0,
+        }
+    }
+
+    /// Move to the next instruction and return it.  Instructions inserted with
+    /// the cursor are skipped.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&mut Instruction> {
+        if self.next >= self.insn.len() {
+            self.current = None;
+            self.next = self.insn.len();
+            return None;
+        }
+        self.current = Some(self.next);
+        self.next += 1;
+        self.insn.get_mut(self.next - 1)
+    }
+
+    /// The current instruction.
+    pub fn current(&self) -> Option<&Instruction> {
+        self.current.map(|i| &self.insn[i])
+    }
+
+    /// The current instruction.
+    pub fn current_mut(&mut self) -> Option<&mut Instruction> {
+        self.current.map(|i| &mut self.insn[i])
+    }
+
+    /// The instructions before the current one (or before the position of the
+    /// removed instruction).
+    pub fn before(&self) -> &[Instruction] {
+        &self.insn[..self.current.unwrap_or(self.next)]
+    }
+
+    /// The instructions the cursor will visit next.
+    pub fn after(&self) -> &[Instruction] {
+        &self.insn[self.next..]
+    }
+
+    /// Insert an instruction before the current one.  Without a current
+    /// instruction, insert it where the cursor is.
+    pub fn insert_before(&mut self, insn: Instruction) {
+        match self.current {
+            Some(i) => {
+                self.insn.insert(i, insn);
+                self.current = Some(i + 1);
+            }
+            None => self.insn.insert(self.next, insn),
+        }
+        self.next += 1;
+    }
+
+    /// Insert an instruction after the current one (and after the instructions
+    /// inserted after it before).  The cursor does not visit it.  Without a
+    /// current instruction, insert it where the cursor is.
+    pub fn insert_after(&mut self, insn: Instruction) {
+        self.insn.insert(self.next, insn);
+        self.next += 1;
+    }
+
+    /// Replace the current instruction, and return the old one.
+    pub fn replace(&mut self, insn: Instruction) -> Instruction {
+        let i = self
+            .current
+            .expect("there is no current instruction to replace");
+        std::mem::replace(&mut self.insn[i], insn)
+    }
+
+    /// Remove the current instruction and return it.  There is no current
+    /// instruction afterwards until `next` is called.
+    pub fn remove(&mut self) -> Instruction {
+        let i = self
+            .current
+            .take()
+            .expect("there is no current instruction to remove");
+        self.next -= 1;
+        self.insn.remove(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn konst(n: i64) -> Instruction {
+        Instruction::Const {
+            dst: ValueId(0),
+            src: n,
+        }
+    }
+
+    #[test]
+    fn rewrites_while_iterating() {
+        let mut insn = (0..5).map(konst).collect::<Vec<_>>();
+        let mut cursor = Cursor::new(&mut insn);
+        let mut visited = vec![];
+        while let Some(Instruction::Const { src, .. }) = cursor.next() {
+            let n = *src;
+            visited.push(n);
+            match n {
+                0 => {
+                    cursor.remove();
+                }
+                1 => cursor.insert_before(konst(10)),
+                2 => {
+                    cursor.replace(konst(20));
+                }
+                3 => {
+                    cursor.insert_after(konst(30));
+                    cursor.insert_after(konst(31));
+                }
+                _ => {
+                    cursor.remove();
+                    cursor.insert_after(konst(40));
+                }
+            }
+        }
+        assert_eq!(visited, vec![0, 1, 2, 3, 4]);
+        assert_eq!(insn, [10, 1, 20, 3, 30, 31, 40].map(konst).to_vec());
+    }
+
+    #[test]
+    fn before_and_after() {
+        let mut insn = (0..3).map(konst).collect::<Vec<_>>();
+        let mut cursor = Cursor::new(&mut insn);
+        assert_eq!(cursor.current(), None);
+        cursor.next();
+        cursor.next();
+        assert_eq!(cursor.current(), Some(&konst(1)));
+        assert_eq!(cursor.before(), &[konst(0)]);
+        assert_eq!(cursor.after(), &[konst(2)]);
+        cursor.remove();
+        assert_eq!(cursor.current(), None);
+        assert_eq!(cursor.before(), &[konst(0)]);
+        assert_eq!(cursor.after(), &[konst(2)]);
+        assert_eq!(cursor.next(), Some(&mut konst(2)));
+        assert_eq!(cursor.next(), None);
+    }
+}
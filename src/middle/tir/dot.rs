@@ -0,0 +1,100 @@
+//! Rendering the CFGs of a tiny IR program as a Graphviz DOT graph.
+//!
+//! Each function becomes a cluster, and each block becomes a node that lists
+//! its instructions in the syntax of `doc/ir.md`.
+
+use std::fmt::Write;
+
+use super::*;
+
+/// Render the CFGs of all functions in the program.  If `label_edges` is set,
+/// the edges out of branches are labeled with the condition under which they
+/// are taken.
+pub fn cfg_dot(program: &Program, label_edges: bool) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph tir {{").unwrap();
+    writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+    for (fname, func) in &program.func {
+        let names = &func.names;
+        let node = |b: &BlockId| quote(&format!("{fname}/{}", names.block(*b)));
+
+        writeln!(out, "  subgraph {} {{", quote(&format!("cluster_{fname}"))).unwrap();
+        writeln!(out, "    label={};", quote(fname)).unwrap();
+        for (id, block) in &func.block {
+            // `\l` ends a left-aligned line in DOT labels.
+            let mut label = format!("{}:\\l", escape(&names.block(*id)));
+            for insn in &block.insn {
+                label += &format!("  {}\\l", escape(&insn.display(names).to_string()));
+            }
+            for term in &block.term {
+                label += &format!("  {}\\l", escape(&term.display(names).to_string()));
+            }
+            writeln!(out, "    {} [label=\"{label}\"];", node(id)).unwrap();
+        }
+        for (id, block) in &func.block {
+            for term in &block.term {
+                let edges: Vec<(BlockId, String)> = match term {
+                    Terminator::Branch { guard, tt, ff } if label_edges => {
+                        let guard = names.value(*guard);
+                        vec![(*tt, guard.to_string()), (*ff, format!("!{guard}"))]
+                    }
+                    _ => term
+                        .successors()
+                        .into_iter()
+                        .map(|s| (s, String::new()))
+                        .collect(),
+                };
+                for (to, label) in edges {
+                    write!(out, "    {} -> {}", node(id), node(&to)).unwrap();
+                    if !label.is_empty() {
+                        write!(out, " [label={}]", quote(&label)).unwrap();
+                    }
+                    writeln!(out, ";").unwrap();
+                }
+            }
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Escape a string for use inside a quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_blocks_and_edges() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.branch("x", "then", "end");
+        b.block("then");
+        b.print("x");
+        b.jump("end");
+        b.block("end");
+        b.exit();
+        let p = b.finish();
+
+        let dot = cfg_dot(&p, false);
+        assert!(dot.starts_with("digraph tir {"));
+        assert!(
+            dot.contains(r#""main/$entry" [label="$entry:\l  $read x\l  $branch x then end\l"];"#)
+        );
+        assert!(dot.contains(r#""main/$entry" -> "main/then";"#));
+        assert!(dot.contains(r#""main/then" -> "main/end";"#));
+        assert_eq!(dot.matches("->").count(), 3);
+
+        let labeled = cfg_dot(&p, true);
+        assert!(labeled.contains(r#""main/$entry" -> "main/then" [label="x"];"#));
+        assert!(labeled.contains(r#""main/$entry" -> "main/end" [label="!x"];"#));
+    }
+}
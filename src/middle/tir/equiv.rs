@@ -0,0 +1,322 @@
+//! Structural equivalence of tiny IR programs.
+//!
+//! Two functions are equivalent if one can be turned into the other by
+//! consistently renaming its variables and blocks.  This lets tests assert on
+//! the shape of a pass's output without depending on the exact names of the
+//! temporaries and blocks the pass created.
+//!
+//! Function names are not renamed, since calls refer to them.  Blocks are
+//! matched by walking both CFGs from their entry blocks in lockstep, so blocks
+//! that are unreachable from the entry are ignored, and so are declarations of
+//! variables that are never used.
+
+use std::collections::HashMap;
+
+use super::*;
+
+/// Are the two programs the same up to renaming of variables and blocks
+/// within each function?
+pub fn equiv(a: &Program, b: &Program) -> bool {
+    a.func.len() == b.func.len()
+        && a.func
+            .iter()
+            .all(|(name, fa)| b.func.get(name).is_some_and(|fb| equiv_function(fa, fb)))
+}
+
+/// Are the two functions the same up to renaming of variables and blocks?
+pub fn equiv_function(a: &Function, b: &Function) -> bool {
+    if a.ret != b.ret || a.params.len() != b.params.len() {
+        return false;
+    }
+    let mut m = Matching {
+        a,
+        b,
+        values: Bijection::default(),
+        blocks: Bijection::default(),
+        worklist: vec![],
+    };
+    if !a.params.iter().zip(&b.params).all(|(x, y)| m.value(x, y)) {
+        return false;
+    }
+    m.block(&Function::entry(), &Function::entry());
+
+    // Phi arguments are keyed by predecessors, which may not be matched yet
+    // when we see the phi, so we compare them at the end.
+    let mut phis = vec![];
+    while let Some((ba, bb)) = m.worklist.pop() {
+        let (ba, bb) = match (a.block.get(&ba), b.block.get(&bb)) {
+            (Some(ba), Some(bb)) => (ba, bb),
+            (None, None) => continue,
+            _ => return false,
+        };
+        if ba.insn.len() != bb.insn.len() || ba.term.len() != bb.term.len() {
+            return false;
+        }
+        for (x, y) in ba.insn.iter().zip(&bb.insn) {
+            if !m.insn(x, y) {
+                return false;
+            }
+            if let (Instruction::Phi { args: x, .. }, Instruction::Phi { args: y, .. }) = (x, y) {
+                phis.push((x, y));
+            }
+        }
+        for (x, y) in ba.term.iter().zip(&bb.term) {
+            if !m.term(x, y) {
+                return false;
+            }
+        }
+    }
+
+    phis.into_iter().all(|(x, y)| {
+        x.len() == y.len()
+            && x.iter().all(|(pred, val)| {
+                let other = m.blocks.forward.get(pred).and_then(|p| y.get(p));
+                other.is_some_and(|other| m.value(val, other))
+            })
+    })
+}
+
+/// A one-to-one correspondence that we build up incrementally.
+struct Bijection<T> {
+    forward: HashMap<T, T>,
+    backward: HashMap<T, T>,
+}
+
+impl<T> Default for Bijection<T> {
+    fn default() -> Self {
+        Bijection {
+            forward: HashMap::new(),
+            backward: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Copy + Eq + std::hash::Hash> Bijection<T> {
+    /// Try to add the pair `x <-> y`.  Returns whether the pair is consistent
+    /// with the existing ones, and whether it is new.
+    fn pair(&mut self, x: T, y: T) -> (bool, bool) {
+        match (self.forward.get(&x), self.backward.get(&y)) {
+            (Some(y2), Some(x2)) => (*y2 == y && *x2 == x, false),
+            (None, None) => {
+                self.forward.insert(x, y);
+                self.backward.insert(y, x);
+                (true, true)
+            }
+            _ => (false, false),
+        }
+    }
+}
+
+struct Matching<'a> {
+    a: &'a Function,
+    b: &'a Function,
+    values: Bijection<ValueId>,
+    blocks: Bijection<BlockId>,
+    /// Matched block pairs we have not compared yet.
+    worklist: Vec<(BlockId, BlockId)>,
+}
+
+impl Matching<'_> {
+    fn value(&mut self, x: &ValueId, y: &ValueId) -> bool {
+        let (ok, new) = self.values.pair(*x, *y);
+        ok && (!new || self.a.type_of(*x) == self.b.type_of(*y))
+    }
+
+    fn values(&mut self, xs: &[ValueId], ys: &[ValueId]) -> bool {
+        xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| self.value(x, y))
+    }
+
+    fn block(&mut self, x: &BlockId, y: &BlockId) -> bool {
+        let (ok, new) = self.blocks.pair(*x, *y);
+        if new {
+            self.worklist.push((*x, *y));
+        }
+        ok
+    }
+
+    fn insn(&mut self, x: &Instruction, y: &Instruction) -> bool {
+        use Instruction::*;
+        match (x, y) {
+            (Copy { dst, src }, Copy { dst: d, src: s }) => self.values(&[*dst, *src], &[*d, *s]),
+            (Const { dst, src }, Const { dst: d, src: s }) => src == s && self.value(dst, d),
+            (
+                Arith { op, dst, lhs, rhs },
+                Arith {
+                    op: o,
+                    dst: d,
+                    lhs: l,
+                    rhs: r,
+                },
+            ) => op == o && self.values(&[*dst, *lhs, *rhs], &[*d, *l, *r]),
+            (
+                Shift {
+                    op,
+                    dst,
+                    src,
+                    amount,
+                },
+                Shift {
+                    op: o,
+                    dst: d,
+                    src: s,
+                    amount: a,
+                },
+            ) => op == o && amount == a && self.values(&[*dst, *src], &[*d, *s]),
+            (Read(x), Read(y)) | (Print(x), Print(y)) => self.value(x, y),
+            (Count(k), Count(l)) => k == l,
+            (Loc(s), Loc(t)) => s == t,
+            (Phi { dst, .. }, Phi { dst: d, .. }) => self.value(dst, d),
+            (
+                Call { dst, callee, args },
+                Call {
+                    dst: d,
+                    callee: c,
+                    args: a,
+                },
+            ) => callee == c && self.value(dst, d) && self.values(args, a),
+            (Alloc { dst, size }, Alloc { dst: d, size: s }) => {
+                self.values(&[*dst, *size], &[*d, *s])
+            }
+            (
+                Load { dst, addr, offset },
+                Load {
+                    dst: d,
+                    addr: a,
+                    offset: o,
+                },
+            ) => offset == o && self.values(&[*dst, *addr], &[*d, *a]),
+            (
+                Store { addr, offset, src },
+                Store {
+                    addr: a,
+                    offset: o,
+                    src: s,
+                },
+            ) => offset == o && self.values(&[*addr, *src], &[*a, *s]),
+            _ => false,
+        }
+    }
+
+    fn term(&mut self, x: &Terminator, y: &Terminator) -> bool {
+        use Terminator::*;
+        match (x, y) {
+            (Exit(None), Exit(None)) => true,
+            (Exit(Some(x)), Exit(Some(y))) => self.value(x, y),
+            (Jump(x), Jump(y)) => self.block(x, y),
+            (
+                Branch { guard, tt, ff },
+                Branch {
+                    guard: g,
+                    tt: t,
+                    ff: f,
+                },
+            ) => self.value(guard, g) && self.block(tt, t) && self.block(ff, f),
+            (Return(x), Return(y)) => self.value(x, y),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    // SECTION: helpers
+
+    // $read x; $if x { := y (+ x x) } {}; $print y, with the given names.
+    fn program(x: &str, y: &str, then: &str, join: &str) -> Program {
+        let mut b = Builder::new();
+        b.read(x);
+        b.branch(x, then, join);
+        b.block(then);
+        b.arith(BOp::Add, y, x, x);
+        b.jump(join);
+        b.block(join);
+        b.print(y);
+        b.exit();
+        b.finish()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn renaming() {
+        let p = program("x", "y", "then", "join");
+        assert!(equiv(&p, &p));
+        assert!(equiv(&p, &program("$t0", "$t1", "b1", "b2")));
+    }
+
+    #[test]
+    fn different_shape() {
+        let p = program("x", "y", "then", "join");
+        // Using one variable for both x and y is not a renaming.
+        assert!(!equiv(&p, &program("x", "x", "then", "join")));
+
+        let mut swapped = p.clone();
+        let main = swapped.func.get_mut(&Program::main()).unwrap();
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        if let Terminator::Branch { tt, ff, .. } = &mut entry.term[0] {
+            std::mem::swap(tt, ff);
+        }
+        assert!(!equiv(&p, &swapped));
+
+        let mut constant = p.clone();
+        let main = constant.func.get_mut(&Program::main()).unwrap();
+        let x = main.var("x");
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        entry.insn[0] = Instruction::Const { dst: x, src: 1 };
+        assert!(!equiv(&p, &constant));
+    }
+
+    #[test]
+    fn phis_and_calls() {
+        let build = |l: &str, r: &str, v: &str| {
+            let mut b = Builder::new();
+            b.read("c");
+            b.branch("c", l, r);
+            b.block(l);
+            b.constant("a", 1);
+            b.jump("join");
+            b.block(r);
+            b.call("b", "f", &["c"]);
+            b.jump("join");
+            b.block("join");
+            b.phi(v, &[(l, "a"), (r, "b")]);
+            b.print(v);
+            b.exit();
+            b.function("f", &["n"], Type::I64);
+            b.ret("n");
+            b.finish()
+        };
+        let p = build("l", "r", "v");
+        assert!(equiv(&p, &build("left", "right", "w")));
+
+        // The phi arguments have to follow the renaming of the blocks.
+        let mut flipped = p.clone();
+        let main = flipped.func.get_mut(&Program::main()).unwrap();
+        let (a, b) = (main.var("a"), main.var("b"));
+        let join = main.block_named("join");
+        if let Instruction::Phi { args, .. } = &mut main.block.get_mut(&join).unwrap().insn[0] {
+            args.values_mut()
+                .for_each(|v| *v = if *v == a { b } else { a });
+        }
+        assert!(!equiv(&p, &flipped));
+    }
+
+    #[test]
+    fn types_matter() {
+        let mut b = Builder::new();
+        b.constant("n", 1);
+        b.alloc("p", "n");
+        b.exit();
+        let ptr = b.finish();
+        let mut b = Builder::new();
+        b.constant("n", 1);
+        // `p` is declared as an i64 here.
+        let (dst, size) = (b.var("p"), b.var("n"));
+        b.insn(Instruction::Alloc { dst, size });
+        b.exit();
+        assert!(!equiv(&ptr, &b.finish()));
+    }
+}
@@ -0,0 +1,163 @@
+//! Printing tiny IR programs in the syntax described in `doc/ir.md`.
+//!
+//! Values and blocks are printed with their names rather than their IDs.
+
+use std::fmt::{Display, Formatter, Result};
+
+use super::*;
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (i, (name, func)) in self.func.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "$fun {name}")?;
+            func.fmt_body(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "$fun _")?;
+        self.fmt_body(f)
+    }
+}
+
+impl Function {
+    /// Print everything after the function name.
+    fn fmt_body(&self, f: &mut Formatter<'_>) -> Result {
+        let n = &self.names;
+        let params = self
+            .params
+            .iter()
+            .map(|p| n.value(*p).to_string())
+            .collect::<Vec<_>>();
+        writeln!(f, "({}) {}", params.join(" "), self.ret)?;
+        for (var, ty) in &self.decl {
+            if !self.params.contains(var) {
+                writeln!(f, "  {} {ty}", n.value(*var))?;
+            }
+        }
+        writeln!(f, ";")?;
+        for (name, block) in &self.block {
+            writeln!(f, "{}:", n.block(*name))?;
+            for insn in &block.insn {
+                writeln!(f, "  {}", insn.display(n))?;
+            }
+            for term in &block.term {
+                writeln!(f, "  {}", term.display(n))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Instruction {
+    /// Display this instruction using the names of its values.
+    pub fn display<'a>(&'a self, names: &'a Names) -> impl Display + 'a {
+        Named(names, self)
+    }
+}
+
+impl Terminator {
+    /// Display this terminator using the names of its values and targets.
+    pub fn display<'a>(&'a self, names: &'a Names) -> impl Display + 'a {
+        Named(names, self)
+    }
+}
+
+struct Named<'a, T>(&'a Names, &'a T);
+
+impl Display for Named<'_, Instruction> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        use Instruction::*;
+        let v = |x: &ValueId| self.0.value(*x);
+        match self.1 {
+            Copy { dst, src } => write!(f, "$copy {} {}", v(dst), v(src)),
+            Const { dst, src } => write!(f, "$const {} {src}", v(dst)),
+            Arith { op, dst, lhs, rhs } => {
+                write!(f, "$arith {op} {} {} {}", v(dst), v(lhs), v(rhs))
+            }
+            Shift {
+                op,
+                dst,
+                src,
+                amount,
+            } => write!(f, "$shift {op} {} {} {amount}", v(dst), v(src)),
+            Read(dst) => write!(f, "$read {}", v(dst)),
+            Print(src) => write!(f, "$print {}", v(src)),
+            Count(k) => write!(f, "$count {k}"),
+            Loc(span) => write!(f, "$loc {} {}", span.start, span.end),
+            Phi { dst, args } => {
+                write!(f, "$phi {}", v(dst))?;
+                for (pred, val) in args {
+                    write!(f, " {} {}", self.0.block(*pred), v(val))?;
+                }
+                Ok(())
+            }
+            Call { dst, callee, args } => {
+                write!(f, "$call {} {callee}", v(dst))?;
+                for arg in args {
+                    write!(f, " {}", v(arg))?;
+                }
+                Ok(())
+            }
+            Alloc { dst, size } => write!(f, "$alloc {} {}", v(dst), v(size)),
+            Load { dst, addr, offset } => write!(f, "$load {} {} {offset}", v(dst), v(addr)),
+            Store { addr, offset, src } => write!(f, "$store {} {offset} {}", v(addr), v(src)),
+        }
+    }
+}
+
+impl Display for Named<'_, Terminator> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let n = self.0;
+        match self.1 {
+            Terminator::Exit(None) => write!(f, "$exit"),
+            Terminator::Exit(Some(status)) => write!(f, "$exit {}", n.value(*status)),
+            Terminator::Jump(target) => write!(f, "$jump {}", n.block(*target)),
+            Terminator::Branch { guard, tt, ff } => write!(
+                f,
+                "$branch {} {} {}",
+                n.value(*guard),
+                n.block(*tt),
+                n.block(*ff)
+            ),
+            Terminator::Return(value) => write!(f, "$return {}", n.value(*value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_program() {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.branch("x", "a", "b");
+        b.block("a");
+        b.jump("b");
+        b.block("b");
+        b.print("x");
+        b.exit();
+        let expected = "\
+$fun main() i64
+  x i64
+;
+$entry:
+  $const x 1
+  $branch x a b
+a:
+  $jump b
+b:
+  $print x
+  $exit
+";
+        assert_eq!(b.finish().to_string(), expected);
+    }
+}
@@ -0,0 +1,621 @@
+//! The tiny IR verifier.
+//!
+//! This checks the well-formedness constraints in `doc/ir.md`, and optionally
+//! the additional invariants of programs in SSA form.  The `_with_cycles`
+//! variants check all of them except that CFGs have no cycles, for the
+//! programs with loops that the loop passes are tested on.
+
+use std::fmt::Debug;
+
+use derive_more::derive::Display;
+
+use super::*;
+use crate::common::*;
+
+#[derive(Display)]
+#[display("Malformed tiny IR: {}", self.0)]
+pub struct VerifyError(String);
+
+impl Debug for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+type VerifyResult = Result<(), VerifyError>;
+
+fn error<T>(msg: String) -> Result<T, VerifyError> {
+    Err(VerifyError(msg))
+}
+
+/// Check that the program is well-formed.
+pub fn verify(program: &Program) -> VerifyResult {
+    verify_program(program, false)
+}
+
+/// Check that the program is well-formed, except that its CFGs may have
+/// cycles.
+pub fn verify_with_cycles(program: &Program) -> VerifyResult {
+    verify_program(program, true)
+}
+
+fn verify_program(program: &Program, allow_cycles: bool) -> VerifyResult {
+    match program.func.get(&Program::main()) {
+        None => return error(format!("There is no function named `{MAIN}`.")),
+        Some(main) if !main.params.is_empty() => {
+            return error(format!("The function `{MAIN}` should not have parameters."))
+        }
+        Some(_) => {}
+    }
+    // Check the signatures first, since calls in all functions rely on them.
+    for (name, func) in &program.func {
+        for param in &func.params {
+            if !func.decl.contains_key(param) {
+                let param = value_name(func, *param);
+                return error(format!(
+                    "In function `{name}`: Parameter `{param}` is not declared."
+                ));
+            }
+        }
+    }
+    for (name, func) in &program.func {
+        in_function(*name, verify_function(program, func, allow_cycles))?;
+    }
+    Ok(())
+}
+
+/// Check that the program is well-formed and in SSA form: each variable is
+/// defined at most once, and each definition dominates all of its uses.
+/// Variables without a definition hold their initial value (or the argument,
+/// for parameters).
+pub fn verify_ssa(program: &Program) -> VerifyResult {
+    verify(program)?;
+    verify_program_ssa(program)
+}
+
+/// Check that the program is well-formed and in SSA form, except that its
+/// CFGs may have cycles.
+pub fn verify_ssa_with_cycles(program: &Program) -> VerifyResult {
+    verify_with_cycles(program)?;
+    verify_program_ssa(program)
+}
+
+fn verify_program_ssa(program: &Program) -> VerifyResult {
+    for (name, func) in &program.func {
+        in_function(*name, verify_function_ssa(func))?;
+    }
+    Ok(())
+}
+
+/// Add the function name to an error message.
+fn in_function(name: Id, result: VerifyResult) -> VerifyResult {
+    result.map_err(|VerifyError(msg)| VerifyError(format!("In function `{name}`: {msg}")))
+}
+
+/// The name of a value for error messages.  Values missing from the name
+/// table are shown by their ID.
+fn value_name(func: &Function, var: ValueId) -> String {
+    match func.names.get_value(var) {
+        Some(name) => name.to_string(),
+        None => var.to_string(),
+    }
+}
+
+/// The name of a block for error messages.
+fn block_name(func: &Function, block: BlockId) -> String {
+    match func.names.get_block(block) {
+        Some(name) => name.to_string(),
+        None => block.to_string(),
+    }
+}
+
+fn verify_function(program: &Program, func: &Function, allow_cycles: bool) -> VerifyResult {
+    if !func.block.contains_key(&Function::entry()) {
+        return error(format!("There is no start block named `{ENTRY}`."));
+    }
+    for var in func.decl.keys() {
+        if func.names.get_value(*var).is_none() {
+            return error(format!("Variable `{var}` has no name."));
+        }
+    }
+    for block in func.block.keys() {
+        if func.names.get_block(*block).is_none() {
+            return error(format!("Block `{block}` has no name."));
+        }
+    }
+    let preds = func.predecessors();
+    for (id, block) in &func.block {
+        let name = func.names.block(*id);
+        if block.term.len() != 1 {
+            return error(format!(
+                "Block `{name}` has {} terminators instead of exactly one.",
+                block.term.len()
+            ));
+        }
+        for succ in block.successors() {
+            if !func.block.contains_key(&succ) {
+                let succ = block_name(func, succ);
+                return error(format!(
+                    "Block `{name}` jumps to an unknown block `{succ}`."
+                ));
+            }
+        }
+
+        let mut seen_non_phi = false;
+        for insn in &block.insn {
+            for var in insn.uses().into_iter().chain(insn.def()) {
+                if !func.decl.contains_key(&var) {
+                    let var = value_name(func, var);
+                    return error(format!(
+                        "Variable `{var}` in block `{name}` is not declared."
+                    ));
+                }
+            }
+            check_types(program, func, *id, insn)?;
+            match insn {
+                Instruction::Phi { dst, args } => {
+                    let dst = func.names.value(*dst);
+                    if seen_non_phi {
+                        return error(format!(
+                            "The phi instruction for `{dst}` in block `{name}` is not at the start of the block."
+                        ));
+                    }
+                    if !args.keys().copied().eq(preds[id].iter().copied()) {
+                        return error(format!(
+                            "The phi instruction for `{dst}` in block `{name}` does not have exactly one argument per predecessor."
+                        ));
+                    }
+                }
+                _ => seen_non_phi = true,
+            }
+        }
+        for var in block.term.iter().flat_map(Terminator::uses) {
+            if !func.decl.contains_key(&var) {
+                let var = value_name(func, var);
+                return error(format!(
+                    "Variable `{var}` in block `{name}` is not declared."
+                ));
+            }
+        }
+        match &block.term[0] {
+            Terminator::Branch { guard, .. } => {
+                expect_types(func, *id, *guard, &[Type::I64, Type::Bool])?
+            }
+            Terminator::Return(value) => expect_type(func, *id, *value, func.ret)?,
+            Terminator::Exit(Some(status)) => expect_type(func, *id, *status, Type::I64)?,
+            Terminator::Exit(None) | Terminator::Jump(_) => {}
+        }
+    }
+
+    if let Some(block) = find_cycle(func).filter(|_| !allow_cycles) {
+        let block = func.names.block(block);
+        return error(format!(
+            "There is a cycle in the CFG through block `{block}`."
+        ));
+    }
+
+    Ok(())
+}
+
+fn verify_function_ssa(func: &Function) -> VerifyResult {
+    // Where each variable is defined: the block and the instruction index.
+    let mut defs: Map<ValueId, (BlockId, usize)> = Map::new();
+    for (name, block) in &func.block {
+        for (i, insn) in block.insn.iter().enumerate() {
+            if let Some(dst) = insn.def() {
+                if defs.insert(dst, (*name, i)).is_some() {
+                    let dst = func.names.value(dst);
+                    return error(format!("Variable `{dst}` is defined more than once."));
+                }
+            }
+        }
+    }
+
+    let dom = DomTree::new(func);
+    // Does the definition of `var` reach the point before instruction `pos` of
+    // `block`?  Uses in terminators have `pos` equal to the block length.
+    let available = |var: ValueId, block: BlockId, pos: usize| match defs.get(&var) {
+        None => true,
+        Some((def_block, i)) if *def_block == block => *i < pos,
+        Some((def_block, _)) => dom.strictly_dominates(*def_block, block),
+    };
+
+    let n = &func.names;
+    for id in dom.reverse_postorder() {
+        let block = &func.block[id];
+        let name = n.block(*id);
+        let end = block.insn.len();
+        for (pos, insn) in block.insn.iter().enumerate() {
+            match insn {
+                Instruction::Phi { dst, args } => {
+                    for (pred, var) in args {
+                        if dom.is_reachable(*pred)
+                            && !available(*var, *pred, func.block[pred].insn.len())
+                        {
+                            let (var, dst, pred) = (n.value(*var), n.value(*dst), n.block(*pred));
+                            return error(format!(
+                                "The argument `{var}` of the phi for `{dst}` in block `{name}` is not available at the end of `{pred}`."
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    for var in insn.uses() {
+                        if !available(var, *id, pos) {
+                            let var = n.value(var);
+                            return error(format!(
+                                "The use of `{var}` in block `{name}` is not dominated by its definition."
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        for var in block.term.iter().flat_map(Terminator::uses) {
+            if !available(var, *id, end) {
+                let var = n.value(var);
+                return error(format!(
+                    "The use of `{var}` in the terminator of block `{name}` is not dominated by its definition."
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a variable has the expected type.  The variable must be
+/// declared.
+fn expect_type(func: &Function, block: BlockId, var: ValueId, expected: Type) -> VerifyResult {
+    expect_types(func, block, var, &[expected])
+}
+
+/// Check that a variable has one of the expected types.  The variable must be
+/// declared.
+fn expect_types(func: &Function, block: BlockId, var: ValueId, expected: &[Type]) -> VerifyResult {
+    let actual = func.decl[&var];
+    if expected.contains(&actual) {
+        Ok(())
+    } else {
+        let (var, block) = (func.names.value(var), func.names.block(block));
+        let expected = expected
+            .iter()
+            .map(Type::to_string)
+            .collect::<Vec<_>>()
+            .join(" or ");
+        error(format!(
+            "Variable `{var}` in block `{block}` has type {actual} but {expected} is expected."
+        ))
+    }
+}
+
+/// The types of the values that memory holds.  Bools don't fit, since a word
+/// of memory may hold any number.
+const WORD_TYPES: [Type; 3] = [Type::I64, Type::F64, Type::Ptr];
+
+/// Check that the operands of an instruction have the types it expects, and
+/// that calls match the signature of the callee.  All variables in the
+/// instruction must be declared.
+fn check_types(
+    program: &Program,
+    func: &Function,
+    block: BlockId,
+    insn: &Instruction,
+) -> VerifyResult {
+    use Instruction::*;
+
+    let ty = |var: &ValueId| func.decl[var];
+    let expect = |var: &ValueId, expected: Type| expect_type(func, block, *var, expected);
+    let expect_word = |var: &ValueId| expect_types(func, block, *var, &WORD_TYPES);
+
+    match insn {
+        Copy { dst, src } => expect(src, ty(dst)),
+        Const { dst, src: 0 | 1 } => expect_types(func, block, *dst, &[Type::I64, Type::Bool]),
+        Const { dst, .. } | Read(dst) => expect(dst, Type::I64),
+        Print(src) => expect(src, Type::I64),
+        Count(_) | Loc(_) => Ok(()),
+        Arith { op, dst, lhs, rhs } => {
+            let (operand, result) = Type::of_bop(*op);
+            expect(lhs, operand)?;
+            expect(rhs, operand)?;
+            expect(dst, result)
+        }
+        Shift {
+            dst, src, amount, ..
+        } => {
+            if *amount >= 64 {
+                let block = func.names.block(block);
+                return error(format!(
+                    "Block `{block}` shifts by {amount} bits, which is more than 63."
+                ));
+            }
+            expect(src, Type::I64)?;
+            expect(dst, Type::I64)
+        }
+        Phi { dst, args } => args.values().try_for_each(|arg| expect(arg, ty(dst))),
+        Call { dst, callee, args } => {
+            let block = func.names.block(block);
+            let Some(target) = program.func.get(callee) else {
+                return error(format!(
+                    "Block `{block}` calls an unknown function `{callee}`."
+                ));
+            };
+            if args.len() != target.params.len() {
+                return error(format!(
+                    "Block `{block}` calls `{callee}` with {} arguments instead of {}.",
+                    args.len(),
+                    target.params.len()
+                ));
+            }
+            for (arg, param) in args.iter().zip(&target.params) {
+                expect(arg, target.decl[param])?;
+            }
+            expect(dst, target.ret)
+        }
+        Alloc { dst, size } => {
+            expect(size, Type::I64)?;
+            expect(dst, Type::Ptr)
+        }
+        Load { dst, addr, .. } => {
+            expect(addr, Type::Ptr)?;
+            expect_word(dst)
+        }
+        Store { addr, src, .. } => {
+            expect(addr, Type::Ptr)?;
+            expect_word(src)
+        }
+    }
+}
+
+/// Find a block on a cycle, if there is any.
+fn find_cycle(func: &Function) -> Option<BlockId> {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum State {
+        InProgress,
+        Done,
+    }
+
+    let mut state: Map<BlockId, State> = Map::new();
+    for root in func.block.keys() {
+        if state.contains_key(root) {
+            continue;
+        }
+        state.insert(*root, State::InProgress);
+        let mut stack = vec![(*root, func.block[root].successors().into_iter())];
+        while let Some((block, succs)) = stack.last_mut() {
+            match succs.next() {
+                Some(s) => match state.get(&s) {
+                    Some(State::InProgress) => return Some(s),
+                    Some(State::Done) => {}
+                    None if func.block.contains_key(&s) => {
+                        state.insert(s, State::InProgress);
+                        stack.push((s, func.block[&s].successors().into_iter()));
+                    }
+                    None => {}
+                },
+                None => {
+                    state.insert(*block, State::Done);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::ast::BOp;
+
+    // SECTION: helpers
+
+    fn block(insn: Vec<Instruction>, term: Vec<Terminator>) -> Block {
+        Block { insn, term }
+    }
+
+    // Create a program whose main function has only the entry block.
+    fn entry_only(term: Vec<Terminator>) -> Program {
+        let mut main = Function::default();
+        main.block.insert(Function::entry(), block(vec![], term));
+        Program::from_main(main)
+    }
+
+    // Create a program with a call to `f`, which adds its two parameters.
+    fn with_f(callee: &str, args: &[&str]) -> Program {
+        let mut b = Builder::new();
+        b.constant("x", 1);
+        b.call("r", callee, args);
+        b.exit();
+        b.function("f", &["a", "b"], Type::I64);
+        b.arith(BOp::Add, "s", "a", "b");
+        b.ret("s");
+        b.finish()
+    }
+
+    fn main_mut(p: &mut Program) -> &mut Function {
+        p.func.get_mut(&Program::main()).unwrap()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn well_formed() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.jump("end");
+        b.block("end");
+        b.print("x");
+        b.exit();
+        let p = b.finish();
+        assert!(verify(&p).is_ok());
+        assert!(verify_ssa(&p).is_ok());
+    }
+
+    #[test]
+    fn malformed() {
+        let mut no_entry = Function::default();
+        let start = no_entry.names.new_block("start");
+        no_entry
+            .block
+            .insert(start, block(vec![], vec![Terminator::Exit(None)]));
+        assert!(verify(&Program::from_main(no_entry)).is_err());
+
+        let mut b = Builder::new();
+        b.print("x");
+        b.exit();
+        let mut undeclared = b.finish();
+        main_mut(&mut undeclared).decl.clear();
+        let err = verify(&undeclared).unwrap_err().to_string();
+        assert!(err.contains("Variable `x`"), "{err}");
+
+        let mut bad_target = entry_only(vec![Terminator::Exit(None)]);
+        let main = main_mut(&mut bad_target);
+        let nowhere = main.names.new_block("nowhere");
+        main.block.get_mut(&Function::entry()).unwrap().term = vec![Terminator::Jump(nowhere)];
+        assert!(verify(&bad_target).is_err());
+
+        let unnamed = entry_only(vec![Terminator::Jump(BlockId(7))]);
+        assert!(verify(&unnamed).is_err());
+
+        let mut b = Builder::new();
+        b.jump("a");
+        b.block("a");
+        b.jump("a");
+        let cycle = b.finish();
+        let err = verify(&cycle).unwrap_err().to_string();
+        assert!(err.contains("cycle"), "{err}");
+        assert!(verify_with_cycles(&cycle).is_ok());
+        assert!(verify_ssa_with_cycles(&cycle).is_ok());
+
+        let two_terms = entry_only(vec![Terminator::Exit(None), Terminator::Exit(None)]);
+        assert!(verify(&two_terms).is_err());
+    }
+
+    #[test]
+    fn malformed_phi() {
+        let mut b = Builder::new();
+        b.jump("a");
+        b.block("a");
+        b.print("y");
+        b.phi("x", &[(ENTRY, "y")]);
+        b.exit();
+        assert!(verify(&b.finish()).is_err());
+
+        let mut b = Builder::new();
+        b.jump("a");
+        b.block("a");
+        b.phi("x", &[]);
+        b.exit();
+        assert!(verify(&b.finish()).is_err());
+    }
+
+    #[test]
+    fn not_ssa() {
+        let mut b = Builder::new();
+        b.read("x");
+        b.read("x");
+        b.exit();
+        let twice = b.finish();
+        assert!(verify(&twice).is_ok());
+        assert!(verify_ssa(&twice).is_err());
+
+        let mut b = Builder::new();
+        b.branch("c", "a", "b");
+        b.block("a");
+        b.read("x");
+        b.jump("b");
+        b.block("b");
+        b.print("x");
+        b.exit();
+        let not_dominated = b.finish();
+        assert!(verify(&not_dominated).is_ok());
+        assert!(verify_ssa(&not_dominated).is_err());
+    }
+
+    #[test]
+    fn calls() {
+        assert!(verify(&with_f("f", &["x", "x"])).is_ok());
+        assert!(verify(&with_f("f", &["x"])).is_err());
+        assert!(verify(&with_f("g", &[])).is_err());
+
+        let mut no_main = with_f("f", &["x", "x"]);
+        no_main.func.remove(&Program::main());
+        assert!(verify(&no_main).is_err());
+    }
+
+    #[test]
+    fn memory() {
+        let mut b = Builder::new();
+        b.constant("n", 2);
+        b.alloc("p", "n");
+        b.store("p", 8, "n");
+        b.load("x", "p", 8);
+        b.print("x");
+        b.exit();
+        let mut p = b.finish();
+        assert!(verify(&p).is_ok());
+
+        // Pointers are not numbers.
+        let mut q = p.clone();
+        let main = main_mut(&mut q);
+        let ptr = main.var("p");
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        entry.insn.push(Instruction::Print(ptr));
+        assert!(verify(&q).is_err());
+
+        // Memory doesn't hold bools.
+        let main = main_mut(&mut p);
+        let x = main.var("x");
+        main.decl.insert(x, Type::Bool);
+        let err = verify(&p).unwrap_err().to_string();
+        assert!(err.contains("but i64 or f64 or ptr is expected"), "{err}");
+    }
+
+    #[test]
+    fn bools_and_floats() {
+        let mut b = Builder::new();
+        b.declare("t", Type::Bool);
+        b.constant("t", 1);
+        b.function("id", &[], Type::F64);
+        b.declare("f", Type::F64);
+        b.ret("f");
+        b.switch_function(MAIN);
+        b.declare("g", Type::F64);
+        b.call("g", "id", &[]);
+        b.branch("t", "a", "b");
+        b.block("a");
+        b.exit();
+        b.block("b");
+        b.exit();
+        let p = b.finish();
+        assert!(verify(&p).is_ok());
+
+        // Only 0 and 1 are bools.
+        let mut two = p.clone();
+        let main = main_mut(&mut two);
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        let Instruction::Const { src, .. } = &mut entry.insn[0] else {
+            unreachable!()
+        };
+        *src = 2;
+        assert!(verify(&two).is_err());
+
+        // Floats are not numbers to branch on or print.
+        let mut branch = p.clone();
+        let main = main_mut(&mut branch);
+        let g = main.var("g");
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        entry.term[0] = Terminator::Branch {
+            guard: g,
+            tt: BlockId(1),
+            ff: BlockId(2),
+        };
+        assert!(verify(&branch).is_err());
+        let mut print = p;
+        let main = main_mut(&mut print);
+        let entry = main.block.get_mut(&Function::entry()).unwrap();
+        entry.insn.push(Instruction::Print(g));
+        assert!(verify(&print).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! Runs the golden tests in `tests/golden` with `smol-test`, in each way it
+//! runs programs that doesn't need a toolchain, without and with
+//! optimizations.
+
+use std::process::Command;
+
+#[test]
+fn golden() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+    for via in ["interp", "emu"] {
+        for level in ["-O0", "-O2"] {
+            let output = Command::new(env!("CARGO_BIN_EXE_smol-test"))
+                .args(["--via", via, level, dir])
+                .output()
+                .unwrap();
+            let report = String::from_utf8(output.stdout).unwrap();
+            assert!(output.status.success(), "{via} {level}:\n{report}");
+            assert!(report.ends_with("# 4 passed, 0 failed\n"), "{report}");
+        }
+    }
+}
+
+#[test]
+fn failures() {
+    let dir = std::env::temp_dir().join(format!("smol-golden-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("wrong.smol"), "$print 1\n").unwrap();
+    std::fs::write(dir.join("wrong.expected"), "2\n").unwrap();
+    std::fs::write(dir.join("broken.smol"), "$print\n").unwrap();
+    std::fs::write(dir.join("broken.expected"), "").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_smol-test"))
+        .arg(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        report.contains("# Parse error: Unexpected end of input.\n"),
+        "{report}"
+    );
+    assert!(
+        report.contains("# The output differs at line 1: expected `2`, got `1`.\n"),
+        "{report}"
+    );
+    assert!(output.stderr.is_empty());
+}
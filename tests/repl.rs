@@ -0,0 +1,45 @@
+//! Runs `smol-repl` on inputs typed one after the other.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Type the lines into the REPL, and return what it printed, without the
+/// prompts, and its errors.
+fn repl(lines: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smol-repl"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(lines.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .replace("smol> ", "")
+        .replace("...   ", "");
+    (stdout, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn keeps_variables() {
+    let (stdout, stderr) = repl(":= x 5\nx\n:= y * x 2\n$print + x y\n:env\n");
+    assert_eq!(stdout, "5\n15\nx = 5\ny = 10\n\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn commands() {
+    let (stdout, stderr) = repl(":= x 1\n:reset\n:env\nx\n:nope\n:quit\n$print 2\n");
+    assert_eq!(stdout, "0\n");
+    assert_eq!(
+        stderr,
+        "Unknown command `:nope`.  `:help` lists the commands.\n"
+    );
+}
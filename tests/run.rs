@@ -0,0 +1,54 @@
+//! Runs `smol-run` on small programs.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Write the source to a file of its own, and run it with the arguments and
+/// the input.
+fn smol_run(name: &str, source: &str, args: &[&str], input: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("smol-run-{}-{name}.smol", std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smol-run"))
+        .args(args)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn traces_statements() {
+    let source = "$read a\n:= b * a 2\n$if < a b {\n  $print b\n} {\n  $print a\n}\n$print 0\n";
+    for level in ["-O0", "-O2"] {
+        let output = smol_run("trace", source, &["--trace", level], "3\n");
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "6\n0\n");
+        let trace = String::from_utf8(output.stderr)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(".smol:").unwrap().1.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trace,
+            [
+                "1: $read a",
+                "2: := b * a 2",
+                "3: $if < a b {",
+                "4: $print b",
+                "8: $print 0"
+            ],
+            "{level}"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Runs `smolc` on small programs, for what only the whole compiler does.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Write the source to a file of its own, and compile it with the arguments.
+fn smolc(name: &str, source: &str, args: &[&str]) -> Output {
+    smolc_input(name, source, args, "")
+}
+
+/// Like [smolc], with the input of the program on stdin.
+fn smolc_input(name: &str, source: &str, args: &[&str], input: &str) -> Output {
+    let path = source_file(name, source);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smolc"))
+        .args(args)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn source_file(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("smolc-{}-{name}.smol", std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn riscv32_constants() {
+    let source = "$print 4294967297\n";
+    let output = smolc("rv32", source, &["--target", "riscv32"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("The constant `4294967297` doesn't fit"),
+        "{}",
+        stderr(&output)
+    );
+    let output = smolc("rv64", source, &["--target", "riscv64"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("main"));
+}
+
+#[test]
+fn asm_comments() {
+    let source = "$read a\n:= b * a 2\n$if < a b {\n  $print b\n} { }\n";
+    let output = smolc("comments", source, &["--asm-comments"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = stdout(&output);
+    let comments = [
+        "# 1: $read a",
+        "# 2: := b * a 2",
+        "# 3: $if < a b {",
+        "# 4: $print b",
+    ];
+    let mut at = 0;
+    for comment in comments {
+        let found = asm[at..].find(comment);
+        assert!(found.is_some(), "no `{comment}` in order:\n{asm}");
+        at += found.unwrap();
+    }
+}
+
+#[test]
+fn debug_info() {
+    let path = source_file("debug", "$read a\n$if a {\n  $print a\n} { }\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_smolc"))
+        .arg("-g")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = stdout(&output);
+    assert!(
+        asm.contains(&format!(".file 1 \"{}\"", path.display())),
+        "{asm}"
+    );
+    for loc in [".loc 1 1 1", ".loc 1 2 1", ".loc 1 3 3"] {
+        assert!(asm.contains(loc), "no `{loc}`:\n{asm}");
+    }
+    let output = smolc("no-debug", "$print 1\n", &[]);
+    assert!(!stdout(&output).contains(".loc"));
+}
+
+#[test]
+fn trap_div_zero() {
+    for level in ["-O0", "-O1", "-O2", "-O3"] {
+        let output = smolc(
+            "div",
+            "$print / 10 0\n",
+            &[level, "--trap-div-zero", "--run"],
+        );
+        assert_eq!(output.status.code(), Some(1), "{level}");
+        assert!(
+            stderr(&output).contains("Division by zero."),
+            "{level}: {}",
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "");
+    }
+}
+
+#[cfg(feature = "cranelift")]
+#[test]
+fn jit_traps() {
+    let args = ["--trap-div-zero", "--run", "--via", "jit"];
+    let output = smolc("jit-div", "$print / 10 0\n", &args);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("The JIT cannot run programs with `--trap-div-zero`."));
+    let args = ["--trap-overflow", "--run", "--via", "jit"];
+    let output = smolc("jit-overflow", "$print * 9223372036854775807 2\n", &args);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("The JIT cannot run programs with `--trap-overflow`."));
+}
+
+#[test]
+fn trap_overflow() {
+    let programs = [
+        ("$read a\n$print * a 4\n", "9223372036854775807\n"),
+        ("$print * 9223372036854775807 2\n", ""),
+        ("$read a\n$print + + a 1 -1\n", "9223372036854775807\n"),
+    ];
+    for (source, input) in programs {
+        for level in ["-O0", "-O2", "-O3"] {
+            let args = [level, "--trap-overflow", "--run"];
+            let output = smolc_input("overflow", source, &args, input);
+            assert_eq!(output.status.code(), Some(1), "{level}: {source}");
+            assert!(
+                stderr(&output).contains("Arithmetic overflow."),
+                "{level}: {source}: {}",
+                stderr(&output)
+            );
+            assert_eq!(stdout(&output), "");
+        }
+    }
+}
+
+#[test]
+fn bare_optimization_level() {
+    let source = ":= x + 1 2\n$print x\n";
+    let optimized = smolc("o2", source, &["--out", "tir", "-O2"]);
+    assert!(optimized.status.success(), "{}", stderr(&optimized));
+    let output = smolc("o", source, &["--out", "tir", "-O"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), stdout(&optimized));
+    let output = smolc("o0", source, &["--out", "tir", "-O0"]);
+    assert_ne!(stdout(&output), stdout(&optimized));
+}
+
+#[test]
+fn syntax_errors() {
+    let output = smolc("syntax", ":= x 1\n$print + x %\n", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("-syntax.smol:2:12\n"), "{stderr}");
+    assert!(stderr.contains("2 | $print + x %\n"), "{stderr}");
+}
+
+#[test]
+fn json_diagnostics() {
+    let output = smolc("json", "$print + 1", &["--message-format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let line = stdout(&output);
+    let span = r#""span":{"start":10,"end":10,"line":1,"column":11}"#;
+    assert!(line.contains(span), "{line}");
+    assert_eq!(line.lines().count(), 1);
+}
+
+#[test]
+fn completions_to_a_closed_pipe() {
+    for command in [&["completions", "bash"][..], &["man"]] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_smolc"))
+            .args(command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Nothing reads the output, like after `head` stops.
+        drop(child.stdout.take());
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stderr(&output), "");
+    }
+}